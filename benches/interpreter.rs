@@ -0,0 +1,121 @@
+//! Criterion benchmarks for the interpreter.
+//! Workloads live in src/bench.rs and are shared with `cognos bench`.
+//! The crate has no library target, so the modules are compiled in directly.
+
+// Bench targets build with cfg(test), but the modules' #[test] fns are dropped.
+#![allow(unused_imports)]
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+#[allow(dead_code)]
+#[path = "../src/token.rs"]
+mod token;
+#[allow(dead_code)]
+#[path = "../src/lexer.rs"]
+mod lexer;
+#[path = "../src/ast.rs"]
+mod ast;
+#[path = "../src/error.rs"]
+mod error;
+#[allow(dead_code)]
+#[path = "../src/parser.rs"]
+mod parser;
+#[allow(dead_code)]
+#[path = "../src/environment.rs"]
+mod environment;
+#[allow(dead_code)]
+#[path = "../src/http_client.rs"]
+mod http_client;
+#[allow(dead_code)]
+#[path = "../src/diagnostics.rs"]
+mod diagnostics;
+#[allow(dead_code)]
+#[path = "../src/auth.rs"]
+mod auth;
+#[path = "../src/oauth.rs"]
+mod oauth;
+#[allow(dead_code)]
+#[path = "../src/providers/mod.rs"]
+mod providers;
+#[allow(dead_code)]
+#[path = "../src/config.rs"]
+mod config;
+#[allow(dead_code)]
+#[path = "../src/query.rs"]
+mod query;
+#[allow(dead_code)]
+#[path = "../src/pretty.rs"]
+mod pretty;
+#[allow(dead_code)]
+#[path = "../src/bundle.rs"]
+mod bundle;
+#[allow(dead_code)]
+#[path = "../src/terminal.rs"]
+mod terminal;
+#[path = "../src/help.rs"]
+mod help;
+#[allow(dead_code)]
+#[path = "../src/plugin.rs"]
+mod plugin;
+#[allow(dead_code)]
+#[path = "../src/trace.rs"]
+mod trace;
+#[allow(dead_code)]
+#[path = "../src/wasm.rs"]
+mod wasm;
+#[allow(dead_code)]
+#[path = "../src/mcp.rs"]
+mod mcp;
+#[allow(dead_code)]
+#[path = "../src/grpc.rs"]
+mod grpc;
+#[allow(dead_code)]
+#[path = "../src/mqtt.rs"]
+mod mqtt;
+#[allow(dead_code)]
+#[path = "../src/nats.rs"]
+mod nats;
+#[allow(dead_code)]
+#[path = "../src/retry.rs"]
+mod retry;
+#[allow(dead_code)]
+#[path = "../src/screen.rs"]
+mod screen;
+#[allow(dead_code)]
+#[path = "../src/memory.rs"]
+mod memory;
+#[allow(dead_code)]
+#[path = "../src/optimize.rs"]
+mod optimize;
+#[allow(dead_code)]
+#[path = "../src/interpreter.rs"]
+mod interpreter;
+#[allow(dead_code)]
+#[path = "../src/bench.rs"]
+mod bench;
+
+fn interpreter_benches(c: &mut Criterion) {
+    for workload in bench::WORKLOADS {
+        let program = bench::prepare(workload).expect("workload parses");
+        c.bench_function(workload.name, |b| {
+            b.iter(|| bench::execute(&program).expect("workload runs"))
+        });
+    }
+}
+
+fn lexer_parser_benches(c: &mut Criterion) {
+    let source: String = bench::WORKLOADS.iter().map(|w| w.source).collect::<Vec<_>>().join("\n");
+    c.bench_function("lex_and_parse", |b| {
+        b.iter(|| {
+            let tokens = lexer::Lexer::new(&source).tokenize();
+            parser::Parser::new(tokens).parse_program().expect("parses")
+        })
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(20);
+    targets = interpreter_benches, lexer_parser_benches
+}
+criterion_main!(benches);
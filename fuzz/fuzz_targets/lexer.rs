@@ -0,0 +1,20 @@
+//! Fuzz the lexer: any UTF-8 input must tokenize without panicking and
+//! always end in a single Eof token.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code)]
+#[path = "../../src/token.rs"]
+mod token;
+#[allow(dead_code)]
+#[path = "../../src/lexer.rs"]
+mod lexer;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(source) = std::str::from_utf8(data) else { return };
+    let tokens = lexer::Lexer::new(source).tokenize();
+    let eofs = tokens.iter().filter(|t| t.token == token::Token::Eof).count();
+    assert_eq!(eofs, 1, "expected exactly one Eof token");
+    assert_eq!(tokens.last().map(|t| &t.token), Some(&token::Token::Eof));
+});
@@ -0,0 +1,32 @@
+//! Fuzz the parser: lexing then parsing arbitrary UTF-8 must return
+//! Ok or Err, never panic. Programs that parse are pretty-printed and
+//! formatted too, since the printer walks every node.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code)]
+#[path = "../../src/token.rs"]
+mod token;
+#[allow(dead_code)]
+#[path = "../../src/lexer.rs"]
+mod lexer;
+#[path = "../../src/ast.rs"]
+mod ast;
+#[path = "../../src/error.rs"]
+mod error;
+#[allow(dead_code)]
+#[path = "../../src/parser.rs"]
+mod parser;
+#[allow(dead_code)]
+#[path = "../../src/pretty.rs"]
+mod pretty;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(source) = std::str::from_utf8(data) else { return };
+    let tokens = lexer::Lexer::new(source).tokenize();
+    if let Ok(program) = parser::Parser::new(tokens).parse_program() {
+        let _ = pretty::pretty_program(&program);
+        let _ = pretty::format_source(source);
+    }
+});
@@ -1,14 +1,39 @@
 #![allow(dead_code)]
-/// Abstract Syntax Tree for Cognos programs.
+//! Abstract Syntax Tree for Cognos programs.
 
-#[derive(Debug, Clone)]
+pub use crate::token::{Name, Span};
+
+/// Values bound before a program starts. Rebinding one would break every
+/// later `read(stdin)` or `http.get()`, so it is a parse error.
+pub const RESERVED_NAMES: &[&str] = &["stdin", "stdout", "http", "grpc"];
+
+/// Built-in functions that a call always reaches, even when a flow has the
+/// same name, so such a flow could never run. The other built-ins (`round`,
+/// `classify`, `type_of`, ...) step aside for a flow of their name.
+pub const BUILTIN_FUNCTIONS: &[&str] = &[
+    "think", "invoke", "emit", "log", "print", "remember", "recall", "recall_scored", "forget",
+    "read", "write", "file", "channel", "download", "history", "clear_history", "shared",
+    "int", "float", "str", "eval", "save", "load", "remove", "cancel", "count_tokens", "chunk_text",
+];
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Program {
-    pub imports: Vec<String>,
+    pub imports: Vec<Import>,
     pub types: Vec<TypeDef>,
     pub flows: Vec<FlowDef>,
+    /// Top-level statements, run once when the file is loaded or imported;
+    /// the variables they set become globals readable from every flow.
+    pub setup: Vec<Stmt>,
+}
+
+/// `import "path"`, or `from "path" import a, b` when `names` is set
+#[derive(Debug, Clone, PartialEq)]
+pub struct Import {
+    pub path: String,
+    pub names: Option<Vec<String>>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum TypeDef {
     Struct {
         name: String,
@@ -29,14 +54,14 @@ impl TypeDef {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct TypeField {
     pub name: String,
     pub ty: TypeExpr,
     pub optional: bool,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct FlowDef {
     pub name: String,
     pub description: Option<String>,
@@ -45,11 +70,14 @@ pub struct FlowDef {
     pub body: Vec<Stmt>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Param {
-    pub name: String,
+    pub name: Name,
+    /// For a variadic parameter, the type of each item
     pub ty: TypeExpr,
     pub default: Option<Expr>,
+    /// `*name: T`: takes the positional arguments left over, as a List
+    pub variadic: bool,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -59,10 +87,26 @@ pub enum TypeExpr {
     Struct(Vec<(String, TypeExpr)>),      // { field: Type, ... }
 }
 
-#[derive(Debug, Clone)]
-pub enum Stmt {
+/// A statement and where it starts in its file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+impl From<StmtKind> for Stmt {
+    fn from(kind: StmtKind) -> Stmt {
+        Stmt { kind, span: Span::default() }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StmtKind {
     /// `name = expr`
-    Assign { name: String, expr: Expr },
+    Assign { name: Name, expr: Expr },
+    /// `name.field = expr` or `name[index] = expr`, through any number of
+    /// steps: `m["a"]["b"] = expr`, `rows[0].score = expr`
+    AssignPath { name: Name, path: Vec<PathStep>, expr: Expr },
     /// `emit(expr)`
     Emit { value: Expr },
     /// `return expr`
@@ -85,31 +129,105 @@ pub enum Stmt {
         max: Option<u32>,
         body: Vec<Stmt>,
     },
+    /// `while cond: body`
+    While {
+        condition: Expr,
+        body: Vec<Stmt>,
+    },
     /// `for item in collection: body`
     For {
-        var: String,
-        value_var: Option<String>,  // for k, v in map
+        var: Name,
+        value_var: Option<Name>,  // for k, v in map
         iterable: Expr,
         body: Vec<Stmt>,
     },
-    /// `try: body catch err: handler`
+    /// `match subject:` with `case pattern:` arms; the first arm that
+    /// matches runs, and none running is not an error
+    Match { subject: Expr, arms: Vec<MatchArm> },
+    /// `try: body` and its `catch` clauses; the first that takes the error
+    /// runs, and an error none takes goes on unwinding
     TryCatch {
         body: Vec<Stmt>,
-        error_var: Option<String>,
-        catch_body: Vec<Stmt>,
+        catches: Vec<Catch>,
     },
-    /// `parallel:` with `branch:` sub-blocks — run all branches concurrently
-    Parallel { branches: Vec<Vec<Stmt>> },
+    /// `parallel:` with `branch:` sub-blocks — run all branches concurrently.
+    /// `results = parallel:` sets `collect`: the branches' results are
+    /// gathered into `results` instead of their variables being merged.
+    Parallel { branches: Vec<Branch>, collect: Option<Name> },
     /// `select:` with `branch:` sub-blocks — run first completing branch
-    Select { branches: Vec<Vec<Stmt>> },
+    Select { branches: Vec<Branch> },
+    /// `with file("out.log") as f: body` — `resource` is closed when the
+    /// body exits, however it exits
+    With { resource: Expr, name: Name, body: Vec<Stmt> },
     /// Bare expression (function call as statement)
     Expr(Expr),
 }
 
-#[derive(Debug, Clone)]
+/// A step into a value on the left of `=`
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathStep {
+    /// `.field`
+    Field(String),
+    /// `[index]`
+    Index(Expr),
+}
+
+/// One `catch` clause: `catch NetworkError as e:`, `catch e:` or `catch:`.
+/// Without a kind it catches every error.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Catch {
+    pub kind: Option<String>,
+    pub var: Option<Name>,
+    pub body: Vec<Stmt>,
+}
+
+impl Catch {
+    /// Whether this clause takes any error: no kind, or `Error`.
+    pub fn catches_all(&self) -> bool {
+        self.kind.as_deref().is_none_or(|k| k == "Error")
+    }
+}
+
+/// One `case` of a `match`: its `|` alternatives and the body run when one
+/// of them matches
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchArm {
+    pub patterns: Vec<Pattern>,
+    pub body: Vec<Stmt>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    /// `_`: anything
+    Wildcard,
+    /// A name: anything, bound to the name for the arm's body
+    Bind(Name),
+    /// A String, Int, Float, Bool or `none` literal, compared with `==`
+    Literal(Expr),
+    /// `{"key": pattern, ...}`: a Map holding each key, with a value that
+    /// matches; other keys are ignored
+    Map(Vec<(String, Pattern)>),
+}
+
+impl Pattern {
+    /// Whether the pattern matches any value, so later arms can't run
+    pub fn is_irrefutable(&self) -> bool {
+        matches!(self, Pattern::Wildcard | Pattern::Bind(_))
+    }
+}
+
+/// One `branch:` (or named `branch fetch_a:`) of a parallel or select block
+#[derive(Debug, Clone, PartialEq)]
+pub struct Branch {
+    /// When set, the branch's variables merge back as a map under this name
+    pub name: Option<Name>,
+    pub body: Vec<Stmt>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Expr {
     /// Variable reference
-    Ident(String),
+    Ident(Name),
     /// String literal
     StringLit(String),
     /// Integer literal
@@ -134,11 +252,13 @@ pub enum Expr {
     /// Slice access: expr[start:end]
     Slice { object: Box<Expr>, start: Option<Box<Expr>>, end: Option<Box<Expr>> },
     /// Method call: expr.method(args)
-    MethodCall { object: Box<Expr>, method: String, args: Vec<Expr> },
+    MethodCall { object: Box<Expr>, method: String, args: Vec<Expr>, kwargs: Vec<(String, Expr)> },
     /// Binary op: left op right
     BinOp { left: Box<Expr>, op: BinOp, right: Box<Expr> },
-    /// Unary op: not expr
+    /// Unary op: not expr, -expr
     UnaryOp { op: UnaryOp, operand: Box<Expr> },
+    /// Chained comparison: 0 <= x < 10, each operand evaluated once
+    Compare { left: Box<Expr>, rest: Vec<(BinOp, Expr)> },
     /// List literal: [a, b, c]
     List(Vec<Expr>),
     /// Map literal: {"key": value, ...}
@@ -146,12 +266,36 @@ pub enum Expr {
     /// F-string: f"hello {name}, you have {count} items"
     /// Parts alternate between literal strings and expressions
     FString(Vec<FStringPart>),
+    /// Lambda: |x, y| x + y
+    Lambda { params: Vec<Name>, body: Box<Expr> },
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum FStringPart {
     Literal(String),
     Expr(Expr),
+    /// `{expr:spec}`, e.g. `{price:.2f}`
+    Formatted(Expr, FormatSpec),
+}
+
+/// The spec after `:` in an f-string interpolation:
+/// `[[fill]align][+][0][width][,][.precision][type]`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct FormatSpec {
+    /// Padding character, with an explicit align (default space)
+    pub fill: Option<char>,
+    /// `<`, `>` or `^`
+    pub align: Option<char>,
+    /// `+`: show the sign of non-negative numbers too
+    pub sign: bool,
+    /// `0`: pad numbers with zeros after the sign
+    pub zero: bool,
+    pub width: Option<usize>,
+    /// `,`: thousands separators
+    pub grouping: bool,
+    pub precision: Option<usize>,
+    /// `f` fixed, `e` exponent, `%` percent, `d` integer, `s` string
+    pub kind: Option<char>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -159,7 +303,8 @@ pub enum BinOp {
     Add,    // +
     Sub,    // -
     Mul,    // *
-    Div,    // /
+    Div,    // / (always Float)
+    FloorDiv, // // (rounds down)
     Mod,    // %
     Eq,     // ==
     NotEq,  // !=
@@ -176,4 +321,5 @@ pub enum BinOp {
 #[derive(Debug, Clone, PartialEq)]
 pub enum UnaryOp {
     Not,
+    Neg,
 }
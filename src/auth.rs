@@ -0,0 +1,204 @@
+//! Credential resolution shared by every provider.
+//!
+//! A key such as `OPENAI_API_KEY` is looked up, first match wins, in:
+//! the environment, `./.env`, `~/.cognos/credentials.json`, the OS keychain
+//! (service `cognos`), and for Anthropic the OpenClaw auth profiles and the
+//! `cognos login` token. `cognos auth status` shows what will be used.
+
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+/// Credentials Cognos knows about, by the service that uses them.
+pub const KEYS: &[(&str, &str)] = &[
+    ("anthropic", "ANTHROPIC_API_KEY"),
+    ("openai", "OPENAI_API_KEY"),
+    ("deepseek", "DEEPSEEK_API_KEY"),
+    ("minimax", "MINIMAX_API_KEY"),
+    ("slack", "SLACK_BOT_TOKEN"),
+    ("nats", "NATS_TOKEN"),
+    ("mqtt", "MQTT_USERNAME"),
+    ("mqtt", "MQTT_PASSWORD"),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Env,
+    DotEnv,
+    ConfigFile,
+    Keychain,
+    AuthProfile(PathBuf),
+    CognosLogin,
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Source::Env => write!(f, "env"),
+            Source::DotEnv => write!(f, ".env"),
+            Source::ConfigFile => write!(f, "~/.cognos/credentials.json"),
+            Source::Keychain => write!(f, "keychain"),
+            Source::AuthProfile(path) => write!(f, "OpenClaw profile {}", path.display()),
+            Source::CognosLogin => write!(f, "cognos login"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Credential {
+    pub value: String,
+    pub source: Source,
+}
+
+/// Find `key` in the first source that has a non-empty value for it. The
+/// environment is checked every time; the files and the keychain once per
+/// process, since every `think()` asks.
+pub fn lookup(key: &str) -> Option<Credential> {
+    if let Ok(value) = std::env::var(key) {
+        if !value.is_empty() {
+            return Some(Credential { value, source: Source::Env });
+        }
+    }
+    static STORED: OnceLock<Mutex<HashMap<String, Option<Credential>>>> = OnceLock::new();
+    let mut stored = STORED.get_or_init(Default::default).lock().unwrap_or_else(|e| e.into_inner());
+    stored.entry(key.to_string()).or_insert_with(|| stored_credential(key)).clone()
+}
+
+/// `key` from `.env`, the config file, the keychain or a provider login,
+/// whichever has it first. The keychain is only asked when both files miss.
+fn stored_credential(key: &str) -> Option<Credential> {
+    let found = |value: String, source: Source| Some(Credential { value, source });
+    if let Some(value) = from_dotenv(key) {
+        return found(value, Source::DotEnv);
+    }
+    if let Some(value) = from_config_file(key) {
+        return found(value, Source::ConfigFile);
+    }
+    if let Some(value) = from_keychain(key) {
+        return found(value, Source::Keychain);
+    }
+    if key == "ANTHROPIC_API_KEY" {
+        if let Some((value, path)) = from_auth_profiles() {
+            return found(value, Source::AuthProfile(path));
+        }
+        if let Some(token) = crate::oauth::load_token() {
+            return found(token.access_token, Source::CognosLogin);
+        }
+    }
+    None
+}
+
+/// Like [`lookup`], but a missing key is an error naming where to put it.
+pub fn require(key: &str) -> Result<String> {
+    match lookup(key) {
+        Some(credential) => Ok(credential.value),
+        None => bail!("{} not set. Set it in env, .env or ~/.cognos/credentials.json (see 'cognos auth status').", key),
+    }
+}
+
+fn home() -> PathBuf {
+    PathBuf::from(std::env::var("HOME").unwrap_or_else(|_| ".".to_string()))
+}
+
+/// A `KEY=value` line in `./.env`, quotes stripped.
+fn from_dotenv(key: &str) -> Option<String> {
+    let content = std::fs::read_to_string(".env").ok()?;
+    content.lines().find_map(|line| {
+        line.trim().strip_prefix(&format!("{}=", key))
+            .map(|val| val.trim_matches('"').trim_matches('\'').to_string())
+    }).filter(|v| !v.is_empty())
+}
+
+/// A flat `{"KEY": "value"}` object in `~/.cognos/credentials.json`.
+fn from_config_file(key: &str) -> Option<String> {
+    let path = home().join(".cognos/credentials.json");
+    let data = std::fs::read_to_string(&path).ok()?;
+    let parsed: serde_json::Value = match serde_json::from_str(&data) {
+        Ok(v) => v,
+        Err(e) => {
+            crate::diagnostics::warning(format!("ignoring {}: {}", path.display(), e));
+            return None;
+        }
+    };
+    parsed[key].as_str().filter(|v| !v.is_empty()).map(|v| v.to_string())
+}
+
+/// macOS Keychain or the freedesktop Secret Service, account = key name.
+fn from_keychain(key: &str) -> Option<String> {
+    let mut cmd = if cfg!(target_os = "macos") {
+        let mut cmd = std::process::Command::new("security");
+        cmd.args(["find-generic-password", "-s", "cognos", "-a", key, "-w"]);
+        cmd
+    } else if cfg!(unix) {
+        let mut cmd = std::process::Command::new("secret-tool");
+        cmd.args(["lookup", "service", "cognos", "key", key]);
+        cmd
+    } else {
+        return None;
+    };
+    let output = cmd.stdin(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (!value.is_empty()).then_some(value)
+}
+
+/// The first OpenClaw agent profile with an Anthropic token.
+fn from_auth_profiles() -> Option<(String, PathBuf)> {
+    let entries = std::fs::read_dir(home().join(".openclaw/agents")).ok()?;
+    for entry in entries.flatten() {
+        let auth_path = entry.path().join("agent/auth-profiles.json");
+        let Ok(data) = std::fs::read_to_string(&auth_path) else { continue };
+        let Ok(parsed) = serde_json::from_str::<serde_json::Value>(&data) else { continue };
+        if let Some(t) = parsed["profiles"]["anthropic:default"]["token"].as_str() {
+            if !t.is_empty() {
+                return Some((t.to_string(), auth_path));
+            }
+        }
+    }
+    None
+}
+
+/// Enough of a secret to tell keys apart without revealing it.
+fn mask(value: &str) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    if chars.len() <= 12 {
+        return "****".to_string();
+    }
+    let head: String = chars[..6].iter().collect();
+    let tail: String = chars[chars.len() - 4..].iter().collect();
+    format!("{}...{}", head, tail)
+}
+
+/// The `cognos auth status` report.
+pub fn status() -> String {
+    let mut out = String::from("Credentials (first match wins: env, .env, ~/.cognos/credentials.json, keychain, provider logins)\n\n");
+    for (service, key) in KEYS {
+        let found = match lookup(key) {
+            Some(c) => format!("{}  ({})", mask(&c.value), c.source),
+            None => "not set".to_string(),
+        };
+        out.push_str(&format!("  {:<10} {:<18} {}\n", service, key, found));
+    }
+    let bin = crate::providers::claude_cli_path();
+    let cli = if crate::providers::claude_cli_installed() { "found" } else { "not found (npm install -g @anthropic-ai/claude-code)" };
+    out.push_str(&format!("  {:<10} {:<18} {}\n", "claude-cli", bin, cli));
+    out.push_str("\nClaude models use the Anthropic API when it has a key, otherwise the claude CLI.\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mask_hides_all_but_the_ends() {
+        assert_eq!(mask("sk-ant-REDACTED"), "sk-ant...wxyz");
+        assert_eq!(mask("short"), "****");
+        assert!(!mask("sk-proj-0123456789secret").contains("secret"));
+    }
+}
@@ -0,0 +1,270 @@
+//! Interpreter benchmarks.
+//! Fixed workloads shared by `cognos bench` and the criterion suite in
+//! `benches/`. Everything runs against MockEnv, so no I/O leaves the process.
+
+use crate::ast::Program;
+use crate::environment::MockEnv;
+use crate::interpreter::Interpreter;
+use anyhow::{bail, Result};
+use std::time::{Duration, Instant};
+
+pub struct Workload {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub source: &'static str,
+}
+
+pub const WORKLOADS: &[Workload] = &[
+    Workload {
+        name: "tight_loop",
+        description: "20k iterations of integer arithmetic and reassignment",
+        source: r#"flow main():
+    total = 0
+    i = 0
+    loop max=20000:
+        total = total + i * 2 % 7
+        i = i + 1
+    write(stdout, total)
+"#,
+    },
+    Workload {
+        name: "flow_calls",
+        description: "200 recursive call chains, 50 flows deep",
+        source: r#"flow depth(n: Int) -> Int:
+    if n == 0:
+        return 0
+    return depth(n - 1) + 1
+
+flow main():
+    total = 0
+    loop max=200:
+        total = total + depth(50)
+    write(stdout, total)
+"#,
+    },
+    Workload {
+        name: "collections",
+        description: "build a 500-item list and map, then copy both 200 times",
+        source: r#"flow main():
+    items = []
+    i = 0
+    loop max=500:
+        items = items + [i]
+        i = i + 1
+    m = {}
+    for x in items:
+        m[str(x)] = x
+    copies = 0
+    loop max=200:
+        snapshot = items
+        other = m
+        copies = copies + snapshot.length + other.length
+    write(stdout, copies)
+"#,
+    },
+    Workload {
+        name: "parallel",
+        description: "20 rounds of four parallel branches",
+        source: r#"flow work() -> Int:
+    total = 0
+    loop max=100:
+        total = total + 1
+    return total
+
+flow main():
+    sum = 0
+    loop max=20:
+        parallel:
+            branch:
+                a = work()
+            branch:
+                b = work()
+            branch:
+                c = work()
+            branch:
+                d = work()
+        sum = sum + a + b + c + d
+    write(stdout, sum)
+"#,
+    },
+    Workload {
+        name: "json",
+        description: "20 save/load round trips of 100 nested records",
+        source: r#"flow main():
+    record = {"id": 1, "name": "item", "tags": ["a", "b", "c"], "meta": {"score": 0.5, "ok": true, "next": none}}
+    records = []
+    loop max=100:
+        records = records + [record]
+    loop max=20:
+        save("bench.json", records)
+        records = load("bench.json")
+    write(stdout, records.length)
+"#,
+    },
+    Workload {
+        name: "map_lookups",
+        description: "20k field reads and updates on a 64-key map",
+        source: r#"flow main():
+    m = {}
+    i = 0
+    loop max=64:
+        m["key" + str(i)] = i
+        i = i + 1
+    total = 0
+    i = 0
+    loop max=20000:
+        key = "key" + str(i % 64)
+        total = total + m[key]
+        m[key] = m[key] + 1
+        i = i + 1
+    write(stdout, total)
+"#,
+    },
+];
+
+/// Parse a workload once so timings measure execution only.
+pub fn prepare(workload: &Workload) -> Result<Program> {
+    let tokens = crate::lexer::Lexer::new(workload.source).tokenize();
+    crate::parser::Parser::new(tokens).parse_program()
+        .map_err(|e| anyhow::anyhow!("workload '{}': {}", workload.name, e))
+}
+
+/// Run a parsed workload on a fresh interpreter.
+pub fn execute(program: &Program) -> Result<()> {
+    let mut interp = Interpreter::with_env(Box::new(MockEnv::new()), None);
+    interp.run(program)
+}
+
+struct Sample {
+    name: &'static str,
+    median: Duration,
+    min: Duration,
+    mean: Duration,
+}
+
+fn measure(workload: &Workload, iterations: usize) -> Result<Sample> {
+    let program = prepare(workload)?;
+    execute(&program)?; // warm-up
+    let mut times = Vec::with_capacity(iterations);
+    for _ in 0..iterations {
+        let start = Instant::now();
+        execute(&program)?;
+        times.push(start.elapsed());
+    }
+    times.sort();
+    let total: Duration = times.iter().sum();
+    Ok(Sample {
+        name: workload.name,
+        median: times[times.len() / 2],
+        min: times[0],
+        mean: total / times.len() as u32,
+    })
+}
+
+pub struct BenchOptions {
+    pub list: bool,
+    pub iterations: usize,
+    pub filter: Option<String>,
+    pub save: Option<String>,
+    pub baseline: Option<String>,
+    /// Allowed slowdown against the baseline, in percent.
+    pub threshold: f64,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        Self { list: false, iterations: 10, filter: None, save: None, baseline: None, threshold: 10.0 }
+    }
+}
+
+/// Run the workloads, print a table, and optionally save or compare against
+/// a baseline. Fails if any median regresses past the threshold.
+pub fn run_bench(opts: &BenchOptions) -> Result<()> {
+    if opts.list {
+        for w in WORKLOADS {
+            println!("{:<14} {}", w.name, w.description);
+        }
+        return Ok(());
+    }
+    if opts.iterations == 0 {
+        bail!("--iterations must be at least 1");
+    }
+    let selected: Vec<&Workload> = WORKLOADS.iter()
+        .filter(|w| opts.filter.as_ref().map(|f| w.name.contains(f.as_str())).unwrap_or(true))
+        .collect();
+    if selected.is_empty() {
+        let names: Vec<&str> = WORKLOADS.iter().map(|w| w.name).collect();
+        bail!("no workload matches filter (available: {})", names.join(", "));
+    }
+
+    let baseline: Option<serde_json::Value> = match &opts.baseline {
+        Some(path) => {
+            let content = std::fs::read_to_string(path)
+                .map_err(|e| anyhow::anyhow!("cannot read baseline {}: {}", path, e))?;
+            Some(serde_json::from_str(&content)
+                .map_err(|e| anyhow::anyhow!("invalid baseline {}: {}", path, e))?)
+        }
+        None => None,
+    };
+
+    println!("{:<14} {:>12} {:>12} {:>12} {:>10}", "workload", "median", "min", "mean", "vs base");
+    let mut results = serde_json::Map::new();
+    let mut regressions = Vec::new();
+    for workload in selected {
+        let sample = measure(workload, opts.iterations)?;
+        let median_ns = sample.median.as_nanos() as u64;
+        let delta = baseline.as_ref()
+            .and_then(|b| b.get(sample.name))
+            .and_then(|v| v.as_u64())
+            .filter(|base| *base > 0)
+            .map(|base| (median_ns as f64 - base as f64) / base as f64 * 100.0);
+        let delta_str = match delta {
+            Some(d) => format!("{:+.1}%", d),
+            None => "-".to_string(),
+        };
+        println!("{:<14} {:>12} {:>12} {:>12} {:>10}",
+            sample.name, fmt_duration(sample.median), fmt_duration(sample.min),
+            fmt_duration(sample.mean), delta_str);
+        if let Some(d) = delta {
+            if d > opts.threshold {
+                regressions.push(format!("{} ({:+.1}%)", sample.name, d));
+            }
+        }
+        results.insert(sample.name.to_string(), serde_json::json!(median_ns));
+    }
+
+    if let Some(path) = &opts.save {
+        std::fs::write(path, serde_json::to_string_pretty(&results)?)?;
+        eprintln!("Saved baseline to {}", path);
+    }
+
+    if !regressions.is_empty() {
+        bail!("performance regression over {}%: {}", opts.threshold, regressions.join(", "));
+    }
+    Ok(())
+}
+
+fn fmt_duration(d: Duration) -> String {
+    let us = d.as_secs_f64() * 1e6;
+    if us >= 1000.0 {
+        format!("{:.2}ms", us / 1000.0)
+    } else {
+        format!("{:.1}µs", us)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_workloads_run() {
+        // Deep flow recursion needs more than the default test-thread stack
+        std::thread::Builder::new().stack_size(16 * 1024 * 1024).spawn(|| {
+            for w in WORKLOADS {
+                let program = prepare(w).unwrap();
+                execute(&program).unwrap_or_else(|e| panic!("workload '{}' failed: {}", w.name, e));
+            }
+        }).unwrap().join().unwrap();
+    }
+}
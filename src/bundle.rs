@@ -0,0 +1,215 @@
+//! `cognos bundle` — pack a program and everything it imports into one
+//! `.cogx` file.
+//!
+//! A bundle is JSON: a manifest plus the source of every module, keyed by
+//! its path relative to the entry file's directory. Sources are stored
+//! pretty-printed from the parsed AST, so `resource()` files are already
+//! inlined as string literals. `cognos run app.cogx` resolves imports
+//! against the bundle instead of the filesystem.
+//!
+//! `cognos build` goes one step further: it appends a bundle to a copy of
+//! the running executable, and an executable that finds one at its end runs
+//! it as `cognos run` would.
+
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+
+/// Bumped when the layout changes in a way older readers can't handle.
+const FORMAT: u64 = 1;
+
+const USAGE: &str = "usage: cognos bundle <main.cog> [-o <app.cogx>]";
+
+const BUILD_USAGE: &str = "usage: cognos build <main.cog|app.cogx> [-o <executable>]";
+
+/// Ends an executable with an embedded bundle, after the bundle's length.
+const MAGIC: &[u8; 8] = b"COGXBNDL";
+
+pub struct Bundle {
+    /// Module key of the program's entry file.
+    pub entry: String,
+    /// Module key → source.
+    pub modules: BTreeMap<String, String>,
+    /// Embedded resource files, as keys like the modules'.
+    pub resources: Vec<String>,
+}
+
+pub fn run(args: &[String]) -> Result<()> {
+    let (entry, out) = match args {
+        [entry] => (entry, None),
+        [entry, flag, out] | [flag, out, entry] if flag == "-o" => (entry, Some(out.clone())),
+        _ => bail!("{}", USAGE),
+    };
+    let out = out.unwrap_or_else(|| Path::new(entry).with_extension("cogx").to_string_lossy().to_string());
+    let bundle = build(Path::new(entry))?;
+    std::fs::write(&out, bundle.to_json())
+        .map_err(|e| anyhow::anyhow!("cannot write '{}': {}", out, e))?;
+    eprintln!("Bundled {} module(s) and {} resource(s) into {}", bundle.modules.len(), bundle.resources.len(), out);
+    Ok(())
+}
+
+/// `cognos build`: write an executable that runs the program.
+pub fn run_build(args: &[String]) -> Result<()> {
+    let (input, out) = match args {
+        [input] => (input, None),
+        [input, flag, out] | [flag, out, input] if flag == "-o" => (input, Some(out.clone())),
+        _ => bail!("{}", BUILD_USAGE),
+    };
+    let out = out.unwrap_or_else(|| Path::new(input).with_extension(std::env::consts::EXE_EXTENSION).to_string_lossy().to_string());
+    let bundle = if input.ends_with(".cogx") { Bundle::read(input)? } else { build(Path::new(input))? };
+    let exe = std::env::current_exe()
+        .map_err(|e| anyhow::anyhow!("cannot find the cognos executable: {}", e))?;
+    let mut binary = std::fs::read(&exe)
+        .map_err(|e| anyhow::anyhow!("cannot read '{}': {}", exe.display(), e))?;
+    let json = bundle.to_json();
+    binary.extend_from_slice(json.as_bytes());
+    binary.extend_from_slice(&(json.len() as u64).to_le_bytes());
+    binary.extend_from_slice(MAGIC);
+    std::fs::write(&out, &binary)
+        .map_err(|e| anyhow::anyhow!("cannot write '{}': {}", out, e))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&out, std::fs::Permissions::from_mode(0o755))
+            .map_err(|e| anyhow::anyhow!("cannot make '{}' executable: {}", out, e))?;
+    }
+    eprintln!("Built {} ({} module(s), {} resource(s))", out, bundle.modules.len(), bundle.resources.len());
+    Ok(())
+}
+
+/// The bundle appended to the running executable by `cognos build`.
+pub fn embedded() -> Option<Bundle> {
+    use std::io::{Read, Seek, SeekFrom};
+    let exe = std::env::current_exe().ok()?;
+    let mut file = std::fs::File::open(&exe).ok()?;
+    let mut trailer = [0u8; 16];
+    file.seek(SeekFrom::End(-16)).ok()?;
+    file.read_exact(&mut trailer).ok()?;
+    if &trailer[8..] != MAGIC {
+        return None;
+    }
+    let len = u64::from_le_bytes(trailer[..8].try_into().ok()?);
+    file.seek(SeekFrom::End(-16 - len as i64)).ok()?;
+    let mut json = String::new();
+    file.take(len).read_to_string(&mut json).ok()?;
+    match Bundle::from_json(&json, &exe.display().to_string()) {
+        Ok(bundle) => Some(bundle),
+        Err(e) => {
+            crate::diagnostics::warning(format!("ignoring the program embedded in this executable: {}", e));
+            None
+        }
+    }
+}
+
+/// Parse `entry` and, transitively, every file it imports.
+pub fn build(entry: &Path) -> Result<Bundle> {
+    let root = entry.parent().unwrap_or(Path::new(""));
+    let entry_key = entry.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .ok_or_else(|| anyhow::anyhow!("'{}' is not a file", entry.display()))?;
+    let mut bundle = Bundle { entry: entry_key.clone(), modules: BTreeMap::new(), resources: Vec::new() };
+    let mut pending = vec![entry_key];
+    while let Some(key) = pending.pop() {
+        if bundle.modules.contains_key(&key) {
+            continue;
+        }
+        let path = root.join(&key);
+        let source = std::fs::read_to_string(&path)
+            .map_err(|e| anyhow::anyhow!("cannot read '{}': {}", path.display(), e))?;
+        let tokens = crate::lexer::Lexer::new(&source).tokenize();
+        let mut parser = crate::parser::Parser::new(tokens).for_file(&path);
+        let program = parser.parse_program()
+            .map_err(|e| anyhow::anyhow!("error in '{}': {}", path.display(), e))?;
+        for resource in parser.resources() {
+            let resource = resource.strip_prefix(root).unwrap_or(resource);
+            bundle.resources.push(normalize(resource));
+        }
+        for import in &program.imports {
+            pending.push(join(&key, &import.path));
+        }
+        bundle.modules.insert(key, crate::pretty::pretty_program(&program));
+    }
+    bundle.resources.sort();
+    bundle.resources.dedup();
+    Ok(bundle)
+}
+
+/// Key of the module `import_path` names, imported from module `from`.
+pub fn join(from: &str, import_path: &str) -> String {
+    normalize(&Path::new(from).parent().unwrap_or(Path::new("")).join(import_path))
+}
+
+/// `lib/./a/../b.cog` → `lib/b.cog`, without touching the filesystem.
+fn normalize(path: &Path) -> String {
+    let mut parts: Vec<Component> = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if matches!(parts.last(), Some(Component::Normal(_))) => { parts.pop(); }
+            other => parts.push(other),
+        }
+    }
+    let normalized: PathBuf = parts.iter().collect();
+    normalized.to_string_lossy().replace('\\', "/")
+}
+
+impl Bundle {
+    pub fn to_json(&self) -> String {
+        let manifest = serde_json::json!({
+            "format": FORMAT,
+            "cognos_version": env!("CARGO_PKG_VERSION"),
+            "entry": self.entry,
+            "modules": self.modules.keys().collect::<Vec<_>>(),
+            "resources": self.resources,
+        });
+        let json = serde_json::json!({ "manifest": manifest, "modules": self.modules });
+        serde_json::to_string_pretty(&json).unwrap_or_default()
+    }
+
+    pub fn read(path: &str) -> Result<Bundle> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("cannot read bundle '{}': {}", path, e))?;
+        Self::from_json(&content, path)
+    }
+
+    /// Parse a bundle's JSON; `path` names it in errors.
+    pub fn from_json(content: &str, path: &str) -> Result<Bundle> {
+        let json: serde_json::Value = serde_json::from_str(content)
+            .map_err(|e| anyhow::anyhow!("{} is not a bundle: {}", path, e))?;
+        let manifest = &json["manifest"];
+        match manifest["format"].as_u64() {
+            Some(FORMAT) => {}
+            Some(other) => bail!("{} is bundle format {}, but this cognos reads format {}", path, other, FORMAT),
+            None => bail!("{} is not a bundle: missing manifest", path),
+        }
+        let entry = manifest["entry"].as_str()
+            .ok_or_else(|| anyhow::anyhow!("{} is not a bundle: manifest has no entry", path))?
+            .to_string();
+        let modules: BTreeMap<String, String> = serde_json::from_value(json["modules"].clone())
+            .map_err(|e| anyhow::anyhow!("{} is not a bundle: {}", path, e))?;
+        if !modules.contains_key(&entry) {
+            bail!("{} is not a bundle: entry '{}' is missing from its modules", path, entry);
+        }
+        let resources = manifest["resources"].as_array()
+            .map(|r| r.iter().filter_map(|v| v.as_str().map(String::from)).collect())
+            .unwrap_or_default();
+        Ok(Bundle { entry, modules, resources })
+    }
+
+    pub fn entry_source(&self) -> &str {
+        &self.modules[&self.entry]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_join_normalizes_relative_to_importer() {
+        assert_eq!(join("main.cog", "lib/a.cog"), "lib/a.cog");
+        assert_eq!(join("lib/a.cog", "./util.cog"), "lib/util.cog");
+        assert_eq!(join("lib/deep/b.cog", "../../shared.cog"), "shared.cog");
+        assert_eq!(join("main.cog", "../outside.cog"), "../outside.cog");
+    }
+}
@@ -0,0 +1,290 @@
+//! `cognos chat` — talk to a model without writing a flow file.
+//!
+//! Each line you type is one turn of a `think(conversation=...)` exchange.
+//! Flows from a `--tools` library are offered as tools and run in-process;
+//! `/save` and `/load` keep the conversation in a JSON session file.
+
+use crate::diagnostics::{paint, Stream, Style};
+use crate::interpreter::{Entries, Interpreter, Value};
+use anyhow::{bail, Result};
+use std::io::BufRead;
+
+/// Tool rounds allowed per message before giving up on the model.
+const MAX_TOOL_ROUNDS: usize = 20;
+
+const BOLD: &str = "\x1b[1m";
+const DIM: &str = "\x1b[2m";
+const CYAN: &str = "\x1b[36m";
+const RESET: &str = "\x1b[0m";
+
+struct Chat {
+    interp: Interpreter,
+    model: String,
+    system: String,
+    tools: Vec<String>,
+    conversation: Vec<Value>,
+    color: bool,
+}
+
+pub fn run(args: &[String]) -> Result<()> {
+    let mut model = crate::interpreter::default_model();
+    let mut system = String::new();
+    let mut tools_lib: Option<String> = None;
+    let mut session: Option<String> = None;
+    let mut allow_shell = false;
+    let mut i = 0;
+    while i < args.len() {
+        let flag = args[i].as_str();
+        if matches!(flag, "--model" | "--system" | "--tools" | "--load") && i + 1 >= args.len() {
+            bail!("{} requires a value", flag);
+        }
+        match flag {
+            "--model" => model = args[i + 1].clone(),
+            "--system" => {
+                system = std::fs::read_to_string(&args[i + 1])
+                    .map_err(|e| anyhow::anyhow!("cannot read '{}': {}", args[i + 1], e))?
+                    .trim_end().to_string();
+            }
+            "--tools" => tools_lib = Some(args[i + 1].clone()),
+            "--load" => session = Some(args[i + 1].clone()),
+            "--allow-shell" => {
+                allow_shell = true;
+                i += 1;
+                continue;
+            }
+            other => bail!("Unknown chat flag: {}", other),
+        }
+        i += 2;
+    }
+
+    let mut interp = Interpreter::with_options(allow_shell);
+    let mut tools = Vec::new();
+    if let Some(ref lib) = tools_lib {
+        interp.import_library(lib)?;
+        tools = interp.flow_names();
+    }
+    let color = crate::diagnostics::enabled(Stream::Stdout);
+    let mut chat = Chat { interp, model, system, tools, conversation: Vec::new(), color };
+    if let Some(ref path) = session {
+        chat.load(path)?;
+    }
+
+    if !crate::diagnostics::quiet() {
+        eprintln!("cognos chat — {}{}. /help for commands, /exit to quit.", chat.model,
+            if chat.tools.is_empty() { String::new() } else { format!(", tools: {}", chat.tools.join(", ")) });
+    }
+    let stdin = std::io::stdin();
+    loop {
+        crate::diagnostics::prompt("> ");
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            // End the prompt line so the shell's own prompt starts clean
+            crate::diagnostics::prompt("\n");
+            break;
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(command) = line.strip_prefix('/') {
+            match chat.command(command) {
+                Ok(true) => continue,
+                Ok(false) => break,
+                Err(e) => {
+                    eprintln!("{}: {}", paint(Stream::Stderr, Style::Error, "Error"), e);
+                    continue;
+                }
+            }
+        }
+        match chat.send(line) {
+            Ok(reply) => println!("{}\n", render_markdown(&reply, chat.color)),
+            Err(e) => eprintln!("{}: {}", paint(Stream::Stderr, Style::Error, "Error"), e),
+        }
+    }
+    Ok(())
+}
+
+impl Chat {
+    /// Run a slash command. `false` means quit.
+    fn command(&mut self, command: &str) -> Result<bool> {
+        let (name, arg) = command.split_once(' ').map_or((command, ""), |(n, a)| (n, a.trim()));
+        match name {
+            "exit" | "quit" => return Ok(false),
+            "help" => eprintln!("/save <file>   save the conversation\n\
+                /load <file>   continue a saved conversation\n\
+                /history       show the conversation so far\n\
+                /clear         start over\n\
+                /model [name]  show or switch the model\n\
+                /tools         list available tools\n\
+                /exit          quit"),
+            "save" if !arg.is_empty() => {
+                self.save(arg)?;
+                eprintln!("Saved {} messages to {}", self.conversation.len(), arg);
+            }
+            "load" if !arg.is_empty() => {
+                self.load(arg)?;
+                eprintln!("Loaded {} messages from {}", self.conversation.len(), arg);
+            }
+            "save" | "load" => bail!("usage: /{} <file>", name),
+            "history" => {
+                for msg in &self.conversation {
+                    let role = msg.get_field("role").map(|v| v.to_string()).unwrap_or_default();
+                    let content = msg.get_field("content").map(|v| v.to_string()).unwrap_or_default();
+                    if !content.is_empty() {
+                        println!("{}{}:{} {}", if self.color { BOLD } else { "" }, role, if self.color { RESET } else { "" }, content);
+                    }
+                }
+            }
+            "clear" => {
+                self.conversation.clear();
+                eprintln!("Conversation cleared");
+            }
+            "model" if arg.is_empty() => eprintln!("{}", self.model),
+            "model" => {
+                self.model = arg.to_string();
+                eprintln!("Model: {}", self.model);
+            }
+            "tools" if self.tools.is_empty() => eprintln!("No tools. Start with: cognos chat --tools lib.cog"),
+            "tools" => eprintln!("{}", self.tools.join(", ")),
+            other => bail!("unknown command '/{}' — try /help", other),
+        }
+        Ok(true)
+    }
+
+    /// One user message, plus as many tool rounds as the model asks for.
+    fn send(&mut self, message: &str) -> Result<String> {
+        let mut reply = self.interp.think_turn(&self.model, &self.system, message, &self.tools, &self.conversation, None)?;
+        let mut conversation = conversation_of(&reply);
+        for _ in 0..MAX_TOOL_ROUNDS {
+            let Some(Value::List(calls)) = reply.get_field("tool_calls").cloned() else { break };
+            if calls.is_empty() || !matches!(reply.get_field("has_tool_calls"), Some(Value::Bool(true))) {
+                break;
+            }
+            let mut results = Vec::new();
+            for call in calls {
+                let name = call.get_field("name").map(|v| v.to_string()).unwrap_or_default();
+                let arguments = call.get_field("arguments").cloned().unwrap_or(Value::None);
+                let id = call.get_field("id").map(|v| v.to_string()).unwrap_or_else(|| name.clone());
+                eprintln!("{}", paint(Stream::Stderr, Style::Dim, &format!("  → {}({})", name, arguments.to_json())));
+                let content = match self.interp.call_tool(&name, arguments) {
+                    Ok(value) => value.to_string(),
+                    Err(e) => format!("Error: {}", e),
+                };
+                results.push(Value::Map(Entries::from([
+                    ("tool_use_id".to_string(), Value::String(id)),
+                    ("content".to_string(), Value::String(content)),
+                ])));
+            }
+            reply = self.interp.think_turn(&self.model, &self.system, "", &self.tools, &conversation, Some(results))?;
+            conversation = conversation_of(&reply);
+        }
+        self.conversation = conversation;
+        Ok(reply.get_field("content").map(|v| v.to_string()).unwrap_or_default())
+    }
+
+    fn save(&self, path: &str) -> Result<()> {
+        let session = serde_json::json!({
+            "model": self.model,
+            "system": self.system,
+            "conversation": Value::List(self.conversation.clone()).to_json(),
+        });
+        std::fs::write(path, serde_json::to_string_pretty(&session)?)
+            .map_err(|e| anyhow::anyhow!("cannot write '{}': {}", path, e))
+    }
+
+    fn load(&mut self, path: &str) -> Result<()> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("cannot load session '{}': {}", path, e))?;
+        let session: serde_json::Value = serde_json::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("{} is not a chat session: {}", path, e))?;
+        let Value::List(conversation) = Value::from_json(session["conversation"].clone()) else {
+            bail!("{} is not a chat session: no conversation list", path);
+        };
+        self.conversation = conversation;
+        if let Some(model) = session["model"].as_str() {
+            self.model = model.to_string();
+        }
+        if let Some(system) = session["system"].as_str() {
+            self.system = system.to_string();
+        }
+        Ok(())
+    }
+}
+
+fn conversation_of(reply: &Value) -> Vec<Value> {
+    match reply.get_field("conversation") {
+        Some(Value::List(items)) => items.clone(),
+        _ => Vec::new(),
+    }
+}
+
+/// Light terminal rendering of the markdown models tend to write: headings
+/// and **bold** in bold, `code` and fenced blocks in cyan, bullets as •.
+fn render_markdown(text: &str, color: bool) -> String {
+    if !color {
+        return text.to_string();
+    }
+    let mut out = Vec::new();
+    let mut in_fence = false;
+    for line in text.lines() {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") {
+            in_fence = !in_fence;
+            out.push(format!("{}{}{}", DIM, line, RESET));
+        } else if in_fence {
+            out.push(format!("{}{}{}", CYAN, line, RESET));
+        } else if let Some(heading) = trimmed.strip_prefix('#') {
+            out.push(format!("{}{}{}", BOLD, heading.trim_start_matches('#').trim(), RESET));
+        } else if let Some(item) = trimmed.strip_prefix("- ").or_else(|| trimmed.strip_prefix("* ")) {
+            let indent = &line[..line.len() - trimmed.len()];
+            out.push(format!("{}• {}", indent, render_inline(item)));
+        } else {
+            out.push(render_inline(line));
+        }
+    }
+    out.join("\n")
+}
+
+/// `**bold**` and `` `code` `` within one line.
+fn render_inline(line: &str) -> String {
+    let mut out = String::new();
+    let (mut bold, mut code) = (false, false);
+    let mut rest = line;
+    while let Some(c) = rest.chars().next() {
+        if c == '`' {
+            code = !code;
+            out.push_str(if code { CYAN } else { RESET });
+            if !code && bold {
+                out.push_str(BOLD);
+            }
+            rest = &rest[1..];
+        } else if !code && rest.starts_with("**") {
+            bold = !bold;
+            out.push_str(if bold { BOLD } else { RESET });
+            rest = &rest[2..];
+        } else {
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    if bold || code {
+        out.push_str(RESET);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_markdown() {
+        let md = "# Title\n- **one** and `x`\n```\nlet a = 1;\n```";
+        assert_eq!(render_markdown(md, false), md);
+        let out = render_markdown(md, true);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], "\x1b[1mTitle\x1b[0m");
+        assert_eq!(lines[1], "• \x1b[1mone\x1b[0m and \x1b[36mx\x1b[0m");
+        assert_eq!(lines[3], "\x1b[36mlet a = 1;\x1b[0m");
+    }
+}
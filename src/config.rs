@@ -0,0 +1,402 @@
+//! `cognos.toml` profiles: named bundles of flags and settings, chosen with
+//! `--profile <name>` (or `COGNOS_PROFILE`), so one program can run against
+//! a local model in development and a hosted one in production.
+//!
+//! ```toml
+//! [profile.dev]
+//! model = "qwen2.5:7b"
+//! allow_shell = true
+//!
+//! [profile.prod]
+//! model = "claude-sonnet-4-20250514"
+//! trace = "logs/trace.jsonl"
+//! session = "state.json"
+//! autosave = "1m"
+//! ```
+//!
+//! `claude_path`, `claude_args` and `claude_session` configure the `claude`
+//! CLI that Claude models fall back to without an API key.
+//!
+//! Flags on the command line win over the profile, and so do environment
+//! variables already set. Without `--profile`, a `[profile.default]` applies
+//! if there is one.
+//!
+//! `[flows."<name or glob>"]` tables (top level, or inside a profile to
+//! override them there) pin the model and temperature of the LLM calls a
+//! flow makes, without touching the `.cog` source:
+//!
+//! ```toml
+//! [flows."summarize_*"]
+//! model = "qwen2.5:7b"
+//! temperature = 0.2
+//! ```
+//!
+//! `[providers.<name>]` tables (top level, or inside a profile) route model
+//! names to a server of your own, ahead of the built-in routing by prefix:
+//!
+//! ```toml
+//! [providers.openrouter]
+//! endpoint = "https://openrouter.ai/api/v1"
+//! api_key_env = "OPENROUTER_API_KEY"
+//! models = ["meta-llama/*", "mistralai/*"]
+//! default_model = "meta-llama/llama-3.1-70b-instruct"   # for model="openrouter"
+//! timeout = 300
+//! ```
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+pub const FILE_NAME: &str = "cognos.toml";
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    profile: BTreeMap<String, Profile>,
+    #[serde(default)]
+    flows: BTreeMap<String, FlowOverride>,
+    #[serde(default)]
+    providers: BTreeMap<String, ProviderConfig>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// Default for `think()` and friends (`COGNOS_MODEL`).
+    pub model: Option<String>,
+    pub ollama_url: Option<String>,
+    /// `COGNOS_LOG` level: info, debug or trace.
+    pub log: Option<String>,
+    pub allow_shell: Option<bool>,
+    /// Let `define_flow()` add flows at run time.
+    pub allow_dynamic: Option<bool>,
+    pub trace: Option<String>,
+    pub trace_level: Option<String>,
+    pub trace_max_mb: Option<f64>,
+    pub trace_max_payload: Option<usize>,
+    pub trace_sample: Option<u32>,
+    pub max_string_mb: Option<f64>,
+    pub max_list_len: Option<usize>,
+    pub max_depth: Option<usize>,
+    pub session: Option<String>,
+    pub autosave: Option<String>,
+    /// Mock environment for `cognos test`.
+    pub env: Option<String>,
+    pub warmup: Option<String>,
+    pub keep_alive: Option<String>,
+    pub num_ctx: Option<i64>,
+    /// The `claude` CLI binary (`COGNOS_CLAUDE_PATH`).
+    pub claude_path: Option<String>,
+    /// Extra arguments for every `claude` run (`COGNOS_CLAUDE_ARGS`).
+    pub claude_args: Option<String>,
+    /// Continue one `claude` session across calls (`COGNOS_CLAUDE_SESSION`).
+    pub claude_session: Option<bool>,
+    /// Native plugins to load, as with `--plugin`.
+    #[serde(default)]
+    pub plugins: Vec<String>,
+    /// Per-flow overrides, on top of the file's top-level `[flows]`.
+    #[serde(default)]
+    pub flows: BTreeMap<String, FlowOverride>,
+    /// Model servers, on top of the file's top-level `[providers]`.
+    #[serde(default)]
+    pub providers: BTreeMap<String, ProviderConfig>,
+}
+
+/// What `cognos.toml` changes about the LLM calls of matching flows.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FlowOverride {
+    /// Used instead of the model the call asks for.
+    pub model: Option<String>,
+    /// Used when the call doesn't pass `temperature=` itself.
+    pub temperature: Option<f64>,
+}
+
+/// A model server declared in `cognos.toml`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ProviderConfig {
+    /// Base URL, e.g. `http://localhost:8000/v1` (the Ollama server's root
+    /// for `api = "ollama"`).
+    pub endpoint: String,
+    /// Wire format: "openai" (chat completions, the default) or "ollama".
+    pub api: Option<String>,
+    /// Env var (or .env / credentials entry) holding the API key; without
+    /// one, no key is sent.
+    pub api_key_env: Option<String>,
+    /// Model names or glob patterns served here.
+    #[serde(default)]
+    pub models: Vec<String>,
+    /// The model sent for `model="<provider name>"`.
+    pub default_model: Option<String>,
+    /// Request timeout in seconds, instead of `COGNOS_LLM_TIMEOUT`.
+    pub timeout: Option<u64>,
+}
+
+pub const PROVIDER_APIS: &[&str] = &["openai", "ollama"];
+
+/// Providers by name, as `cognos.toml` declares them.
+#[derive(Debug, Default)]
+pub struct Providers(BTreeMap<String, ProviderConfig>);
+
+impl Providers {
+    /// The provider serving `model`: one named by it, then an exact entry
+    /// in `models`, then the longest matching pattern.
+    pub fn get(&self, model: &str) -> Option<(&str, &ProviderConfig)> {
+        let named = self.0.get_key_value(model);
+        let listed = || self.0.iter().find(|(_, p)| p.models.iter().any(|m| m == model));
+        let matched = || self.0.iter()
+            .flat_map(|(name, p)| p.models.iter().filter(|m| glob_match(m, model)).map(move |m| (m.len(), name, p)))
+            .max_by_key(|(len, _, _)| *len)
+            .map(|(_, name, p)| (name, p));
+        named.or_else(listed).or_else(matched).map(|(name, p)| (name.as_str(), p))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &ProviderConfig)> {
+        self.0.iter()
+    }
+}
+
+/// Flow overrides by flow name or glob pattern (`*` and `?`).
+#[derive(Debug, Default)]
+pub struct FlowOverrides(BTreeMap<String, FlowOverride>);
+
+impl FlowOverrides {
+    /// The override for `flow`: an exact name beats a pattern, and a longer
+    /// pattern beats a shorter one.
+    pub fn get(&self, flow: &str) -> Option<&FlowOverride> {
+        self.0.get(flow).or_else(|| self.0.iter()
+            .filter(|(pattern, _)| glob_match(pattern, flow))
+            .max_by_key(|(pattern, _)| pattern.len())
+            .map(|(_, o)| o))
+    }
+}
+
+impl Profile {
+    /// Environment variables the profile sets, for those not already set.
+    pub fn apply_env(&self) {
+        let session = self.claude_session.map(|on| if on { "1" } else { "0" }.to_string());
+        for (key, value) in [
+            ("COGNOS_MODEL", &self.model), ("OLLAMA_URL", &self.ollama_url), ("COGNOS_LOG", &self.log),
+            ("COGNOS_CLAUDE_PATH", &self.claude_path), ("COGNOS_CLAUDE_ARGS", &self.claude_args), ("COGNOS_CLAUDE_SESSION", &session),
+        ] {
+            if let Some(value) = value {
+                if std::env::var_os(key).is_none() {
+                    std::env::set_var(key, value);
+                }
+            }
+        }
+    }
+
+    /// The profile's run flags, to go in front of the command line's own so
+    /// that those override them.
+    pub fn flags(&self) -> Vec<String> {
+        let mut flags = Vec::new();
+        if self.allow_shell == Some(true) {
+            flags.push("--allow-shell".to_string());
+        }
+        if self.allow_dynamic == Some(true) {
+            flags.push("--allow-dynamic".to_string());
+        }
+        let valued = [
+            ("--trace", self.trace.clone()),
+            ("--trace-level", self.trace_level.clone()),
+            ("--trace-max-mb", self.trace_max_mb.map(|n| n.to_string())),
+            ("--trace-max-payload", self.trace_max_payload.map(|n| n.to_string())),
+            ("--trace-sample", self.trace_sample.map(|n| n.to_string())),
+            ("--max-string-mb", self.max_string_mb.map(|n| n.to_string())),
+            ("--max-list-len", self.max_list_len.map(|n| n.to_string())),
+            ("--max-depth", self.max_depth.map(|n| n.to_string())),
+            ("--session", self.session.clone()),
+            ("--autosave", self.autosave.clone()),
+            ("--env", self.env.clone()),
+            ("--warmup", self.warmup.clone()),
+            ("--keep-alive", self.keep_alive.clone()),
+            ("--num-ctx", self.num_ctx.map(|n| n.to_string())),
+        ];
+        for (flag, value) in valued {
+            if let Some(value) = value {
+                flags.push(flag.to_string());
+                flags.push(value);
+            }
+        }
+        for plugin in &self.plugins {
+            flags.push("--plugin".to_string());
+            flags.push(plugin.clone());
+        }
+        flags
+    }
+
+    pub fn flow_overrides(&self) -> FlowOverrides {
+        FlowOverrides(self.flows.clone())
+    }
+
+    pub fn providers(&self) -> Providers {
+        Providers(self.providers.clone())
+    }
+}
+
+/// `*` matches any run of characters, `?` any one.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let (p, n): (Vec<char>, Vec<char>) = (pattern.chars().collect(), name.chars().collect());
+    // Position after the last `*`, and where in `name` it resumes
+    let (mut pi, mut ni, mut star) = (0, 0, None);
+    while ni < n.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == n[ni]) {
+            pi += 1;
+            ni += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi + 1, ni));
+            pi += 1;
+        } else if let Some((sp, sn)) = star {
+            pi = sp;
+            ni = sn + 1;
+            star = Some((sp, sn + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+/// `cognos.toml` in the current directory or the nearest parent.
+fn find() -> Option<PathBuf> {
+    let dir = std::env::current_dir().ok()?;
+    dir.ancestors().map(|d| d.join(FILE_NAME)).find(|p| p.is_file())
+}
+
+/// The profile to run with: `name` if given (an error if it doesn't exist),
+/// otherwise `default` when the file has one. Top-level `[flows]` and
+/// `[providers]` are merged into it, so a file with only those still yields
+/// a profile.
+pub fn load_profile(name: Option<&str>) -> Result<Option<Profile>> {
+    let Some(path) = find() else {
+        if let Some(name) = name {
+            bail!("profile '{}' requested but no {} found here or in a parent directory", name, FILE_NAME);
+        }
+        return Ok(None);
+    };
+    let ConfigFile { mut profile, flows, providers } = parse(&path)?;
+    let selected = match name {
+        Some(name) => match profile.remove(name) {
+            Some(profile) => Some(profile),
+            None if profile.is_empty() => bail!("{} has no profiles; add a [profile.{}] section", path.display(), name),
+            None => bail!("{} has no profile '{}' (available: {})", path.display(), name,
+                profile.keys().cloned().collect::<Vec<_>>().join(", ")),
+        },
+        None => profile.remove("default"),
+    };
+    if flows.is_empty() && providers.is_empty() {
+        return Ok(selected);
+    }
+    let mut selected = selected.unwrap_or_default();
+    for (pattern, flow) in flows {
+        selected.flows.entry(pattern).or_insert(flow);
+    }
+    for (name, provider) in providers {
+        selected.providers.entry(name).or_insert(provider);
+    }
+    Ok(Some(selected))
+}
+
+fn parse(path: &Path) -> Result<ConfigFile> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("cannot read {}: {}", path.display(), e))?;
+    let config: ConfigFile = toml::from_str(&content)
+        .map_err(|e| anyhow::anyhow!("invalid {}: {}", path.display(), e))?;
+    let all_flows = config.flows.iter().chain(config.profile.values().flat_map(|p| &p.flows));
+    for (pattern, flow) in all_flows {
+        if let Some(t) = flow.temperature.filter(|t| !(0.0..=2.0).contains(t)) {
+            bail!("invalid {}: flows.\"{}\": temperature must be between 0 and 2, got {}", path.display(), pattern, t);
+        }
+    }
+    let all_providers = config.providers.iter().chain(config.profile.values().flat_map(|p| &p.providers));
+    for (name, provider) in all_providers {
+        if !provider.endpoint.starts_with("http://") && !provider.endpoint.starts_with("https://") {
+            bail!("invalid {}: providers.{}: endpoint must be an http(s) URL, got \"{}\"", path.display(), name, provider.endpoint);
+        }
+        if let Some(api) = provider.api.as_deref().filter(|api| !PROVIDER_APIS.contains(api)) {
+            bail!("invalid {}: providers.{}: api must be one of {}, got \"{}\"", path.display(), name, PROVIDER_APIS.join(", "), api);
+        }
+        if provider.timeout == Some(0) {
+            bail!("invalid {}: providers.{}: timeout must be a positive number of seconds", path.display(), name);
+        }
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_flags_and_unknown_keys() {
+        let config: ConfigFile = toml::from_str(r#"
+[profile.prod]
+model = "claude-sonnet-4-20250514"
+allow_shell = true
+trace = "trace.jsonl"
+trace_max_mb = 50
+num_ctx = 8192
+claude_path = "/opt/claude/bin/claude"
+claude_session = true
+"#).unwrap();
+        let prod = &config.profile["prod"];
+        assert_eq!(prod.model.as_deref(), Some("claude-sonnet-4-20250514"));
+        assert_eq!(prod.claude_path.as_deref(), Some("/opt/claude/bin/claude"));
+        assert_eq!(prod.claude_session, Some(true));
+        assert_eq!(prod.flags(), ["--allow-shell", "--trace", "trace.jsonl", "--trace-max-mb", "50", "--num-ctx", "8192"]);
+
+        let err = toml::from_str::<ConfigFile>("[profile.prod]\npolicy = \"policy.cog\"\n").err().unwrap();
+        assert!(err.to_string().contains("unknown field `policy`"), "got: {}", err);
+    }
+
+    #[test]
+    fn test_flow_overrides_match_exact_then_longest_glob() {
+        let config: ConfigFile = toml::from_str(r#"
+[flows."*"]
+temperature = 0.7
+
+[flows."summarize_*"]
+model = "qwen2.5:7b"
+
+[flows.summarize_report]
+model = "claude-sonnet-4-20250514"
+"#).unwrap();
+        let overrides = FlowOverrides(config.flows);
+        assert_eq!(overrides.get("summarize_report").unwrap().model.as_deref(), Some("claude-sonnet-4-20250514"));
+        assert_eq!(overrides.get("summarize_notes").unwrap().model.as_deref(), Some("qwen2.5:7b"));
+        assert_eq!(overrides.get("main").unwrap().temperature, Some(0.7));
+        assert!(FlowOverrides::default().get("main").is_none());
+
+        assert!(glob_match("a?c*", "abcdef"));
+        assert!(glob_match("*_step_*", "plan_step_2"));
+        assert!(!glob_match("a*c", "abd"));
+    }
+
+    #[test]
+    fn test_providers_match_name_then_listed_then_longest_glob() {
+        let config: ConfigFile = toml::from_str(r#"
+[providers.openrouter]
+endpoint = "https://openrouter.ai/api/v1"
+api_key_env = "OPENROUTER_API_KEY"
+models = ["*/*"]
+
+[providers.vllm]
+endpoint = "http://gpu-box:8000/v1"
+models = ["meta-llama/*", "qwen-coder"]
+default_model = "meta-llama/Llama-3.1-8B-Instruct"
+timeout = 600
+"#).unwrap();
+        let providers = Providers(config.providers);
+        let name = |model| providers.get(model).map(|(name, _)| name);
+        assert_eq!(name("vllm"), Some("vllm"));
+        assert_eq!(name("qwen-coder"), Some("vllm"));
+        assert_eq!(name("meta-llama/Llama-3.1-8B-Instruct"), Some("vllm"));
+        assert_eq!(name("mistralai/mixtral-8x7b"), Some("openrouter"));
+        assert_eq!(name("gpt-4o"), None);
+        assert_eq!(providers.get("vllm").unwrap().1.timeout, Some(600));
+    }
+}
@@ -0,0 +1,149 @@
+//! Styled CLI output: red errors with a code frame, yellow warnings, green
+//! passes. Color is only used when the stream is a terminal, `NO_COLOR` is
+//! unset and `--no-color` wasn't given, so piped output stays plain.
+//! `-q/--quiet` silences `log()` output and input prompts.
+
+use crate::error::CognosError;
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static DISABLED: AtomicBool = AtomicBool::new(false);
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Clone, Copy)]
+pub enum Style {
+    Error,
+    Warning,
+    Success,
+    Hint,
+    Dim,
+    /// String values in pretty-printed output
+    Literal,
+    /// Numbers, booleans and none in pretty-printed output
+    Number,
+}
+
+/// Turn color off for the rest of the process (`--no-color`).
+pub fn disable() {
+    DISABLED.store(true, Ordering::Relaxed);
+}
+
+/// Drop informational output for the rest of the process (`-q/--quiet`).
+pub fn set_quiet() {
+    QUIET.store(true, Ordering::Relaxed);
+}
+
+pub fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Show an input prompt on stderr, but only to a person at a terminal:
+/// piped input gets no prompt, so nothing leaks into captured output.
+pub fn prompt(text: &str) {
+    if !quiet() && std::io::stdin().is_terminal() {
+        use std::io::Write;
+        eprint!("{}", text);
+        let _ = std::io::stderr().flush();
+    }
+}
+
+pub fn enabled(stream: Stream) -> bool {
+    let tty = match stream {
+        Stream::Stdout => std::io::stdout().is_terminal(),
+        Stream::Stderr => std::io::stderr().is_terminal(),
+    };
+    tty && !DISABLED.load(Ordering::Relaxed) && std::env::var_os("NO_COLOR").is_none()
+}
+
+/// `text` in `style`, if `stream` gets color.
+pub fn paint(stream: Stream, style: Style, text: &str) -> String {
+    if !enabled(stream) {
+        return text.to_string();
+    }
+    let code = match style {
+        Style::Error => "1;31",
+        Style::Warning => "1;33",
+        Style::Success => "1;32",
+        Style::Hint => "36",
+        Style::Dim => "2",
+        Style::Literal => "32",
+        Style::Number => "33",
+    };
+    format!("\x1b[{}m{}\x1b[0m", code, text)
+}
+
+/// `Warning: <message>` on stderr.
+pub fn warning(message: impl std::fmt::Display) {
+    eprintln!("{}: {}", paint(Stream::Stderr, Style::Warning, "Warning"), message);
+}
+
+/// `<label>: <error>` on stderr. Errors that carry a line number get the
+/// offending line of `source` (path, text) shown under the message.
+pub fn error(label: &str, err: &anyhow::Error, source: Option<(&str, &str)>) {
+    let label = paint(Stream::Stderr, Style::Error, label);
+    let Some(e) = err.downcast_ref::<CognosError>().filter(|e| e.line > 0) else {
+        eprintln!("{}: {}", label, err);
+        return;
+    };
+    eprintln!("{}: line {}: {}", label, e.line, e.message);
+    if let Some((path, text)) = source {
+        eprint!("{}", code_frame(path, text, e.line, None));
+    }
+    if let Some(ref hint) = e.hint {
+        eprintln!("  {}: {}", paint(Stream::Stderr, Style::Hint, "hint"), hint);
+    }
+}
+
+/// `<label>: <error>` on stderr for an error raised at `line`:`col` of
+/// `path`, with that line of `text` and a caret under the column. Without
+/// the text (a file that can't be read), just the position.
+pub fn error_at(label: &str, err: &anyhow::Error, path: &str, text: Option<&str>, line: usize, col: usize) {
+    eprintln!("{}: {}", paint(Stream::Stderr, Style::Error, label), err);
+    match text.map(|text| code_frame(path, text, line, Some(col))).filter(|frame| !frame.is_empty()) {
+        Some(frame) => eprint!("{}", frame),
+        None => eprintln!("{:>width$}--> {}:{}:{}", "", path, line, col, width = line.to_string().len()),
+    }
+}
+
+/// The line `line` (1-based) of `text`, with a gutter and file reference,
+/// and a caret under column `col` when given.
+fn code_frame(path: &str, text: &str, line: usize, col: Option<usize>) -> String {
+    let Some(code) = text.lines().nth(line - 1) else { return String::new() };
+    let width = line.to_string().len();
+    let gutter = paint(Stream::Stderr, Style::Dim, &format!("{:>width$} |", ""));
+    let (position, marker) = match col {
+        Some(col) => (format!("{}:{}", line, col),
+            format!("{}{}", " ".repeat(col), paint(Stream::Stderr, Style::Error, "^"))),
+        None => (line.to_string(), String::new()),
+    };
+    format!("{:>width$}--> {}:{}\n{}\n{} {}\n{}{}\n",
+        "", path, position,
+        gutter,
+        paint(Stream::Stderr, Style::Dim, &format!("{} |", line)), code,
+        gutter, marker)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_code_frame_shows_the_line() {
+        // Test output isn't a terminal, so no escape codes
+        let frame = code_frame("app.cog", "flow main():\n    x = )\n", 2, None);
+        assert_eq!(frame, " --> app.cog:2\n  |\n2 |     x = )\n  |\n");
+        assert_eq!(code_frame("app.cog", "one line", 9, None), "");
+    }
+
+    #[test]
+    fn test_code_frame_marks_the_column() {
+        let frame = code_frame("app.cog", "flow main():\n    print(x)\n", 2, Some(5));
+        assert_eq!(frame, " --> app.cog:2:5\n  |\n2 |     print(x)\n  |     ^\n");
+    }
+}
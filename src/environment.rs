@@ -1,17 +1,35 @@
-/// Environment trait — abstracts all I/O the interpreter needs.
-/// RealEnv talks to the OS. MockEnv returns canned responses.
+//! Environment trait — abstracts all I/O the interpreter needs.
+//! RealEnv talks to the OS. MockEnv returns canned responses.
 
 use anyhow::Result;
+use crate::http_client::Service;
 
 pub trait Env {
     fn read_stdin(&mut self) -> Result<String>;
     fn write_stdout(&mut self, content: &str) -> Result<()>;
+    /// Write part of a line to stdout, e.g. a piece of a streamed reply.
+    fn stream_stdout(&mut self, content: &str) -> Result<()>;
+    /// Show a line from `print()`, `emit()` or `help()`. It goes to the
+    /// terminal even under a mock, which only captures `write(stdout)`.
+    fn print(&mut self, content: &str) -> Result<()> {
+        println!("{}", content);
+        Ok(())
+    }
     fn read_file(&self, path: &str) -> Result<String>;
     fn write_file(&mut self, path: &str, content: &str) -> Result<()>;
+    fn append_file(&mut self, path: &str, content: &str) -> Result<()>;
     fn exec_shell(&mut self, command: &str) -> Result<ShellResult>;
     fn call_llm(&mut self, request: LlmRequest) -> Result<LlmResponse>;
     fn http_get(&self, url: &str) -> Result<String>;
     fn http_post(&self, url: &str, body: &str) -> Result<String>;
+    fn grpc_call(&self, call: &crate::grpc::Call) -> Result<serde_json::Value>;
+    /// Capture the screen as a PNG at `path`, returning where the image is:
+    /// `path`, or for a mock its next canned image.
+    fn screenshot(&mut self, path: &str) -> Result<String>;
+    /// Width and height of the main screen, in pixels.
+    fn screen_size(&self) -> Result<(u32, u32)>;
+    /// Upload a file to OpenAI for `purpose`, returning its file id.
+    fn upload_file(&mut self, path: &str, purpose: &str) -> Result<String>;
 
     fn allow_shell(&self) -> bool;
 
@@ -27,6 +45,7 @@ pub struct ShellResult {
     pub exit_code: i32,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct LlmRequest {
     pub model: String,
@@ -41,18 +60,104 @@ pub struct LlmRequest {
 pub struct LlmResponse {
     pub content: String,
     pub tool_calls: Option<Vec<serde_json::Value>>,
+    /// As a provider would report it ("length" for a cut-off reply).
+    pub finish_reason: Option<String>,
     pub raw_json: Option<serde_json::Value>,
+    /// Fail the call with this message instead, as a flaky provider would;
+    /// `raw_json` may say how (`"status"`, `"transient"`).
+    pub error: Option<String>,
+}
+
+// ─── Shell and paths ───
+
+/// The shell `__exec_shell__` hands command lines to.
+#[derive(Debug, PartialEq)]
+pub struct Shell {
+    pub program: String,
+    /// Flags before the command line: `-c`, `/C`, `-Command`.
+    pub args: Vec<&'static str>,
+}
+
+impl Shell {
+    /// `COGNOS_SHELL` if set. Otherwise `sh`; on Windows, `sh` only when one
+    /// is on PATH (Git Bash, MSYS) and PowerShell when not.
+    pub fn detect() -> Shell {
+        let chosen = std::env::var("COGNOS_SHELL").ok().filter(|s| !s.is_empty());
+        Shell::choose(chosen.as_deref(), cfg!(windows), || on_path("sh"))
+    }
+
+    fn choose(chosen: Option<&str>, windows: bool, has_sh: impl FnOnce() -> bool) -> Shell {
+        let program = match chosen {
+            Some(program) => program.to_string(),
+            None if !windows || has_sh() => "sh".to_string(),
+            None => "powershell".to_string(),
+        };
+        let file = program.rsplit(['/', '\\']).next().unwrap_or_default().to_lowercase();
+        let args = match file.strip_suffix(".exe").unwrap_or(&file) {
+            "cmd" => vec!["/C"],
+            "powershell" | "pwsh" => vec!["-NoProfile", "-NonInteractive", "-Command"],
+            _ => vec!["-c"],
+        };
+        Shell { program, args }
+    }
+
+    pub fn command(&self, line: &str) -> std::process::Command {
+        let mut command = std::process::Command::new(&self.program);
+        command.args(&self.args);
+        // cmd.exe does its own parsing; Rust's argument quoting would break it
+        #[cfg(windows)]
+        if self.args == ["/C"] {
+            use std::os::windows::process::CommandExt;
+            command.raw_arg(line);
+            return command;
+        }
+        command.arg(line);
+        command
+    }
+}
+
+pub fn on_path(program: &str) -> bool {
+    let suffixes: &[&str] = if cfg!(windows) { &[".exe", ""] } else { &[""] };
+    std::env::var_os("PATH").is_some_and(|paths| {
+        std::env::split_paths(&paths)
+            .any(|dir| suffixes.iter().any(|suffix| dir.join(format!("{}{}", program, suffix)).is_file()))
+    })
+}
+
+/// `path` with `/` and `\` both read as separators, so a program written on
+/// one OS finds its files on another.
+pub fn native_path(path: &str) -> std::path::PathBuf {
+    if cfg!(windows) {
+        path.replace('/', "\\").into()
+    } else {
+        path.replace('\\', "/").into()
+    }
+}
+
+/// `err` with `what` in front of its message, still an `io::Error` so
+/// `catch IOError` takes it.
+pub fn io_error(what: String, err: std::io::Error) -> anyhow::Error {
+    anyhow::Error::new(std::io::Error::new(err.kind(), format!("{}: {}", what, err)))
+}
+
+/// A canonicalized path for messages, without Windows' `\\?\` prefix.
+pub fn display_path(path: &std::path::Path) -> String {
+    let shown = path.display().to_string();
+    shown.strip_prefix(r"\\?\").map(String::from).unwrap_or(shown)
 }
 
 // ─── RealEnv ───
 
 pub struct RealEnv {
     pub allow_shell: bool,
+    /// stdin and stdout carry a protocol (`cognos serve-mcp`), so the
+    /// program's output goes to stderr and it can't read stdin.
+    pub stdio_reserved: bool,
 }
 
 impl RealEnv {
     pub fn new(allow_shell: bool) -> Self {
-        Self { allow_shell }
+        Self { allow_shell, stdio_reserved: false }
     }
 }
 
@@ -60,6 +165,9 @@ impl Env for RealEnv {
     fn is_mock(&self) -> bool { false }
     fn read_stdin(&mut self) -> Result<String> {
         use std::io::BufRead;
+        if self.stdio_reserved {
+            anyhow::bail!("stdin carries the MCP protocol here, so read() from it isn't available");
+        }
         let mut line = String::new();
         let bytes = std::io::stdin().lock().read_line(&mut line)?;
         if bytes == 0 { anyhow::bail!("end of input (EOF)"); }
@@ -67,25 +175,50 @@ impl Env for RealEnv {
     }
 
     fn write_stdout(&mut self, content: &str) -> Result<()> {
-        println!("{}", content);
+        if self.stdio_reserved {
+            eprintln!("{}", content);
+        } else {
+            println!("{}", content);
+        }
+        Ok(())
+    }
+
+    fn print(&mut self, content: &str) -> Result<()> {
+        self.write_stdout(content)
+    }
+
+    fn stream_stdout(&mut self, content: &str) -> Result<()> {
+        use std::io::Write;
+        if self.stdio_reserved {
+            eprint!("{}", content);
+            return Ok(());
+        }
+        print!("{}", content);
+        let _ = std::io::stdout().flush();
         Ok(())
     }
 
     fn read_file(&self, path: &str) -> Result<String> {
-        std::fs::read_to_string(path)
-            .map_err(|e| anyhow::anyhow!("cannot read '{}': {}", path, e))
+        std::fs::read_to_string(native_path(path))
+            .map_err(|e| io_error(format!("cannot read '{}'", path), e))
     }
 
     fn write_file(&mut self, path: &str, content: &str) -> Result<()> {
-        std::fs::write(path, content)
-            .map_err(|e| anyhow::anyhow!("cannot write '{}': {}", path, e))
+        std::fs::write(native_path(path), content)
+            .map_err(|e| io_error(format!("cannot write '{}'", path), e))
+    }
+
+    fn append_file(&mut self, path: &str, content: &str) -> Result<()> {
+        use std::io::Write;
+        std::fs::OpenOptions::new().create(true).append(true).open(native_path(path))
+            .and_then(|mut file| file.write_all(content.as_bytes()))
+            .map_err(|e| io_error(format!("cannot write '{}'", path), e))
     }
 
     fn exec_shell(&mut self, command: &str) -> Result<ShellResult> {
-        let output = std::process::Command::new("sh")
-            .arg("-c")
-            .arg(command)
-            .output()?;
+        let shell = Shell::detect();
+        let output = shell.command(command).output()
+            .map_err(|e| anyhow::anyhow!("cannot start shell '{}': {}", shell.program, e))?;
         Ok(ShellResult {
             stdout: String::from_utf8_lossy(&output.stdout).trim_end().to_string(),
             exit_code: output.status.code().unwrap_or(-1),
@@ -99,34 +232,71 @@ impl Env for RealEnv {
     }
 
     fn http_get(&self, url: &str) -> Result<String> {
-        let resp = reqwest::blocking::get(url)
-            .map_err(|e| anyhow::anyhow!("HTTP GET error: {}", e))?;
-        Ok(resp.text().unwrap_or_default())
+        let resp = crate::http_client::client(Service::Http).get(url)
+            .send()
+            .map_err(|e| crate::retry::request_failed(format!("HTTP GET error: {}", e), &e))?;
+        http_body("GET", resp)
     }
 
     fn http_post(&self, url: &str, body: &str) -> Result<String> {
-        let client = reqwest::blocking::Client::new();
+        let client = crate::http_client::client(Service::Http);
         let resp = client.post(url)
             .header("Content-Type", "application/json")
             .body(body.to_string())
             .send()
-            .map_err(|e| anyhow::anyhow!("HTTP POST error: {}", e))?;
-        Ok(resp.text().unwrap_or_default())
+            .map_err(|e| crate::retry::request_failed(format!("HTTP POST error: {}", e), &e))?;
+        http_body("POST", resp)
+    }
+
+    fn grpc_call(&self, call: &crate::grpc::Call) -> Result<serde_json::Value> {
+        crate::grpc::call(call)
+    }
+
+    fn screenshot(&mut self, path: &str) -> Result<String> {
+        crate::screen::capture(&native_path(path))?;
+        Ok(path.to_string())
+    }
+
+    fn screen_size(&self) -> Result<(u32, u32)> {
+        crate::screen::size()
+    }
+
+    fn upload_file(&mut self, path: &str, purpose: &str) -> Result<String> {
+        crate::providers::upload_file(&native_path(path), purpose)
     }
 
     fn allow_shell(&self) -> bool { self.allow_shell }
 }
 
+/// The body of an `http.*` response. Rate limiting and server errors come
+/// back as a `StatusError` holding the body, so a retry policy can see them.
+fn http_body(method: &str, resp: reqwest::blocking::Response) -> Result<String> {
+    let status = resp.status();
+    let body = resp.text().unwrap_or_default();
+    if status.as_u16() == 429 || status.is_server_error() {
+        return Err(crate::retry::StatusError { what: format!("HTTP {}", method), status: status.as_u16(), body }.into());
+    }
+    Ok(body)
+}
+
 // ─── MockEnv ───
 
 pub struct MockEnv {
     pub stdin_lines: Vec<String>,
     stdin_index: usize,
     pub stdout_buffer: Vec<String>,
+    /// Streamed output not yet ended by a newline.
+    stdout_partial: String,
     pub files: std::collections::HashMap<String, String>,
     pub shell_responses: std::collections::HashMap<String, String>,
     pub llm_responses: Vec<LlmResponse>,
     llm_index: usize,
+    /// Image paths `screenshot()` returns in turn
+    pub screenshots: Vec<String>,
+    screenshot_index: usize,
+    pub screen_size: Option<(u32, u32)>,
+    /// Paths given to `file_upload()`, in order; the ids are made up
+    pub uploads: Vec<String>,
     pub allow_shell: bool,
 }
 
@@ -136,10 +306,15 @@ impl MockEnv {
             stdin_lines: Vec::new(),
             stdin_index: 0,
             stdout_buffer: Vec::new(),
+            stdout_partial: String::new(),
             files: std::collections::HashMap::new(),
             shell_responses: std::collections::HashMap::new(),
             llm_responses: Vec::new(),
             llm_index: 0,
+            screenshots: Vec::new(),
+            screenshot_index: 0,
+            screen_size: None,
+            uploads: Vec::new(),
             allow_shell: true,
         }
     }
@@ -175,20 +350,41 @@ impl MockEnv {
                     env.llm_responses.push(LlmResponse {
                         content: s.to_string(),
                         tool_calls: None,
+                        finish_reason: None,
                         raw_json: None,
+                        error: None,
                     });
                 } else if resp.is_object() {
                     let content = resp.get("content").and_then(|v| v.as_str()).unwrap_or("").to_string();
-                    let tool_calls = resp.get("tool_calls").and_then(|v| v.as_array()).map(|arr| arr.clone());
+                    let tool_calls = resp.get("tool_calls").and_then(|v| v.as_array()).cloned();
+                    let finish_reason = resp.get("finish_reason").and_then(|v| v.as_str()).map(String::from);
+                    let error = resp.get("error").and_then(|v| v.as_str()).map(String::from);
                     env.llm_responses.push(LlmResponse {
                         content,
                         tool_calls,
+                        finish_reason,
                         raw_json: Some(resp.clone()),
+                        error,
                     });
                 }
             }
         }
 
+        if let Some(shots) = json.get("screenshots").and_then(|v| v.as_array()) {
+            env.screenshots = shots.iter()
+                .filter_map(|v| v.as_str().map(String::from))
+                .collect();
+        }
+
+        if let Some(size) = json.get("screen_size") {
+            let dims: Option<Vec<u32>> = size.as_array()
+                .and_then(|dims| dims.iter().map(|d| d.as_u64().and_then(|d| u32::try_from(d).ok())).collect());
+            match dims.as_deref() {
+                Some(&[width, height]) => env.screen_size = Some((width, height)),
+                _ => anyhow::bail!("screen_size must be [width, height], got {}", size),
+            }
+        }
+
         if let Some(allow) = json.get("allow_shell").and_then(|v| v.as_bool()) {
             env.allow_shell = allow;
         }
@@ -210,14 +406,25 @@ impl Env for MockEnv {
     }
 
     fn write_stdout(&mut self, content: &str) -> Result<()> {
-        self.stdout_buffer.push(content.to_string());
+        let line = std::mem::take(&mut self.stdout_partial) + content;
+        self.stdout_buffer.push(line);
+        Ok(())
+    }
+
+    fn stream_stdout(&mut self, content: &str) -> Result<()> {
+        self.stdout_partial.push_str(content);
+        while let Some(end) = self.stdout_partial.find('\n') {
+            let line = self.stdout_partial[..end].to_string();
+            self.stdout_partial.drain(..=end);
+            self.stdout_buffer.push(line);
+        }
         Ok(())
     }
 
     fn read_file(&self, path: &str) -> Result<String> {
         self.files.get(path)
             .cloned()
-            .ok_or_else(|| anyhow::anyhow!("cannot read '{}': No such file or directory (os error 2)", path))
+            .ok_or_else(|| io_error(format!("cannot read '{}'", path), std::io::ErrorKind::NotFound.into()))
     }
 
     fn write_file(&mut self, path: &str, content: &str) -> Result<()> {
@@ -226,6 +433,12 @@ impl Env for MockEnv {
         Ok(())
     }
 
+    fn append_file(&mut self, path: &str, content: &str) -> Result<()> {
+        self.files.entry(path.to_string()).or_default().push_str(content);
+        log::info!("MockEnv: append_file({}, {} bytes)", path, content.len());
+        Ok(())
+    }
+
     fn exec_shell(&mut self, command: &str) -> Result<ShellResult> {
         // Try exact match first, then prefix match
         if let Some(output) = self.shell_responses.get(command) {
@@ -245,6 +458,16 @@ impl Env for MockEnv {
         }
         let resp = self.llm_responses[self.llm_index].clone();
         self.llm_index += 1;
+        if let Some(error) = resp.error {
+            // "status" stands in for an error response, "transient" for a
+            // request that timed out or couldn't connect
+            let field = |name| resp.raw_json.as_ref().and_then(|json| json.get(name));
+            if let Some(status) = field("status").and_then(|s| s.as_u64()).and_then(|s| u16::try_from(s).ok()) {
+                return Err(crate::retry::StatusError { what: "LLM API".to_string(), status, body: error }.into());
+            }
+            let transient = field("transient").and_then(|t| t.as_bool()).unwrap_or(false);
+            return Err(crate::retry::RequestFailed { message: error, transient }.into());
+        }
         Ok(resp)
     }
 
@@ -260,9 +483,68 @@ impl Env for MockEnv {
             .ok_or_else(|| anyhow::anyhow!("MockEnv: no mock for POST {}", url))
     }
 
+    /// Mocked under `endpoint/service/method`, as JSON
+    fn grpc_call(&self, call: &crate::grpc::Call) -> Result<serde_json::Value> {
+        let key = format!("{}/{}/{}", call.endpoint, call.service, call.method);
+        let reply = self.files.get(&key)
+            .ok_or_else(|| anyhow::anyhow!("MockEnv: no mock for gRPC {}", key))?;
+        serde_json::from_str(reply).map_err(|e| anyhow::anyhow!("MockEnv: mock for gRPC {} is not JSON: {}", key, e))
+    }
+
+    /// The next canned image; nothing is written to `path`
+    fn screenshot(&mut self, _path: &str) -> Result<String> {
+        let Some(image) = self.screenshots.get(self.screenshot_index).cloned() else {
+            anyhow::bail!("MockEnv: no more screenshots (used {})", self.screenshot_index);
+        };
+        self.screenshot_index += 1;
+        Ok(image)
+    }
+
+    fn screen_size(&self) -> Result<(u32, u32)> {
+        self.screen_size.ok_or_else(|| anyhow::anyhow!("MockEnv: no screen_size configured"))
+    }
+
+    /// `file-mock-1`, `file-mock-2`, ...; nothing is read or sent
+    fn upload_file(&mut self, path: &str, purpose: &str) -> Result<String> {
+        self.uploads.push(path.to_string());
+        log::info!("MockEnv: upload_file({}, purpose={})", path, purpose);
+        Ok(format!("file-mock-{}", self.uploads.len()))
+    }
+
     fn allow_shell(&self) -> bool { self.allow_shell }
 
     fn captured_stdout(&self) -> Option<Vec<String>> {
         Some(self.stdout_buffer.clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shell_choice() {
+        assert_eq!(Shell::choose(None, false, || unreachable!()), Shell { program: "sh".into(), args: vec!["-c"] });
+        assert_eq!(Shell::choose(None, true, || true).program, "sh");
+        let powershell = Shell::choose(None, true, || false);
+        assert_eq!((powershell.program.as_str(), powershell.args.last()), ("powershell", Some(&"-Command")));
+        assert_eq!(Shell::choose(Some("C:\\Windows\\System32\\CMD.EXE"), true, || true).args, vec!["/C"]);
+        assert_eq!(Shell::choose(Some("pwsh"), false, || true).args, vec!["-NoProfile", "-NonInteractive", "-Command"]);
+        assert_eq!(Shell::choose(Some("/bin/bash"), false, || true).args, vec!["-c"]);
+    }
+
+    #[test]
+    fn test_shell_runs_command_line() {
+        let output = Shell::detect().command("echo hello").output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim_end(), "hello");
+    }
+
+    #[test]
+    fn test_native_path_accepts_either_separator() {
+        let expected: std::path::PathBuf = ["lib", "prompts", "system.md"].iter().collect();
+        assert_eq!(native_path("lib/prompts/system.md"), expected);
+        assert_eq!(native_path("lib\\prompts\\system.md"), expected);
+        assert_eq!(display_path(std::path::Path::new(r"\\?\C:\agents\main.cog")), r"C:\agents\main.cog");
+    }
+}
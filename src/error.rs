@@ -1,6 +1,6 @@
+//! Cognos error system.
+//! Every error has a code, location, message, and optional hint.
 #![allow(dead_code)]
-/// Cognos error system.
-/// Every error has a code, location, message, and optional hint.
 
 use crate::token::Token;
 use std::fmt;
@@ -62,6 +62,9 @@ impl CognosError {
     }
 }
 
+/// Error kinds a `catch` clause can name; `Error` matches all of them.
+pub const ERROR_KINDS: &[&str] = &["Error", "NetworkError", "RateLimitError", "ParseError", "TypeError", "IOError", "RuntimeError"];
+
 /// Generate a context-aware parse error for an unexpected token.
 /// This is the single place that maps every token to a helpful message.
 pub fn unexpected_token(line: usize, got: &Token, context: &str) -> CognosError {
@@ -83,6 +86,10 @@ pub fn unexpected_token(line: usize, got: &Token, context: &str) -> CognosError
             "found 'loop' where an expression was expected".into(),
             Some("'loop' is a statement — usage: loop max=N: ...".into()),
         ),
+        Token::While => (
+            "found 'while' where an expression was expected".into(),
+            Some("'while' is a statement — usage: while condition: ...".into()),
+        ),
         Token::For => (
             "found 'for' where an expression was expected".into(),
             Some("'for' is a statement — usage: for item in collection: ...".into()),
@@ -127,6 +134,10 @@ pub fn unexpected_token(line: usize, got: &Token, context: &str) -> CognosError
             "found 'select' where an expression was expected".into(),
             Some("use: select:\\n    branch:\\n        stmts".into()),
         ),
+        Token::With => (
+            "found 'with' where an expression was expected".into(),
+            Some("use: with file(\"out.log\") as f:\n    stmts".into()),
+        ),
         Token::Branch => (
             "found 'branch' where an expression was expected".into(),
             Some("branch: is only valid inside a parallel: block".into()),
@@ -145,6 +156,7 @@ pub fn unexpected_token(line: usize, got: &Token, context: &str) -> CognosError
         Token::Minus => ("unexpected '-' — missing left operand".into(), None),
         Token::Star => ("unexpected '*' — missing left operand".into(), None),
         Token::Slash => ("unexpected '/' — missing left operand".into(), None),
+        Token::SlashSlash => ("unexpected '//' — missing left operand".into(), None),
         Token::Eq => (
             "unexpected '=' — not a valid expression".into(),
             Some("did you mean '==' for comparison?".into()),
@@ -221,6 +233,10 @@ pub fn unexpected_token(line: usize, got: &Token, context: &str) -> CognosError
             format!("unexpected number {}", context),
             None,
         ),
+        Token::BadNumber(text) => (
+            format!("invalid number literal '{}'", text),
+            Some("Ints must fit in 64 bits (up to 9_223_372_036_854_775_807) and hex needs digits: 0xFF".into()),
+        ),
         Token::True | Token::False => (
             format!("unexpected {} {}", got, context),
             None,
@@ -247,7 +263,7 @@ pub fn unexpected_token(line: usize, got: &Token, context: &str) -> CognosError
             None,
         ),
         Token::Pipe => (
-            "unexpected '|' — use in enum definitions: \"a\" | \"b\"".into(),
+            "unexpected '|' — use in enum definitions (\"a\" | \"b\") or lambdas (|x| x * 2)".into(),
             None,
         ),
         Token::Percent => (
@@ -0,0 +1,1381 @@
+//! The `grpc` module: unary and streaming calls to gRPC services, with the
+//! `.proto` file parsed at run time instead of compiled in.
+//!
+//! ```cognos
+//! order = grpc.call("localhost:50051", "shop.Orders", "Get", {"id": 7}, proto="shop.proto")
+//! ```
+//!
+//! The message Map is encoded with the method's input type and the reply
+//! decoded with its output type. Decoded messages have every field, under
+//! its name in the `.proto`: unset scalars are their zero value, unset
+//! messages and fields with explicit presence `none`, enums their value's
+//! name, 64-bit integers Ints (Strings past the Int range) and bytes base64.
+//! Imports resolve next to the importing file, then next to the root
+//! `.proto`; the `google/protobuf` well-known types are bundled as plain
+//! messages. Groups and `stream` interleaving aren't supported: a
+//! client-streaming method takes a List of messages, all sent up front, and a
+//! server-streaming one returns a List.
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::Engine as _;
+use serde_json::Value as Json;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub struct Call {
+    /// `host:port`, `http://host:port` (plaintext HTTP/2) or `https://host:port`
+    pub endpoint: String,
+    /// Full (`shop.Orders`) or, when unambiguous, short (`Orders`) name
+    pub service: String,
+    pub method: String,
+    pub message: Json,
+    pub proto: String,
+    /// Sent as request headers; `-bin` keys are base64-encoded
+    pub metadata: Vec<(String, String)>,
+    pub timeout: Duration,
+}
+
+/// Make `call` and return the reply as JSON.
+pub fn call(call: &Call) -> Result<Json> {
+    let schema = Schema::load(Path::new(&call.proto))?;
+    let (service, method) = schema.method(&call.service, &call.method)?;
+    let messages = match (&call.message, method.client_streaming) {
+        (Json::Array(items), true) => items.iter().collect(),
+        (_, true) => bail!("{}.{} takes a stream of messages; pass a List", service, method.name),
+        (message, false) => vec![message],
+    };
+    let mut frames = Vec::new();
+    for message in messages {
+        let bytes = schema.encode(&method.input, message)?;
+        frames.push(0);
+        frames.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+        frames.extend_from_slice(&bytes);
+    }
+    for (key, _) in &call.metadata {
+        if matches!(key.as_str(), "content-type" | "te" | "user-agent") || key.starts_with("grpc-") || key.starts_with(':') {
+            bail!("metadata key '{}' is set by the client and can't be overridden", key);
+        }
+    }
+    let path = format!("/{}/{}", service, method.name);
+    log::info!("grpc: {} {}", call.endpoint, path);
+    let replies = transport(&call.endpoint, &path, frames, &call.metadata, call.timeout)?;
+    let mut decoded = replies.iter().map(|r| schema.decode(&method.output, r)).collect::<Result<Vec<_>>>()?;
+    if method.server_streaming {
+        return Ok(Json::Array(decoded));
+    }
+    match decoded.len() {
+        1 => Ok(decoded.remove(0)),
+        n => bail!("{} replied with {} messages instead of one", path, n),
+    }
+}
+
+// ─── Schema ───
+
+#[derive(Debug, Clone, PartialEq)]
+enum Kind {
+    Double,
+    Float,
+    Int32,
+    Int64,
+    UInt32,
+    UInt64,
+    SInt32,
+    SInt64,
+    Fixed32,
+    Fixed64,
+    SFixed32,
+    SFixed64,
+    Bool,
+    String,
+    Bytes,
+    /// A full message name, once resolved
+    Message(String),
+    Enum(String),
+    /// A type name as written, resolved from `scope` after all files load
+    Named { name: String, scope: String },
+}
+
+impl Kind {
+    fn scalar(name: &str) -> Option<Kind> {
+        Some(match name {
+            "double" => Kind::Double,
+            "float" => Kind::Float,
+            "int32" => Kind::Int32,
+            "int64" => Kind::Int64,
+            "uint32" => Kind::UInt32,
+            "uint64" => Kind::UInt64,
+            "sint32" => Kind::SInt32,
+            "sint64" => Kind::SInt64,
+            "fixed32" => Kind::Fixed32,
+            "fixed64" => Kind::Fixed64,
+            "sfixed32" => Kind::SFixed32,
+            "sfixed64" => Kind::SFixed64,
+            "bool" => Kind::Bool,
+            "string" => Kind::String,
+            "bytes" => Kind::Bytes,
+            _ => return None,
+        })
+    }
+
+    fn wire_type(&self) -> u8 {
+        match self {
+            Kind::Double | Kind::Fixed64 | Kind::SFixed64 => 1,
+            Kind::Float | Kind::Fixed32 | Kind::SFixed32 => 5,
+            Kind::String | Kind::Bytes | Kind::Message(_) | Kind::Named { .. } => 2,
+            _ => 0,
+        }
+    }
+
+    /// Whether repeated values of this kind can be packed
+    fn packable(&self) -> bool {
+        self.wire_type() != 2
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Field {
+    name: String,
+    json_name: String,
+    number: u32,
+    kind: Kind,
+    repeated: bool,
+    /// proto3 `optional`, oneof members and proto2 singular fields
+    presence: bool,
+    packed: bool,
+    /// `map<K, V>`: `kind` is the value and this the key
+    map_key: Option<Kind>,
+    oneof: Option<usize>,
+}
+
+#[derive(Debug, Default)]
+struct Message {
+    fields: Vec<Field>,
+}
+
+#[derive(Debug)]
+struct Enum {
+    values: Vec<(String, i32)>,
+}
+
+#[derive(Debug, Clone)]
+struct Method {
+    name: String,
+    input: String,
+    output: String,
+    client_streaming: bool,
+    server_streaming: bool,
+}
+
+#[derive(Debug, Default)]
+struct Schema {
+    messages: HashMap<String, Message>,
+    enums: HashMap<String, Enum>,
+    /// Full service name to its methods, as written before resolving
+    services: Vec<(String, Vec<(Method, String)>)>,
+    /// Imports that weren't found; fine unless a type from one is used
+    missing: Vec<String>,
+}
+
+/// The `google/protobuf` files we bundle, as plain messages.
+fn well_known(import: &str) -> Option<&'static str> {
+    Some(match import.strip_prefix("google/protobuf/")? {
+        "empty.proto" => "syntax = \"proto3\"; package google.protobuf; message Empty {}",
+        "timestamp.proto" => "syntax = \"proto3\"; package google.protobuf; message Timestamp { int64 seconds = 1; int32 nanos = 2; }",
+        "duration.proto" => "syntax = \"proto3\"; package google.protobuf; message Duration { int64 seconds = 1; int32 nanos = 2; }",
+        "field_mask.proto" => "syntax = \"proto3\"; package google.protobuf; message FieldMask { repeated string paths = 1; }",
+        "any.proto" => "syntax = \"proto3\"; package google.protobuf; message Any { string type_url = 1; bytes value = 2; }",
+        "wrappers.proto" => concat!(
+            "syntax = \"proto3\"; package google.protobuf;",
+            "message DoubleValue { double value = 1; } message FloatValue { float value = 1; }",
+            "message Int64Value { int64 value = 1; } message UInt64Value { uint64 value = 1; }",
+            "message Int32Value { int32 value = 1; } message UInt32Value { uint32 value = 1; }",
+            "message BoolValue { bool value = 1; } message StringValue { string value = 1; }",
+            "message BytesValue { bytes value = 1; }",
+        ),
+        "struct.proto" => concat!(
+            "syntax = \"proto3\"; package google.protobuf;",
+            "message Struct { map<string, Value> fields = 1; }",
+            "message Value { oneof kind { NullValue null_value = 1; double number_value = 2; string string_value = 3;",
+            " bool bool_value = 4; Struct struct_value = 5; ListValue list_value = 6; } }",
+            "enum NullValue { NULL_VALUE = 0; }",
+            "message ListValue { repeated Value values = 1; }",
+        ),
+        // descriptor.proto and the like only matter to options, which we skip
+        _ => "syntax = \"proto3\"; package google.protobuf;",
+    })
+}
+
+impl Schema {
+    fn load(path: &Path) -> Result<Schema> {
+        let mut schema = Schema::default();
+        let root = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        let source = std::fs::read_to_string(path).map_err(|e| anyhow!("can't read {}: {}", path.display(), e))?;
+        let mut seen = HashSet::new();
+        seen.insert(path.to_path_buf());
+        schema.load_source(&path.display().to_string(), &source, &root, &root, &mut seen)?;
+        schema.resolve()?;
+        Ok(schema)
+    }
+
+    fn load_source(&mut self, file: &str, source: &str, dir: &Path, root: &Path, seen: &mut HashSet<PathBuf>) -> Result<()> {
+        let imports = ProtoParser::new(file, source)?.parse_file(self)?;
+        for import in imports {
+            if let Some(source) = well_known(&import) {
+                if seen.insert(PathBuf::from(&import)) {
+                    self.load_source(&import, source, dir, root, seen)?;
+                }
+                continue;
+            }
+            let Some(path) = [dir, root].iter().map(|d| d.join(&import)).find(|p| p.is_file()) else {
+                log::warn!("grpc: {}: import \"{}\" not found", file, import);
+                self.missing.push(import);
+                continue;
+            };
+            if seen.insert(path.clone()) {
+                let source = std::fs::read_to_string(&path).map_err(|e| anyhow!("can't read {}: {}", path.display(), e))?;
+                let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+                self.load_source(&path.display().to_string(), &source, &dir, root, seen)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The full name `name` means when written inside `scope`.
+    fn lookup(&self, name: &str, scope: &str) -> Option<Kind> {
+        let found = |full: &str| {
+            if self.messages.contains_key(full) {
+                Some(Kind::Message(full.to_string()))
+            } else if self.enums.contains_key(full) {
+                Some(Kind::Enum(full.to_string()))
+            } else {
+                None
+            }
+        };
+        if let Some(absolute) = name.strip_prefix('.') {
+            return found(absolute);
+        }
+        let mut scope = scope;
+        loop {
+            let full = if scope.is_empty() { name.to_string() } else { format!("{}.{}", scope, name) };
+            if let Some(kind) = found(&full) {
+                return Some(kind);
+            }
+            if scope.is_empty() {
+                return None;
+            }
+            scope = scope.rfind('.').map_or("", |i| &scope[..i]);
+        }
+    }
+
+    fn unresolved(&self, name: &str, scope: &str) -> anyhow::Error {
+        let context = if scope.is_empty() { String::new() } else { format!(" in {}", scope) };
+        match self.missing.as_slice() {
+            [] => anyhow!("unknown type '{}'{}", name, context),
+            missing => anyhow!("unknown type '{}'{} (imports not found: {})", name, context, missing.join(", ")),
+        }
+    }
+
+    fn resolve(&mut self) -> Result<()> {
+        let mut resolved = Vec::new();
+        for (message, def) in &self.messages {
+            for (i, field) in def.fields.iter().enumerate() {
+                if let Kind::Named { name, scope } = &field.kind {
+                    let kind = self.lookup(name, scope).ok_or_else(|| self.unresolved(name, scope))?;
+                    resolved.push((message.clone(), i, kind));
+                }
+            }
+        }
+        for (message, i, kind) in resolved {
+            if let Some(def) = self.messages.get_mut(&message) {
+                def.fields[i].kind = kind;
+            }
+        }
+        let mut services = std::mem::take(&mut self.services);
+        for (_, methods) in &mut services {
+            for (method, scope) in methods {
+                for name in [&mut method.input, &mut method.output] {
+                    match self.lookup(name, scope) {
+                        Some(Kind::Message(full)) => *name = full,
+                        _ => return Err(self.unresolved(name, scope)),
+                    }
+                }
+            }
+        }
+        self.services = services;
+        Ok(())
+    }
+
+    fn method(&self, service: &str, method: &str) -> Result<(&str, &Method)> {
+        let short = |full: &str| full.rsplit('.').next() == Some(service);
+        let (name, methods) = match self.services.iter().find(|(full, _)| full == service) {
+            Some(found) => found,
+            None => match self.services.iter().filter(|(full, _)| short(full)).collect::<Vec<_>>().as_slice() {
+                [found] => *found,
+                [] => {
+                    let known: Vec<&str> = self.services.iter().map(|(s, _)| s.as_str()).collect();
+                    bail!("no service '{}' in the proto; it defines: {}", service, if known.is_empty() { "none".to_string() } else { known.join(", ") });
+                }
+                _ => bail!("service name '{}' is ambiguous; use its full name", service),
+            },
+        };
+        match methods.iter().find(|(m, _)| m.name == method) {
+            Some((m, _)) => Ok((name, m)),
+            None => {
+                let known: Vec<&str> = methods.iter().map(|(m, _)| m.name.as_str()).collect();
+                bail!("service {} has no method '{}'; it has: {}", name, method, known.join(", "))
+            }
+        }
+    }
+
+    // ─── Encoding ───
+
+    fn encode(&self, message: &str, value: &Json) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        self.encode_message(message, value, &mut out, message)?;
+        Ok(out)
+    }
+
+    fn encode_message(&self, message: &str, value: &Json, out: &mut Vec<u8>, path: &str) -> Result<()> {
+        let def = &self.messages[message];
+        let Json::Object(entries) = value else {
+            bail!("{}: expected a Map for message {}, got {}", path, message, json_type(value));
+        };
+        for (key, value) in entries {
+            let field = def.fields.iter().find(|f| f.name == *key || f.json_name == *key)
+                .ok_or_else(|| anyhow!("{}: message {} has no field '{}'", path, message, key))?;
+            if value.is_null() {
+                continue;
+            }
+            let path = format!("{}.{}", path, key);
+            if let Some(key_kind) = &field.map_key {
+                let Json::Object(map) = value else { bail!("{}: expected a Map, got {}", path, json_type(value)) };
+                for (k, v) in map {
+                    let mut entry = Vec::new();
+                    let k = match key_kind {
+                        Kind::String => Json::String(k.clone()),
+                        Kind::Bool => Json::Bool(k == "true"),
+                        _ => Json::String(k.clone()),
+                    };
+                    self.encode_value(1, key_kind, &k, &mut entry, &path)?;
+                    self.encode_value(2, &field.kind, v, &mut entry, &path)?;
+                    write_tag(out, field.number, 2);
+                    write_varint(out, entry.len() as u64);
+                    out.extend_from_slice(&entry);
+                }
+            } else if field.repeated {
+                let Json::Array(items) = value else { bail!("{}: expected a List, got {}", path, json_type(value)) };
+                if field.packed && field.kind.packable() {
+                    let mut packed = Vec::new();
+                    for item in items {
+                        self.encode_scalar(&field.kind, item, &mut packed, &path)?;
+                    }
+                    write_tag(out, field.number, 2);
+                    write_varint(out, packed.len() as u64);
+                    out.extend_from_slice(&packed);
+                } else {
+                    for item in items {
+                        self.encode_value(field.number, &field.kind, item, out, &path)?;
+                    }
+                }
+            } else {
+                self.encode_value(field.number, &field.kind, value, out, &path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn encode_value(&self, number: u32, kind: &Kind, value: &Json, out: &mut Vec<u8>, path: &str) -> Result<()> {
+        write_tag(out, number, kind.wire_type());
+        match kind {
+            Kind::String => match value {
+                Json::String(s) => write_bytes(out, s.as_bytes()),
+                other => bail!("{}: expected a String, got {}", path, json_type(other)),
+            },
+            Kind::Bytes => match value {
+                Json::String(s) => {
+                    let bytes = base64::engine::general_purpose::STANDARD.decode(s)
+                        .or_else(|_| base64::engine::general_purpose::URL_SAFE.decode(s))
+                        .map_err(|_| anyhow!("{}: bytes must be a base64 String", path))?;
+                    write_bytes(out, &bytes);
+                }
+                other => bail!("{}: expected a base64 String, got {}", path, json_type(other)),
+            },
+            Kind::Message(name) => {
+                let mut nested = Vec::new();
+                self.encode_message(name, value, &mut nested, path)?;
+                write_bytes(out, &nested);
+            }
+            _ => self.encode_scalar(kind, value, out, path)?,
+        }
+        Ok(())
+    }
+
+    /// A number, bool or enum value, without its tag.
+    fn encode_scalar(&self, kind: &Kind, value: &Json, out: &mut Vec<u8>, path: &str) -> Result<()> {
+        let int = |min: i128, max: i128| -> Result<i128> {
+            let n = match value {
+                Json::Number(n) => n.as_i64().map(i128::from).or_else(|| n.as_u64().map(i128::from))
+                    .or_else(|| n.as_f64().filter(|f| f.fract() == 0.0).map(|f| f as i128)),
+                Json::String(s) => s.trim().parse::<i128>().ok(),
+                _ => None,
+            };
+            match n {
+                Some(n) if (min..=max).contains(&n) => Ok(n),
+                Some(n) => bail!("{}: {} is out of range for {:?}", path, n, kind),
+                None => bail!("{}: expected an Int, got {}", path, json_type(value)),
+            }
+        };
+        let float = || -> Result<f64> {
+            match value {
+                Json::Number(n) => Ok(n.as_f64().unwrap_or_default()),
+                Json::String(s) if s == "NaN" => Ok(f64::NAN),
+                Json::String(s) if s == "Infinity" => Ok(f64::INFINITY),
+                Json::String(s) if s == "-Infinity" => Ok(f64::NEG_INFINITY),
+                other => bail!("{}: expected a number, got {}", path, json_type(other)),
+            }
+        };
+        const I32: (i128, i128) = (i32::MIN as i128, i32::MAX as i128);
+        const I64: (i128, i128) = (i64::MIN as i128, i64::MAX as i128);
+        match kind {
+            Kind::Int32 => write_varint(out, int(I32.0, I32.1)? as i64 as u64),
+            Kind::Int64 => write_varint(out, int(I64.0, I64.1)? as i64 as u64),
+            Kind::UInt32 => write_varint(out, int(0, u32::MAX as i128)? as u64),
+            Kind::UInt64 => write_varint(out, int(0, u64::MAX as i128)? as u64),
+            Kind::SInt32 | Kind::SInt64 => {
+                let (min, max) = if *kind == Kind::SInt32 { I32 } else { I64 };
+                let n = int(min, max)? as i64;
+                write_varint(out, ((n << 1) ^ (n >> 63)) as u64);
+            }
+            Kind::Bool => match value {
+                Json::Bool(b) => write_varint(out, *b as u64),
+                other => bail!("{}: expected a Bool, got {}", path, json_type(other)),
+            },
+            Kind::Enum(name) => {
+                let number = match value {
+                    Json::String(s) => self.enums[name].values.iter().find(|(v, _)| v == s).map(|(_, n)| *n)
+                        .ok_or_else(|| anyhow!("{}: '{}' is not a value of enum {}", path, s, name))?,
+                    _ => int(I32.0, I32.1)? as i32,
+                };
+                write_varint(out, number as i64 as u64);
+            }
+            Kind::Fixed32 => out.extend_from_slice(&(int(0, u32::MAX as i128)? as u32).to_le_bytes()),
+            Kind::SFixed32 => out.extend_from_slice(&(int(I32.0, I32.1)? as i32).to_le_bytes()),
+            Kind::Fixed64 => out.extend_from_slice(&(int(0, u64::MAX as i128)? as u64).to_le_bytes()),
+            Kind::SFixed64 => out.extend_from_slice(&(int(I64.0, I64.1)? as i64).to_le_bytes()),
+            Kind::Float => out.extend_from_slice(&(float()? as f32).to_le_bytes()),
+            Kind::Double => out.extend_from_slice(&float()?.to_le_bytes()),
+            Kind::String | Kind::Bytes | Kind::Message(_) | Kind::Named { .. } => unreachable!("not a scalar"),
+        }
+        Ok(())
+    }
+
+    // ─── Decoding ───
+
+    fn decode(&self, message: &str, bytes: &[u8]) -> Result<Json> {
+        self.decode_message(message, bytes).with_context(|| format!("can't decode the reply as {}", message))
+    }
+
+    fn decode_message(&self, message: &str, bytes: &[u8]) -> Result<Json> {
+        let def = &self.messages[message];
+        let mut values: HashMap<u32, Json> = HashMap::new();
+        // Repeats of a singular message field merge, as concatenating them does
+        let mut nested: HashMap<u32, Vec<u8>> = HashMap::new();
+        let mut reader = Reader { bytes, pos: 0 };
+        while !reader.done() {
+            let key = reader.varint()?;
+            let (number, wire) = ((key >> 3) as u32, (key & 7) as u8);
+            let Some(field) = def.fields.iter().find(|f| f.number == number) else {
+                reader.skip(wire)?;
+                continue;
+            };
+            if let Some(oneof) = field.oneof {
+                for other in def.fields.iter().filter(|f| f.oneof == Some(oneof) && f.number != number) {
+                    values.remove(&other.number);
+                    nested.remove(&other.number);
+                }
+            }
+            if let Some(key_kind) = &field.map_key {
+                let mut entry = Reader { bytes: reader.length_delimited()?, pos: 0 };
+                let (mut k, mut v) = (None, None);
+                while !entry.done() {
+                    let key = entry.varint()?;
+                    match key >> 3 {
+                        1 => k = Some(self.decode_value(key_kind, (key & 7) as u8, &mut entry)?),
+                        2 => v = Some(self.decode_value(&field.kind, (key & 7) as u8, &mut entry)?),
+                        _ => entry.skip((key & 7) as u8)?,
+                    }
+                }
+                let k = match k.unwrap_or_else(|| self.zero(key_kind)) {
+                    Json::String(s) => s,
+                    other => other.to_string(),
+                };
+                let v = v.unwrap_or_else(|| self.zero(&field.kind));
+                if let Json::Object(map) = values.entry(number).or_insert_with(|| Json::Object(Default::default())) {
+                    map.insert(k, v);
+                }
+            } else if field.repeated {
+                let Json::Array(items) = values.entry(number).or_insert_with(|| Json::Array(Vec::new())) else { continue };
+                if wire == 2 && field.kind.packable() {
+                    let mut packed = Reader { bytes: reader.length_delimited()?, pos: 0 };
+                    while !packed.done() {
+                        items.push(self.decode_value(&field.kind, field.kind.wire_type(), &mut packed)?);
+                    }
+                } else {
+                    items.push(self.decode_value(&field.kind, wire, &mut reader)?);
+                }
+            } else if let (Kind::Message(_), 2) = (&field.kind, wire) {
+                nested.entry(number).or_default().extend_from_slice(reader.length_delimited()?);
+            } else {
+                values.insert(number, self.decode_value(&field.kind, wire, &mut reader)?);
+            }
+        }
+        let mut out = serde_json::Map::new();
+        for field in &def.fields {
+            let value = match (values.remove(&field.number), nested.remove(&field.number), &field.kind) {
+                (_, Some(bytes), Kind::Message(name)) => self.decode_message(name, &bytes)?,
+                (Some(value), _, _) => value,
+                _ if field.map_key.is_some() => Json::Object(Default::default()),
+                _ if field.repeated => Json::Array(Vec::new()),
+                _ if field.presence || matches!(field.kind, Kind::Message(_)) => Json::Null,
+                _ => self.zero(&field.kind),
+            };
+            out.insert(field.name.clone(), value);
+        }
+        Ok(Json::Object(out))
+    }
+
+    fn zero(&self, kind: &Kind) -> Json {
+        match kind {
+            Kind::Double | Kind::Float => Json::from(0.0),
+            Kind::Bool => Json::Bool(false),
+            Kind::String | Kind::Bytes => Json::String(String::new()),
+            Kind::Enum(name) => {
+                let values = &self.enums[name].values;
+                let zero = values.iter().find(|(_, n)| *n == 0).or(values.first());
+                zero.map_or(Json::from(0), |(v, _)| Json::String(v.clone()))
+            }
+            Kind::Message(_) | Kind::Named { .. } => Json::Null,
+            _ => Json::from(0),
+        }
+    }
+
+    fn decode_value(&self, kind: &Kind, wire: u8, reader: &mut Reader) -> Result<Json> {
+        if wire != kind.wire_type() {
+            bail!("wire type {} doesn't fit a {:?} field", wire, kind);
+        }
+        let unsigned = |n: u64| i64::try_from(n).map_or_else(|_| Json::String(n.to_string()), Json::from);
+        let float = |f: f64| if f.is_finite() {
+            Json::from(f)
+        } else {
+            Json::String(if f.is_nan() { "NaN" } else if f > 0.0 { "Infinity" } else { "-Infinity" }.to_string())
+        };
+        Ok(match kind {
+            Kind::Int32 => Json::from(reader.varint()? as i64 as i32),
+            Kind::Int64 => Json::from(reader.varint()? as i64),
+            Kind::UInt32 => Json::from(reader.varint()? as u32),
+            Kind::UInt64 => unsigned(reader.varint()?),
+            Kind::SInt32 | Kind::SInt64 => {
+                let n = reader.varint()?;
+                Json::from((n >> 1) as i64 ^ -((n & 1) as i64))
+            }
+            Kind::Bool => Json::Bool(reader.varint()? != 0),
+            Kind::Enum(name) => {
+                let number = reader.varint()? as i64 as i32;
+                match self.enums[name].values.iter().find(|(_, n)| *n == number) {
+                    Some((value, _)) => Json::String(value.clone()),
+                    None => Json::from(number),
+                }
+            }
+            Kind::Fixed32 => Json::from(u32::from_le_bytes(reader.array()?)),
+            Kind::SFixed32 => Json::from(i32::from_le_bytes(reader.array()?)),
+            Kind::Fixed64 => unsigned(u64::from_le_bytes(reader.array()?)),
+            Kind::SFixed64 => Json::from(i64::from_le_bytes(reader.array()?)),
+            Kind::Float => float(f32::from_le_bytes(reader.array()?) as f64),
+            Kind::Double => float(f64::from_le_bytes(reader.array()?)),
+            Kind::String => Json::String(String::from_utf8(reader.length_delimited()?.to_vec()).context("string field is not UTF-8")?),
+            Kind::Bytes => Json::String(base64::engine::general_purpose::STANDARD.encode(reader.length_delimited()?)),
+            Kind::Message(name) => self.decode_message(name, reader.length_delimited()?)?,
+            Kind::Named { .. } => unreachable!("resolved when the schema loads"),
+        })
+    }
+}
+
+fn json_type(value: &Json) -> &'static str {
+    match value {
+        Json::Null => "none",
+        Json::Bool(_) => "Bool",
+        Json::Number(n) if n.is_f64() => "Float",
+        Json::Number(_) => "Int",
+        Json::String(_) => "String",
+        Json::Array(_) => "List",
+        Json::Object(_) => "Map",
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn write_tag(out: &mut Vec<u8>, number: u32, wire: u8) {
+    write_varint(out, (u64::from(number) << 3) | u64::from(wire));
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn done(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(n).filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| anyhow!("message is truncated"))?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap_or([0; N]))
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut n = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            n |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        bail!("varint is too long")
+    }
+
+    fn length_delimited(&mut self) -> Result<&'a [u8]> {
+        let len = self.varint()?;
+        self.take(usize::try_from(len)?)
+    }
+
+    fn skip(&mut self, wire: u8) -> Result<()> {
+        match wire {
+            0 => { self.varint()?; }
+            1 => { self.take(8)?; }
+            2 => { self.length_delimited()?; }
+            5 => { self.take(4)?; }
+            _ => bail!("unsupported wire type {}", wire),
+        }
+        Ok(())
+    }
+}
+
+// ─── .proto parsing ───
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    /// A name, possibly dotted (`google.protobuf.Empty`, `.pkg.Msg`)
+    Ident(String),
+    Int(i64),
+    /// A float or other number we only skip over
+    Number(String),
+    Str(String),
+    Sym(char),
+}
+
+impl std::fmt::Display for Tok {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Tok::Ident(s) | Tok::Number(s) => write!(f, "'{}'", s),
+            Tok::Int(n) => write!(f, "'{}'", n),
+            Tok::Str(s) => write!(f, "\"{}\"", s),
+            Tok::Sym(c) => write!(f, "'{}'", c),
+        }
+    }
+}
+
+struct ProtoParser<'a> {
+    file: &'a str,
+    toks: Vec<(Tok, usize)>,
+    pos: usize,
+    package: String,
+    proto3: bool,
+}
+
+impl<'a> ProtoParser<'a> {
+    fn new(file: &'a str, source: &str) -> Result<Self> {
+        let chars: Vec<char> = source.chars().collect();
+        let mut toks = Vec::new();
+        let (mut i, mut line) = (0, 1);
+        while i < chars.len() {
+            let c = chars[i];
+            let next = chars.get(i + 1).copied();
+            if c == '\n' {
+                line += 1;
+                i += 1;
+            } else if c.is_whitespace() {
+                i += 1;
+            } else if c == '/' && next == Some('/') {
+                while i < chars.len() && chars[i] != '\n' { i += 1; }
+            } else if c == '/' && next == Some('*') {
+                i += 2;
+                while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                    if chars[i] == '\n' { line += 1; }
+                    i += 1;
+                }
+                i += 2;
+            } else if c.is_alphabetic() || c == '_' || (c == '.' && next.is_some_and(|n| n.is_alphabetic() || n == '_')) {
+                let start = i;
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.') { i += 1; }
+                toks.push((Tok::Ident(chars[start..i].iter().collect()), line));
+            } else if c.is_ascii_digit() || (c == '.' && next.is_some_and(|n| n.is_ascii_digit())) {
+                let start = i;
+                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '.'
+                    || (matches!(chars[i], '+' | '-') && matches!(chars[i - 1], 'e' | 'E'))) {
+                    i += 1;
+                }
+                let text: String = chars[start..i].iter().collect();
+                let int = if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+                    i64::from_str_radix(hex, 16).ok()
+                } else if text.len() > 1 && text.starts_with('0') {
+                    i64::from_str_radix(&text[1..], 8).ok()
+                } else {
+                    text.parse().ok()
+                };
+                toks.push((int.map_or(Tok::Number(text), Tok::Int), line));
+            } else if c == '"' || c == '\'' {
+                let mut s = String::new();
+                i += 1;
+                while i < chars.len() && chars[i] != c {
+                    if chars[i] == '\\' && i + 1 < chars.len() {
+                        i += 1;
+                        s.push(match chars[i] { 'n' => '\n', 't' => '\t', 'r' => '\r', '0' => '\0', other => other });
+                    } else {
+                        s.push(chars[i]);
+                    }
+                    i += 1;
+                }
+                if i >= chars.len() {
+                    bail!("{}:{}: unterminated string", file, line);
+                }
+                i += 1;
+                toks.push((Tok::Str(s), line));
+            } else {
+                toks.push((Tok::Sym(c), line));
+                i += 1;
+            }
+        }
+        Ok(Self { file, toks, pos: 0, package: String::new(), proto3: false })
+    }
+
+    fn error(&self, message: impl std::fmt::Display) -> anyhow::Error {
+        let line = self.toks.get(self.pos).or(self.toks.last()).map_or(1, |(_, l)| *l);
+        anyhow!("{}:{}: {}", self.file, line, message)
+    }
+
+    fn peek(&self) -> Option<&Tok> {
+        self.toks.get(self.pos).map(|(t, _)| t)
+    }
+
+    fn next(&mut self) -> Result<Tok> {
+        let tok = self.peek().cloned().ok_or_else(|| self.error("unexpected end of file"))?;
+        self.pos += 1;
+        Ok(tok)
+    }
+
+    fn is_ident(&self, word: &str) -> bool {
+        matches!(self.peek(), Some(Tok::Ident(s)) if s == word)
+    }
+
+    fn eat_ident(&mut self, word: &str) -> bool {
+        let found = self.is_ident(word);
+        if found { self.pos += 1; }
+        found
+    }
+
+    fn eat_sym(&mut self, c: char) -> bool {
+        let found = self.peek() == Some(&Tok::Sym(c));
+        if found { self.pos += 1; }
+        found
+    }
+
+    fn expect_sym(&mut self, c: char) -> Result<()> {
+        match self.next()? {
+            Tok::Sym(s) if s == c => Ok(()),
+            other => { self.pos -= 1; Err(self.error(format!("expected '{}', got {}", c, other))) }
+        }
+    }
+
+    fn ident(&mut self) -> Result<String> {
+        match self.next()? {
+            Tok::Ident(s) => Ok(s),
+            other => { self.pos -= 1; Err(self.error(format!("expected a name, got {}", other))) }
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        match self.next()? {
+            Tok::Str(s) => Ok(s),
+            other => { self.pos -= 1; Err(self.error(format!("expected a string, got {}", other))) }
+        }
+    }
+
+    fn int(&mut self) -> Result<i64> {
+        let negative = self.eat_sym('-');
+        match self.next()? {
+            Tok::Int(n) => Ok(if negative { -n } else { n }),
+            other => { self.pos -= 1; Err(self.error(format!("expected a number, got {}", other))) }
+        }
+    }
+
+    /// Skip to the `;` ending a statement, over any nested brackets.
+    fn skip_statement(&mut self) -> Result<()> {
+        let mut depth = 0;
+        loop {
+            match self.next()? {
+                Tok::Sym('{' | '[' | '(') => depth += 1,
+                Tok::Sym('}' | ']' | ')') => depth -= 1,
+                Tok::Sym(';') if depth == 0 => return Ok(()),
+                _ => {}
+            }
+        }
+    }
+
+    /// Skip a `{ ... }` block.
+    fn skip_block(&mut self) -> Result<()> {
+        self.expect_sym('{')?;
+        let mut depth = 1;
+        while depth > 0 {
+            match self.next()? {
+                Tok::Sym('{') => depth += 1,
+                Tok::Sym('}') => depth -= 1,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Parse the file into `schema`, returning its imports.
+    fn parse_file(mut self, schema: &mut Schema) -> Result<Vec<String>> {
+        let mut imports = Vec::new();
+        while let Some(tok) = self.peek().cloned() {
+            match tok {
+                Tok::Sym(';') => self.pos += 1,
+                Tok::Ident(word) => {
+                    self.pos += 1;
+                    match word.as_str() {
+                        "syntax" => {
+                            self.expect_sym('=')?;
+                            self.proto3 = self.string()? == "proto3";
+                            self.expect_sym(';')?;
+                        }
+                        // Editions default to explicit presence, close to proto3 `optional`
+                        "edition" => {
+                            self.proto3 = true;
+                            self.skip_statement()?;
+                        }
+                        "package" => {
+                            self.package = self.ident()?;
+                            self.expect_sym(';')?;
+                        }
+                        "import" => {
+                            if !self.eat_ident("public") { self.eat_ident("weak"); }
+                            imports.push(self.string()?);
+                            self.expect_sym(';')?;
+                        }
+                        "option" => self.skip_statement()?,
+                        "message" => {
+                            let scope = self.package.clone();
+                            self.parse_message(&scope, schema)?;
+                        }
+                        "enum" => {
+                            let scope = self.package.clone();
+                            self.parse_enum(&scope, schema)?;
+                        }
+                        "service" => self.parse_service(schema)?,
+                        "extend" => {
+                            self.ident()?;
+                            self.skip_block()?;
+                        }
+                        _ => { self.pos -= 1; return Err(self.error(format!("unexpected '{}'", word))); }
+                    }
+                }
+                other => return Err(self.error(format!("unexpected {}", other))),
+            }
+        }
+        Ok(imports)
+    }
+
+    fn parse_message(&mut self, scope: &str, schema: &mut Schema) -> Result<()> {
+        let name = join(scope, &self.ident()?);
+        self.expect_sym('{')?;
+        let mut message = Message::default();
+        let mut oneofs = 0;
+        loop {
+            if self.eat_sym('}') {
+                break;
+            }
+            if self.eat_sym(';') {
+                continue;
+            }
+            match self.peek() {
+                Some(Tok::Ident(word)) => match word.as_str() {
+                    "message" => { self.pos += 1; self.parse_message(&name, schema)?; }
+                    "enum" => { self.pos += 1; self.parse_enum(&name, schema)?; }
+                    "option" | "reserved" | "extensions" => self.skip_statement()?,
+                    "extend" => { self.pos += 1; self.ident()?; self.skip_block()?; }
+                    "oneof" => {
+                        self.pos += 1;
+                        self.ident()?;
+                        self.expect_sym('{')?;
+                        while !self.eat_sym('}') {
+                            if self.eat_ident("option") {
+                                self.skip_statement()?;
+                            } else if !self.eat_sym(';') {
+                                message.fields.push(self.parse_field(&name, Some(oneofs))?);
+                            }
+                        }
+                        oneofs += 1;
+                    }
+                    _ => message.fields.push(self.parse_field(&name, None)?),
+                },
+                _ => return Err(self.error(format!("unexpected {} in message {}", self.peek().map_or("end of file".to_string(), |t| t.to_string()), name))),
+            }
+        }
+        schema.messages.insert(name, message);
+        Ok(())
+    }
+
+    fn parse_field(&mut self, scope: &str, oneof: Option<usize>) -> Result<Field> {
+        let (mut repeated, mut presence) = (false, oneof.is_some());
+        let mut map_key = None;
+        let type_name = if self.is_ident("map") && self.toks.get(self.pos + 1).is_some_and(|(t, _)| *t == Tok::Sym('<')) {
+            self.pos += 2;
+            let key = self.ident()?;
+            map_key = Some(Kind::scalar(&key).ok_or_else(|| self.error(format!("'{}' can't be a map key", key)))?);
+            self.expect_sym(',')?;
+            let value = self.ident()?;
+            self.expect_sym('>')?;
+            value
+        } else {
+            if self.eat_ident("repeated") {
+                repeated = true;
+            } else if self.eat_ident("optional") || self.eat_ident("required") || !self.proto3 {
+                presence = true;
+            }
+            self.ident()?
+        };
+        if type_name == "group" {
+            return Err(self.error("groups are not supported"));
+        }
+        let name = self.ident()?;
+        self.expect_sym('=')?;
+        let number = self.int()?;
+        let number = u32::try_from(number).map_err(|_| self.error(format!("invalid field number {}", number)))?;
+        let mut packed = self.proto3;
+        let mut json_name = lower_camel(&name);
+        if self.eat_sym('[') {
+            loop {
+                let option = self.ident().or_else(|_| {
+                    // Custom options: `(my.option).field`
+                    let mut depth = 0;
+                    while !(depth == 0 && self.peek() == Some(&Tok::Sym('='))) {
+                        match self.next()? {
+                            Tok::Sym('(') => depth += 1,
+                            Tok::Sym(')') => depth -= 1,
+                            _ => {}
+                        }
+                    }
+                    Ok::<_, anyhow::Error>(String::new())
+                })?;
+                self.expect_sym('=')?;
+                let value = self.next()?;
+                match (option.as_str(), value) {
+                    ("packed", Tok::Ident(v)) => packed = v == "true",
+                    ("json_name", Tok::Str(v)) => json_name = v,
+                    (_, Tok::Sym('{')) => {
+                        self.pos -= 1;
+                        self.skip_block()?;
+                    }
+                    _ => {}
+                }
+                if self.eat_sym(']') {
+                    break;
+                }
+                self.expect_sym(',')?;
+            }
+        }
+        self.expect_sym(';')?;
+        let kind = Kind::scalar(&type_name).unwrap_or(Kind::Named { name: type_name, scope: scope.to_string() });
+        Ok(Field { name, json_name, number, kind, repeated, presence: presence && map_key.is_none(), packed, map_key, oneof })
+    }
+
+    fn parse_enum(&mut self, scope: &str, schema: &mut Schema) -> Result<()> {
+        let name = join(scope, &self.ident()?);
+        self.expect_sym('{')?;
+        let mut values = Vec::new();
+        while !self.eat_sym('}') {
+            if self.eat_sym(';') {
+                continue;
+            }
+            if self.is_ident("option") || self.is_ident("reserved") {
+                self.skip_statement()?;
+                continue;
+            }
+            let value = self.ident()?;
+            self.expect_sym('=')?;
+            let number = self.int()?;
+            if self.peek() == Some(&Tok::Sym('[')) {
+                self.skip_statement()?;
+            } else {
+                self.expect_sym(';')?;
+            }
+            values.push((value, number as i32));
+        }
+        schema.enums.insert(name, Enum { values });
+        Ok(())
+    }
+
+    fn parse_service(&mut self, schema: &mut Schema) -> Result<()> {
+        let name = self.ident()?;
+        let name = join(&self.package, &name);
+        self.expect_sym('{')?;
+        let mut methods = Vec::new();
+        while !self.eat_sym('}') {
+            if self.eat_sym(';') {
+                continue;
+            }
+            if self.is_ident("option") {
+                self.skip_statement()?;
+                continue;
+            }
+            if !self.eat_ident("rpc") {
+                return Err(self.error(format!("expected 'rpc' in service {}", name)));
+            }
+            let method = self.ident()?;
+            let mut types = Vec::new();
+            for keyword in [None, Some("returns")] {
+                if let Some(keyword) = keyword {
+                    if !self.eat_ident(keyword) {
+                        return Err(self.error("expected 'returns'"));
+                    }
+                }
+                self.expect_sym('(')?;
+                // `stream` can also be a type's name: `(stream)`
+                let stream = self.is_ident("stream") && matches!(self.toks.get(self.pos + 1), Some((Tok::Ident(_), _)));
+                if stream { self.pos += 1; }
+                types.push((self.ident()?, stream));
+                self.expect_sym(')')?;
+            }
+            if self.peek() == Some(&Tok::Sym('{')) {
+                self.skip_block()?;
+            } else {
+                self.expect_sym(';')?;
+            }
+            let [(input, client_streaming), (output, server_streaming)] = <[_; 2]>::try_from(types).unwrap_or_default();
+            methods.push((Method { name: method, input, output, client_streaming, server_streaming }, self.package.clone()));
+        }
+        schema.services.push((name, methods));
+        Ok(())
+    }
+}
+
+fn join(scope: &str, name: &str) -> String {
+    if scope.is_empty() { name.to_string() } else { format!("{}.{}", scope, name) }
+}
+
+/// `order_id` → `orderId`, the JSON name protobuf gives a field
+fn lower_camel(name: &str) -> String {
+    let mut out = String::new();
+    let mut upper = false;
+    for c in name.chars() {
+        if c == '_' {
+            upper = true;
+        } else if upper {
+            out.extend(c.to_uppercase());
+            upper = false;
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+// ─── Transport ───
+
+const STATUS_NAMES: [&str; 17] = [
+    "OK", "CANCELLED", "UNKNOWN", "INVALID_ARGUMENT", "DEADLINE_EXCEEDED", "NOT_FOUND", "ALREADY_EXISTS",
+    "PERMISSION_DENIED", "RESOURCE_EXHAUSTED", "FAILED_PRECONDITION", "ABORTED", "OUT_OF_RANGE",
+    "UNIMPLEMENTED", "INTERNAL", "UNAVAILABLE", "DATA_LOSS", "UNAUTHENTICATED",
+];
+
+/// `(tls, host, port)` for an endpoint.
+fn parse_endpoint(endpoint: &str) -> Result<(bool, String, u16)> {
+    let (tls, rest) = match endpoint.split_once("://") {
+        Some(("https", rest)) => (true, rest),
+        Some(("http", rest)) => (false, rest),
+        Some((scheme, _)) => bail!("endpoint '{}': use http:// or https://, not {}://", endpoint, scheme),
+        None => (false, endpoint),
+    };
+    let url = reqwest::Url::parse(&format!("{}://{}", if tls { "https" } else { "http" }, rest.trim_end_matches('/')))
+        .map_err(|e| anyhow!("invalid endpoint '{}': {}", endpoint, e))?;
+    let host = url.host_str().ok_or_else(|| anyhow!("endpoint '{}' has no host", endpoint))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']').to_string();
+    Ok((tls, host, url.port_or_known_default().unwrap_or(80)))
+}
+
+/// Send the framed request to `path` and return the reply's messages.
+fn transport(endpoint: &str, path: &str, frames: Vec<u8>, metadata: &[(String, String)], timeout: Duration) -> Result<Vec<Vec<u8>>> {
+    let (tls, host, port) = parse_endpoint(endpoint)?;
+    let mut headers = Vec::new();
+    for (key, value) in metadata {
+        let value = if key.ends_with("-bin") {
+            base64::engine::general_purpose::STANDARD_NO_PAD.encode(value)
+        } else {
+            value.clone()
+        };
+        headers.push((key.to_ascii_lowercase(), value));
+    }
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    runtime.block_on(async {
+        let exchange = async {
+            let tcp = tokio::net::TcpStream::connect((host.as_str(), port)).await
+                .map_err(|e| anyhow!("UNAVAILABLE: can't connect to {}: {}", endpoint, e))?;
+            tcp.set_nodelay(true)?;
+            let authority = if host.contains(':') { format!("[{}]:{}", host, port) } else { format!("{}:{}", host, port) };
+            if tls {
+                let connector = native_tls::TlsConnector::builder().request_alpns(&["h2"]).build()?;
+                let stream = tokio_native_tls::TlsConnector::from(connector).connect(&host, tcp).await
+                    .map_err(|e| anyhow!("UNAVAILABLE: TLS handshake with {} failed: {}", endpoint, e))?;
+                exchange(stream, &format!("https://{}{}", authority, path), frames, &headers, timeout).await
+            } else {
+                exchange(tcp, &format!("http://{}{}", authority, path), frames, &headers, timeout).await
+            }
+        };
+        tokio::time::timeout(timeout, exchange).await
+            .map_err(|_| anyhow!("DEADLINE_EXCEEDED: no reply from {} within {}s", endpoint, timeout.as_secs()))?
+    })
+}
+
+async fn exchange<T>(io: T, uri: &str, frames: Vec<u8>, headers: &[(String, String)], timeout: Duration) -> Result<Vec<Vec<u8>>>
+where
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let (client, connection) = h2::client::handshake(io).await
+        .map_err(|e| anyhow!("UNAVAILABLE: HTTP/2 handshake failed ({}); is it a gRPC server?", e))?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            log::debug!("grpc: connection ended: {}", e);
+        }
+    });
+    let mut request = http::Request::builder()
+        .method(http::Method::POST)
+        .uri(uri)
+        .header("content-type", "application/grpc")
+        .header("te", "trailers")
+        .header("grpc-timeout", format!("{}m", timeout.as_millis().min(99_999_999)))
+        .header("user-agent", concat!("cognos/", env!("CARGO_PKG_VERSION")));
+    for (key, value) in headers {
+        request = request.header(key.as_str(), value.as_str());
+    }
+    let mut client = client.ready().await?;
+    let (response, mut send) = client.send_request(request.body(())?, false)?;
+    send.send_data(bytes::Bytes::from(frames), true)?;
+    let (head, mut body) = response.await?.into_parts();
+    if head.status != http::StatusCode::OK {
+        bail!("the server answered HTTP {}; is {} a gRPC endpoint?", head.status, uri);
+    }
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        let _ = body.flow_control().release_capacity(chunk.len());
+        data.extend_from_slice(&chunk);
+    }
+    let trailers = body.trailers().await?;
+    let status = |headers: &http::HeaderMap| {
+        let code = headers.get("grpc-status")?.to_str().ok()?.parse::<usize>().unwrap_or(2);
+        let message = headers.get("grpc-message").and_then(|m| m.to_str().ok())
+            .map(|m| urlencoding::decode(m).map_or_else(|_| m.to_string(), |m| m.into_owned()))
+            .unwrap_or_default();
+        Some((code, message))
+    };
+    let (code, message) = trailers.as_ref().and_then(status).or_else(|| status(&head.headers))
+        .ok_or_else(|| anyhow!("the reply has no grpc-status; is {} a gRPC endpoint?", uri))?;
+    if code != 0 {
+        let name = STATUS_NAMES.get(code).copied().unwrap_or("UNKNOWN");
+        if message.is_empty() {
+            bail!("{}", name);
+        }
+        bail!("{}: {}", name, message);
+    }
+    let mut messages = Vec::new();
+    let mut reader = Reader { bytes: &data, pos: 0 };
+    while !reader.done() {
+        let [compressed, len @ ..] = reader.array::<5>()?;
+        if compressed != 0 {
+            bail!("the server sent a compressed message, which isn't supported");
+        }
+        messages.push(reader.take(u32::from_be_bytes(len) as usize)?.to_vec());
+    }
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn schema(source: &str) -> Schema {
+        let mut schema = Schema::default();
+        let imports = ProtoParser::new("test.proto", source).unwrap().parse_file(&mut schema).unwrap();
+        for import in imports {
+            let imports = ProtoParser::new(&import, well_known(&import).unwrap()).unwrap().parse_file(&mut schema).unwrap();
+            assert!(imports.is_empty());
+        }
+        schema.resolve().unwrap();
+        schema
+    }
+
+    const SHOP: &str = r#"
+        syntax = "proto3";
+        package shop;
+        import "google/protobuf/timestamp.proto";
+        option go_package = "example.com/shop";
+
+        /* An order */
+        message Order {
+          int64 id = 1;
+          string customer_name = 2 [json_name = "customer"];
+          repeated int32 quantities = 3;
+          Status status = 4;
+          map<string, double> prices = 5;
+          Address ship_to = 6;
+          optional string note = 7;
+          oneof payment {
+            string card = 8;
+            bytes token = 9;
+          }
+          sint64 balance = 10;
+          google.protobuf.Timestamp placed = 11;
+          repeated Item items = 12;
+          message Item { string sku = 1; uint64 count = 2; }
+          enum Status { PENDING = 0; SHIPPED = 1 [deprecated = true]; }
+          reserved 20 to 25;
+        }
+        message Address { string city = 1; fixed32 zip = 2; }
+        message GetRequest { int64 id = 1; }
+
+        service Orders {
+          option (google.api.default_host) = "shop.example.com";
+          rpc Get (GetRequest) returns (Order) {
+            option (google.api.http) = { get: "/v1/orders/{id}" };
+          }
+          rpc Watch (GetRequest) returns (stream Order);
+          rpc Upload (stream Order) returns (GetRequest);
+        }
+    "#;
+
+    #[test]
+    fn test_parses_and_resolves_a_proto() {
+        let schema = schema(SHOP);
+        let (service, method) = schema.method("Orders", "Get").unwrap();
+        assert_eq!((service, method.input.as_str(), method.output.as_str()), ("shop.Orders", "shop.GetRequest", "shop.Order"));
+        assert!(schema.method("shop.Orders", "Watch").unwrap().1.server_streaming);
+        assert!(schema.method("Orders", "Upload").unwrap().1.client_streaming);
+        let err = schema.method("Orders", "Delete").unwrap_err();
+        assert!(err.to_string().contains("it has: Get, Watch, Upload"), "{}", err);
+
+        let order = &schema.messages["shop.Order"];
+        let field = |name: &str| order.fields.iter().find(|f| f.name == name).unwrap();
+        assert_eq!(field("status").kind, Kind::Enum("shop.Order.Status".into()));
+        assert_eq!(field("items").kind, Kind::Message("shop.Order.Item".into()));
+        assert_eq!(field("placed").kind, Kind::Message("google.protobuf.Timestamp".into()));
+        assert_eq!(field("customer_name").json_name, "customer");
+        assert_eq!(field("ship_to").json_name, "shipTo");
+        assert!(field("note").presence && field("card").presence && !field("id").presence);
+
+        let mut broken = Schema::default();
+        ProtoParser::new("x.proto", "syntax = \"proto3\"; message A { Missing m = 1; }").unwrap().parse_file(&mut broken).unwrap();
+        assert!(broken.resolve().unwrap_err().to_string().contains("unknown type 'Missing'"));
+        let err = ProtoParser::new("x.proto", "message A {\n  int32 = 1;\n}").unwrap().parse_file(&mut Schema::default()).unwrap_err();
+        assert_eq!(err.to_string(), "x.proto:2: expected a name, got '='");
+    }
+
+    #[test]
+    fn test_messages_round_trip() {
+        let schema = schema(SHOP);
+        let order = json!({
+            "id": 9_007_199_254_740_993i64,
+            "customer": "Ada",
+            "quantities": [1, -2, 300],
+            "status": "SHIPPED",
+            "prices": {"apple": 1.5},
+            "shipTo": {"city": "Paris", "zip": 75001},
+            "card": "4242",
+            "token": "AAEC",
+            "balance": -5,
+            "items": [{"sku": "a", "count": "18446744073709551615"}],
+        });
+        let bytes = schema.encode("shop.Order", &order).unwrap();
+        // The later oneof member wins
+        assert_eq!(schema.decode("shop.Order", &bytes).unwrap(), json!({
+            "id": 9_007_199_254_740_993i64,
+            "customer_name": "Ada",
+            "quantities": [1, -2, 300],
+            "status": "SHIPPED",
+            "prices": {"apple": 1.5},
+            "ship_to": {"city": "Paris", "zip": 75001},
+            "note": null,
+            "card": null,
+            "token": "AAEC",
+            "balance": -5,
+            "placed": null,
+            "items": [{"sku": "a", "count": "18446744073709551615"}],
+        }));
+        // Unset fields decode to their defaults
+        let empty = schema.decode("shop.Order", &[]).unwrap();
+        assert_eq!((&empty["id"], &empty["status"], &empty["quantities"]), (&json!(0), &json!("PENDING"), &json!([])));
+
+        for (message, error) in [
+            (json!({"nope": 1}), "shop.Order: message shop.Order has no field 'nope'"),
+            (json!({"status": "LOST"}), "shop.Order.status: 'LOST' is not a value of enum shop.Order.Status"),
+            (json!({"quantities": [1, 3_000_000_000i64]}), "out of range"),
+            (json!({"ship_to": "Paris"}), "expected a Map for message shop.Address, got String"),
+        ] {
+            let err = schema.encode("shop.Order", &message).unwrap_err();
+            assert!(err.to_string().contains(error), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_wire_format_matches_protobuf() {
+        let schema = schema("syntax = \"proto3\"; message T { int32 a = 1; string b = 2; repeated int32 c = 4; sint32 d = 5; }");
+        // The examples from the protobuf encoding guide
+        assert_eq!(schema.encode("T", &json!({"a": 150})).unwrap(), [0x08, 0x96, 0x01]);
+        assert_eq!(schema.encode("T", &json!({"b": "testing"})).unwrap(), b"\x12\x07testing");
+        assert_eq!(schema.encode("T", &json!({"c": [3, 270, 86942]})).unwrap(), [0x22, 0x06, 0x03, 0x8E, 0x02, 0x9E, 0xA7, 0x05]);
+        assert_eq!(schema.encode("T", &json!({"d": -2})).unwrap(), [0x28, 0x03]);
+        // Unpacked repeats and unknown fields are read too
+        assert_eq!(schema.decode("T", &[0x20, 0x03, 0x20, 0x04, 0x30, 0x01]).unwrap()["c"], json!([3, 4]));
+        assert!(schema.decode("T", &[0x12, 0x07, b't']).unwrap_err().to_string().contains("can't decode the reply as T"));
+    }
+
+    #[test]
+    fn test_endpoints() {
+        assert_eq!(parse_endpoint("localhost:50051").unwrap(), (false, "localhost".to_string(), 50051));
+        assert_eq!(parse_endpoint("https://api.example.com").unwrap(), (true, "api.example.com".to_string(), 443));
+        assert_eq!(parse_endpoint("http://[::1]:9000/").unwrap(), (false, "::1".to_string(), 9000));
+        assert!(parse_endpoint("grpc://x:1").is_err());
+    }
+}
@@ -0,0 +1,87 @@
+//! Signatures and one-line summaries of the built-in functions, for
+//! `help()` and the REPL. Keep in step with §5 of the language spec.
+
+pub struct Builtin {
+    pub name: &'static str,
+    pub signature: &'static str,
+    pub summary: &'static str,
+}
+
+const fn builtin(name: &'static str, signature: &'static str, summary: &'static str) -> Builtin {
+    Builtin { name, signature, summary }
+}
+
+pub const BUILTINS: &[Builtin] = &[
+    // LLM
+    builtin("think", "think(context, model=\"\", system=\"\", tools=[], format=\"\", examples=[], images=[], conversation=[], tool_results=, temperature=, num_ctx=, keep_alive=, json_mode=false, auto_continue=false, max_tool_result=, stream=false, on_token=, retries=, backoff=\"exponential\", files=[], endpoint=, api_key=, logprobs=false, n=1, reduce=, judge=, repair=0) -> String | Map | List",
+        "Ask a model; with tools= it may call flows and returns a Map"),
+    builtin("classify", "classify(text, labels=[...], model=\"\") -> String", "Pick the label that fits the text best"),
+    builtin("judge", "judge(answer, criteria, model=\"\", question=, reference=, threshold=0.75) -> Map", "Grade an answer against criteria: {score, reasoning, pass}"),
+    builtin("extract", "extract(text, type=\"T\", model=\"\", chunk_size=2000, dedup=true) -> Map | List",
+        "Pull a typed value out of text, chunking long inputs"),
+    builtin("summarize", "summarize(text_or_file, max_words=200, model=\"\", chunk_size=3000) -> String", "Summarize text or a file"),
+    builtin("builtin_tool", "builtin_tool(type, name=?, ...) -> Map", "A provider's own tool (computer use, web search, code interpreter) for think(tools=[...])"),
+    builtin("file_upload", "file_upload(path, purpose=\"user_data\") -> String", "Upload a file to OpenAI; returns its id for think(files=[...])"),
+    builtin("count_tokens", "count_tokens(text, model=\"\") -> Int", "Count the tokens text takes for a model"),
+    builtin("chunk_text", "chunk_text(text, max_tokens=1000, overlap=100, model=\"\") -> List", "Split text into overlapping chunks"),
+    builtin("invoke", "invoke(name, args) -> Value", "Call a flow by name with a Map of arguments"),
+    builtin("history", "history() -> List", "The conversation turns so far"),
+    builtin("clear_history", "clear_history()", "Forget the conversation so far"),
+    // I/O
+    builtin("read", "read(handle?, prompt=?) -> String", "Read a line from stdin or a whole file"),
+    builtin("prompt_user", "prompt_user(question, choices=?, default=?) -> String", "Ask the person at the terminal"),
+    builtin("write", "write(handle, content)", "Write to stdout or a file handle, with a newline"),
+    builtin("file", "file(path, mode=\"write\") -> Handle", "Open a file for writing or appending"),
+    builtin("channel", "channel(provider, ...) -> Handle", "Open a Slack, NATS or MQTT channel to read and write messages"),
+    builtin("download", "download(url, path, channel=?)", "Save a URL's body to a file"),
+    builtin("screenshot", "screenshot(path) -> String", "Capture the screen as a PNG; returns the image's path"),
+    builtin("screen_size", "screen_size() -> Map", "The screen's width and height in pixels"),
+    builtin("resource", "resource(path) -> String", "A file read at parse time and bundled with the program"),
+    builtin("wasm", "wasm(module, input?, dirs=?, ro_dirs=?, fuel=?, memory_mb=?) -> Value", "Run a WASI module sandboxed to the directories it's given"),
+    builtin("mcp", "mcp(transport, command=?, args=?, env=?, url=?, headers=?, timeout=60) -> Handle", "Connect to an MCP server; its tools work in think(tools=[...]) and invoke()"),
+    // Persistence and memory
+    builtin("save", "save(path, value)", "Write a value to a file as JSON"),
+    builtin("load", "load(path) -> Value", "Read a value saved with save()"),
+    builtin("remember", "remember(text, score=?)", "Store a fact in long-term memory"),
+    builtin("recall", "recall(query, limit=5) -> List", "The stored facts closest to a query"),
+    builtin("recall_scored", "recall_scored(query, limit=5) -> List", "recall() with similarity and score for each fact"),
+    builtin("forget", "forget(query) -> Int", "Remove matching facts; returns how many"),
+    // Output
+    builtin("log", "log(message)", "Write a message to stderr"),
+    builtin("print", "print(value, ...)", "Write values to stdout, separated by spaces"),
+    builtin("emit", "emit(value, pretty=false)", "Write a value to stdout"),
+    builtin("pprint", "pprint(value, depth=, max_items=, max_string=)", "Write a value to stdout, indented"),
+    // Values
+    builtin("type_of", "type_of(value) -> String", "The name of a value's type"),
+    builtin("is_string", "is_string(value) -> Bool", "Whether a value is a String"),
+    builtin("is_int", "is_int(value) -> Bool", "Whether a value is an Int"),
+    builtin("is_list", "is_list(value) -> Bool", "Whether a value is a List"),
+    builtin("is_map", "is_map(value) -> Bool", "Whether a value is a Map"),
+    builtin("is_none", "is_none(value) -> Bool", "Whether a value is none"),
+    builtin("get_path", "get_path(value, path, default=none) -> Value", "Follow a dotted path into nested maps and lists"),
+    builtin("query", "query(value, path) -> List", "Everything a JSONPath matches"),
+    builtin("int", "int(value) -> Int", "Convert to Int"),
+    builtin("float", "float(value) -> Float", "Convert to Float"),
+    builtin("str", "str(value) -> String", "Convert to String"),
+    builtin("round", "round(x, places?) -> Int | Float", "Round to the nearest Int, or to places decimals"),
+    builtin("divmod", "divmod(a, b) -> List", "[a // b, a % b]"),
+    builtin("remove", "remove(map, key) -> Map", "The map without key"),
+    builtin("eval", "eval(source, vars?) -> Value", "Run Cognos source and return its result"),
+    builtin("define_flow", "define_flow(source) -> String", "Add a flow from its source at run time (needs --allow-dynamic)"),
+    // Concurrency
+    builtin("shared", "shared(initial?) -> Shared", "A map every branch and task sees the same copy of"),
+    builtin("await", "await(handle) -> Value", "Wait for an async task's result"),
+    builtin("cancel", "cancel(handle)", "Stop an async task"),
+    // Introspection
+    builtin("schema", "schema(type, text=false) -> Map | String", "The JSON Schema for a type, as a value or as JSON text"),
+    builtin("help", "help(name?)", "Describe a built-in, flow or type, or list them all"),
+    builtin("flows", "flows() -> List", "The flows defined, with their parameters"),
+    builtin("types", "types() -> List", "The types defined, with their fields or variants"),
+];
+
+/// A built-in, or a function a `--plugin` added.
+pub fn lookup(name: &str) -> Option<&'static Builtin> {
+    BUILTINS.iter()
+        .chain(crate::plugin::all().iter().flat_map(|p| &p.builtins))
+        .find(|b| b.name == name)
+}
@@ -0,0 +1,141 @@
+//! Shared HTTP clients.
+//! One lazily built reqwest client per upstream, so repeated calls reuse
+//! pooled keep-alive connections instead of paying a new TCP/TLS handshake.
+//!
+//! Timeouts (seconds) can be overridden with `COGNOS_HTTP_TIMEOUT` for
+//! `http.*`, downloads and Slack, and `COGNOS_LLM_TIMEOUT` for model providers.
+
+use reqwest::blocking::Client;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Service {
+    /// `http.get`/`http.post` and file downloads
+    Http,
+    Slack,
+    Anthropic,
+    /// OpenAI and OpenAI-compatible endpoints
+    OpenAi,
+    /// Local Ollama server (chat and embeddings)
+    Ollama,
+}
+
+impl Service {
+    const ALL: [Service; 5] = [Service::Http, Service::Slack, Service::Anthropic, Service::OpenAi, Service::Ollama];
+
+    fn index(self) -> usize {
+        Self::ALL.iter().position(|s| *s == self).unwrap()
+    }
+
+    /// Request timeout: the service default unless overridden by env var.
+    pub fn timeout(self) -> Duration {
+        let (var, default) = match self {
+            Service::Http | Service::Slack => ("COGNOS_HTTP_TIMEOUT", 60),
+            Service::Anthropic | Service::OpenAi => ("COGNOS_LLM_TIMEOUT", 120),
+            // Cold-loading a local model can take minutes
+            Service::Ollama => ("COGNOS_LLM_TIMEOUT", 600),
+        };
+        let secs = std::env::var(var).ok()
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .filter(|s| *s > 0)
+            .unwrap_or(default);
+        Duration::from_secs(secs)
+    }
+}
+
+static CLIENTS: [OnceLock<Client>; 5] = [const { OnceLock::new() }; 5];
+
+/// The shared client for `service`, built on first use.
+/// Cloning a reqwest client is cheap; clones share the connection pool.
+pub fn client(service: Service) -> Client {
+    CLIENTS[service.index()].get_or_init(|| build(service, service.timeout())).clone()
+}
+
+/// Clients with a timeout of their own, e.g. a `cognos.toml` provider's.
+static CUSTOM: Mutex<Vec<(Service, Duration, Client)>> = Mutex::new(Vec::new());
+
+/// The client for `service`, or for `timeout` instead of the service's
+/// own when given. Each distinct timeout gets one shared client.
+pub fn client_for(service: Service, timeout: Option<Duration>) -> Client {
+    let Some(timeout) = timeout else { return client(service) };
+    let mut custom = CUSTOM.lock().unwrap();
+    if let Some((_, _, client)) = custom.iter().find(|(s, t, _)| *s == service && *t == timeout) {
+        return client.clone();
+    }
+    let client = build(service, timeout);
+    custom.push((service, timeout, client.clone()));
+    client
+}
+
+fn build(service: Service, timeout: Duration) -> Client {
+    log::debug!("building HTTP client for {:?} (timeout {:?})", service, timeout);
+    Client::builder()
+        .timeout(timeout)
+        .connect_timeout(CONNECT_TIMEOUT)
+        .pool_idle_timeout(IDLE_TIMEOUT)
+        .tcp_keepalive(IDLE_TIMEOUT)
+        .build()
+        .unwrap_or_else(|_| Client::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_is_shared() {
+        let (url, server) = serve_keep_alive(3);
+        for _ in 0..3 {
+            let body = client(Service::Http).get(&url).send().unwrap().text().unwrap();
+            assert_eq!(body, "ok");
+        }
+        assert_eq!(server.join().unwrap(), 1, "requests should reuse one connection");
+    }
+
+    /// Serve `requests` keep-alive responses, returning the URL and a handle
+    /// that yields how many connections were accepted.
+    fn serve_keep_alive(requests: usize) -> (String, std::thread::JoinHandle<usize>) {
+        use std::io::{BufRead, BufReader, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let mut served = 0;
+            let mut connections = 0;
+            while served < requests {
+                let (stream, _) = listener.accept().unwrap();
+                connections += 1;
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut writer = stream;
+                while served < requests {
+                    let mut line = String::new();
+                    // Read the request head; a closed connection means a new one is coming
+                    let mut closed = false;
+                    loop {
+                        line.clear();
+                        if reader.read_line(&mut line).unwrap_or(0) == 0 { closed = true; break; }
+                        if line == "\r\n" { break; }
+                    }
+                    if closed { break; }
+                    writer.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").unwrap();
+                    served += 1;
+                }
+            }
+            connections
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn test_timeout_env_override() {
+        assert_eq!(Service::Anthropic.timeout(), Duration::from_secs(120));
+        std::env::set_var("COGNOS_HTTP_TIMEOUT", "5");
+        assert_eq!(Service::Slack.timeout(), Duration::from_secs(5));
+        std::env::set_var("COGNOS_HTTP_TIMEOUT", "junk");
+        assert_eq!(Service::Slack.timeout(), Duration::from_secs(60));
+        std::env::remove_var("COGNOS_HTTP_TIMEOUT");
+    }
+}
@@ -1,11 +1,14 @@
-/// Tree-walking interpreter for Cognos.
-/// Executes a parsed AST directly — no kernel needed.
+//! Tree-walking interpreter for Cognos.
+//! Executes a parsed AST directly — no kernel needed.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}};
 use crate::ast::*;
 use crate::environment::{Env, RealEnv};
+use crate::error::{CognosError, ErrorKind};
+use crate::http_client::Service;
 use crate::memory::MemoryStore;
+use crate::providers::{self, Capabilities, ChatRequest, ChatResponse, OllamaOptions, TokenLogprob};
 use crate::trace::{Tracer, TraceEvent};
 use anyhow::{bail, Result};
 
@@ -17,22 +20,73 @@ pub enum Value {
     Float(f64),
     Bool(bool),
     List(Vec<Value>),
-    Map(Vec<(std::string::String, Value)>),  // ordered key-value pairs
+    Map(Entries),
     Handle(Handle),
     Module(std::string::String),
     Future(u64),
+    Shared(Shared),
+    Lambda(Lambda),
     None,
 }
 
+/// A map's entries, in insertion order. Setting a key that is already
+/// there replaces its value and keeps its place, so a key appears once.
+pub type Entries = indexmap::IndexMap<std::string::String, Value>;
+
+/// A map made with `shared()`, for state that parallel and select branches
+/// and async tasks change together. Copies of the value share one store, so
+/// branches see each other's writes; every method takes the lock for its
+/// whole read-modify-write.
+#[derive(Debug, Clone, Default)]
+pub struct Shared(Arc<Mutex<Entries>>);
+
+thread_local! {
+    /// Shared maps this thread is inside `update()` of, by address.
+    static UPDATING: std::cell::RefCell<Vec<usize>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+impl Shared {
+    fn id(&self) -> usize {
+        Arc::as_ptr(&self.0) as *const () as usize
+    }
+
+    /// The entries, locked. Using a map from inside its own `update()` flow
+    /// would wait on itself forever, so that is an error instead.
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, Entries>> {
+        if UPDATING.with(|u| u.borrow().contains(&self.id())) {
+            bail!("a shared map can't be used inside its own update() flow; return the new value instead");
+        }
+        Ok(self.0.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    fn entries(&self) -> Entries {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+/// `|x| x * 2`. The variables its body reads are copied in when it is made,
+/// so it behaves the same wherever it is called from.
+#[derive(Debug, Clone)]
+pub struct Lambda {
+    params: Vec<Name>,
+    body: Arc<Expr>,
+    captured: Arc<HashMap<Name, Value>>,
+}
+
 #[derive(Debug, Clone)]
 pub enum Handle {
     Stdin,
     Stdout,
     File(std::string::String),
+    /// `file(path, mode="append")`, or a file inside `with`: writes add to
+    /// the end instead of replacing the contents
+    AppendFile(std::string::String),
     Channel {
         provider: std::string::String,
         config: HashMap<std::string::String, std::string::String>,
     },
+    /// `mcp(...)`: a connected Model Context Protocol server
+    Mcp(Arc<crate::mcp::Client>),
 }
 
 impl std::fmt::Display for Value {
@@ -40,7 +94,7 @@ impl std::fmt::Display for Value {
         match self {
             Value::String(s) => write!(f, "{}", s),
             Value::Int(n) => write!(f, "{}", n),
-            Value::Float(n) => write!(f, "{}", n),
+            Value::Float(n) => write!(f, "{}", format_float(*n)),
             Value::Bool(b) => write!(f, "{}", b),
             Value::List(items) => {
                 write!(f, "[")?;
@@ -62,8 +116,15 @@ impl std::fmt::Display for Value {
             Value::Handle(Handle::Stdin) => write!(f, "stdin"),
             Value::Handle(Handle::Stdout) => write!(f, "stdout"),
             Value::Handle(Handle::File(path)) => write!(f, "file(\"{}\")", path),
+            Value::Handle(Handle::AppendFile(path)) => write!(f, "file(\"{}\", mode=\"append\")", path),
             Value::Handle(Handle::Channel { ref provider, .. }) => write!(f, "channel(\"{}\")", provider),
+            Value::Handle(Handle::Mcp(ref client)) => write!(f, "mcp(\"{}\")", client.label),
             Value::Future(id) => write!(f, "<future:{}>", id),
+            Value::Shared(shared) => write!(f, "shared({})", Value::Map(shared.entries())),
+            Value::Lambda(lambda) => write!(f, "{}", crate::pretty::pretty_expr(&Expr::Lambda {
+                params: lambda.params.clone(),
+                body: Box::new((*lambda.body).clone()),
+            })),
             Value::None => write!(f, "none"),
         }
     }
@@ -71,7 +132,7 @@ impl std::fmt::Display for Value {
 }
 
 impl Value {
-    fn is_truthy(&self) -> bool {
+    pub(crate) fn is_truthy(&self) -> bool {
         match self {
             Value::Bool(b) => *b,
             Value::String(s) => !s.is_empty(),
@@ -82,3812 +143,6172 @@ impl Value {
             Value::Handle(_) => true,
             Value::Module(_) => true,
             Value::Future(_) => true,
+            Value::Shared(shared) => !shared.entries().is_empty(),
+            Value::Lambda(_) => true,
             Value::None => false,
         }
     }
 
     /// Get a field from a Map value
-    fn get_field(&self, field: &str) -> Option<&Value> {
+    pub fn get_field(&self, field: &str) -> Option<&Value> {
         if let Value::Map(entries) = self {
-            entries.iter().find(|(k, _)| k == field).map(|(_, v)| v)
+            entries.get(field)
         } else {
             None
         }
     }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Value::String(s) => serde_json::Value::String(s.clone()),
+            Value::Int(n) => serde_json::json!(*n),
+            Value::Float(f) => serde_json::json!(*f),
+            Value::Bool(b) => serde_json::Value::Bool(*b),
+            Value::None => serde_json::Value::Null,
+            Value::List(items) => serde_json::Value::Array(items.iter().map(|v| v.to_json()).collect()),
+            Value::Map(pairs) => {
+                let mut map = serde_json::Map::new();
+                for (k, v) in pairs { map.insert(k.clone(), v.to_json()); }
+                serde_json::Value::Object(map)
+            }
+            Value::Handle(_) => serde_json::Value::String("<handle>".into()),
+            Value::Module(name) => serde_json::Value::String(format!("<module:{}>", name)),
+            Value::Future(id) => serde_json::Value::String(format!("<future:{}>", id)),
+            // Saved as its current contents; it loads back as a plain Map
+            Value::Shared(shared) => Value::Map(shared.entries()).to_json(),
+            Value::Lambda(_) => serde_json::Value::String(self.to_string()),
+        }
+    }
+
+    pub fn from_json(v: serde_json::Value) -> Value {
+        match v {
+            serde_json::Value::Null => Value::None,
+            serde_json::Value::Bool(b) => Value::Bool(b),
+            serde_json::Value::Number(n) => {
+                if let Some(i) = n.as_i64() {
+                    Value::Int(i)
+                } else {
+                    Value::Float(n.as_f64().unwrap_or(0.0))
+                }
+            }
+            serde_json::Value::String(s) => Value::String(s),
+            serde_json::Value::Array(arr) => {
+                Value::List(arr.into_iter().map(Value::from_json).collect())
+            }
+            serde_json::Value::Object(map) => {
+                Value::Map(map.into_iter().map(|(k, v)| (k, Value::from_json(v))).collect())
+            }
+        }
+    }
+}
+
+/// What `--watch-var` follows: the variables, and the value each had when
+/// last reported.
+#[derive(Clone, Default)]
+struct Watch {
+    names: Vec<Name>,
+    /// By call depth (0 for globals) and name.
+    seen: HashMap<(usize, Name), Value>,
+}
+
+/// A watched variable's change: a List that grew or shrank at the end by
+/// what was added or how much was dropped, anything else old and new value.
+fn describe_change(name: &str, before: Option<&Value>, now: &Value) -> std::string::String {
+    let Some(before) = before else { return format!("{} = {}", name, watch_preview(now)) };
+    match (before, now) {
+        (Value::List(old), Value::List(new)) if new.len() > old.len() && old.iter().zip(new).all(|(a, b)| value_eq(a, b)) => {
+            format!("{} += {}", name, watch_preview(&Value::List(new[old.len()..].to_vec())))
+        }
+        (Value::List(old), Value::List(new)) if new.len() < old.len() && new.iter().zip(old).all(|(a, b)| value_eq(a, b)) => {
+            format!("{} lost its last {} item(s), now {}", name, old.len() - new.len(), new.len())
+        }
+        _ => format!("{}: {} → {}", name, watch_preview(before), watch_preview(now)),
+    }
 }
 
+/// A value on one line, as JSON so strings show their quotes, cut to 80 chars.
+fn watch_preview(value: &Value) -> std::string::String {
+    let text = value.to_json().to_string();
+    if text.chars().count() <= 80 {
+        return text;
+    }
+    format!("{}…", text.chars().take(79).collect::<std::string::String>())
+}
+
+/// Structural equality: same type and same contents, Map keys in any order.
+/// Unlike comparing `to_string()`, `1` and `"1"` differ.
 fn value_eq(a: &Value, b: &Value) -> bool {
     match (a, b) {
         (Value::String(a), Value::String(b)) => a == b,
         (Value::Int(a), Value::Int(b)) => a == b,
         (Value::Float(a), Value::Float(b)) => a == b,
         (Value::Bool(a), Value::Bool(b)) => a == b,
+        (Value::None, Value::None) => true,
+        (Value::List(a), Value::List(b)) => a.len() == b.len() && a.iter().zip(b).all(|(x, y)| value_eq(x, y)),
+        (Value::Map(a), Value::Map(b)) => a.len() == b.len()
+            && a.iter().all(|(k, v)| b.get(k).is_some_and(|v2| value_eq(v, v2))),
+        (Value::Module(a), Value::Module(b)) => a == b,
+        (Value::Future(a), Value::Future(b)) => a == b,
+        (Value::Handle(_), Value::Handle(_)) => a.to_string() == b.to_string(),
+        (Value::Shared(a), Value::Shared(b)) => a.id() == b.id(),
+        (Value::Lambda(a), Value::Lambda(b)) => Arc::ptr_eq(&a.body, &b.body) && Arc::ptr_eq(&a.captured, &b.captured),
         _ => false,
     }
 }
 
-fn type_name(v: &Value) -> &'static str {
-    match v {
-        Value::String(_) => "String",
-        Value::Int(_) => "Int",
-        Value::Float(_) => "Float",
-        Value::Bool(_) => "Bool",
-        Value::List(_) => "List",
-        Value::Map(_) => "Map",
-        Value::Handle(_) => "Handle",
-        Value::Module(_) => "Module",
-        Value::Future(_) => "Future",
-        Value::None => "None",
+/// Whether `value` matches `pattern`, collecting what it binds in `bound`.
+/// `bound` is left empty when it doesn't match.
+fn pattern_matches(pattern: &Pattern, value: &Value, bound: &mut Vec<(Name, Value)>) -> bool {
+    let start = bound.len();
+    let matched = match (pattern, value) {
+        (Pattern::Wildcard, _) => true,
+        (Pattern::Bind(name), _) => {
+            bound.push((name.clone(), value.clone()));
+            true
+        }
+        (Pattern::Literal(literal), _) => {
+            let literal = match literal {
+                Expr::StringLit(s) => Value::String(s.clone()),
+                Expr::IntLit(n) => Value::Int(*n),
+                Expr::FloatLit(f) => Value::Float(*f),
+                Expr::BoolLit(b) => Value::Bool(*b),
+                _ => Value::None,
+            };
+            value_eq(&literal, value)
+        }
+        (Pattern::Map(entries), Value::Map(map)) => entries.iter()
+            .all(|(key, p)| map.get(key).is_some_and(|v| pattern_matches(p, v, bound))),
+        (Pattern::Map(_), _) => false,
+    };
+    if !matched {
+        bound.truncate(start);
     }
+    matched
 }
 
-fn op_str(op: &BinOp) -> &'static str {
-    match op {
-        BinOp::Add => "+", BinOp::Sub => "-", BinOp::Mul => "*", BinOp::Div => "/",
-        BinOp::Eq => "==", BinOp::NotEq => "!=",
-        BinOp::Lt => "<", BinOp::Gt => ">", BinOp::LtEq => "<=", BinOp::GtEq => ">=",
-        BinOp::And => "and", BinOp::Or => "or",
-        BinOp::In => "in", BinOp::NotIn => "not in", BinOp::Mod => "%",
+/// How a Float displays: rounded to 15 significant digits, so binary noise
+/// like 0.1 + 0.2 = 0.30000000000000004 shows as 0.3, with an exponent only
+/// for very large or small magnitudes. Never depends on the locale.
+fn format_float(n: f64) -> std::string::String {
+    if n.is_nan() {
+        return "nan".to_string();
+    }
+    if n.is_infinite() {
+        return if n > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+    if n == 0.0 {
+        return n.to_string();
+    }
+    let rounded: f64 = format!("{:.14e}", n).parse().unwrap_or(n);
+    if rounded.abs() >= 1e16 || rounded.abs() < 1e-6 {
+        format!("{:e}", rounded)
+    } else {
+        rounded.to_string()
     }
 }
 
-enum ControlFlow {
-    Normal,
-    Break,
-    Continue,
-    Return(Value),
+/// `a // b` for Ints: the quotient rounded down, not toward zero. None on
+/// overflow; `b` is not 0.
+fn floor_div(a: i64, b: i64) -> Option<i64> {
+    let q = a.checked_div(b)?;
+    Some(if a % b != 0 && (a < 0) != (b < 0) { q - 1 } else { q })
 }
 
-pub struct Interpreter {
-    vars: HashMap<std::string::String, Value>,
-    flows: HashMap<std::string::String, crate::ast::FlowDef>,
-    types: HashMap<std::string::String, crate::ast::TypeDef>,
-    env: Arc<Mutex<Box<dyn Env + Send>>>,
-    tracer: Option<Arc<Tracer>>,
-    import_stack: Vec<std::string::String>,
-    conversation_history: Vec<(std::string::String, std::string::String)>,
-    next_future_id: u64,
-    async_handles: HashMap<u64, (std::thread::JoinHandle<Result<Value>>, Arc<AtomicBool>)>,
-    cancelled: Arc<AtomicBool>,
-    memory: Option<Arc<MemoryStore>>,
+/// `a % b` for Ints: the remainder with the sign of `b`, so that
+/// `a == (a // b) * b + a % b`. It is 0 even where the quotient overflows;
+/// `b` is not 0.
+fn floor_mod(a: i64, b: i64) -> i64 {
+    let r = a.wrapping_rem(b);
+    if r != 0 && (r < 0) != (b < 0) { r + b } else { r }
 }
 
-impl Interpreter {
-    pub fn new() -> Self {
-        Self::with_options(false)
+/// `floor_mod` for Floats, the remainder `divmod()` gives.
+fn floor_mod_f64(a: f64, b: f64) -> f64 {
+    let r = a % b;
+    if r != 0.0 && (r < 0.0) != (b < 0.0) { r + b } else { r }
+}
+
+/// How an `http.*` call that failed for good ends. Without `retries=` an
+/// error status is still a response, and the call returns its body.
+fn http_failure(err: anyhow::Error, policy: &crate::retry::Policy) -> Result<Value> {
+    match err.downcast::<crate::retry::StatusError>() {
+        Ok(status) if policy.retries == 0 => Ok(Value::String(status.body)),
+        Ok(status) => Err(status.into()),
+        Err(err) => Err(err),
     }
+}
 
-    pub fn with_options(allow_shell: bool) -> Self {
-        Self::with_full_options(allow_shell, None)
+/// An Int or Float as f64; anything else as NaN.
+fn as_f64(v: &Value) -> f64 {
+    match v {
+        Value::Int(n) => *n as f64,
+        Value::Float(f) => *f,
+        _ => f64::NAN,
     }
+}
 
-    pub fn with_full_options(allow_shell: bool, tracer: Option<Arc<Tracer>>) -> Self {
-        Self::with_env(Box::new(RealEnv::new(allow_shell)), tracer)
+/// `int()` of a Float: toward zero, and an error rather than a clamp when
+/// there is no Int to give.
+fn float_to_int(f: f64) -> Result<Value> {
+    // i64::MAX as f64 rounds up to 2^63, which is already out of range
+    if f.is_nan() || f >= i64::MAX as f64 || f < i64::MIN as f64 {
+        bail!("cannot convert {} to Int", format_float(f));
     }
+    Ok(Value::Int(f as i64))
+}
 
-    pub fn with_env(env: Box<dyn Env + Send>, tracer: Option<Arc<Tracer>>) -> Self {
-        let mut vars = HashMap::new();
-        vars.insert("stdin".to_string(), Value::Handle(Handle::Stdin));
-        vars.insert("stdout".to_string(), Value::Handle(Handle::Stdout));
-        // math module removed (P11: lean core runtime)
-        vars.insert("http".to_string(), Value::Module("http".to_string()));
-        Self { vars, flows: HashMap::new(), types: HashMap::new(), env: Arc::from(Mutex::new(env)), tracer, import_stack: Vec::new(), conversation_history: Vec::new(), next_future_id: 0, async_handles: HashMap::new(), cancelled: Arc::new(AtomicBool::new(false)), memory: None }
+/// Apply an f-string format spec (`{x:.2f}`, `{name:>10}`, `{n:,d}`).
+fn format_with_spec(val: &Value, spec: &crate::ast::FormatSpec) -> Result<std::string::String> {
+    let number = match val {
+        Value::Int(n) => Some(*n as f64),
+        Value::Float(f) => Some(*f),
+        _ => None,
+    };
+    let body = match (spec.kind, val, number) {
+        (Some('d'), Value::Int(n), _) => n.unsigned_abs().to_string(),
+        (Some('d'), _, _) => bail!("format spec 'd' requires an Int, got {} ({})", val, type_name(val)),
+        // nan and inf have no digits to format; the sign comes from below
+        (Some('f' | 'e' | '%') | None, Value::Float(f), _) if !f.is_finite() => format_float(f.abs()),
+        (Some('f'), _, Some(x)) => format!("{:.*}", spec.precision.unwrap_or(6), x.abs()),
+        (Some('e'), _, Some(x)) => format!("{:.*e}", spec.precision.unwrap_or(6), x.abs()),
+        (Some('%'), _, Some(x)) => format!("{:.*}%", spec.precision.unwrap_or(6), x.abs() * 100.0),
+        (Some(kind @ ('f' | 'e' | '%')), _, None) => bail!("format spec '{}' requires a number, got {} ({})", kind, val, type_name(val)),
+        // A precision on a plain number means that many decimals
+        (None, _, Some(x)) if spec.precision.is_some() => format!("{:.*}", spec.precision.unwrap_or(0), x.abs()),
+        (None, Value::Int(n), _) => n.unsigned_abs().to_string(),
+        (None, Value::Float(f), _) => format_float(f.abs()),
+        // Strings (and `s`): precision truncates
+        _ => {
+            let text = val.to_string();
+            match spec.precision {
+                Some(max) => text.chars().take(max).collect(),
+                None => text,
+            }
+        }
+    };
+    let is_number = number.is_some() && spec.kind != Some('s');
+    let mut body = if is_number && spec.grouping { group_thousands(&body) } else { body };
+    let sign = match number {
+        Some(x) if is_number && x < 0.0 => "-",
+        Some(_) if is_number && spec.sign => "+",
+        _ => "",
+    };
+    let width = spec.width.unwrap_or(0);
+    let len = sign.chars().count() + body.chars().count();
+    if spec.zero && is_number && spec.align.is_none() && len < width {
+        body = format!("{}{}", "0".repeat(width - len), body);
     }
+    let text = format!("{}{}", sign, body);
+    let pad = width.saturating_sub(text.chars().count());
+    let fill = spec.fill.unwrap_or(' ').to_string();
+    Ok(match spec.align.unwrap_or(if is_number { '>' } else { '<' }) {
+        '>' => format!("{}{}", fill.repeat(pad), text),
+        '^' => format!("{}{}{}", fill.repeat(pad / 2), text, fill.repeat(pad - pad / 2)),
+        _ => format!("{}{}", text, fill.repeat(pad)),
+    })
+}
 
-    pub fn set_memory(&mut self, store: MemoryStore) {
-        self.memory = Some(Arc::new(store));
+/// `1234567.5` → `1,234,567.5`: commas in the integer digits only.
+fn group_thousands(digits: &str) -> std::string::String {
+    let split = digits.find(|c: char| !c.is_ascii_digit()).unwrap_or(digits.len());
+    let (int, rest) = digits.split_at(split);
+    let mut out = std::string::String::new();
+    for (i, c) in int.chars().enumerate() {
+        if i > 0 && (int.len() - i) % 3 == 0 {
+            out.push(',');
+        }
+        out.push(c);
     }
+    out.push_str(rest);
+    out
+}
 
-    fn get_memory(&self) -> Result<&MemoryStore> {
-        self.memory.as_ref().map(|m| m.as_ref())
-            .ok_or_else(|| anyhow::anyhow!("memory not enabled. Use --memory-db <path> or --memory to enable"))
+/// Limits for `pprint()` and `emit(..., pretty=true)`; `None` is unlimited.
+#[derive(Clone, Copy, Default)]
+struct PrettyOptions {
+    depth: Option<usize>,
+    max_items: Option<usize>,
+    max_string: Option<usize>,
+    color: bool,
+}
+
+/// Widest a List or Map of plain values can be and still print on one line.
+const PRETTY_WIDTH: usize = 80;
+
+/// Indented, optionally colored rendering of nested values. A top-level
+/// String prints as-is, like `emit()`; nested Strings are quoted.
+fn pretty_value(value: &Value, opts: PrettyOptions) -> std::string::String {
+    let mut out = std::string::String::new();
+    match value {
+        Value::String(s) => out.push_str(&truncate_chars(s, opts.max_string)),
+        _ => pretty_into(&mut out, value, opts, 0),
     }
+    out
+}
 
-    pub fn load_session(&mut self, path: &str) -> anyhow::Result<()> {
-        let content = std::fs::read_to_string(path)
-            .map_err(|e| anyhow::anyhow!("cannot load session '{}': {}", path, e))?;
-        let json: serde_json::Value = serde_json::from_str(&content)?;
-        if let Some(obj) = json.as_object() {
-            for (k, v) in obj {
-                self.vars.insert(k.clone(), self.json_to_value(v.clone()));
-            }
+fn pretty_into(out: &mut std::string::String, value: &Value, opts: PrettyOptions, level: usize) {
+    use crate::diagnostics::Style;
+    let entries = match value {
+        Value::List(items) => items.iter().map(|v| (None, v.clone())).collect::<Vec<_>>(),
+        Value::Map(entries) => entries.iter().map(|(k, v)| (Some(k.as_str()), v.clone())).collect(),
+        Value::Shared(shared) => {
+            out.push_str("shared(");
+            pretty_into(out, &Value::Map(shared.entries()), opts, level);
+            out.push(')');
+            return;
+        }
+        _ => {
+            out.push_str(&pretty_scalar(value, opts));
+            return;
+        }
+    };
+    let (open, close, noun) = if matches!(value, Value::List(_)) { ("[", "]", "item") } else { ("{", "}", "key") };
+    if entries.is_empty() {
+        out.push_str(open);
+        out.push_str(close);
+        return;
+    }
+    if opts.depth.is_some_and(|depth| level >= depth) {
+        let plural = if entries.len() == 1 { "" } else { "s" };
+        out.push_str(&styled(&format!("{}… {} {}{}{}", open, entries.len(), noun, plural, close), Style::Dim, opts));
+        return;
+    }
+    let shown = opts.max_items.map_or(entries.len(), |max| max.min(entries.len()));
+    let hidden = entries.len() - shown;
+    let render = |key: Option<&str>, v: &Value, opts: PrettyOptions, level: usize| {
+        let mut item = std::string::String::new();
+        if let Some(key) = key {
+            item.push_str(&styled(&serde_json::to_string(key).unwrap_or_default(), Style::Hint, opts));
+            item.push_str(": ");
+        }
+        pretty_into(&mut item, v, opts, level);
+        item
+    };
+    let more = (hidden > 0).then(|| format!("… {} more", hidden));
+
+    // Short runs of plain values stay on one line
+    let flat = entries.iter().all(|(_, v)| !matches!(v, Value::List(_) | Value::Map(_) | Value::Shared(_)));
+    if flat {
+        let plain = PrettyOptions { color: false, ..opts };
+        let mut width = level * 2 + 2;
+        for (key, v) in &entries[..shown] {
+            width += render(*key, v, plain, level + 1).chars().count() + 2;
+        }
+        if width + more.as_ref().map_or(0, |m| m.chars().count()) <= PRETTY_WIDTH {
+            let mut parts: Vec<std::string::String> = entries[..shown].iter().map(|(key, v)| render(*key, v, opts, level + 1)).collect();
+            parts.extend(more.map(|m| styled(&m, Style::Dim, opts)));
+            out.push_str(&format!("{}{}{}", open, parts.join(", "), close));
+            return;
         }
-        log::info!("Loaded session from {}", path);
-        Ok(())
     }
 
-    pub fn save_session(&self, path: &str) -> anyhow::Result<()> {
-        let mut map = serde_json::Map::new();
-        for (k, v) in &self.vars {
-            // Skip builtins
-            match k.as_str() {
-                "stdin" | "stdout" | "http" => continue,
-                _ => {}
-            }
-            map.insert(k.clone(), self.value_to_json(v));
+    let pad = "  ".repeat(level + 1);
+    out.push_str(open);
+    out.push('\n');
+    for (i, (key, v)) in entries[..shown].iter().enumerate() {
+        out.push_str(&pad);
+        out.push_str(&render(*key, v, opts, level + 1));
+        if i + 1 < shown || more.is_some() {
+            out.push(',');
         }
-        std::fs::write(path, serde_json::to_string_pretty(&serde_json::Value::Object(map))?)?;
-        log::info!("Saved session to {}", path);
-        Ok(())
+        out.push('\n');
     }
-
-    pub fn captured_stdout(&self) -> Option<Vec<String>> {
-        self.env.lock().unwrap().captured_stdout()
+    if let Some(more) = more {
+        out.push_str(&pad);
+        out.push_str(&styled(&more, Style::Dim, opts));
+        out.push('\n');
     }
+    out.push_str(&"  ".repeat(level));
+    out.push_str(close);
+}
 
-    fn trace(&self, event: TraceEvent) {
-        if let Some(ref tracer) = self.tracer {
-            tracer.emit(event);
+fn pretty_scalar(value: &Value, opts: PrettyOptions) -> std::string::String {
+    use crate::diagnostics::Style;
+    match value {
+        Value::String(s) => {
+            let text = serde_json::to_string(&truncate_chars(s, opts.max_string)).unwrap_or_default();
+            styled(&text, Style::Literal, opts)
         }
+        Value::Int(_) | Value::Float(_) | Value::Bool(_) | Value::None => styled(&value.to_string(), Style::Number, opts),
+        _ => styled(&value.to_string(), Style::Dim, opts),
     }
+}
 
-    fn is_full_trace(&self) -> bool {
-        self.tracer.as_ref().map(|t| t.level == crate::trace::TraceLevel::Full).unwrap_or(false)
+/// `s` cut to `max` characters, marked with `…` when shortened.
+fn truncate_chars(s: &str, max: Option<usize>) -> std::string::String {
+    match max {
+        Some(max) if s.chars().count() > max => format!("{}…", s.chars().take(max).collect::<std::string::String>()),
+        _ => s.to_string(),
     }
+}
 
-    fn trace_llm(&self, model: &str, provider: &str, latency_ms: u64, prompt: &str, system: &str, response: &str, has_tool_calls: bool) {
-        let full = self.is_full_trace();
-        self.trace(TraceEvent::LlmCall {
-            model: model.to_string(), provider: provider.to_string(),
-            latency_ms, prompt_chars: prompt.len(), response_chars: response.len(),
-            has_tool_calls, error: None,
-            prompt: if full { Some(prompt.to_string()) } else { None },
-            response: if full { Some(response.to_string()) } else { None },
-            system: if full { Some(system.to_string()) } else { None },
-        });
+fn styled(text: &str, style: crate::diagnostics::Style, opts: PrettyOptions) -> std::string::String {
+    if opts.color {
+        crate::diagnostics::paint(crate::diagnostics::Stream::Stdout, style, text)
+    } else {
+        text.to_string()
     }
+}
 
-    pub fn run(&mut self, program: &Program) -> Result<()> {
-        self.run_with_base(program, None)
+/// Walk `path` ("user.name", "items.0.id") through nested Maps and Lists.
+/// Numeric segments index Lists (negative from the end); anything missing
+/// or of the wrong type along the way gives `None`.
+fn lookup_path<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').filter(|seg| !seg.is_empty()).try_fold(value, |current, seg| match current {
+        Value::Map(_) => current.get_field(seg),
+        Value::List(items) => {
+            let i: i64 = seg.parse().ok()?;
+            let i = if i < 0 { items.len() as i64 + i } else { i };
+            usize::try_from(i).ok().and_then(|i| items.get(i))
+        }
+        _ => None,
+    })
+}
+
+fn type_name(v: &Value) -> &'static str {
+    match v {
+        Value::String(_) => "String",
+        Value::Int(_) => "Int",
+        Value::Float(_) => "Float",
+        Value::Bool(_) => "Bool",
+        Value::List(_) => "List",
+        Value::Map(_) => "Map",
+        Value::Handle(_) => "Handle",
+        Value::Module(_) => "Module",
+        Value::Future(_) => "Future",
+        Value::Shared(_) => "Shared",
+        Value::Lambda(_) => "Lambda",
+        Value::None => "None",
     }
+}
 
-    pub fn run_with_base(&mut self, program: &Program, base_path: Option<&std::path::Path>) -> Result<()> {
-        // Resolve imports
-        for import_path in &program.imports {
-            let resolved = if let Some(base) = base_path {
-                base.parent().unwrap_or(base).join(import_path)
-            } else {
-                std::path::PathBuf::from(import_path)
+/// Model for LLM builtins called without `model=`: `COGNOS_MODEL`, else qwen2.5:7b.
+pub fn default_model() -> std::string::String {
+    std::env::var("COGNOS_MODEL").unwrap_or_else(|_| "qwen2.5:7b".to_string())
+}
+
+/// The kind a caught error has for `catch Kind as e:`, from the typed
+/// errors in its chain; what the message says never decides it. Anything
+/// else is a RuntimeError.
+fn error_kind(err: &anyhow::Error) -> &'static str {
+    use crate::retry::{RequestFailed, StatusError};
+    let by_status = |status: u16| match status {
+        429 => "RateLimitError",
+        500..=599 => "NetworkError",
+        _ => "RuntimeError",
+    };
+    for cause in err.chain() {
+        if let Some(e) = cause.downcast_ref::<CognosError>() {
+            return match e.kind {
+                ErrorKind::Parse => "ParseError",
+                ErrorKind::Type => "TypeError",
+                ErrorKind::Runtime => "RuntimeError",
             };
-            let canonical = resolved.canonicalize()
-                .unwrap_or_else(|_| resolved.clone())
-                .to_string_lossy().to_string();
-            if self.import_stack.contains(&canonical) {
-                bail!("circular import detected: '{}' is already being imported", import_path);
-            }
-            self.import_stack.push(canonical.clone());
-            log::info!("Importing {:?}", resolved);
-            let source = std::fs::read_to_string(&resolved)
-                .map_err(|e| anyhow::anyhow!("cannot import '{}': {}", import_path, e))?;
-            let mut lexer = crate::lexer::Lexer::new(&source);
-            let tokens = lexer.tokenize();
-            let mut parser = crate::parser::Parser::new(tokens);
-            let imported = parser.parse_program()
-                .map_err(|e| anyhow::anyhow!("error in '{}': {}", import_path, e))?;
-            // Recursively resolve imports in the imported file
-            self.run_with_base(&Program {
-                imports: imported.imports,
-                types: imported.types,
-                flows: vec![], // don't run flows from imports
-            }, Some(&resolved))?;
-            // Register imported flows
-            for flow in &imported.flows {
-                log::info!("Imported flow '{}'", flow.name);
-                self.flows.insert(flow.name.clone(), flow.clone());
-            }
-            self.import_stack.pop();
         }
-
-        // Register all types
-        for td in &program.types {
-            log::info!("Registered type '{}'", td.name());
-            self.types.insert(td.name().to_string(), td.clone());
+        if let Some(e) = cause.downcast_ref::<StatusError>() {
+            return by_status(e.status);
         }
-
-        // Register all flows
-        for flow in &program.flows {
-            self.flows.insert(flow.name.clone(), flow.clone());
+        if let Some(e) = cause.downcast_ref::<RequestFailed>() {
+            return if e.transient { "NetworkError" } else { "RuntimeError" };
         }
-
-        // Find "main" flow, or use the first one
-        let flow = program.flows.iter()
-            .find(|f| f.name == "main")
-            .or_else(|| program.flows.first())
-            .cloned();
-
-        match flow {
-            Some(f) => {
-                // Bind flow parameters — in CLI mode, read from stdin
-                log::info!("Running flow '{}'", f.name);
-                for param in &f.params {
-                    log::debug!("Reading param '{}' from stdin", param.name);
-                    let val = self.env.lock().unwrap().read_stdin()?;
-                    log::debug!("  {} = {:?}", param.name, val);
-                    self.vars.insert(param.name.clone(), Value::String(val));
-                }
-                self.run_block(&f.body)?;
-                Ok(())
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            if e.is_timeout() || e.is_connect() {
+                return "NetworkError";
             }
-            None => Ok(()),
+            return e.status().map_or("RuntimeError", |s| by_status(s.as_u16()));
+        }
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            return if crate::retry::transient_io(e) { "NetworkError" } else { "IOError" };
         }
     }
+    "RuntimeError"
+}
 
-    /// Register a type (for REPL use)
-    pub fn register_type(&mut self, td: crate::ast::TypeDef) {
-        self.types.insert(td.name().to_string(), td);
-    }
+/// The label in `labels` that `answer` names, ignoring case, surrounding
+/// whitespace, quotes and trailing punctuation.
+fn match_label<'a>(answer: &str, labels: &'a [std::string::String]) -> Option<&'a std::string::String> {
+    let answer = answer.trim().trim_matches(|c: char| c == '"' || c == '\'' || c == '`' || c == '.' || c.is_whitespace());
+    labels.iter().find(|l| l.eq_ignore_ascii_case(answer))
+}
 
-    /// Register a flow (for REPL use)
-    pub fn register_flow(&mut self, flow: crate::ast::FlowDef) {
-        self.flows.insert(flow.name.clone(), flow);
-    }
+/// Per-call LLM settings that `think()` kwargs and `cognos.toml` flow
+/// overrides can set.
+#[derive(Clone, Debug, Default)]
+struct CallOptions {
+    temperature: Option<f64>,
+    /// Ask the provider itself for a JSON reply (`json_mode=true`).
+    json: bool,
+    /// `stream=true`: take the reply as it's generated, a piece at a time.
+    stream: bool,
+    /// `on_token=`: the flow each streamed piece goes to, instead of stdout.
+    on_token: Option<std::string::String>,
+    /// `retries=`, over `--llm-retries`.
+    retries: Option<u32>,
+    /// `backoff=`: how the wait between retries grows.
+    backoff: Option<crate::retry::Backoff>,
+    /// `files=`: ids from `file_upload()` to attach to the prompt.
+    files: Vec<std::string::String>,
+    /// `endpoint=`: an OpenAI-compatible server to send the call to,
+    /// whatever the model's name would route to.
+    endpoint: Option<std::string::String>,
+    /// `api_key=`: the key for `endpoint=`.
+    api_key: Option<std::string::String>,
+    /// `logprobs=true`: return the reply's token log probabilities.
+    logprobs: bool,
+    /// The JSON Schema of a `format=` type, for providers that enforce one.
+    schema: Option<serde_json::Value>,
+}
 
-    /// Call a flow with no args, keeping current vars (for REPL use)
-    pub fn call_flow_entry(&mut self, name: &str) -> Result<()> {
-        let flow = self.flows.get(name).cloned()
-            .ok_or_else(|| anyhow::anyhow!("unknown flow: {}", name))?;
-        self.run_block(&flow.body)?;
-        Ok(())
+/// What a parallel or select branch changed, relative to the snapshot it
+/// started from.
+struct BranchChanges {
+    /// Variables that are new, or whose value differs by `value_eq`.
+    vars: HashMap<Name, Value>,
+    /// Conversation turns the branch added.
+    history: Vec<(std::string::String, std::string::String)>,
+}
+
+impl BranchChanges {
+    fn between(vars: &HashMap<Name, Value>, history: &[(std::string::String, std::string::String)], branch: Interpreter) -> Self {
+        let changed = branch.vars.into_iter()
+            .filter(|(k, v)| vars.get(k).is_none_or(|old| !value_eq(old, v)))
+            .collect();
+        // A branch that cleared the history contributes everything it has since
+        let added = match branch.conversation_history.strip_prefix(history) {
+            Some(added) => added.to_vec(),
+            None => branch.conversation_history,
+        };
+        BranchChanges { vars: changed, history: added }
     }
 
-    /// Call a user-defined flow with positional and keyword arguments
-    fn call_flow(&mut self, name: &str, args: Vec<Value>, kwargs: Vec<(std::string::String, Value)>) -> Result<Value> {
-        let flow = self.flows.get(name).cloned()
-            .ok_or_else(|| anyhow::anyhow!("unknown flow: {}", name))?;
+    /// A named branch's variables, gathered into one map under its name.
+    fn namespaced(self, name: &Name) -> Self {
+        let mut entries: Entries = self.vars.into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect();
+        entries.sort_keys();
+        BranchChanges { vars: HashMap::from([(name.clone(), Value::Map(entries))]), history: self.history }
+    }
+}
 
-        // Build parameter bindings from positional args + kwargs
-        let mut bindings: HashMap<std::string::String, Value> = HashMap::new();
+/// Flows and types a file offers its importers: its own, plus everything it
+/// imports.
+#[derive(Debug, Clone, Default)]
+struct Exports {
+    /// By name: the canonical path of the defining file, and the flow.
+    flows: HashMap<std::string::String, (std::string::String, FlowDef)>,
+    types: HashMap<std::string::String, TypeDef>,
+}
 
-        // First, bind positional args in order
-        if args.len() > flow.params.len() {
-            bail!("{}() expects {} args, got {}", name, flow.params.len(), args.len());
-        }
-        for (i, val) in args.iter().enumerate() {
-            bindings.insert(flow.params[i].name.clone(), val.clone());
+impl Exports {
+    fn insert_flow(&mut self, source: &str, flow: FlowDef) {
+        if let Some((previous, _)) = self.flows.get(&flow.name).filter(|(p, _)| p != source) {
+            crate::diagnostics::warning(format!("flow '{}' is defined in both '{}' and '{}'; using the latter", flow.name, previous, source));
         }
+        self.flows.insert(flow.name.clone(), (source.to_string(), flow));
+    }
 
-        // Then, bind kwargs by name
-        for (k, v) in &kwargs {
-            // Check kwarg name is a valid parameter
-            if !flow.params.iter().any(|p| &p.name == k) {
-                bail!("{}(): unknown keyword argument '{}'", name, k);
-            }
-            // Check for duplicate (already bound by positional)
-            if bindings.contains_key(k) {
-                bail!("{}(): duplicate argument for '{}'", name, k);
-            }
-            bindings.insert(k.clone(), v.clone());
+    fn merge(&mut self, other: Exports) {
+        for (_, (source, flow)) in other.flows {
+            self.insert_flow(&source, flow);
         }
+        self.types.extend(other.types);
+    }
 
-        // Check all params are bound; use defaults if available
-        for param in &flow.params {
-            if !bindings.contains_key(&param.name) {
-                if let Some(ref default_expr) = param.default {
-                    let val = self.eval(default_expr)?;
-                    bindings.insert(param.name.clone(), val);
-                } else {
-                    bail!("{}(): missing required argument '{}'", name, param.name);
-                }
-            }
-        }
+    /// Everything, or just `names` and the flows and types they use, which
+    /// have to come along for the named ones to work.
+    fn select(&self, names: Option<&[std::string::String]>, path: &str) -> Result<Exports> {
+        let Some(names) = names else { return Ok(self.clone()) };
+        let mut selected = Exports::default();
+        let mut pending: Vec<std::string::String> = Vec::new();
+        for name in names {
+            if !self.flows.contains_key(name) && !self.types.contains_key(name) {
+                bail!("'{}' is not defined in '{}'", name, path);
+            }
+            pending.push(name.clone());
+        }
+        while let Some(name) = pending.pop() {
+            let mut used = HashSet::new();
+            if let Some((source, flow)) = self.flows.get(&name) {
+                if selected.flows.contains_key(&name) { continue; }
+                selected.flows.insert(name, (source.clone(), flow.clone()));
+                flow_names_used(flow, &mut used);
+            } else if let Some(td) = self.types.get(&name) {
+                if selected.types.contains_key(&name) { continue; }
+                selected.types.insert(name, td.clone());
+                type_def_names_used(td, &mut used);
+            }
+            pending.extend(used.into_iter().filter(|n| self.flows.contains_key(n) || self.types.contains_key(n)));
+        }
+        Ok(selected)
+    }
+}
 
-        // Save current vars, set up new scope (preserve builtins)
-        let saved_vars = self.vars.clone();
-        let mut new_vars = HashMap::new();
-        // Preserve builtins
-        for key in &["stdin", "stdout", "http"] {
-            if let Some(v) = saved_vars.get(*key) {
-                new_vars.insert(key.to_string(), v.clone());
-            }
-        }
-        for (k, v) in bindings {
-            new_vars.insert(k, v);
+/// Warn about `from ... import` names the program never mentions.
+fn warn_unused_imports(program: &Program) {
+    let mut used = HashSet::new();
+    for flow in &program.flows {
+        flow_names_used(flow, &mut used);
+    }
+    for td in &program.types {
+        type_def_names_used(td, &mut used);
+    }
+    stmts_names_used(&program.setup, &mut used);
+    for import in &program.imports {
+        for name in import.names.iter().flatten().filter(|n| !used.contains(*n)) {
+            crate::diagnostics::warning(format!("'{}' is imported from '{}' but never used", name, import.path));
         }
-        self.vars = new_vars;
+    }
+}
 
-        log::info!("Calling flow '{}'", name);
-        let result = self.run_block(&flow.body)?;
+/// What `flows()` reports about a flow.
+fn flow_info(flow: &FlowDef) -> Value {
+    let params = flow.params.iter().map(|p| Value::Map(Entries::from([
+        ("name".to_string(), Value::String(p.name.to_string())),
+        ("type".to_string(), Value::String(crate::pretty::pretty_type(&p.ty))),
+        ("default".to_string(), p.default.as_ref().map_or(Value::None, |d| Value::String(crate::pretty::pretty_expr(d)))),
+        ("variadic".to_string(), Value::Bool(p.variadic)),
+    ]))).collect();
+    Value::Map(Entries::from([
+        ("name".to_string(), Value::String(flow.name.clone())),
+        ("description".to_string(), flow.description.clone().map_or(Value::None, Value::String)),
+        ("params".to_string(), Value::List(params)),
+        ("returns".to_string(), flow.return_type.as_ref().map_or(Value::None, |t| Value::String(crate::pretty::pretty_type(t)))),
+    ]))
+}
 
-        // Restore vars
-        self.vars = saved_vars;
+/// What `types()` reports about a type.
+fn type_info(td: &TypeDef) -> Value {
+    match td {
+        TypeDef::Struct { name, fields } => Value::Map(Entries::from([
+            ("name".to_string(), Value::String(name.clone())),
+            ("kind".to_string(), Value::String("struct".to_string())),
+            ("fields".to_string(), Value::List(fields.iter().map(|f| Value::Map(Entries::from([
+                ("name".to_string(), Value::String(f.name.clone())),
+                ("type".to_string(), Value::String(crate::pretty::pretty_type(&f.ty))),
+                ("optional".to_string(), Value::Bool(f.optional)),
+            ]))).collect())),
+        ])),
+        TypeDef::Enum { name, variants } => Value::Map(Entries::from([
+            ("name".to_string(), Value::String(name.clone())),
+            ("kind".to_string(), Value::String("enum".to_string())),
+            ("variants".to_string(), Value::List(variants.iter().cloned().map(Value::String).collect())),
+        ])),
+    }
+}
 
-        match result {
-            ControlFlow::Return(v) => Ok(v),
-            _ => Ok(Value::None),
+/// Names a flow may refer to: flows it calls, variables, types, and string
+/// literals, which can name flows too (`invoke("f")`, `tools=["f"]`).
+fn flow_names_used(flow: &FlowDef, used: &mut HashSet<std::string::String>) {
+    for param in &flow.params {
+        type_names_used(&param.ty, used);
+        if let Some(default) = &param.default {
+            expr_names_used(default, used);
         }
     }
+    if let Some(ty) = &flow.return_type {
+        type_names_used(ty, used);
+    }
+    stmts_names_used(&flow.body, used);
+}
 
-    fn run_block(&mut self, stmts: &[Stmt]) -> Result<ControlFlow> {
-        for stmt in stmts {
-            if self.cancelled.load(Ordering::Relaxed) {
-                return Ok(ControlFlow::Normal);
-            }
-            match self.run_stmt(stmt)? {
-                ControlFlow::Normal => {}
-                other => return Ok(other),
-            }
+fn type_def_names_used(td: &TypeDef, used: &mut HashSet<std::string::String>) {
+    if let TypeDef::Struct { fields, .. } = td {
+        for field in fields {
+            type_names_used(&field.ty, used);
         }
-        Ok(ControlFlow::Normal)
     }
+}
 
-    fn run_stmt(&mut self, stmt: &Stmt) -> Result<ControlFlow> {
-        match stmt {
-            Stmt::Pass => Ok(ControlFlow::Normal),
-
-            Stmt::Assign { name, expr } => {
-                let val = self.eval(expr)?;
-                self.vars.insert(name.clone(), val);
-                Ok(ControlFlow::Normal)
-            }
-
-            Stmt::Emit { value } => {
-                // emit(x) is sugar for write(stdout, x)
-                let val = self.eval(value)?;
-                println!("{}", val);
-                Ok(ControlFlow::Normal)
-            }
-
-            Stmt::Return { value } => {
-                let val = self.eval(value)?;
-                Ok(ControlFlow::Return(val))
-            }
-
-            Stmt::Break => Ok(ControlFlow::Break),
-            Stmt::Continue => Ok(ControlFlow::Continue),
-
-            Stmt::Expr(expr) => {
-                self.eval(expr)?;
-                Ok(ControlFlow::Normal)
-            }
+fn type_names_used(ty: &TypeExpr, used: &mut HashSet<std::string::String>) {
+    match ty {
+        TypeExpr::Named(name) => { used.insert(name.clone()); }
+        TypeExpr::Generic(name, args) => {
+            used.insert(name.clone());
+            for arg in args { type_names_used(arg, used); }
+        }
+        TypeExpr::Struct(fields) => {
+            for (_, ty) in fields { type_names_used(ty, used); }
+        }
+    }
+}
 
-            Stmt::If { condition, body, elifs, else_body } => {
-                let cond = self.eval(condition)?;
-                if cond.is_truthy() {
-                    return self.run_block(body);
-                }
-                for (elif_cond, elif_body) in elifs {
-                    let c = self.eval(elif_cond)?;
-                    if c.is_truthy() {
-                        return self.run_block(elif_body);
+fn stmts_names_used(stmts: &[Stmt], used: &mut HashSet<std::string::String>) {
+    for stmt in stmts {
+        match &stmt.kind {
+            StmtKind::AssignPath { path, expr, .. } => {
+                for step in path {
+                    if let PathStep::Index(index) = step {
+                        expr_names_used(index, used);
                     }
                 }
-                if !else_body.is_empty() {
-                    return self.run_block(else_body);
+                expr_names_used(expr, used);
+            }
+            StmtKind::Assign { expr, .. } | StmtKind::Emit { value: expr } | StmtKind::Return { value: expr } | StmtKind::Expr(expr) => {
+                expr_names_used(expr, used);
+            }
+            StmtKind::Break | StmtKind::Continue | StmtKind::Pass => {}
+            StmtKind::If { condition, body, elifs, else_body } => {
+                expr_names_used(condition, used);
+                stmts_names_used(body, used);
+                for (cond, body) in elifs {
+                    expr_names_used(cond, used);
+                    stmts_names_used(body, used);
                 }
-                Ok(ControlFlow::Normal)
+                stmts_names_used(else_body, used);
             }
-
-            Stmt::TryCatch { body, error_var, catch_body } => {
-                match self.run_block(body) {
-                    Ok(cf) => Ok(cf),
-                    Err(e) => {
-                        if let Some(var) = error_var {
-                            self.vars.insert(var.clone(), Value::String(format!("{}", e)));
-                        }
-                        self.run_block(catch_body)
-                    }
-                }
+            StmtKind::Loop { body, .. } => stmts_names_used(body, used),
+            StmtKind::While { condition, body } => {
+                expr_names_used(condition, used);
+                stmts_names_used(body, used);
             }
-
-            Stmt::For { var, value_var, iterable, body } => {
-                let collection = self.eval(iterable)?;
-                match (&collection, value_var) {
-                    (Value::Map(entries), Some(vv)) => {
-                        // for key, value in map:
-                        let entries = entries.clone();
-                        for (k, v) in entries {
-                            self.vars.insert(var.clone(), Value::String(k));
-                            self.vars.insert(vv.clone(), v);
-                            match self.run_block(body)? {
-                                ControlFlow::Break => break,
-                                ControlFlow::Continue => continue,
-                                ControlFlow::Return(v) => return Ok(ControlFlow::Return(v)),
-                                ControlFlow::Normal => {}
-                            }
-                        }
-                    }
-                    (Value::List(items), Some(vv)) => {
-                        // for index, value in list:
-                        let items = items.clone();
-                        for (i, item) in items.into_iter().enumerate() {
-                            self.vars.insert(var.clone(), Value::Int(i as i64));
-                            self.vars.insert(vv.clone(), item);
-                            match self.run_block(body)? {
-                                ControlFlow::Break => break,
-                                ControlFlow::Continue => continue,
-                                ControlFlow::Return(v) => return Ok(ControlFlow::Return(v)),
-                                ControlFlow::Normal => {}
-                            }
-                        }
-                    }
-                    (_, Some(_)) => bail!("two-variable for loop requires a Map or List"),
-                    _ => {
-                        // Single variable iteration
-                        let items: Vec<Value> = match collection {
-                            Value::List(items) => items,
-                            Value::Map(entries) => entries.into_iter()
-                                .map(|(k, _)| Value::String(k))
-                                .collect(),
-                            Value::String(s) => s.chars()
-                                .map(|c| Value::String(c.to_string()))
-                                .collect(),
-                            other => bail!("cannot iterate over {} (type: {})", other, type_name(&other)),
-                        };
-                        for item in items {
-                            self.vars.insert(var.clone(), item);
-                            match self.run_block(body)? {
-                                ControlFlow::Break => break,
-                                ControlFlow::Continue => continue,
-                                ControlFlow::Return(v) => return Ok(ControlFlow::Return(v)),
-                                ControlFlow::Normal => {}
-                            }
-                        }
-                    }
-                }
-                Ok(ControlFlow::Normal)
+            StmtKind::For { iterable, body, .. } => {
+                expr_names_used(iterable, used);
+                stmts_names_used(body, used);
             }
-
-            Stmt::Parallel { branches } => {
-                self.run_parallel(branches)?;
-                Ok(ControlFlow::Normal)
+            StmtKind::TryCatch { body, catches } => {
+                stmts_names_used(body, used);
+                for catch in catches { stmts_names_used(&catch.body, used); }
             }
-
-            Stmt::Select { branches } => {
-                self.run_select(branches)
+            StmtKind::Match { subject, arms } => {
+                expr_names_used(subject, used);
+                for arm in arms { stmts_names_used(&arm.body, used); }
+            }
+            StmtKind::Parallel { branches, .. } | StmtKind::Select { branches } => {
+                for branch in branches { stmts_names_used(&branch.body, used); }
             }
+            StmtKind::With { resource, body, .. } => {
+                expr_names_used(resource, used);
+                stmts_names_used(body, used);
+            }
+        }
+    }
+}
 
-            Stmt::Loop { max, body } => {
-                match max {
-                    Some(limit) => {
-                        for _ in 0..*limit {
-                            match self.run_block(body)? {
-                                ControlFlow::Break => break,
-                                ControlFlow::Continue => continue,
-                                ControlFlow::Return(v) => return Ok(ControlFlow::Return(v)),
-                                ControlFlow::Normal => {}
-                            }
-                        }
-                    }
-                    None => {
-                        loop {
-                            match self.run_block(body)? {
-                                ControlFlow::Break => break,
-                                ControlFlow::Continue => continue,
-                                ControlFlow::Return(v) => return Ok(ControlFlow::Return(v)),
-                                ControlFlow::Normal => {}
-                            }
-                        }
-                    }
+fn expr_names_used(expr: &Expr, used: &mut HashSet<std::string::String>) {
+    match expr {
+        Expr::Ident(name) => { used.insert(name.to_string()); }
+        Expr::StringLit(s) => { used.insert(s.clone()); }
+        Expr::IntLit(_) | Expr::FloatLit(_) | Expr::BoolLit(_) | Expr::NoneLiteral => {}
+        Expr::Call { name, args, kwargs } => {
+            used.insert(name.clone());
+            for arg in args { expr_names_used(arg, used); }
+            for (_, v) in kwargs { expr_names_used(v, used); }
+        }
+        Expr::MethodCall { object, args, kwargs, .. } => {
+            expr_names_used(object, used);
+            for arg in args { expr_names_used(arg, used); }
+            for (_, v) in kwargs { expr_names_used(v, used); }
+        }
+        Expr::Async(inner) | Expr::Field { object: inner, .. } | Expr::UnaryOp { operand: inner, .. } => {
+            expr_names_used(inner, used);
+        }
+        Expr::Index { object, index } => {
+            expr_names_used(object, used);
+            expr_names_used(index, used);
+        }
+        Expr::Slice { object, start, end } => {
+            expr_names_used(object, used);
+            for e in [start, end].into_iter().flatten() { expr_names_used(e, used); }
+        }
+        Expr::BinOp { left, right, .. } => {
+            expr_names_used(left, used);
+            expr_names_used(right, used);
+        }
+        Expr::Compare { left, rest } => {
+            expr_names_used(left, used);
+            for (_, e) in rest { expr_names_used(e, used); }
+        }
+        Expr::List(items) => {
+            for item in items { expr_names_used(item, used); }
+        }
+        Expr::Map(entries) => {
+            for (_, v) in entries { expr_names_used(v, used); }
+        }
+        Expr::FString(parts) => {
+            for part in parts {
+                match part {
+                    FStringPart::Literal(_) => {}
+                    FStringPart::Expr(e) | FStringPart::Formatted(e, _) => expr_names_used(e, used),
                 }
-                Ok(ControlFlow::Normal)
             }
         }
+        Expr::Lambda { body, .. } => expr_names_used(body, used),
     }
+}
 
-    fn run_parallel(&mut self, branches: &[Vec<Stmt>]) -> Result<()> {
-        // Each branch runs concurrently as a block of statements.
-        // Each branch gets a snapshot of current vars; new/changed vars are merged back.
-        let env = self.env.clone();
-        let flows = self.flows.clone();
-        let types = self.types.clone();
-        let vars = self.vars.clone();
-        let tracer = self.tracer.clone();
-        let memory = self.memory.clone();
+/// Order two `sort_by()` keys: numbers by value, Strings alphabetically.
+fn compare_keys(a: &Value, b: &Value) -> Result<std::cmp::Ordering> {
+    let number = |v: &Value| match v {
+        Value::Int(n) => Some(*n as f64),
+        Value::Float(f) => Some(*f),
+        _ => None,
+    };
+    match (a, b) {
+        (Value::Int(a), Value::Int(b)) => Ok(a.cmp(b)),
+        (Value::String(a), Value::String(b)) => Ok(a.cmp(b)),
+        _ => match (number(a), number(b)) {
+            (Some(a), Some(b)) => Ok(a.total_cmp(&b)),
+            _ => bail!(".sort_by() keys must be all numbers or all Strings, got {} and {}", type_name(a), type_name(b)),
+        },
+    }
+}
 
-        // Each branch returns its final vars (new/changed only)
-        let results: Vec<Result<HashMap<String, Value>>> = std::thread::scope(|s| {
-            let handles: Vec<_> = branches.iter().map(|branch| {
-                let env = env.clone();
-                let flows = flows.clone();
-                let types = types.clone();
-                let vars = vars.clone();
-                let tracer = tracer.clone();
-                let memory = memory.clone();
-                let branch = branch.clone();
-                s.spawn(move || {
-                    let mut interp = Interpreter {
-                        vars: vars.clone(),
-                        flows,
-                        types,
-                        env,
-                        tracer,
-                        import_stack: Vec::new(),
-                        conversation_history: Vec::new(),
-                        next_future_id: 0,
-                        async_handles: HashMap::new(),
-                        cancelled: Arc::new(AtomicBool::new(false)),
-                        memory: memory.clone(),
-                    };
-                    interp.run_block(&branch)?;
-                    // Return only new/changed vars
-                    let mut changed = HashMap::new();
-                    for (k, v) in &interp.vars {
-                        match vars.get(k) {
-                            None => { changed.insert(k.clone(), v.clone()); }
-                            Some(old) => {
-                                if old.to_string() != v.to_string() {
-                                    changed.insert(k.clone(), v.clone());
-                                }
-                            }
-                        }
-                    }
-                    Ok(changed)
-                })
-            }).collect();
+/// Whether `value` is, or contains, the future `id`.
+fn holds_future(value: &Value, id: u64) -> bool {
+    match value {
+        Value::Future(f) => *f == id,
+        Value::List(items) => items.iter().any(|v| holds_future(v, id)),
+        Value::Map(entries) => entries.iter().any(|(_, v)| holds_future(v, id)),
+        Value::Lambda(lambda) => lambda.captured.values().any(|v| holds_future(v, id)),
+        _ => false,
+    }
+}
 
-            handles.into_iter().map(|h| {
-                match h.join() {
-                    Ok(r) => r,
-                    Err(_) => Err(anyhow::anyhow!("parallel branch panicked")),
-                }
-            }).collect()
-        });
+/// The trace label for branch `index` of a parallel/select block, under
+/// whatever branch the block itself runs in.
+fn branch_label(parent: Option<&str>, index: usize, branch: &Branch) -> std::string::String {
+    let own = branch.name.as_ref().map_or_else(|| (index + 1).to_string(), |n| n.to_string());
+    match parent {
+        Some(parent) => format!("{}/{}", parent, own),
+        None => own,
+    }
+}
 
-        // Check for errors, merge results
-        let mut errors = Vec::new();
-        for result in results {
-            match result {
-                Ok(changed) => {
-                    for (k, v) in changed {
-                        self.vars.insert(k, v);
-                    }
-                }
-                Err(e) => errors.push(e),
+/// One LLM reply, before it becomes the caller's Value.
+struct LlmReply {
+    content: std::string::String,
+    tool_calls: Option<Vec<Value>>,
+    finish_reason: Option<std::string::String>,
+    /// Per-token log probabilities, when `logprobs=true` asked for them.
+    logprobs: Option<Vec<TokenLogprob>>,
+}
+
+impl LlmReply {
+    /// A String, or a Map when tools were offered or called or logprobs
+    /// asked for.
+    fn into_value(self, with_tools: bool) -> Value {
+        if self.tool_calls.is_none() && !with_tools && self.logprobs.is_none() {
+            return Value::String(self.content);
+        }
+        let mut entries = Entries::from([("content".to_string(), Value::String(self.content))]);
+        if with_tools || self.tool_calls.is_some() {
+            let has_tool_calls = self.tool_calls.is_some();
+            if let Some(calls) = self.tool_calls {
+                entries.insert("tool_calls".to_string(), Value::List(calls));
             }
+            entries.insert("has_tool_calls".to_string(), Value::Bool(has_tool_calls));
         }
-
-        if !errors.is_empty() {
-            let msgs: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
-            bail!("parallel block errors:\n  {}", msgs.join("\n  "));
+        entries.insert("finish_reason".to_string(), self.finish_reason.map_or(Value::None, Value::String));
+        if let Some(logprobs) = self.logprobs {
+            entries.extend(logprob_entries(&logprobs));
         }
+        Value::Map(entries)
+    }
+}
 
-        Ok(())
+/// The `logprobs` ({token, logprob} Maps) and `confidence` keys of a result Map.
+fn logprob_entries(logprobs: &[TokenLogprob]) -> [(std::string::String, Value); 2] {
+    let tokens = logprobs.iter().map(|t| Value::Map(Entries::from([
+        ("token".to_string(), Value::String(t.token.clone())),
+        ("logprob".to_string(), Value::Float(t.logprob)),
+    ]))).collect();
+    [
+        ("logprobs".to_string(), Value::List(tokens)),
+        ("confidence".to_string(), providers::confidence(logprobs).map_or(Value::None, Value::Float)),
+    ]
+}
+
+/// The grading instructions `judge()` sends as the system prompt. Anchoring
+/// every grade and warning against length and tone keeps scores comparable
+/// across answers and runs.
+const JUDGE_RUBRIC: &str = "You are a strict, impartial grader. Judge the answer only against the \
+    criteria, treating the reference answer, if there is one, as correct. Grade it on this scale:\n\
+    5 - meets every criterion; you can find nothing to correct.\n\
+    4 - meets the criteria, with minor flaws that don't change its usefulness.\n\
+    3 - partly meets them: some criteria are met, others are missed or wrong.\n\
+    2 - mostly fails them, though some of it is of use.\n\
+    1 - fails them, or is wrong, off-topic or empty.\n\
+    Don't reward length, confidence or politeness. Reason first, then grade.\n\
+    Respond ONLY with JSON: {\"reasoning\": \"<one to three sentences, citing the criteria>\", \"grade\": <1 to 5>}";
+
+/// The grade (1 to 5) and reasoning of a `judge()` reply, if it has both.
+fn judge_verdict(reply: &Value) -> Option<(i64, std::string::String)> {
+    let Value::Map(entries) = reply else { return None };
+    let grade = match entries.get("grade")? {
+        Value::Int(g) => *g,
+        Value::Float(g) if g.fract() == 0.0 => *g as i64,
+        Value::String(g) => g.trim().parse().ok()?,
+        _ => return None,
+    };
+    let reasoning = entries.get("reasoning").map_or_else(std::string::String::new, |r| r.to_string());
+    (1..=5).contains(&grade).then_some((grade, reasoning))
+}
+
+/// Most samples one `think(n=)` call asks for.
+const MAX_SAMPLES: usize = 20;
+
+/// What two samples must share to count as the same answer: text compares
+/// without case, surrounding whitespace, quotes or a final period; other
+/// values compare as JSON.
+fn sample_key(value: &Value) -> std::string::String {
+    match value {
+        Value::String(s) => s.trim()
+            .trim_matches(|c: char| c == '"' || c == '\'' || c == '`' || c.is_whitespace())
+            .trim_end_matches('.')
+            .to_lowercase(),
+        other => other.to_json().to_string(),
     }
+}
 
-    fn run_select(&mut self, branches: &[Vec<Stmt>]) -> Result<ControlFlow> {
-        let env = self.env.clone();
-        let flows = self.flows.clone();
-        let types = self.types.clone();
-        let vars = self.vars.clone();
-        let tracer = self.tracer.clone();
-        let memory = self.memory.clone();
-        let cancelled = Arc::new(AtomicBool::new(false));
+/// The index of the most common sample; the earliest one wins a tie.
+fn majority(samples: &[Value]) -> usize {
+    let keys: Vec<_> = samples.iter().map(sample_key).collect();
+    let mut best = (0, 0);
+    for (i, key) in keys.iter().enumerate() {
+        let votes = keys.iter().filter(|k| *k == key).count();
+        if votes > best.1 {
+            best = (i, votes);
+        }
+    }
+    best.0
+}
 
-        let (tx, rx) = std::sync::mpsc::channel();
+/// Follow-up requests `think(auto_continue=true)` makes for one reply.
+const MAX_CONTINUATIONS: usize = 3;
 
-        let mut handles = Vec::new();
-        for (i, branch) in branches.iter().enumerate() {
-            let env = env.clone();
-            let flows = flows.clone();
-            let types = types.clone();
-            let vars = vars.clone();
-            let tracer = tracer.clone();
-            let memory = memory.clone();
-            let branch = branch.clone();
-            let cancelled = cancelled.clone();
-            let tx = tx.clone();
+const CONTINUE_PROMPT: &str = "Continue your answer from exactly where it stops. \
+    Don't repeat any of it and don't comment on the interruption.";
 
-            let handle = std::thread::spawn(move || {
-                let mut interp = Interpreter {
-                    vars: vars.clone(),
-                    flows,
-                    types,
-                    env,
-                    tracer,
-                    import_stack: Vec::new(),
-                    conversation_history: Vec::new(),
-                    next_future_id: 0,
-                    async_handles: HashMap::new(),
-                    cancelled: cancelled.clone(),
-                    memory: memory.clone(),
-                };
-                let mut flow = ControlFlow::Normal;
-                for stmt in &branch {
-                    if cancelled.load(Ordering::Relaxed) {
-                        return;
-                    }
-                    match interp.run_stmt(stmt) {
-                        Ok(ControlFlow::Normal) => {}
-                        Ok(cf) => { flow = cf; break; }
-                        Err(_) => return,
-                    }
-                }
-                // Compute changed vars
-                let mut changed = HashMap::new();
-                for (k, v) in &interp.vars {
-                    match vars.get(k) {
-                        None => { changed.insert(k.clone(), v.clone()); }
-                        Some(old) => {
-                            if old.to_string() != v.to_string() {
-                                changed.insert(k.clone(), v.clone());
-                            }
-                        }
-                    }
-                }
-                let _ = tx.send((i, changed, flow));
-            });
-            handles.push(handle);
-        }
-        drop(tx);
+/// Why a reply with this finish reason is not the whole answer, if it isn't.
+fn incomplete_reply(model: &str, finish_reason: Option<&str>) -> Option<std::string::String> {
+    match finish_reason? {
+        "length" => Some(format!("{} hit its output length limit; the reply is cut off \
+            (think(auto_continue=true) asks for the rest)", model)),
+        "content_filter" => Some(format!("{}'s reply was cut short by the provider's content filter", model)),
+        _ => None,
+    }
+}
 
-        // Wait for first branch to complete
-        let mut result_flow = ControlFlow::Normal;
-        match rx.recv() {
-            Ok((_winner_idx, winner_vars, flow)) => {
-                // Cancel all other branches
-                cancelled.store(true, Ordering::Relaxed);
-                // Merge winner vars
-                for (k, v) in winner_vars {
-                    self.vars.insert(k, v);
-                }
-                result_flow = flow;
-            }
-            Err(_) => {
-                // All branches failed without sending
-            }
-        }
+fn warn_incomplete(model: &str, finish_reason: Option<&str>) {
+    if let Some(message) = incomplete_reply(model, finish_reason) {
+        crate::diagnostics::warning(message);
+    }
+}
 
-        // Don't join — let threads die on their own (they check cancelled flag)
-        // They'll be cleaned up when handles are dropped
-        for h in handles {
-            let _ = h.join();
-        }
+/// Session-file key holding the `history()` conversation next to the vars.
+const HISTORY_KEY: &str = "__history__";
 
-        Ok(result_flow)
+/// Arguments as compact JSON for the trace, cut to 200 chars.
+fn args_summary(args: Entries) -> std::string::String {
+    Value::Map(args).to_json().to_string().chars().take(200).collect()
+}
+
+/// Split `text` into chunks that each measure at most about `max` by `len`,
+/// breaking between paragraphs where possible, then lines, then words, then
+/// anywhere. Pieces are measured separately, so a chunk can run a token or
+/// two over when joins tokenize differently.
+fn chunk_text(text: &str, max: usize, len: &dyn Fn(&str) -> usize) -> Vec<std::string::String> {
+    let mut chunks = Vec::new();
+    let mut current = std::string::String::new();
+    let mut current_len = 0;
+    for piece in split_to_fit(text, max, len, 0) {
+        let piece_len = len(piece);
+        if current_len > 0 && current_len + piece_len > max {
+            chunks.push(std::mem::take(&mut current));
+            current_len = 0;
+        }
+        current.push_str(piece);
+        current_len += piece_len;
     }
+    chunks.push(current);
+    chunks.retain(|c| !c.trim().is_empty());
+    chunks
+}
 
-    fn eval(&mut self, expr: &Expr) -> Result<Value> {
-        match expr {
-            Expr::StringLit(s) => Ok(Value::String(s.clone())),
-            Expr::IntLit(n) => Ok(Value::Int(*n)),
-            Expr::FloatLit(n) => Ok(Value::Float(*n)),
-            Expr::BoolLit(b) => Ok(Value::Bool(*b)),
-            Expr::NoneLiteral => Ok(Value::None),
+/// `text` as pieces that each fit in `max`, cut at the coarsest separator
+/// that works, from `level` on.
+fn split_to_fit<'a>(text: &'a str, max: usize, len: &dyn Fn(&str) -> usize, level: usize) -> Vec<&'a str> {
+    const SEPARATORS: [&str; 3] = ["\n\n", "\n", " "];
+    if len(text) <= max {
+        return vec![text];
+    }
+    if let Some(sep) = SEPARATORS.get(level) {
+        return text.split_inclusive(sep).flat_map(|part| split_to_fit(part, max, len, level + 1)).collect();
+    }
+    // No separator left: cut at the longest prefix that fits, at least one char
+    let mut pieces = Vec::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let bounds: Vec<usize> = rest.char_indices().skip(1).map(|(i, _)| i).chain([rest.len()]).collect();
+        let fits = bounds.partition_point(|&end| len(&rest[..end]) <= max).max(1);
+        let (piece, tail) = rest.split_at(bounds[fits - 1]);
+        pieces.push(piece);
+        rest = tail;
+    }
+    pieces
+}
 
-            Expr::Ident(name) => {
-                match self.vars.get(name) {
-                    Some(v) => Ok(v.clone()),
-                    None => {
-                        let builtins = ["think", "invoke", "emit", "log", "print", "remember", "recall", "recall_scored", "forget", "read", "write", "file", "channel", "download", "__exec_shell__", "history", "clear_history"];
-                        if builtins.contains(&name.as_str()) {
-                            bail!("'{}' is a function — did you mean {}(...)?", name, name)
-                        } else if self.flows.contains_key(name) {
-                            bail!("'{}' is a flow — did you mean {}(...)?", name, name)
-                        } else {
-                            bail!("undefined variable: '{}'", name)
-                        }
-                    }
-                }
-            }
+/// Start each chunk after the first with the last `overlap` (by `len`) of the
+/// chunk before it, beginning at a word boundary.
+fn overlap_chunks(chunks: Vec<std::string::String>, overlap: usize, len: &dyn Fn(&str) -> usize) -> Vec<std::string::String> {
+    if overlap == 0 {
+        return chunks;
+    }
+    let mut out: Vec<std::string::String> = Vec::with_capacity(chunks.len());
+    for (i, chunk) in chunks.iter().enumerate() {
+        if i == 0 {
+            out.push(chunk.clone());
+            continue;
+        }
+        let prev = &chunks[i - 1];
+        let starts: Vec<usize> = prev.char_indices().map(|(i, _)| i).collect();
+        let mut start = starts[starts.partition_point(|&s| len(&prev[s..]) > overlap).min(starts.len() - 1)];
+        if start > 0 && !prev[..start].ends_with(char::is_whitespace) {
+            start = prev[start..].find(char::is_whitespace).map_or(prev.len(), |w| start + w);
+        }
+        let tail = prev[start..].trim_start();
+        out.push(format!("{}{}", tail, chunk));
+    }
+    out
+}
 
-            Expr::Async(inner) => {
-                // Spawn the expression evaluation in a background thread
-                let env = self.env.clone();
-                let flows = self.flows.clone();
-                let types = self.types.clone();
-                let vars = self.vars.clone();
-                let tracer = self.tracer.clone();
-                let memory = self.memory.clone();
-                let inner = (**inner).clone();
-                let cancel_token = Arc::new(AtomicBool::new(false));
-                let cancel_token2 = cancel_token.clone();
+/// `text` in chunks of at most `max_tokens` tokens of `model`, each repeating
+/// about `overlap` tokens from the end of the previous one.
+fn chunk_tokens(model: &str, text: &str, max_tokens: usize, overlap: usize) -> Result<Vec<std::string::String>> {
+    if overlap >= max_tokens {
+        bail!("overlap= ({}) must be smaller than max_tokens= ({})", overlap, max_tokens);
+    }
+    let len = |s: &str| providers::count_tokens(model, s);
+    Ok(overlap_chunks(chunk_text(text, max_tokens - overlap, &len), overlap, &len))
+}
 
-                let handle = std::thread::spawn(move || {
-                    let mut interp = Interpreter {
-                        vars,
-                        flows,
-                        types,
-                        env,
-                        tracer,
-                        import_stack: Vec::new(),
-                        conversation_history: Vec::new(),
-                        next_future_id: 0,
-                        async_handles: HashMap::new(),
-                        cancelled: cancel_token2,
-                        memory,
-                    };
-                    interp.eval(&inner)
-                });
+/// Per-result cap for `think(tool_results=...)`: `COGNOS_MAX_TOOL_RESULT`
+/// chars (0 = unlimited), default 20000.
+fn default_max_tool_result() -> Option<usize> {
+    match std::env::var("COGNOS_MAX_TOOL_RESULT").ok().and_then(|v| v.trim().parse::<usize>().ok()) {
+        Some(0) => None,
+        Some(n) => Some(n),
+        None => Some(20_000),
+    }
+}
 
-                let id = self.next_future_id;
-                self.next_future_id += 1;
-                self.async_handles.insert(id, (handle, cancel_token));
-                Ok(Value::Future(id))
-            }
+/// Keep the head and tail of `text` within `max` chars, with a marker
+/// saying how much was elided. `None` if it already fits.
+fn truncate_tool_result(text: &str, max: usize) -> Option<std::string::String> {
+    let total = text.chars().count();
+    if total <= max {
+        return None;
+    }
+    let head = max * 2 / 3;
+    let tail = max - head;
+    let byte_at = |chars: usize| text.char_indices().nth(chars).map_or(text.len(), |(i, _)| i);
+    Some(format!("{}\n\n... [{} of {} chars elided] ...\n\n{}",
+        &text[..byte_at(head)], total - max, total, &text[byte_at(total - tail)..]))
+}
 
-            Expr::List(items) => {
-                let vals: Result<Vec<Value>> = items.iter().map(|i| self.eval(i)).collect();
-                Ok(Value::List(vals?))
-            }
+/// One `examples=` item as an (input, output) pair. Strings are sent as-is;
+/// anything else is sent as JSON so it matches what `format=` asks for.
+fn few_shot_example(item: &Value) -> Result<(std::string::String, std::string::String)> {
+    let part = |key: &str| match item.get_field(key) {
+        Some(Value::String(s)) => Some(s.clone()),
+        Some(Value::None) | None => None,
+        Some(other) => Some(other.to_json().to_string()),
+    };
+    match (item, part("input"), part("output")) {
+        (Value::Map(_), Some(input), Some(output)) => Ok((input, output)),
+        _ => bail!("examples= items must be Maps with \"input\" and \"output\", got {}", item),
+    }
+}
 
-            Expr::Map(entries) => {
-                let mut result = Vec::new();
-                for (k, v) in entries {
-                    let val = self.eval(v)?;
-                    result.push((k.clone(), val));
-                }
-                Ok(Value::Map(result))
-            }
+/// Name of the tool call `id` in the conversation's most recent assistant turn.
+fn tool_call_name(conversation: &[Value], id: &str) -> Option<std::string::String> {
+    conversation.iter().rev().find_map(|msg| {
+        let Some(Value::List(calls)) = msg.get_field("tool_calls") else { return None };
+        calls.iter().find(|c| c.get_field("id").map(|v| v.to_string()).as_deref() == Some(id))
+            .and_then(|c| c.get_field("name")).map(|v| v.to_string())
+    })
+}
 
-            Expr::FString(parts) => {
-                let mut result = std::string::String::new();
-                for part in parts {
-                    match part {
-                        crate::ast::FStringPart::Literal(s) => result.push_str(s),
-                        crate::ast::FStringPart::Expr(e) => {
-                            let val = self.eval(e)?;
-                            result.push_str(&val.to_string());
-                        }
-                    }
-                }
-                Ok(Value::String(result))
-            }
+/// Whether `items` is a conversation: message Maps, each with a role.
+fn is_conversation(items: &[Value]) -> bool {
+    items.iter().all(|msg| msg.get_field("role").is_some())
+}
 
-            Expr::Call { name, args, kwargs } => {
-                self.call_builtin(name, args, kwargs)
-            }
+/// Whether `msg` opens a turn: a user message carrying a prompt, as opposed
+/// to one that only returns tool results.
+fn opens_turn(msg: &Value) -> bool {
+    if msg.get_field("role").map(|r| r.to_string()).as_deref() != Some("user") {
+        return false;
+    }
+    match msg.get_field("content") {
+        Some(Value::List(blocks)) => !blocks.iter()
+            .all(|b| b.get_field("type").map(|t| t.to_string()).as_deref() == Some("tool_result")),
+        _ => true,
+    }
+}
 
-            Expr::Field { object, field } => {
-                let val = self.eval(object)?;
-                // Module constants: math.pi, math.e
-                if let Value::Module(ref mod_name) = val {
-                    return match (mod_name.as_str(), field.as_str()) {
-                        // math module removed (P11)
-                        _ => bail!("{} has no constant '{}'", mod_name, field),
-                    };
-                }
-                match (&val, field.as_str()) {
-                    (Value::String(s), "length") => Ok(Value::Int(s.len() as i64)),
-                    (Value::String(s), "content") => Ok(Value::String(s.clone())),
-                    (Value::List(l), "length") => Ok(Value::Int(l.len() as i64)),
-                    (Value::Map(e), "length") => Ok(Value::Int(e.len() as i64)),
-                    (Value::Map(_), _) => {
-                        match val.get_field(field) {
-                            Some(v) => Ok(v.clone()),
-                            None => bail!("map has no key '{}'", field),
-                        }
-                    }
-                    _ => bail!("cannot access field '{}' on {} (type: {})", field, val, type_name(&val)),
-                }
-            }
+/// Drop the last `turns` turns of a conversation, each from its prompt
+/// through the replies and tool results that followed; the dropped messages.
+fn rollback_conversation(items: &mut Vec<Value>, turns: usize) -> Result<Value> {
+    if !is_conversation(items) {
+        bail!(".rollback() works on conversations: Lists of message Maps with a \"role\"");
+    }
+    if turns == 0 {
+        return Ok(Value::List(Vec::new()));
+    }
+    let starts: Vec<usize> = items.iter().enumerate().filter(|(_, msg)| opens_turn(msg)).map(|(i, _)| i).collect();
+    if starts.len() < turns {
+        bail!(".rollback({}): the conversation has only {} turn(s)", turns, starts.len());
+    }
+    Ok(Value::List(items.split_off(starts[starts.len() - turns])))
+}
 
-            Expr::Index { object, index } => {
-                let val = self.eval(object)?;
-                let idx = self.eval(index)?;
-                match (&val, &idx) {
-                    (Value::List(items), Value::Int(i)) => {
-                        let i = if *i < 0 { items.len() as i64 + i } else { *i } as usize;
-                        items.get(i).cloned()
-                            .ok_or_else(|| anyhow::anyhow!("index {} out of range (list has {} elements)", i, items.len()))
-                    }
-                    (Value::String(s), Value::Int(i)) => {
-                        let chars: Vec<char> = s.chars().collect();
-                        let i = if *i < 0 { chars.len() as i64 + i } else { *i } as usize;
-                        chars.get(i).map(|c| Value::String(c.to_string()))
-                            .ok_or_else(|| anyhow::anyhow!("index {} out of range (string has {} characters)", i, chars.len()))
-                    }
-                    (Value::Map(entries), Value::String(key)) => {
-                        entries.iter().find(|(k, _)| k == key)
-                            .map(|(_, v)| v.clone())
-                            .ok_or_else(|| anyhow::anyhow!("map has no key '{}'", key))
-                    }
-                    _ => bail!("cannot index {} with {} (type: {}[{}])", type_name(&val), idx, type_name(&val), type_name(&idx)),
-                }
-            }
+fn op_str(op: &BinOp) -> &'static str {
+    match op {
+        BinOp::Add => "+", BinOp::Sub => "-", BinOp::Mul => "*", BinOp::Div => "/",
+        BinOp::Eq => "==", BinOp::NotEq => "!=",
+        BinOp::Lt => "<", BinOp::Gt => ">", BinOp::LtEq => "<=", BinOp::GtEq => ">=",
+        BinOp::And => "and", BinOp::Or => "or",
+        BinOp::In => "in", BinOp::NotIn => "not in", BinOp::Mod => "%", BinOp::FloorDiv => "//",
+    }
+}
 
-            Expr::Slice { object, start, end } => {
-                let val = self.eval(object)?;
-                let s = start.as_ref().map(|e| self.eval(e)).transpose()?;
-                let e = end.as_ref().map(|e| self.eval(e)).transpose()?;
+/// `left op right` on two values, within `limits`. `and`/`or` here have
+/// both operands already; `eval` short-circuits them before getting this far.
+pub(crate) fn binop(limits: &ValueLimits, left: &Value, op: &BinOp, right: &Value) -> Result<Value> {
+    match (left, op, right) {
+        // String concat
+        (Value::String(a), BinOp::Add, Value::String(b)) => {
+            limits.check_string(a.len() + b.len())?;
+            Ok(Value::String(format!("{}{}", a, b)))
+        }
+
+        // List concatenation
+        (Value::List(a), BinOp::Add, Value::List(b)) => {
+            limits.check_len(a.len() + b.len())?;
+            let mut result = a.clone();
+            result.extend(b.clone());
+            Ok(Value::List(result))
+        }
+
+        // String repeat
+        (Value::String(s), BinOp::Mul, Value::Int(n)) | (Value::Int(n), BinOp::Mul, Value::String(s)) => {
+            if *n < 0 { bail!("cannot repeat string a negative number of times"); }
+            limits.check_string(s.len().saturating_mul(*n as usize))?;
+            Ok(Value::String(s.repeat(*n as usize)))
+        }
+
+        // Int arithmetic, checked: a result outside 64 bits is an error, never a wrap
+        (Value::Int(_), BinOp::Div | BinOp::FloorDiv, Value::Int(0)) => bail!("division by zero"),
+        (Value::Int(_), BinOp::Mod, Value::Int(0)) => bail!("modulo by zero"),
+        // `/` is true division, even for two Ints
+        (Value::Int(a), BinOp::Div, Value::Int(b)) => Ok(Value::Float(*a as f64 / *b as f64)),
+        (Value::Int(a), BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::FloorDiv | BinOp::Mod, Value::Int(b)) => {
+            let result = match op {
+                BinOp::Add => a.checked_add(*b),
+                BinOp::Sub => a.checked_sub(*b),
+                BinOp::Mul => a.checked_mul(*b),
+                BinOp::FloorDiv => floor_div(*a, *b),
+                _ => Some(floor_mod(*a, *b)),
+            };
+            result.map(Value::Int)
+                .ok_or_else(|| anyhow::anyhow!("integer overflow in {} {} {}", a, op_str(op), b))
+        }
+
+        // Float arithmetic
+        (Value::Float(a), BinOp::Add, Value::Float(b)) => Ok(Value::Float(a + b)),
+        (Value::Float(a), BinOp::Sub, Value::Float(b)) => Ok(Value::Float(a - b)),
+        (Value::Float(a), BinOp::Mul, Value::Float(b)) => Ok(Value::Float(a * b)),
+        (Value::Float(a), BinOp::Div, Value::Float(b)) => {
+            if *b == 0.0 { bail!("division by zero"); }
+            Ok(Value::Float(a / b))
+        }
+
+        // Mixed Int/Float arithmetic (promote to Float)
+        (Value::Int(a), BinOp::Add, Value::Float(b)) => Ok(Value::Float(*a as f64 + b)),
+        (Value::Float(a), BinOp::Add, Value::Int(b)) => Ok(Value::Float(a + *b as f64)),
+        (Value::Int(a), BinOp::Sub, Value::Float(b)) => Ok(Value::Float(*a as f64 - b)),
+        (Value::Float(a), BinOp::Sub, Value::Int(b)) => Ok(Value::Float(a - *b as f64)),
+        (Value::Int(a), BinOp::Mul, Value::Float(b)) => Ok(Value::Float(*a as f64 * b)),
+        (Value::Float(a), BinOp::Mul, Value::Int(b)) => Ok(Value::Float(a * *b as f64)),
+        (Value::Int(a), BinOp::Div, Value::Float(b)) => {
+            if *b == 0.0 { bail!("division by zero"); }
+            Ok(Value::Float(*a as f64 / b))
+        }
+        (Value::Float(a), BinOp::Div, Value::Int(b)) => {
+            if *b == 0 { bail!("division by zero"); }
+            Ok(Value::Float(a / *b as f64))
+        }
+
+        // Floor division with a Float on either side gives a whole Float
+        (Value::Int(_) | Value::Float(_), BinOp::FloorDiv, Value::Int(_) | Value::Float(_)) => {
+            let (a, b) = (as_f64(left), as_f64(right));
+            if b == 0.0 { bail!("division by zero"); }
+            Ok(Value::Float((a / b).floor()))
+        }
+        (Value::Int(_) | Value::Float(_), BinOp::Mod, Value::Int(_) | Value::Float(_)) => {
+            let (a, b) = (as_f64(left), as_f64(right));
+            if b == 0.0 { bail!("modulo by zero"); }
+            Ok(Value::Float(floor_mod_f64(a, b)))
+        }
+
+        // Comparisons
+        (Value::Int(a), BinOp::Eq, Value::Int(b)) => Ok(Value::Bool(a == b)),
+        (Value::Int(a), BinOp::NotEq, Value::Int(b)) => Ok(Value::Bool(a != b)),
+        (Value::Int(a), BinOp::Lt, Value::Int(b)) => Ok(Value::Bool(a < b)),
+        (Value::Int(a), BinOp::Gt, Value::Int(b)) => Ok(Value::Bool(a > b)),
+        (Value::Int(a), BinOp::LtEq, Value::Int(b)) => Ok(Value::Bool(a <= b)),
+        (Value::Int(a), BinOp::GtEq, Value::Int(b)) => Ok(Value::Bool(a >= b)),
+
+        (Value::Float(a), BinOp::Eq, Value::Float(b)) => Ok(Value::Bool(a == b)),
+        (Value::Float(a), BinOp::NotEq, Value::Float(b)) => Ok(Value::Bool(a != b)),
+        (Value::Float(a), BinOp::Lt, Value::Float(b)) => Ok(Value::Bool(a < b)),
+        (Value::Float(a), BinOp::Gt, Value::Float(b)) => Ok(Value::Bool(a > b)),
+        (Value::Float(a), BinOp::LtEq, Value::Float(b)) => Ok(Value::Bool(a <= b)),
+        (Value::Float(a), BinOp::GtEq, Value::Float(b)) => Ok(Value::Bool(a >= b)),
+
+        (Value::String(a), BinOp::Eq, Value::String(b)) => Ok(Value::Bool(a == b)),
+        (Value::String(a), BinOp::NotEq, Value::String(b)) => Ok(Value::Bool(a != b)),
+
+        // Boolean comparison
+        (Value::Bool(a), BinOp::Eq, Value::Bool(b)) => Ok(Value::Bool(a == b)),
+        (Value::Bool(a), BinOp::NotEq, Value::Bool(b)) => Ok(Value::Bool(a != b)),
+
+        // Lists and Maps compare by contents, Map keys in any order
+        (Value::List(_), BinOp::Eq, Value::List(_)) | (Value::Map(_), BinOp::Eq, Value::Map(_))
+        | (Value::Shared(_), BinOp::Eq, Value::Shared(_)) => Ok(Value::Bool(value_eq(left, right))),
+        (Value::List(_), BinOp::NotEq, Value::List(_)) | (Value::Map(_), BinOp::NotEq, Value::Map(_))
+        | (Value::Shared(_), BinOp::NotEq, Value::Shared(_)) => Ok(Value::Bool(!value_eq(left, right))),
+
+        // None comparison
+        (Value::None, BinOp::Eq, Value::None) => Ok(Value::Bool(true)),
+        (Value::None, BinOp::Eq, _) => Ok(Value::Bool(false)),
+        (_, BinOp::Eq, Value::None) => Ok(Value::Bool(false)),
+        (Value::None, BinOp::NotEq, Value::None) => Ok(Value::Bool(false)),
+        (Value::None, BinOp::NotEq, _) => Ok(Value::Bool(true)),
+        (_, BinOp::NotEq, Value::None) => Ok(Value::Bool(true)),
+
+        // Logic returns the deciding operand, like Python (eval short-circuits first)
+        (_, BinOp::And, _) => Ok(if left.is_truthy() { right.clone() } else { left.clone() }),
+        (_, BinOp::Or, _) => Ok(if left.is_truthy() { left.clone() } else { right.clone() }),
+
+        // Containment: "x" in "xyz", item in [list], key in {map}
+        (_, BinOp::In, Value::String(s)) => {
+            Ok(Value::Bool(s.contains(&left.to_string())))
+        }
+        // Lists by `==`, as `.contains()` does; Map keys are Strings
+        (_, BinOp::In, Value::List(items)) => Ok(Value::Bool(items.iter().any(|item| value_eq(item, left)))),
+        (Value::String(key), BinOp::In, Value::Map(entries)) => Ok(Value::Bool(entries.contains_key(key))),
+
+        // Negated containment: "x" not in "xyz", item not in [list], key not in {map}
+        (_, BinOp::NotIn, Value::String(s)) => {
+            Ok(Value::Bool(!s.contains(&left.to_string())))
+        }
+        (_, BinOp::NotIn, Value::List(items)) => Ok(Value::Bool(!items.iter().any(|item| value_eq(item, left)))),
+        (Value::String(key), BinOp::NotIn, Value::Map(entries)) => Ok(Value::Bool(!entries.contains_key(key))),
+        (_, BinOp::In | BinOp::NotIn, Value::Map(_)) => bail!("cannot {} {} Map — map keys are Strings", type_name(left), op_str(op)),
+
+        _ => bail!("cannot {} {} {} — {} {} {} not supported",
+            type_name(left), op_str(op), type_name(right),
+            type_name(left), op_str(op), type_name(right)),
+    }
+}
 
-                // Helper to resolve a slice index (supports negative)
-                fn resolve_slice_idx(idx: i64, len: usize) -> usize {
-                    if idx < 0 {
-                        let resolved = len as i64 + idx;
-                        if resolved < 0 { 0 } else { resolved as usize }
-                    } else {
-                        (idx as usize).min(len)
-                    }
-                }
+/// `not value` or `-value`.
+pub(crate) fn unary(op: &UnaryOp, value: Value) -> Result<Value> {
+    match (op, value) {
+        (UnaryOp::Not, v) => Ok(Value::Bool(!v.is_truthy())),
+        (UnaryOp::Neg, Value::Int(n)) => n.checked_neg()
+            .map(Value::Int)
+            .ok_or_else(|| anyhow::anyhow!("integer overflow negating {}", n)),
+        (UnaryOp::Neg, Value::Float(f)) => Ok(Value::Float(-f)),
+        (UnaryOp::Neg, other) => bail!("cannot negate {} (type: {})", other, type_name(&other)),
+    }
+}
 
-                match val {
-                    Value::String(ref sv) => {
-                        let chars: Vec<char> = sv.chars().collect();
-                        let len = chars.len();
-                        let start_idx = match s { Some(Value::Int(i)) => resolve_slice_idx(i, len), None => 0, _ => bail!("slice start must be Int") };
-                        let end_idx = match e { Some(Value::Int(i)) => resolve_slice_idx(i, len), None => len, _ => bail!("slice end must be Int") };
-                        if start_idx >= end_idx { return Ok(Value::String(String::new())); }
-                        Ok(Value::String(chars[start_idx..end_idx].iter().collect()))
-                    }
-                    Value::List(ref items) => {
-                        let len = items.len();
-                        let start_idx = match s { Some(Value::Int(i)) => resolve_slice_idx(i, len), None => 0, _ => bail!("slice start must be Int") };
-                        let end_idx = match e { Some(Value::Int(i)) => resolve_slice_idx(i, len), None => len, _ => bail!("slice end must be Int") };
-                        if start_idx >= end_idx { return Ok(Value::List(vec![])); }
-                        Ok(Value::List(items[start_idx..end_idx].to_vec()))
-                    }
-                    other => bail!("cannot slice {} (type: {})", other, type_name(&other)),
-                }
-            }
+/// Caps on how far a value can grow, so a runaway loop fails with a
+/// catchable error instead of taking all memory.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ValueLimits {
+    pub max_string_bytes: usize,
+    pub max_list_len: usize,
+    /// Lists and maps inside lists and maps.
+    pub max_depth: usize,
+}
 
-            Expr::MethodCall { object, method, args } => {
-                let val = self.eval(object)?;
-                let mut arg_vals = Vec::new();
-                for a in args {
-                    arg_vals.push(self.eval(a)?);
-                }
-                if let Value::Module(ref mod_name) = val {
-                    return self.call_module(mod_name, method, arg_vals);
-                }
-                self.call_method(val, method, arg_vals)
-            }
+impl Default for ValueLimits {
+    fn default() -> Self {
+        Self { max_string_bytes: 64 << 20, max_list_len: 10_000_000, max_depth: 256 }
+    }
+}
 
-            Expr::BinOp { left, op, right } => {
-                let l = self.eval(left)?;
-                let r = self.eval(right)?;
-                self.eval_binop(&l, op, &r)
-            }
+impl ValueLimits {
+    fn check_string(&self, bytes: usize) -> Result<()> {
+        if bytes > self.max_string_bytes {
+            bail!("string of {} bytes is over the {}-byte limit (--max-string-mb)", bytes, self.max_string_bytes);
+        }
+        Ok(())
+    }
 
-            Expr::UnaryOp { op, operand } => {
-                let v = self.eval(operand)?;
-                match op {
-                    UnaryOp::Not => Ok(Value::Bool(!v.is_truthy())),
-                }
-            }
+    /// Lists, and maps by their number of entries.
+    fn check_len(&self, len: usize) -> Result<()> {
+        if len > self.max_list_len {
+            bail!("collection of {} items is over the {}-item limit (--max-list-len)", len, self.max_list_len);
         }
+        Ok(())
     }
 
-    fn call_builtin(&mut self, name: &str, args: &[Expr], kwargs: &[(std::string::String, Expr)]) -> Result<Value> {
-        match name {
-            "print" | "emit" => {
-                for (i, arg) in args.iter().enumerate() {
-                    if i > 0 { print!(" "); }
-                    let val = self.eval(arg)?;
-                    print!("{}", val);
-                }
-                println!();
-                Ok(Value::None)
-            }
-            "think" => {
-                if args.is_empty() {
-                    bail!("think() requires at least one argument");
-                }
-                let context = self.eval(&args[0])?;
-
-                let default_model = std::env::var("COGNOS_MODEL").unwrap_or_else(|_| "qwen2.5:7b".to_string());
-                let mut model = default_model;
-                let mut system = std::string::String::new();
-                let mut format_type: Option<std::string::String> = None;
-                let mut tool_names: Vec<std::string::String> = Vec::new();
-                let mut image_paths: Vec<std::string::String> = Vec::new();
-                let mut conversation: Option<Vec<Value>> = None;
-                let mut tool_results: Option<Vec<Value>> = None;
-                
-                for (k, v) in kwargs {
-                    let val = self.eval(v)?;
-                    match k.as_str() {
-                        "model" => model = val.to_string(),
-                        "system" => system = val.to_string(),
-                        "format" => format_type = Some(val.to_string()),
-                        "conversation" => {
-                            match val {
-                                Value::List(items) => conversation = Some(items),
-                                Value::None => conversation = None,
-                                _ => bail!("conversation= must be a List or none, got {}", type_name(&val)),
-                            }
-                        }
-                        "tool_results" => {
-                            match val {
-                                Value::List(items) => tool_results = Some(items),
-                                Value::None => tool_results = None,
-                                _ => bail!("tool_results= must be a List or none, got {}", type_name(&val)),
-                            }
-                        }
-                        "images" => {
-                            if let Value::List(items) = val {
-                                for item in items {
-                                    image_paths.push(item.to_string());
-                                }
-                            } else {
-                                // Single image path
-                                image_paths.push(val.to_string());
-                            }
-                        }
-                        "tools" => {
-                            if let Value::List(items) = val {
-                                for item in items {
-                                    tool_names.push(item.to_string());
-                                }
-                            } else {
-                                bail!("tools= must be a list, got {}", type_name(&val));
-                            }
-                        }
-                        _ => bail!("think(): unknown kwarg '{}'", k),
-                    }
-                }
+    /// `items` about to go into a new list or map.
+    fn check_nesting<'a>(&self, items: impl IntoIterator<Item = &'a Value>) -> Result<()> {
+        items.into_iter().try_for_each(|item| self.check_nesting_at(1, item))
+    }
 
-                // If format= is a type name, inject schema into system prompt
-                if let Some(ref type_name) = format_type {
-                    let schema_instruction = if type_name == "json" {
-                        "Respond ONLY with valid JSON. No markdown, no explanation.".to_string()
-                    } else if let Some(td) = self.types.get(type_name).cloned() {
-                        let schema = self.type_to_schema(&td);
-                        format!("Respond ONLY with valid JSON matching this exact schema:\n{}\nNo markdown, no explanation, just the JSON object.", schema)
-                    } else {
-                        bail!("think(): unknown format type '{}' — define it with: type {}: ...", type_name, type_name)
-                    };
-                    if system.is_empty() {
-                        system = schema_instruction;
-                    } else {
-                        system = format!("{}\n\n{}", system, schema_instruction);
-                    }
-                }
+    /// `value` about to be stored `depth` lists and maps down.
+    fn check_nesting_at(&self, depth: usize, value: &Value) -> Result<()> {
+        if depth + nesting(value, self.max_depth) > self.max_depth {
+            bail!("value nested more than {} levels deep (--max-depth)", self.max_depth);
+        }
+        Ok(())
+    }
+}
 
-                // Build tool definitions from flow signatures
-                let tool_defs = if !tool_names.is_empty() {
-                    let mut tools = Vec::new();
-                    for name in &tool_names {
-                        let flow = self.flows.get(name)
-                            .ok_or_else(|| anyhow::anyhow!("tools: flow '{}' not defined", name))?
-                            .clone();
-                        tools.push(self.flow_to_tool_json(&flow));
-                    }
-                    Some(tools)
-                } else {
-                    None
-                };
+/// Levels of lists and maps in `value`, counted no further than `cap`.
+fn nesting(value: &Value, cap: usize) -> usize {
+    if cap == 0 {
+        return 0;
+    }
+    let inner = match value {
+        Value::List(items) => items.iter().map(|v| nesting(v, cap - 1)).max(),
+        Value::Map(entries) => entries.iter().map(|(_, v)| nesting(v, cap - 1)).max(),
+        _ => return 0,
+    };
+    1 + inner.unwrap_or(0)
+}
 
-                let prompt_text = context.to_string();
+/// Store `value` at `keys` inside `target`, for `m["a"][0] = value`. Every
+/// step but the last must already exist; the last may add a map key.
+fn set_path(target: &mut Value, keys: &[Value], value: Value, limits: &ValueLimits) -> Result<()> {
+    let Some((key, rest)) = keys.split_first() else {
+        *target = value;
+        return Ok(());
+    };
+    match (target, key) {
+        (Value::Map(entries), Value::String(key)) => set_entry(entries, key, rest, value, limits),
+        // Shared in place, so other branches see it
+        (Value::Shared(shared), Value::String(key)) => set_entry(&mut *shared.lock()?, key, rest, value, limits),
+        (Value::List(items), Value::Int(i)) => {
+            let len = items.len();
+            let index = if *i < 0 { len as i64 + i } else { *i };
+            let item = usize::try_from(index).ok().and_then(|index| items.get_mut(index))
+                .ok_or_else(|| anyhow::anyhow!("index {} out of range (list has {} elements)", i, len))?;
+            set_path(item, rest, value, limits)
+        }
+        (Value::Map(_) | Value::Shared(_), other) => bail!("map key must be a String, got {}", type_name(other)),
+        (Value::List(_), other) => bail!("list index must be an Int, got {}", type_name(other)),
+        (other, _) => bail!("cannot index-assign on {} (type: {})", other, type_name(other)),
+    }
+}
 
-                // Multi-turn conversation mode
-                if let Some(ref conv) = conversation {
-                    // Claude: use native Anthropic API
-                    if model.starts_with("claude") {
-                        return self.call_anthropic_api_multi_turn(&model, &system, &prompt_text, tool_defs, conv.clone(), tool_results);
-                    }
-                    // Non-Claude models: use OpenAI-compatible multi-turn API
-                    return self.call_openai_multi_turn(&model, &system, &prompt_text, tool_defs, conv.clone(), tool_results);
-                }
+fn set_entry(entries: &mut Entries, key: &str, rest: &[Value], value: Value, limits: &ValueLimits) -> Result<()> {
+    match entries.get_mut(key) {
+        Some(child) => set_path(child, rest, value, limits),
+        None if rest.is_empty() => {
+            limits.check_len(entries.len() + 1)?;
+            entries.insert(key.to_string(), value);
+            Ok(())
+        }
+        None => bail!("map has no key '{}'", key),
+    }
+}
 
-                // Single-turn mode (no conversation)
-                let raw_result = self.call_llm(&model, &system, &prompt_text, tool_defs.clone(), &image_paths)?;
+fn has_native_tools(tools: &Option<Vec<serde_json::Value>>) -> bool {
+    tools.iter().flatten().any(providers::is_native_tool)
+}
 
-                // think() without tools= returns String; with tools= returns Map
-                let result = raw_result;
+/// The tool name Anthropic expects for a dated `builtin_tool()` type: the
+/// type less its date, except for the text editors' own names. OpenAI's
+/// hosted tools (`web_search`, ...) have no date and take no name.
+fn native_tool_name(tool_type: &str) -> Option<&str> {
+    let (name, date) = tool_type.rsplit_once('_')?;
+    if !date.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    Some(match tool_type {
+        "text_editor_20241022" | "text_editor_20250124" => "str_replace_editor",
+        t if t.starts_with("text_editor_") => "str_replace_based_edit_tool",
+        _ => name,
+    })
+}
 
-                // Track conversation history
-                self.conversation_history.push(("user".to_string(), prompt_text.clone()));
-                let response_text = match &result {
-                    Value::Map(entries) => entries.iter()
-                        .find(|(k, _)| k == "content")
-                        .map(|(_, v)| v.to_string())
-                        .unwrap_or_default(),
-                    other => other.to_string(),
-                };
-                self.conversation_history.push(("assistant".to_string(), response_text));
+/// List methods that change the list they are called on.
+const LIST_MUTATORS: &[&str] = &["push", "pop", "insert", "rollback"];
 
-                // If format= specified, parse JSON and validate against type
-                if let Some(ref tn) = format_type {
-                    // Extract content string from the wrapper Map for JSON parsing
-                    let content_val = match &result {
-                        Value::Map(entries) => entries.iter()
-                            .find(|(k, _)| k == "content")
-                            .map(|(_, v)| v.clone())
-                            .unwrap_or(result.clone()),
-                        other => other.clone(),
-                    };
-                    let parsed = self.parse_json_response(&content_val)?;
-                    if tn != "json" {
-                        if let Some(td) = self.types.get(tn).cloned() {
-                            self.validate_type(&parsed, &td)?;
-                        }
-                    }
-                    Ok(parsed)
-                } else {
-                    Ok(result)
-                }
-            }
-            "file" => {
-                if args.is_empty() { bail!("file() requires a path argument"); }
-                let path = self.eval(&args[0])?.to_string();
-                Ok(Value::Handle(Handle::File(path)))
+/// The variable an expression starts from and the field and index steps
+/// from it, for `rows[0].tags.push(x)`; `None` for anything else.
+fn place(mut expr: &Expr) -> Option<(&Name, Vec<&Expr>)> {
+    let mut steps = Vec::new();
+    loop {
+        match expr {
+            Expr::Ident(name) => {
+                steps.reverse();
+                return Some((name, steps));
             }
-            "channel" => {
-                // channel("slack", token="xoxb-...", channel="#general")
-                if args.is_empty() { bail!("channel() requires a provider name: channel(\"slack\", token=\"...\", channel=\"...\")"); }
-                let provider = self.eval(&args[0])?.to_string();
-                let mut config = HashMap::new();
-                for (k, v) in kwargs {
-                    config.insert(k.clone(), self.eval(v)?.to_string());
-                }
-                // Validate provider-specific config
-                match provider.as_str() {
-                    "slack" => {
-                        if !config.contains_key("token") {
-                            // Try env var
-                            if let Ok(token) = std::env::var("SLACK_BOT_TOKEN") {
-                                config.insert("token".to_string(), token);
-                            } else {
-                                bail!("slack channel requires token= or SLACK_BOT_TOKEN env var");
-                            }
-                        }
-                        if !config.contains_key("channel") {
-                            bail!("slack channel requires channel= parameter");
-                        }
-                    }
-                    other => bail!("unknown channel provider: '{}'. Supported: slack", other),
-                }
-                log::info!("channel: created {} handle", provider);
-                Ok(Value::Handle(Handle::Channel { provider, config }))
+            Expr::Field { object, .. } | Expr::Index { object, .. } => {
+                steps.push(expr);
+                expr = object;
             }
-            "download" => {
-                // download(url, path, channel=handle) — HTTP GET → save to file
-                // channel= kwarg provides auth from channel handle automatically
-                if args.len() < 2 { bail!("download(url, path) or download(url, path, channel=handle)"); }
-                let url = self.eval(&args[0])?.to_string();
-                let path = self.eval(&args[1])?.to_string();
+            _ => return None,
+        }
+    }
+}
 
-                // Build auth headers from kwargs
-                let mut headers = reqwest::header::HeaderMap::new();
-                for (k, v) in kwargs {
-                    match k.as_str() {
-                        "channel" => {
-                            if let Value::Handle(Handle::Channel { ref provider, ref config }) = self.eval(v)? {
-                                match provider.as_str() {
-                                    "slack" => {
-                                        if let Some(token) = config.get("token") {
-                                            headers.insert(
-                                                reqwest::header::AUTHORIZATION,
-                                                reqwest::header::HeaderValue::from_str(&format!("Bearer {}", token))
-                                                    .map_err(|e| anyhow::anyhow!("invalid auth header: {}", e))?,
-                                            );
-                                        }
-                                    }
-                                    _ => {}
-                                }
-                            }
-                        }
-                        "headers" => {
-                            if let Value::Map(pairs) = self.eval(v)? {
-                                for (hk, hv) in &pairs {
-                                    headers.insert(
-                                        reqwest::header::HeaderName::from_bytes(hk.as_bytes())
-                                            .map_err(|e| anyhow::anyhow!("invalid header name '{}': {}", hk, e))?,
-                                        reqwest::header::HeaderValue::from_str(&hv.to_string())
-                                            .map_err(|e| anyhow::anyhow!("invalid header value: {}", e))?,
-                                    );
-                                }
-                            }
-                        }
-                        _ => {}
-                    }
-                }
+/// Run `f` on the value at `keys` inside `target`, in place. Every step
+/// must already exist.
+fn update_path<T>(target: &mut Value, keys: &[Value], f: &mut dyn FnMut(&mut Value) -> Result<T>) -> Result<T> {
+    let Some((key, rest)) = keys.split_first() else { return f(target) };
+    match (target, key) {
+        (Value::Map(entries), Value::String(key)) => {
+            let child = entries.get_mut(key).ok_or_else(|| anyhow::anyhow!("map has no key '{}'", key))?;
+            update_path(child, rest, f)
+        }
+        // Shared in place, so other branches see it
+        (Value::Shared(shared), Value::String(key)) => {
+            let mut entries = shared.lock()?;
+            let child = entries.get_mut(key).ok_or_else(|| anyhow::anyhow!("map has no key '{}'", key))?;
+            update_path(child, rest, f)
+        }
+        (Value::List(items), Value::Int(i)) => {
+            let len = items.len();
+            let item = list_slot(*i, len).and_then(|index| items.get_mut(index))
+                .ok_or_else(|| anyhow::anyhow!("index {} out of range (list has {} elements)", i, len))?;
+            update_path(item, rest, f)
+        }
+        (Value::Map(_) | Value::Shared(_), other) => bail!("map key must be a String, got {}", type_name(other)),
+        (Value::List(_), other) => bail!("list index must be an Int, got {}", type_name(other)),
+        (other, _) => bail!("cannot index {} (type: {})", other, type_name(other)),
+    }
+}
 
-                let client = reqwest::blocking::Client::new();
-                let resp = client.get(&url)
-                    .headers(headers)
-                    .send()
-                    .map_err(|e| anyhow::anyhow!("download failed: {}", e))?;
+/// `index` into a list of `len` items, counting from the end when negative.
+fn list_slot(index: i64, len: usize) -> Option<usize> {
+    let index = if index < 0 { len as i64 + index } else { index };
+    usize::try_from(index).ok()
+}
 
-                if !resp.status().is_success() {
-                    bail!("download failed: HTTP {}", resp.status());
-                }
+/// `.push(item)`, `.pop(index?)` or `.insert(index, item)` on `items`, a
+/// list `depth` steps inside its variable.
+fn list_mutation(items: &mut Vec<Value>, method: &str, args: &[Value], depth: usize, limits: &ValueLimits) -> Result<Value> {
+    let len = items.len();
+    match (method, args) {
+        ("push", [item]) => {
+            limits.check_len(len + 1)?;
+            limits.check_nesting_at(depth + 1, item)?;
+            items.push(item.clone());
+            Ok(Value::None)
+        }
+        ("pop", []) => items.pop().ok_or_else(|| anyhow::anyhow!(".pop() from an empty list")),
+        ("pop", [Value::Int(i)]) => {
+            let index = list_slot(*i, len).filter(|&index| index < len)
+                .ok_or_else(|| anyhow::anyhow!(".pop(): index {} out of range (list has {} elements)", i, len))?;
+            Ok(items.remove(index))
+        }
+        ("insert", [Value::Int(i), item]) => {
+            // Inserting at the length appends
+            let index = list_slot(*i, len).filter(|&index| index <= len)
+                .ok_or_else(|| anyhow::anyhow!(".insert(): index {} out of range (list has {} elements)", i, len))?;
+            limits.check_len(len + 1)?;
+            limits.check_nesting_at(depth + 1, item)?;
+            items.insert(index, item.clone());
+            Ok(Value::None)
+        }
+        ("rollback", []) => rollback_conversation(items, 1),
+        ("rollback", [Value::Int(n)]) if *n >= 0 => rollback_conversation(items, *n as usize),
+        ("rollback", _) => bail!(".rollback() takes an optional number of turns, e.g. conv.rollback() or conv.rollback(2)"),
+        ("push", _) => bail!(".push() takes one item, e.g. items.push(x)"),
+        ("pop", _) => bail!(".pop() takes an optional Int index, e.g. items.pop() or items.pop(0)"),
+        _ => bail!(".insert() takes an Int index and an item, e.g. items.insert(0, x)"),
+    }
+}
 
-                let bytes = resp.bytes()
-                    .map_err(|e| anyhow::anyhow!("download read failed: {}", e))?;
+/// What `mutate_list` made of a list-changing method call.
+enum ListCall {
+    /// Changed the List in place, giving this result
+    Done(Value),
+    /// The place holds something else: its value and the arguments, already
+    /// evaluated, for an ordinary method call
+    Other(Value, Vec<Value>),
+    /// The object isn't a variable or a field or index of one
+    NotAPlace,
+}
 
-                // Create parent dirs if needed
-                if let Some(parent) = std::path::Path::new(&path).parent() {
-                    std::fs::create_dir_all(parent)
-                        .map_err(|e| anyhow::anyhow!("cannot create directory: {}", e))?;
-                }
-                std::fs::write(&path, &bytes)
-                    .map_err(|e| anyhow::anyhow!("cannot write file '{}': {}", path, e))?;
+enum ControlFlow {
+    Normal,
+    Break,
+    Continue,
+    Return(Value),
+}
 
-                log::info!("download: {} → {} ({} bytes)", url, path, bytes.len());
-                Ok(Value::Int(bytes.len() as i64))
+/// Where a runtime error was raised: the statement, and the flow and file
+/// holding it.
+#[derive(Debug, Clone)]
+pub struct ErrorLocation {
+    pub flow: Option<std::string::String>,
+    /// The file of an imported flow or library; `None` for the file being run
+    pub file: Option<std::string::String>,
+    pub line: usize,
+    pub col: usize,
+}
+
+pub struct Interpreter {
+    vars: HashMap<Name, Value>,
+    flows: HashMap<std::string::String, crate::ast::FlowDef>,
+    types: HashMap<std::string::String, crate::ast::TypeDef>,
+    env: Arc<Mutex<Box<dyn Env + Send>>>,
+    tracer: Option<Arc<Tracer>>,
+    import_stack: Vec<std::string::String>,
+    /// What each file already imported offers, by canonical path; each is
+    /// parsed and set up only once.
+    imported: HashMap<std::string::String, Arc<Exports>>,
+    /// Modules of the `.cogx` bundle being run; imports resolve against it
+    /// instead of the filesystem.
+    bundle: Option<Arc<crate::bundle::Bundle>>,
+    /// Canonical path of the file each imported flow came from.
+    flow_sources: HashMap<std::string::String, std::string::String>,
+    conversation_history: Vec<(std::string::String, std::string::String)>,
+    next_future_id: u64,
+    async_handles: HashMap<u64, (std::thread::JoinHandle<Result<Value>>, Arc<AtomicBool>)>,
+    cancelled: Arc<AtomicBool>,
+    memory: Option<Arc<MemoryStore>>,
+    ollama: OllamaOptions,
+    /// In-flight `warmup()`; the first real LLM call waits for it.
+    warmup: Option<std::thread::JoinHandle<()>>,
+    /// Flows currently running, innermost last and the entry flow included:
+    /// the trace's call tree and `cognos.toml` flow overrides go by it.
+    flow_stack: Vec<std::string::String>,
+    /// Model and temperature overrides by flow name, from `cognos.toml`.
+    flow_overrides: Arc<crate::config::FlowOverrides>,
+    /// Variables set by top-level statements of the program and its
+    /// imports; flows read them when a name isn't a local.
+    globals: Arc<HashMap<Name, Value>>,
+    /// `--autosave`: session path, interval, and when it was last written.
+    autosave: Option<(std::string::String, std::time::Duration, std::time::Instant)>,
+    /// Parallel/select branch this interpreter runs, e.g. "fetch_a" or
+    /// "fetch_a/2" when nested; unnamed branches go by 1-based position.
+    branch: Option<std::string::String>,
+    /// `loop`/`for` statements running, outermost first, when tracing:
+    /// every trace event carries them so a run's turns can be grouped.
+    loops: Vec<crate::trace::LoopFrame>,
+    /// How big strings, lists and nesting may grow.
+    limits: ValueLimits,
+    /// `--llm-retries`: how LLM calls without `retries=` retry.
+    llm_retry: crate::retry::Policy,
+    /// `--allow-dynamic`: whether `define_flow()` may add flows at run time.
+    allow_dynamic: bool,
+    /// Flows `define_flow()` added; only these may be defined again.
+    dynamic_flows: HashSet<std::string::String>,
+    /// Tools of the `mcp()` servers connected so far, by name; `invoke()`
+    /// and tool calls reach them when no flow has the name.
+    mcp_tools: HashMap<std::string::String, Arc<crate::mcp::Client>>,
+    /// `--watch-var`: variables whose changes are reported.
+    watch: Watch,
+    /// Where the error now unwinding was raised: the innermost statement
+    /// that failed, and the flow and file it is in.
+    raised_at: Option<ErrorLocation>,
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Self::with_options(false)
+    }
+
+    pub fn with_options(allow_shell: bool) -> Self {
+        Self::with_full_options(allow_shell, None)
+    }
+
+    pub fn with_full_options(allow_shell: bool, tracer: Option<Arc<Tracer>>) -> Self {
+        Self::with_env(Box::new(RealEnv::new(allow_shell)), tracer)
+    }
+
+    pub fn with_env(env: Box<dyn Env + Send>, tracer: Option<Arc<Tracer>>) -> Self {
+        let mut vars: HashMap<Name, Value> = HashMap::new();
+        vars.insert("stdin".into(), Value::Handle(Handle::Stdin));
+        vars.insert("stdout".into(), Value::Handle(Handle::Stdout));
+        // math module removed (P11: lean core runtime)
+        vars.insert("http".into(), Value::Module("http".to_string()));
+        vars.insert("grpc".into(), Value::Module("grpc".to_string()));
+        Self { vars, flows: HashMap::new(), types: HashMap::new(), env: Arc::from(Mutex::new(env)), tracer, import_stack: Vec::new(), imported: HashMap::new(), bundle: None, flow_sources: HashMap::new(), conversation_history: Vec::new(), next_future_id: 0, async_handles: HashMap::new(), cancelled: Arc::new(AtomicBool::new(false)), memory: None, ollama: OllamaOptions::default(), warmup: None, flow_stack: Vec::new(), flow_overrides: Arc::default(), globals: Arc::default(), autosave: None, branch: None, loops: Vec::new(), limits: ValueLimits::default(), llm_retry: crate::retry::Policy::default(), allow_dynamic: false, dynamic_flows: HashSet::new(), mcp_tools: HashMap::new(), watch: Watch::default(), raised_at: None }
+    }
+
+    pub fn set_memory(&mut self, store: MemoryStore) {
+        self.memory = Some(Arc::new(store));
+    }
+
+    /// Defaults for Ollama calls; `think()` kwargs override them per call.
+    pub fn set_ollama_options(&mut self, options: OllamaOptions) {
+        self.ollama = options;
+    }
+
+    /// Start loading an Ollama model in the background so the first
+    /// `think()` doesn't stall on it. Failures are logged, not fatal.
+    pub fn warmup(&mut self, model: &str) {
+        if self.env.lock().unwrap().is_mock() {
+            return;
+        }
+        let model = model.to_string();
+        let options = self.ollama.clone();
+        self.warmup = Some(std::thread::spawn(move || {
+            let start = std::time::Instant::now();
+            match providers::ollama_warmup(&model, &options) {
+                Ok(()) => log::info!("Warmed up '{}' in {}ms", model, start.elapsed().as_millis()),
+                Err(e) => crate::diagnostics::warning(format!("warmup failed: {}", e)),
             }
-            "read" => {
-                // read() or read(handle) — default: stdin
-                let handle = if args.is_empty() {
-                    Handle::Stdin
-                } else {
-                    match self.eval(&args[0])? {
-                        Value::Handle(h) => h,
-                        other => bail!("read() expects a handle, got {} (type: {})", other, type_name(&other)),
-                    }
-                };
-                match handle {
-                    Handle::Stdin => {
-                        match self.env.lock().unwrap().read_stdin() {
-                            Ok(val) => {
-                                if let Some(ref tracer) = self.tracer {
-                                    tracer.increment_turn();
-                                }
-                                let full = self.is_full_trace();
-                                self.trace(TraceEvent::IoOp {
-                                    operation: "read".into(), handle_type: "stdin".into(),
-                                    path: None, bytes: val.len(),
-                                    content: if full { Some(val.clone()) } else { None },
-                                });
-                                Ok(Value::String(val))
+        }));
+    }
+
+    fn get_memory(&self) -> Result<&MemoryStore> {
+        self.memory.as_ref().map(|m| m.as_ref())
+            .ok_or_else(|| anyhow::anyhow!("memory not enabled. Use --memory-db <path> or --memory to enable"))
+    }
+
+    pub fn load_session(&mut self, path: &str) -> anyhow::Result<()> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("cannot load session '{}': {}", path, e))?;
+        let json: serde_json::Value = serde_json::from_str(&content)?;
+        if let Some(obj) = json.as_object() {
+            for (k, v) in obj {
+                if k == HISTORY_KEY {
+                    self.conversation_history = v.as_array().into_iter().flatten().filter_map(|m| {
+                        Some((m["role"].as_str()?.to_string(), m["content"].as_str()?.to_string()))
+                    }).collect();
+                    continue;
+                }
+                self.vars.insert(k.as_str().into(), self.json_to_value(v.clone()));
+            }
+        }
+        log::info!("Loaded session from {}", path);
+        Ok(())
+    }
+
+    pub fn save_session(&self, path: &str) -> anyhow::Result<()> {
+        let mut map = serde_json::Map::new();
+        for (k, v) in &self.vars {
+            // Skip builtins
+            match &**k {
+                "stdin" | "stdout" | "http" | "grpc" => continue,
+                _ => {}
+            }
+            map.insert(k.to_string(), self.value_to_json(v));
+        }
+        if !self.conversation_history.is_empty() {
+            let history = self.conversation_history.iter()
+                .map(|(role, content)| serde_json::json!({"role": role, "content": content}))
+                .collect();
+            map.insert(HISTORY_KEY.to_string(), serde_json::Value::Array(history));
+        }
+        // Write then rename, so a crash mid-save keeps the previous session
+        let tmp = format!("{}.tmp", path);
+        std::fs::write(&tmp, serde_json::to_string_pretty(&serde_json::Value::Object(map))?)?;
+        std::fs::rename(&tmp, path)?;
+        log::info!("Saved session to {}", path);
+        Ok(())
+    }
+
+    /// Model and temperature overrides for LLM calls made inside matching flows.
+    pub fn set_flow_overrides(&mut self, overrides: crate::config::FlowOverrides) {
+        self.flow_overrides = Arc::new(overrides);
+    }
+
+    pub fn set_value_limits(&mut self, limits: ValueLimits) {
+        self.limits = limits;
+    }
+
+    /// Retry transient LLM failures this many times unless a call says otherwise.
+    pub fn set_llm_retries(&mut self, retries: u32) {
+        self.llm_retry.retries = retries;
+    }
+
+    /// Report on stderr each change to these variables, with where it happened.
+    pub fn set_watched_vars(&mut self, names: &[std::string::String]) {
+        self.watch.names = names.iter().map(|n| Name::from(n.as_str())).collect();
+    }
+
+    /// Let `define_flow()` add flows while the program runs.
+    pub fn set_allow_dynamic(&mut self, allow: bool) {
+        self.allow_dynamic = allow;
+    }
+
+    /// Resolve imports against a `.cogx` bundle's modules.
+    pub fn set_bundle(&mut self, bundle: crate::bundle::Bundle) {
+        self.bundle = Some(Arc::new(bundle));
+    }
+
+    /// The model and options an LLM call runs with, after `cognos.toml`
+    /// overrides for the innermost running flow that has one.
+    fn with_flow_override(&self, model: &str, options: CallOptions) -> (std::string::String, CallOptions) {
+        let found = self.flow_stack.iter().rev()
+            .find_map(|flow| self.flow_overrides.get(flow).map(|o| (flow, o)));
+        let Some((flow, o)) = found else { return (model.to_string(), options) };
+        let model = match o.model {
+            Some(ref m) if m != model => {
+                log::info!("Flow '{}': using model {} instead of {} (cognos.toml)", flow, m, model);
+                m.clone()
+            }
+            _ => model.to_string(),
+        };
+        (model, CallOptions { temperature: options.temperature.or(o.temperature), ..options })
+    }
+
+    /// Save the session to `path` every `every` while the entry flow runs.
+    pub fn set_autosave(&mut self, path: &str, every: std::time::Duration) {
+        self.autosave = Some((path.to_string(), every, std::time::Instant::now()));
+    }
+
+    /// Called between statements of the entry flow, where `vars` is the
+    /// session's scope rather than some called flow's locals.
+    fn autosave_if_due(&mut self) {
+        let Some((ref path, every, ref mut last)) = self.autosave else { return };
+        if last.elapsed() < every {
+            return;
+        }
+        *last = std::time::Instant::now();
+        let path = path.clone();
+        if let Err(e) = self.save_session(&path) {
+            crate::diagnostics::warning(format!("autosave to {} failed: {}", path, e));
+        }
+    }
+
+    pub fn captured_stdout(&self) -> Option<Vec<String>> {
+        self.env.lock().unwrap().captured_stdout()
+    }
+
+    fn trace(&self, event: TraceEvent) {
+        if let Some(ref tracer) = self.tracer {
+            tracer.emit_in_loops(event, &self.loops);
+        }
+    }
+
+    fn is_full_trace(&self) -> bool {
+        self.tracer.as_ref().map(|t| t.level == crate::trace::TraceLevel::Full).unwrap_or(false)
+    }
+
+    /// Send `request` to `plugin`, tracing the call as `function`.
+    fn call_plugin(&self, plugin: &crate::plugin::Plugin, function: &str, request: serde_json::Value) -> Result<serde_json::Value> {
+        let start = std::time::Instant::now();
+        let result = plugin.call(request);
+        self.trace(TraceEvent::PluginCall {
+            plugin: plugin.name.clone(),
+            function: function.to_string(),
+            latency_ms: start.elapsed().as_millis() as u64,
+            error: result.as_ref().err().map(|e| e.to_string()),
+        });
+        result
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[allow(clippy::too_many_arguments)]
+    fn trace_llm(&self, model: &str, provider: &str, latency_ms: u64, prompt: &str, system: &str, response: &str, has_tool_calls: bool, finish_reason: Option<&str>) {
+        if self.tracer.is_none() {
+            return;
+        }
+        let full = self.is_full_trace();
+        self.trace(TraceEvent::LlmCall {
+            model: model.to_string(), provider: provider.to_string(),
+            latency_ms, prompt_chars: prompt.len(), response_chars: response.len(),
+            prompt_tokens: providers::count_tokens(model, system) + providers::count_tokens(model, prompt),
+            response_tokens: providers::count_tokens(model, response),
+            has_tool_calls, finish_reason: finish_reason.map(String::from), error: None,
+            prompt: if full { Some(prompt.to_string()) } else { None },
+            response: if full { Some(response.to_string()) } else { None },
+            system: if full { Some(system.to_string()) } else { None },
+            branch: self.branch.clone(),
+        });
+        if let Some(message) = incomplete_reply(model, finish_reason) {
+            self.trace(TraceEvent::Warning { category: finish_reason.unwrap_or_default().to_string(), message });
+        }
+    }
+
+    pub fn run(&mut self, program: &Program) -> Result<()> {
+        self.run_with_base(program, None)
+    }
+
+    /// Import a file relative to `base_path`, registering its types and flows.
+    fn import_file(&mut self, import_path: &str, base_path: Option<&std::path::Path>) -> Result<()> {
+        let import = Import { path: import_path.to_string(), names: None };
+        let exports = self.resolve_import(&import, base_path)?;
+        self.register_exports(exports);
+        Ok(())
+    }
+
+    /// What `import` brings in: all of the file's exports, or the names it
+    /// lists. Files are keyed by canonical path, so a library reached through
+    /// several imports is only read, parsed and set up the first time.
+    fn resolve_import(&mut self, import: &Import, base_path: Option<&std::path::Path>) -> Result<Exports> {
+        let import_path = import.path.as_str();
+        let resolved = if let Some(base) = base_path {
+            base.parent().unwrap_or(base).join(crate::environment::native_path(import_path))
+        } else {
+            crate::environment::native_path(import_path)
+        };
+        let canonical = match (&self.bundle, base_path) {
+            (Some(_), Some(base)) => crate::bundle::join(&base.to_string_lossy(), import_path),
+            (Some(_), None) => crate::bundle::join("", import_path),
+            (None, _) => crate::environment::display_path(&resolved.canonicalize().unwrap_or_else(|_| resolved.clone())),
+        };
+        if self.import_stack.contains(&canonical) {
+            bail!("circular import detected: '{}' is already being imported", import_path);
+        }
+        let exports = match self.imported.get(&canonical) {
+            Some(exports) => {
+                log::info!("Already imported {:?}", resolved);
+                exports.clone()
+            }
+            None => {
+                self.import_stack.push(canonical.clone());
+                let loaded = self.load_module(import_path, &resolved, &canonical);
+                self.import_stack.pop();
+                let exports = Arc::new(loaded?);
+                self.imported.insert(canonical, exports.clone());
+                exports
+            }
+        };
+        exports.select(import.names.as_deref(), import_path)
+    }
+
+    /// Parse a library, resolve its own imports and run its top-level code.
+    fn load_module(&mut self, import_path: &str, resolved: &std::path::Path, canonical: &str) -> Result<Exports> {
+        log::info!("Importing {:?}", resolved);
+        let source = match &self.bundle {
+            Some(bundle) => bundle.modules.get(canonical).cloned()
+                .ok_or_else(|| anyhow::anyhow!("cannot import '{}': {} is not in the bundle", import_path, canonical))?,
+            None => std::fs::read_to_string(resolved)
+                .map_err(|e| anyhow::anyhow!("cannot import '{}': {}", import_path, e))?,
+        };
+        let mut lexer = crate::lexer::Lexer::new(&source);
+        let tokens = lexer.tokenize();
+        let mut parser = crate::parser::Parser::new(tokens).for_file(resolved);
+        let imported = parser.parse_program()
+            .map_err(|e| anyhow::anyhow!("error in '{}': {}", import_path, e))?;
+        let imported = crate::optimize::program(imported, &self.limits);
+        // Recursively resolve imports in the imported file; they are
+        // re-exported, so a library can be a facade over others
+        let mut exports = Exports::default();
+        for nested in &imported.imports {
+            let nested = self.resolve_import(nested, Some(resolved))?;
+            exports.merge(nested);
+        }
+        for td in imported.types {
+            log::info!("Registered type '{}'", td.name());
+            exports.types.insert(td.name().to_string(), td);
+        }
+        for flow in imported.flows {
+            log::info!("Imported flow '{}'", flow.name);
+            exports.insert_flow(canonical, flow);
+        }
+        // Top-level code sees exactly the library's own flows and imports
+        let library_flows = exports.flows.iter().map(|(name, (_, flow))| (name.clone(), flow.clone())).collect();
+        let library_types = exports.types.clone();
+        let saved_flows = std::mem::replace(&mut self.flows, library_flows);
+        let saved_types = std::mem::replace(&mut self.types, library_types);
+        let result = self.run_setup(&imported.setup, import_path);
+        self.flows = saved_flows;
+        self.types = saved_types;
+        result?;
+        Ok(exports)
+    }
+
+    /// Make an import's flows and types callable from the program.
+    fn register_exports(&mut self, exports: Exports) {
+        for (name, td) in exports.types {
+            log::info!("Registered type '{}'", name);
+            self.types.insert(name, td);
+        }
+        for (name, (source, flow)) in exports.flows {
+            if let Some(previous) = self.flow_sources.get(&name).filter(|p| **p != source) {
+                crate::diagnostics::warning(format!("flow '{}' is defined in both '{}' and '{}'; using the latter", name, previous, source));
+            }
+            self.flow_sources.insert(name.clone(), source);
+            self.flows.insert(name, flow);
+        }
+    }
+
+    pub fn run_with_base(&mut self, program: &Program, base_path: Option<&std::path::Path>) -> Result<()> {
+        // Resolve imports
+        for import in &program.imports {
+            let exports = self.resolve_import(import, base_path)?;
+            self.register_exports(exports);
+        }
+        warn_unused_imports(program);
+        let program = &crate::optimize::program(program.clone(), &self.limits);
+
+        // Register all types
+        for td in &program.types {
+            log::info!("Registered type '{}'", td.name());
+            self.types.insert(td.name().to_string(), td.clone());
+        }
+
+        // Register all flows
+        for flow in &program.flows {
+            if let Some(source) = self.flow_sources.get(&flow.name) {
+                crate::diagnostics::warning(format!("flow '{}' shadows the one imported from '{}'", flow.name, source));
+            }
+            self.flows.insert(flow.name.clone(), flow.clone());
+        }
+        let file = base_path.map_or_else(|| "<main>".to_string(), |p| p.display().to_string());
+        self.run_setup(&program.setup, &file)?;
+
+        // Find "main" flow, or use the first one
+        let flow = program.flows.iter()
+            .find(|f| f.name == "main")
+            .or_else(|| program.flows.first())
+            .cloned();
+
+        match flow {
+            Some(f) => {
+                // Bind flow parameters — in CLI mode, read from stdin
+                log::info!("Running flow '{}'", f.name);
+                for param in &f.params {
+                    if param.variadic {
+                        self.vars.insert(param.name.clone(), Value::List(Vec::new()));
+                        continue;
+                    }
+                    log::debug!("Reading param '{}' from stdin", param.name);
+                    let val = self.env.lock().unwrap().read_stdin()?;
+                    log::debug!("  {} = {:?}", param.name, val);
+                    self.vars.insert(param.name.clone(), Value::String(val));
+                }
+                let start = std::time::Instant::now();
+                self.trace(TraceEvent::FlowStart { name: f.name.clone(), depth: 0, args: None });
+                self.flow_stack.push(f.name.clone());
+                self.watch_flow_start();
+                let result = self.run_block(&f.body);
+                self.flow_stack.pop();
+                self.trace(TraceEvent::FlowEnd {
+                    name: f.name.clone(), depth: 0,
+                    duration_ms: start.elapsed().as_millis() as u64,
+                    result_type: result.as_ref().ok().map(|_| "None".to_string()),
+                    error: result.as_ref().err().map(|e| e.to_string()),
+                });
+                result?;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Register a type (for REPL use)
+    pub fn register_type(&mut self, td: crate::ast::TypeDef) {
+        self.types.insert(td.name().to_string(), td);
+    }
+
+    /// Register a flow (for REPL use)
+    pub fn register_flow(&mut self, flow: crate::ast::FlowDef) {
+        self.flows.insert(flow.name.clone(), flow);
+    }
+
+    /// Call a flow with no args, keeping current vars (for REPL use)
+    pub fn call_flow_entry(&mut self, name: &str) -> Result<()> {
+        let flow = self.flows.get(name).cloned()
+            .ok_or_else(|| anyhow::anyhow!("unknown flow: {}", name))?;
+        self.run_block(&flow.body)?;
+        Ok(())
+    }
+
+    /// Register a `.cog` library's types and flows without running anything
+    pub fn import_library(&mut self, path: &str) -> Result<()> {
+        self.import_file(path, None)
+    }
+
+    /// Names of all registered flows, sorted
+    pub fn flow_names(&self) -> Vec<std::string::String> {
+        let mut names: Vec<std::string::String> = self.flows.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Flows with a docstring as `think(tools=)` definitions, sorted by
+    /// name: what `cognos serve-mcp` offers as tools.
+    pub fn documented_tools(&self) -> Vec<serde_json::Value> {
+        let mut flows: Vec<&FlowDef> = self.flows.values().filter(|f| f.description.is_some()).collect();
+        flows.sort_by(|a, b| a.name.cmp(&b.name));
+        flows.into_iter().map(|flow| self.flow_to_tool_json(flow, None)).collect()
+    }
+
+    /// One `think(conversation=...)` step, for loops driven from Rust such as
+    /// `cognos chat`. `tools` are flow names, as in `think(tools=...)`.
+    pub fn think_turn(&mut self, model: &str, system: &str, prompt: &str, tools: &[std::string::String], conversation: &[Value], mut tool_results: Option<Vec<Value>>) -> Result<Value> {
+        let max_result = default_max_tool_result();
+        let tool_defs = if tools.is_empty() {
+            None
+        } else {
+            Some(tools.iter().map(|name| self.flows.get(name)
+                .map(|flow| self.flow_to_tool_json(flow, max_result))
+                .ok_or_else(|| anyhow::anyhow!("tools: flow '{}' not defined", name)))
+                .collect::<Result<Vec<_>>>()?)
+        };
+        if let (Some(results), Some(max)) = (tool_results.as_mut(), max_result) {
+            self.truncate_tool_results(results, conversation, max);
+        }
+        self.call_llm_turn(model, system, &[], prompt, tool_defs, conversation, tool_results, CallOptions::default())
+    }
+
+    /// Run the flow a tool call names, with its arguments Map as kwargs
+    pub fn call_tool(&mut self, name: &str, arguments: Value) -> Result<Value> {
+        let kwargs = match arguments {
+            Value::Map(entries) => entries,
+            Value::None => Entries::new(),
+            other => bail!("{}(): tool arguments must be a Map, got {}", name, type_name(&other)),
+        };
+        self.call_flow_as_tool(name, kwargs)
+    }
+
+    /// `call_flow` for a tool call, traced as `tool_exec`.
+    fn call_flow_as_tool(&mut self, name: &str, kwargs: Entries) -> Result<Value> {
+        if !self.flows.contains_key(name) {
+            if let Some(client) = self.mcp_tools.get(name).cloned() {
+                return self.call_mcp_tool(&client, name, kwargs);
+            }
+        }
+        let args_summary = args_summary(kwargs.clone());
+        let start = std::time::Instant::now();
+        let result = self.call_flow(name, vec![], kwargs.into_iter().collect());
+        self.trace(TraceEvent::ToolExec {
+            name: name.to_string(), args_summary,
+            latency_ms: start.elapsed().as_millis() as u64,
+            result_chars: result.as_ref().map(|v| v.to_string().len()).unwrap_or(0),
+            success: result.is_ok(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        });
+        result
+    }
+
+    /// A tool of an `mcp()` server, traced like a flow called as a tool.
+    fn call_mcp_tool(&mut self, client: &crate::mcp::Client, name: &str, kwargs: Entries) -> Result<Value> {
+        let args_summary = args_summary(kwargs.clone());
+        let start = std::time::Instant::now();
+        let result = client.call(name, Value::Map(kwargs).to_json()).map(|json| self.json_to_value(json));
+        self.trace(TraceEvent::ToolExec {
+            name: name.to_string(), args_summary,
+            latency_ms: start.elapsed().as_millis() as u64,
+            result_chars: result.as_ref().map(|v| v.to_string().len()).unwrap_or(0),
+            success: result.is_ok(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        });
+        result
+    }
+
+    /// Call a user-defined flow with positional and keyword arguments
+    fn call_flow(&mut self, name: &str, args: Vec<Value>, kwargs: Vec<(std::string::String, Value)>) -> Result<Value> {
+        let flow = self.flows.get(name).cloned()
+            .ok_or_else(|| anyhow::anyhow!("unknown flow: {}", name))?;
+
+        // Build parameter bindings from positional args + kwargs
+        let mut bindings: HashMap<Name, Value> = HashMap::new();
+
+        // First, bind positional args in order; a variadic parameter takes the rest
+        let variadic = flow.params.iter().position(|p| p.variadic);
+        let positional = variadic.unwrap_or(flow.params.len());
+        if variadic.is_none() && args.len() > positional {
+            bail!("{}() expects {} args, got {}", name, flow.params.len(), args.len());
+        }
+        for (param, val) in flow.params.iter().zip(&args).take(positional) {
+            bindings.insert(param.name.clone(), val.clone());
+        }
+        if let Some(i) = variadic.filter(|_| args.len() > positional) {
+            bindings.insert(flow.params[i].name.clone(), Value::List(args[positional..].to_vec()));
+        }
+
+        // Then, bind kwargs by name
+        for (k, v) in &kwargs {
+            // Check kwarg name is a valid parameter
+            if !flow.params.iter().any(|p| *p.name == **k) {
+                bail!("{}(): unknown keyword argument '{}'", name, k);
+            }
+            // Check for duplicate (already bound by positional)
+            if bindings.contains_key(k.as_str()) {
+                bail!("{}(): duplicate argument for '{}'", name, k);
+            }
+            if variadic.is_some_and(|i| *flow.params[i].name == **k) && !matches!(v, Value::List(_)) {
+                bail!("{}(): *{} passed by keyword must be a List, got {}", name, k, type_name(v));
+            }
+            bindings.insert(Name::from(k.as_str()), v.clone());
+        }
+
+        // Check all params are bound; use defaults if available
+        for param in &flow.params {
+            if !bindings.contains_key(&param.name) {
+                if param.variadic {
+                    bindings.insert(param.name.clone(), Value::List(Vec::new()));
+                } else if let Some(ref default_expr) = param.default {
+                    let val = self.eval(default_expr)?;
+                    bindings.insert(param.name.clone(), val);
+                } else {
+                    bail!("{}(): missing required argument '{}'", name, param.name);
+                }
+            }
+        }
+
+        // Set up new scope (preserve builtins); the caller's vars are moved
+        // aside rather than cloned and restored even if the body fails
+        let mut new_vars = self.builtin_vars();
+        for (k, v) in bindings {
+            new_vars.insert(k, v);
+        }
+        let saved_vars = std::mem::replace(&mut self.vars, new_vars);
+
+        log::info!("Calling flow '{}'", name);
+        let start = std::time::Instant::now();
+        if self.tracer.is_some() {
+            let args = self.is_full_trace().then(|| args_summary(flow.params.iter()
+                .filter_map(|p| self.vars.get(&p.name).map(|v| (p.name.to_string(), v.clone())))
+                .collect()));
+            self.trace(TraceEvent::FlowStart { name: name.to_string(), depth: self.flow_stack.len(), args });
+        }
+        self.flow_stack.push(name.to_string());
+        self.watch_flow_start();
+        let first_future = self.next_future_id;
+        let result = self.run_block(&flow.body);
+        self.flow_stack.pop();
+
+        // Restore vars
+        self.vars = saved_vars;
+        let result = result.map(|r| match r {
+            ControlFlow::Return(v) => v,
+            _ => Value::None,
+        });
+        self.cancel_pending_futures(name, first_future, result.as_ref().ok());
+        if self.tracer.is_some() {
+            self.trace(TraceEvent::FlowEnd {
+                name: name.to_string(), depth: self.flow_stack.len(),
+                duration_ms: start.elapsed().as_millis() as u64,
+                result_type: result.as_ref().ok().map(|v| type_name(v).to_string()),
+                error: result.as_ref().err().map(|e| e.to_string()),
+            });
+        }
+        result
+    }
+
+    /// Run a lambda's body in a scope of its captures and parameters.
+    fn call_lambda(&mut self, lambda: &Lambda, args: Vec<Value>) -> Result<Value> {
+        if args.len() != lambda.params.len() {
+            bail!("lambda {} takes {} argument(s), got {}", Value::Lambda(lambda.clone()), lambda.params.len(), args.len());
+        }
+        let mut new_vars = self.builtin_vars();
+        new_vars.extend(lambda.captured.iter().map(|(k, v)| (k.clone(), v.clone())));
+        new_vars.extend(lambda.params.iter().cloned().zip(args));
+        let saved_vars = std::mem::replace(&mut self.vars, new_vars);
+        let result = self.eval(&lambda.body);
+        self.vars = saved_vars;
+        result
+    }
+
+    /// Call what a higher-order method was given: a lambda, or a flow's name.
+    fn call_function(&mut self, method: &str, function: &Value, args: Vec<Value>) -> Result<Value> {
+        match function {
+            Value::Lambda(lambda) => self.call_lambda(lambda, args),
+            Value::String(name) if self.flows.contains_key(name.as_str()) => self.call_flow(name, args, Vec::new()),
+            Value::String(name) => bail!(".{}(): no flow named '{}'", method, name),
+            other => bail!(".{}() takes a lambda or a flow's name, got {}", method, type_name(other)),
+        }
+    }
+
+    /// The builtin handles a fresh scope starts with.
+    fn builtin_vars(&self) -> HashMap<Name, Value> {
+        let mut vars = HashMap::new();
+        for key in &["stdin", "stdout", "http", "grpc"] {
+            if let Some((k, v)) = self.vars.get_key_value(*key) {
+                vars.insert(k.clone(), v.clone());
+            }
+        }
+        vars
+    }
+
+    /// Run a file's top-level statements in a scope of their own, then keep
+    /// what they set as globals.
+    fn run_setup(&mut self, setup: &[Stmt], file: &str) -> Result<()> {
+        if setup.is_empty() {
+            return Ok(());
+        }
+        let builtins = self.builtin_vars();
+        let saved_vars = std::mem::replace(&mut self.vars, builtins.clone());
+        let result = self.run_block(setup);
+        let scope = std::mem::replace(&mut self.vars, saved_vars);
+        result.map_err(|e| anyhow::anyhow!("in top-level code of '{}': {}", file, e))?;
+        let globals = Arc::make_mut(&mut self.globals);
+        for (name, value) in scope {
+            if !builtins.contains_key(&name) {
+                globals.insert(name, value);
+            }
+        }
+        Ok(())
+    }
+
+    fn run_block(&mut self, stmts: &[Stmt]) -> Result<ControlFlow> {
+        for stmt in stmts {
+            if self.cancelled.load(Ordering::Relaxed) {
+                return Ok(ControlFlow::Normal);
+            }
+            if self.flow_stack.len() == 1 && self.autosave.is_some() {
+                self.autosave_if_due();
+            }
+            match self.run_stmt(stmt)? {
+                ControlFlow::Normal => {}
+                other => return Ok(other),
+            }
+        }
+        Ok(ControlFlow::Normal)
+    }
+
+    fn run_stmt(&mut self, stmt: &Stmt) -> Result<ControlFlow> {
+        let result = self.run_stmt_kind(stmt);
+        if !self.watch.names.is_empty() {
+            self.report_watched(stmt.span);
+        }
+        match result {
+            // Outer statements fail in turn; the innermost one says where
+            Err(_) if self.raised_at.is_none() => self.raised_at = Some(self.location(stmt.span)),
+            Err(_) => {}
+            Ok(_) => self.raised_at = None,
+        }
+        result
+    }
+
+    /// `--watch-var`: one line on stderr for each watched variable that
+    /// changed since the last statement, at the statement that changed it.
+    /// Locals are tracked by call depth, so a call's variables aren't taken
+    /// for changes to its caller's.
+    fn report_watched(&mut self, span: Span) {
+        for name in &self.watch.names {
+            let (depth, now) = match self.vars.get(name) {
+                Some(local) => (self.flow_stack.len(), local),
+                None => match self.globals.get(name) {
+                    Some(global) => (0, global),
+                    None => continue,
+                },
+            };
+            let key = (depth, name.clone());
+            let before = self.watch.seen.get(&key);
+            if before.is_some_and(|before| value_eq(before, now)) {
+                continue;
+            }
+            let change = describe_change(name, before, now);
+            let at = self.location(span);
+            let place = match (&at.flow, &at.file) {
+                (Some(flow), Some(file)) => format!("{} in {}, line {}", flow, file, at.line),
+                (Some(flow), None) => format!("{}, line {}", flow, at.line),
+                (None, _) => format!("top level, line {}", at.line),
+            };
+            eprintln!("[watch] {}  ({})", change, place);
+            self.watch.seen.insert(key, now.clone());
+        }
+    }
+
+    /// `--watch-var` at the start of a flow call: forget the last call at
+    /// this depth and take the watched parameters as they came in.
+    fn watch_flow_start(&mut self) {
+        if self.watch.names.is_empty() {
+            return;
+        }
+        let depth = self.flow_stack.len();
+        self.watch.seen.retain(|(d, _), _| *d < depth);
+        for name in &self.watch.names {
+            if let Some(value) = self.vars.get(name) {
+                self.watch.seen.insert((depth, name.clone()), value.clone());
+            }
+        }
+    }
+
+    /// Where `span` is in the code now running.
+    fn location(&self, span: Span) -> ErrorLocation {
+        let flow = self.flow_stack.last().cloned();
+        let file = flow.as_ref().and_then(|f| self.flow_sources.get(f))
+            .or_else(|| self.import_stack.last())
+            .cloned();
+        ErrorLocation { flow, file, line: span.line, col: span.col }
+    }
+
+    /// Where the error `run`/`run_with_base` last returned was raised, if it
+    /// came from a statement.
+    pub fn error_location(&self) -> Option<&ErrorLocation> {
+        self.raised_at.as_ref()
+    }
+
+    /// The variable `name` to change in place. A global changed through a
+    /// path becomes a local, as with `name = ...`.
+    fn local_mut(&mut self, name: &Name) -> Result<&mut Value> {
+        if !self.vars.contains_key(name) {
+            let global = self.globals.get(name).cloned()
+                .ok_or_else(|| anyhow::anyhow!("undefined variable: '{}'", name))?;
+            self.vars.insert(name.clone(), global);
+        }
+        Ok(self.vars.get_mut(name).expect("bound above"))
+    }
+
+    fn run_stmt_kind(&mut self, stmt: &Stmt) -> Result<ControlFlow> {
+        if let (Some(tracer), StmtKind::For { .. } | StmtKind::Loop { .. } | StmtKind::While { .. }) = (&self.tracer, &stmt.kind) {
+            let id = tracer.next_loop_id();
+            self.loops.push(crate::trace::LoopFrame { id, iteration: 0 });
+            let result = self.exec_stmt(stmt);
+            self.loops.pop();
+            return result;
+        }
+        self.exec_stmt(stmt)
+    }
+
+    /// One pass through a loop body, counted in the innermost trace loop frame.
+    fn run_iteration(&mut self, body: &[Stmt]) -> Result<ControlFlow> {
+        if let Some(frame) = self.loops.last_mut() {
+            frame.iteration += 1;
+        }
+        self.run_block(body)
+    }
+
+    fn exec_stmt(&mut self, stmt: &Stmt) -> Result<ControlFlow> {
+        match &stmt.kind {
+            StmtKind::Pass => Ok(ControlFlow::Normal),
+
+            StmtKind::Assign { name, expr } => {
+                let val = self.eval(expr)?;
+                self.vars.insert(name.clone(), val);
+                Ok(ControlFlow::Normal)
+            }
+
+            StmtKind::AssignPath { name, path, expr } => {
+                let val = self.eval(expr)?;
+                let mut keys = Vec::with_capacity(path.len());
+                for step in path {
+                    keys.push(match step {
+                        PathStep::Field(field) => Value::String(field.clone()),
+                        PathStep::Index(index) => self.eval(index)?,
+                    });
+                }
+                self.limits.check_nesting_at(keys.len(), &val)?;
+                let limits = self.limits;
+                set_path(self.local_mut(name)?, &keys, val, &limits)?;
+                Ok(ControlFlow::Normal)
+            }
+
+            StmtKind::Emit { value } => {
+                // emit(x) is sugar for write(stdout, x)
+                let val = self.eval(value)?;
+                self.env.lock().unwrap().print(&val.to_string())?;
+                Ok(ControlFlow::Normal)
+            }
+
+            StmtKind::Return { value } => {
+                let val = self.eval(value)?;
+                Ok(ControlFlow::Return(val))
+            }
+
+            StmtKind::Break => Ok(ControlFlow::Break),
+            StmtKind::Continue => Ok(ControlFlow::Continue),
+
+            StmtKind::Expr(expr) => {
+                self.eval(expr)?;
+                Ok(ControlFlow::Normal)
+            }
+
+            StmtKind::If { condition, body, elifs, else_body } => {
+                let cond = self.eval(condition)?;
+                if cond.is_truthy() {
+                    return self.run_block(body);
+                }
+                for (elif_cond, elif_body) in elifs {
+                    let c = self.eval(elif_cond)?;
+                    if c.is_truthy() {
+                        return self.run_block(elif_body);
+                    }
+                }
+                if !else_body.is_empty() {
+                    return self.run_block(else_body);
+                }
+                Ok(ControlFlow::Normal)
+            }
+
+            StmtKind::With { resource, name, body } => {
+                let resource = self.eval(resource)?;
+                let resource = self.open_resource(resource)?;
+                self.vars.insert(name.clone(), resource.clone());
+                let result = self.run_block(body);
+                self.close_resource(&resource);
+                result
+            }
+
+            StmtKind::TryCatch { body, catches } => {
+                self.raised_at = None;
+                let e = match self.run_block(body) {
+                    Ok(cf) => return Ok(cf),
+                    Err(e) => e,
+                };
+                let raised_at = self.raised_at.clone();
+                let source = raised_at.as_ref().and_then(|at| at.flow.clone())
+                    .or_else(|| self.flow_stack.last().cloned());
+                let kind = error_kind(&e);
+                let Some(catch) = catches.iter().find(|c| c.catches_all() || c.kind.as_deref() == Some(kind)) else {
+                    return Err(e);
+                };
+                if let Some(var) = &catch.var {
+                    let error = Entries::from([
+                        ("kind".to_string(), Value::String(kind.to_string())),
+                        ("message".to_string(), Value::String(e.to_string())),
+                        ("source".to_string(), source.map_or(Value::None, Value::String)),
+                        ("line".to_string(), raised_at.as_ref().map_or(Value::None, |at| Value::Int(at.line as i64))),
+                    ]);
+                    self.vars.insert(var.clone(), Value::Map(error));
+                }
+                self.raised_at = None;
+                self.run_block(&catch.body)
+            }
+
+            StmtKind::For { var, value_var, iterable, body } => {
+                let collection = self.eval(iterable)?;
+                match (&collection, value_var) {
+                    (Value::Map(entries), Some(vv)) => {
+                        // for key, value in map:
+                        let entries = entries.clone();
+                        for (k, v) in entries {
+                            self.vars.insert(var.clone(), Value::String(k));
+                            self.vars.insert(vv.clone(), v);
+                            match self.run_iteration(body)? {
+                                ControlFlow::Break => break,
+                                ControlFlow::Continue => continue,
+                                ControlFlow::Return(v) => return Ok(ControlFlow::Return(v)),
+                                ControlFlow::Normal => {}
                             }
-                            Err(e) if e.to_string().contains("EOF") => {
-                                self.trace(TraceEvent::IoOp {
-                                    operation: "read".into(), handle_type: "stdin".into(),
-                                    path: None, bytes: 0,
-                                    content: Some("EOF".into()),
-                                });
-                                Ok(Value::None)
+                        }
+                    }
+                    (Value::List(items), Some(vv)) => {
+                        // for index, value in list:
+                        let items = items.clone();
+                        for (i, item) in items.into_iter().enumerate() {
+                            self.vars.insert(var.clone(), Value::Int(i as i64));
+                            self.vars.insert(vv.clone(), item);
+                            match self.run_iteration(body)? {
+                                ControlFlow::Break => break,
+                                ControlFlow::Continue => continue,
+                                ControlFlow::Return(v) => return Ok(ControlFlow::Return(v)),
+                                ControlFlow::Normal => {}
                             }
-                            Err(e) => Err(e),
                         }
                     }
-                    Handle::Stdout => bail!("cannot read from stdout"),
-                    Handle::File(path) => {
-                        let content = self.env.lock().unwrap().read_file(&path)?;
-                        let full = self.is_full_trace();
-                        self.trace(TraceEvent::IoOp {
-                            operation: "read".into(), handle_type: "file".into(),
-                            path: Some(path), bytes: content.len(),
-                            content: if full { Some(content.chars().take(1000).collect()) } else { None },
-                        });
-                        Ok(Value::String(content))
+                    (_, Some(_)) => bail!("two-variable for loop requires a Map or List"),
+                    _ => {
+                        // Single variable iteration
+                        let items: Vec<Value> = match collection {
+                            Value::List(items) => items,
+                            Value::Map(entries) => entries.into_iter()
+                                .map(|(k, _)| Value::String(k))
+                                .collect(),
+                            Value::String(s) => s.chars()
+                                .map(|c| Value::String(c.to_string()))
+                                .collect(),
+                            other => bail!("cannot iterate over {} (type: {})", other, type_name(&other)),
+                        };
+                        for item in items {
+                            self.vars.insert(var.clone(), item);
+                            match self.run_iteration(body)? {
+                                ControlFlow::Break => break,
+                                ControlFlow::Continue => continue,
+                                ControlFlow::Return(v) => return Ok(ControlFlow::Return(v)),
+                                ControlFlow::Normal => {}
+                            }
+                        }
+                    }
+                }
+                Ok(ControlFlow::Normal)
+            }
+
+            StmtKind::Parallel { branches, collect } => {
+                self.run_parallel(branches, collect.as_ref())?;
+                Ok(ControlFlow::Normal)
+            }
+
+            StmtKind::Select { branches } => {
+                self.run_select(branches)
+            }
+
+            StmtKind::Loop { max, body } => {
+                match max {
+                    Some(limit) => {
+                        for _ in 0..*limit {
+                            match self.run_iteration(body)? {
+                                ControlFlow::Break => break,
+                                ControlFlow::Continue => continue,
+                                ControlFlow::Return(v) => return Ok(ControlFlow::Return(v)),
+                                ControlFlow::Normal => {}
+                            }
+                        }
+                    }
+                    None => {
+                        loop {
+                            match self.run_iteration(body)? {
+                                ControlFlow::Break => break,
+                                ControlFlow::Continue => continue,
+                                ControlFlow::Return(v) => return Ok(ControlFlow::Return(v)),
+                                ControlFlow::Normal => {}
+                            }
+                        }
+                    }
+                }
+                Ok(ControlFlow::Normal)
+            }
+
+            StmtKind::Match { subject, arms } => {
+                let value = self.eval(subject)?;
+                let mut bound = Vec::new();
+                for arm in arms {
+                    if arm.patterns.iter().any(|p| pattern_matches(p, &value, &mut bound)) {
+                        for (name, v) in bound {
+                            self.vars.insert(name, v);
+                        }
+                        return self.run_block(&arm.body);
+                    }
+                }
+                Ok(ControlFlow::Normal)
+            }
+
+            StmtKind::While { condition, body } => {
+                while self.eval(condition)?.is_truthy() {
+                    match self.run_iteration(body)? {
+                        ControlFlow::Break => break,
+                        ControlFlow::Continue => continue,
+                        ControlFlow::Return(v) => return Ok(ControlFlow::Return(v)),
+                        ControlFlow::Normal => {}
+                    }
+                }
+                Ok(ControlFlow::Normal)
+            }
+        }
+    }
+
+    fn run_parallel(&mut self, branches: &[Branch], collect: Option<&Name>) -> Result<()> {
+        // Each branch runs concurrently as a block of statements, on a
+        // snapshot of the vars and conversation history; see merge_branch.
+        // Collecting blocks keep each branch's result instead of its vars.
+        let env = self.env.clone();
+        let flows = self.flows.clone();
+        let types = self.types.clone();
+        let vars = self.vars.clone();
+        let history = self.conversation_history.clone();
+        let tracer = self.tracer.clone();
+        let memory = self.memory.clone();
+        let ollama = self.ollama.clone();
+        let flow_stack = self.flow_stack.clone();
+        let flow_overrides = self.flow_overrides.clone();
+        let bundle = self.bundle.clone();
+        let globals = self.globals.clone();
+
+        let results: Vec<Result<(BranchChanges, Value)>> = std::thread::scope(|s| {
+            let handles: Vec<_> = branches.iter().enumerate().map(|(i, branch)| {
+                let label = branch_label(self.branch.as_deref(), i, branch);
+                let loops = self.loops.clone();
+                let limits = self.limits;
+                let llm_retry = self.llm_retry;
+                let allow_dynamic = self.allow_dynamic;
+                let dynamic_flows = self.dynamic_flows.clone();
+                let mcp_tools = self.mcp_tools.clone();
+                let watch = self.watch.clone();
+                let env = env.clone();
+                let flows = flows.clone();
+                let types = types.clone();
+                let vars = vars.clone();
+                let history = &history;
+                let tracer = tracer.clone();
+                let memory = memory.clone();
+                let ollama = ollama.clone();
+                let flow_stack = flow_stack.clone();
+                let flow_overrides = flow_overrides.clone();
+                let bundle = bundle.clone();
+                let globals = globals.clone();
+                let branch = branch.clone();
+                s.spawn(move || {
+                    let mut interp = Interpreter {
+                        vars: vars.clone(),
+                        flows,
+                        types,
+                        env,
+                        tracer,
+                        import_stack: Vec::new(),
+                        imported: HashMap::new(),
+                        bundle,
+                        flow_sources: HashMap::new(),
+                        conversation_history: history.clone(),
+                        next_future_id: 0,
+                        async_handles: HashMap::new(),
+                        cancelled: Arc::new(AtomicBool::new(false)),
+                        memory: memory.clone(),
+                        ollama,
+                        warmup: None,
+                        flow_stack,
+                        flow_overrides,
+                        globals,
+                        autosave: None,
+                        branch: Some(label),
+                        loops,
+                        limits,
+                        llm_retry,
+                        allow_dynamic,
+                        dynamic_flows,
+                        mcp_tools,
+                        watch,
+                        raised_at: None,
+                    };
+                    let result = interp.run_branch(&branch.body)?;
+                    let changes = BranchChanges::between(&vars, history, interp);
+                    let changes = match &branch.name {
+                        Some(name) if collect.is_none() => changes.namespaced(name),
+                        _ => changes,
+                    };
+                    Ok((changes, result))
+                })
+            }).collect();
+
+            handles.into_iter().map(|h| {
+                match h.join() {
+                    Ok(r) => r,
+                    Err(_) => Err(anyhow::anyhow!("parallel branch panicked")),
+                }
+            }).collect()
+        });
+
+        let mut errors = Vec::new();
+        let mut changes = Vec::new();
+        let mut values = Vec::new();
+        for result in results {
+            match result {
+                Ok((branch, value)) => {
+                    changes.push(branch);
+                    values.push(value);
+                }
+                Err(e) => errors.push(e),
+            }
+        }
+        if !errors.is_empty() {
+            let msgs: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+            bail!("parallel block errors:\n  {}", msgs.join("\n  "));
+        }
+
+        if let Some(target) = collect {
+            // A list in branch order, or a map once any branch is named
+            // (unnamed ones keyed by position); only history is merged.
+            let collected = if branches.iter().any(|b| b.name.is_some()) {
+                Value::Map(branches.iter().enumerate().zip(values)
+                    .map(|((i, b), v)| (b.name.as_ref().map_or_else(|| (i + 1).to_string(), |n| n.to_string()), v))
+                    .collect())
+            } else {
+                Value::List(values)
+            };
+            for branch in changes {
+                self.conversation_history.extend(branch.history);
+            }
+            self.vars.insert(target.clone(), collected);
+            return Ok(());
+        }
+
+        // Two branches may only set the same variable if they agree on it
+        let mut set_by: HashMap<Name, (usize, Value)> = HashMap::new();
+        for (i, branch) in changes.iter().enumerate() {
+            for (name, value) in &branch.vars {
+                if let Some((first, earlier)) = set_by.get(name) {
+                    if !value_eq(earlier, value) {
+                        bail!("parallel branches {} and {} both set '{}' to different values; \
+                            collect results from branches in a shared() map instead", first + 1, i + 1, name);
+                    }
+                }
+                set_by.insert(name.clone(), (i, value.clone()));
+            }
+        }
+        for branch in changes {
+            self.merge_branch(branch);
+        }
+        Ok(())
+    }
+
+    /// Run a parallel branch's body for its result: the value of a `return`,
+    /// else of a bare expression ending the branch, else none.
+    fn run_branch(&mut self, body: &[Stmt]) -> Result<Value> {
+        let (last, init) = match body.split_last() {
+            Some((Stmt { kind: StmtKind::Expr(expr), .. }, init)) => (Some(expr), init),
+            _ => (None, body),
+        };
+        if let ControlFlow::Return(v) = self.run_block(init)? {
+            return Ok(v);
+        }
+        match last {
+            Some(expr) if !self.cancelled.load(Ordering::Relaxed) => self.eval(expr),
+            _ => Ok(Value::None),
+        }
+    }
+
+    /// Take in a finished branch's changes: its new and changed variables,
+    /// and the conversation turns it added, after any earlier branch's.
+    fn merge_branch(&mut self, branch: BranchChanges) {
+        self.vars.extend(branch.vars);
+        self.conversation_history.extend(branch.history);
+    }
+
+    fn run_select(&mut self, branches: &[Branch]) -> Result<ControlFlow> {
+        let env = self.env.clone();
+        let flows = self.flows.clone();
+        let types = self.types.clone();
+        let vars = self.vars.clone();
+        let history = self.conversation_history.clone();
+        let tracer = self.tracer.clone();
+        let memory = self.memory.clone();
+        let ollama = self.ollama.clone();
+        let flow_stack = self.flow_stack.clone();
+        let flow_overrides = self.flow_overrides.clone();
+        let bundle = self.bundle.clone();
+        let globals = self.globals.clone();
+        let cancelled = Arc::new(AtomicBool::new(false));
+
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        let mut handles = Vec::new();
+        for (i, branch) in branches.iter().enumerate() {
+            let label = branch_label(self.branch.as_deref(), i, branch);
+            let loops = self.loops.clone();
+            let limits = self.limits;
+            let llm_retry = self.llm_retry;
+            let allow_dynamic = self.allow_dynamic;
+            let dynamic_flows = self.dynamic_flows.clone();
+            let mcp_tools = self.mcp_tools.clone();
+            let watch = self.watch.clone();
+            let env = env.clone();
+            let flows = flows.clone();
+            let types = types.clone();
+            let vars = vars.clone();
+            let history = history.clone();
+            let tracer = tracer.clone();
+            let memory = memory.clone();
+            let ollama = ollama.clone();
+            let flow_stack = flow_stack.clone();
+            let flow_overrides = flow_overrides.clone();
+            let bundle = bundle.clone();
+            let globals = globals.clone();
+            let branch = branch.clone();
+            let cancelled = cancelled.clone();
+            let tx = tx.clone();
+
+            let handle = std::thread::spawn(move || {
+                let mut interp = Interpreter {
+                    vars: vars.clone(),
+                    flows,
+                    types,
+                    env,
+                    tracer,
+                    import_stack: Vec::new(),
+                    imported: HashMap::new(),
+                    bundle,
+                    flow_sources: HashMap::new(),
+                    conversation_history: history.clone(),
+                    next_future_id: 0,
+                    async_handles: HashMap::new(),
+                    cancelled: cancelled.clone(),
+                    memory: memory.clone(),
+                    ollama,
+                    warmup: None,
+                    flow_stack,
+                    flow_overrides,
+                    globals,
+                    autosave: None,
+                    branch: Some(label),
+                    loops,
+                    limits,
+                    llm_retry,
+                    allow_dynamic,
+                    dynamic_flows,
+                    mcp_tools,
+                    watch,
+                    raised_at: None,
+                };
+                let mut flow = ControlFlow::Normal;
+                for stmt in &branch.body {
+                    if cancelled.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    match interp.run_stmt(stmt) {
+                        Ok(ControlFlow::Normal) => {}
+                        Ok(cf) => { flow = cf; break; }
+                        Err(_) => return,
+                    }
+                }
+                let changes = BranchChanges::between(&vars, &history, interp);
+                let changes = match &branch.name {
+                    Some(name) => changes.namespaced(name),
+                    None => changes,
+                };
+                let _ = tx.send((i, changes, flow));
+            });
+            handles.push(handle);
+        }
+        drop(tx);
+
+        // Wait for first branch to complete
+        let mut result_flow = ControlFlow::Normal;
+        match rx.recv() {
+            Ok((_winner_idx, winner, flow)) => {
+                // Cancel all other branches; only the winner's changes count
+                cancelled.store(true, Ordering::Relaxed);
+                self.merge_branch(winner);
+                result_flow = flow;
+            }
+            Err(_) => {
+                // All branches failed without sending
+            }
+        }
+
+        // Don't join — let threads die on their own (they check cancelled flag)
+        // They'll be cleaned up when handles are dropped
+        for h in handles {
+            let _ = h.join();
+        }
+
+        Ok(result_flow)
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Result<Value> {
+        match expr {
+            Expr::Lambda { params, body } => {
+                let mut used = HashSet::new();
+                expr_names_used(body, &mut used);
+                let captured = self.vars.iter()
+                    .filter(|(k, _)| used.contains(&***k) && !params.contains(k))
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect();
+                Ok(Value::Lambda(Lambda { params: params.clone(), body: Arc::new((**body).clone()), captured: Arc::new(captured) }))
+            }
+            Expr::StringLit(s) => Ok(Value::String(s.clone())),
+            Expr::IntLit(n) => Ok(Value::Int(*n)),
+            Expr::FloatLit(n) => Ok(Value::Float(*n)),
+            Expr::BoolLit(b) => Ok(Value::Bool(*b)),
+            Expr::NoneLiteral => Ok(Value::None),
+
+            Expr::Ident(name) => {
+                match self.vars.get(name).or_else(|| self.globals.get(name)) {
+                    Some(v) => Ok(v.clone()),
+                    None => {
+                        if crate::help::lookup(name).is_some() || crate::plugin::builtin(name).is_some() {
+                            bail!("'{}' is a function — did you mean {}(...)?", name, name)
+                        } else if self.flows.contains_key(&**name) {
+                            bail!("'{}' is a flow — did you mean {}(...)?", name, name)
+                        } else {
+                            bail!("undefined variable: '{}'", name)
+                        }
+                    }
+                }
+            }
+
+            Expr::Async(inner) => {
+                // Spawn the expression evaluation in a background thread
+                let env = self.env.clone();
+                let flows = self.flows.clone();
+                let types = self.types.clone();
+                let vars = self.vars.clone();
+                let history = self.conversation_history.clone();
+                let tracer = self.tracer.clone();
+                let memory = self.memory.clone();
+                let ollama = self.ollama.clone();
+                let flow_stack = self.flow_stack.clone();
+                let flow_overrides = self.flow_overrides.clone();
+                let bundle = self.bundle.clone();
+                let globals = self.globals.clone();
+                let branch = self.branch.clone();
+                let loops = self.loops.clone();
+                let limits = self.limits;
+                let llm_retry = self.llm_retry;
+                let allow_dynamic = self.allow_dynamic;
+                let dynamic_flows = self.dynamic_flows.clone();
+                let mcp_tools = self.mcp_tools.clone();
+                let watch = self.watch.clone();
+                let inner = (**inner).clone();
+                let cancel_token = Arc::new(AtomicBool::new(false));
+                let cancel_token2 = cancel_token.clone();
+
+                let handle = std::thread::spawn(move || {
+                    let mut interp = Interpreter {
+                        vars,
+                        flows,
+                        types,
+                        env,
+                        tracer,
+                        import_stack: Vec::new(),
+                        imported: HashMap::new(),
+                        bundle,
+                        flow_sources: HashMap::new(),
+                        conversation_history: history,
+                        next_future_id: 0,
+                        async_handles: HashMap::new(),
+                        cancelled: cancel_token2,
+                        memory,
+                        ollama,
+                        warmup: None,
+                        flow_stack,
+                        flow_overrides,
+                        globals,
+                        autosave: None,
+                        branch,
+                        loops,
+                        limits,
+                        llm_retry,
+                        allow_dynamic,
+                        dynamic_flows,
+                        mcp_tools,
+                        watch,
+                        raised_at: None,
+                    };
+                    interp.eval(&inner)
+                });
+
+                let id = self.next_future_id;
+                self.next_future_id += 1;
+                self.async_handles.insert(id, (handle, cancel_token));
+                Ok(Value::Future(id))
+            }
+
+            Expr::List(items) => {
+                let vals = items.iter().map(|i| self.eval(i)).collect::<Result<Vec<Value>>>()?;
+                self.limits.check_nesting(&vals)?;
+                Ok(Value::List(vals))
+            }
+
+            Expr::Map(entries) => {
+                let mut result = Entries::new();
+                for (k, v) in entries {
+                    let val = self.eval(v)?;
+                    result.insert(k.clone(), val);
+                }
+                self.limits.check_nesting(result.iter().map(|(_, v)| v))?;
+                Ok(Value::Map(result))
+            }
+
+            Expr::FString(parts) => {
+                let mut result = std::string::String::new();
+                for part in parts {
+                    match part {
+                        crate::ast::FStringPart::Literal(s) => result.push_str(s),
+                        crate::ast::FStringPart::Expr(e) => {
+                            let val = self.eval(e)?;
+                            result.push_str(&val.to_string());
+                        }
+                        crate::ast::FStringPart::Formatted(e, spec) => {
+                            let val = self.eval(e)?;
+                            result.push_str(&format_with_spec(&val, spec)?);
+                        }
                     }
-                    Handle::Channel { ref provider, ref config } => {
-                        match provider.as_str() {
-                            "slack" => self.read_slack_channel(config),
-                            _ => bail!("read() not supported for channel provider '{}'", provider),
+                }
+                self.limits.check_string(result.len())?;
+                Ok(Value::String(result))
+            }
+
+            Expr::Call { name, args, kwargs } => {
+                self.call_builtin(name, args, kwargs)
+            }
+
+            Expr::Field { object, field } => {
+                let val = self.eval(object)?;
+                // Module constants: math.pi, math.e
+                if let Value::Module(ref mod_name) = val {
+                    bail!("{} has no constant '{}'", mod_name, field);
+                }
+                match (&val, field.as_str()) {
+                    (Value::String(s), "length") => Ok(Value::Int(s.len() as i64)),
+                    (Value::String(s), "content") => Ok(Value::String(s.clone())),
+                    (Value::List(l), "length") => Ok(Value::Int(l.len() as i64)),
+                    (Value::Map(e), "length") => Ok(Value::Int(e.len() as i64)),
+                    (Value::Handle(Handle::Mcp(client)), "tools") => Ok(Value::List(client.tools.iter().map(|tool| Value::Map(Entries::from([
+                        ("name".to_string(), Value::String(tool.name.clone())),
+                        ("description".to_string(), Value::String(tool.description.clone())),
+                        ("parameters".to_string(), Value::from_json(tool.input_schema.clone())),
+                    ]))).collect())),
+                    (Value::Map(_), _) => {
+                        match val.get_field(field) {
+                            Some(v) => Ok(v.clone()),
+                            None => bail!("map has no key '{}'", field),
                         }
                     }
+                    _ => bail!("cannot access field '{}' on {} (type: {})", field, val, type_name(&val)),
                 }
             }
-            "write" => {
-                if args.len() < 2 { bail!("write(handle, content) — e.g. write(stdout, \"hello\") or write(file(\"path\"), content)"); }
-                let handle = match self.eval(&args[0])? {
-                    Value::Handle(h) => h,
-                    other => bail!("write() first argument must be a handle, got {}", type_name(&other)),
-                };
-                let content = self.eval(&args[1])?.to_string();
-                match handle {
-                    Handle::Stdin => bail!("cannot write to stdin"),
-                    Handle::Stdout => {
-                        self.env.lock().unwrap().write_stdout(&content)?;
-                        let full = self.is_full_trace();
-                        self.trace(TraceEvent::IoOp {
-                            operation: "write".into(), handle_type: "stdout".into(),
-                            path: None, bytes: content.len(),
-                            content: if full { Some(content) } else { None },
-                        });
-                        Ok(Value::None)
+
+            Expr::Index { object, index } => {
+                let val = self.eval(object)?;
+                let idx = self.eval(index)?;
+                match (&val, &idx) {
+                    (Value::List(items), Value::Int(i)) => {
+                        let i = if *i < 0 { items.len() as i64 + i } else { *i } as usize;
+                        items.get(i).cloned()
+                            .ok_or_else(|| anyhow::anyhow!("index {} out of range (list has {} elements)", i, items.len()))
                     }
-                    Handle::File(path) => {
-                        self.env.lock().unwrap().write_file(&path, &content)?;
-                        let full = self.is_full_trace();
-                        self.trace(TraceEvent::IoOp {
-                            operation: "write".into(), handle_type: "file".into(),
-                            path: Some(path), bytes: content.len(),
-                            content: if full { Some(content) } else { None },
-                        });
-                        Ok(Value::None)
+                    (Value::String(s), Value::Int(i)) => {
+                        let chars: Vec<char> = s.chars().collect();
+                        let i = if *i < 0 { chars.len() as i64 + i } else { *i } as usize;
+                        chars.get(i).map(|c| Value::String(c.to_string()))
+                            .ok_or_else(|| anyhow::anyhow!("index {} out of range (string has {} characters)", i, chars.len()))
                     }
-                    Handle::Channel { ref provider, ref config } => {
-                        match provider.as_str() {
-                            "slack" => self.write_slack_channel(config, &content),
-                            _ => bail!("write() not supported for channel provider '{}'", provider),
-                        }
+                    (Value::Map(entries), Value::String(key)) => {
+                        entries.get(key).cloned()
+                            .ok_or_else(|| anyhow::anyhow!("map has no key '{}'", key))
                     }
+                    _ => bail!("cannot index {} with {} (type: {}[{}])", type_name(&val), idx, type_name(&val), type_name(&idx)),
                 }
             }
-            "int" => {
-                // int(value) — cast to integer
-                if args.is_empty() { bail!("int() requires one argument"); }
-                let val = self.eval(&args[0])?;
-                match val {
-                    Value::Int(n) => Ok(Value::Int(n)),
-                    Value::Float(f) => Ok(Value::Int(f as i64)),
-                    Value::String(s) => {
-                        let trimmed = s.trim();
-                        trimmed.parse::<i64>()
-                            .map(Value::Int)
-                            .map_err(|_| anyhow::anyhow!("cannot convert '{}' to Int", trimmed))
+
+            Expr::Slice { object, start, end } => {
+                let val = self.eval(object)?;
+                let s = start.as_ref().map(|e| self.eval(e)).transpose()?;
+                let e = end.as_ref().map(|e| self.eval(e)).transpose()?;
+
+                // Helper to resolve a slice index (supports negative)
+                fn resolve_slice_idx(idx: i64, len: usize) -> usize {
+                    if idx < 0 {
+                        let resolved = len as i64 + idx;
+                        if resolved < 0 { 0 } else { resolved as usize }
+                    } else {
+                        (idx as usize).min(len)
                     }
-                    Value::Bool(b) => Ok(Value::Int(if b { 1 } else { 0 })),
-                    other => bail!("cannot convert {} to Int", type_name(&other)),
                 }
-            }
-            "float" => {
-                if args.is_empty() { bail!("float() requires one argument"); }
-                let val = self.eval(&args[0])?;
+
                 match val {
-                    Value::Float(f) => Ok(Value::Float(f)),
-                    Value::Int(n) => Ok(Value::Float(n as f64)),
-                    Value::String(s) => {
-                        let trimmed = s.trim();
-                        trimmed.parse::<f64>()
-                            .map(Value::Float)
-                            .map_err(|_| anyhow::anyhow!("cannot convert '{}' to Float", trimmed))
+                    Value::String(ref sv) => {
+                        let chars: Vec<char> = sv.chars().collect();
+                        let len = chars.len();
+                        let start_idx = match s { Some(Value::Int(i)) => resolve_slice_idx(i, len), None => 0, _ => bail!("slice start must be Int") };
+                        let end_idx = match e { Some(Value::Int(i)) => resolve_slice_idx(i, len), None => len, _ => bail!("slice end must be Int") };
+                        if start_idx >= end_idx { return Ok(Value::String(String::new())); }
+                        Ok(Value::String(chars[start_idx..end_idx].iter().collect()))
                     }
-                    other => bail!("cannot convert {} to Float", type_name(&other)),
+                    Value::List(ref items) => {
+                        let len = items.len();
+                        let start_idx = match s { Some(Value::Int(i)) => resolve_slice_idx(i, len), None => 0, _ => bail!("slice start must be Int") };
+                        let end_idx = match e { Some(Value::Int(i)) => resolve_slice_idx(i, len), None => len, _ => bail!("slice end must be Int") };
+                        if start_idx >= end_idx { return Ok(Value::List(vec![])); }
+                        Ok(Value::List(items[start_idx..end_idx].to_vec()))
+                    }
+                    other => bail!("cannot slice {} (type: {})", other, type_name(&other)),
                 }
             }
-            "str" => {
-                if args.is_empty() { bail!("str() requires one argument"); }
-                let val = self.eval(&args[0])?;
-                Ok(Value::String(val.to_string()))
-            }
-            "eval" => {
-                // eval(source, vars={}) — parse and execute Cognos source code at runtime.
-                // Any flows defined in the source are registered in the current interpreter.
-                // Variables from the optional `vars` Map are injected into scope before execution.
-                // Returns the last expression value, or None if the code only has statements.
-                if args.is_empty() {
-                    bail!("eval() requires a source string: eval(\"flow main(): ...\")");
-                }
-                let source = self.eval(&args[0])?.to_string();
-                
-                // Optional: inject variables from a Map
-                let inject_vars = if args.len() > 1 {
-                    match self.eval(&args[1])? {
-                        Value::Map(entries) => entries,
-                        other => bail!("eval() second argument must be a Map, got {}", type_name(&other)),
-                    }
+
+            Expr::MethodCall { object, method, args, kwargs } => {
+                let in_place = if LIST_MUTATORS.contains(&method.as_str()) && kwargs.is_empty() {
+                    self.mutate_list(object, method, args)?
                 } else {
-                    vec![]
-                };
-                
-                // Parse the source — try as-is first, then wrap in a flow if needed
-                let program = {
-                    let mut lexer = crate::lexer::Lexer::new(&source);
-                    let tokens = lexer.tokenize();
-                    let mut parser = crate::parser::Parser::new(tokens);
-                    match parser.parse_program() {
-                        Ok(prog) => prog,
-                        Err(_) => {
-                            // If parsing fails, wrap in a synthetic main flow
-                            // Indent each line by 4 spaces for the flow body
-                            let indented: String = source.lines()
-                                .map(|line| format!("    {}", line))
-                                .collect::<Vec<_>>()
-                                .join("\n");
-                            let wrapped = format!("flow __eval_main__():\n{}", indented);
-                            let mut lexer2 = crate::lexer::Lexer::new(&wrapped);
-                            let tokens2 = lexer2.tokenize();
-                            let mut parser2 = crate::parser::Parser::new(tokens2);
-                            parser2.parse_program()
-                                .map_err(|e| anyhow::anyhow!("eval() parse error: {}", e))?
+                    ListCall::NotAPlace
+                };
+                let (val, arg_vals) = match in_place {
+                    ListCall::Done(result) => return Ok(result),
+                    ListCall::Other(val, arg_vals) => (val, arg_vals),
+                    ListCall::NotAPlace => {
+                        let val = self.eval(object)?;
+                        let mut arg_vals = Vec::new();
+                        for a in args {
+                            arg_vals.push(self.eval(a)?);
                         }
+                        (val, arg_vals)
                     }
                 };
-                
-                // Register any flows defined in the eval'd code
-                for flow in &program.flows {
-                    log::info!("eval: registered flow '{}'", flow.name);
-                    self.flows.insert(flow.name.clone(), flow.clone());
+                let mut kwarg_vals = Vec::new();
+                for (k, v) in kwargs {
+                    kwarg_vals.push((k.clone(), self.eval(v)?));
                 }
-                
-                // Register any types
-                for td in &program.types {
-                    self.types.insert(td.name().to_string(), td.clone());
+                if let Value::Future(id) = val {
+                    return self.call_future_method(id, method, arg_vals, kwarg_vals);
                 }
-                
-                // Inject variables into current scope
-                for (k, v) in inject_vars {
-                    self.vars.insert(k, v);
+                if let Value::Module(ref mod_name) = val {
+                    return self.call_module(mod_name, method, arg_vals, kwarg_vals);
                 }
-                
-                // Handle imports in eval'd code
-                for import_path in &program.imports {
-                    let resolved = std::path::PathBuf::from(import_path);
-                    let import_source = std::fs::read_to_string(&resolved)
-                        .map_err(|e| anyhow::anyhow!("eval() import error '{}': {}", import_path, e))?;
-                    let mut import_lexer = crate::lexer::Lexer::new(&import_source);
-                    let import_tokens = import_lexer.tokenize();
-                    let mut import_parser = crate::parser::Parser::new(import_tokens);
-                    let imported = import_parser.parse_program()
-                        .map_err(|e| anyhow::anyhow!("eval() import parse error '{}': {}", import_path, e))?;
-                    for flow in &imported.flows {
-                        self.flows.insert(flow.name.clone(), flow.clone());
+                if let Some((k, _)) = kwarg_vals.first() {
+                    bail!(".{}(): unknown kwarg '{}'", method, k);
+                }
+                self.call_method(val, method, arg_vals)
+            }
+
+            // `a and b` / `a or b` only evaluate `b` when `a` doesn't decide
+            // the result, and return whichever operand did
+            Expr::BinOp { left, op: op @ (BinOp::And | BinOp::Or), right } => {
+                let l = self.eval(left)?;
+                if l.is_truthy() == matches!(op, BinOp::Or) {
+                    return Ok(l);
+                }
+                self.eval(right)
+            }
+
+            Expr::BinOp { left, op, right } => {
+                let l = self.eval(left)?;
+                let r = self.eval(right)?;
+                self.eval_binop(&l, op, &r)
+            }
+
+            Expr::UnaryOp { op, operand } => {
+                let v = self.eval(operand)?;
+                unary(op, v)
+            }
+
+            Expr::Compare { left, rest } => {
+                let mut l = self.eval(left)?;
+                for (op, right) in rest {
+                    let r = self.eval(right)?;
+                    if !self.eval_binop(&l, op, &r)?.is_truthy() {
+                        return Ok(Value::Bool(false));
                     }
+                    l = r;
                 }
-                
-                // If there's a __eval_main__ (wrapped bare statements), execute its body directly
-                // in the current scope so injected variables are visible.
-                // Save vars snapshot so we can restore on failure (prevents scope corruption).
-                if let Some(eval_flow) = program.flows.iter().find(|f| f.name == "__eval_main__") {
-                    let saved_vars = self.vars.clone();
-                    match self.run_block(&eval_flow.body) {
-                        Ok(_) => return Ok(Value::None),
-                        Err(e) => {
-                            // Restore caller's scope on eval failure
-                            self.vars = saved_vars;
-                            return Err(e);
+                Ok(Value::Bool(true))
+            }
+        }
+    }
+
+    fn call_builtin(&mut self, name: &str, args: &[Expr], kwargs: &[(std::string::String, Expr)]) -> Result<Value> {
+        match name {
+            "print" | "emit" | "pprint" if name != "pprint" || !self.flows.contains_key(name) => {
+                let mut pretty = None;
+                let mut opts = PrettyOptions { color: crate::diagnostics::enabled(crate::diagnostics::Stream::Stdout), ..Default::default() };
+                for (k, v) in kwargs {
+                    let val = self.eval(v)?;
+                    let limit = match (k.as_str(), &val) {
+                        ("pretty", _) => {
+                            pretty = Some(val.is_truthy());
+                            continue;
                         }
+                        ("depth" | "max_items" | "max_string", Value::Int(n)) if *n >= 0 => Some(*n as usize),
+                        ("depth" | "max_items" | "max_string", _) => bail!("{}(): {}= must be a non-negative Int, got {}", name, k, val),
+                        _ => bail!("{}(): unknown kwarg '{}'", name, k),
+                    };
+                    match k.as_str() {
+                        "depth" => opts.depth = limit,
+                        "max_items" => opts.max_items = limit,
+                        _ => opts.max_string = limit,
                     }
                 }
-                
-                // If there's a "main" flow, call it
-                if let Some(_) = program.flows.iter().find(|f| f.name == "main") {
-                    return self.call_flow("main", vec![], vec![]);
+                // Any limit implies pretty output unless pretty=false says otherwise
+                let limited = opts.depth.is_some() || opts.max_items.is_some() || opts.max_string.is_some();
+                let pretty = pretty.unwrap_or(name == "pprint" || limited);
+                let mut line = std::string::String::new();
+                for (i, arg) in args.iter().enumerate() {
+                    let val = self.eval(arg)?;
+                    if pretty {
+                        if i > 0 { line.push('\n'); }
+                        line.push_str(&pretty_value(&val, opts));
+                    } else {
+                        if i > 0 { line.push(' '); }
+                        line.push_str(&val.to_string());
+                    }
                 }
-                
-                // Otherwise just register flows and return None
+                self.env.lock().unwrap().print(&line)?;
                 Ok(Value::None)
             }
-            "invoke" => {
-                // invoke(name, args) — call a flow by string name with a Map of arguments
+            "think" => {
                 if args.is_empty() {
-                    bail!("invoke() requires a flow name: invoke(\"flow_name\", {{\"arg\": value}})");
+                    bail!("think() requires at least one argument");
                 }
-                let flow_name = self.eval(&args[0])?.to_string();
-                let flow_args = if args.len() > 1 {
-                    self.eval(&args[1])?
+                let context = self.eval(&args[0])?;
+
+                let mut model = default_model();
+                let mut system = std::string::String::new();
+                let mut format_type: Option<std::string::String> = None;
+                let mut tool_names: Vec<std::string::String> = Vec::new();
+                // builtin_tool() definitions, sent to the provider as they are
+                let mut native_tools: Vec<serde_json::Value> = Vec::new();
+                let mut image_paths: Vec<std::string::String> = Vec::new();
+                let mut conversation: Option<Vec<Value>> = None;
+                let mut tool_results: Option<Vec<Value>> = None;
+                let mut max_tool_result = default_max_tool_result();
+                let mut examples: Vec<(std::string::String, std::string::String)> = Vec::new();
+                let mut ollama = self.ollama.clone();
+                let mut options = CallOptions::default();
+                let mut auto_continue = false;
+                let mut samples = 1;
+                let mut reduce: Option<std::string::String> = None;
+                let mut judge_model: Option<std::string::String> = None;
+                let mut repair = 0;
+                
+                for (k, v) in kwargs {
+                    let val = self.eval(v)?;
+                    match k.as_str() {
+                        "model" => model = val.to_string(),
+                        "system" => system = self.compose_system(val)?,
+                        "format" => format_type = Some(val.to_string()),
+                        "conversation" => {
+                            match val {
+                                Value::List(items) => conversation = Some(items),
+                                Value::None => conversation = None,
+                                _ => bail!("conversation= must be a List or none, got {}", type_name(&val)),
+                            }
+                        }
+                        "tool_results" => {
+                            match val {
+                                Value::List(items) => tool_results = Some(items),
+                                Value::None => tool_results = None,
+                                _ => bail!("tool_results= must be a List or none, got {}", type_name(&val)),
+                            }
+                        }
+                        "endpoint" => match val {
+                            Value::String(url) if url.starts_with("http://") || url.starts_with("https://") => options.endpoint = Some(url),
+                            Value::None => options.endpoint = None,
+                            _ => bail!("endpoint= must be an http(s) URL such as \"http://localhost:8000/v1\", got {}", val),
+                        },
+                        "logprobs" => match val {
+                            Value::Bool(b) => options.logprobs = b,
+                            _ => bail!("logprobs= must be a Bool, got {}", type_name(&val)),
+                        },
+                        "api_key" => match val {
+                            Value::String(key) => options.api_key = Some(key),
+                            Value::None => options.api_key = None,
+                            _ => bail!("api_key= must be a String, got {}", type_name(&val)),
+                        },
+                        "files" => {
+                            options.files = match val {
+                                Value::List(items) => items.iter().map(|id| id.to_string()).collect(),
+                                Value::None => Vec::new(),
+                                id => vec![id.to_string()],
+                            }
+                        }
+                        "images" => {
+                            if let Value::List(items) = val {
+                                for item in items {
+                                    image_paths.push(item.to_string());
+                                }
+                            } else {
+                                // Single image path
+                                image_paths.push(val.to_string());
+                            }
+                        }
+                        "examples" => {
+                            examples = match val {
+                                Value::List(items) => items.iter().map(few_shot_example).collect::<Result<_>>()?,
+                                Value::None => Vec::new(),
+                                _ => bail!("examples= must be a List of Maps, got {}", type_name(&val)),
+                            }
+                        }
+                        "tools" => {
+                            if let Value::List(items) = val {
+                                for item in items {
+                                    match item {
+                                        Value::Map(_) => native_tools.push(item.to_json()),
+                                        // Every tool of an mcp() server
+                                        Value::Handle(Handle::Mcp(client)) => {
+                                            for tool in &client.tools {
+                                                self.mcp_tools.insert(tool.name.clone(), client.clone());
+                                                native_tools.push(tool.to_tool_json());
+                                            }
+                                        }
+                                        other => tool_names.push(other.to_string()),
+                                    }
+                                }
+                            } else {
+                                bail!("tools= must be a list, got {}", type_name(&val));
+                            }
+                        }
+                        "keep_alive" => {
+                            ollama.keep_alive = match val {
+                                Value::Int(secs) => Some(serde_json::json!(secs)),
+                                Value::String(s) => Some(serde_json::json!(s)),
+                                _ => bail!("keep_alive= must be a duration String or Int seconds, got {}", type_name(&val)),
+                            }
+                        }
+                        "num_ctx" => {
+                            match val {
+                                Value::Int(n) if n > 0 => ollama.num_ctx = Some(n),
+                                _ => bail!("num_ctx= must be a positive Int, got {}", val),
+                            }
+                        }
+                        "temperature" => {
+                            options.temperature = match val {
+                                Value::Float(t) if (0.0..=2.0).contains(&t) => Some(t),
+                                Value::Int(t) if (0..=2).contains(&t) => Some(t as f64),
+                                _ => bail!("temperature= must be a number from 0 to 2, got {}", val),
+                            }
+                        }
+                        "auto_continue" => match val {
+                            Value::Bool(b) => auto_continue = b,
+                            _ => bail!("auto_continue= must be a Bool, got {}", type_name(&val)),
+                        },
+                        "json_mode" => match val {
+                            Value::Bool(b) => options.json = b,
+                            _ => bail!("json_mode= must be a Bool, got {}", type_name(&val)),
+                        },
+                        "stream" => match val {
+                            Value::Bool(b) => options.stream = b,
+                            _ => bail!("stream= must be a Bool, got {}", type_name(&val)),
+                        },
+                        "on_token" => match val {
+                            Value::String(flow) if self.flows.contains_key(&flow) => options.on_token = Some(flow),
+                            Value::String(flow) => bail!("on_token: flow '{}' not defined", flow),
+                            _ => bail!("on_token= must be a flow name, got {}", type_name(&val)),
+                        },
+                        "retries" => match val {
+                            Value::Int(n) if n >= 0 => options.retries = Some(n.min(u32::MAX as i64) as u32),
+                            _ => bail!("retries= must be a non-negative Int, got {}", val),
+                        },
+                        "backoff" => match val {
+                            Value::String(s) => options.backoff = Some(crate::retry::Backoff::parse(&s)?),
+                            _ => bail!("backoff= must be a String, got {}", type_name(&val)),
+                        },
+                        "n" => match val {
+                            Value::Int(n) if (1..=MAX_SAMPLES as i64).contains(&n) => samples = n as usize,
+                            _ => bail!("n= must be an Int from 1 to {}, got {}", MAX_SAMPLES, val),
+                        },
+                        "reduce" => match val {
+                            Value::String(r) if r == "majority" || r == "judge" => reduce = Some(r),
+                            Value::None => reduce = None,
+                            _ => bail!("reduce= must be \"majority\" or \"judge\", got {}", val),
+                        },
+                        "repair" => match val {
+                            Value::Int(n) if n >= 0 => repair = n as usize,
+                            _ => bail!("repair= must be a non-negative Int, got {}", val),
+                        },
+                        "judge" => match val {
+                            Value::String(m) => judge_model = Some(m),
+                            Value::None => judge_model = None,
+                            _ => bail!("judge= must be a model name, got {}", type_name(&val)),
+                        },
+                        "max_tool_result" => {
+                            max_tool_result = match val {
+                                Value::Int(n) if n > 0 => Some(n as usize),
+                                Value::Int(0) | Value::None => None,
+                                _ => bail!("max_tool_result= must be a non-negative Int or none, got {}", val),
+                            }
+                        }
+                        _ => bail!("think(): unknown kwarg '{}'", k),
+                    }
+                }
+
+                if options.on_token.is_some() && !options.stream {
+                    bail!("think(): on_token= only takes effect with stream=true");
+                }
+                if options.logprobs && (format_type.is_some() || options.json) {
+                    bail!("think(): logprobs= returns a Map around the reply text, so it can't be combined with format= or json_mode=; parse the content yourself");
+                }
+                if options.api_key.is_some() && options.endpoint.is_none() {
+                    bail!("think(): api_key= is for the server given with endpoint=; providers' own keys come from the environment");
+                }
+                if options.stream && (!tool_names.is_empty() || !native_tools.is_empty() || conversation.is_some()) {
+                    bail!("think(): stream=true can't be combined with tools= or conversation=");
+                }
+                if samples > 1 && (options.stream || options.logprobs || auto_continue || conversation.is_some() || !tool_names.is_empty() || !native_tools.is_empty()) {
+                    bail!("think(): n= samples plain replies, so it can't be combined with tools=, conversation=, stream=, auto_continue= or logprobs=");
+                }
+                if repair > 0 && format_type.is_none() && !options.json {
+                    bail!("think(): repair= re-asks when a format= reply doesn't validate, so it needs format=");
+                }
+                if repair > 0 && conversation.is_some() {
+                    bail!("think(): repair= only works without conversation=");
+                }
+                if reduce.is_some() && samples < 2 {
+                    bail!("think(): reduce= combines several samples — ask for them with n=");
+                }
+                if judge_model.is_some() && reduce.as_deref() != Some("judge") {
+                    bail!("think(): judge= names the model for reduce=\"judge\"");
+                }
+
+                // The provider-side JSON mode still needs telling what to write
+                if options.json && format_type.is_none() {
+                    format_type = Some("json".to_string());
+                }
+
+                // If format= is a type name, inject schema into system prompt
+                if let Some(ref type_name) = format_type {
+                    let schema_instruction = if type_name == "json" {
+                        "Respond ONLY with valid JSON. No markdown, no explanation.".to_string()
+                    } else if let Some(td) = self.types.get(type_name).cloned() {
+                        // A type the schema can't express is left to the prompt alone
+                        options.schema = self.json_schema(&TypeExpr::Named(type_name.clone()), &mut Vec::new()).ok();
+                        let schema = self.type_to_schema(&td);
+                        format!("Respond ONLY with valid JSON matching this exact schema:\n{}\nNo markdown, no explanation, just the JSON object.", schema)
+                    } else {
+                        bail!("think(): unknown format type '{}' — define it with: type {}: ...", type_name, type_name)
+                    };
+                    if system.is_empty() {
+                        system = schema_instruction;
+                    } else {
+                        system = format!("{}\n\n{}", system, schema_instruction);
+                    }
+                }
+
+                // Build tool definitions from flow signatures
+                let tool_defs = if !tool_names.is_empty() || !native_tools.is_empty() {
+                    let mut tools = Vec::new();
+                    for name in &tool_names {
+                        let Some(flow) = self.flows.get(name).cloned() else {
+                            match self.mcp_tools.get(name).and_then(|client| client.tools.iter().find(|t| t.name == *name)) {
+                                Some(tool) => tools.push(tool.to_tool_json()),
+                                None => bail!("tools: flow '{}' not defined", name),
+                            }
+                            continue;
+                        };
+                        tools.push(self.flow_to_tool_json(&flow, max_tool_result));
+                    }
+                    tools.extend(native_tools);
+                    Some(tools)
                 } else {
-                    Value::Map(vec![])
+                    None
                 };
 
-                // Convert Map to kwargs
-                let kwarg_vals = match flow_args {
-                    Value::Map(entries) => entries,
-                    other => bail!("invoke() second argument must be a Map, got {}", type_name(&other)),
-                };
+                let prompt_text = context.to_string();
 
-                self.call_flow(&flow_name, vec![], kwarg_vals)
-            }
-            "__exec_shell__" => {
-                if !self.env.lock().unwrap().allow_shell() {
-                    bail!("shell execution is disabled — use: cognos run --allow-shell file.cog");
+                // Multi-turn conversation mode
+                if let Some(ref conv) = conversation {
+                    if auto_continue {
+                        bail!("think(): auto_continue= only works without conversation=; check the reply's finish_reason instead");
+                    }
+                    if let (Some(results), Some(max)) = (tool_results.as_mut(), max_tool_result) {
+                        self.truncate_tool_results(results, conv, max);
+                    }
+                    return self.call_llm_turn(&model, &system, &examples, &prompt_text, tool_defs, conv, tool_results, options);
                 }
-                if args.is_empty() { bail!("__exec_shell__() requires a command string"); }
-                let cmd = self.eval(&args[0])?.to_string();
-                log::info!("__exec_shell__ → {:?}", cmd);
-                let shell_start = std::time::Instant::now();
-                let result = self.env.lock().unwrap().exec_shell(&cmd)?;
-                let shell_output = if self.is_full_trace() { Some(result.stdout.clone()) } else { None };
-                self.trace(TraceEvent::ShellExec {
-                    command: cmd, latency_ms: shell_start.elapsed().as_millis() as u64,
-                    exit_code: result.exit_code, output_chars: result.stdout.len(), output: shell_output,
-                });
-                Ok(Value::String(result.stdout))
-            }
-            "save" => {
-                // save(path, value) — persist a value as JSON via Env
-                if args.len() < 2 { bail!("save(path, value)"); }
-                let path = self.eval(&args[0])?.to_string();
-                let value = self.eval(&args[1])?;
-                let json = self.value_to_json(&value);
-                let content = serde_json::to_string_pretty(&json)?;
-                self.env.lock().unwrap().write_file(&path, &content)?;
-                log::info!("Saved to {}", path);
-                Ok(Value::None)
-            }
-            "write_text" => {
-                // write_text(path, content) — write raw text to a file
-                if args.len() < 2 { bail!("write_text(path, content)"); }
-                let path = self.eval(&args[0])?.to_string();
-                let content = self.eval(&args[1])?.to_string();
-                // Create parent directories if needed
-                if let Some(parent) = std::path::Path::new(&path).parent() {
-                    std::fs::create_dir_all(parent).ok();
+
+                // Several samples of the same call, optionally reduced to one
+                if samples > 1 {
+                    let replies = self.sample_llm(samples, &model, &system, &examples, &prompt_text, &image_paths, &ollama, options.clone())?;
+                    let values = match format_type {
+                        Some(ref tn) => replies.into_iter()
+                            .map(|mut reply| self.parse_or_repair(&mut reply, tn, repair, &model, &system, &prompt_text, &ollama, options.clone()))
+                            .collect::<Result<Vec<_>>>()?,
+                        None => replies.into_iter().map(Value::String).collect(),
+                    };
+                    let Some(reduce) = reduce else { return Ok(Value::List(values)) };
+                    let chosen = if reduce == "majority" {
+                        majority(&values)
+                    } else {
+                        let judge_model = judge_model.unwrap_or_else(|| model.clone());
+                        self.judge_samples(&judge_model, &prompt_text, &values, &ollama, options)?
+                    };
+                    let votes = values.iter().filter(|v| sample_key(v) == sample_key(&values[chosen])).count();
+                    log::info!("think(): {} samples, {} chose #{} ({} vote(s))", values.len(), reduce, chosen + 1, votes);
+                    self.conversation_history.push(("user".to_string(), prompt_text));
+                    self.conversation_history.push(("assistant".to_string(), values[chosen].to_string()));
+                    return Ok(Value::Map(Entries::from([
+                        ("content".to_string(), values[chosen].clone()),
+                        ("samples".to_string(), Value::List(values)),
+                        ("votes".to_string(), Value::Int(votes as i64)),
+                    ])));
+                }
+
+                // Single-turn mode (no conversation)
+                let with_tools = tool_defs.is_some();
+                let mut reply = self.request_llm(&model, &system, &examples, &prompt_text, tool_defs, &image_paths, &ollama, options.clone())?;
+                if auto_continue {
+                    reply = self.continue_truncated(reply, &model, &system, &prompt_text, &ollama, options.clone())?;
+                }
+                // A reply streamed to stdout ends its line
+                if options.stream && options.on_token.is_none() && !reply.content.is_empty() && !reply.content.ends_with('\n') {
+                    self.env.lock().unwrap().write_stdout("")?;
+                }
+                warn_incomplete(&model, reply.finish_reason.as_deref());
+
+                // think() without tools= returns String; with tools= returns Map
+                // If format= specified, parse JSON and validate against type
+                let parsed = match format_type {
+                    Some(ref tn) => Some(self.parse_or_repair(&mut reply.content, tn, repair, &model, &system, &prompt_text, &ollama, options)),
+                    None => None,
+                };
+
+                // Track conversation history
+                self.conversation_history.push(("user".to_string(), prompt_text.clone()));
+                self.conversation_history.push(("assistant".to_string(), reply.content.clone()));
+
+                match parsed {
+                    Some(parsed) => parsed,
+                    None => Ok(reply.into_value(with_tools)),
                 }
-                self.env.lock().unwrap().write_file(&path, &content)?;
-                log::info!("write_text: {} ({} bytes)", path, content.len());
-                Ok(Value::None)
-            }
-            "read_text" => {
-                // read_text(path) — read raw text from a file
-                if args.is_empty() { bail!("read_text(path)"); }
-                let path = self.eval(&args[0])?.to_string();
-                let content = self.env.lock().unwrap().read_file(&path)?;
-                log::info!("read_text: {} ({} bytes)", path, content.len());
-                Ok(Value::String(content))
-            }
-            "load" => {
-                // load(path) — load a JSON file back to a Value via Env
-                if args.is_empty() { bail!("load(path)"); }
-                let path = self.eval(&args[0])?.to_string();
-                let content = self.env.lock().unwrap().read_file(&path)?;
-                let json: serde_json::Value = serde_json::from_str(&content)
-                    .map_err(|e| anyhow::anyhow!("load JSON error: {}", e))?;
-                log::info!("Loaded from {}", path);
-                Ok(self.json_to_value(json))
             }
-            "remember" => {
-                if args.is_empty() { bail!("remember(text) requires a string argument"); }
+            // A user flow of the same name takes precedence
+            "classify" if !self.flows.contains_key(name) => {
+                if args.is_empty() {
+                    bail!("classify() requires text to classify");
+                }
                 let text = self.eval(&args[0])?.to_string();
-                // Check for score= kwarg → remember_scored
-                let mut score: Option<f64> = None;
+                let mut model = default_model();
+                let mut labels: Vec<std::string::String> = Vec::new();
                 for (k, v) in kwargs {
-                    if k == "score" {
-                        score = Some(match self.eval(v)? {
-                            Value::Float(f) => f,
-                            Value::Int(i) => i as f64,
-                            other => bail!("remember(score=) must be a number, got {:?}", other),
-                        });
+                    let val = self.eval(v)?;
+                    match k.as_str() {
+                        "model" => model = val.to_string(),
+                        "labels" => match val {
+                            Value::List(items) => labels = items.iter().map(|i| i.to_string()).collect(),
+                            _ => bail!("labels= must be a List of Strings, got {}", type_name(&val)),
+                        },
+                        _ => bail!("classify(): unknown kwarg '{}'", k),
                     }
                 }
-                let mem = self.get_memory()?;
-                if let Some(s) = score {
-                    mem.remember_scored(&text, s)?;
-                } else {
-                    mem.remember(&text)?;
+                if labels.is_empty() {
+                    bail!("classify() requires labels=[...]");
+                }
+                let system = format!(
+                    "Classify the user's text into exactly one of these labels: {}.\nRespond with the label only, nothing else.",
+                    labels.join(", "));
+                let ollama = self.ollama.clone();
+                let answer = self.call_llm(&model, &system, &[], &text, None, &[], &ollama, CallOptions::default())?.to_string();
+                if let Some(label) = match_label(&answer, &labels) {
+                    return Ok(Value::String(label.clone()));
+                }
+                // One retry, telling the model what was wrong
+                log::info!("classify(): off-list answer '{}', retrying", answer);
+                let retry = format!("{}\n\n(Your previous answer '{}' is not one of the labels. Answer with exactly one of: {}.)",
+                    text, answer.trim(), labels.join(", "));
+                let answer = self.call_llm(&model, &system, &[], &retry, None, &[], &ollama, CallOptions::default())?.to_string();
+                match match_label(&answer, &labels) {
+                    Some(label) => Ok(Value::String(label.clone())),
+                    None => bail!("classify(): model answered '{}', which is not one of: {}", answer.trim(), labels.join(", ")),
                 }
-                Ok(Value::None)
             }
-            "recall" => {
-                if args.is_empty() { bail!("recall(query) requires a query string"); }
-                let query = self.eval(&args[0])?.to_string();
-                let limit = if args.len() > 1 {
-                    match self.eval(&args[1])? {
-                        Value::Int(n) => n as usize,
-                        _ => 5,
-                    }
-                } else {
-                    // Check kwargs for limit=
-                    let mut lim = 5usize;
-                    for (k, v) in kwargs {
-                        if k == "limit" {
-                            if let Value::Int(n) = self.eval(v)? {
-                                lim = n as usize;
-                            }
-                        }
+            "judge" if !self.flows.contains_key(name) => {
+                if args.len() < 2 {
+                    bail!("judge() requires an answer and criteria: judge(answer, criteria)");
+                }
+                let answer = self.eval(&args[0])?.to_string();
+                let criteria = self.eval(&args[1])?.to_string();
+                let mut model = default_model();
+                let mut question: Option<std::string::String> = None;
+                let mut reference: Option<std::string::String> = None;
+                let mut threshold = 0.75;
+                for (k, v) in kwargs {
+                    let val = self.eval(v)?;
+                    match k.as_str() {
+                        "model" => model = val.to_string(),
+                        "question" => question = (!matches!(val, Value::None)).then(|| val.to_string()),
+                        "reference" => reference = (!matches!(val, Value::None)).then(|| val.to_string()),
+                        "threshold" => threshold = match val {
+                            Value::Float(t) if (0.0..=1.0).contains(&t) => t,
+                            Value::Int(t) if (0..=1).contains(&t) => t as f64,
+                            _ => bail!("threshold= must be a number from 0 to 1, got {}", val),
+                        },
+                        _ => bail!("judge(): unknown kwarg '{}'", k),
                     }
-                    lim
-                };
-                let mem = self.get_memory()?;
-                let facts = mem.recall(&query, limit)?;
-                Ok(Value::List(facts.into_iter().map(Value::String).collect()))
+                }
+                self.judge(&model, &answer, &criteria, question.as_deref(), reference.as_deref(), threshold)
             }
-            "recall_scored" => {
-                if args.is_empty() { bail!("recall_scored(query) requires a query string"); }
-                let query = self.eval(&args[0])?.to_string();
-                let limit = if args.len() > 1 {
-                    match self.eval(&args[1])? {
-                        Value::Int(n) => n as usize,
-                        _ => 5,
-                    }
-                } else {
-                    let mut lim = 5usize;
-                    for (k, v) in kwargs {
-                        if k == "limit" {
-                            if let Value::Int(n) = self.eval(v)? {
-                                lim = n as usize;
-                            }
-                        }
+            "extract" if !self.flows.contains_key(name) => {
+                if args.is_empty() {
+                    bail!("extract() requires text to extract from");
+                }
+                let text = self.eval(&args[0])?.to_string();
+                let mut model = default_model();
+                let mut type_arg: Option<std::string::String> = None;
+                let mut chunk_size: usize = 2000;
+                let mut dedup = true;
+                for (k, v) in kwargs {
+                    let val = self.eval(v)?;
+                    match k.as_str() {
+                        "model" => model = val.to_string(),
+                        "type" => type_arg = Some(val.to_string()),
+                        "chunk_size" => match val {
+                            Value::Int(n) if n > 0 => chunk_size = n as usize,
+                            _ => bail!("chunk_size= must be a positive Int, got {}", val),
+                        },
+                        "dedup" => dedup = val.is_truthy(),
+                        _ => bail!("extract(): unknown kwarg '{}'", k),
                     }
-                    lim
+                }
+                let type_arg = type_arg
+                    .ok_or_else(|| anyhow::anyhow!("extract() requires type=\"TypeName\" or type=\"List[TypeName]\""))?;
+                let (item_type, many) = match type_arg.strip_prefix("List[").and_then(|t| t.strip_suffix(']')) {
+                    Some(inner) => (inner.trim().to_string(), true),
+                    None => (type_arg.clone(), false),
                 };
-                let mem = self.get_memory()?;
-                let results = mem.recall_scored(&query, limit)?;
-                let maps: Vec<Value> = results.into_iter().map(|(text, similarity, quality)| {
-                    Value::Map(vec![
-                        ("text".to_string(), Value::String(text)),
-                        ("similarity".to_string(), Value::Float(similarity)),
-                        ("score".to_string(), Value::Float(quality)),
-                    ])
-                }).collect();
-                Ok(Value::List(maps))
-            }
-            "forget" => {
-                if args.is_empty() { bail!("forget(query) requires a query string"); }
-                let query = self.eval(&args[0])?.to_string();
-                let mem = self.get_memory()?;
-                let removed = mem.forget(&query)?;
-                Ok(Value::Int(removed as i64))
-            }
-            "await" => {
-                if args.is_empty() { bail!("await() requires a future handle"); }
-                let val = self.eval(&args[0])?;
-                match val {
-                    Value::Future(id) => {
-                        let (handle, _cancel_token) = self.async_handles.remove(&id)
-                            .ok_or_else(|| anyhow::anyhow!("invalid or already-consumed future handle {}", id))?;
-                        if _cancel_token.load(Ordering::Relaxed) {
-                            bail!("async task was cancelled");
-                        }
-                        match handle.join() {
-                            Ok(result) => result,
-                            Err(_) => bail!("async task panicked"),
-                        }
-                    }
-                    other => bail!("await() expects a Future, got {} (type: {})", other, type_name(&other)),
+                let td = self.types.get(&item_type).cloned()
+                    .ok_or_else(|| anyhow::anyhow!("extract(): unknown type '{}' — define it with: type {}: ...", item_type, item_type))?;
+                let schema = self.type_to_schema(&td);
+                let ollama = self.ollama.clone();
+
+                if !many {
+                    let system = format!("Extract the {} described in the user's text.\nRespond ONLY with valid JSON matching this exact schema:\n{}\nNo markdown, no explanation, just the JSON.", item_type, schema);
+                    let raw = self.call_llm(&model, &system, &[], &text, None, &[], &ollama, CallOptions::default())?;
+                    let parsed = self.parse_json_response(&raw)?;
+                    self.check_type(&parsed, &td)?;
+                    return Ok(parsed);
                 }
-            }
-            "cancel" => {
-                if args.is_empty() { bail!("cancel() requires a future handle"); }
-                let val = self.eval(&args[0])?;
-                match val {
-                    Value::Future(id) => {
-                        if let Some((_handle, cancel_token)) = self.async_handles.remove(&id) {
-                            cancel_token.store(true, Ordering::Relaxed);
+
+                // List[T]: one call per chunk, results concatenated in document order
+                let system = format!("Extract every {} in the user's text.\nRespond ONLY with a valid JSON array whose items match this exact schema:\n{}\nRespond with [] if there are none. No markdown, no explanation, just the JSON array.", item_type, schema);
+                let chunks = chunk_tokens(&model, &text, chunk_size, 0)?;
+                let mut items = Vec::new();
+                let mut seen = std::collections::HashSet::new();
+                for (i, chunk) in chunks.iter().enumerate() {
+                    log::info!("extract(): chunk {}/{} ({} tokens)", i + 1, chunks.len(), providers::count_tokens(&model, chunk));
+                    let raw = self.call_llm(&model, &system, &[], chunk, None, &[], &ollama, CallOptions::default())?;
+                    let found = match self.parse_json_response(&raw)? {
+                        Value::List(found) => found,
+                        // A lone object where an array was asked for
+                        single @ Value::Map(_) => vec![single],
+                        other => bail!("extract(): expected a JSON array of {}, got {}", item_type, type_name(&other)),
+                    };
+                    for item in found {
+                        self.check_type(&item, &td)
+                            .map_err(|e| anyhow::anyhow!("extract(): chunk {}/{}: {}", i + 1, chunks.len(), e))?;
+                        if dedup && !seen.insert(item.to_json().to_string()) {
+                            continue;
                         }
-                        Ok(Value::None)
+                        items.push(item);
                     }
-                    other => bail!("cancel() expects a Future, got {} (type: {})", other, type_name(&other)),
                 }
+                Ok(Value::List(items))
             }
-            "__map_set__" => {
-                if args.len() < 3 { bail!("__map_set__ requires 3 arguments"); }
-                let map_val = self.eval(&args[0])?;
-                let key_val = self.eval(&args[1])?;
-                let value = self.eval(&args[2])?;
-                match (map_val, key_val) {
-                    (Value::Map(mut entries), Value::String(key)) => {
-                        // Update existing or insert new
-                        if let Some(entry) = entries.iter_mut().find(|(k, _)| k == &key) {
-                            entry.1 = value;
-                        } else {
-                            entries.push((key, value));
-                        }
-                        Ok(Value::Map(entries))
+            "summarize" if !self.flows.contains_key(name) => {
+                if args.is_empty() {
+                    bail!("summarize() requires text or a file handle");
+                }
+                let text = match self.eval(&args[0])? {
+                    Value::Handle(Handle::File(path)) => self.env.lock().unwrap().read_file(&path)
+                        .map_err(|e| anyhow::anyhow!("summarize(): {}", e))?,
+                    other => other.to_string(),
+                };
+                let mut model = default_model();
+                let mut max_words: usize = 200;
+                let mut chunk_size: usize = 3000;
+                for (k, v) in kwargs {
+                    let val = self.eval(v)?;
+                    match k.as_str() {
+                        "model" => model = val.to_string(),
+                        "max_words" => match val {
+                            Value::Int(n) if n > 0 => max_words = n as usize,
+                            _ => bail!("max_words= must be a positive Int, got {}", val),
+                        },
+                        "chunk_size" => match val {
+                            Value::Int(n) if n > 0 => chunk_size = n as usize,
+                            _ => bail!("chunk_size= must be a positive Int, got {}", val),
+                        },
+                        _ => bail!("summarize(): unknown kwarg '{}'", k),
                     }
-                    (Value::Map(_), other) => bail!("map key must be a String, got {}", type_name(&other)),
-                    (other, _) => bail!("cannot index-assign on {} (type: {})", other, type_name(&other)),
                 }
+                Ok(Value::String(self.summarize(&model, &text, max_words, chunk_size)?))
             }
-            "remove" => {
-                if args.len() < 2 { bail!("remove(map, key) requires two arguments"); }
-                let map_val = self.eval(&args[0])?;
-                let key_val = self.eval(&args[1])?;
-                match (map_val, key_val) {
-                    (Value::Map(entries), Value::String(key)) => {
-                        let new_entries: Vec<(std::string::String, Value)> = entries.into_iter()
-                            .filter(|(k, _)| k != &key)
-                            .collect();
-                        Ok(Value::Map(new_entries))
+            "count_tokens" => {
+                if args.is_empty() {
+                    bail!("count_tokens() requires text");
+                }
+                let text = self.eval(&args[0])?.to_string();
+                let mut model = default_model();
+                for (k, v) in kwargs {
+                    match k.as_str() {
+                        "model" => model = self.eval(v)?.to_string(),
+                        _ => bail!("count_tokens(): unknown kwarg '{}'", k),
                     }
-                    (Value::Map(_), other) => bail!("remove() key must be a String, got {}", type_name(&other)),
-                    (other, _) => bail!("remove() first argument must be a Map, got {}", type_name(&other)),
                 }
+                Ok(Value::Int(providers::count_tokens(&model, &text) as i64))
             }
-            "log" => {
-                for arg in args {
-                    let val = self.eval(arg)?;
-                    eprintln!("[log] {}", val);
+            "chunk_text" => {
+                if args.is_empty() {
+                    bail!("chunk_text() requires text");
                 }
-                Ok(Value::None)
-            }
-            "history" => {
-                let entries: Vec<Value> = self.conversation_history.iter().map(|(role, content)| {
-                    Value::Map(vec![
-                        ("role".to_string(), Value::String(role.clone())),
-                        ("content".to_string(), Value::String(content.clone())),
-                    ])
-                }).collect();
-                Ok(Value::List(entries))
-            }
-            "clear_history" => {
-                self.conversation_history.clear();
-                Ok(Value::None)
-            }
-            _ => {
-                // Try user-defined flow
-                if self.flows.contains_key(name) {
-                    let mut arg_vals = Vec::new();
-                    for arg in args {
-                        arg_vals.push(self.eval(arg)?);
-                    }
-                    let mut kwarg_vals = Vec::new();
-                    for (k, v) in kwargs {
-                        kwarg_vals.push((k.clone(), self.eval(v)?));
+                let text = self.eval(&args[0])?.to_string();
+                let mut model = default_model();
+                let mut max_tokens: usize = 1000;
+                let mut overlap: usize = 100;
+                for (k, v) in kwargs {
+                    let val = self.eval(v)?;
+                    match k.as_str() {
+                        "model" => model = val.to_string(),
+                        "max_tokens" => match val {
+                            Value::Int(n) if n > 0 => max_tokens = n as usize,
+                            _ => bail!("max_tokens= must be a positive Int, got {}", val),
+                        },
+                        "overlap" => match val {
+                            Value::Int(n) if n >= 0 => overlap = n as usize,
+                            _ => bail!("overlap= must be a non-negative Int, got {}", val),
+                        },
+                        _ => bail!("chunk_text(): unknown kwarg '{}'", k),
                     }
-                    return self.call_flow(name, arg_vals, kwarg_vals);
                 }
-                bail!("unknown function: {}()", name)
-            }
-        }
-    }
-
-    fn call_module(&mut self, module: &str, method: &str, args: Vec<Value>) -> Result<Value> {
-        match module {
-            "math" => bail!("math module was removed (P11: lean core runtime). Use shell() for math operations."),
-            "http" => self.call_http(method, args),
-            _ => bail!("unknown module '{}'", module),
-        }
-    }
-
-    fn to_float(v: &Value) -> Result<f64> {
-        match v {
-            Value::Float(f) => Ok(*f),
-            Value::Int(i) => Ok(*i as f64),
-            other => bail!("expected a number, got {} (type: {})", other, type_name(other)),
-        }
-    }
-
-    // math module removed — P11: lean core runtime
-
-    fn call_http(&mut self, method: &str, args: Vec<Value>) -> Result<Value> {
-        match method {
-            "get" => {
-                if args.is_empty() { bail!("http.get() requires a URL"); }
-                let url = args[0].to_string();
-                log::info!("http.get({})", url);
-                let body = self.env.lock().unwrap().http_get(&url)?;
-                Ok(Value::String(body))
-            }
-            "post" => {
-                if args.len() < 2 { bail!("http.post(url, body)"); }
-                let url = args[0].to_string();
-                let body = args[1].to_string();
-                log::info!("http.post({})", url);
-                let resp = self.env.lock().unwrap().http_post(&url, &body)?;
-                Ok(Value::String(resp))
-            }
-            _ => bail!("http has no function '{}'", method),
-        }
-    }
-
-    fn call_method(&mut self, obj: Value, method: &str, args: Vec<Value>) -> Result<Value> {
-        match (&obj, method) {
-            // ── String methods ──
-            (Value::String(s), "upper") => Ok(Value::String(s.to_uppercase())),
-            (Value::String(s), "lower") => Ok(Value::String(s.to_lowercase())),
-            (Value::String(s), "strip") => Ok(Value::String(s.trim().to_string())),
-            (Value::String(s), "starts_with") => {
-                let prefix = self.expect_string_arg(method, &args, 0)?;
-                Ok(Value::Bool(s.starts_with(&prefix)))
-            }
-            (Value::String(s), "ends_with") => {
-                let suffix = self.expect_string_arg(method, &args, 0)?;
-                Ok(Value::Bool(s.ends_with(&suffix)))
+                let chunks = chunk_tokens(&model, &text, max_tokens, overlap)?;
+                Ok(Value::List(chunks.into_iter().map(Value::String).collect()))
             }
-            (Value::String(s), "contains") => {
-                let needle = self.expect_string_arg(method, &args, 0)?;
-                Ok(Value::Bool(s.contains(&needle)))
+            "file" => {
+                if args.is_empty() { bail!("file() requires a path argument"); }
+                let path = self.eval(&args[0])?.to_string();
+                let mut append = false;
+                for (k, v) in kwargs {
+                    match (k.as_str(), self.eval(v)?.to_string().as_str()) {
+                        ("mode", "write") => append = false,
+                        ("mode", "append") => append = true,
+                        ("mode", other) => bail!("file(): mode must be \"write\" or \"append\", got \"{}\"", other),
+                        _ => bail!("file(): unknown kwarg '{}'", k),
+                    }
+                }
+                Ok(Value::Handle(if append { Handle::AppendFile(path) } else { Handle::File(path) }))
             }
-            (Value::String(s), "replace") => {
-                let from = self.expect_string_arg(method, &args, 0)?;
-                let to = self.expect_string_arg(method, &args, 1)?;
-                Ok(Value::String(s.replace(&from, &to)))
+            "channel" => {
+                // channel("slack", token="xoxb-...", channel="#general")
+                if args.is_empty() { bail!("channel() requires a provider name: channel(\"slack\", token=\"...\", channel=\"...\")"); }
+                let provider = self.eval(&args[0])?.to_string();
+                let mut config = HashMap::new();
+                for (k, v) in kwargs {
+                    config.insert(k.clone(), self.eval(v)?.to_string());
+                }
+                // Validate provider-specific config
+                match provider.as_str() {
+                    "slack" => {
+                        if !config.contains_key("token") {
+                            if let Some(c) = crate::auth::lookup("SLACK_BOT_TOKEN") {
+                                config.insert("token".to_string(), c.value);
+                            } else {
+                                bail!("slack channel requires token= or SLACK_BOT_TOKEN (env, .env or ~/.cognos/credentials.json)");
+                            }
+                        }
+                        if !config.contains_key("channel") {
+                            bail!("slack channel requires channel= parameter");
+                        }
+                    }
+                    "nats" => {
+                        for key in config.keys() {
+                            if !["url", "subject", "queue", "token", "user", "password", "timeout"].contains(&key.as_str()) {
+                                bail!("nats channel: unknown kwarg '{}'", key);
+                            }
+                        }
+                        let Some(subject) = config.get("subject").cloned() else {
+                            bail!("nats channel requires subject= parameter");
+                        };
+                        if let Some(t) = config.get("timeout") {
+                            if t.parse::<f64>().map_or(true, |t| !t.is_finite() || t < 0.0) {
+                                bail!("nats channel: timeout= must be a number of seconds, got '{}'", t);
+                            }
+                        }
+                        let url_has_credentials = config.get("url").is_some_and(|u| u.contains('@'));
+                        if !config.contains_key("token") && !config.contains_key("user") && !url_has_credentials {
+                            if let Some(c) = crate::auth::lookup("NATS_TOKEN") {
+                                config.insert("token".to_string(), c.value);
+                            }
+                        }
+                        // Subscribe now, so read() sees everything published from here on
+                        let queue = config.get("queue").cloned().unwrap_or_default();
+                        crate::nats::subscribe(&crate::nats::Options::from_config(&config), &subject, &queue)?;
+                    }
+                    "mqtt" => {
+                        for key in config.keys() {
+                            if !["broker", "topic", "qos", "retain", "username", "password", "client_id", "timeout"].contains(&key.as_str()) {
+                                bail!("mqtt channel: unknown kwarg '{}'", key);
+                            }
+                        }
+                        if !config.contains_key("broker") { bail!("mqtt channel requires broker= parameter"); }
+                        if !config.contains_key("topic") { bail!("mqtt channel requires topic= parameter"); }
+                        crate::mqtt::qos(&config)?;
+                        if let Some(r) = config.get("retain") {
+                            if r != "true" && r != "false" { bail!("mqtt channel: retain= must be true or false, got '{}'", r); }
+                        }
+                        if let Some(t) = config.get("timeout") {
+                            if t.parse::<f64>().map_or(true, |t| !t.is_finite() || t < 0.0) {
+                                bail!("mqtt channel: timeout= must be a number of seconds, got '{}'", t);
+                            }
+                        }
+                        let url_has_credentials = config["broker"].contains('@');
+                        if !config.contains_key("username") && !url_has_credentials {
+                            if let Some(c) = crate::auth::lookup("MQTT_USERNAME") {
+                                config.insert("username".to_string(), c.value);
+                            }
+                        }
+                        if config.contains_key("username") && !config.contains_key("password") {
+                            if let Some(c) = crate::auth::lookup("MQTT_PASSWORD") {
+                                config.insert("password".to_string(), c.value);
+                            }
+                        }
+                        crate::mqtt::connect(&crate::mqtt::Options::from_config(&config))?;
+                    }
+                    other if crate::plugin::channel(other).is_some() => {}
+                    other => {
+                        let mut supported = vec!["slack", "nats", "mqtt"];
+                        supported.extend(crate::plugin::all().iter().flat_map(|p| p.channels.iter().map(String::as_str)));
+                        bail!("unknown channel provider: '{}'. Supported: {}", other, supported.join(", "))
+                    }
+                }
+                log::info!("channel: created {} handle", provider);
+                Ok(Value::Handle(Handle::Channel { provider, config }))
             }
-            (Value::String(s), "truncate") => {
-                let max = match args.get(0) {
-                    Some(Value::Int(n)) => *n as usize,
-                    _ => bail!(".truncate() requires an Int argument"),
+            "builtin_tool" if !self.flows.contains_key(name) => {
+                // builtin_tool("computer_20250124", display_width_px=1280, ...):
+                // a provider's own tool, for think(tools=[...])
+                let [tool_type] = args else { bail!("builtin_tool(type, ...) takes the tool type, e.g. builtin_tool(\"bash_20250124\")") };
+                let tool_type = match self.eval(tool_type)? {
+                    Value::String(t) => t,
+                    other => bail!("builtin_tool(): type must be a String, got {}", type_name(&other)),
                 };
-                if s.len() <= max {
-                    Ok(Value::String(s.clone()))
-                } else {
-                    let truncated: String = s.chars().take(max).collect();
-                    Ok(Value::String(format!("{}...", truncated)))
+                let mut tool = Entries::from([("type".to_string(), Value::String(tool_type.clone()))]);
+                if let Some(name) = native_tool_name(&tool_type) {
+                    tool.insert("name".to_string(), Value::String(name.to_string()));
                 }
-            }
-            (Value::String(s), "split") => {
-                let delim = self.expect_string_arg(method, &args, 0)?;
-                let parts: Vec<Value> = s.split(&delim).map(|p| Value::String(p.to_string())).collect();
-                Ok(Value::List(parts))
-            }
-            (Value::String(s), "index") => {
-                let needle = self.expect_string_arg(method, &args, 0)?;
-                match s.find(&needle) {
-                    Some(pos) => Ok(Value::Int(pos as i64)),
-                    None => bail!("substring '{}' not found in string", needle),
+                for (k, v) in kwargs {
+                    let val = self.eval(v)?;
+                    tool.insert(k.clone(), val);
+                }
+                Ok(Value::Map(tool))
+            }
+            "file_upload" if !self.flows.contains_key(name) => {
+                // file_upload(path, purpose="user_data"): an OpenAI file id, for
+                // think(files=[...]) and hosted tools' file_ids
+                let [path] = args else { bail!("file_upload(path) takes the file to upload") };
+                let path = self.eval(path)?.to_string();
+                let mut purpose = "user_data".to_string();
+                for (k, v) in kwargs {
+                    match k.as_str() {
+                        "purpose" => purpose = self.eval(v)?.to_string(),
+                        _ => bail!("file_upload(): unknown kwarg '{}'", k),
+                    }
+                }
+                let id = self.env.lock().unwrap().upload_file(&path, &purpose)?;
+                Ok(Value::String(id))
+            }
+            "screenshot" if !self.flows.contains_key(name) => {
+                // screenshot(path): the screen as a PNG, for think(images=[...])
+                let [path] = args else { bail!("screenshot(path) takes the PNG file to write") };
+                if let Some((k, _)) = kwargs.first() {
+                    bail!("screenshot(): unknown kwarg '{}'", k);
                 }
+                let path = self.eval(path)?.to_string();
+                let mut env = self.env.lock().unwrap();
+                if !env.allow_shell() {
+                    bail!("screenshot() runs a screen capture program — use: cognos run --allow-shell file.cog");
+                }
+                let image = env.screenshot(&path)?;
+                Ok(Value::String(image))
             }
-            (Value::String(s), "find") => {
-                let needle = self.expect_string_arg(method, &args, 0)?;
-                match s.find(&needle) {
-                    Some(pos) => Ok(Value::Int(pos as i64)),
-                    None => Ok(Value::Int(-1)),
+            "screen_size" if !self.flows.contains_key(name) => {
+                if !args.is_empty() || !kwargs.is_empty() {
+                    bail!("screen_size() takes no arguments");
                 }
+                let env = self.env.lock().unwrap();
+                if !env.allow_shell() {
+                    bail!("screen_size() runs a screen capture program — use: cognos run --allow-shell file.cog");
+                }
+                let (width, height) = env.screen_size()?;
+                Ok(Value::Map(Entries::from([
+                    ("width".to_string(), Value::Int(width.into())),
+                    ("height".to_string(), Value::Int(height.into())),
+                ])))
             }
+            "download" => {
+                // download(url, path, channel=handle) — HTTP GET → save to file
+                // channel= kwarg provides auth from channel handle automatically
+                if args.len() < 2 { bail!("download(url, path) or download(url, path, channel=handle)"); }
+                let url = self.eval(&args[0])?.to_string();
+                let path = self.eval(&args[1])?.to_string();
 
-            // ── List methods ──
-            (Value::List(items), "contains") => {
-                if args.is_empty() { bail!(".contains() requires an argument"); }
-                let needle = &args[0];
-                let found = items.iter().any(|item| value_eq(item, needle));
-                Ok(Value::Bool(found))
-            }
-            (Value::List(items), "join") => {
-                let sep = if args.is_empty() { "".to_string() } else { args[0].to_string() };
-                let joined: Vec<std::string::String> = items.iter().map(|v| v.to_string()).collect();
-                Ok(Value::String(joined.join(&sep)))
-            }
-            (Value::List(items), "reversed") => {
-                let mut rev = items.clone();
-                rev.reverse();
-                Ok(Value::List(rev))
-            }
-            (Value::List(_items), "push") => {
-                // push mutates — need to handle specially
-                bail!("push() not yet supported — lists are immutable. Use: new_list = old_list + [item]")
-            }
+                // Build auth headers from kwargs
+                let mut headers = reqwest::header::HeaderMap::new();
+                for (k, v) in kwargs {
+                    match k.as_str() {
+                        "channel" => {
+                            if let Value::Handle(Handle::Channel { ref provider, ref config }) = self.eval(v)? {
+                                if provider.as_str() == "slack" {
+                                    if let Some(token) = config.get("token") {
+                                        headers.insert(
+                                            reqwest::header::AUTHORIZATION,
+                                            reqwest::header::HeaderValue::from_str(&format!("Bearer {}", token))
+                                                .map_err(|e| anyhow::anyhow!("invalid auth header: {}", e))?,
+                                        );
+                                    }
+                                }
+                            }
+                        }
+                        "headers" => {
+                            if let Value::Map(pairs) = self.eval(v)? {
+                                for (hk, hv) in &pairs {
+                                    headers.insert(
+                                        reqwest::header::HeaderName::from_bytes(hk.as_bytes())
+                                            .map_err(|e| anyhow::anyhow!("invalid header name '{}': {}", hk, e))?,
+                                        reqwest::header::HeaderValue::from_str(&hv.to_string())
+                                            .map_err(|e| anyhow::anyhow!("invalid header value: {}", e))?,
+                                    );
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
 
-            // ── Map methods ──
-            (Value::Map(entries), "keys") => {
-                let keys: Vec<Value> = entries.iter().map(|(k, _)| Value::String(k.clone())).collect();
-                Ok(Value::List(keys))
-            }
-            (Value::Map(entries), "values") => {
-                let vals: Vec<Value> = entries.iter().map(|(_, v)| v.clone()).collect();
-                Ok(Value::List(vals))
-            }
-            (Value::Map(entries), "contains") => {
-                let key = self.expect_string_arg(method, &args, 0)?;
-                Ok(Value::Bool(entries.iter().any(|(k, _)| k == &key)))
-            }
+                let client = crate::http_client::client(Service::Http);
+                let resp = client.get(&url)
+                    .headers(headers)
+                    .send()
+                    .map_err(|e| crate::retry::request_failed(format!("download failed: {}", e), &e))?;
 
-            _ => bail!("'{}' has no method '{}' (type: {})", obj, method, type_name(&obj)),
-        }
-    }
+                if !resp.status().is_success() {
+                    bail!("download failed: HTTP {}", resp.status());
+                }
 
-    fn expect_string_arg(&self, method: &str, args: &[Value], idx: usize) -> Result<std::string::String> {
-        match args.get(idx) {
-            Some(Value::String(s)) => Ok(s.clone()),
-            Some(other) => bail!(".{}() argument {} must be a String, got {}", method, idx + 1, type_name(other)),
-            None => bail!(".{}() requires at least {} argument(s)", method, idx + 1),
-        }
-    }
+                let bytes = resp.bytes()
+                    .map_err(|e| anyhow::anyhow!("download read failed: {}", e))?;
 
-    fn type_to_schema(&self, td: &TypeDef) -> std::string::String {
-        match td {
-            TypeDef::Struct { fields, .. } => {
-                let field_strs: Vec<std::string::String> = fields.iter().map(|f| {
-                    let ty_str = self.type_expr_to_json_type(&f.ty);
-                    if f.optional {
-                        format!("  \"{}\"?: {}", f.name, ty_str)
-                    } else {
-                        format!("  \"{}\": {}", f.name, ty_str)
+                // Create parent dirs if needed
+                if let Some(parent) = std::path::Path::new(&path).parent() {
+                    std::fs::create_dir_all(parent)
+                        .map_err(|e| crate::environment::io_error("cannot create directory".to_string(), e))?;
+                }
+                std::fs::write(&path, &bytes)
+                    .map_err(|e| crate::environment::io_error(format!("cannot write file '{}'", path), e))?;
+
+                log::info!("download: {} → {} ({} bytes)", url, path, bytes.len());
+                Ok(Value::Int(bytes.len() as i64))
+            }
+            "read" => {
+                // read() or read(handle) — default: stdin
+                let mut prompt = None;
+                for (k, v) in kwargs {
+                    match k.as_str() {
+                        "prompt" => prompt = Some(self.eval(v)?.to_string()),
+                        _ => bail!("read(): unknown kwarg '{}'", k),
                     }
-                }).collect();
-                format!("{{\n{}\n}}", field_strs.join(",\n"))
+                }
+                let handle = if args.is_empty() {
+                    Handle::Stdin
+                } else {
+                    match self.eval(&args[0])? {
+                        Value::Handle(h) => h,
+                        other => bail!("read() expects a handle, got {} (type: {})", other, type_name(&other)),
+                    }
+                };
+                match handle {
+                    Handle::Stdin => {
+                        if let Some(ref prompt) = prompt {
+                            if !self.env.lock().unwrap().is_mock() {
+                                crate::diagnostics::prompt(prompt);
+                            }
+                        }
+                        match self.env.lock().unwrap().read_stdin() {
+                            Ok(val) => {
+                                if let Some(ref tracer) = self.tracer {
+                                    tracer.increment_turn();
+                                }
+                                let full = self.is_full_trace();
+                                self.trace(TraceEvent::IoOp {
+                                    operation: "read".into(), handle_type: "stdin".into(),
+                                    path: None, bytes: val.len(),
+                                    content: if full { Some(val.clone()) } else { None },
+                                });
+                                Ok(Value::String(val))
+                            }
+                            Err(e) if e.to_string().contains("EOF") => {
+                                self.trace(TraceEvent::IoOp {
+                                    operation: "read".into(), handle_type: "stdin".into(),
+                                    path: None, bytes: 0,
+                                    content: Some("EOF".into()),
+                                });
+                                Ok(Value::None)
+                            }
+                            Err(e) => Err(e),
+                        }
+                    }
+                    Handle::Stdout => bail!("cannot read from stdout"),
+                    Handle::Mcp(_) => bail!("cannot read from an mcp() server; call its tools with .call() or think(tools=[...])"),
+                    Handle::File(path) | Handle::AppendFile(path) => {
+                        let content = self.env.lock().unwrap().read_file(&path)?;
+                        let full = self.is_full_trace();
+                        self.trace(TraceEvent::IoOp {
+                            operation: "read".into(), handle_type: "file".into(),
+                            path: Some(path), bytes: content.len(),
+                            content: if full { Some(content.chars().take(1000).collect()) } else { None },
+                        });
+                        Ok(Value::String(content))
+                    }
+                    Handle::Channel { ref provider, ref config } => {
+                        match provider.as_str() {
+                            "slack" => self.read_slack_channel(config),
+                            "nats" => self.read_nats_channel(config),
+                            "mqtt" => self.read_mqtt_channel(config),
+                            other => match crate::plugin::channel(other) {
+                                Some(plugin) => {
+                                    let request = serde_json::json!({ "call": "channel_read", "channel": other, "config": config });
+                                    Ok(Value::from_json(self.call_plugin(plugin, "read", request)?))
+                                }
+                                None => bail!("read() not supported for channel provider '{}'", provider),
+                            },
+                        }
+                    }
+                }
             }
-            TypeDef::Enum { variants, .. } => {
-                let quoted: Vec<std::string::String> = variants.iter().map(|v| format!("\"{}\"", v)).collect();
-                format!("one of: {}", quoted.join(", "))
+            "write" => {
+                if args.len() < 2 { bail!("write(handle, content) — e.g. write(stdout, \"hello\") or write(file(\"path\"), content)"); }
+                let handle = match self.eval(&args[0])? {
+                    Value::Handle(h) => h,
+                    other => bail!("write() first argument must be a handle, got {}", type_name(&other)),
+                };
+                let content = self.eval(&args[1])?.to_string();
+                match handle {
+                    Handle::Stdin => bail!("cannot write to stdin"),
+                    Handle::Mcp(_) => bail!("cannot write to an mcp() server; call its tools with .call() or think(tools=[...])"),
+                    Handle::Stdout => {
+                        self.env.lock().unwrap().write_stdout(&content)?;
+                        let full = self.is_full_trace();
+                        self.trace(TraceEvent::IoOp {
+                            operation: "write".into(), handle_type: "stdout".into(),
+                            path: None, bytes: content.len(),
+                            content: if full { Some(content) } else { None },
+                        });
+                        Ok(Value::None)
+                    }
+                    Handle::File(ref path) | Handle::AppendFile(ref path) => {
+                        let path = path.clone();
+                        if matches!(handle, Handle::AppendFile(_)) {
+                            self.env.lock().unwrap().append_file(&path, &content)?;
+                        } else {
+                            self.env.lock().unwrap().write_file(&path, &content)?;
+                        }
+                        let full = self.is_full_trace();
+                        self.trace(TraceEvent::IoOp {
+                            operation: "write".into(), handle_type: "file".into(),
+                            path: Some(path), bytes: content.len(),
+                            content: if full { Some(content) } else { None },
+                        });
+                        Ok(Value::None)
+                    }
+                    Handle::Channel { ref provider, ref config } => {
+                        match provider.as_str() {
+                            "slack" => self.write_slack_channel(config, &content),
+                            "nats" => {
+                                let subject = config.get("subject").ok_or_else(|| anyhow::anyhow!("nats: missing subject"))?;
+                                crate::nats::publish(&crate::nats::Options::from_config(config), subject, &content)?;
+                                log::info!("nats: published {} bytes to {}", content.len(), subject);
+                                Ok(Value::None)
+                            }
+                            "mqtt" => {
+                                let topic = config.get("topic").ok_or_else(|| anyhow::anyhow!("mqtt: missing topic"))?;
+                                let retain = config.get("retain").is_some_and(|r| r == "true");
+                                crate::mqtt::publish(&crate::mqtt::Options::from_config(config), topic, crate::mqtt::qos(config)?, retain, &content)?;
+                                log::info!("mqtt: published {} bytes to {}", content.len(), topic);
+                                Ok(Value::None)
+                            }
+                            other => match crate::plugin::channel(other) {
+                                Some(plugin) => {
+                                    let request = serde_json::json!({ "call": "channel_write", "channel": other, "config": config, "content": content });
+                                    self.call_plugin(plugin, "write", request)?;
+                                    Ok(Value::None)
+                                }
+                                None => bail!("write() not supported for channel provider '{}'", provider),
+                            },
+                        }
+                    }
+                }
             }
-        }
-    }
-
-    fn type_expr_to_json_type(&self, ty: &TypeExpr) -> std::string::String {
-        match ty {
-            TypeExpr::Named(n) => match n.as_str() {
-                "String" | "Text" => "<string>".to_string(),
-                "Int" => "<integer>".to_string(),
-                "Float" => "<number>".to_string(),
-                "Bool" => "<boolean>".to_string(),
-                other => {
-                    if let Some(td) = self.types.get(other) {
-                        self.type_to_schema(td)
-                    } else {
-                        format!("<{}>", other)
+            "int" => {
+                // int(value) — cast to integer
+                if args.is_empty() { bail!("int() requires one argument"); }
+                let val = self.eval(&args[0])?;
+                match val {
+                    Value::Int(n) => Ok(Value::Int(n)),
+                    Value::Float(f) => float_to_int(f),
+                    Value::String(s) => {
+                        let trimmed = s.trim();
+                        trimmed.parse::<i64>()
+                            .map(Value::Int)
+                            .map_err(|_| anyhow::anyhow!("cannot convert '{}' to Int", trimmed))
                     }
+                    Value::Bool(b) => Ok(Value::Int(if b { 1 } else { 0 })),
+                    other => bail!("cannot convert {} to Int", type_name(&other)),
                 }
             }
-            TypeExpr::Generic(name, args) => match name.as_str() {
-                "List" => {
-                    let inner = args.first().map(|a| self.type_expr_to_json_type(a)).unwrap_or("<any>".to_string());
-                    format!("[{}, ...]", inner)
+            "round" if !self.flows.contains_key(name) => {
+                if args.is_empty() || args.len() > 2 || !kwargs.is_empty() {
+                    bail!("round() takes a number and optional decimal places: round(x) or round(x, 2)");
                 }
-                "Map" => {
-                    if args.len() >= 2 {
-                        let key = self.type_expr_to_json_type(&args[0]);
-                        let val = self.type_expr_to_json_type(&args[1]);
-                        format!("{{{}: {}, ...}}", key, val)
-                    } else {
-                        "<object>".to_string()
+                let val = self.eval(&args[0])?;
+                let places = match args.get(1).map(|a| self.eval(a)).transpose()? {
+                    None => None,
+                    Some(Value::Int(n)) if n >= 0 => Some(n as usize),
+                    Some(other) => bail!("round() decimal places must be a non-negative Int, got {}", other),
+                };
+                match (val, places) {
+                    (Value::Int(n), _) => Ok(Value::Int(n)),
+                    (Value::Float(f), None) => float_to_int(f.round()),
+                    // Through the decimal text, so round(x, 2) agrees with f"{x:.2f}"
+                    (Value::Float(f), Some(places)) => Ok(Value::Float(format!("{:.*}", places, f).parse().unwrap_or(f))),
+                    (other, _) => bail!("round() requires a number, got {}", type_name(&other)),
+                }
+            }
+            "divmod" if !self.flows.contains_key(name) => {
+                if args.len() != 2 || !kwargs.is_empty() {
+                    bail!("divmod() takes two numbers: divmod(a, b)");
+                }
+                let a = self.eval(&args[0])?;
+                let b = self.eval(&args[1])?;
+                match (&a, &b) {
+                    (Value::Int(_) | Value::Float(_), Value::Int(0)) => bail!("division by zero"),
+                    (Value::Int(x), Value::Int(y)) => {
+                        let q = floor_div(*x, *y)
+                            .ok_or_else(|| anyhow::anyhow!("integer overflow in divmod({}, {})", x, y))?;
+                        Ok(Value::List(vec![Value::Int(q), Value::Int(floor_mod(*x, *y))]))
+                    }
+                    (Value::Int(_) | Value::Float(_), Value::Int(_) | Value::Float(_)) => {
+                        let (x, y) = (as_f64(&a), as_f64(&b));
+                        if y == 0.0 { bail!("division by zero"); }
+                        Ok(Value::List(vec![Value::Float((x / y).floor()), Value::Float(floor_mod_f64(x, y))]))
                     }
+                    _ => bail!("divmod() requires two numbers, got {} and {}", type_name(&a), type_name(&b)),
                 }
-                _ => format!("<{}>", name),
             }
-            TypeExpr::Struct(_) => "<object>".to_string(),
-        }
-    }
-
-    fn validate_type(&self, val: &Value, td: &crate::ast::TypeDef) -> Result<()> {
-        match td {
-            TypeDef::Enum { name, variants } => {
+            "float" => {
+                if args.is_empty() { bail!("float() requires one argument"); }
+                let val = self.eval(&args[0])?;
                 match val {
+                    Value::Float(f) => Ok(Value::Float(f)),
+                    Value::Int(n) => Ok(Value::Float(n as f64)),
                     Value::String(s) => {
-                        if !variants.contains(s) {
-                            bail!("type {} validation failed: '{}' is not one of [{}]",
-                                name, s, variants.join(", "));
-                        }
-                        Ok(())
+                        let trimmed = s.trim();
+                        trimmed.parse::<f64>()
+                            .map(Value::Float)
+                            .map_err(|_| anyhow::anyhow!("cannot convert '{}' to Float", trimmed))
                     }
-                    other => bail!("expected {} (String enum), got {}", name, type_name(other)),
+                    other => bail!("cannot convert {} to Float", type_name(&other)),
                 }
             }
-            TypeDef::Struct { name, fields } => {
-                let map = match val {
-                    Value::Map(entries) => entries,
-                    other => bail!("expected {} (Map), got {}", name, type_name(other)),
+            "str" => {
+                if args.is_empty() { bail!("str() requires one argument"); }
+                let val = self.eval(&args[0])?;
+                Ok(Value::String(val.to_string()))
+            }
+            "eval" => {
+                // eval(source, vars={}) — parse and execute Cognos source code at runtime.
+                // Any flows defined in the source are registered in the current interpreter.
+                // Variables from the optional `vars` Map are injected into scope before execution.
+                // Returns the last expression value, or None if the code only has statements.
+                if args.is_empty() {
+                    bail!("eval() requires a source string: eval(\"flow main(): ...\")");
+                }
+                let source = self.eval(&args[0])?.to_string();
+                
+                // Optional: inject variables from a Map
+                let inject_vars = if args.len() > 1 {
+                    match self.eval(&args[1])? {
+                        Value::Map(entries) => entries,
+                        other => bail!("eval() second argument must be a Map, got {}", type_name(&other)),
+                    }
+                } else {
+                    Entries::new()
                 };
-
-                let mut errors = Vec::new();
-
-                for field in fields {
-                    match map.iter().find(|(k, _)| k == &field.name) {
-                        None => {
-                            if !field.optional {
-                                errors.push(format!("missing field '{}'", field.name));
-                            }
+                
+                // Parse the source — try as-is first, then wrap in a flow if needed
+                let program = {
+                    let mut lexer = crate::lexer::Lexer::new(&source);
+                    let tokens = lexer.tokenize();
+                    let mut parser = crate::parser::Parser::new(tokens);
+                    match parser.parse_program() {
+                        Ok(prog) => prog,
+                        Err(_) => {
+                            // If parsing fails, wrap in a synthetic main flow
+                            // Indent each line by 4 spaces for the flow body
+                            let indented: String = source.lines()
+                                .map(|line| format!("    {}", line))
+                                .collect::<Vec<_>>()
+                                .join("\n");
+                            let wrapped = format!("flow __eval_main__():\n{}", indented);
+                            let mut lexer2 = crate::lexer::Lexer::new(&wrapped);
+                            let tokens2 = lexer2.tokenize();
+                            let mut parser2 = crate::parser::Parser::new(tokens2);
+                            parser2.parse_program()
+                                .map_err(|e| anyhow::anyhow!("eval() parse error: {}", e))?
                         }
-                        Some((_, val)) => {
-                            if let Err(e) = self.validate_field_value(val, &field.ty) {
-                                errors.push(format!("field '{}': {}", field.name, e));
-                            }
+                    }
+                };
+                
+                // Register any flows defined in the eval'd code
+                for flow in &program.flows {
+                    log::info!("eval: registered flow '{}'", flow.name);
+                    self.flows.insert(flow.name.clone(), flow.clone());
+                }
+                
+                // Register any types
+                for td in &program.types {
+                    self.types.insert(td.name().to_string(), td.clone());
+                }
+                
+                // Inject variables into current scope
+                for (k, v) in inject_vars {
+                    self.vars.insert(k.into(), v);
+                }
+                
+                // Handle imports in eval'd code
+                for import in &program.imports {
+                    let exports = self.resolve_import(import, None)
+                        .map_err(|e| anyhow::anyhow!("eval() import error: {}", e))?;
+                    self.register_exports(exports);
+                }
+                
+                // Bare statements (top-level, or wrapped in __eval_main__) run directly
+                // in the current scope so injected variables are visible.
+                // Save vars snapshot so we can restore on failure (prevents scope corruption).
+                let bare = program.flows.iter().find(|f| f.name == "__eval_main__").map(|f| &f.body)
+                    .or(Some(&program.setup).filter(|setup| !setup.is_empty()));
+                if let Some(body) = bare {
+                    let saved_vars = self.vars.clone();
+                    match self.run_block(body) {
+                        Ok(_) => return Ok(Value::None),
+                        Err(e) => {
+                            // Restore caller's scope on eval failure
+                            self.vars = saved_vars;
+                            return Err(e);
                         }
                     }
                 }
+                
+                // If there's a "main" flow, call it
+                if program.flows.iter().find(|f| f.name == "main").is_some() {
+                    return self.call_flow("main", vec![], vec![]);
+                }
+                
+                // Otherwise just register flows and return None
+                Ok(Value::None)
+            }
+            "invoke" => {
+                // invoke(name, args) — call a flow by string name with a Map of arguments
+                if args.is_empty() {
+                    bail!("invoke() requires a flow name: invoke(\"flow_name\", {{\"arg\": value}})");
+                }
+                let flow_name = self.eval(&args[0])?.to_string();
+                let flow_args = if args.len() > 1 {
+                    self.eval(&args[1])?
+                } else {
+                    Value::Map(Entries::new())
+                };
 
-                if errors.is_empty() {
-                    Ok(())
+                // Convert Map to kwargs
+                let kwarg_vals = match flow_args {
+                    Value::Map(entries) => entries,
+                    other => bail!("invoke() second argument must be a Map, got {}", type_name(&other)),
+                };
+
+                self.call_flow_as_tool(&flow_name, kwarg_vals)
+            }
+            "__exec_shell__" => {
+                if !self.env.lock().unwrap().allow_shell() {
+                    bail!("shell execution is disabled — use: cognos run --allow-shell file.cog");
+                }
+                if args.is_empty() { bail!("__exec_shell__() requires a command string"); }
+                let cmd = self.eval(&args[0])?.to_string();
+                log::info!("__exec_shell__ → {:?}", cmd);
+                let shell_start = std::time::Instant::now();
+                let result = self.env.lock().unwrap().exec_shell(&cmd)?;
+                let shell_output = if self.is_full_trace() { Some(result.stdout.clone()) } else { None };
+                self.trace(TraceEvent::ShellExec {
+                    command: cmd, latency_ms: shell_start.elapsed().as_millis() as u64,
+                    exit_code: result.exit_code, output_chars: result.stdout.len(), output: shell_output,
+                });
+                Ok(Value::String(result.stdout))
+            }
+            "save" => {
+                // save(path, value) — persist a value as JSON via Env
+                if args.len() < 2 { bail!("save(path, value)"); }
+                let path = self.eval(&args[0])?.to_string();
+                let value = self.eval(&args[1])?;
+                let json = self.value_to_json(&value);
+                let content = serde_json::to_string_pretty(&json)?;
+                self.env.lock().unwrap().write_file(&path, &content)?;
+                log::info!("Saved to {}", path);
+                Ok(Value::None)
+            }
+            "write_text" => {
+                // write_text(path, content) — write raw text to a file
+                if args.len() < 2 { bail!("write_text(path, content)"); }
+                let path = self.eval(&args[0])?.to_string();
+                let content = self.eval(&args[1])?.to_string();
+                // Create parent directories if needed
+                if let Some(parent) = std::path::Path::new(&path).parent() {
+                    std::fs::create_dir_all(parent).ok();
+                }
+                self.env.lock().unwrap().write_file(&path, &content)?;
+                log::info!("write_text: {} ({} bytes)", path, content.len());
+                Ok(Value::None)
+            }
+            "read_text" => {
+                // read_text(path) — read raw text from a file
+                if args.is_empty() { bail!("read_text(path)"); }
+                let path = self.eval(&args[0])?.to_string();
+                let content = self.env.lock().unwrap().read_file(&path)?;
+                log::info!("read_text: {} ({} bytes)", path, content.len());
+                Ok(Value::String(content))
+            }
+            "load" => {
+                // load(path) — load a JSON file back to a Value via Env
+                if args.is_empty() { bail!("load(path)"); }
+                let path = self.eval(&args[0])?.to_string();
+                let content = self.env.lock().unwrap().read_file(&path)?;
+                let json: serde_json::Value = serde_json::from_str(&content)
+                    .map_err(|e| anyhow::anyhow!("load JSON error: {}", e))?;
+                log::info!("Loaded from {}", path);
+                Ok(self.json_to_value(json))
+            }
+            "remember" => {
+                if args.is_empty() { bail!("remember(text) requires a string argument"); }
+                let text = self.eval(&args[0])?.to_string();
+                // Check for score= kwarg → remember_scored
+                let mut score: Option<f64> = None;
+                for (k, v) in kwargs {
+                    if k == "score" {
+                        score = Some(match self.eval(v)? {
+                            Value::Float(f) => f,
+                            Value::Int(i) => i as f64,
+                            other => bail!("remember(score=) must be a number, got {:?}", other),
+                        });
+                    }
+                }
+                let mem = self.get_memory()?;
+                if let Some(s) = score {
+                    mem.remember_scored(&text, s)?;
                 } else {
-                    bail!("type {} validation failed:\n  {}\nLLM response: {}", name, errors.join("\n  "), val)
+                    mem.remember(&text)?;
                 }
+                Ok(Value::None)
             }
-        }
-    }
-
-    fn validate_field_value(&self, val: &Value, ty: &crate::ast::TypeExpr) -> Result<()> {
-        match ty {
-            crate::ast::TypeExpr::Named(name) => {
-                let ok = match name.as_str() {
-                    "Int" => matches!(val, Value::Int(_)),
-                    "Float" => matches!(val, Value::Float(_) | Value::Int(_)),
-                    "String" | "Text" => matches!(val, Value::String(_)),
-                    "Bool" => matches!(val, Value::Bool(_)),
-                    "List" => matches!(val, Value::List(_)),
-                    "Map" => matches!(val, Value::Map(_)),
-                    other => {
-                        // Check for user-defined type (struct or enum)
-                        if let Some(td) = self.types.get(other) {
-                            return self.validate_type(val, td);
+            "recall" => {
+                if args.is_empty() { bail!("recall(query) requires a query string"); }
+                let query = self.eval(&args[0])?.to_string();
+                let limit = if args.len() > 1 {
+                    match self.eval(&args[1])? {
+                        Value::Int(n) => n as usize,
+                        _ => 5,
+                    }
+                } else {
+                    // Check kwargs for limit=
+                    let mut lim = 5usize;
+                    for (k, v) in kwargs {
+                        if k == "limit" {
+                            if let Value::Int(n) = self.eval(v)? {
+                                lim = n as usize;
+                            }
                         }
-                        true
                     }
+                    lim
                 };
-                if !ok {
-                    bail!("expected {}, got {} ({})", name, type_name(val), val);
-                }
-                Ok(())
+                let mem = self.get_memory()?;
+                let facts = mem.recall(&query, limit)?;
+                Ok(Value::List(facts.into_iter().map(Value::String).collect()))
             }
-            crate::ast::TypeExpr::Generic(name, args) => {
-                match name.as_str() {
-                    "List" => {
-                        let items = match val {
-                            Value::List(items) => items,
-                            other => bail!("expected List, got {}", type_name(other)),
-                        };
-                        // Validate inner type if specified
-                        if let Some(inner_ty) = args.first() {
-                            for (i, item) in items.iter().enumerate() {
-                                if let Err(e) = self.validate_field_value(item, inner_ty) {
-                                    bail!("element [{}]: {}", i, e);
-                                }
-                            }
-                        }
-                        Ok(())
+            "recall_scored" => {
+                if args.is_empty() { bail!("recall_scored(query) requires a query string"); }
+                let query = self.eval(&args[0])?.to_string();
+                let limit = if args.len() > 1 {
+                    match self.eval(&args[1])? {
+                        Value::Int(n) => n as usize,
+                        _ => 5,
                     }
-                    "Map" => {
-                        let entries = match val {
-                            Value::Map(entries) => entries,
-                            other => bail!("expected Map, got {}", type_name(other)),
-                        };
-                        if args.len() >= 2 {
-                            let val_ty = &args[1];
-                            for (k, v) in entries {
-                                if let Err(e) = self.validate_field_value(v, val_ty) {
-                                    bail!("key '{}': {}", k, e);
-                                }
+                } else {
+                    let mut lim = 5usize;
+                    for (k, v) in kwargs {
+                        if k == "limit" {
+                            if let Value::Int(n) = self.eval(v)? {
+                                lim = n as usize;
                             }
                         }
-                        Ok(())
                     }
-                    _ => Ok(()),
-                }
-            }
-            crate::ast::TypeExpr::Struct(_) => Ok(()),
-        }
-    }
-
-    fn parse_json_response(&self, val: &Value) -> Result<Value> {
-        let s = val.to_string();
-        // Strip markdown code fences if present
-        let json_str = s.trim();
-        let json_str = if json_str.starts_with("```") {
-            let inner = json_str.trim_start_matches("```json").trim_start_matches("```");
-            inner.trim_end_matches("```").trim()
-        } else {
-            json_str
-        };
-
-        // Try parsing as-is first
-        if let Ok(parsed) = serde_json::from_str::<serde_json::Value>(json_str) {
-            return Ok(self.json_to_value(parsed));
-        }
-
-        // LLM may have added text after the JSON — extract the first JSON object or array
-        let extracted = Self::extract_json(json_str);
-        if let Some(ref extracted_str) = extracted {
-            if let Ok(parsed) = serde_json::from_str::<serde_json::Value>(extracted_str) {
-                return Ok(self.json_to_value(parsed));
-            }
-        }
-
-        Err(anyhow::anyhow!("LLM returned invalid JSON. Could not extract valid JSON from response.\nResponse was: {}", json_str))
-    }
-
-    fn extract_json(s: &str) -> Option<String> {
-        // Find the first { or [ and match its closing bracket
-        let start_char = s.chars().position(|c| c == '{' || c == '[')?;
-        let open = s.as_bytes()[start_char] as char;
-        let close = if open == '{' { '}' } else { ']' };
-        let mut depth = 0;
-        let mut in_string = false;
-        let mut escape = false;
-        for (i, ch) in s[start_char..].char_indices() {
-            if escape {
-                escape = false;
-                continue;
+                    lim
+                };
+                let mem = self.get_memory()?;
+                let results = mem.recall_scored(&query, limit)?;
+                let maps: Vec<Value> = results.into_iter().map(|(text, similarity, quality)| {
+                    Value::Map(Entries::from([
+                        ("text".to_string(), Value::String(text)),
+                        ("similarity".to_string(), Value::Float(similarity)),
+                        ("score".to_string(), Value::Float(quality)),
+                    ]))
+                }).collect();
+                Ok(Value::List(maps))
             }
-            if ch == '\\' && in_string {
-                escape = true;
-                continue;
+            "forget" => {
+                if args.is_empty() { bail!("forget(query) requires a query string"); }
+                let query = self.eval(&args[0])?.to_string();
+                let mem = self.get_memory()?;
+                let removed = mem.forget(&query)?;
+                Ok(Value::Int(removed as i64))
             }
-            if ch == '"' {
-                in_string = !in_string;
-                continue;
+            "await" => {
+                if args.is_empty() { bail!("await() requires a future handle"); }
+                let val = self.eval(&args[0])?;
+                match val {
+                    Value::Future(id) => self.await_future(id),
+                    other => bail!("await() expects a Future, got {} (type: {})", other, type_name(&other)),
+                }
             }
-            if !in_string {
-                if ch == open { depth += 1; }
-                if ch == close {
-                    depth -= 1;
-                    if depth == 0 {
-                        return Some(s[start_char..start_char + i + ch.len_utf8()].to_string());
+            "cancel" => {
+                if args.is_empty() { bail!("cancel() requires a future handle"); }
+                let val = self.eval(&args[0])?;
+                match val {
+                    Value::Future(id) => {
+                        if let Some((_handle, cancel_token)) = self.async_handles.remove(&id) {
+                            cancel_token.store(true, Ordering::Relaxed);
+                        }
+                        Ok(Value::None)
                     }
+                    other => bail!("cancel() expects a Future, got {} (type: {})", other, type_name(&other)),
                 }
             }
-        }
-        None
-    }
-
-    fn value_to_json(&self, value: &Value) -> serde_json::Value {
-        match value {
-            Value::String(s) => serde_json::Value::String(s.clone()),
-            Value::Int(n) => serde_json::json!(*n),
-            Value::Float(f) => serde_json::json!(*f),
-            Value::Bool(b) => serde_json::Value::Bool(*b),
-            Value::None => serde_json::Value::Null,
-            Value::List(items) => serde_json::Value::Array(items.iter().map(|v| self.value_to_json(v)).collect()),
-            Value::Map(pairs) => {
-                let mut map = serde_json::Map::new();
-                for (k, v) in pairs { map.insert(k.clone(), self.value_to_json(v)); }
-                serde_json::Value::Object(map)
-            }
-            Value::Handle(_) => serde_json::Value::String("<handle>".into()),
-            Value::Module(name) => serde_json::Value::String(format!("<module:{}>", name)),
-            Value::Future(id) => serde_json::Value::String(format!("<future:{}>", id)),
-        }
-    }
-
-    fn json_to_value(&self, v: serde_json::Value) -> Value {
-        match v {
-            serde_json::Value::Null => Value::None,
-            serde_json::Value::Bool(b) => Value::Bool(b),
-            serde_json::Value::Number(n) => {
-                if let Some(i) = n.as_i64() {
-                    Value::Int(i)
-                } else {
-                    Value::Float(n.as_f64().unwrap_or(0.0))
+            "remove" => {
+                if args.len() < 2 { bail!("remove(map, key) requires two arguments"); }
+                let map_val = self.eval(&args[0])?;
+                let key_val = self.eval(&args[1])?;
+                match (map_val, key_val) {
+                    (Value::Map(mut entries), Value::String(key)) => {
+                        entries.shift_remove(&key);
+                        Ok(Value::Map(entries))
+                    }
+                    (Value::Map(_), other) => bail!("remove() key must be a String, got {}", type_name(&other)),
+                    (other, _) => bail!("remove() first argument must be a Map, got {}", type_name(&other)),
                 }
             }
-            serde_json::Value::String(s) => Value::String(s),
-            serde_json::Value::Array(arr) => {
-                Value::List(arr.into_iter().map(|v| self.json_to_value(v)).collect())
-            }
-            serde_json::Value::Object(map) => {
-                let entries: Vec<(std::string::String, Value)> = map.into_iter()
-                    .map(|(k, v)| (k, self.json_to_value(v)))
-                    .collect();
-                Value::Map(entries)
-            }
-        }
-    }
-
-    fn flow_to_tool_json(&self, flow: &FlowDef) -> serde_json::Value {
-        let mut properties = serde_json::Map::new();
-        let mut required = Vec::new();
-        for param in &flow.params {
-            let ty = match &param.ty {
-                TypeExpr::Named(n) => match n.as_str() {
-                    "String" => "string",
-                    "Int" => "integer",
-                    "Float" => "number",
-                    "Bool" => "boolean",
-                    _ => "string",
-                },
-                _ => "string",
-            };
-            properties.insert(param.name.clone(), serde_json::json!({
-                "type": ty,
-                "description": format!("Parameter '{}'", param.name)
-            }));
-            required.push(serde_json::Value::String(param.name.clone()));
-        }
-        let desc = flow.description.clone()
-            .unwrap_or_else(|| format!("Flow '{}'", flow.name));
-        serde_json::json!({
-            "type": "function",
-            "function": {
-                "name": flow.name,
-                "description": desc,
-                "parameters": {
-                    "type": "object",
-                    "properties": properties,
-                    "required": required
+            "log" => {
+                for arg in args {
+                    let val = self.eval(arg)?;
+                    if !crate::diagnostics::quiet() {
+                        eprintln!("[log] {}", val);
+                    }
                 }
+                Ok(Value::None)
             }
-        })
-    }
-
-    fn call_llm(&mut self, model: &str, system: &str, prompt: &str, tools: Option<Vec<serde_json::Value>>, images: &[std::string::String]) -> Result<Value> {
-        // Check if mock env handles LLM calls
-        if self.env.lock().unwrap().is_mock() {
-            // Mock environment — use env.call_llm
-            let request = crate::environment::LlmRequest {
-                model: model.to_string(), system: system.to_string(),
-                prompt: prompt.to_string(), tools: tools.clone(),
-                format: None, history: vec![],
-            };
-            let resp = self.env.lock().unwrap().call_llm(request)?;
-            let has_tc = resp.tool_calls.is_some();
-            self.trace_llm(model, "mock", 0, prompt, system, &resp.content, has_tc);
-            if let Some(tc) = resp.tool_calls {
-                let tool_calls: Vec<Value> = tc.iter().map(|c| {
-                    let name = c["name"].as_str().unwrap_or("").to_string();
-                    let arguments = self.json_to_value(c["arguments"].clone());
-                    Value::Map(vec![
-                        ("name".to_string(), Value::String(name)),
-                        ("arguments".to_string(), arguments),
-                    ])
+            "history" => {
+                let entries: Vec<Value> = self.conversation_history.iter().map(|(role, content)| {
+                    Value::Map(Entries::from([
+                        ("role".to_string(), Value::String(role.clone())),
+                        ("content".to_string(), Value::String(content.clone())),
+                    ]))
                 }).collect();
-                return Ok(Value::Map(vec![
-                    ("content".to_string(), Value::String(resp.content)),
-                    ("tool_calls".to_string(), Value::List(tool_calls)),
-                    ("has_tool_calls".to_string(), Value::Bool(true)),
-                ]));
+                Ok(Value::List(entries))
             }
-            if tools.is_some() {
-                return Ok(Value::Map(vec![
-                    ("content".to_string(), Value::String(resp.content)),
-                    ("has_tool_calls".to_string(), Value::Bool(false)),
-                ]));
+            "clear_history" => {
+                self.conversation_history.clear();
+                Ok(Value::None)
             }
-            return Ok(Value::String(resp.content));
-        }
-        // Real environment — route to correct provider
-        if model.starts_with("claude") {
-            // If images are provided, use Anthropic API (CLI doesn't support images)
-            if !images.is_empty() {
-                return self.call_anthropic_api_with_images(model, system, prompt, tools, images);
+            // A user flow of the same name takes precedence
+            "query" if !self.flows.contains_key(name) => {
+                if args.len() != 2 || !kwargs.is_empty() {
+                    bail!("query() takes a value and a path: query(response, \"$.items[*].name\")");
+                }
+                let val = match self.eval(&args[0])? {
+                    Value::Shared(shared) => Value::Map(shared.entries()),
+                    other => other,
+                };
+                let Value::String(path) = self.eval(&args[1])? else {
+                    bail!("query() path must be a String like \"$.items[*].name\"");
+                };
+                Ok(Value::List(crate::query::query(&val, &path)?))
             }
-            // Use Anthropic API if key is available (native tool support), fall back to CLI
-            if std::env::var("ANTHROPIC_API_KEY").is_ok() || std::path::Path::new(".env").exists() {
-                return self.call_anthropic_api(model, system, prompt, tools);
+            // Files embed resource("...") when parsed; this is the REPL and
+            // eval() fallback, relative to the working directory
+            "resource" if !self.flows.contains_key(name) => {
+                if args.len() != 1 || !kwargs.is_empty() {
+                    bail!("resource() takes one path, e.g. resource(\"prompts/system.md\")");
+                }
+                let Value::String(path) = self.eval(&args[0])? else {
+                    bail!("resource() path must be a String");
+                };
+                let content = self.env.lock().unwrap().read_file(&path)
+                    .map_err(|e| anyhow::anyhow!("resource(\"{}\"): {}", path, e))?;
+                Ok(Value::String(content))
             }
-            return self.call_claude_cli(model, system, prompt, tools);
-        }
-        if model.starts_with("deepseek") {
-            return self.call_openai_compat(model, system, prompt, tools,
-                "https://api.deepseek.com/v1/chat/completions", "DEEPSEEK_API_KEY");
-        }
-        if model.starts_with("MiniMax") || model.starts_with("minimax") {
-            return self.call_openai_compat(model, system, prompt, tools,
-                "https://api.minimax.io/v1/chat/completions", "MINIMAX_API_KEY");
-        }
-        if model.starts_with("gpt-") || model.starts_with("o1-") || model.starts_with("o3-") {
-            return self.call_openai(model, system, prompt, tools);
-        }
-        self.call_ollama(model, system, prompt, tools, images)
-    }
-
-    fn call_claude_cli(&self, model: &str, system: &str, prompt: &str, tools: Option<Vec<serde_json::Value>>) -> Result<Value> {
-        log::info!("Calling Claude CLI: model={}, tools={}", model, tools.as_ref().map(|t| t.len()).unwrap_or(0));
-        let call_start = std::time::Instant::now();
-
-        // Build system prompt with tools embedded
-        let mut full_system = system.to_string();
-        if let Some(ref tool_defs) = tools {
-            full_system.push_str("\n\n## TOOL USE INSTRUCTIONS\n\nYou MUST use tools when they would help answer the user's question. You have FULL PERMISSION to use all tools listed below. NEVER say you need permission or cannot use a tool.\n\nTo call a tool, your ENTIRE response must be ONLY this JSON (no other text before or after):\n```\n{\"tool_calls\": [{\"name\": \"TOOL_NAME\", \"arguments\": {\"param\": \"value\"}}]}\n```\n\nYou can call multiple tools at once:\n```\n{\"tool_calls\": [{\"name\": \"tool1\", \"arguments\": {...}}, {\"name\": \"tool2\", \"arguments\": {...}}]}\n```\n\nAvailable tools:\n");
-            for t in tool_defs {
-                let name = t["function"]["name"].as_str().unwrap_or("");
-                let desc = t["function"]["description"].as_str().unwrap_or("");
-                let params = serde_json::to_string_pretty(&t["function"]["parameters"]).unwrap_or_default();
-                full_system.push_str(&format!("\n### {}\n{}\nParameters: {}\n", name, desc, params));
+            "prompt_user" if !self.flows.contains_key(name) => {
+                if args.len() != 1 {
+                    bail!("prompt_user() takes a question: prompt_user(\"Deploy to?\", choices=[\"staging\", \"prod\"])");
+                }
+                let question = self.eval(&args[0])?.to_string();
+                let mut choices: Option<Vec<std::string::String>> = None;
+                let mut default = None;
+                for (k, v) in kwargs {
+                    match (k.as_str(), self.eval(v)?) {
+                        ("choices", Value::List(items)) if !items.is_empty() => choices = Some(items.iter().map(|c| c.to_string()).collect()),
+                        ("choices", other) => bail!("prompt_user(): choices must be a non-empty List, got {}", type_name(&other)),
+                        ("default", value) => default = Some(value.to_string()),
+                        _ => bail!("prompt_user(): unknown kwarg '{}'", k),
+                    }
+                }
+                let start = match (&choices, &default) {
+                    (Some(choices), Some(d)) => Some(choices.iter().position(|c| c == d)
+                        .ok_or_else(|| anyhow::anyhow!("prompt_user(): default '{}' is not one of the choices", d))?),
+                    _ => None,
+                };
+                let interactive = !self.env.lock().unwrap().is_mock() && crate::terminal::interactive();
+                let answer = match (interactive, &choices) {
+                    (true, Some(choices)) => choices[crate::terminal::select(&question, choices, start.unwrap_or(0))?].clone(),
+                    (true, None) => crate::terminal::ask(&question, default.as_deref())?
+                        .ok_or_else(|| anyhow::anyhow!("prompt_user(): no answer (end of input)"))?,
+                    (false, _) => self.prompt_plain(&question, choices.as_deref(), default.as_deref())?,
+                };
+                if let Some(ref tracer) = self.tracer {
+                    tracer.increment_turn();
+                }
+                let full = self.is_full_trace();
+                self.trace(TraceEvent::IoOp {
+                    operation: "prompt".into(), handle_type: "stdin".into(),
+                    path: None, bytes: answer.len(),
+                    content: if full { Some(answer.clone()) } else { None },
+                });
+                Ok(Value::String(answer))
             }
-            full_system.push_str("\nRULES:\n1. If a tool can help, USE IT. Your entire response must be the JSON tool call.\n2. If no tool is needed, respond with plain text (no JSON).\n3. NEVER ask for permission. NEVER say you cannot use a tool. You have full access.\n4. For web/internet questions, use web_search. For system info, use shell. For files, use read_file.\n");
-        }
-
-        let output = std::process::Command::new("claude")
-            .args([
-                "-p",
-                "--output-format", "json",
-                "--no-session-persistence",
-                "--model", model,
-                "--system-prompt", &full_system,
-                "--tools", "",
-            ])
-            .stdin(std::process::Stdio::piped())
-            .stdout(std::process::Stdio::piped())
-            .stderr(std::process::Stdio::null())
-            .spawn()
-            .and_then(|mut child| {
-                use std::io::Write;
-                if let Some(ref mut stdin) = child.stdin {
-                    stdin.write_all(prompt.as_bytes())?;
+            "get_path" if !self.flows.contains_key(name) => {
+                if !(2..=3).contains(&args.len()) || !kwargs.is_empty() {
+                    bail!("get_path() takes a value, a path and an optional default: get_path(result, \"user.name\", \"\")");
                 }
-                child.wait_with_output()
-            })
-            .map_err(|e| anyhow::anyhow!("Claude CLI error: {}. Is 'claude' installed?", e))?;
-
-        if !output.status.success() {
-            let err = std::string::String::from_utf8_lossy(&output.stdout);
-            bail!("Claude CLI failed (exit {}): {}", output.status, err);
-        }
-
-        let stdout = std::string::String::from_utf8_lossy(&output.stdout);
-
-        // Parse JSON response from claude CLI
-        let parsed: serde_json::Value = serde_json::from_str(&stdout)
-            .map_err(|e| anyhow::anyhow!("Failed to parse Claude CLI output: {}\nRaw: {}", e, &stdout[..stdout.len().min(500)]))?;
-
-        let raw_text = parsed["result"].as_str().unwrap_or("").to_string();
-
-        if parsed["is_error"] == serde_json::Value::Bool(true) {
-            bail!("Claude CLI error: {}", raw_text);
-        }
-
-        let latency = call_start.elapsed().as_millis() as u64;
-        log::info!("Claude CLI response: {} chars in {}ms", raw_text.len(), latency);
-
-        // Parse tool calls from response text
-        if tools.is_some() {
-            if let Some(tool_calls) = self.parse_tool_calls_from_text(&raw_text) {
-                let content = raw_text.split("```json").next().unwrap_or("").trim().to_string();
-                self.trace_llm(model, "claude-cli", latency, prompt, &full_system, &raw_text, true);
-                return Ok(Value::Map(vec![
-                    ("content".to_string(), Value::String(content)),
-                    ("tool_calls".to_string(), Value::List(tool_calls)),
-                    ("has_tool_calls".to_string(), Value::Bool(true)),
-                ]));
-            }
-            self.trace_llm(model, "claude-cli", latency, prompt, &full_system, &raw_text, false);
-            return Ok(Value::Map(vec![
-                ("content".to_string(), Value::String(raw_text)),
-                ("has_tool_calls".to_string(), Value::Bool(false)),
-            ]));
-        }
-
-        self.trace_llm(model, "claude-cli", latency, prompt, &full_system, &raw_text, false);
-        Ok(Value::String(raw_text))
-    }
-
-    fn parse_tool_calls_from_text(&self, text: &str) -> Option<Vec<Value>> {
-        // Look for tool_calls JSON in various formats
-        let json_str = if let Some(start) = text.find("```json") {
-            let after = &text[start + 7..];
-            after.find("```").map(|end| after[..end].trim())
-        } else if let Some(start) = text.find("```\n{") {
-            let after = &text[start + 4..];
-            after.find("```").map(|end| after[..end].trim())
-        } else if let Some(start) = text.find("{\"tool_calls\"") {
-            // Raw JSON
-            let after = &text[start..];
-            let mut depth = 0;
-            let mut end = 0;
-            for (i, c) in after.chars().enumerate() {
-                match c {
-                    '{' => depth += 1,
-                    '}' => { depth -= 1; if depth == 0 { end = i + 1; break; } }
-                    _ => {}
-                }
-            }
-            if end > 0 { Some(&after[..end]) } else { None }
-        } else {
-            None
-        }?;
-
-        let parsed: serde_json::Value = serde_json::from_str(json_str).ok()?;
-        let calls = parsed["tool_calls"].as_array()?;
-
-        if calls.is_empty() {
-            return None;
-        }
-
-        let result: Vec<Value> = calls.iter().map(|c| {
-            let name = c["name"].as_str().unwrap_or("").to_string();
-            let arguments = self.json_to_value(c["arguments"].clone());
-            Value::Map(vec![
-                ("name".to_string(), Value::String(name)),
-                ("arguments".to_string(), arguments),
-            ])
-        }).collect();
-
-        Some(result)
-    }
-
-    fn call_anthropic_api_with_token(&self, model: &str, system: &str, prompt: &str, tools: Option<Vec<serde_json::Value>>, provided_token: &str) -> Result<Value> {
-        // Use provided token directly
-        let token = provided_token.to_string();
-        let call_start = std::time::Instant::now();
-        log::info!("Calling Anthropic API: model={}, tools={}", model, tools.as_ref().map(|t| t.len()).unwrap_or(0));
-
-        // Build request body
-        let mut body = serde_json::json!({
-            "model": model,
-            "max_tokens": 4096,
-            "messages": [{"role": "user", "content": prompt}]
-        });
-        if !system.is_empty() {
-            body["system"] = serde_json::json!(system);
-        }
-        if let Some(ref tool_defs) = tools {
-            let api_tools: Vec<serde_json::Value> = tool_defs.iter().map(|t| {
-                serde_json::json!({
-                    "name": t["function"]["name"].as_str().unwrap_or("unknown"),
-                    "description": t["function"]["description"].as_str().unwrap_or(""),
-                    "input_schema": t["function"]["parameters"]
+                let val = self.eval(&args[0])?;
+                let Value::String(path) = self.eval(&args[1])? else {
+                    bail!("get_path() path must be a String like \"user.name\" or \"items.0.id\"");
+                };
+                let default = match args.get(2) {
+                    Some(expr) => self.eval(expr)?,
+                    None => Value::None,
+                };
+                Ok(lookup_path(&val, &path).cloned().unwrap_or(default))
+            }
+            "type_of" | "is_string" | "is_int" | "is_list" | "is_map" | "is_none" if !self.flows.contains_key(name) => {
+                if args.len() != 1 || !kwargs.is_empty() {
+                    bail!("{}() takes exactly one value", name);
+                }
+                let val = self.eval(&args[0])?;
+                Ok(match name {
+                    "type_of" => Value::String(type_name(&val).to_string()),
+                    "is_string" => Value::Bool(matches!(val, Value::String(_))),
+                    "is_int" => Value::Bool(matches!(val, Value::Int(_))),
+                    "is_list" => Value::Bool(matches!(val, Value::List(_))),
+                    "is_map" => Value::Bool(matches!(val, Value::Map(_))),
+                    _ => Value::Bool(matches!(val, Value::None)),
                 })
-            }).collect();
-            body["tools"] = serde_json::json!(api_tools);
-        }
-
-        let client = reqwest::blocking::Client::new();
-        let mut req = client.post("https://api.anthropic.com/v1/messages");
-        if !token.starts_with("sk-ant-oat") {
-            req = req.header("x-api-key", &token);
-        } else {
-            req = req.header("Authorization", format!("Bearer {}", token));
-            req = req.header("anthropic-beta", "oauth-2025-04-20");
-        }
-        let resp = req
-            .header("anthropic-version", "2023-06-01")
-            .header("content-type", "application/json")
-            .json(&body)
-            .send()
-            .map_err(|e| anyhow::anyhow!("Anthropic API request failed: {}", e))?;
-
-        let status = resp.status();
-        let resp_text = resp.text().map_err(|e| anyhow::anyhow!("Failed to read API response: {}", e))?;
-
-        if !status.is_success() {
-            bail!("Anthropic API error ({}): {}", status, &resp_text[..resp_text.len().min(500)]);
-        }
-
-        let parsed: serde_json::Value = serde_json::from_str(&resp_text)
-            .map_err(|e| anyhow::anyhow!("Failed to parse API response: {}", e))?;
-
-        let latency = call_start.elapsed().as_millis() as u64;
-        let stop_reason = parsed["stop_reason"].as_str().unwrap_or("");
-        let content_blocks = parsed["content"].as_array()
-            .ok_or_else(|| anyhow::anyhow!("No content in API response"))?;
-
-        let mut text_parts: Vec<String> = Vec::new();
-        let mut tool_calls: Vec<Value> = Vec::new();
-        for block in content_blocks {
-            match block["type"].as_str() {
-                Some("text") => { if let Some(t) = block["text"].as_str() { text_parts.push(t.to_string()); } }
-                Some("tool_use") => {
-                    let name = block["name"].as_str().unwrap_or("").to_string();
-                    let arguments = self.json_to_value(block["input"].clone());
-                    tool_calls.push(Value::Map(vec![
-                        ("name".to_string(), Value::String(name)),
-                        ("arguments".to_string(), arguments),
-                    ]));
+            }
+            "help" if !self.flows.contains_key(name) => {
+                if args.len() > 1 || !kwargs.is_empty() {
+                    bail!("help() takes at most one name: help(\"think\")");
                 }
-                _ => {}
+                let mut lines = Vec::new();
+                let Some(arg) = args.first() else {
+                    lines.push("Built-in functions:".to_string());
+                    for b in crate::help::BUILTINS.iter().chain(crate::plugin::all().iter().flat_map(|p| &p.builtins)) {
+                        lines.push(format!("  {:<14} {}", b.name, b.summary));
+                    }
+                    let mut flows: Vec<_> = self.flows.values().filter(|f| !f.name.starts_with("__")).collect();
+                    flows.sort_by(|a, b| a.name.cmp(&b.name));
+                    if !flows.is_empty() {
+                        lines.push("Flows:".to_string());
+                        for f in flows {
+                            lines.push(format!("  {}", crate::pretty::flow_signature(f)));
+                        }
+                    }
+                    if !self.types.is_empty() {
+                        let mut types: Vec<_> = self.types.keys().map(|t| t.as_str()).collect();
+                        types.sort();
+                        lines.push(format!("Types: {}", types.join(", ")));
+                    }
+                    self.env.lock().unwrap().print(&lines.join("\n"))?;
+                    return Ok(Value::None);
+                };
+                let topic = match self.eval(arg)? {
+                    Value::String(s) => s,
+                    other => bail!("help() takes a name as a String, got {}", type_name(&other)),
+                };
+                if let Some(flow) = self.flows.get(topic.as_str()) {
+                    lines.push(crate::pretty::flow_signature(flow));
+                    if let Some(doc) = &flow.description {
+                        lines.push(format!("    {}", doc));
+                    }
+                } else if let Some(b) = crate::help::lookup(&topic) {
+                    lines.push(b.signature.to_string());
+                    lines.push(format!("    {}", b.summary));
+                } else if let Some(td) = self.types.get(&topic) {
+                    let program = crate::pretty::pretty_program(&Program {
+                        imports: Vec::new(), types: vec![td.clone()], setup: Vec::new(), flows: Vec::new(),
+                    });
+                    lines.push(program.trim_end().to_string());
+                } else {
+                    bail!("help(): no built-in, flow or type named '{}'", topic);
+                }
+                self.env.lock().unwrap().print(&lines.join("\n"))?;
+                Ok(Value::None)
             }
-        }
-        let content = text_parts.join("\n");
-        log::info!("Anthropic API: {}ms, stop={}, tools={}", latency, stop_reason, tool_calls.len());
-
-        if stop_reason == "tool_use" || !tool_calls.is_empty() {
-            self.trace_llm(model, "anthropic-api", latency, prompt, system, &content, true);
-            return Ok(Value::Map(vec![
-                ("content".to_string(), Value::String(content)),
-                ("tool_calls".to_string(), Value::List(tool_calls)),
-                ("has_tool_calls".to_string(), Value::Bool(true)),
-            ]));
-        }
-        if tools.is_some() {
-            self.trace_llm(model, "anthropic-api", latency, prompt, system, &content, false);
-            return Ok(Value::Map(vec![
-                ("content".to_string(), Value::String(content)),
-                ("has_tool_calls".to_string(), Value::Bool(false)),
-            ]));
-        }
-        self.trace_llm(model, "anthropic-api", latency, prompt, system, &content, false);
-        Ok(Value::String(content))
-    }
-
-    fn call_anthropic_api_with_images(&self, model: &str, system: &str, prompt: &str, tools: Option<Vec<serde_json::Value>>, images: &[String]) -> Result<Value> {
-        // Read ANTHROPIC_API_KEY from environment
-        let token = std::env::var("ANTHROPIC_API_KEY")
-            .map_err(|_| anyhow::anyhow!("ANTHROPIC_API_KEY not set — needed for vision (Claude CLI doesn't support images)"))?;
-
-        let call_start = std::time::Instant::now();
-        log::info!("Calling Anthropic API (vision): model={}, images={}, tools={}", model, images.len(), tools.as_ref().map(|t| t.len()).unwrap_or(0));
-
-        // Build multimodal content: images first, then text
-        let mut content_parts: Vec<serde_json::Value> = Vec::new();
-        for path in images {
-            let data = std::fs::read(path)
-                .map_err(|e| anyhow::anyhow!("Failed to read image {}: {}", path, e))?;
-            let b64 = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, &data);
-            let media_type = if path.ends_with(".png") { "image/png" }
-                else if path.ends_with(".gif") { "image/gif" }
-                else if path.ends_with(".webp") { "image/webp" }
-                else { "image/jpeg" };
-            content_parts.push(serde_json::json!({
-                "type": "image",
-                "source": { "type": "base64", "media_type": media_type, "data": b64 }
-            }));
-        }
-        content_parts.push(serde_json::json!({ "type": "text", "text": prompt }));
-
-        let mut body = serde_json::json!({
-            "model": model,
-            "max_tokens": 4096,
-            "messages": [{"role": "user", "content": content_parts}]
-        });
-        if !system.is_empty() {
-            body["system"] = serde_json::json!(system);
-        }
-        if let Some(ref tool_defs) = tools {
-            let api_tools: Vec<serde_json::Value> = tool_defs.iter().map(|t| {
-                serde_json::json!({
-                    "name": t["function"]["name"].as_str().unwrap_or("unknown"),
-                    "description": t["function"]["description"].as_str().unwrap_or(""),
-                    "input_schema": t["function"]["parameters"]
-                })
-            }).collect();
-            body["tools"] = serde_json::json!(api_tools);
-        }
-
-        let client = reqwest::blocking::Client::new();
-        let mut req = client.post("https://api.anthropic.com/v1/messages");
-        if !token.starts_with("sk-ant-oat") {
-            req = req.header("x-api-key", &token);
-        } else {
-            req = req.header("Authorization", format!("Bearer {}", token));
-            req = req.header("anthropic-beta", "oauth-2025-04-20");
-        }
-        let resp = req
-            .header("anthropic-version", "2023-06-01")
-            .header("content-type", "application/json")
-            .json(&body)
-            .send()
-            .map_err(|e| anyhow::anyhow!("Anthropic API request failed: {}", e))?;
-
-        let status = resp.status();
-        let resp_text = resp.text().map_err(|e| anyhow::anyhow!("Failed to read API response: {}", e))?;
-
-        if !status.is_success() {
-            bail!("Anthropic API error ({}): {}", status, &resp_text[..resp_text.len().min(500)]);
-        }
-
-        let parsed: serde_json::Value = serde_json::from_str(&resp_text)
-            .map_err(|e| anyhow::anyhow!("Failed to parse API response: {}", e))?;
-
-        let latency = call_start.elapsed().as_millis() as u64;
-        let stop_reason = parsed["stop_reason"].as_str().unwrap_or("");
-        let content_blocks = parsed["content"].as_array()
-            .ok_or_else(|| anyhow::anyhow!("No content in API response"))?;
-
-        let mut text_parts: Vec<String> = Vec::new();
-        let mut tool_calls: Vec<Value> = Vec::new();
-        for block in content_blocks {
-            match block["type"].as_str() {
-                Some("text") => { if let Some(t) = block["text"].as_str() { text_parts.push(t.to_string()); } }
-                Some("tool_use") => {
-                    let name = block["name"].as_str().unwrap_or("").to_string();
-                    let arguments = self.json_to_value(block["input"].clone());
-                    tool_calls.push(Value::Map(vec![
-                        ("name".to_string(), Value::String(name)),
-                        ("arguments".to_string(), arguments),
-                    ]));
+            "flows" if !self.flows.contains_key(name) => {
+                if !args.is_empty() || !kwargs.is_empty() {
+                    bail!("flows() takes no arguments");
                 }
-                _ => {}
+                // `__repl__` and the like are the runtime's, not the program's
+                let mut flows: Vec<_> = self.flows.values().filter(|f| !f.name.starts_with("__")).collect();
+                flows.sort_by(|a, b| a.name.cmp(&b.name));
+                Ok(Value::List(flows.into_iter().map(flow_info).collect()))
+            }
+            "schema" if !self.flows.contains_key(name) => {
+                if args.len() != 1 {
+                    bail!("schema() takes one type: schema(\"Review\") or schema(\"List[Review]\")");
+                }
+                let mut as_text = false;
+                for (k, v) in kwargs {
+                    match (k.as_str(), self.eval(v)?) {
+                        ("text", Value::Bool(b)) => as_text = b,
+                        ("text", other) => bail!("schema(): text= must be a Bool, got {}", type_name(&other)),
+                        _ => bail!("schema(): unknown kwarg '{}'", k),
+                    }
+                }
+                let text = match self.eval(&args[0])? {
+                    Value::String(s) => s,
+                    other => bail!("schema() takes a type name as a String, got {}", type_name(&other)),
+                };
+                let ty = crate::parser::parse_type_expr(&text)
+                    .map_err(|e| anyhow::anyhow!("schema(): '{}' is not a type: {}", text, e))?;
+                // `#` is only the named type itself when that's the top level
+                let mut outer = if matches!(ty, TypeExpr::Named(_)) { Vec::new() } else { vec![std::string::String::new()] };
+                let schema = self.json_schema(&ty, &mut outer)?;
+                if as_text {
+                    return Ok(Value::String(serde_json::to_string_pretty(&schema)?));
+                }
+                Ok(Value::from_json(schema))
             }
-        }
-        let content = text_parts.join("\n");
-        log::info!("Anthropic API (vision): {}ms, stop={}, tools={}", latency, stop_reason, tool_calls.len());
-
-        if stop_reason == "tool_use" || !tool_calls.is_empty() {
-            self.trace_llm(model, "anthropic-api-vision", latency, prompt, system, &content, true);
-            return Ok(Value::Map(vec![
-                ("content".to_string(), Value::String(content)),
-                ("tool_calls".to_string(), Value::List(tool_calls)),
-                ("has_tool_calls".to_string(), Value::Bool(true)),
-            ]));
-        }
-        if tools.is_some() {
-            self.trace_llm(model, "anthropic-api-vision", latency, prompt, system, &content, false);
-            return Ok(Value::Map(vec![
-                ("content".to_string(), Value::String(content)),
-                ("has_tool_calls".to_string(), Value::Bool(false)),
-            ]));
-        }
-        self.trace_llm(model, "anthropic-api-vision", latency, prompt, system, &content, false);
-        Ok(Value::String(content))
-    }
-
-    #[allow(dead_code)]
-    fn call_anthropic_api(&self, model: &str, system: &str, prompt: &str, tools: Option<Vec<serde_json::Value>>) -> Result<Value> {
-        let call_start = std::time::Instant::now();
-
-        // Read token: ANTHROPIC_API_KEY env var first, then OpenClaw auth-profiles
-        let home = std::env::var("HOME").unwrap_or_default();
-        let mut token: Option<String> = None;
-        let mut token_source = "none";
-        if let Ok(key) = std::env::var("ANTHROPIC_API_KEY") {
-            if !key.is_empty() {
-                log::info!("Using ANTHROPIC_API_KEY env var ({}...)", &key[..key.len().min(20)]);
-                token = Some(key);
-                token_source = "env-var";
-            }
-        }
-        if token.is_none() {
-            let openclaw_agents = std::path::PathBuf::from(&home).join(".openclaw/agents");
-            if let Ok(entries) = std::fs::read_dir(&openclaw_agents) {
-                for entry in entries.flatten() {
-                    let auth_path = entry.path().join("agent/auth-profiles.json");
-                    if let Ok(data) = std::fs::read_to_string(&auth_path) {
-                        if let Ok(parsed) = serde_json::from_str::<serde_json::Value>(&data) {
-                            if let Some(t) = parsed["profiles"]["anthropic:default"]["token"].as_str() {
-                                if !t.is_empty() {
-                                    log::info!("Using Anthropic token from {:?} ({}...)", auth_path, &t[..t.len().min(20)]);
-                                    token = Some(t.to_string());
-                                    token_source = "openclaw-auth-profiles";
-                                    break;
-                                }
+            "define_flow" if !self.flows.contains_key(name) => {
+                if args.len() != 1 || !kwargs.is_empty() {
+                    bail!("define_flow() takes the flow's source: define_flow(\"flow double(x: Int) -> Int:\\n    return x * 2\")");
+                }
+                if !self.allow_dynamic {
+                    bail!("define_flow() is disabled — use: cognos run --allow-dynamic file.cog");
+                }
+                let source = match self.eval(&args[0])? {
+                    Value::String(s) => s,
+                    other => bail!("define_flow() takes the source as a String, got {}", type_name(&other)),
+                };
+                let tokens = crate::lexer::Lexer::new(&source).tokenize();
+                let program = crate::parser::Parser::new(tokens).parse_program()
+                    .map_err(|e| anyhow::anyhow!("define_flow(): {}", e))?;
+                let flow = match program.flows.as_slice() {
+                    [flow] if program.imports.is_empty() && program.types.is_empty() && program.setup.is_empty() => flow.clone(),
+                    [_] => bail!("define_flow(): the source may only hold the flow itself"),
+                    flows => bail!("define_flow() takes exactly one flow, got {}", flows.len()),
+                };
+                if flow.name.starts_with("__") {
+                    bail!("define_flow(): flow names starting with '__' are reserved for the runtime");
+                }
+                let replaced = self.dynamic_flows.contains(&flow.name);
+                if self.flows.contains_key(&flow.name) && !replaced {
+                    bail!("define_flow(): flow '{}' is already defined by the program and can't be replaced", flow.name);
+                }
+                log::info!("define_flow: registered flow '{}'", flow.name);
+                self.trace(TraceEvent::FlowDefined { name: flow.name.clone(), source, replaced });
+                self.dynamic_flows.insert(flow.name.clone());
+                let name = flow.name.clone();
+                self.flows.insert(name.clone(), flow);
+                Ok(Value::String(name))
+            }
+            "mcp" if !self.flows.contains_key(name) => {
+                let transport = match args.first().map(|a| self.eval(a)).transpose()? {
+                    Some(Value::String(s)) if args.len() == 1 => s,
+                    _ => bail!("mcp(transport, ...) — e.g. mcp(\"stdio\", command=\"npx\", args=[\"-y\", \"@modelcontextprotocol/server-filesystem\", \".\"]) or mcp(\"http\", url=\"http://localhost:8000/mcp\")"),
+                };
+                let (mut command, mut command_args, mut vars, mut url, mut headers) = (None, None, Vec::new(), None, Vec::new());
+                let mut timeout = crate::mcp::DEFAULT_TIMEOUT;
+                for (k, v) in kwargs {
+                    match (k.as_str(), self.eval(v)?) {
+                        ("command", Value::String(s)) => command = Some(s),
+                        ("url", Value::String(s)) => url = Some(s),
+                        ("args", Value::List(items)) => command_args = Some(items.iter().map(|i| i.to_string()).collect::<Vec<_>>()),
+                        ("env", Value::Map(entries)) => vars = entries.into_iter().map(|(k, v)| (k, v.to_string())).collect(),
+                        ("headers", Value::Map(entries)) => headers = entries.into_iter().map(|(k, v)| (k, v.to_string())).collect(),
+                        ("timeout", Value::Int(n)) if n > 0 => timeout = std::time::Duration::from_secs(n as u64),
+                        ("command" | "url", other) => bail!("mcp(): {}= must be a String, got {}", k, type_name(&other)),
+                        ("args", other) => bail!("mcp(): args= must be a List of Strings, got {}", type_name(&other)),
+                        ("env" | "headers", other) => bail!("mcp(): {}= must be a Map, got {}", k, type_name(&other)),
+                        ("timeout", other) => bail!("mcp(): timeout= must be a positive Int of seconds, got {}", other),
+                        _ => bail!("mcp(): unknown kwarg '{}'", k),
+                    }
+                }
+                let client = match transport.as_str() {
+                    "stdio" => {
+                        if url.is_some() || !headers.is_empty() {
+                            bail!("mcp(\"stdio\") takes command=, args= and env=; url= and headers= are for mcp(\"http\")");
+                        }
+                        if !self.env.lock().unwrap().allow_shell() {
+                            bail!("mcp(\"stdio\") starts a server process — use: cognos run --allow-shell file.cog");
+                        }
+                        // Without args=, the command is split on whitespace
+                        let (program, program_args) = match (command, command_args) {
+                            (Some(command), Some(args)) => (command, args),
+                            (Some(command), None) => {
+                                let mut words = command.split_whitespace().map(str::to_string);
+                                (words.next().unwrap_or_default(), words.collect())
+                            }
+                            (None, _) => bail!("mcp(\"stdio\") needs command=, the server to start"),
+                        };
+                        if program.is_empty() {
+                            bail!("mcp(\"stdio\") needs command=, the server to start");
+                        }
+                        crate::mcp::Client::stdio(&program, &program_args, &vars, timeout)?
+                    }
+                    "http" => {
+                        if command.is_some() || command_args.is_some() || !vars.is_empty() {
+                            bail!("mcp(\"http\") takes url= and headers=; command=, args= and env= are for mcp(\"stdio\")");
+                        }
+                        let Some(url) = url else { bail!("mcp(\"http\") needs url=, the server's endpoint") };
+                        crate::mcp::Client::http(&url, headers, timeout)?
+                    }
+                    other => bail!("mcp(): unknown transport '{}' — use \"stdio\" or \"http\"", other),
+                };
+                let client = Arc::new(client);
+                for tool in &client.tools {
+                    self.mcp_tools.insert(tool.name.clone(), client.clone());
+                }
+                Ok(Value::Handle(Handle::Mcp(client)))
+            }
+            "wasm" if !self.flows.contains_key(name) => {
+                if args.is_empty() || args.len() > 2 {
+                    bail!("wasm(module, input?) — e.g. wasm(\"tools/grep.wasm\", query, dirs={{\"/data\": \"./data\"}})");
+                }
+                let module = match self.eval(&args[0])? {
+                    Value::String(s) => s,
+                    other => bail!("wasm() takes the module path as a String, got {}", type_name(&other)),
+                };
+                let stdin = match args.get(1).map(|a| self.eval(a)).transpose()? {
+                    None | Some(Value::None) => Vec::new(),
+                    Some(Value::String(s)) => s.into_bytes(),
+                    Some(other) => self.value_to_json(&other).to_string().into_bytes(),
+                };
+                let mut options = crate::wasm::WasmOptions::default();
+                for (k, v) in kwargs {
+                    match (k.as_str(), self.eval(v)?) {
+                        (kind @ ("dirs" | "ro_dirs"), Value::Map(entries)) => {
+                            for (guest, host) in entries {
+                                options.dirs.push(crate::wasm::MappedDir {
+                                    guest,
+                                    host: std::path::PathBuf::from(host.to_string()),
+                                    writable: kind == "dirs",
+                                });
                             }
                         }
+                        ("dirs" | "ro_dirs", other) => bail!("wasm(): {}= maps guest paths to host directories, got {}", k, type_name(&other)),
+                        ("fuel", Value::Int(n)) if n > 0 => options.fuel = n as u64,
+                        ("memory_mb", Value::Int(n)) if n > 0 => options.memory_mb = n as usize,
+                        ("fuel" | "memory_mb", other) => bail!("wasm(): {}= must be a positive Int, got {}", k, other),
+                        _ => bail!("wasm(): unknown kwarg '{}'", k),
+                    }
+                }
+                let start = std::time::Instant::now();
+                let result = crate::wasm::run(std::path::Path::new(&module), stdin, &options);
+                let (exit_code, fuel_used, output_chars) = match &result {
+                    Ok(out) => (Some(out.exit_code), Some(out.fuel_used), out.stdout.len()),
+                    Err(_) => (None, None, 0),
+                };
+                self.trace(TraceEvent::WasmExec {
+                    module: module.clone(),
+                    latency_ms: start.elapsed().as_millis() as u64,
+                    exit_code, fuel_used, output_chars,
+                    error: result.as_ref().err().map(|e| e.to_string()),
+                });
+                let out = result?;
+                if out.exit_code != 0 {
+                    let stderr = out.stderr.trim();
+                    if stderr.is_empty() {
+                        bail!("{} exited with status {}", module, out.exit_code);
                     }
+                    bail!("{} exited with status {}: {}", module, out.exit_code, stderr);
                 }
+                // Tools that answer in JSON come back as values
+                Ok(match serde_json::from_str::<serde_json::Value>(&out.stdout) {
+                    Ok(json) if !out.stdout.trim().is_empty() => self.json_to_value(json),
+                    _ => Value::String(out.stdout),
+                })
+            }
+            "types" if !self.flows.contains_key(name) => {
+                if !args.is_empty() || !kwargs.is_empty() {
+                    bail!("types() takes no arguments");
+                }
+                let mut types: Vec<_> = self.types.values().collect();
+                types.sort_by(|a, b| a.name().cmp(b.name()));
+                Ok(Value::List(types.into_iter().map(type_info).collect()))
+            }
+            "shared" => {
+                let entries = match args.first().map(|a| self.eval(a)).transpose()? {
+                    None => Entries::new(),
+                    Some(Value::Map(entries)) => entries,
+                    Some(other) => bail!("shared() takes an optional Map of initial values, got {}", type_name(&other)),
+                };
+                Ok(Value::Shared(Shared(Arc::new(Mutex::new(entries)))))
+            }
+            _ => {
+                // Try user-defined flow
+                if self.flows.contains_key(name) {
+                    let mut arg_vals = Vec::new();
+                    for arg in args {
+                        arg_vals.push(self.eval(arg)?);
+                    }
+                    let mut kwarg_vals = Vec::new();
+                    for (k, v) in kwargs {
+                        kwarg_vals.push((k.clone(), self.eval(v)?));
+                    }
+                    return self.call_flow(name, arg_vals, kwarg_vals);
+                }
+                if let Some(Value::Lambda(lambda)) = self.vars.get(name).cloned() {
+                    if let Some((k, _)) = kwargs.first() {
+                        bail!("{}(): a lambda takes no keyword arguments, got '{}'", name, k);
+                    }
+                    let arg_vals = args.iter().map(|a| self.eval(a)).collect::<Result<Vec<_>>>()?;
+                    return self.call_lambda(&lambda, arg_vals);
+                }
+                if let Some(plugin) = crate::plugin::builtin(name) {
+                    let args = args.iter().map(|a| Ok(self.eval(a)?.to_json())).collect::<Result<Vec<_>>>()?;
+                    let mut kwarg_vals = serde_json::Map::new();
+                    for (k, v) in kwargs {
+                        kwarg_vals.insert(k.clone(), self.eval(v)?.to_json());
+                    }
+                    let request = serde_json::json!({ "call": "builtin", "name": name, "args": args, "kwargs": kwarg_vals });
+                    let result = self.call_plugin(plugin, name, request).map_err(|e| anyhow::anyhow!("{}(): {}", name, e))?;
+                    return Ok(Value::from_json(result));
+                }
+                bail!("unknown function: {}()", name)
             }
         }
-        let token = token.ok_or_else(|| anyhow::anyhow!(
-            "No Anthropic token found. Run 'openclaw configure' or set ANTHROPIC_API_KEY."
-        ))?;
-        log::info!("Token source: {}, prefix: {}...", token_source, &token[..token.len().min(20)]);
-
-        log::info!("Calling Anthropic API: model={}, tools={}", model, tools.as_ref().map(|t| t.len()).unwrap_or(0));
+    }
 
-        // Build request body
-        let mut body = serde_json::json!({
-            "model": model,
-            "max_tokens": 4096,
-            "messages": [{"role": "user", "content": prompt}]
-        });
-        if !system.is_empty() {
-            body["system"] = serde_json::json!(system);
+    fn call_module(&mut self, module: &str, method: &str, args: Vec<Value>, kwargs: Vec<(String, Value)>) -> Result<Value> {
+        if module == "grpc" {
+            return self.call_grpc(method, args, kwargs);
         }
-
-        // Add native tools
-        if let Some(ref tool_defs) = tools {
-            let api_tools: Vec<serde_json::Value> = tool_defs.iter().map(|t| {
-                serde_json::json!({
-                    "name": t["function"]["name"].as_str().unwrap_or("unknown"),
-                    "description": t["function"]["description"].as_str().unwrap_or(""),
-                    "input_schema": t["function"]["parameters"]
-                })
-            }).collect();
-            log::debug!("API tools payload: {}", serde_json::to_string_pretty(&api_tools).unwrap_or_default());
-            body["tools"] = serde_json::json!(api_tools);
+        if module == "http" {
+            return self.call_http(method, args, kwargs);
         }
-        log::debug!("API request body: {}", serde_json::to_string(&body).unwrap_or_default());
-
-        let client = reqwest::blocking::Client::new();
-        let mut req = client.post("https://api.anthropic.com/v1/messages");
-        if !token.starts_with("sk-ant-oat") {
-            req = req.header("x-api-key", &token);
-        } else {
-            req = req.header("Authorization", format!("Bearer {}", token));
-            req = req.header("anthropic-beta", "oauth-2025-04-20");
+        if let Some((k, _)) = kwargs.first() {
+            bail!("{}.{}(): unknown kwarg '{}'", module, method, k);
         }
-        let resp = req
-            .header("anthropic-version", "2023-06-01")
-            .header("content-type", "application/json")
-            .json(&body)
-            .send()
-            .map_err(|e| anyhow::anyhow!("Anthropic API request failed: {}", e))?;
-
-        let status = resp.status();
-        let resp_text = resp.text().map_err(|e| anyhow::anyhow!("Failed to read API response: {}", e))?;
-
-        if !status.is_success() {
-            bail!("Anthropic API error ({}): {}", status, &resp_text[..resp_text.len().min(500)]);
+        match module {
+            "math" => bail!("math module was removed (P11: lean core runtime). Use shell() for math operations."),
+            _ => bail!("unknown module '{}'", module),
         }
+    }
 
-        let parsed: serde_json::Value = serde_json::from_str(&resp_text)
-            .map_err(|e| anyhow::anyhow!("Failed to parse API response: {}", e))?;
-
-        let latency = call_start.elapsed().as_millis() as u64;
-        let stop_reason = parsed["stop_reason"].as_str().unwrap_or("");
-
-        // Extract content blocks
-        let content_blocks = parsed["content"].as_array()
-            .ok_or_else(|| anyhow::anyhow!("No content in API response"))?;
+    #[allow(dead_code)]
+    fn to_float(v: &Value) -> Result<f64> {
+        match v {
+            Value::Float(f) => Ok(*f),
+            Value::Int(i) => Ok(*i as f64),
+            other => bail!("expected a number, got {} (type: {})", other, type_name(other)),
+        }
+    }
 
-        let mut text_parts: Vec<String> = Vec::new();
-        let mut tool_calls: Vec<Value> = Vec::new();
+    // math module removed — P11: lean core runtime
 
-        for block in content_blocks {
-            match block["type"].as_str() {
-                Some("text") => {
-                    if let Some(t) = block["text"].as_str() {
-                        text_parts.push(t.to_string());
+    fn call_http(&mut self, method: &str, args: Vec<Value>, kwargs: Vec<(String, Value)>) -> Result<Value> {
+        let mut policy = crate::retry::Policy::default();
+        for (k, v) in kwargs {
+            match (k.as_str(), v) {
+                ("retries", Value::Int(n)) if n >= 0 => policy.retries = n.min(u32::MAX as i64) as u32,
+                ("retries", other) => bail!("http.{}(): retries= must be a non-negative Int, got {}", method, other),
+                ("backoff", Value::String(s)) => policy.backoff = crate::retry::Backoff::parse(&s)?,
+                ("backoff", other) => bail!("http.{}(): backoff= must be a String, got {}", method, type_name(&other)),
+                (other, _) => bail!("http.{}(): unknown kwarg '{}'", method, other),
+            }
+        }
+        let call = format!("http.{}", method);
+        match method {
+            "get" => {
+                if args.is_empty() { bail!("http.get() requires a URL"); }
+                let url = args[0].to_string();
+                log::info!("http.get({})", url);
+                let mut attempt = 1;
+                loop {
+                    let result = self.env.lock().unwrap().http_get(&url);
+                    match result {
+                        Ok(body) => return Ok(Value::String(body)),
+                        Err(e) if self.wait_to_retry(&call, &url, &policy, attempt, &e) => attempt += 1,
+                        Err(e) => return http_failure(e, &policy),
                     }
                 }
-                Some("tool_use") => {
-                    let name = block["name"].as_str().unwrap_or("").to_string();
-                    let arguments = self.json_to_value(block["input"].clone());
-                    tool_calls.push(Value::Map(vec![
-                        ("name".to_string(), Value::String(name)),
-                        ("arguments".to_string(), arguments),
-                    ]));
+            }
+            "post" => {
+                if args.len() < 2 { bail!("http.post(url, body)"); }
+                let url = args[0].to_string();
+                let body = args[1].to_string();
+                log::info!("http.post({})", url);
+                let mut attempt = 1;
+                loop {
+                    let result = self.env.lock().unwrap().http_post(&url, &body);
+                    match result {
+                        Ok(resp) => return Ok(Value::String(resp)),
+                        Err(e) if self.wait_to_retry(&call, &url, &policy, attempt, &e) => attempt += 1,
+                        Err(e) => return http_failure(e, &policy),
+                    }
                 }
-                _ => {}
             }
+            _ => bail!("http has no function '{}'", method),
         }
+    }
 
-        let content = text_parts.join("\n");
-        log::info!("Anthropic API: {}ms, stop={}, tools={}", latency, stop_reason, tool_calls.len());
-
-        if stop_reason == "tool_use" || !tool_calls.is_empty() {
-            self.trace_llm(model, "anthropic-api", latency, prompt, system, &content, true);
-            return Ok(Value::Map(vec![
-                ("content".to_string(), Value::String(content)),
-                ("tool_calls".to_string(), Value::List(tool_calls)),
-                ("has_tool_calls".to_string(), Value::Bool(true)),
-            ]));
+    fn call_grpc(&mut self, method: &str, args: Vec<Value>, kwargs: Vec<(String, Value)>) -> Result<Value> {
+        if method != "call" {
+            bail!("grpc has no function '{}'", method);
         }
+        let [endpoint, service, rpc, message] = <[Value; 4]>::try_from(args).map_err(|_| {
+            anyhow::anyhow!("grpc.call(endpoint, service, method, message, proto=) — e.g. grpc.call(\"localhost:50051\", \"shop.Orders\", \"Get\", {{\"id\": 7}}, proto=\"shop.proto\")")
+        })?;
+        let text = |what: &str, value: Value| match value {
+            Value::String(s) => Ok(s),
+            other => bail!("grpc.call() takes the {} as a String, got {}", what, type_name(&other)),
+        };
+        let mut call = crate::grpc::Call {
+            endpoint: text("endpoint", endpoint)?,
+            service: text("service", service)?,
+            method: text("method", rpc)?,
+            message: self.value_to_json(&message),
+            proto: String::new(),
+            metadata: Vec::new(),
+            timeout: Service::Http.timeout(),
+        };
+        for (k, v) in kwargs {
+            match (k.as_str(), v) {
+                ("proto", Value::String(path)) => call.proto = path,
+                ("metadata", Value::Map(entries)) => {
+                    call.metadata = entries.into_iter().map(|(k, v)| (k, v.to_string())).collect();
+                }
+                ("timeout", Value::Int(n)) if n > 0 => call.timeout = std::time::Duration::from_secs(n as u64),
+                ("timeout", Value::Float(f)) if f > 0.0 => call.timeout = std::time::Duration::from_secs_f64(f),
+                ("proto", other) => bail!("grpc.call(): proto= is the path of a .proto file, got {}", type_name(&other)),
+                ("metadata", other) => bail!("grpc.call(): metadata= must be a Map of header values, got {}", type_name(&other)),
+                ("timeout", other) => bail!("grpc.call(): timeout= must be a positive number of seconds, got {}", other),
+                _ => bail!("grpc.call(): unknown kwarg '{}'", k),
+            }
+        }
+        if call.proto.is_empty() {
+            bail!("grpc.call() needs proto=\"file.proto\" describing {}", call.service);
+        }
+        log::info!("grpc.call({}, {}/{})", call.endpoint, call.service, call.method);
+        let start = std::time::Instant::now();
+        let result = self.env.lock().unwrap().grpc_call(&call);
+        self.trace(TraceEvent::GrpcCall {
+            endpoint: call.endpoint.clone(),
+            method: format!("{}/{}", call.service, call.method),
+            latency_ms: start.elapsed().as_millis() as u64,
+            error: result.as_ref().err().map(|e| e.to_string()),
+        });
+        let reply = result.map_err(|e| anyhow::anyhow!("grpc.call({}/{}): {}", call.service, call.method, e))?;
+        Ok(self.json_to_value(reply))
+    }
 
-        if tools.is_some() {
-            self.trace_llm(model, "anthropic-api", latency, prompt, system, &content, false);
-            return Ok(Value::Map(vec![
-                ("content".to_string(), Value::String(content)),
-                ("has_tool_calls".to_string(), Value::Bool(false)),
-            ]));
+    /// Block on a future and take its result; the handle is used up.
+    fn await_future(&mut self, id: u64) -> Result<Value> {
+        let (handle, cancel_token) = self.async_handles.remove(&id)
+            .ok_or_else(|| anyhow::anyhow!("invalid or already-consumed future handle {}", id))?;
+        if cancel_token.load(Ordering::Relaxed) {
+            bail!("async task was cancelled");
+        }
+        match handle.join() {
+            Ok(result) => result,
+            Err(_) => bail!("async task panicked"),
         }
-
-        self.trace_llm(model, "anthropic-api", latency, prompt, system, &content, false);
-        Ok(Value::String(content))
     }
 
-    fn call_openai(&self, model: &str, system: &str, prompt: &str, tools: Option<Vec<serde_json::Value>>) -> Result<Value> {
-        self.call_openai_compat(model, system, prompt, tools,
-            "https://api.openai.com/v1/chat/completions", "OPENAI_API_KEY")
+    /// What a `with` block binds. A file is emptied once, up front, and
+    /// written to by appending, so several write()s in the block add up.
+    fn open_resource(&mut self, resource: Value) -> Result<Value> {
+        match resource {
+            Value::Handle(Handle::File(path)) => {
+                self.env.lock().unwrap().write_file(&path, "")?;
+                Ok(Value::Handle(Handle::AppendFile(path)))
+            }
+            Value::Handle(_) | Value::Future(_) => Ok(resource),
+            other => bail!("with needs a handle or a future, got {}", type_name(&other)),
+        }
     }
 
-    fn call_openai_compat(&self, model: &str, system: &str, prompt: &str, tools: Option<Vec<serde_json::Value>>,
-                          endpoint: &str, env_key: &str) -> Result<Value> {
-        let api_key = std::env::var(env_key)
-            .or_else(|_| {
-                let env_path = std::path::Path::new(".env");
-                if env_path.exists() {
-                    std::fs::read_to_string(env_path).ok().and_then(|content| {
-                        content.lines().find_map(|line| {
-                            let line = line.trim();
-                            line.strip_prefix(&format!("{}=", env_key))
-                                .map(|val| val.trim_matches('"').trim_matches('\'').to_string())
-                        })
-                    }).ok_or_else(|| std::env::VarError::NotPresent)
-                } else { Err(std::env::VarError::NotPresent) }
-            })
-            .map_err(|_| anyhow::anyhow!("{} not set. Set it in env or .env file.", env_key))?;
-
-        log::info!("Calling {}: model={}, tools={}", env_key, model, tools.as_ref().map(|t| t.len()).unwrap_or(0));
-        let call_start = std::time::Instant::now();
-
-        let mut messages = Vec::new();
-        if !system.is_empty() {
-            messages.push(serde_json::json!({"role": "system", "content": system}));
+    /// End of a `with` block, normal or not: a task still running is
+    /// cancelled, and handles are traced as closed.
+    fn close_resource(&mut self, resource: &Value) {
+        match resource {
+            Value::Future(id) => {
+                if let Some((_, cancel_token)) = self.async_handles.remove(id) {
+                    cancel_token.store(true, Ordering::Relaxed);
+                    log::info!("with: cancelled async task {} still running at block exit", id);
+                }
+            }
+            Value::Handle(Handle::AppendFile(path)) => self.trace(TraceEvent::IoOp {
+                operation: "close".into(), handle_type: "file".into(),
+                path: Some(path.clone()), bytes: 0, content: None,
+            }),
+            Value::Handle(Handle::Channel { provider, .. }) => self.trace(TraceEvent::IoOp {
+                operation: "close".into(), handle_type: "channel".into(),
+                path: Some(provider.clone()), bytes: 0, content: None,
+            }),
+            _ => {}
         }
-        messages.push(serde_json::json!({"role": "user", "content": prompt}));
-
-        let mut body = serde_json::json!({
-            "model": model,
-            "messages": messages
-        });
+    }
 
-        if let Some(ref tool_defs) = tools {
-            body["tools"] = serde_json::json!(tool_defs);
-            body["tool_choice"] = serde_json::json!("auto");
+    /// `prompt_user()` without a terminal: the question and numbered choices
+    /// go to stdout and the answer, a number or a choice, comes from stdin.
+    fn prompt_plain(&mut self, question: &str, choices: Option<&[std::string::String]>, default: Option<&str>) -> Result<std::string::String> {
+        let mut text = question.to_string();
+        if let Some(d) = default {
+            text.push_str(&format!(" ({})", d));
         }
-
-        let client = reqwest::blocking::Client::builder()
-            .timeout(std::time::Duration::from_secs(120))
-            .build()?;
-
-        let resp = client.post(endpoint)
-            .header("Authorization", format!("Bearer {}", api_key))
-            .header("Content-Type", "application/json")
-            .json(&body)
-            .send()
-            .map_err(|e| anyhow::anyhow!("API error: {}", e))?;
-
-        let json: serde_json::Value = resp.json()
-            .map_err(|e| anyhow::anyhow!("OpenAI JSON error: {}", e))?;
-
-        if let Some(err) = json.get("error") {
-            bail!("OpenAI API error: {}", err);
+        for (i, choice) in choices.unwrap_or_default().iter().enumerate() {
+            text.push_str(&format!("\n  {}) {}", i + 1, choice));
         }
-
-        let choice = &json["choices"][0]["message"];
-        let raw_content = choice["content"].as_str().unwrap_or("").to_string();
-        // Strip <think>...</think> tags (MiniMax reasoning tokens)
-        let content = if let Some(end) = raw_content.find("</think>") {
-            raw_content[end + 8..].trim().to_string()
-        } else {
-            raw_content
-        };
-
-        // Check for tool calls
-        if let Some(tool_calls_arr) = choice.get("tool_calls").and_then(|v| v.as_array()) {
-            if !tool_calls_arr.is_empty() {
-                let tc: Vec<Value> = tool_calls_arr.iter().map(|c| {
-                    let func = &c["function"];
-                    let name = func["name"].as_str().unwrap_or("").to_string();
-                    let args_str = func["arguments"].as_str().unwrap_or("{}");
-                    let arguments = serde_json::from_str::<serde_json::Value>(args_str)
-                        .map(|v| self.json_to_value(v))
-                        .unwrap_or(Value::Map(vec![]));
-                    Value::Map(vec![
-                        ("name".to_string(), Value::String(name)),
-                        ("arguments".to_string(), arguments),
-                    ])
-                }).collect();
-
-                let latency = call_start.elapsed().as_millis() as u64;
-                self.trace_llm(model, "openai", latency, prompt, system, &content, true);
-                return Ok(Value::Map(vec![
-                    ("content".to_string(), Value::String(content)),
-                    ("tool_calls".to_string(), Value::List(tc)),
-                    ("has_tool_calls".to_string(), Value::Bool(true)),
-                ]));
+        let mut env = self.env.lock().unwrap();
+        env.write_stdout(&text)?;
+        loop {
+            let answer = match env.read_stdin() {
+                Ok(line) => line.trim().to_string(),
+                Err(e) if e.to_string().contains("end of input") => match default {
+                    Some(d) => return Ok(d.to_string()),
+                    None => bail!("prompt_user(): no answer (end of input)"),
+                },
+                Err(e) => return Err(e),
+            };
+            let Some(choices) = choices else {
+                return Ok(match (answer.is_empty(), default) {
+                    (true, Some(d)) => d.to_string(),
+                    _ => answer,
+                });
+            };
+            if let (true, Some(d)) = (answer.is_empty(), default) {
+                return Ok(d.to_string());
+            }
+            if let Some(i) = answer.parse::<usize>().ok().filter(|i| (1..=choices.len()).contains(i)) {
+                return Ok(choices[i - 1].clone());
             }
+            if let Some(choice) = choices.iter().find(|c| c.eq_ignore_ascii_case(&answer)) {
+                return Ok(choice.clone());
+            }
+            env.write_stdout(&format!("Please answer 1-{} or one of: {}", choices.len(), choices.join(", ")))?;
         }
+    }
 
-        let latency = call_start.elapsed().as_millis() as u64;
-        self.trace_llm(model, "openai", latency, prompt, system, &content, false);
-
-        if tools.is_some() {
-            Ok(Value::Map(vec![
-                ("content".to_string(), Value::String(content)),
-                ("has_tool_calls".to_string(), Value::Bool(false)),
-            ]))
-        } else {
-            Ok(Value::String(content))
-        }
-    }
-
-    fn call_anthropic(&self, model: &str, system: &str, prompt: &str, tools: Option<Vec<serde_json::Value>>) -> Result<Value> {
-        let api_key = std::env::var("ANTHROPIC_API_KEY")
-            .or_else(|_| {
-                // Try .env file
-                let env_path = std::path::Path::new(".env");
-                if env_path.exists() {
-                    std::fs::read_to_string(env_path).ok().and_then(|content| {
-                        content.lines().find_map(|line| {
-                            let line = line.trim();
-                            line.strip_prefix("ANTHROPIC_API_KEY=")
-                                .map(|val| val.trim_matches('"').trim_matches('\'').to_string())
-                        })
-                    }).ok_or_else(|| std::env::VarError::NotPresent)
-                } else { Err(std::env::VarError::NotPresent) }
-            })
-            .map_err(|_| anyhow::anyhow!("ANTHROPIC_API_KEY not set. Set it in env or .env file."))?;
-
-        log::info!("Calling Anthropic: model={}, system={:?}, tools={}", model, system, tools.as_ref().map(|t| t.len()).unwrap_or(0));
-
-        let messages = vec![serde_json::json!({"role": "user", "content": prompt})];
-
-        let mut body = serde_json::json!({
-            "model": model,
-            "max_tokens": 4096,
-            "messages": messages,
-            "stream": false
-        });
-
-        if !system.is_empty() {
-            body["system"] = serde_json::Value::String(system.to_string());
+    /// `future.done()` and `future.result(timeout=secs)`
+    fn call_future_method(&mut self, id: u64, method: &str, args: Vec<Value>, kwargs: Vec<(std::string::String, Value)>) -> Result<Value> {
+        if !args.is_empty() {
+            bail!(".{}() takes no positional arguments", method);
+        }
+        match method {
+            "done" => {
+                if let Some((k, _)) = kwargs.first() {
+                    bail!(".done(): unknown kwarg '{}'", k);
+                }
+                let (handle, cancel_token) = self.async_handles.get(&id)
+                    .ok_or_else(|| anyhow::anyhow!("invalid or already-consumed future handle {}", id))?;
+                Ok(Value::Bool(handle.is_finished() || cancel_token.load(Ordering::Relaxed)))
+            }
+            "result" => {
+                let mut timeout = None;
+                for (k, v) in kwargs {
+                    match (k.as_str(), v) {
+                        ("timeout", Value::Int(n)) if n >= 0 => timeout = Some(n as f64),
+                        ("timeout", Value::Float(n)) if n >= 0.0 => timeout = Some(n),
+                        ("timeout", Value::None) => {}
+                        ("timeout", other) => bail!(".result(): timeout must be a number of seconds, got {} ({})", other, type_name(&other)),
+                        (k, _) => bail!(".result(): unknown kwarg '{}'", k),
+                    }
+                }
+                if let Some(secs) = timeout {
+                    let deadline = std::time::Instant::now() + std::time::Duration::from_secs_f64(secs);
+                    loop {
+                        let (handle, _) = self.async_handles.get(&id)
+                            .ok_or_else(|| anyhow::anyhow!("invalid or already-consumed future handle {}", id))?;
+                        if handle.is_finished() {
+                            break;
+                        }
+                        if std::time::Instant::now() >= deadline {
+                            bail!("async task did not finish within {}s; the future is still pending", secs);
+                        }
+                        std::thread::sleep(std::time::Duration::from_millis(5));
+                    }
+                }
+                self.await_future(id)
+            }
+            _ => bail!("a Future has no method '{}'; use .done(), .result(timeout=) or await()", method),
         }
+    }
 
-        if let Some(ref tool_defs) = tools {
-            // Convert from OpenAI format to Anthropic format
-            let anthropic_tools: Vec<serde_json::Value> = tool_defs.iter().map(|t| {
-                serde_json::json!({
-                    "name": t["function"]["name"],
-                    "description": t["function"]["description"],
-                    "input_schema": t["function"]["parameters"]
-                })
-            }).collect();
-            body["tools"] = serde_json::json!(anthropic_tools);
+    /// Cancel async tasks the flow started (ids from `since` on) that are
+    /// still pending when it ends, unless it hands them back in its result.
+    fn cancel_pending_futures(&mut self, flow: &str, since: u64, result: Option<&Value>) {
+        let pending: Vec<u64> = self.async_handles.keys()
+            .filter(|&&id| id >= since && !result.is_some_and(|v| holds_future(v, id)))
+            .copied()
+            .collect();
+        if pending.is_empty() {
+            return;
+        }
+        for id in &pending {
+            if let Some((_, cancel_token)) = self.async_handles.remove(id) {
+                cancel_token.store(true, Ordering::Relaxed);
+            }
+        }
+        let message = format!("flow '{}' ended with {} async task(s) never awaited; cancelled them \
+            (await() or cancel() each future before the flow returns)", flow, pending.len());
+        self.trace(TraceEvent::Warning { category: "async".to_string(), message: message.clone() });
+        crate::diagnostics::warning(message);
+    }
+
+    /// `items.push(x)`, `items.pop()`, `rows[0].tags.insert(i, x)`: change
+    /// the list at a variable's path in place, like `items[0] = x` does.
+    /// Anything else is left for `call_method`, with each argument evaluated
+    /// once either way.
+    fn mutate_list(&mut self, object: &Expr, method: &str, args: &[Expr]) -> Result<ListCall> {
+        let Some((name, steps)) = place(object) else { return Ok(ListCall::NotAPlace) };
+        let mut keys = Vec::with_capacity(steps.len());
+        for step in steps {
+            keys.push(match step {
+                Expr::Field { field, .. } => Value::String(field.clone()),
+                Expr::Index { index, .. } => self.eval(index)?,
+                _ => unreachable!("place() returns fields and indexes"),
+            });
         }
+        let mut arg_vals = Vec::with_capacity(args.len());
+        for a in args {
+            arg_vals.push(self.eval(a)?);
+        }
+        let limits = self.limits;
+        let depth = keys.len();
+        let done = update_path(self.local_mut(name)?, &keys, &mut |target| match target {
+            Value::List(items) => list_mutation(items, method, &arg_vals, depth, &limits).map(|v| Ok(ListCall::Done(v))),
+            other => Ok(Err(other.clone())),
+        })?;
+        Ok(done.unwrap_or_else(|held| ListCall::Other(held, arg_vals)))
+    }
 
-        let client = reqwest::blocking::Client::builder()
-            .timeout(std::time::Duration::from_secs(120))
-            .build()?;
+    fn call_method(&mut self, obj: Value, method: &str, args: Vec<Value>) -> Result<Value> {
+        match (&obj, method) {
+            // ── String methods ──
+            (Value::String(s), "upper") => Ok(Value::String(s.to_uppercase())),
+            (Value::String(s), "lower") => Ok(Value::String(s.to_lowercase())),
+            (Value::String(s), "strip") => Ok(Value::String(s.trim().to_string())),
+            (Value::String(s), "starts_with") => {
+                let prefix = self.expect_string_arg(method, &args, 0)?;
+                Ok(Value::Bool(s.starts_with(&prefix)))
+            }
+            (Value::String(s), "ends_with") => {
+                let suffix = self.expect_string_arg(method, &args, 0)?;
+                Ok(Value::Bool(s.ends_with(&suffix)))
+            }
+            (Value::String(s), "contains") => {
+                let needle = self.expect_string_arg(method, &args, 0)?;
+                Ok(Value::Bool(s.contains(&needle)))
+            }
+            (Value::String(s), "replace") => {
+                let from = self.expect_string_arg(method, &args, 0)?;
+                let to = self.expect_string_arg(method, &args, 1)?;
+                Ok(Value::String(s.replace(&from, &to)))
+            }
+            (Value::String(s), "truncate") => {
+                let max = match args.first() {
+                    Some(Value::Int(n)) => *n as usize,
+                    _ => bail!(".truncate() requires an Int argument"),
+                };
+                if s.len() <= max {
+                    Ok(Value::String(s.clone()))
+                } else {
+                    let truncated: String = s.chars().take(max).collect();
+                    Ok(Value::String(format!("{}...", truncated)))
+                }
+            }
+            (Value::String(s), "split") => {
+                let delim = self.expect_string_arg(method, &args, 0)?;
+                let parts: Vec<Value> = s.split(&delim).map(|p| Value::String(p.to_string())).collect();
+                Ok(Value::List(parts))
+            }
+            (Value::String(s), "index") => {
+                let needle = self.expect_string_arg(method, &args, 0)?;
+                match s.find(&needle) {
+                    Some(pos) => Ok(Value::Int(pos as i64)),
+                    None => bail!("substring '{}' not found in string", needle),
+                }
+            }
+            (Value::String(s), "find") => {
+                let needle = self.expect_string_arg(method, &args, 0)?;
+                match s.find(&needle) {
+                    Some(pos) => Ok(Value::Int(pos as i64)),
+                    None => Ok(Value::Int(-1)),
+                }
+            }
 
-        let is_oauth = api_key.starts_with("sk-ant-oat");
-        let mut req = client.post("https://api.anthropic.com/v1/messages")
-            .header("anthropic-version", "2023-06-01")
-            .header("content-type", "application/json");
+            // ── Shared map methods ──
+            (Value::Shared(shared), "get") => {
+                let key = self.expect_string_arg(method, &args, 0)?;
+                let entries = shared.lock()?;
+                let found = entries.get(&key).cloned();
+                Ok(found.or_else(|| args.get(1).cloned()).unwrap_or(Value::None))
+            }
+            (Value::Shared(shared), "set") => {
+                let key = self.expect_string_arg(method, &args, 0)?;
+                let Some(value) = args.get(1).cloned() else { bail!(".set() requires a key and a value") };
+                let mut entries = shared.lock()?;
+                entries.insert(key.clone(), value);
+                Ok(Value::None)
+            }
+            (Value::Shared(shared), "append") => {
+                let key = self.expect_string_arg(method, &args, 0)?;
+                let Some(item) = args.get(1).cloned() else { bail!(".append() requires a key and an item") };
+                let mut entries = shared.lock()?;
+                let mut items = match entries.get(&key).cloned() {
+                    None | Some(Value::None) => Vec::new(),
+                    Some(Value::List(items)) => items,
+                    Some(other) => bail!(".append(): '{}' holds a {}, not a List", key, type_name(&other)),
+                };
+                self.limits.check_len(items.len() + 1)?;
+                self.limits.check_nesting([&item])?;
+                items.push(item);
+                let len = items.len();
+                entries.insert(key.clone(), Value::List(items));
+                Ok(Value::Int(len as i64))
+            }
+            (Value::Shared(shared), "update") => {
+                // update(key, "flow"): the flow gets the current value (none
+                // if unset) and returns the new one, with the map locked
+                // throughout so no other branch writes in between
+                let key = self.expect_string_arg(method, &args, 0)?;
+                let flow = self.expect_string_arg(method, &args, 1)?;
+                if !self.flows.contains_key(flow.as_str()) {
+                    bail!(".update(): no flow named '{}'", flow);
+                }
+                let mut entries = shared.lock()?;
+                let current = entries.get(&key).cloned().unwrap_or(Value::None);
+                UPDATING.with(|u| u.borrow_mut().push(shared.id()));
+                let result = self.call_flow(&flow, vec![current], Vec::new());
+                UPDATING.with(|u| u.borrow_mut().pop());
+                let value = result?;
+                entries.insert(key.clone(), value.clone());
+                Ok(value)
+            }
+            (Value::Shared(shared), "keys") => {
+                Ok(Value::List(shared.lock()?.iter().map(|(k, _)| Value::String(k.clone())).collect()))
+            }
+            (Value::Shared(shared), "snapshot") => Ok(Value::Map(shared.lock()?.clone())),
 
-        if is_oauth {
-            req = req.header("Authorization", format!("Bearer {}", api_key))
-                .header("anthropic-beta", "claude-code-20250219,oauth-2025-04-20");
-        } else {
-            req = req.header("x-api-key", &api_key);
-        }
+            // ── List methods ──
+            (Value::List(items), "contains") => {
+                if args.is_empty() { bail!(".contains() requires an argument"); }
+                let needle = &args[0];
+                let found = items.iter().any(|item| value_eq(item, needle));
+                Ok(Value::Bool(found))
+            }
+            (Value::List(items), "join") => {
+                let sep = if args.is_empty() { "".to_string() } else { args[0].to_string() };
+                let joined: Vec<std::string::String> = items.iter().map(|v| v.to_string()).collect();
+                self.limits.check_string(joined.iter().map(|s| s.len()).sum::<usize>() + sep.len() * joined.len().saturating_sub(1))?;
+                Ok(Value::String(joined.join(&sep)))
+            }
+            (Value::List(items), "reversed") => {
+                let mut rev = items.clone();
+                rev.reverse();
+                Ok(Value::List(rev))
+            }
+            (Value::List(items), "map" | "filter" | "sort_by") => {
+                let [function] = args.as_slice() else { bail!(".{}() takes one function, e.g. .{}(|x| x.name)", method, method) };
+                let mut results = Vec::with_capacity(items.len());
+                for item in items {
+                    results.push(self.call_function(method, function, vec![item.clone()])?);
+                }
+                match method {
+                    "map" => Ok(Value::List(results)),
+                    "filter" => Ok(Value::List(items.iter().zip(&results)
+                        .filter(|(_, keep)| keep.is_truthy())
+                        .map(|(item, _)| item.clone())
+                        .collect())),
+                    _ => {
+                        // Stable, so items with equal keys keep their order
+                        let mut keyed: Vec<(Value, Value)> = results.into_iter().zip(items.iter().cloned()).collect();
+                        let mut error = None;
+                        keyed.sort_by(|(a, _), (b, _)| compare_keys(a, b).unwrap_or_else(|e| {
+                            error.get_or_insert(e);
+                            std::cmp::Ordering::Equal
+                        }));
+                        if let Some(e) = error {
+                            return Err(e);
+                        }
+                        Ok(Value::List(keyed.into_iter().map(|(_, item)| item).collect()))
+                    }
+                }
+            }
+            (Value::List(items), "reduce") => {
+                // reduce(f, initial): f(acc, item) for each item; without an
+                // initial value the first item starts the accumulator
+                let (function, mut acc, rest) = match args.as_slice() {
+                    [function] => match items.split_first() {
+                        Some((first, rest)) => (function, first.clone(), rest),
+                        None => bail!(".reduce() of an empty list needs an initial value: .reduce(f, initial)"),
+                    },
+                    [function, initial] => (function, initial.clone(), items.as_slice()),
+                    _ => bail!(".reduce() takes a function and an optional initial value, e.g. .reduce(|acc, x| acc + x, 0)"),
+                };
+                for item in rest {
+                    acc = self.call_function(method, function, vec![acc, item.clone()])?;
+                }
+                Ok(acc)
+            }
+            (Value::Handle(Handle::Mcp(client)), "call") => {
+                let client = client.clone();
+                let tool = self.expect_string_arg(method, &args, 0)?;
+                let tool_args = match args.get(1) {
+                    None | Some(Value::None) => Entries::new(),
+                    Some(Value::Map(entries)) => entries.clone(),
+                    Some(other) => bail!(".call(tool, args): args must be a Map, got {}", type_name(other)),
+                };
+                if !client.tools.iter().any(|t| t.name == tool) {
+                    let names: Vec<&str> = client.tools.iter().map(|t| t.name.as_str()).collect();
+                    bail!("{} has no tool '{}' (tools: {})", obj, tool, names.join(", "));
+                }
+                self.call_mcp_tool(&client, &tool, tool_args)
+            }
+            (Value::List(items), "fork") => {
+                if !args.is_empty() {
+                    bail!(".fork() takes no arguments");
+                }
+                if !is_conversation(items) {
+                    bail!(".fork() works on conversations: Lists of message Maps with a \"role\"");
+                }
+                Ok(obj.clone())
+            }
+            // Reached only when the list isn't in a variable: see mutate_list
+            (Value::List(_), "rollback") => {
+                bail!(".rollback() changes a conversation held in a variable, e.g. conv.rollback(1)")
+            }
+            (Value::List(_), "push" | "pop" | "insert") => {
+                bail!(".{}() changes a list held in a variable, e.g. items.{}(...); \
+                    for a new list use concatenation: items + [item]", method, method)
+            }
 
-        let resp = req.json(&body)
-            .send()
-            .map_err(|e| anyhow::anyhow!("Anthropic error: {}", e))?;
+            // ── Map methods ──
+            (Value::Map(entries), "keys") => {
+                let keys: Vec<Value> = entries.iter().map(|(k, _)| Value::String(k.clone())).collect();
+                Ok(Value::List(keys))
+            }
+            (Value::Map(entries), "values") => {
+                let vals: Vec<Value> = entries.iter().map(|(_, v)| v.clone()).collect();
+                Ok(Value::List(vals))
+            }
+            (Value::Map(entries), "contains") => {
+                let key = self.expect_string_arg(method, &args, 0)?;
+                Ok(Value::Bool(entries.contains_key(&key)))
+            }
 
-        let json: serde_json::Value = resp.json()
-            .map_err(|e| anyhow::anyhow!("Anthropic JSON error: {}", e))?;
+            _ => bail!("'{}' has no method '{}' (type: {})", obj, method, type_name(&obj)),
+        }
+    }
 
-        if let Some(err) = json.get("error") {
-            bail!("Anthropic API error: {}", err);
+    fn expect_string_arg(&self, method: &str, args: &[Value], idx: usize) -> Result<std::string::String> {
+        match args.get(idx) {
+            Some(Value::String(s)) => Ok(s.clone()),
+            Some(other) => bail!(".{}() argument {} must be a String, got {}", method, idx + 1, type_name(other)),
+            None => bail!(".{}() requires at least {} argument(s)", method, idx + 1),
         }
+    }
 
-        // Parse response
-        let content_blocks = json["content"].as_array()
-            .ok_or_else(|| anyhow::anyhow!("Anthropic: no content in response"))?;
+    fn type_to_schema(&self, td: &TypeDef) -> std::string::String {
+        match td {
+            TypeDef::Struct { fields, .. } => {
+                let field_strs: Vec<std::string::String> = fields.iter().map(|f| {
+                    let ty_str = self.type_expr_to_json_type(&f.ty);
+                    if f.optional {
+                        format!("  \"{}\"?: {}", f.name, ty_str)
+                    } else {
+                        format!("  \"{}\": {}", f.name, ty_str)
+                    }
+                }).collect();
+                format!("{{\n{}\n}}", field_strs.join(",\n"))
+            }
+            TypeDef::Enum { variants, .. } => {
+                let quoted: Vec<std::string::String> = variants.iter().map(|v| format!("\"{}\"", v)).collect();
+                format!("one of: {}", quoted.join(", "))
+            }
+        }
+    }
 
-        let mut text_content = std::string::String::new();
-        let mut tool_calls = Vec::new();
+    /// The JSON Schema for `ty`, with named types inlined. `outer` holds
+    /// the named types being expanded; a type containing itself refers back
+    /// to the root with `{"$ref": "#"}`.
+    fn json_schema(&self, ty: &TypeExpr, outer: &mut Vec<std::string::String>) -> Result<serde_json::Value> {
+        Ok(match ty {
+            TypeExpr::Named(n) => match n.as_str() {
+                "String" | "Text" => serde_json::json!({"type": "string"}),
+                "Int" => serde_json::json!({"type": "integer"}),
+                "Float" => serde_json::json!({"type": "number"}),
+                "Bool" => serde_json::json!({"type": "boolean"}),
+                "None" => serde_json::json!({"type": "null"}),
+                "List" => serde_json::json!({"type": "array"}),
+                "Map" => serde_json::json!({"type": "object"}),
+                other => {
+                    let Some(td) = self.types.get(other) else {
+                        bail!("schema(): unknown type '{}' — define it with: type {}: ...", other, other)
+                    };
+                    if let Some(pos) = outer.iter().position(|t| t == other) {
+                        if pos == 0 {
+                            return Ok(serde_json::json!({"$ref": "#"}));
+                        }
+                        bail!("schema(): type '{}' contains itself below the top level, which an inline schema can't express", other);
+                    }
+                    outer.push(other.to_string());
+                    let schema = match td {
+                        TypeDef::Struct { name, fields } => {
+                            let mut properties = serde_json::Map::new();
+                            for f in fields {
+                                properties.insert(f.name.clone(), self.json_schema(&f.ty, outer)?);
+                            }
+                            let required: Vec<&str> = fields.iter().filter(|f| !f.optional).map(|f| f.name.as_str()).collect();
+                            serde_json::json!({"title": name, "type": "object", "properties": properties, "required": required})
+                        }
+                        TypeDef::Enum { name, variants } => serde_json::json!({"title": name, "type": "string", "enum": variants}),
+                    };
+                    outer.pop();
+                    schema
+                }
+            },
+            TypeExpr::Generic(name, args) => match (name.as_str(), args.as_slice()) {
+                ("List", [item]) => serde_json::json!({"type": "array", "items": self.json_schema(item, outer)?}),
+                ("Map", [_, value]) => serde_json::json!({"type": "object", "additionalProperties": self.json_schema(value, outer)?}),
+                _ => bail!("schema(): can't describe {}[...]; use List[T] or Map[K, V]", name),
+            },
+            TypeExpr::Struct(fields) => {
+                let mut properties = serde_json::Map::new();
+                for (field, ty) in fields {
+                    properties.insert(field.clone(), self.json_schema(ty, outer)?);
+                }
+                let required: Vec<&str> = fields.iter().map(|(f, _)| f.as_str()).collect();
+                serde_json::json!({"type": "object", "properties": properties, "required": required})
+            }
+        })
+    }
 
-        for block in content_blocks {
-            match block["type"].as_str() {
-                Some("text") => {
-                    text_content.push_str(block["text"].as_str().unwrap_or(""));
+    fn type_expr_to_json_type(&self, ty: &TypeExpr) -> std::string::String {
+        match ty {
+            TypeExpr::Named(n) => match n.as_str() {
+                "String" | "Text" => "<string>".to_string(),
+                "Int" => "<integer>".to_string(),
+                "Float" => "<number>".to_string(),
+                "Bool" => "<boolean>".to_string(),
+                other => {
+                    if let Some(td) = self.types.get(other) {
+                        self.type_to_schema(td)
+                    } else {
+                        format!("<{}>", other)
+                    }
+                }
+            }
+            TypeExpr::Generic(name, args) => match name.as_str() {
+                "List" => {
+                    let inner = args.first().map(|a| self.type_expr_to_json_type(a)).unwrap_or("<any>".to_string());
+                    format!("[{}, ...]", inner)
                 }
-                Some("tool_use") => {
-                    let name = block["name"].as_str().unwrap_or("").to_string();
-                    let arguments = self.json_to_value(block["input"].clone());
-                    tool_calls.push(Value::Map(vec![
-                        ("name".to_string(), Value::String(name)),
-                        ("arguments".to_string(), arguments),
-                    ]));
+                "Map" => {
+                    if args.len() >= 2 {
+                        let key = self.type_expr_to_json_type(&args[0]);
+                        let val = self.type_expr_to_json_type(&args[1]);
+                        format!("{{{}: {}, ...}}", key, val)
+                    } else {
+                        "<object>".to_string()
+                    }
                 }
-                _ => {}
+                _ => format!("<{}>", name),
             }
-        }
-
-        if !tool_calls.is_empty() {
-            return Ok(Value::Map(vec![
-                ("content".to_string(), Value::String(text_content)),
-                ("tool_calls".to_string(), Value::List(tool_calls)),
-                ("has_tool_calls".to_string(), Value::Bool(true)),
-            ]));
-        }
-
-        if tools.is_some() {
-            Ok(Value::Map(vec![
-                ("content".to_string(), Value::String(text_content)),
-                ("has_tool_calls".to_string(), Value::Bool(false)),
-            ]))
-        } else {
-            Ok(Value::String(text_content))
+            TypeExpr::Struct(_) => "<object>".to_string(),
         }
     }
 
-    fn call_ollama(&self, model: &str, system: &str, prompt: &str, tools: Option<Vec<serde_json::Value>>, images: &[std::string::String]) -> Result<Value> {
-        log::info!("Calling Ollama: model={}, system={:?}, tools={}, images={}", model, system, tools.as_ref().map(|t| t.len()).unwrap_or(0), images.len());
-        let call_start = std::time::Instant::now();
-
-        let mut messages = Vec::new();
-        if !system.is_empty() {
-            messages.push(serde_json::json!({"role": "system", "content": system}));
-        }
+    /// `validate_type`, failing with a TypeError that `catch TypeError` sees.
+    fn check_type(&self, val: &Value, td: &crate::ast::TypeDef) -> Result<()> {
+        self.validate_type(val, td).map_err(|e| CognosError::type_error(e.to_string()).into())
+    }
 
-        // Build user message with optional images (base64-encoded)
-        let mut user_msg = serde_json::json!({"role": "user", "content": prompt});
-        if !images.is_empty() {
-            let mut b64_images = Vec::new();
-            for path in images {
-                let bytes = std::fs::read(path)
-                    .map_err(|e| anyhow::anyhow!("cannot read image '{}': {}", path, e))?;
-                use base64::Engine;
-                b64_images.push(serde_json::Value::String(
-                    base64::engine::general_purpose::STANDARD.encode(&bytes)
-                ));
-                log::info!("image: {} ({} bytes)", path, bytes.len());
+    fn validate_type(&self, val: &Value, td: &crate::ast::TypeDef) -> Result<()> {
+        match td {
+            TypeDef::Enum { name, variants } => {
+                match val {
+                    Value::String(s) => {
+                        if !variants.contains(s) {
+                            bail!("type {} validation failed: '{}' is not one of [{}]",
+                                name, s, variants.join(", "));
+                        }
+                        Ok(())
+                    }
+                    other => bail!("expected {} (String enum), got {}", name, type_name(other)),
+                }
             }
-            user_msg["images"] = serde_json::Value::Array(b64_images);
-        }
-        messages.push(user_msg);
-
-        let mut body = serde_json::json!({
-            "model": model,
-            "messages": messages,
-            "stream": false
-        });
+            TypeDef::Struct { name, fields } => {
+                let map = match val {
+                    Value::Map(entries) => entries,
+                    other => bail!("expected {} (Map), got {}", name, type_name(other)),
+                };
 
-        if let Some(ref tool_defs) = tools {
-            body["tools"] = serde_json::json!(tool_defs);
-        }
+                let mut errors = Vec::new();
 
-        let client = reqwest::blocking::Client::new();
-        let resp = client.post("http://localhost:11434/api/chat")
-            .json(&body)
-            .send()
-            .map_err(|e| anyhow::anyhow!("Ollama error: {}", e))?;
-
-        let json: serde_json::Value = resp.json()
-            .map_err(|e| anyhow::anyhow!("Ollama JSON error: {}", e))?;
-
-        let message = &json["message"];
-        let content = message["content"].as_str().unwrap_or("").to_string();
-
-        // Check for tool calls
-        if let Some(tool_calls) = message.get("tool_calls") {
-            if let Some(calls) = tool_calls.as_array() {
-                if !calls.is_empty() {
-                    let tc: Vec<Value> = calls.iter().map(|c| {
-                        let func = &c["function"];
-                        let name = func["name"].as_str().unwrap_or("").to_string();
-                        let arguments = self.json_to_value(func["arguments"].clone());
-                        Value::Map(vec![
-                            ("name".to_string(), Value::String(name)),
-                            ("arguments".to_string(), arguments),
-                        ])
-                    }).collect();
+                for field in fields {
+                    match map.get(&field.name) {
+                        None => {
+                            if !field.optional {
+                                errors.push(format!("missing field '{}'", field.name));
+                            }
+                        }
+                        Some(val) => {
+                            if let Err(e) = self.validate_field_value(val, &field.ty) {
+                                errors.push(format!("field '{}': {}", field.name, e));
+                            }
+                        }
+                    }
+                }
 
-                    let latency = call_start.elapsed().as_millis() as u64;
-                    self.trace_llm(model, "ollama", latency, prompt, system, &content, true);
-                    return Ok(Value::Map(vec![
-                        ("content".to_string(), Value::String(content)),
-                        ("tool_calls".to_string(), Value::List(tc)),
-                        ("has_tool_calls".to_string(), Value::Bool(true)),
-                    ]));
+                if errors.is_empty() {
+                    Ok(())
+                } else {
+                    bail!("type {} validation failed:\n  {}\nLLM response: {}", name, errors.join("\n  "), val)
                 }
             }
         }
-
-        let latency = call_start.elapsed().as_millis() as u64;
-        self.trace_llm(model, "ollama", latency, prompt, system, &content, false);
-        // No tool calls — return simple string or structured map
-        if tools.is_some() {
-            Ok(Value::Map(vec![
-                ("content".to_string(), Value::String(content)),
-                ("has_tool_calls".to_string(), Value::Bool(false)),
-            ]))
-        } else {
-            Ok(Value::String(content))
-        }
     }
 
-    fn call_openai_multi_turn(&mut self, model: &str, system: &str, prompt: &str, tools: Option<Vec<serde_json::Value>>, conversation: Vec<Value>, tool_results: Option<Vec<Value>>) -> Result<Value> {
-        let call_start = std::time::Instant::now();
-
-        // Determine endpoint and env key based on model
-        let (endpoint, env_key) = if model.starts_with("deepseek") {
-            ("https://api.deepseek.com/v1/chat/completions", "DEEPSEEK_API_KEY")
-        } else if model.contains("minimax") || model.starts_with("MiniMax") {
-            ("https://api.minimax.chat/v1/text/chatcompletion_v2", "MINIMAX_API_KEY")
-        } else {
-            ("https://api.openai.com/v1/chat/completions", "OPENAI_API_KEY")
-        };
-
-        let api_key = std::env::var(env_key)
-            .or_else(|_| {
-                let env_path = std::path::Path::new(".env");
-                if env_path.exists() {
-                    std::fs::read_to_string(env_path).ok().and_then(|content| {
-                        content.lines().find_map(|line| {
-                            let line = line.trim();
-                            line.strip_prefix(&format!("{}=", env_key))
-                                .map(|val| val.trim_matches('"').trim_matches('\'').to_string())
-                        })
-                    }).ok_or_else(|| std::env::VarError::NotPresent)
-                } else { Err(std::env::VarError::NotPresent) }
-            })
-            .map_err(|_| anyhow::anyhow!("{} not set. Set it in env or .env file.", env_key))?;
-
-        log::info!("Calling OpenAI-compat API (multi-turn): model={}, endpoint={}, conversation_msgs={}, tools={}",
-                   model, endpoint, conversation.len(), tools.as_ref().map(|t| t.len()).unwrap_or(0));
-
-        // Build messages array
-        let mut messages: Vec<serde_json::Value> = Vec::new();
-
-        // System message
-        if !system.is_empty() {
-            messages.push(serde_json::json!({"role": "system", "content": system}));
-        }
-
-        // Convert conversation history to OpenAI format
-        for msg in &conversation {
-            if let Value::Map(entries) = msg {
-                let role = entries.iter().find(|(k, _)| k == "role")
-                    .map(|(_, v)| v.to_string()).unwrap_or_default();
-                let content = entries.iter().find(|(k, _)| k == "content");
-                let has_tool_calls = entries.iter().find(|(k, _)| k == "has_tool_calls")
-                    .map(|(_, v)| matches!(v, Value::Bool(true))).unwrap_or(false);
-
-                if role == "assistant" && has_tool_calls {
-                    // Assistant message with tool calls — reconstruct OpenAI format
-                    let content_str = content.map(|(_, v)| v.to_string()).unwrap_or_default();
-                    let tool_calls_val = entries.iter().find(|(k, _)| k == "tool_calls");
-                    let mut msg_json = serde_json::json!({"role": "assistant"});
-                    if !content_str.is_empty() {
-                        msg_json["content"] = serde_json::json!(content_str);
-                    } else {
-                        msg_json["content"] = serde_json::Value::Null;
+    fn validate_field_value(&self, val: &Value, ty: &crate::ast::TypeExpr) -> Result<()> {
+        match ty {
+            crate::ast::TypeExpr::Named(name) => {
+                let ok = match name.as_str() {
+                    "Int" => matches!(val, Value::Int(_)),
+                    "Float" => matches!(val, Value::Float(_) | Value::Int(_)),
+                    "String" | "Text" => matches!(val, Value::String(_)),
+                    "Bool" => matches!(val, Value::Bool(_)),
+                    "List" => matches!(val, Value::List(_)),
+                    "Map" => matches!(val, Value::Map(_)),
+                    other => {
+                        // Check for user-defined type (struct or enum)
+                        if let Some(td) = self.types.get(other) {
+                            return self.validate_type(val, td);
+                        }
+                        true
                     }
-                    if let Some((_, Value::List(calls))) = tool_calls_val {
-                        let tc: Vec<serde_json::Value> = calls.iter().map(|call| {
-                            if let Value::Map(ce) = call {
-                                let id = ce.iter().find(|(k, _)| k == "id")
-                                    .map(|(_, v)| v.to_string()).unwrap_or_default();
-                                let name = ce.iter().find(|(k, _)| k == "name")
-                                    .map(|(_, v)| v.to_string()).unwrap_or_default();
-                                let args = ce.iter().find(|(k, _)| k == "arguments")
-                                    .map(|(_, v)| self.value_to_json(v))
-                                    .unwrap_or(serde_json::json!({}));
-                                serde_json::json!({
-                                    "id": id,
-                                    "type": "function",
-                                    "function": {
-                                        "name": name,
-                                        "arguments": serde_json::to_string(&args).unwrap_or_default()
-                                    }
-                                })
-                            } else {
-                                serde_json::json!({})
+                };
+                if !ok {
+                    bail!("expected {}, got {} ({})", name, type_name(val), val);
+                }
+                Ok(())
+            }
+            crate::ast::TypeExpr::Generic(name, args) => {
+                match name.as_str() {
+                    "List" => {
+                        let items = match val {
+                            Value::List(items) => items,
+                            other => bail!("expected List, got {}", type_name(other)),
+                        };
+                        // Validate inner type if specified
+                        if let Some(inner_ty) = args.first() {
+                            for (i, item) in items.iter().enumerate() {
+                                if let Err(e) = self.validate_field_value(item, inner_ty) {
+                                    bail!("element [{}]: {}", i, e);
+                                }
                             }
-                        }).collect();
-                        msg_json["tool_calls"] = serde_json::json!(tc);
+                        }
+                        Ok(())
                     }
-                    messages.push(msg_json);
-                } else if role == "tool" {
-                    // Tool result message
-                    let tool_call_id = entries.iter().find(|(k, _)| k == "tool_call_id")
-                        .map(|(_, v)| v.to_string()).unwrap_or_default();
-                    let content_str = content.map(|(_, v)| v.to_string()).unwrap_or_default();
-                    messages.push(serde_json::json!({
-                        "role": "tool",
-                        "tool_call_id": tool_call_id,
-                        "content": content_str
-                    }));
-                } else {
-                    // Regular user/assistant message
-                    let content_str = content.map(|(_, v)| v.to_string()).unwrap_or_default();
-                    messages.push(serde_json::json!({
-                        "role": role,
-                        "content": content_str
-                    }));
-                }
-            }
-        }
-
-        // Add new user message or tool results
-        if let Some(ref tr) = tool_results {
-            // Tool results are sent as individual "tool" role messages in OpenAI format
-            for result in tr {
-                if let Value::Map(entries) = result {
-                    let tool_call_id = entries.iter().find(|(k, _)| k == "tool_call_id" || k == "tool_use_id")
-                        .map(|(_, v)| v.to_string()).unwrap_or_default();
-                    let content = entries.iter().find(|(k, _)| k == "content")
-                        .map(|(_, v)| v.to_string()).unwrap_or_default();
-                    messages.push(serde_json::json!({
-                        "role": "tool",
-                        "tool_call_id": tool_call_id,
-                        "content": content
-                    }));
-                }
-            }
-            // If there's also a prompt, add it as a user message after tool results
-            if !prompt.is_empty() {
-                messages.push(serde_json::json!({"role": "user", "content": prompt}));
-            }
-        } else if !prompt.is_empty() {
-            messages.push(serde_json::json!({"role": "user", "content": prompt}));
-        }
-
-        // Truncate old tool message content to manage tokens
-        let msg_count = messages.len();
-        if msg_count > 8 {
-            let keep_full_from = msg_count.saturating_sub(6);
-            for (i, msg) in messages.iter_mut().enumerate() {
-                if i >= keep_full_from { break; }
-                if msg.get("role").and_then(|r| r.as_str()) == Some("tool") {
-                    if let Some(c) = msg.get_mut("content") {
-                        let text = c.as_str().unwrap_or("").to_string();
-                        if text.len() > 200 {
-                            *c = serde_json::json!(format!("{}...(truncated)", &text[..200]));
+                    "Map" => {
+                        let entries = match val {
+                            Value::Map(entries) => entries,
+                            other => bail!("expected Map, got {}", type_name(other)),
+                        };
+                        if args.len() >= 2 {
+                            let val_ty = &args[1];
+                            for (k, v) in entries {
+                                if let Err(e) = self.validate_field_value(v, val_ty) {
+                                    bail!("key '{}': {}", k, e);
+                                }
+                            }
                         }
+                        Ok(())
                     }
+                    _ => Ok(()),
                 }
             }
+            crate::ast::TypeExpr::Struct(_) => Ok(()),
         }
+    }
 
-        // Build request body
-        let mut body = serde_json::json!({
-            "model": model,
-            "messages": messages
-        });
-
-        if let Some(ref tool_defs) = tools {
-            body["tools"] = serde_json::json!(tool_defs);
-            body["tool_choice"] = serde_json::json!("auto");
-        }
-
-        let client = reqwest::blocking::Client::builder()
-            .timeout(std::time::Duration::from_secs(120))
-            .build()?;
-
-        let resp = client.post(endpoint)
-            .header("Authorization", format!("Bearer {}", api_key))
-            .header("Content-Type", "application/json")
-            .json(&body)
-            .send()
-            .map_err(|e| anyhow::anyhow!("API error: {}", e))?;
-
-        let status = resp.status();
-        let resp_text = resp.text().map_err(|e| anyhow::anyhow!("Failed to read API response: {}", e))?;
-
-        if !status.is_success() {
-            bail!("OpenAI-compat API error ({}): {}", status, &resp_text[..resp_text.len().min(500)]);
+    /// Parse `content` as `format=` asks. A reply that doesn't parse or
+    /// validate is shown back to the model with the error, up to `repair`
+    /// times; `content` ends up holding the last reply.
+    #[allow(clippy::too_many_arguments)]
+    fn parse_or_repair(&mut self, content: &mut std::string::String, type_name: &str, repair: usize, model: &str, system: &str, prompt: &str, ollama: &OllamaOptions, options: CallOptions) -> Result<Value> {
+        let mut attempt = 0;
+        loop {
+            let err = match self.parse_format(&Value::String(content.clone()), type_name) {
+                Ok(parsed) => return Ok(parsed),
+                Err(e) if attempt < repair => e,
+                Err(e) => return Err(e),
+            };
+            attempt += 1;
+            log::info!("think(): reply failed format={} ({}), re-asking ({}/{})", type_name, err, attempt, repair);
+            self.trace(TraceEvent::Retry {
+                call: "repair".to_string(), target: model.to_string(),
+                attempt: attempt as u32, retries: repair as u32,
+                delay_ms: 0, error: err.to_string(),
+            });
+            let request = format!("{}\n\n---\nYour previous reply:\n\n{}\n\n---\nIt was rejected: {}\nReply again with only the corrected JSON.", prompt, content, err);
+            let reply = self.request_llm(model, system, &[], &request, None, &[], ollama, options.clone())?;
+            warn_incomplete(model, reply.finish_reason.as_deref());
+            *content = reply.content;
         }
+    }
 
-        let json: serde_json::Value = serde_json::from_str(&resp_text)
-            .map_err(|e| anyhow::anyhow!("JSON parse error: {}", e))?;
-
-        if let Some(err) = json.get("error") {
-            bail!("API error: {}", err);
+    /// A reply parsed as `format=` asks: JSON, checked against the type if it names one.
+    fn parse_format(&self, content: &Value, type_name: &str) -> Result<Value> {
+        let parsed = self.parse_json_response(content)?;
+        if type_name != "json" {
+            if let Some(td) = self.types.get(type_name).cloned() {
+                self.check_type(&parsed, &td)?;
+            }
         }
+        Ok(parsed)
+    }
 
-        let choice = &json["choices"][0]["message"];
-        let raw_content = choice["content"].as_str().unwrap_or("").to_string();
-        // Strip <think>...</think> tags (MiniMax reasoning tokens)
-        let content = if let Some(end) = raw_content.find("</think>") {
-            raw_content[end + 8..].trim().to_string()
+    fn parse_json_response(&self, val: &Value) -> Result<Value> {
+        let s = val.to_string();
+        // Strip markdown code fences if present
+        let json_str = s.trim();
+        let json_str = if json_str.starts_with("```") {
+            let inner = json_str.trim_start_matches("```json").trim_start_matches("```");
+            inner.trim_end_matches("```").trim()
         } else {
-            raw_content
+            json_str
         };
 
-        let latency = call_start.elapsed().as_millis() as u64;
-
-        // Check for tool calls
-        let mut tool_calls: Vec<Value> = Vec::new();
-        if let Some(tool_calls_arr) = choice.get("tool_calls").and_then(|v| v.as_array()) {
-            for c in tool_calls_arr {
-                let id = c["id"].as_str().unwrap_or("").to_string();
-                let func = &c["function"];
-                let name = func["name"].as_str().unwrap_or("").to_string();
-                let args_str = func["arguments"].as_str().unwrap_or("{}");
-                let arguments = serde_json::from_str::<serde_json::Value>(args_str)
-                    .map(|v| self.json_to_value(v))
-                    .unwrap_or(Value::Map(vec![]));
-                tool_calls.push(Value::Map(vec![
-                    ("id".to_string(), Value::String(id)),
-                    ("name".to_string(), Value::String(name)),
-                    ("arguments".to_string(), arguments),
-                ]));
-            }
+        // Try parsing as-is first
+        if let Ok(parsed) = serde_json::from_str::<serde_json::Value>(json_str) {
+            return Ok(self.json_to_value(parsed));
         }
 
-        let has_tool_calls = !tool_calls.is_empty();
-
-        // Build updated conversation
-        let mut updated_conversation = conversation.clone();
-
-        // Add user message(s) that were sent
-        if let Some(ref tr) = tool_results {
-            for result in tr {
-                if let Value::Map(entries) = result {
-                    let tool_call_id = entries.iter().find(|(k, _)| k == "tool_call_id" || k == "tool_use_id")
-                        .map(|(_, v)| v.to_string()).unwrap_or_default();
-                    let content_val = entries.iter().find(|(k, _)| k == "content")
-                        .map(|(_, v)| v.to_string()).unwrap_or_default();
-                    updated_conversation.push(Value::Map(vec![
-                        ("role".to_string(), Value::String("tool".to_string())),
-                        ("tool_call_id".to_string(), Value::String(tool_call_id)),
-                        ("content".to_string(), Value::String(content_val)),
-                    ]));
-                }
+        // LLM may have added text after the JSON — extract the first JSON object or array
+        let extracted = Self::extract_json(json_str);
+        if let Some(ref extracted_str) = extracted {
+            if let Ok(parsed) = serde_json::from_str::<serde_json::Value>(extracted_str) {
+                return Ok(self.json_to_value(parsed));
             }
         }
-        if !prompt.is_empty() && tool_results.is_none() {
-            updated_conversation.push(Value::Map(vec![
-                ("role".to_string(), Value::String("user".to_string())),
-                ("content".to_string(), Value::String(prompt.to_string())),
-            ]));
-        }
-
-        // Add assistant response
-        let mut assistant_msg = vec![
-            ("role".to_string(), Value::String("assistant".to_string())),
-            ("content".to_string(), Value::String(content.clone())),
-            ("has_tool_calls".to_string(), Value::Bool(has_tool_calls)),
-        ];
-        if has_tool_calls {
-            assistant_msg.push(("tool_calls".to_string(), Value::List(tool_calls.clone())));
-        }
-        updated_conversation.push(Value::Map(assistant_msg));
-
-        self.trace_llm(model, "openai-multi-turn", latency, prompt, system, &content, has_tool_calls);
 
-        Ok(Value::Map(vec![
-            ("content".to_string(), Value::String(content)),
-            ("conversation".to_string(), Value::List(updated_conversation)),
-            ("has_tool_calls".to_string(), Value::Bool(has_tool_calls)),
-            ("tool_calls".to_string(), Value::List(tool_calls)),
-        ]))
+        Err(CognosError::parse(0, format!("LLM returned invalid JSON. Could not extract valid JSON from response.\nResponse was: {}", json_str)).into())
     }
 
-    fn call_anthropic_api_multi_turn(&mut self, model: &str, system: &str, prompt: &str, tools: Option<Vec<serde_json::Value>>, conversation: Vec<Value>, tool_results: Option<Vec<Value>>) -> Result<Value> {
-        let call_start = std::time::Instant::now();
-
-        // Read token: ANTHROPIC_API_KEY env var first (explicit), then OpenClaw auth-profiles
-        let mut token: Option<String> = None;
-        
-        if let Ok(key) = std::env::var("ANTHROPIC_API_KEY") {
-            if !key.is_empty() {
-                token = Some(key);
-            }
-        }
-        
-        if token.is_none() {
-            let home = std::env::var("HOME").unwrap_or_default();
-            let openclaw_agents = std::path::PathBuf::from(&home).join(".openclaw/agents");
-            if let Ok(entries) = std::fs::read_dir(&openclaw_agents) {
-                for entry in entries.flatten() {
-                    let auth_path = entry.path().join("agent/auth-profiles.json");
-                    if let Ok(data) = std::fs::read_to_string(&auth_path) {
-                        if let Ok(parsed) = serde_json::from_str::<serde_json::Value>(&data) {
-                            if let Some(t) = parsed["profiles"]["anthropic:default"]["token"].as_str() {
-                                if !t.is_empty() {
-                                    token = Some(t.to_string());
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                }
+    fn extract_json(s: &str) -> Option<String> {
+        // Find the first { or [ and match its closing bracket
+        let start_char = s.chars().position(|c| c == '{' || c == '[')?;
+        let open = s.as_bytes()[start_char] as char;
+        let close = if open == '{' { '}' } else { ']' };
+        let mut depth = 0;
+        let mut in_string = false;
+        let mut escape = false;
+        for (i, ch) in s[start_char..].char_indices() {
+            if escape {
+                escape = false;
+                continue;
             }
-        }
-        let token = token.ok_or_else(|| anyhow::anyhow!(
-            "No Anthropic token found. Run 'openclaw configure' or set ANTHROPIC_API_KEY."
-        ))?;
-
-        log::info!("Calling Anthropic API (multi-turn): model={}, conversation_msgs={}, tools={}", 
-                   model, conversation.len(), tools.as_ref().map(|t| t.len()).unwrap_or(0));
-
-        // Build messages array from conversation
-        let mut messages: Vec<serde_json::Value> = Vec::new();
-        
-        // Convert conversation to API messages
-        for msg in &conversation {
-            if let Value::Map(entries) = msg {
-                let role = entries.iter().find(|(k, _)| k == "role")
-                    .map(|(_, v)| v.to_string()).unwrap_or_default();
-                let content = entries.iter().find(|(k, _)| k == "content");
-                
-                if let Some((_, content_val)) = content {
-                    match content_val {
-                        Value::String(text) => {
-                            messages.push(serde_json::json!({
-                                "role": role,
-                                "content": text
-                            }));
-                        }
-                        Value::List(blocks) => {
-                            // Content blocks (for tool results)
-                            let content_blocks: Vec<serde_json::Value> = blocks.iter().map(|block| {
-                                if let Value::Map(block_entries) = block {
-                                    let block_type = block_entries.iter().find(|(k, _)| k == "type")
-                                        .map(|(_, v)| v.to_string()).unwrap_or_default();
-                                    match block_type.as_str() {
-                                        "tool_result" => {
-                                            let tool_use_id = block_entries.iter().find(|(k, _)| k == "tool_use_id")
-                                                .map(|(_, v)| v.to_string()).unwrap_or_default();
-                                            let content = block_entries.iter().find(|(k, _)| k == "content")
-                                                .map(|(_, v)| v.to_string()).unwrap_or_default();
-                                            serde_json::json!({
-                                                "type": "tool_result",
-                                                "tool_use_id": tool_use_id,
-                                                "content": content
-                                            })
-                                        }
-                                        "tool_use" => {
-                                            let id = block_entries.iter().find(|(k, _)| k == "id")
-                                                .map(|(_, v)| v.to_string()).unwrap_or_default();
-                                            let name = block_entries.iter().find(|(k, _)| k == "name")
-                                                .map(|(_, v)| v.to_string()).unwrap_or_default();
-                                            let input = block_entries.iter().find(|(k, _)| k == "input")
-                                                .map(|(_, v)| self.value_to_json(v))
-                                                .unwrap_or(serde_json::json!({}));
-                                            serde_json::json!({
-                                                "type": "tool_use",
-                                                "id": id,
-                                                "name": name,
-                                                "input": input
-                                            })
-                                        }
-                                        "text" => {
-                                            let text = block_entries.iter().find(|(k, _)| k == "text")
-                                                .map(|(_, v)| v.to_string()).unwrap_or_default();
-                                            serde_json::json!({
-                                                "type": "text",
-                                                "text": text
-                                            })
-                                        }
-                                        _ => {
-                                            let text = block_entries.iter().find(|(k, _)| k == "text" || k == "content")
-                                                .map(|(_, v)| v.to_string()).unwrap_or_default();
-                                            serde_json::json!({
-                                                "type": "text",
-                                                "text": text
-                                            })
-                                        }
-                                    }
-                                } else {
-                                    // Fallback: convert value to text block
-                                    serde_json::json!({
-                                        "type": "text", 
-                                        "text": block.to_string()
-                                    })
-                                }
-                            }).collect();
-                            messages.push(serde_json::json!({
-                                "role": role,
-                                "content": content_blocks
-                            }));
-                        }
-                        _ => {
-                            // Fallback: convert to string
-                            messages.push(serde_json::json!({
-                                "role": role,
-                                "content": content_val.to_string()
-                            }));
-                        }
-                    }
-                }
+            if ch == '\\' && in_string {
+                escape = true;
+                continue;
             }
-        }
-
-        // Add new user message if prompt is provided
-        if !prompt.is_empty() {
-            if let Some(tr) = tool_results {
-                // Tool results message with content blocks
-                let tool_result_blocks: Vec<serde_json::Value> = tr.iter().map(|result| {
-                    if let Value::Map(entries) = result {
-                        let tool_use_id = entries.iter().find(|(k, _)| k == "tool_use_id")
-                            .map(|(_, v)| v.to_string()).unwrap_or_default();
-                        let content = entries.iter().find(|(k, _)| k == "content")
-                            .map(|(_, v)| v.to_string()).unwrap_or_default();
-                        serde_json::json!({
-                            "type": "tool_result",
-                            "tool_use_id": tool_use_id,
-                            "content": content
-                        })
-                    } else {
-                        serde_json::json!({
-                            "type": "tool_result",
-                            "tool_use_id": "unknown",
-                            "content": result.to_string()
-                        })
-                    }
-                }).collect();
-                
-                if !prompt.trim().is_empty() {
-                    // Prepend prompt text if present
-                    let mut content_blocks = vec![serde_json::json!({
-                        "type": "text",
-                        "text": prompt
-                    })];
-                    content_blocks.extend(tool_result_blocks);
-                    messages.push(serde_json::json!({
-                        "role": "user",
-                        "content": content_blocks
-                    }));
-                } else {
-                    // Tool results only
-                    messages.push(serde_json::json!({
-                        "role": "user",
-                        "content": tool_result_blocks
-                    }));
-                }
-            } else {
-                // Regular text message
-                messages.push(serde_json::json!({
-                    "role": "user",
-                    "content": prompt
-                }));
+            if ch == '"' {
+                in_string = !in_string;
+                continue;
             }
-        } else if let Some(tr) = tool_results {
-            // Tool results only, no prompt
-            let tool_result_blocks: Vec<serde_json::Value> = tr.iter().map(|result| {
-                if let Value::Map(entries) = result {
-                    let tool_use_id = entries.iter().find(|(k, _)| k == "tool_use_id")
-                        .map(|(_, v)| v.to_string()).unwrap_or_default();
-                    let content = entries.iter().find(|(k, _)| k == "content")
-                        .map(|(_, v)| v.to_string()).unwrap_or_default();
-                    serde_json::json!({
-                        "type": "tool_result",
-                        "tool_use_id": tool_use_id,
-                        "content": content
-                    })
-                } else {
-                    serde_json::json!({
-                        "type": "tool_result",
-                        "tool_use_id": "unknown", 
-                        "content": result.to_string()
-                    })
-                }
-            }).collect();
-            messages.push(serde_json::json!({
-                "role": "user",
-                "content": tool_result_blocks
-            }));
-        }
-
-        // Truncate old tool_result content to stay under context limits.
-        // Keep last 6 messages at full size, truncate tool_result content in older messages.
-        let msg_count = messages.len();
-        if msg_count > 8 {
-            let keep_full_from = msg_count.saturating_sub(6);
-            for (i, msg) in messages.iter_mut().enumerate() {
-                if i >= keep_full_from { break; }
-                if let Some(content) = msg.get_mut("content") {
-                    if let Some(blocks) = content.as_array_mut() {
-                        for block in blocks.iter_mut() {
-                            if block.get("type").and_then(|t| t.as_str()) == Some("tool_result") {
-                                if let Some(c) = block.get_mut("content") {
-                                    let text = c.as_str().unwrap_or("").to_string();
-                                    if text.len() > 200 {
-                                        *c = serde_json::json!(format!("{}...(truncated)", &text[..200]));
-                                    }
-                                }
-                            }
-                        }
+            if !in_string {
+                if ch == open { depth += 1; }
+                if ch == close {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some(s[start_char..start_char + i + ch.len_utf8()].to_string());
                     }
                 }
             }
         }
+        None
+    }
 
-        // Build request body
-        let mut body = serde_json::json!({
-            "model": model,
-            "max_tokens": 4096,
-            "messages": messages
-        });
-        
-        if !system.is_empty() {
-            body["system"] = serde_json::json!(system);
-        }
+    fn value_to_json(&self, value: &Value) -> serde_json::Value {
+        value.to_json()
+    }
 
-        // Add native tools
-        if let Some(ref tool_defs) = tools {
-            let api_tools: Vec<serde_json::Value> = tool_defs.iter().map(|t| {
-                serde_json::json!({
-                    "name": t["function"]["name"].as_str().unwrap_or("unknown"),
-                    "description": t["function"]["description"].as_str().unwrap_or(""),
-                    "input_schema": t["function"]["parameters"]
-                })
-            }).collect();
-            body["tools"] = serde_json::json!(api_tools);
-        }
+    fn json_to_value(&self, v: serde_json::Value) -> Value {
+        Value::from_json(v)
+    }
 
-        let client = reqwest::blocking::Client::new();
-        let mut req = client.post("https://api.anthropic.com/v1/messages");
-        if !token.starts_with("sk-ant-oat") {
-            req = req.header("x-api-key", &token);
-        } else {
-            req = req.header("Authorization", format!("Bearer {}", token));
-            req = req.header("anthropic-beta", "oauth-2025-04-20");
+    /// Build a `system=` prompt. A List is joined with blank lines, each
+    /// `file(...)` element read in; segments are traced separately.
+    fn compose_system(&mut self, val: Value) -> Result<std::string::String> {
+        let items = match val {
+            Value::List(items) => items,
+            Value::Handle(Handle::File(_)) => vec![val],
+            other => return Ok(other.to_string()),
+        };
+        let mut segments = Vec::new();
+        for item in items {
+            match item {
+                Value::None => {}
+                Value::Handle(Handle::File(path)) => {
+                    let content = self.env.lock().unwrap().read_file(&path)
+                        .map_err(|e| anyhow::anyhow!("system=: cannot read '{}': {}", path, e))?;
+                    segments.push((format!("file:{}", path), content.trim_end().to_string()));
+                }
+                Value::String(s) => segments.push(("inline".to_string(), s)),
+                other => segments.push((type_name(&other).to_string(), other.to_string())),
+            }
         }
-        let resp = req
-            .header("anthropic-version", "2023-06-01")
-            .header("content-type", "application/json")
-            .json(&body)
-            .send()
-            .map_err(|e| anyhow::anyhow!("Anthropic API request failed: {}", e))?;
-
-        let status = resp.status();
-        let resp_text = resp.text().map_err(|e| anyhow::anyhow!("Failed to read API response: {}", e))?;
+        let system = segments.iter().map(|(_, text)| text.as_str())
+            .filter(|text| !text.is_empty())
+            .collect::<Vec<_>>().join("\n\n");
+        self.trace(TraceEvent::SystemPrompt { segments });
+        Ok(system)
+    }
 
-        if !status.is_success() {
-            bail!("Anthropic API error ({}): {}", status, &resp_text[..resp_text.len().min(500)]);
+    /// Map-reduce summary: chunks are summarized in parallel, then the partial
+    /// summaries are combined, in as many rounds as it takes to fit one call.
+    fn summarize(&mut self, model: &str, text: &str, max_words: usize, chunk_size: usize) -> Result<std::string::String> {
+        let chunks = chunk_tokens(model, text, chunk_size, 0)?;
+        if chunks.is_empty() {
+            return Ok(std::string::String::new());
+        }
+        if chunks.len() == 1 {
+            let system = format!("Summarize the user's text in at most {} words. Reply with the summary only.", max_words);
+            return Ok(self.summarize_stage("single", model, &system, chunks)?.remove(0));
+        }
+        let system = format!("This is one part of a longer document. Summarize it in at most {} words, \
+            keeping names, numbers and decisions. Reply with the summary only.", max_words);
+        let mut parts = self.summarize_stage("map", model, &system, chunks)?;
+        let reduce = format!("These are summaries of consecutive parts of one document. Combine them into one \
+            summary of at most {} words. Reply with the summary only.", max_words);
+        loop {
+            let joined = parts.join("\n\n");
+            let groups = chunk_tokens(model, &joined, chunk_size, 0)?;
+            // Stop regrouping once it no longer shrinks the input
+            if groups.len() <= 1 || groups.len() >= parts.len() {
+                return Ok(self.summarize_stage("reduce", model, &reduce, vec![joined])?.remove(0));
+            }
+            parts = self.summarize_stage("reduce", model, &reduce, groups)?;
         }
+    }
 
-        let parsed: serde_json::Value = serde_json::from_str(&resp_text)
-            .map_err(|e| anyhow::anyhow!("Failed to parse API response: {}", e))?;
-
-        let latency = call_start.elapsed().as_millis() as u64;
-        let stop_reason = parsed["stop_reason"].as_str().unwrap_or("");
-        let content_blocks = parsed["content"].as_array()
-            .ok_or_else(|| anyhow::anyhow!("No content in API response"))?;
-
-        let mut text_parts: Vec<String> = Vec::new();
-        let mut tool_calls: Vec<Value> = Vec::new();
-        let mut tool_call_id_counter = 0;
+    /// One summarize() round: a call per input, up to 4 at a time against a
+    /// real provider, in order against a mock.
+    fn summarize_stage(&mut self, stage: &str, model: &str, system: &str, inputs: Vec<std::string::String>) -> Result<Vec<std::string::String>> {
+        let start = std::time::Instant::now();
+        let mut outputs = Vec::new();
+        if self.env.lock().unwrap().is_mock() {
+            for input in &inputs {
+                let ollama = self.ollama.clone();
+                outputs.push(self.call_llm(model, system, &[], input, None, &[], &ollama, CallOptions::default())?.to_string());
+            }
+        } else {
+            if let Some(warmup) = self.warmup.take() {
+                let _ = warmup.join();
+            }
+            let (model, options) = self.with_flow_override(model, CallOptions::default());
+            let model = model.as_str();
+            let ollama = &self.ollama;
+            for batch in inputs.chunks(4) {
+                let results: Vec<Result<(ChatResponse, u64)>> = std::thread::scope(|scope| {
+                    let handles: Vec<_> = batch.iter().map(|input| scope.spawn(move || {
+                        let provider = providers::resolve(model, Capabilities::default(), ollama);
+                        let call_start = std::time::Instant::now();
+                        let resp = provider.chat(&ChatRequest { model, system, prompt: input, tools: None, images: &[], files: &[], examples: &[], temperature: options.temperature, json: false, schema: None, logprobs: false })?;
+                        Ok((resp, call_start.elapsed().as_millis() as u64))
+                    })).collect();
+                    handles.into_iter()
+                        .map(|h| h.join().unwrap_or_else(|_| Err(anyhow::anyhow!("summarize(): worker panicked"))))
+                        .collect()
+                });
+                for (input, result) in batch.iter().zip(results) {
+                    let (resp, latency) = result?;
+                    self.trace_llm(model, resp.label, latency, input, resp.system.as_deref().unwrap_or(system), &resp.content, false, resp.finish_reason.as_deref());
+                    warn_incomplete(model, resp.finish_reason.as_deref());
+                    outputs.push(resp.content.trim().to_string());
+                }
+            }
+        }
+        log::info!("summarize(): {} stage, {} call(s)", stage, inputs.len());
+        self.trace(TraceEvent::SummarizeStage {
+            stage: stage.to_string(),
+            calls: inputs.len(),
+            input_chars: inputs.iter().map(|i| i.chars().count()).sum(),
+            output_chars: outputs.iter().map(|o| o.chars().count()).sum(),
+            latency_ms: start.elapsed().as_millis() as u64,
+        });
+        Ok(outputs)
+    }
 
-        for block in content_blocks {
-            match block["type"].as_str() {
-                Some("text") => {
-                    if let Some(t) = block["text"].as_str() {
-                        text_parts.push(t.to_string());
-                    }
+    /// `n` replies to the same single-turn call: all at once against a real
+    /// provider, in order against a mock. A sample that fails is tried again
+    /// under the call's retry policy, if it has one.
+    #[allow(clippy::too_many_arguments)]
+    fn sample_llm(&mut self, n: usize, model: &str, system: &str, examples: &[(std::string::String, std::string::String)], prompt: &str, images: &[std::string::String], ollama: &OllamaOptions, options: CallOptions) -> Result<Vec<std::string::String>> {
+        let mut replies = Vec::new();
+        if self.env.lock().unwrap().is_mock() {
+            for _ in 0..n {
+                let reply = self.request_llm(model, system, examples, prompt, None, images, ollama, options.clone())?;
+                warn_incomplete(model, reply.finish_reason.as_deref());
+                replies.push(reply.content);
+            }
+            return Ok(replies);
+        }
+        if let Some(warmup) = self.warmup.take() {
+            let _ = warmup.join();
+        }
+        let (routed, routed_options) = self.with_flow_override(model, options.clone());
+        let need = Capabilities { vision: !images.is_empty(), files: !routed_options.files.is_empty(), ..Default::default() };
+        let req = ChatRequest { model: &routed, system, prompt, tools: None, images, files: &routed_options.files, examples, temperature: routed_options.temperature, json: routed_options.json, schema: routed_options.schema.as_ref(), logprobs: false };
+        let endpoint = routed_options.endpoint.as_deref();
+        let api_key = routed_options.api_key.as_deref();
+        let results: Vec<Result<(ChatResponse, u64)>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..n).map(|_| scope.spawn(move || {
+                let provider = match endpoint {
+                    Some(url) => providers::at_endpoint(url, api_key.map(str::to_string)),
+                    None => providers::resolve(req.model, need, ollama),
+                };
+                providers::check_support(provider.as_ref(), &need)?;
+                let call_start = std::time::Instant::now();
+                let resp = provider.chat(&req)?;
+                Ok((resp, call_start.elapsed().as_millis() as u64))
+            })).collect();
+            handles.into_iter()
+                .map(|h| h.join().unwrap_or_else(|_| Err(anyhow::anyhow!("think(): sampling worker panicked"))))
+                .collect()
+        });
+        let policy = self.retry_policy(&options);
+        for result in results {
+            match result {
+                Ok((resp, latency)) => {
+                    self.trace_llm(&routed, resp.label, latency, prompt, resp.system.as_deref().unwrap_or(system), &resp.content, false, resp.finish_reason.as_deref());
+                    warn_incomplete(&routed, resp.finish_reason.as_deref());
+                    replies.push(resp.content);
                 }
-                Some("tool_use") => {
-                    let default_id = format!("call_{}", tool_call_id_counter);
-                    let id = block["id"].as_str().unwrap_or(&default_id);
-                    tool_call_id_counter += 1;
-                    let name = block["name"].as_str().unwrap_or("").to_string();
-                    let arguments = self.json_to_value(block["input"].clone());
-                    tool_calls.push(Value::Map(vec![
-                        ("id".to_string(), Value::String(id.to_string())),
-                        ("name".to_string(), Value::String(name)),
-                        ("arguments".to_string(), arguments),
-                    ]));
+                Err(e) if self.wait_to_retry("llm", &routed, &policy, 1, &e) => {
+                    let policy = crate::retry::Policy { retries: policy.retries - 1, ..policy };
+                    let options = CallOptions { retries: Some(policy.retries), ..options.clone() };
+                    let reply = self.request_llm(model, system, examples, prompt, None, images, ollama, options)?;
+                    warn_incomplete(&routed, reply.finish_reason.as_deref());
+                    replies.push(reply.content);
                 }
-                _ => {}
+                Err(e) => return Err(e),
             }
         }
+        Ok(replies)
+    }
 
-        let content = text_parts.join("\n");
-        let has_tool_calls = !tool_calls.is_empty();
-
-        // Build updated conversation — must include both user message and assistant response
-        let mut updated_conversation = conversation.clone();
-        
-        // Add the user message that was sent in this call.
-        // Grab the last user message from the messages array we just built (it has the right format).
-        if let Some(last_user_msg) = messages.iter().rev().find(|m| m["role"] == "user") {
-            updated_conversation.push(self.json_to_value(last_user_msg.clone()));
-        }
-        
-        // Add the assistant's response — store raw content blocks for API fidelity
-        // The Anthropic API needs tool_use blocks in assistant messages so that
-        // subsequent tool_result user messages can reference them by id.
-        let raw_content_blocks: Vec<Value> = content_blocks.iter().map(|block| {
-            self.json_to_value(block.clone())
-        }).collect();
-        
-        let mut assistant_msg = vec![
-            ("role".to_string(), Value::String("assistant".to_string())),
-            ("content".to_string(), Value::List(raw_content_blocks)),
-            ("has_tool_calls".to_string(), Value::Bool(has_tool_calls)),
-        ];
-        
-        if has_tool_calls {
-            assistant_msg.push(("tool_calls".to_string(), Value::List(tool_calls.clone())));
+    /// Ask `model` which of `samples` best answers `prompt`; its index.
+    fn judge_samples(&mut self, model: &str, prompt: &str, samples: &[Value], ollama: &OllamaOptions, options: CallOptions) -> Result<usize> {
+        let system = "You are given a question and several candidate answers to it. Pick the best \
+            one: the most correct and complete, and where they disagree, the one the evidence \
+            supports. Reply with the number of that answer only.";
+        let listing: Vec<std::string::String> = samples.iter().enumerate()
+            .map(|(i, s)| format!("Answer {}:\n{}", i + 1, s))
+            .collect();
+        let request = format!("Question:\n{}\n\n{}", prompt, listing.join("\n\n"));
+        let options = CallOptions { json: false, schema: None, ..options };
+        let verdict = self.call_llm(model, system, &[], &request, None, &[], ollama, options)?.to_string();
+        let number: std::string::String = verdict.chars()
+            .skip_while(|c| !c.is_ascii_digit())
+            .take_while(|c| c.is_ascii_digit())
+            .collect();
+        match number.parse::<usize>() {
+            Ok(k) if (1..=samples.len()).contains(&k) => Ok(k - 1),
+            _ => bail!("think(): judge {} answered '{}', not an answer number from 1 to {}", model, verdict.trim(), samples.len()),
         }
-        
-        updated_conversation.push(Value::Map(assistant_msg));
+    }
 
-        log::info!("Anthropic API (multi-turn): {}ms, stop={}, tools={}", latency, stop_reason, tool_calls.len());
-        self.trace_llm(model, "anthropic-api-multi-turn", latency, prompt, system, &content, has_tool_calls);
+    /// Grade `answer` against `criteria` on the `JUDGE_RUBRIC` scale: a Map
+    /// of `score` (0 to 1), the grader's `reasoning` and whether the score
+    /// reaches `threshold` (`pass`). An unusable grade gets one retry.
+    fn judge(&mut self, model: &str, answer: &str, criteria: &str, question: Option<&str>, reference: Option<&str>, threshold: f64) -> Result<Value> {
+        let start = std::time::Instant::now();
+        let mut request = format!("Criteria:\n{}\n\n", criteria);
+        if let Some(question) = question {
+            request.push_str(&format!("Question:\n{}\n\n", question));
+        }
+        if let Some(reference) = reference {
+            request.push_str(&format!("Reference answer:\n{}\n\n", reference));
+        }
+        request.push_str(&format!("Answer to grade:\n{}", answer));
+        let ollama = self.ollama.clone();
+        let options = CallOptions { json: true, ..Default::default() };
+        let mut verdict = self.call_llm(model, JUDGE_RUBRIC, &[], &request, None, &[], &ollama, options.clone())?;
+        let (grade, reasoning) = match judge_verdict(&self.parse_json_response(&verdict).unwrap_or(Value::None)) {
+            Some(graded) => graded,
+            None => {
+                log::info!("judge(): unusable verdict '{}', retrying", verdict);
+                let retry = format!("{}\n\n(Your previous reply was not a JSON object with \"reasoning\" and a \"grade\" from 1 to 5. Reply with exactly that.)", request);
+                verdict = self.call_llm(model, JUDGE_RUBRIC, &[], &retry, None, &[], &ollama, options)?;
+                judge_verdict(&self.parse_json_response(&verdict).unwrap_or(Value::None))
+                    .ok_or_else(|| anyhow::anyhow!("judge(): {} replied '{}', not a grade from 1 to 5", model, verdict.to_string().trim()))?
+            }
+        };
+        let score = (grade - 1) as f64 / 4.0;
+        let pass = score >= threshold;
+        self.trace(TraceEvent::Judge {
+            model: model.to_string(),
+            score,
+            threshold,
+            pass,
+            latency_ms: start.elapsed().as_millis() as u64,
+            reasoning: reasoning.clone(),
+        });
+        Ok(Value::Map(Entries::from([
+            ("score".to_string(), Value::Float(score)),
+            ("reasoning".to_string(), Value::String(reasoning)),
+            ("pass".to_string(), Value::Bool(pass)),
+        ])))
+    }
 
-        // Return structured response with conversation
-        Ok(Value::Map(vec![
-            ("content".to_string(), Value::String(content)),
-            ("conversation".to_string(), Value::List(updated_conversation)),
-            ("has_tool_calls".to_string(), Value::Bool(has_tool_calls)),
-            ("tool_calls".to_string(), Value::List(tool_calls)),
-        ]))
+    /// Cut oversized `content` in tool results down to `max` chars, tracing each cut.
+    fn truncate_tool_results(&self, results: &mut [Value], conversation: &[Value], max: usize) {
+        for result in results.iter_mut() {
+            let Value::Map(entries) = result else { continue };
+            let id = entries.get("tool_use_id").or_else(|| entries.get("tool_call_id"))
+                .map(|v| v.to_string()).unwrap_or_default();
+            let Some(content) = entries.get_mut("content") else { continue };
+            let text = content.to_string();
+            let Some(truncated) = truncate_tool_result(&text, max) else { continue };
+            log::info!("Truncated tool result {} from {} to {} chars", id, text.chars().count(), max);
+            self.trace(TraceEvent::ToolResultTruncated {
+                tool: tool_call_name(conversation, &id),
+                tool_use_id: id,
+                original_chars: text.chars().count(),
+                kept_chars: max,
+            });
+            *content = Value::String(truncated);
+        }
     }
 
-    fn eval_binop(&self, left: &Value, op: &BinOp, right: &Value) -> Result<Value> {
-        match (left, op, right) {
-            // String concat
-            (Value::String(a), BinOp::Add, Value::String(b)) => Ok(Value::String(format!("{}{}", a, b))),
-
-            // List concatenation
-            (Value::List(a), BinOp::Add, Value::List(b)) => {
-                let mut result = a.clone();
-                result.extend(b.clone());
-                Ok(Value::List(result))
-            }
-
-            // String repeat
-            (Value::String(s), BinOp::Mul, Value::Int(n)) => {
-                if *n < 0 { bail!("cannot repeat string a negative number of times"); }
-                Ok(Value::String(s.repeat(*n as usize)))
-            }
-            (Value::Int(n), BinOp::Mul, Value::String(s)) => {
-                if *n < 0 { bail!("cannot repeat string a negative number of times"); }
-                Ok(Value::String(s.repeat(*n as usize)))
-            }
-
-            // Int arithmetic
-            (Value::Int(a), BinOp::Add, Value::Int(b)) => Ok(Value::Int(a + b)),
-            (Value::Int(a), BinOp::Sub, Value::Int(b)) => Ok(Value::Int(a - b)),
-            (Value::Int(a), BinOp::Mul, Value::Int(b)) => Ok(Value::Int(a * b)),
-            (Value::Int(a), BinOp::Div, Value::Int(b)) => {
-                if *b == 0 { bail!("division by zero"); }
-                Ok(Value::Int(a / b))
+    fn flow_to_tool_json(&self, flow: &FlowDef, max_result: Option<usize>) -> serde_json::Value {
+        let mut properties = serde_json::Map::new();
+        let mut required = Vec::new();
+        for param in &flow.params {
+            let ty = match &param.ty {
+                TypeExpr::Named(n) => match n.as_str() {
+                    "String" => "string",
+                    "Int" => "integer",
+                    "Float" => "number",
+                    "Bool" => "boolean",
+                    _ => "string",
+                },
+                _ => "string",
+            };
+            if param.variadic {
+                properties.insert(param.name.to_string(), serde_json::json!({
+                    "type": "array",
+                    "items": { "type": ty },
+                    "description": format!("Parameter '{}'", param.name)
+                }));
+                continue;
             }
-            (Value::Int(a), BinOp::Mod, Value::Int(b)) => {
-                if *b == 0 { bail!("modulo by zero"); }
-                Ok(Value::Int(a % b))
+            properties.insert(param.name.to_string(), serde_json::json!({
+                "type": ty,
+                "description": format!("Parameter '{}'", param.name)
+            }));
+            required.push(serde_json::Value::String(param.name.to_string()));
+        }
+        let mut desc = flow.description.clone()
+            .unwrap_or_else(|| format!("Flow '{}'", flow.name));
+        if let Some(max) = max_result {
+            desc.push_str(&format!(" (results over {} chars are cut to their start and end)", max));
+        }
+        serde_json::json!({
+            "type": "function",
+            "function": {
+                "name": flow.name,
+                "description": desc,
+                "parameters": {
+                    "type": "object",
+                    "properties": properties,
+                    "required": required
+                }
             }
+        })
+    }
 
-            // Float arithmetic
-            (Value::Float(a), BinOp::Add, Value::Float(b)) => Ok(Value::Float(a + b)),
-            (Value::Float(a), BinOp::Sub, Value::Float(b)) => Ok(Value::Float(a - b)),
-            (Value::Float(a), BinOp::Mul, Value::Float(b)) => Ok(Value::Float(a * b)),
-            (Value::Float(a), BinOp::Div, Value::Float(b)) => {
-                if *b == 0.0 { bail!("division by zero"); }
-                Ok(Value::Float(a / b))
-            }
+    #[allow(clippy::too_many_arguments)]
+    fn call_llm(&mut self, model: &str, system: &str, examples: &[(std::string::String, std::string::String)], prompt: &str, tools: Option<Vec<serde_json::Value>>, images: &[std::string::String], ollama: &OllamaOptions, options: CallOptions) -> Result<Value> {
+        let with_tools = tools.is_some();
+        let reply = self.request_llm(model, system, examples, prompt, tools, images, ollama, options)?;
+        warn_incomplete(model, reply.finish_reason.as_deref());
+        Ok(reply.into_value(with_tools))
+    }
 
-            // Mixed Int/Float arithmetic (promote to Float)
-            (Value::Int(a), BinOp::Add, Value::Float(b)) => Ok(Value::Float(*a as f64 + b)),
-            (Value::Float(a), BinOp::Add, Value::Int(b)) => Ok(Value::Float(a + *b as f64)),
-            (Value::Int(a), BinOp::Sub, Value::Float(b)) => Ok(Value::Float(*a as f64 - b)),
-            (Value::Float(a), BinOp::Sub, Value::Int(b)) => Ok(Value::Float(a - *b as f64)),
-            (Value::Int(a), BinOp::Mul, Value::Float(b)) => Ok(Value::Float(*a as f64 * b)),
-            (Value::Float(a), BinOp::Mul, Value::Int(b)) => Ok(Value::Float(a * *b as f64)),
-            (Value::Int(a), BinOp::Div, Value::Float(b)) => {
-                if *b == 0.0 { bail!("division by zero"); }
-                Ok(Value::Float(*a as f64 / b))
+    /// One single-turn LLM call, against the mock or the model's provider.
+    #[allow(clippy::too_many_arguments)]
+    fn request_llm(&mut self, model: &str, system: &str, examples: &[(std::string::String, std::string::String)], prompt: &str, tools: Option<Vec<serde_json::Value>>, images: &[std::string::String], ollama: &OllamaOptions, options: CallOptions) -> Result<LlmReply> {
+        let (model, options) = self.with_flow_override(model, options);
+        let model = model.as_str();
+        // Check if mock env handles LLM calls
+        if self.env.lock().unwrap().is_mock() {
+            // Mock environment — use env.call_llm
+            let request = crate::environment::LlmRequest {
+                model: model.to_string(), system: system.to_string(),
+                prompt: prompt.to_string(), tools: tools.clone(),
+                format: options.json.then(|| "json".to_string()), history: vec![],
+            };
+            let policy = self.retry_policy(&options);
+            let mut attempt = 1;
+            let resp = loop {
+                let result = self.env.lock().unwrap().call_llm(request.clone());
+                match result {
+                    Ok(resp) => break resp,
+                    Err(e) if self.wait_to_retry("llm", model, &policy, attempt, &e) => attempt += 1,
+                    Err(e) => return Err(e),
+                }
+            };
+            if options.stream && !resp.content.is_empty() {
+                self.stream_token(options.on_token.as_deref(), &resp.content)?;
             }
-            (Value::Float(a), BinOp::Div, Value::Int(b)) => {
-                if *b == 0 { bail!("division by zero"); }
-                Ok(Value::Float(a / *b as f64))
+            let has_tc = resp.tool_calls.is_some();
+            self.trace_llm(model, "mock", 0, prompt, system, &resp.content, has_tc, resp.finish_reason.as_deref());
+            let tool_calls = resp.tool_calls.map(|tc| tc.iter().map(|c| {
+                let name = c["name"].as_str().unwrap_or("").to_string();
+                let arguments = self.json_to_value(c["arguments"].clone());
+                Value::Map(Entries::from([
+                    ("name".to_string(), Value::String(name)),
+                    ("arguments".to_string(), arguments),
+                ]))
+            }).collect());
+            // Canned logprobs: [{"token": ..., "logprob": ...}, ...]
+            let logprobs = options.logprobs.then(|| resp.raw_json.as_ref()
+                .and_then(|raw| raw["logprobs"].as_array())
+                .map(|tokens| tokens.iter().filter_map(|t| Some(TokenLogprob {
+                    token: t["token"].as_str()?.to_string(),
+                    logprob: t["logprob"].as_f64()?,
+                })).collect())
+                .unwrap_or_default());
+            return Ok(LlmReply { content: resp.content, tool_calls, finish_reason: resp.finish_reason, logprobs });
+        }
+        // Real environment — route to correct provider
+        if let Some(warmup) = self.warmup.take() {
+            let _ = warmup.join();
+        }
+        let need = Capabilities { tools: tools.is_some(), vision: !images.is_empty(), native_tools: has_native_tools(&tools), files: !options.files.is_empty(), logprobs: options.logprobs, ..Default::default() };
+        let provider = match options.endpoint {
+            Some(ref url) => providers::at_endpoint(url, options.api_key.clone()),
+            None => providers::resolve(model, need, ollama),
+        };
+        providers::check_support(provider.as_ref(), &need)?;
+        let call_start = std::time::Instant::now();
+        let req = ChatRequest { model, system, prompt, tools: tools.as_deref(), images, files: &options.files, examples, temperature: options.temperature, json: options.json, schema: options.schema.as_ref(), logprobs: options.logprobs };
+        let policy = self.retry_policy(&options);
+        let mut attempt = 1;
+        let resp = loop {
+            // Part of a reply already shown can't be taken back, so that one isn't retried
+            let mut streamed = false;
+            let result = if options.stream {
+                provider.chat_stream(&req, &mut |token| {
+                    streamed = true;
+                    self.stream_token(options.on_token.as_deref(), token)
+                })
+            } else {
+                provider.chat(&req)
+            };
+            match result {
+                Ok(resp) => break resp,
+                Err(e) if !streamed && self.wait_to_retry("llm", model, &policy, attempt, &e) => attempt += 1,
+                Err(e) => return Err(e),
             }
+        };
+        let latency = call_start.elapsed().as_millis() as u64;
+        let has_tc = !resp.tool_calls.is_empty();
+        self.trace_llm(model, resp.label, latency, prompt, resp.system.as_deref().unwrap_or(system), &resp.content, has_tc, resp.finish_reason.as_deref());
+        Ok(LlmReply {
+            content: resp.content,
+            tool_calls: has_tc.then(|| resp.tool_calls.iter().map(|tc| tc.to_value()).collect()),
+            finish_reason: resp.finish_reason,
+            logprobs: resp.logprobs,
+        })
+    }
 
-            // Comparisons
-            (Value::Int(a), BinOp::Eq, Value::Int(b)) => Ok(Value::Bool(a == b)),
-            (Value::Int(a), BinOp::NotEq, Value::Int(b)) => Ok(Value::Bool(a != b)),
-            (Value::Int(a), BinOp::Lt, Value::Int(b)) => Ok(Value::Bool(a < b)),
-            (Value::Int(a), BinOp::Gt, Value::Int(b)) => Ok(Value::Bool(a > b)),
-            (Value::Int(a), BinOp::LtEq, Value::Int(b)) => Ok(Value::Bool(a <= b)),
-            (Value::Int(a), BinOp::GtEq, Value::Int(b)) => Ok(Value::Bool(a >= b)),
-
-            (Value::Float(a), BinOp::Eq, Value::Float(b)) => Ok(Value::Bool(a == b)),
-            (Value::Float(a), BinOp::NotEq, Value::Float(b)) => Ok(Value::Bool(a != b)),
-            (Value::Float(a), BinOp::Lt, Value::Float(b)) => Ok(Value::Bool(a < b)),
-            (Value::Float(a), BinOp::Gt, Value::Float(b)) => Ok(Value::Bool(a > b)),
-            (Value::Float(a), BinOp::LtEq, Value::Float(b)) => Ok(Value::Bool(a <= b)),
-            (Value::Float(a), BinOp::GtEq, Value::Float(b)) => Ok(Value::Bool(a >= b)),
-
-            (Value::String(a), BinOp::Eq, Value::String(b)) => Ok(Value::Bool(a == b)),
-            (Value::String(a), BinOp::NotEq, Value::String(b)) => Ok(Value::Bool(a != b)),
-
-            // Boolean comparison
-            (Value::Bool(a), BinOp::Eq, Value::Bool(b)) => Ok(Value::Bool(a == b)),
-            (Value::Bool(a), BinOp::NotEq, Value::Bool(b)) => Ok(Value::Bool(a != b)),
-
-            // None comparison
-            (Value::None, BinOp::Eq, Value::None) => Ok(Value::Bool(true)),
-            (Value::None, BinOp::Eq, _) => Ok(Value::Bool(false)),
-            (_, BinOp::Eq, Value::None) => Ok(Value::Bool(false)),
-            (Value::None, BinOp::NotEq, Value::None) => Ok(Value::Bool(false)),
-            (Value::None, BinOp::NotEq, _) => Ok(Value::Bool(true)),
-            (_, BinOp::NotEq, Value::None) => Ok(Value::Bool(true)),
-
-            // Boolean logic
-            (Value::Bool(a), BinOp::And, Value::Bool(b)) => Ok(Value::Bool(*a && *b)),
-            (Value::Bool(a), BinOp::Or, Value::Bool(b)) => Ok(Value::Bool(*a || *b)),
+    /// The retry policy of an LLM call: its own `retries=`/`backoff=`, else `--llm-retries`.
+    fn retry_policy(&self, options: &CallOptions) -> crate::retry::Policy {
+        crate::retry::Policy {
+            retries: options.retries.unwrap_or(self.llm_retry.retries),
+            backoff: options.backoff.unwrap_or(self.llm_retry.backoff),
+        }
+    }
 
-            // Truthy logic
-            (_, BinOp::And, _) => Ok(Value::Bool(left.is_truthy() && right.is_truthy())),
-            (_, BinOp::Or, _) => Ok(Value::Bool(left.is_truthy() || right.is_truthy())),
+    /// After failed attempt `attempt` at `call`, decide whether to try again.
+    /// If so, trace the failure and wait out the backoff (no waiting under
+    /// the mock environment).
+    fn wait_to_retry(&self, call: &str, target: &str, policy: &crate::retry::Policy, attempt: u32, err: &anyhow::Error) -> bool {
+        if !policy.should_retry(attempt, err) {
+            return false;
+        }
+        let delay = policy.delay(attempt);
+        log::info!("{} {}: attempt {} of {} failed ({}), retrying in {}ms", call, target, attempt, policy.retries + 1, err, delay.as_millis());
+        self.trace(TraceEvent::Retry {
+            call: call.to_string(), target: target.to_string(),
+            attempt, retries: policy.retries,
+            delay_ms: delay.as_millis() as u64, error: err.to_string(),
+        });
+        if !self.env.lock().unwrap().is_mock() {
+            std::thread::sleep(delay);
+        }
+        true
+    }
 
-            // Containment: "x" in "xyz", item in [list], key in {map}
-            (_, BinOp::In, Value::String(s)) => {
-                Ok(Value::Bool(s.contains(&left.to_string())))
-            }
-            (_, BinOp::In, Value::List(items)) => {
-                let needle = left.to_string();
-                Ok(Value::Bool(items.iter().any(|item| item.to_string() == needle)))
-            }
-            (_, BinOp::In, Value::Map(entries)) => {
-                let key = left.to_string();
-                Ok(Value::Bool(entries.iter().any(|(k, _)| k == &key)))
+    /// Pass one piece of a streamed reply to the `on_token=` flow, or to stdout.
+    fn stream_token(&mut self, on_token: Option<&str>, token: &str) -> Result<()> {
+        match on_token {
+            Some(flow) => {
+                self.call_flow(flow, vec![Value::String(token.to_string())], Vec::new())?;
             }
+            None => self.env.lock().unwrap().stream_stdout(token)?,
+        }
+        Ok(())
+    }
 
-            // Negated containment: "x" not in "xyz", item not in [list], key not in {map}
-            (_, BinOp::NotIn, Value::String(s)) => {
-                Ok(Value::Bool(!s.contains(&left.to_string())))
-            }
-            (_, BinOp::NotIn, Value::List(items)) => {
-                let needle = left.to_string();
-                Ok(Value::Bool(!items.iter().any(|item| item.to_string() == needle)))
-            }
-            (_, BinOp::NotIn, Value::Map(entries)) => {
-                let key = left.to_string();
-                Ok(Value::Bool(!entries.iter().any(|(k, _)| k == &key)))
-            }
+    /// Ask for the rest of a reply cut off at the output length limit, up to
+    /// `MAX_CONTINUATIONS` times, and append it. Replies with tool calls are
+    /// left alone.
+    fn continue_truncated(&mut self, mut reply: LlmReply, model: &str, system: &str, prompt: &str, ollama: &OllamaOptions, options: CallOptions) -> Result<LlmReply> {
+        // A continuation is a fragment, not a JSON document of its own
+        let options = CallOptions { json: false, schema: None, ..options };
+        for round in 1..=MAX_CONTINUATIONS {
+            if reply.finish_reason.as_deref() != Some("length") || reply.tool_calls.is_some() {
+                break;
+            }
+            log::info!("think(): reply cut off at {} chars, asking for the rest ({}/{})", reply.content.chars().count(), round, MAX_CONTINUATIONS);
+            let request = format!("{}\n\n---\nYour answer so far, which was cut off:\n\n{}\n\n---\n{}", prompt, reply.content, CONTINUE_PROMPT);
+            let next = self.request_llm(model, system, &[], &request, None, &[], ollama, options.clone())?;
+            reply.content.push_str(&next.content);
+            reply.finish_reason = next.finish_reason;
+            if let (Some(logprobs), Some(more)) = (reply.logprobs.as_mut(), next.logprobs) {
+                logprobs.extend(more);
+            }
+        }
+        Ok(reply)
+    }
 
-            _ => bail!("cannot {} {} {} — {} {} {} not supported",
-                type_name(left), op_str(op), type_name(right),
-                type_name(left), op_str(op), type_name(right)),
+    /// One step of a `conversation=` exchange, answering either `prompt` or `tool_results`.
+    #[allow(clippy::too_many_arguments)]
+    fn call_llm_turn(&mut self, model: &str, system: &str, examples: &[(std::string::String, std::string::String)], prompt: &str, tools: Option<Vec<serde_json::Value>>, conversation: &[Value], tool_results: Option<Vec<Value>>, options: CallOptions) -> Result<Value> {
+        let (model, options) = self.with_flow_override(model, options);
+        let model = model.as_str();
+        if let Some(warmup) = self.warmup.take() {
+            let _ = warmup.join();
+        }
+        let need = Capabilities { tools: tools.is_some(), multi_turn: true, native_tools: has_native_tools(&tools), files: !options.files.is_empty(), logprobs: options.logprobs, ..Default::default() };
+        let provider = match options.endpoint {
+            Some(ref url) => providers::at_endpoint(url, options.api_key.clone()),
+            None => providers::resolve(model, need, &self.ollama),
+        };
+        providers::check_support(provider.as_ref(), &need)?;
+        let call_start = std::time::Instant::now();
+        let req = ChatRequest { model, system, prompt, tools: tools.as_deref(), images: &[], files: &options.files, examples, temperature: options.temperature, json: options.json, schema: options.schema.as_ref(), logprobs: options.logprobs };
+        let policy = self.retry_policy(&options);
+        let mut attempt = 1;
+        let turn = loop {
+            match provider.chat_turn(&req, conversation, tool_results.as_deref()) {
+                Ok(turn) => break turn,
+                Err(e) if self.wait_to_retry("llm", model, &policy, attempt, &e) => attempt += 1,
+                Err(e) => return Err(e),
+            }
+        };
+        let latency = call_start.elapsed().as_millis() as u64;
+        let resp = turn.response;
+        let has_tool_calls = !resp.tool_calls.is_empty();
+        self.trace_llm(model, resp.label, latency, prompt, system, &resp.content, has_tool_calls, resp.finish_reason.as_deref());
+        warn_incomplete(model, resp.finish_reason.as_deref());
+
+        let mut result = Entries::from([
+            ("content".to_string(), Value::String(resp.content)),
+            ("conversation".to_string(), Value::List(turn.conversation)),
+            ("has_tool_calls".to_string(), Value::Bool(has_tool_calls)),
+            ("tool_calls".to_string(), Value::List(resp.tool_calls.iter().map(|tc| tc.to_value()).collect())),
+            ("finish_reason".to_string(), resp.finish_reason.map_or(Value::None, Value::String)),
+        ]);
+        if let Some(logprobs) = resp.logprobs {
+            result.extend(logprob_entries(&logprobs));
         }
+        Ok(Value::Map(result))
+    }
+
+    fn eval_binop(&self, left: &Value, op: &BinOp, right: &Value) -> Result<Value> {
+        binop(&self.limits, left, op, right)
     }
 
     // --- Channel I/O: Slack ---
 
     #[cfg(test)]
     fn build_messages_from_conversation(&self, conversation: &[Value], prompt: &str, tool_results: Option<&[Value]>) -> Result<Vec<serde_json::Value>> {
-        let mut messages: Vec<serde_json::Value> = Vec::new();
-        
-        // Convert conversation to API messages
-        for msg in conversation {
-            if let Value::Map(entries) = msg {
-                let role = entries.iter().find(|(k, _)| k == "role")
-                    .map(|(_, v)| v.to_string()).unwrap_or_default();
-                let content = entries.iter().find(|(k, _)| k == "content");
-                
-                if let Some((_, content_val)) = content {
-                    match content_val {
-                        Value::String(text) => {
-                            messages.push(serde_json::json!({
-                                "role": role,
-                                "content": text
-                            }));
-                        }
-                        Value::List(blocks) => {
-                            // Content blocks (for tool results)
-                            let content_blocks: Vec<serde_json::Value> = blocks.iter().map(|block| {
-                                if let Value::Map(block_entries) = block {
-                                    let block_type = block_entries.iter().find(|(k, _)| k == "type")
-                                        .map(|(_, v)| v.to_string()).unwrap_or_default();
-                                    match block_type.as_str() {
-                                        "tool_result" => {
-                                            let tool_use_id = block_entries.iter().find(|(k, _)| k == "tool_use_id")
-                                                .map(|(_, v)| v.to_string()).unwrap_or_default();
-                                            let content = block_entries.iter().find(|(k, _)| k == "content")
-                                                .map(|(_, v)| v.to_string()).unwrap_or_default();
-                                            serde_json::json!({
-                                                "type": "tool_result",
-                                                "tool_use_id": tool_use_id,
-                                                "content": content
-                                            })
-                                        }
-                                        "tool_use" => {
-                                            let id = block_entries.iter().find(|(k, _)| k == "id")
-                                                .map(|(_, v)| v.to_string()).unwrap_or_default();
-                                            let name = block_entries.iter().find(|(k, _)| k == "name")
-                                                .map(|(_, v)| v.to_string()).unwrap_or_default();
-                                            let input = block_entries.iter().find(|(k, _)| k == "input")
-                                                .map(|(_, v)| self.value_to_json(v))
-                                                .unwrap_or(serde_json::json!({}));
-                                            serde_json::json!({
-                                                "type": "tool_use",
-                                                "id": id,
-                                                "name": name,
-                                                "input": input
-                                            })
-                                        }
-                                        "text" => {
-                                            let text = block_entries.iter().find(|(k, _)| k == "text")
-                                                .map(|(_, v)| v.to_string()).unwrap_or_default();
-                                            serde_json::json!({
-                                                "type": "text",
-                                                "text": text
-                                            })
-                                        }
-                                        _ => {
-                                            let text = block_entries.iter().find(|(k, _)| k == "text" || k == "content")
-                                                .map(|(_, v)| v.to_string()).unwrap_or_default();
-                                            serde_json::json!({
-                                                "type": "text",
-                                                "text": text
-                                            })
-                                        }
-                                    }
-                                } else {
-                                    // Fallback: convert value to text block
-                                    serde_json::json!({
-                                        "type": "text", 
-                                        "text": block.to_string()
-                                    })
-                                }
-                            }).collect();
-                            messages.push(serde_json::json!({
-                                "role": role,
-                                "content": content_blocks
-                            }));
-                        }
-                        _ => {
-                            // Fallback: convert to string
-                            messages.push(serde_json::json!({
-                                "role": role,
-                                "content": content_val.to_string()
-                            }));
-                        }
-                    }
-                }
-            }
-        }
-
-        // Add new user message if prompt is provided
-        if !prompt.is_empty() {
-            if let Some(tr) = tool_results {
-                // Tool results message with content blocks
-                let tool_result_blocks: Vec<serde_json::Value> = tr.iter().map(|result| {
-                    if let Value::Map(entries) = result {
-                        let tool_use_id = entries.iter().find(|(k, _)| k == "tool_use_id")
-                            .map(|(_, v)| v.to_string()).unwrap_or_default();
-                        let content = entries.iter().find(|(k, _)| k == "content")
-                            .map(|(_, v)| v.to_string()).unwrap_or_default();
-                        serde_json::json!({
-                            "type": "tool_result",
-                            "tool_use_id": tool_use_id,
-                            "content": content
-                        })
-                    } else {
-                        serde_json::json!({
-                            "type": "tool_result",
-                            "tool_use_id": "unknown",
-                            "content": result.to_string()
-                        })
-                    }
-                }).collect();
-                
-                if !prompt.trim().is_empty() {
-                    // Prepend prompt text if present
-                    let mut content_blocks = vec![serde_json::json!({
-                        "type": "text",
-                        "text": prompt
-                    })];
-                    content_blocks.extend(tool_result_blocks);
-                    messages.push(serde_json::json!({
-                        "role": "user",
-                        "content": content_blocks
-                    }));
-                } else {
-                    // Tool results only
-                    messages.push(serde_json::json!({
-                        "role": "user",
-                        "content": tool_result_blocks
-                    }));
-                }
-            } else {
-                // Regular text message
-                messages.push(serde_json::json!({
-                    "role": "user",
-                    "content": prompt
-                }));
-            }
-        } else if let Some(tr) = tool_results {
-            // Tool results only, no prompt
-            let tool_result_blocks: Vec<serde_json::Value> = tr.iter().map(|result| {
-                if let Value::Map(entries) = result {
-                    let tool_use_id = entries.iter().find(|(k, _)| k == "tool_use_id")
-                        .map(|(_, v)| v.to_string()).unwrap_or_default();
-                    let content = entries.iter().find(|(k, _)| k == "content")
-                        .map(|(_, v)| v.to_string()).unwrap_or_default();
-                    serde_json::json!({
-                        "type": "tool_result",
-                        "tool_use_id": tool_use_id,
-                        "content": content
-                    })
-                } else {
-                    serde_json::json!({
-                        "type": "tool_result",
-                        "tool_use_id": "unknown", 
-                        "content": result.to_string()
-                    })
-                }
-            }).collect();
-            messages.push(serde_json::json!({
-                "role": "user",
-                "content": tool_result_blocks
-            }));
-        }
-
-        Ok(messages)
+        crate::providers::anthropic_messages(conversation, prompt, tool_results)
     }
 
     fn write_slack_channel(&mut self, config: &HashMap<std::string::String, std::string::String>, text: &str) -> Result<Value> {
         let token = config.get("token").ok_or_else(|| anyhow::anyhow!("slack: missing token"))?;
         let channel = config.get("channel").ok_or_else(|| anyhow::anyhow!("slack: missing channel"))?;
 
-        let client = reqwest::blocking::Client::new();
+        let client = crate::http_client::client(Service::Slack);
         let resp = client.post("https://slack.com/api/chat.postMessage")
             .bearer_auth(token)
             .json(&serde_json::json!({
@@ -3895,7 +6316,7 @@ impl Interpreter {
                 "text": text,
             }))
             .send()
-            .map_err(|e| anyhow::anyhow!("slack write failed: {}", e))?;
+            .map_err(|e| crate::retry::request_failed(format!("slack write failed: {}", e), &e))?;
 
         let json: serde_json::Value = resp.json()?;
         if json["ok"].as_bool() != Some(true) {
@@ -3904,12 +6325,49 @@ impl Interpreter {
         // Update last_ts so we don't read back our own message
         if let Some(ts) = json["ts"].as_str() {
             let last_ts_key = format!("__slack_last_ts_{}", channel);
-            self.vars.insert(last_ts_key, Value::String(ts.to_string()));
+            self.vars.insert(last_ts_key.into(), Value::String(ts.to_string()));
         }
         log::info!("slack: sent message to {}", channel);
         Ok(Value::None)
     }
 
+    fn read_nats_channel(&mut self, config: &HashMap<std::string::String, std::string::String>) -> Result<Value> {
+        let subject = config.get("subject").ok_or_else(|| anyhow::anyhow!("nats: missing subject"))?;
+        let queue = config.get("queue").cloned().unwrap_or_default();
+        let timeout = config.get("timeout")
+            .and_then(|t| t.parse::<f64>().ok())
+            .map(std::time::Duration::from_secs_f64);
+        let options = crate::nats::Options::from_config(config);
+        let Some(message) = crate::nats::next_message(&options, subject, &queue, timeout)? else {
+            return Ok(Value::None);
+        };
+        log::info!("nats: received message on {}", message.subject);
+        // Return normalized message shape: {text, subject, reply}
+        Ok(Value::Map(Entries::from([
+            ("text".to_string(), Value::String(message.payload)),
+            ("subject".to_string(), Value::String(message.subject)),
+            ("reply".to_string(), message.reply.map_or(Value::None, Value::String)),
+        ])))
+    }
+
+    fn read_mqtt_channel(&mut self, config: &HashMap<std::string::String, std::string::String>) -> Result<Value> {
+        let topic = config.get("topic").ok_or_else(|| anyhow::anyhow!("mqtt: missing topic"))?;
+        let timeout = config.get("timeout")
+            .and_then(|t| t.parse::<f64>().ok())
+            .map(std::time::Duration::from_secs_f64);
+        let options = crate::mqtt::Options::from_config(config);
+        let Some(message) = crate::mqtt::next_message(&options, topic, crate::mqtt::qos(config)?, timeout)? else {
+            return Ok(Value::None);
+        };
+        log::info!("mqtt: received message on {}", message.topic);
+        // Return normalized message shape: {text, topic, retained}
+        Ok(Value::Map(Entries::from([
+            ("text".to_string(), Value::String(message.payload)),
+            ("topic".to_string(), Value::String(message.topic)),
+            ("retained".to_string(), Value::Bool(message.retained)),
+        ])))
+    }
+
     fn read_slack_channel(&mut self, config: &HashMap<std::string::String, std::string::String>) -> Result<Value> {
         let token = config.get("token").ok_or_else(|| anyhow::anyhow!("slack: missing token"))?;
         let channel = config.get("channel").ok_or_else(|| anyhow::anyhow!("slack: missing channel"))?;
@@ -3920,12 +6378,12 @@ impl Interpreter {
 
         // Track last seen timestamp to only get new messages
         let last_ts_key = format!("__slack_last_ts_{}", channel);
-        let mut last_ts = match self.vars.get(&last_ts_key) {
+        let mut last_ts = match self.vars.get(last_ts_key.as_str()) {
             Some(Value::String(ts)) => ts.clone(),
             _ => "0".to_string(),
         };
 
-        let client = reqwest::blocking::Client::new();
+        let client = crate::http_client::client(Service::Slack);
         loop {
             let mut url = format!(
                 "https://slack.com/api/conversations.history?channel={}&limit=5",
@@ -3938,7 +6396,7 @@ impl Interpreter {
             let resp = client.get(&url)
                 .bearer_auth(token)
                 .send()
-                .map_err(|e| anyhow::anyhow!("slack read failed: {}", e))?;
+                .map_err(|e| crate::retry::request_failed(format!("slack read failed: {}", e), &e))?;
 
             let json: serde_json::Value = resp.json()?;
             if json["ok"].as_bool() != Some(true) {
@@ -3968,14 +6426,14 @@ impl Interpreter {
                     let text = msg["text"].as_str().unwrap_or("").to_string();
                     let user = msg["user"].as_str().unwrap_or("unknown").to_string();
                     last_ts = ts.to_string();
-                    self.vars.insert(last_ts_key, Value::String(last_ts));
+                    self.vars.insert(last_ts_key.as_str().into(), Value::String(last_ts));
 
                     log::info!("slack: received message from {} in {}", user, channel);
 
                     // Extract files (attachments, inline images, etc)
                     let files = if let Some(file_arr) = msg["files"].as_array() {
                         file_arr.iter().map(|f| {
-                            Value::Map(vec![
+                            Value::Map(Entries::from([
                                 ("name".to_string(), Value::String(
                                     f["name"].as_str().unwrap_or("unknown").to_string())),
                                 ("url".to_string(), Value::String(
@@ -3986,19 +6444,19 @@ impl Interpreter {
                                     f["mimetype"].as_str().unwrap_or("application/octet-stream").to_string())),
                                 ("size".to_string(), Value::Int(
                                     f["size"].as_i64().unwrap_or(0))),
-                            ])
+                            ]))
                         }).collect()
                     } else {
                         vec![]
                     };
 
                     // Return normalized message shape: {text, user, ts, files}
-                    return Ok(Value::Map(vec![
+                    return Ok(Value::Map(Entries::from([
                         ("text".to_string(), Value::String(text)),
                         ("user".to_string(), Value::String(user)),
                         ("ts".to_string(), Value::String(ts.to_string())),
                         ("files".to_string(), Value::List(files)),
-                    ]));
+                    ])));
                 }
             }
 
@@ -4008,6 +6466,22 @@ impl Interpreter {
     }
 }
 
+#[cfg(test)]
+mod sampling_tests {
+    use super::*;
+
+    #[test]
+    fn majority_groups_equivalent_replies() {
+        let samples: Vec<Value> = ["Lyon", "Paris", " \"paris.\"", "Lyon"].iter()
+            .map(|s| Value::String(s.to_string())).collect();
+        // Two each: the earliest of the tied groups wins
+        assert_eq!(majority(&samples), 0);
+        assert_eq!(sample_key(&samples[1]), sample_key(&samples[2]));
+        let numbers = [Value::Int(3), Value::Int(4), Value::Int(4)];
+        assert_eq!(majority(&numbers), 1);
+    }
+}
+
 #[cfg(test)]
 mod multi_turn_tests {
     use super::*;
@@ -4020,9 +6494,10 @@ mod multi_turn_tests {
     fn create_flow_def(name: &str, params: Vec<(&str, &str)>, description: Option<&str>) -> FlowDef {
         let parameters = params.into_iter().map(|(param_name, param_type)| {
             Param {
-                name: param_name.to_string(),
+                name: param_name.into(),
                 ty: TypeExpr::Named(param_type.to_string()),
                 default: None,
+                variadic: false,
             }
         }).collect();
 
@@ -4052,15 +6527,15 @@ mod multi_turn_tests {
     fn test_conversation_building_single_turn() {
         let interp = create_test_interpreter();
         let conversation = vec![
-            Value::Map(vec![
+            Value::Map(Entries::from([
                 ("role".to_string(), Value::String("user".to_string())),
                 ("content".to_string(), Value::String("What's 2+2?".to_string())),
-            ]),
-            Value::Map(vec![
+            ])),
+            Value::Map(Entries::from([
                 ("role".to_string(), Value::String("assistant".to_string())),
                 ("content".to_string(), Value::String("2+2 equals 4.".to_string())),
                 ("has_tool_calls".to_string(), Value::Bool(false)),
-            ])
+            ]))
         ];
         
         let messages = interp.build_messages_from_conversation(&conversation, "What about 3+3?", None).unwrap();
@@ -4078,24 +6553,24 @@ mod multi_turn_tests {
     fn test_conversation_building_multiple_turns() {
         let interp = create_test_interpreter();
         let conversation = vec![
-            Value::Map(vec![
+            Value::Map(Entries::from([
                 ("role".to_string(), Value::String("user".to_string())),
                 ("content".to_string(), Value::String("Hello".to_string())),
-            ]),
-            Value::Map(vec![
+            ])),
+            Value::Map(Entries::from([
                 ("role".to_string(), Value::String("assistant".to_string())),
                 ("content".to_string(), Value::String("Hi there!".to_string())),
                 ("has_tool_calls".to_string(), Value::Bool(false)),
-            ]),
-            Value::Map(vec![
+            ])),
+            Value::Map(Entries::from([
                 ("role".to_string(), Value::String("user".to_string())),
                 ("content".to_string(), Value::String("How are you?".to_string())),
-            ]),
-            Value::Map(vec![
+            ])),
+            Value::Map(Entries::from([
                 ("role".to_string(), Value::String("assistant".to_string())),
                 ("content".to_string(), Value::String("I'm doing well!".to_string())),
                 ("has_tool_calls".to_string(), Value::Bool(false)),
-            ])
+            ]))
         ];
         
         let messages = interp.build_messages_from_conversation(&conversation, "Great!", None).unwrap();
@@ -4109,16 +6584,16 @@ mod multi_turn_tests {
     fn test_conversation_building_with_tool_result_blocks() {
         let interp = create_test_interpreter();
         let conversation = vec![
-            Value::Map(vec![
+            Value::Map(Entries::from([
                 ("role".to_string(), Value::String("user".to_string())),
                 ("content".to_string(), Value::List(vec![
-                    Value::Map(vec![
+                    Value::Map(Entries::from([
                         ("type".to_string(), Value::String("tool_result".to_string())),
                         ("tool_use_id".to_string(), Value::String("call_123".to_string())),
                         ("content".to_string(), Value::String("File contents here".to_string())),
-                    ])
+                    ]))
                 ])),
-            ])
+            ]))
         ];
         
         let messages = interp.build_messages_from_conversation(&conversation, "", None).unwrap();
@@ -4139,7 +6614,7 @@ mod multi_turn_tests {
         let interp = create_test_interpreter();
         let flow = create_flow_def("simple_tool", vec![], Some("A simple tool with no parameters"));
         
-        let schema = interp.flow_to_tool_json(&flow);
+        let schema = interp.flow_to_tool_json(&flow, None);
         
         assert_eq!(schema["function"]["name"], "simple_tool");
         assert_eq!(schema["function"]["description"], "A simple tool with no parameters");
@@ -4153,7 +6628,7 @@ mod multi_turn_tests {
         let interp = create_test_interpreter();
         let flow = create_flow_def("read_file", vec![("path", "String"), ("encoding", "String")], Some("Read a file"));
         
-        let schema = interp.flow_to_tool_json(&flow);
+        let schema = interp.flow_to_tool_json(&flow, None);
         
         assert_eq!(schema["function"]["name"], "read_file");
         assert_eq!(schema["function"]["description"], "Read a file");
@@ -4174,7 +6649,7 @@ mod multi_turn_tests {
         let interp = create_test_interpreter();
         let flow = create_flow_def("mystery_tool", vec![("input", "Int")], None);
         
-        let schema = interp.flow_to_tool_json(&flow);
+        let schema = interp.flow_to_tool_json(&flow, None);
         
         assert_eq!(schema["function"]["name"], "mystery_tool");
         assert_eq!(schema["function"]["description"], "Flow 'mystery_tool'");
@@ -4212,11 +6687,11 @@ mod multi_turn_tests {
                     let id = block["id"].as_str().unwrap_or("call_0");
                     let name = block["name"].as_str().unwrap_or("").to_string();
                     let arguments = interp.json_to_value(block["input"].clone());
-                    tool_calls.push(Value::Map(vec![
+                    tool_calls.push(Value::Map(Entries::from([
                         ("id".to_string(), Value::String(id.to_string())),
                         ("name".to_string(), Value::String(name)),
                         ("arguments".to_string(), arguments),
-                    ]));
+                    ])));
                 }
                 _ => {}
             }
@@ -4264,11 +6739,11 @@ mod multi_turn_tests {
                     let id = block["id"].as_str().unwrap_or("call_0");
                     let name = block["name"].as_str().unwrap_or("").to_string();
                     let arguments = interp.json_to_value(block["input"].clone());
-                    tool_calls.push(Value::Map(vec![
+                    tool_calls.push(Value::Map(Entries::from([
                         ("id".to_string(), Value::String(id.to_string())),
                         ("name".to_string(), Value::String(name)),
                         ("arguments".to_string(), arguments),
-                    ]));
+                    ])));
                 }
                 _ => {}
             }
@@ -4279,13 +6754,13 @@ mod multi_turn_tests {
         assert_eq!(tool_calls.len(), 1);
         
         if let Value::Map(ref call) = tool_calls[0] {
-            let id = call.iter().find(|(k, _)| k == "id").unwrap().1.to_string();
-            let name = call.iter().find(|(k, _)| k == "name").unwrap().1.to_string();
+            let id = call["id"].to_string();
+            let name = call["name"].to_string();
             assert_eq!(id, "call_abc123");
             assert_eq!(name, "read_file");
             
-            if let Value::Map(ref args) = call.iter().find(|(k, _)| k == "arguments").unwrap().1 {
-                let path = args.iter().find(|(k, _)| k == "path").unwrap().1.to_string();
+            if let Value::Map(ref args) = call["arguments"] {
+                let path = args["path"].to_string();
                 assert_eq!(path, "main.py");
             }
         }
@@ -4336,11 +6811,11 @@ mod multi_turn_tests {
                     let id = block["id"].as_str().unwrap_or("call_0");
                     let name = block["name"].as_str().unwrap_or("").to_string();
                     let arguments = interp.json_to_value(block["input"].clone());
-                    tool_calls.push(Value::Map(vec![
+                    tool_calls.push(Value::Map(Entries::from([
                         ("id".to_string(), Value::String(id.to_string())),
                         ("name".to_string(), Value::String(name)),
                         ("arguments".to_string(), arguments),
-                    ]));
+                    ])));
                 }
                 _ => {}
             }
@@ -4349,12 +6824,12 @@ mod multi_turn_tests {
         assert_eq!(tool_calls.len(), 2);
         
         if let Value::Map(ref call1) = tool_calls[0] {
-            let id = call1.iter().find(|(k, _)| k == "id").unwrap().1.to_string();
+            let id = call1["id"].to_string();
             assert_eq!(id, "call_1");
         }
         
         if let Value::Map(ref call2) = tool_calls[1] {
-            let id = call2.iter().find(|(k, _)| k == "id").unwrap().1.to_string();
+            let id = call2["id"].to_string();
             assert_eq!(id, "call_2");
         }
     }
@@ -4366,10 +6841,10 @@ mod multi_turn_tests {
         let interp = create_test_interpreter();
         
         let tool_results = vec![
-            Value::Map(vec![
+            Value::Map(Entries::from([
                 ("tool_use_id".to_string(), Value::String("call_123".to_string())),
                 ("content".to_string(), Value::String("def main():\n    print('hello')".to_string())),
-            ])
+            ]))
         ];
 
         let messages = interp.build_messages_from_conversation(&[], "", Some(&tool_results)).unwrap();
@@ -4389,14 +6864,14 @@ mod multi_turn_tests {
         let interp = create_test_interpreter();
         
         let tool_results = vec![
-            Value::Map(vec![
+            Value::Map(Entries::from([
                 ("tool_use_id".to_string(), Value::String("call_1".to_string())),
                 ("content".to_string(), Value::String("content1".to_string())),
-            ]),
-            Value::Map(vec![
+            ])),
+            Value::Map(Entries::from([
                 ("tool_use_id".to_string(), Value::String("call_2".to_string())),
                 ("content".to_string(), Value::String("content2".to_string())),
-            ])
+            ]))
         ];
 
         let messages = interp.build_messages_from_conversation(&[], "", Some(&tool_results)).unwrap();
@@ -4416,10 +6891,10 @@ mod multi_turn_tests {
         let interp = create_test_interpreter();
         
         let tool_results = vec![
-            Value::Map(vec![
+            Value::Map(Entries::from([
                 ("tool_use_id".to_string(), Value::String("call_123".to_string())),
                 ("content".to_string(), Value::String("file content".to_string())),
-            ])
+            ]))
         ];
 
         let messages = interp.build_messages_from_conversation(&[], "Here's the result:", Some(&tool_results)).unwrap();
@@ -4447,32 +6922,32 @@ mod multi_turn_tests {
         let mut conversation = vec![];
         
         // Add user message
-        conversation.push(Value::Map(vec![
+        conversation.push(Value::Map(Entries::from([
             ("role".to_string(), Value::String("user".to_string())),
             ("content".to_string(), Value::String("Hello".to_string())),
-        ]));
+        ])));
         
         // Simulate adding assistant response
-        let assistant_msg = Value::Map(vec![
+        let assistant_msg = Value::Map(Entries::from([
             ("role".to_string(), Value::String("assistant".to_string())),
             ("content".to_string(), Value::String("Hi there!".to_string())),
             ("has_tool_calls".to_string(), Value::Bool(false)),
-        ]);
+        ]));
         conversation.push(assistant_msg);
         
         assert_eq!(conversation.len(), 2);
         
         // Add another turn
-        conversation.push(Value::Map(vec![
+        conversation.push(Value::Map(Entries::from([
             ("role".to_string(), Value::String("user".to_string())),
             ("content".to_string(), Value::String("How are you?".to_string())),
-        ]));
+        ])));
         
-        conversation.push(Value::Map(vec![
+        conversation.push(Value::Map(Entries::from([
             ("role".to_string(), Value::String("assistant".to_string())),
             ("content".to_string(), Value::String("I'm doing well!".to_string())),
             ("has_tool_calls".to_string(), Value::Bool(false)),
-        ]));
+        ])));
         
         assert_eq!(conversation.len(), 4);
     }
@@ -4482,46 +6957,46 @@ mod multi_turn_tests {
         let _interp = create_test_interpreter();
         
         let mut conversation = vec![
-            Value::Map(vec![
+            Value::Map(Entries::from([
                 ("role".to_string(), Value::String("user".to_string())),
                 ("content".to_string(), Value::String("Read main.py".to_string())),
-            ])
+            ]))
         ];
 
         // Add assistant response with tool calls
-        conversation.push(Value::Map(vec![
+        conversation.push(Value::Map(Entries::from([
             ("role".to_string(), Value::String("assistant".to_string())),
             ("content".to_string(), Value::String("I'll read that file.".to_string())),
             ("has_tool_calls".to_string(), Value::Bool(true)),
             ("tool_calls".to_string(), Value::List(vec![
-                Value::Map(vec![
+                Value::Map(Entries::from([
                     ("id".to_string(), Value::String("call_123".to_string())),
                     ("name".to_string(), Value::String("read_file".to_string())),
-                    ("arguments".to_string(), Value::Map(vec![
+                    ("arguments".to_string(), Value::Map(Entries::from([
                         ("path".to_string(), Value::String("main.py".to_string()))
-                    ])),
-                ])
+                    ]))),
+                ]))
             ])),
-        ]));
+        ])));
 
         // Add user message with tool results
-        conversation.push(Value::Map(vec![
+        conversation.push(Value::Map(Entries::from([
             ("role".to_string(), Value::String("user".to_string())),
             ("content".to_string(), Value::List(vec![
-                Value::Map(vec![
+                Value::Map(Entries::from([
                     ("type".to_string(), Value::String("tool_result".to_string())),
                     ("tool_use_id".to_string(), Value::String("call_123".to_string())),
                     ("content".to_string(), Value::String("def main(): pass".to_string())),
-                ])
+                ]))
             ])),
-        ]));
+        ])));
 
         // Add final assistant response
-        conversation.push(Value::Map(vec![
+        conversation.push(Value::Map(Entries::from([
             ("role".to_string(), Value::String("assistant".to_string())),
             ("content".to_string(), Value::String("The file contains a simple main function.".to_string())),
             ("has_tool_calls".to_string(), Value::Bool(false)),
-        ]));
+        ])));
 
         // A complete tool-use turn should add exactly 4 messages:
         // 1. User request, 2. Assistant with tool calls, 3. User with tool results, 4. Assistant final response
@@ -4571,11 +7046,11 @@ mod multi_turn_tests {
                 let id = block["id"].as_str().unwrap_or("call_0");
                 let name = block["name"].as_str().unwrap_or("").to_string();
                 let arguments = interp.json_to_value(block["input"].clone());
-                tool_calls.push(Value::Map(vec![
+                tool_calls.push(Value::Map(Entries::from([
                     ("id".to_string(), Value::String(id.to_string())),
                     ("name".to_string(), Value::String(name)),
                     ("arguments".to_string(), arguments),
-                ]));
+                ])));
             }
         }
 
@@ -4587,10 +7062,10 @@ mod multi_turn_tests {
         let interp = create_test_interpreter();
         
         let tool_results = vec![
-            Value::Map(vec![
+            Value::Map(Entries::from([
                 ("tool_use_id".to_string(), Value::String("call_123".to_string())),
                 ("content".to_string(), Value::String("".to_string())), // Empty result
-            ])
+            ]))
         ];
 
         let messages = interp.build_messages_from_conversation(&[], "", Some(&tool_results)).unwrap();
@@ -4607,10 +7082,10 @@ mod multi_turn_tests {
         
         let long_content = "a".repeat(10000); // 10k characters
         let tool_results = vec![
-            Value::Map(vec![
+            Value::Map(Entries::from([
                 ("tool_use_id".to_string(), Value::String("call_123".to_string())),
                 ("content".to_string(), Value::String(long_content.clone())),
-            ])
+            ]))
         ];
 
         let messages = interp.build_messages_from_conversation(&[], "", Some(&tool_results)).unwrap();
@@ -4626,10 +7101,10 @@ mod multi_turn_tests {
         
         // Test conversation with missing role
         let malformed_conversation = vec![
-            Value::Map(vec![
+            Value::Map(Entries::from([
                 ("content".to_string(), Value::String("Hello".to_string())),
                 // Missing "role" field
-            ])
+            ]))
         ];
 
         let messages = interp.build_messages_from_conversation(&malformed_conversation, "Hi", None).unwrap();
@@ -4646,10 +7121,10 @@ mod multi_turn_tests {
         
         // Tool result missing tool_use_id
         let malformed_tool_results = vec![
-            Value::Map(vec![
+            Value::Map(Entries::from([
                 ("content".to_string(), Value::String("some content".to_string())),
                 // Missing "tool_use_id" field
-            ])
+            ]))
         ];
 
         let messages = interp.build_messages_from_conversation(&[], "", Some(&malformed_tool_results)).unwrap();
@@ -4666,7 +7141,7 @@ mod multi_turn_tests {
         let interp = create_test_interpreter();
         
         // Test complex nested structure
-        let original = Value::Map(vec![
+        let original = Value::Map(Entries::from([
             ("text".to_string(), Value::String("hello".to_string())),
             ("number".to_string(), Value::Int(42)),
             ("list".to_string(), Value::List(vec![
@@ -4674,10 +7149,10 @@ mod multi_turn_tests {
                 Value::Int(123),
                 Value::Bool(true)
             ])),
-            ("nested".to_string(), Value::Map(vec![
+            ("nested".to_string(), Value::Map(Entries::from([
                 ("inner".to_string(), Value::String("value".to_string()))
-            ]))
-        ]);
+            ])))
+        ]));
 
         // Convert to JSON and back
         let json = interp.value_to_json(&original);
@@ -4690,7 +7165,7 @@ mod multi_turn_tests {
                 
                 // Check each field
                 for (key, orig_val) in orig_map {
-                    let rt_val = &rt_map.iter().find(|(k, _)| k == key).unwrap().1;
+                    let rt_val = &rt_map[key];
                     // For this test, just verify the structure is preserved
                     assert_eq!(std::mem::discriminant(orig_val), std::mem::discriminant(rt_val));
                 }
@@ -4699,4 +7174,81 @@ mod multi_turn_tests {
             }
         }
     }
+
+    #[test]
+    fn test_truncate_tool_result_keeps_head_and_tail() {
+        assert_eq!(truncate_tool_result("short", 10), None);
+        let text = format!("{}{}", "a".repeat(500), "z".repeat(500));
+        let cut = truncate_tool_result(&text, 300).unwrap();
+        assert!(cut.starts_with(&"a".repeat(200)));
+        assert!(cut.ends_with(&"z".repeat(100)));
+        assert!(cut.contains("[700 of 1000 chars elided]"), "got: {}", cut);
+        // Multi-byte chars are never split
+        let cut = truncate_tool_result(&"é".repeat(50), 10).unwrap();
+        assert!(cut.starts_with("éééééé\n"));
+    }
+
+    #[test]
+    fn test_tool_call_name_finds_latest_assistant_call() {
+        let call = |id: &str, name: &str| Value::Map(Entries::from([
+            ("id".to_string(), Value::String(id.to_string())),
+            ("name".to_string(), Value::String(name.to_string())),
+        ]));
+        let conversation = vec![Value::Map(Entries::from([
+            ("role".to_string(), Value::String("assistant".to_string())),
+            ("tool_calls".to_string(), Value::List(vec![call("t1", "shell"), call("t2", "fetch_page")])),
+        ]))];
+        assert_eq!(tool_call_name(&conversation, "t2").as_deref(), Some("fetch_page"));
+        assert_eq!(tool_call_name(&conversation, "t9"), None);
+    }
+
+    #[test]
+    fn test_match_label_tolerates_case_and_punctuation() {
+        let labels = vec!["bug".to_string(), "Feature".to_string()];
+        assert_eq!(match_label("Bug.", &labels).map(|s| s.as_str()), Some("bug"));
+        assert_eq!(match_label(" \"feature\"\n", &labels).map(|s| s.as_str()), Some("Feature"));
+        assert_eq!(match_label("It is a bug", &labels), None);
+    }
+
+    #[test]
+    fn test_chunk_text_prefers_paragraph_then_line_breaks() {
+        let chars = |s: &str| s.chars().count();
+        assert_eq!(chunk_text("short", 100, &chars), vec!["short"]);
+        assert!(chunk_text("  \n", 100, &chars).is_empty());
+        let chunks = chunk_text("aaaa\n\nbbbb\n\ncccc", 12, &chars);
+        assert_eq!(chunks, vec!["aaaa\n\nbbbb\n\n", "cccc"]);
+        let chunks = chunk_text("one line\ntwo line\nthree", 10, &chars);
+        assert_eq!(chunks, vec!["one line\n", "two line\n", "three"]);
+        let chunks = chunk_text("alpha beta gamma delta", 12, &chars);
+        assert_eq!(chunks, vec!["alpha beta ", "gamma delta"]);
+        let chunks = chunk_text(&"é".repeat(25), 10, &chars);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks.concat(), "é".repeat(25));
+    }
+
+    #[test]
+    fn test_overlap_chunks_repeat_previous_tail_from_a_word_start() {
+        let chars = |s: &str| s.chars().count();
+        let chunks = vec!["one two three ".to_string(), "four five".to_string()];
+        assert_eq!(overlap_chunks(chunks, 8, &chars), vec!["one two three ", "three four five"]);
+        assert!(chunk_tokens("qwen2.5:7b", "text", 10, 10).is_err());
+    }
+
+    #[test]
+    fn test_session_keeps_conversation_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("s.json");
+        let path = path.to_str().unwrap();
+        let mut interp = create_test_interpreter();
+        interp.vars.insert("count".into(), Value::Int(2));
+        interp.conversation_history.push(("user".to_string(), "hi".to_string()));
+        interp.conversation_history.push(("assistant".to_string(), "hello".to_string()));
+        interp.save_session(path).unwrap();
+
+        let mut restored = create_test_interpreter();
+        restored.load_session(path).unwrap();
+        assert_eq!(restored.conversation_history, interp.conversation_history);
+        assert!(matches!(restored.vars.get("count"), Some(Value::Int(2))));
+        assert!(!restored.vars.contains_key(HISTORY_KEY));
+    }
 }
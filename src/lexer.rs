@@ -1,7 +1,8 @@
-/// Indentation-aware lexer for Cognos.
-/// Produces Indent/Dedent tokens based on leading whitespace (Python-style).
+//! Indentation-aware lexer for Cognos.
+//! Produces Indent/Dedent tokens based on leading whitespace (Python-style).
 
-use crate::token::{Token, Spanned};
+use crate::token::{Comment, Token, Spanned, Name};
+use std::collections::HashSet;
 
 pub struct Lexer {
     source: Vec<char>,
@@ -12,6 +13,10 @@ pub struct Lexer {
     pending: Vec<Spanned>,
     at_line_start: bool,
     bracket_depth: usize,
+    names: HashSet<Name>,
+    comments: Vec<Comment>,
+    /// Where each `"""` string or f-string token starts.
+    triple_quoted: Vec<(usize, usize)>,
 }
 
 impl Lexer {
@@ -25,9 +30,22 @@ impl Lexer {
             pending: Vec::new(),
             at_line_start: true,
             bracket_depth: 0,
+            names: HashSet::new(),
+            comments: Vec::new(),
+            triple_quoted: Vec::new(),
         }
     }
 
+    /// The comments `tokenize` skipped, in source order.
+    pub fn comments(&self) -> &[Comment] {
+        &self.comments
+    }
+
+    /// Line and column of the triple-quoted string tokens.
+    pub fn triple_quoted(&self) -> &[(usize, usize)] {
+        &self.triple_quoted
+    }
+
     pub fn tokenize(&mut self) -> Vec<Spanned> {
         let mut tokens = Vec::new();
 
@@ -56,6 +74,9 @@ impl Lexer {
                 }
                 if peek >= self.source.len() || self.source[peek] == '\n' || self.source[peek] == '#' {
                     // Blank or comment-only line — skip entire line
+                    if peek < self.source.len() && self.source[peek] == '#' {
+                        self.comment(peek, self.col + (peek - self.pos));
+                    }
                     while self.pos < self.source.len() && self.source[self.pos] != '\n' {
                         self.advance();
                     }
@@ -80,6 +101,7 @@ impl Lexer {
 
             // Comments
             if ch == '#' {
+                self.comment(self.pos, self.col);
                 while self.pos < self.source.len() && self.source[self.pos] != '\n' {
                     self.advance();
                 }
@@ -100,7 +122,7 @@ impl Lexer {
 
             // String literals
             if ch == '"' {
-                tokens.push(self.read_string());
+                tokens.push(self.read_string(false));
                 continue;
             }
 
@@ -116,6 +138,13 @@ impl Lexer {
                 continue;
             }
 
+            // Raw string: r"..." keeps backslashes as written
+            if ch == 'r' && self.pos + 1 < self.source.len() && self.source[self.pos + 1] == '"' {
+                self.advance(); // skip 'r'
+                tokens.push(self.read_string(true));
+                continue;
+            }
+
             // Identifiers and keywords
             if ch.is_alphabetic() || ch == '_' {
                 tokens.push(self.read_ident());
@@ -132,6 +161,7 @@ impl Lexer {
                     ('>', '=') => Some(Token::GtEq),
                     ('-', '>') => Some(Token::Arrow),
                     ('=', '>') => Some(Token::FatArrow),
+                    ('/', '/') => Some(Token::SlashSlash),
                     _ => None,
                 };
                 if let Some(tok) = two {
@@ -177,6 +207,15 @@ impl Lexer {
         tokens
     }
 
+    /// Record the comment whose `#` is at `pos`.
+    fn comment(&mut self, pos: usize, col: usize) {
+        let end = self.source[pos..].iter().position(|&c| c == '\n').map_or(self.source.len(), |n| pos + n);
+        let text: String = self.source[pos + 1..end].iter().collect();
+        let line_start = self.source[..pos].iter().rposition(|&c| c == '\n').map_or(0, |n| n + 1);
+        let own_line = self.source[line_start..pos].iter().all(|&c| c == ' ' || c == '\t');
+        self.comments.push(Comment { line: self.line, col, text: text.trim_end().to_string(), own_line });
+    }
+
     fn handle_indentation(&mut self, tokens: &mut Vec<Spanned>) {
         // Skip blank lines
         let _start = self.pos;
@@ -203,54 +242,17 @@ impl Lexer {
         }
     }
 
-    fn read_string(&mut self) -> Spanned {
+    /// `"..."` or `"""..."""`; `raw` strings (after an `r` prefix) take
+    /// backslashes literally, so `r"\d+"` is the three characters `\d+`.
+    fn read_string(&mut self, raw: bool) -> Spanned {
         let line = self.line;
-        let col = self.col;
-        self.advance(); // skip opening "
-
-        // Check for triple-quoted string """..."""
-        let triple = self.pos + 1 < self.source.len()
-            && self.source[self.pos] == '"'
-            && self.source[self.pos + 1] == '"';
-        if triple {
-            self.advance(); // skip second "
-            self.advance(); // skip third "
-        }
-
-        let mut s = String::new();
-        while self.pos < self.source.len() {
-            if triple {
-                // Look for closing """
-                if self.source[self.pos] == '"'
-                    && self.pos + 2 < self.source.len()
-                    && self.source[self.pos + 1] == '"'
-                    && self.source[self.pos + 2] == '"'
-                {
-                    self.advance(); // skip first "
-                    self.advance(); // skip second "
-                    self.advance(); // skip third "
-                    return Spanned { token: Token::StringLit(s), line, col };
-                }
-            } else if self.source[self.pos] == '"' {
-                break;
-            }
-            if self.source[self.pos] == '\\' && self.pos + 1 < self.source.len() {
-                self.advance();
-                match self.source[self.pos] {
-                    'n' => s.push('\n'),
-                    't' => s.push('\t'),
-                    '"' => s.push('"'),
-                    '\\' => s.push('\\'),
-                    c => { s.push('\\'); s.push(c); }
-                }
-            } else {
-                s.push(self.source[self.pos]);
-            }
-            self.advance();
-        }
-        if self.pos < self.source.len() {
-            self.advance(); // skip closing "
+        // Report the token at its `r` prefix
+        let col = if raw { self.col - 1 } else { self.col };
+        if self.source[self.pos..].starts_with(&['"'; 3]) {
+            self.triple_quoted.push((line, col));
         }
+        let text = self.read_quoted(raw);
+        let s = if raw { text } else { unescape(&text, false) };
         Spanned { token: Token::StringLit(s), line, col }
     }
 
@@ -258,9 +260,20 @@ impl Lexer {
         let line = self.line;
         let col = self.col;
         self.advance(); // skip 'f'
-        self.advance(); // skip opening '"'
+        if self.source[self.pos..].starts_with(&['"'; 3]) {
+            self.triple_quoted.push((line, col));
+        }
+        let text = self.read_quoted(false);
+        Spanned { token: Token::FStringLit(unescape(&text, true)), line, col }
+    }
 
-        // Check for triple-quoted f-string f"""..."""
+    /// The text of a `"..."` or `"""..."""` literal starting at its opening
+    /// quote, as written (escapes are left for `unescape`). Triple-quoted
+    /// text is dedented. Leaves `pos` after the closing quote.
+    fn read_quoted(&mut self, raw: bool) -> String {
+        self.advance(); // skip opening "
+
+        // Check for triple-quoted string """..."""
         let triple = self.pos + 1 < self.source.len()
             && self.source[self.pos] == '"'
             && self.source[self.pos + 1] == '"';
@@ -281,49 +294,82 @@ impl Lexer {
                     self.advance(); // skip first "
                     self.advance(); // skip second "
                     self.advance(); // skip third "
-                    return Spanned { token: Token::FStringLit(s), line, col };
+                    return dedent(&s);
                 }
             } else if self.source[self.pos] == '"' {
-                break;
+                self.advance(); // skip closing "
+                return s;
             }
-            if self.source[self.pos] == '\\' && self.pos + 1 < self.source.len() {
+            // Keep an escape with its character, so \" doesn't end the string
+            if !raw && self.source[self.pos] == '\\' && self.pos + 1 < self.source.len() {
+                s.push('\\');
                 self.advance();
-                match self.source[self.pos] {
-                    'n' => s.push('\n'),
-                    't' => s.push('\t'),
-                    '"' => s.push('"'),
-                    '\\' => s.push('\\'),
-                    '{' => s.push('{'),
-                    '}' => s.push('}'),
-                    c => { s.push('\\'); s.push(c); }
-                }
-            } else {
-                s.push(self.source[self.pos]);
             }
+            s.push(self.source[self.pos]);
             self.advance();
         }
-        if self.pos < self.source.len() {
-            self.advance(); // skip closing '"'
-        }
-        Spanned { token: Token::FStringLit(s), line, col }
+        if triple { dedent(&s) } else { s }
     }
 
+    /// `42`, `1_000_000`, `2.5`, `1.5e6`, `3e-4` or `0xFF`. Underscores are
+    /// only for readability.
     fn read_number(&mut self) -> Spanned {
         let line = self.line;
         let col = self.col;
+        let peek = |lexer: &Self, ahead: usize| lexer.source.get(lexer.pos + ahead).copied();
         let mut s = String::new();
+
+        if peek(self, 0) == Some('0') && matches!(peek(self, 1), Some('x' | 'X')) {
+            self.advance();
+            self.advance();
+            while peek(self, 0).is_some_and(|c| c.is_ascii_hexdigit() || c == '_') {
+                s.push(self.source[self.pos]);
+                self.advance();
+            }
+            let digits = s.replace('_', "");
+            let token = match i64::from_str_radix(&digits, 16) {
+                Ok(n) => Token::IntLit(n),
+                Err(_) => Token::BadNumber(format!("0x{}", s)),
+            };
+            return Spanned { token, line, col };
+        }
+
         let mut is_float = false;
-        while self.pos < self.source.len() && (self.source[self.pos].is_ascii_digit() || self.source[self.pos] == '.') {
+        while peek(self, 0).is_some_and(|c| c.is_ascii_digit() || c == '.' || c == '_') {
             if self.source[self.pos] == '.' {
                 is_float = true;
             }
             s.push(self.source[self.pos]);
             self.advance();
         }
+        // Exponent, only when digits follow: 1.5e6, 2E-3
+        let exponent_digit = |ahead: usize| peek(self, ahead).is_some_and(|c| c.is_ascii_digit());
+        if matches!(peek(self, 0), Some('e' | 'E'))
+            && (exponent_digit(1) || (matches!(peek(self, 1), Some('+' | '-')) && exponent_digit(2)))
+        {
+            is_float = true;
+            s.push(self.source[self.pos]);
+            self.advance();
+            if matches!(peek(self, 0), Some('+' | '-')) {
+                s.push(self.source[self.pos]);
+                self.advance();
+            }
+            while peek(self, 0).is_some_and(|c| c.is_ascii_digit() || c == '_') {
+                s.push(self.source[self.pos]);
+                self.advance();
+            }
+        }
+        let digits = s.replace('_', "");
         let token = if is_float {
-            Token::FloatLit(s.parse().unwrap_or(0.0))
+            match digits.parse::<f64>() {
+                Ok(n) if n.is_finite() => Token::FloatLit(n),
+                _ => Token::BadNumber(s),
+            }
         } else {
-            Token::IntLit(s.parse().unwrap_or(0))
+            match digits.parse() {
+                Ok(n) => Token::IntLit(n),
+                Err(_) => Token::BadNumber(s),
+            }
         };
         Spanned { token, line, col }
     }
@@ -343,6 +389,7 @@ impl Lexer {
             "else" => Token::Else,
             "elif" => Token::Elif,
             "loop" => Token::Loop,
+            "while" => Token::While,
             "break" => Token::Break,
             "continue" => Token::Continue,
             "return" => Token::Return,
@@ -364,11 +411,21 @@ impl Lexer {
             "none" => Token::None_,
             "pass" => Token::Pass,
             "select" => Token::Select,
-            _ => Token::Ident(s),
+            "with" => Token::With,
+            _ => Token::Ident(self.intern(&s)),
         };
         Spanned { token, line, col }
     }
 
+    fn intern(&mut self, s: &str) -> Name {
+        if let Some(name) = self.names.get(s) {
+            return name.clone();
+        }
+        let name: Name = Name::from(s);
+        self.names.insert(name.clone());
+        name
+    }
+
     fn advance(&mut self) {
         if self.pos < self.source.len() {
             if self.source[self.pos] == '\n' {
@@ -386,6 +443,54 @@ impl Lexer {
     }
 }
 
+/// Process `\n`, `\t`, `\"` and `\\` escapes; other backslashes stay as
+/// written. In f-strings `\{` and `\}` become `{{` and `}}`, which the
+/// f-string parser keeps as literal braces.
+fn unescape(text: &str, fstring: bool) -> String {
+    let mut s = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            s.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => s.push('\n'),
+            Some('t') => s.push('\t'),
+            Some('"') => s.push('"'),
+            Some('\\') => s.push('\\'),
+            Some(c @ ('{' | '}')) if fstring => {
+                s.push(c);
+                s.push(c);
+            }
+            Some(c) => {
+                s.push('\\');
+                s.push(c);
+            }
+            None => s.push('\\'),
+        }
+    }
+    s
+}
+
+/// A triple-quoted string whose text starts on the line after the opening
+/// quotes can be indented with the surrounding code: the first newline, the
+/// lines' common indentation and a last line holding only the closing quotes
+/// are dropped. Text that starts on the opening line is kept as written.
+fn dedent(text: &str) -> String {
+    let Some(body) = text.strip_prefix('\n') else { return text.to_string() };
+    let mut lines: Vec<&str> = body.split('\n').collect();
+    if lines.len() > 1 && lines.last().is_some_and(|l| l.trim().is_empty()) {
+        lines.pop();
+    }
+    let indent_of = |l: &str| l.len() - l.trim_start_matches([' ', '\t']).len();
+    let indent = lines.iter().filter(|l| !l.trim().is_empty()).map(|l| indent_of(l)).min().unwrap_or(0);
+    lines.iter()
+        .map(|l| if l.trim().is_empty() { "" } else { &l[indent..] })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -422,6 +527,41 @@ mod tests {
     }
 
     #[test]
+    fn test_raw_and_triple_quoted_strings() {
+        let source = "a = r\"\\d+\\n\"\nb = \"\"\"line one\n  \"quoted\" two\"\"\"\nc = r\"\"\"C:\\tmp \"x\" y\"\"\"\n";
+        let tokens: Vec<Token> = Lexer::new(source).tokenize().into_iter().map(|s| s.token).collect();
+        assert!(tokens.contains(&Token::StringLit("\\d+\\n".into())));
+        assert!(tokens.contains(&Token::StringLit("line one\n  \"quoted\" two".into())));
+        assert!(tokens.contains(&Token::StringLit("C:\\tmp \"x\" y".into())));
+        // `r` alone is still an identifier
+        let tokens: Vec<Token> = Lexer::new("r = 1").tokenize().into_iter().map(|s| s.token).collect();
+        assert!(matches!(&tokens[0], Token::Ident(_)));
+    }
+
+    #[test]
+    fn test_triple_quoted_strings_dedent() {
+        let source = "flow main:\n    p = \"\"\"\n        Review:\n          - \\\"{x}\\\"\n\n        Done.\n        \"\"\"\n    q = \"\"\"  as written\n  kept\"\"\"\n";
+        let tokens: Vec<Token> = Lexer::new(source).tokenize().into_iter().map(|s| s.token).collect();
+        assert!(tokens.contains(&Token::StringLit("Review:\n  - \"{x}\"\n\nDone.".into())), "{:?}", tokens);
+        assert!(tokens.contains(&Token::StringLit("  as written\n  kept".into())));
+    }
+
+    #[test]
+    fn test_number_literals() {
+        let tokens: Vec<Token> = Lexer::new("1_000_000 0xFF 0x_ff_ff 1.5e6 2E-3 1_0.2_5 7e+2 3.e").tokenize()
+            .into_iter().map(|s| s.token).collect();
+        assert_eq!(&tokens[..8], &[
+            Token::IntLit(1_000_000), Token::IntLit(255), Token::IntLit(0xffff), Token::FloatLit(1.5e6),
+            Token::FloatLit(0.002), Token::FloatLit(10.25), Token::FloatLit(700.0), Token::FloatLit(3.0),
+        ]);
+        // `e` without digits isn't an exponent
+        assert!(matches!(&tokens[8], Token::Ident(_)));
+        let tokens: Vec<Token> = Lexer::new("0x 99999999999999999999").tokenize().into_iter().map(|s| s.token).collect();
+        assert_eq!(&tokens[..2], &[Token::BadNumber("0x".into()), Token::BadNumber("99999999999999999999".into())]);
+    }
+
+    #[test]
+    #[allow(clippy::approx_constant)]
     fn test_string_and_numbers() {
         let source = r#"x = "hello world"
 y = 42
@@ -436,13 +576,15 @@ z = 3.14
 
     #[test]
     fn test_operators() {
-        let source = "a == b != c -> d => e";
+        let source = "a == b != c -> d => e // f / g";
         let mut lexer = Lexer::new(source);
         let tokens: Vec<Token> = lexer.tokenize().into_iter().map(|s| s.token).collect();
         assert!(tokens.contains(&Token::EqEq));
         assert!(tokens.contains(&Token::NotEq));
         assert!(tokens.contains(&Token::Arrow));
         assert!(tokens.contains(&Token::FatArrow));
+        assert_eq!(tokens.iter().filter(|t| **t == Token::SlashSlash).count(), 1);
+        assert_eq!(tokens.iter().filter(|t| **t == Token::Slash).count(), 1);
     }
 
     #[test]
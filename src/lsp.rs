@@ -0,0 +1,365 @@
+//! `cognos lsp` — a Language Server for editors, speaking JSON-RPC over
+//! stdin/stdout.
+//!
+//! Every change re-lexes and re-parses the whole document. Parse errors
+//! become diagnostics; go-to-definition and completion work from the token
+//! stream, whose tokens carry their line and column, so they keep working
+//! while the file has a syntax error. Hover shows flow signatures from the
+//! last version of the document that parsed.
+
+use crate::ast::Program;
+use crate::error::CognosError;
+use crate::lexer::Lexer;
+use crate::parser::Parser;
+use crate::token::{Spanned, Token};
+use anyhow::{bail, Result};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+
+/// LSP `CompletionItemKind`s.
+const KIND_FUNCTION: u32 = 3;
+const KIND_ENUM: u32 = 13;
+const KIND_STRUCT: u32 = 22;
+
+pub fn run() -> Result<()> {
+    let mut input = std::io::stdin().lock();
+    let mut out = std::io::stdout().lock();
+    let mut server = Server::default();
+    while let Some(msg) = read_message(&mut input)? {
+        if msg["method"] == "exit" {
+            if !server.shutdown {
+                bail!("lsp: exit without shutdown");
+            }
+            return Ok(());
+        }
+        for reply in server.handle(&msg) {
+            write_message(&mut out, &reply)?;
+        }
+    }
+    Ok(())
+}
+
+/// One message: `Content-Length` and other headers, a blank line, then the
+/// JSON body. `None` at the end of input.
+fn read_message(input: &mut impl BufRead) -> Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = Some(value.trim().parse::<usize>()?);
+            }
+        }
+    }
+    let Some(length) = length else { bail!("lsp: message without a Content-Length header") };
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+fn write_message(out: &mut impl Write, msg: &Value) -> Result<()> {
+    let body = msg.to_string();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    out.flush()?;
+    Ok(())
+}
+
+struct Document {
+    text: String,
+    /// The last version of the text that parsed.
+    program: Option<Program>,
+}
+
+#[derive(Default)]
+struct Server {
+    docs: HashMap<String, Document>,
+    shutdown: bool,
+}
+
+impl Server {
+    /// The responses and notifications to send for one client message.
+    fn handle(&mut self, msg: &Value) -> Vec<Value> {
+        let method = msg["method"].as_str().unwrap_or_default();
+        let params = &msg["params"];
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default().to_string();
+        let result = match method {
+            "initialize" => Some(json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "hoverProvider": true,
+                    "definitionProvider": true,
+                    "completionProvider": {},
+                },
+                "serverInfo": {"name": "cognos", "version": env!("CARGO_PKG_VERSION")},
+            })),
+            "shutdown" => {
+                self.shutdown = true;
+                Some(Value::Null)
+            }
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                return vec![self.update(uri, text.to_string())];
+            }
+            "textDocument/didChange" => {
+                // Full sync: the last change holds the whole text
+                let Some(text) = params["contentChanges"].as_array().and_then(|c| c.last()).and_then(|c| c["text"].as_str()) else {
+                    return Vec::new();
+                };
+                return vec![self.update(uri, text.to_string())];
+            }
+            "textDocument/didClose" => {
+                self.docs.remove(&uri);
+                return vec![publish(&uri, Vec::new())];
+            }
+            "textDocument/hover" => Some(self.hover(&uri, &params["position"])),
+            "textDocument/definition" => Some(self.definition(&uri, &params["position"])),
+            "textDocument/completion" => Some(self.completion(&uri)),
+            _ => None,
+        };
+        let Some(id) = msg.get("id") else { return Vec::new() };
+        match result {
+            Some(result) => vec![json!({"jsonrpc": "2.0", "id": id, "result": result})],
+            None => vec![json!({
+                "jsonrpc": "2.0", "id": id,
+                "error": {"code": -32601, "message": format!("method not found: {}", method)},
+            })],
+        }
+    }
+
+    /// Store a document's new text and publish its diagnostics.
+    fn update(&mut self, uri: String, text: String) -> Value {
+        let parsed = parse(&uri, &text);
+        let diagnostics = match &parsed {
+            Ok(_) => Vec::new(),
+            Err(e) => vec![diagnostic(&text, e)],
+        };
+        let doc = self.docs.entry(uri.clone()).or_insert(Document { text: String::new(), program: None });
+        doc.text = text;
+        if let Ok(program) = parsed {
+            doc.program = Some(program);
+        }
+        publish(&uri, diagnostics)
+    }
+
+    /// The document, its tokens and the identifier at `position`.
+    fn ident_at(&self, uri: &str, position: &Value) -> Option<(&Document, Vec<Spanned>, usize)> {
+        let doc = self.docs.get(uri)?;
+        let (line, col) = from_position(&doc.text, position);
+        let tokens = Lexer::new(&doc.text).tokenize();
+        let i = tokens.iter().position(|t| match &t.token {
+            // The cursor just past the name still counts as on it
+            Token::Ident(name) => t.line == line && (t.col..=t.col + name.chars().count()).contains(&col),
+            _ => false,
+        })?;
+        Some((doc, tokens, i))
+    }
+
+    fn hover(&self, uri: &str, position: &Value) -> Value {
+        let Some((doc, tokens, i)) = self.ident_at(uri, position) else { return Value::Null };
+        let Token::Ident(name) = &tokens[i].token else { return Value::Null };
+        let program = doc.program.as_ref();
+        let text = if let Some(flow) = program.and_then(|p| p.flows.iter().find(|f| *f.name == **name)) {
+            let signature = format!("```cognos\n{}\n```", crate::pretty::flow_signature(flow));
+            match &flow.description {
+                Some(doc) => format!("{}\n\n{}", signature, doc),
+                None => signature,
+            }
+        } else if let Some(td) = program.and_then(|p| p.types.iter().find(|t| t.name() == &**name)) {
+            let one = Program { imports: Vec::new(), types: vec![td.clone()], flows: Vec::new(), setup: Vec::new() };
+            format!("```cognos\n{}\n```", crate::pretty::pretty_program(&one).trim_end())
+        } else if let Some(b) = crate::help::lookup(name) {
+            format!("```cognos\n{}\n```\n\n{}", b.signature, b.summary)
+        } else {
+            return Value::Null;
+        };
+        json!({"contents": {"kind": "markdown", "value": text}, "range": range(&doc.text, &tokens[i])})
+    }
+
+    fn definition(&self, uri: &str, position: &Value) -> Value {
+        let Some((doc, tokens, i)) = self.ident_at(uri, position) else { return Value::Null };
+        let Token::Ident(name) = &tokens[i].token else { return Value::Null };
+        match definitions(&tokens).get(&**name) {
+            Some(def) => json!({"uri": uri, "range": range(&doc.text, def)}),
+            None => Value::Null,
+        }
+    }
+
+    /// Built-ins, then the document's own flows and types.
+    fn completion(&self, uri: &str) -> Value {
+        let mut items: Vec<Value> = crate::help::BUILTINS.iter().map(|b| json!({
+            "label": b.name, "kind": KIND_FUNCTION, "detail": b.signature, "documentation": b.summary,
+        })).collect();
+        if let Some(doc) = self.docs.get(uri) {
+            let tokens = Lexer::new(&doc.text).tokenize();
+            let mut defs: Vec<_> = definitions(&tokens).into_iter().collect();
+            defs.sort_by_key(|(name, _)| *name);
+            for (name, def) in defs {
+                let is_flow = tokens.iter().position(|t| std::ptr::eq(t, def))
+                    .is_some_and(|i| i > 0 && tokens[i - 1].token == Token::Flow);
+                let kind = if is_flow { KIND_FUNCTION } else {
+                    let is_enum = doc.program.as_ref()
+                        .and_then(|p| p.types.iter().find(|t| t.name() == name))
+                        .is_some_and(|t| matches!(t, crate::ast::TypeDef::Enum { .. }));
+                    if is_enum { KIND_ENUM } else { KIND_STRUCT }
+                };
+                let mut item = json!({"label": name, "kind": kind});
+                if let Some(flow) = doc.program.as_ref().and_then(|p| p.flows.iter().find(|f| f.name == name)) {
+                    item["detail"] = crate::pretty::flow_signature(flow).into();
+                }
+                items.push(item);
+            }
+        }
+        Value::Array(items)
+    }
+}
+
+fn publish(uri: &str, diagnostics: Vec<Value>) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": {"uri": uri, "diagnostics": diagnostics},
+    })
+}
+
+fn parse(uri: &str, text: &str) -> Result<Program> {
+    let mut parser = Parser::new(Lexer::new(text).tokenize());
+    // `resource()` paths resolve against the file, as for `cognos run`
+    if let Some(path) = uri.strip_prefix("file://") {
+        parser = parser.for_file(std::path::Path::new(&*urlencoding::decode(path)?));
+    }
+    parser.parse_program()
+}
+
+/// A parse error as an LSP diagnostic covering the line it names.
+fn diagnostic(text: &str, err: &anyhow::Error) -> Value {
+    let (line, message) = match err.downcast_ref::<CognosError>() {
+        Some(e) => (e.line, match &e.hint {
+            Some(hint) => format!("{}\nhint: {}", e.message, hint),
+            None => e.message.clone(),
+        }),
+        None => (0, err.to_string()),
+    };
+    let line = line.max(1) - 1;
+    let code = text.lines().nth(line).unwrap_or_default();
+    let indent = code.len() - code.trim_start().len();
+    json!({
+        "range": {
+            "start": {"line": line, "character": code[..indent].encode_utf16().count()},
+            "end": {"line": line, "character": code.encode_utf16().count()},
+        },
+        "severity": 1,
+        "source": "cognos",
+        "message": message,
+    })
+}
+
+/// The token naming each flow and type defined, by name.
+fn definitions(tokens: &[Spanned]) -> HashMap<&str, &Spanned> {
+    tokens.windows(2).filter_map(|w| match (&w[0].token, &w[1].token) {
+        (Token::Flow | Token::Type, Token::Ident(name)) => Some((&**name, &w[1])),
+        _ => None,
+    }).collect()
+}
+
+/// The LSP range of an identifier token.
+fn range(text: &str, token: &Spanned) -> Value {
+    let len = match &token.token {
+        Token::Ident(name) => name.chars().count(),
+        _ => 0,
+    };
+    json!({"start": position(text, token.line, token.col), "end": position(text, token.line, token.col + len)})
+}
+
+/// The LSP position (0-based line, UTF-16 offset) of a 1-based line and
+/// char column.
+fn position(text: &str, line: usize, col: usize) -> Value {
+    let code = text.lines().nth(line - 1).unwrap_or_default();
+    let character: usize = code.chars().take(col - 1).map(char::len_utf16).sum();
+    json!({"line": line - 1, "character": character})
+}
+
+/// The 1-based line and char column of an LSP position.
+fn from_position(text: &str, position: &Value) -> (usize, usize) {
+    let line = position["line"].as_u64().unwrap_or(0) as usize;
+    let target = position["character"].as_u64().unwrap_or(0) as usize;
+    let mut units = 0;
+    let mut col = 1;
+    for c in text.lines().nth(line).unwrap_or_default().chars() {
+        if units >= target {
+            break;
+        }
+        units += c.len_utf16();
+        col += 1;
+    }
+    (line + 1, col)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URI: &str = "untitled:agent.cog";
+
+    fn open(server: &mut Server, text: &str) -> Value {
+        let msg = json!({"method": "textDocument/didOpen", "params": {"textDocument": {"uri": URI, "text": text}}});
+        server.handle(&msg).remove(0)
+    }
+
+    fn request(server: &mut Server, method: &str, line: usize, character: usize) -> Value {
+        let msg = json!({"id": 1, "method": method, "params": {
+            "textDocument": {"uri": URI}, "position": {"line": line, "character": character},
+        }});
+        server.handle(&msg).remove(0)["result"].take()
+    }
+
+    #[test]
+    fn test_hover_definition_and_diagnostics() {
+        let mut server = Server::default();
+        let src = "type Mood: \"happy\" | \"sad\"\n\nflow greet(name: String) -> String:\n    \"Say hello\"\n    return f\"hi {name}\"\n\nflow main():\n    print(greet(\"🙂\"), greet(\"x\"))\n";
+        assert_eq!(open(&mut server, src)["params"]["diagnostics"], json!([]));
+
+        let hover = request(&mut server, "textDocument/hover", 7, 10);
+        assert_eq!(hover["contents"]["value"], "```cognos\nflow greet(name: String) -> String\n```\n\nSay hello");
+        assert_eq!(hover["range"], json!({"start": {"line": 7, "character": 10}, "end": {"line": 7, "character": 15}}));
+        let hover = request(&mut server, "textDocument/hover", 7, 5);
+        assert!(hover["contents"]["value"].as_str().unwrap().starts_with("```cognos\nprint(value, ...)"));
+        let hover = request(&mut server, "textDocument/hover", 0, 6);
+        assert_eq!(hover["contents"]["value"], "```cognos\ntype Mood: \"happy\" | \"sad\"\n```");
+
+        // Past a non-ASCII char, the column is counted in UTF-16 units
+        let def = request(&mut server, "textDocument/definition", 7, 24);
+        assert_eq!(def["range"], json!({"start": {"line": 2, "character": 5}, "end": {"line": 2, "character": 10}}));
+        assert_eq!(request(&mut server, "textDocument/definition", 7, 5), Value::Null);
+
+        // A syntax error is reported on its line; definitions still resolve
+        let broken = src.replace("    return f", "    return = f");
+        let diags = open(&mut server, &broken)["params"]["diagnostics"].take();
+        assert_eq!(diags[0]["range"]["start"], json!({"line": 4, "character": 4}));
+        assert_eq!(diags[0]["severity"], 1);
+        assert_eq!(request(&mut server, "textDocument/definition", 7, 12)["range"]["start"], json!({"line": 2, "character": 5}));
+        assert_eq!(request(&mut server, "textDocument/hover", 7, 12)["contents"]["value"],
+            "```cognos\nflow greet(name: String) -> String\n```\n\nSay hello");
+
+        let items = request(&mut server, "textDocument/completion", 0, 0);
+        let labels: Vec<&str> = items.as_array().unwrap().iter().map(|i| i["label"].as_str().unwrap()).collect();
+        assert!(labels.contains(&"think") && labels.ends_with(&["Mood", "greet", "main"]), "got: {:?}", labels);
+        assert_eq!(items.as_array().unwrap().last().unwrap()["detail"], "flow main");
+    }
+
+    #[test]
+    fn test_read_message_frames() {
+        let body = r#"{"jsonrpc":"2.0","id":1,"method":"shutdown"}"#;
+        let input = format!("Content-Length: {}\r\nContent-Type: application/vscode-jsonrpc\r\n\r\n{}", body.len(), body);
+        let mut reader = std::io::BufReader::new(input.as_bytes());
+        assert_eq!(read_message(&mut reader).unwrap().unwrap()["method"], "shutdown");
+        assert!(read_message(&mut reader).unwrap().is_none());
+    }
+}
@@ -4,12 +4,35 @@ mod ast;
 mod parser;
 mod pretty;
 mod oauth;
+mod auth;
 mod interpreter;
+mod optimize;
 mod repl;
+mod chat;
 mod environment;
+mod http_client;
+mod providers;
 mod error;
 mod trace;
 mod memory;
+mod bench;
+mod diagnostics;
+mod session;
+mod config;
+mod query;
+mod bundle;
+mod terminal;
+mod help;
+mod lsp;
+mod plugin;
+mod wasm;
+mod mcp;
+mod mcp_server;
+mod grpc;
+mod mqtt;
+mod nats;
+mod retry;
+mod screen;
 
 use std::env;
 use std::fs;
@@ -21,16 +44,62 @@ fn default_memory_path() -> String {
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    // An executable from `cognos build` runs its program; the command line
+    // holds only flags, as for `cognos run`
+    let mut bundle = bundle::embedded();
+    if bundle.is_some() {
+        let exe = args[0].clone();
+        args.splice(1..1, ["run".to_string(), exe]);
+    }
+    if args.iter().any(|a| a == "--no-color") {
+        diagnostics::disable();
+        args.retain(|a| a != "--no-color");
+    }
+    if args.iter().any(|a| a == "-q" || a == "--quiet") {
+        diagnostics::set_quiet();
+        args.retain(|a| a != "-q" && a != "--quiet");
+    }
+    let mut profile_name = env::var("COGNOS_PROFILE").ok();
+    if let Some(i) = args.iter().position(|a| a == "--profile") {
+        if i + 1 >= args.len() {
+            eprintln!("--profile requires a name from cognos.toml");
+            std::process::exit(1);
+        }
+        profile_name = Some(args.remove(i + 1));
+        args.remove(i);
+    }
+    let profile = config::load_profile(profile_name.as_deref()).unwrap_or_else(|e| {
+        diagnostics::error("Error", &e, None);
+        std::process::exit(1);
+    });
+    if let Some(ref profile) = profile {
+        profile.apply_env();
+        providers::register(profile.providers());
+    }
 
     if args.len() < 2 {
         eprintln!("Usage: cognos <file.cog>              # run the program");
         eprintln!("       cognos run [-v|-vv|-vvv] <file> # run with verbosity");
         eprintln!("       cognos parse <file.cog>         # parse and pretty-print");
         eprintln!("       cognos tokens <file.cog>        # show raw tokens");
+        eprintln!("       cognos fmt [--check] <file.cog>... # format files in place");
         eprintln!("       cognos repl                     # interactive REPL");
+        eprintln!("       cognos chat [--model m] [--system f] [--tools lib.cog] # chat with a model");
         eprintln!("       cognos trace-to-mock <file.jsonl> # convert trace to mock JSON");
-        eprintln!("\nEnv: COGNOS_LOG=info|debug|trace");
+        eprintln!("       cognos fuzz-corpus <dir> [src...] # write fuzz seeds from examples/ and lib/");
+        eprintln!("       cognos bench [--save f] [--baseline f] # interpreter benchmarks");
+        eprintln!("       cognos bundle <main.cog> [-o f]  # pack a program and its imports into a .cogx");
+        eprintln!("       cognos build <main.cog> [-o f]   # build an executable that runs the program");
+        eprintln!("       cognos session show|set|unset|diff # inspect and edit --session files");
+        eprintln!("       cognos auth status              # show which credentials will be used");
+        eprintln!("       cognos models                   # list models think() can use");
+        eprintln!("       cognos lsp                      # language server for editors, over stdio");
+        eprintln!("       cognos serve-mcp <file.cog>     # serve documented flows as MCP tools, over stdio");
+        eprintln!("\nFlags: --no-color disables colored output (as does NO_COLOR)");
+        eprintln!("       --profile <name> applies [profile.<name>] from cognos.toml");
+        eprintln!("       -q/--quiet hides log() output, info logging and input prompts");
+        eprintln!("Env: COGNOS_LOG=info|debug|trace");
         std::process::exit(1);
     }
 
@@ -40,17 +109,110 @@ fn main() {
         return;
     }
 
+    if args.len() >= 3 && args[1] == "fuzz-corpus" {
+        fuzz_corpus(&args[2], &args[3..]);
+        return;
+    }
+
+    if args[1] == "bench" {
+        run_bench(&args[2..]);
+        return;
+    }
+
+    if args[1] == "models" {
+        list_models();
+        return;
+    }
+
+    if args[1] == "chat" {
+        if let Err(e) = chat::run(&args[2..]) {
+            diagnostics::error("Error", &e, None);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if args[1] == "bundle" {
+        if let Err(e) = bundle::run(&args[2..]) {
+            diagnostics::error("Error", &e, None);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if args[1] == "build" {
+        if let Err(e) = bundle::run_build(&args[2..]) {
+            diagnostics::error("Error", &e, None);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if args[1] == "fmt" {
+        run_fmt(&args[2..]);
+        return;
+    }
+
+    if args[1] == "lsp" {
+        if let Err(e) = lsp::run() {
+            diagnostics::error("Error", &e, None);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if args[1] == "serve-mcp" {
+        if let Err(e) = mcp_server::run(&args[2..]) {
+            diagnostics::error("Error", &e, None);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if args[1] == "session" {
+        if let Err(e) = session::run(&args[2..]) {
+            diagnostics::error("Error", &e, None);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if args[1] == "auth" {
+        match args.get(2).map(|s| s.as_str()) {
+            Some("status") => print!("{}", auth::status()),
+            _ => {
+                eprintln!("Usage: cognos auth status");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // Profile flags go first so the command line's own override them
+    if let Some(ref profile) = profile {
+        args.splice(1..1, profile.flags());
+    }
+
     // Parse args: find command, verbosity flags, and file path
     let mut command = "run";
     let mut verbosity = 0u8;
     let mut file_path = None;
     let mut allow_shell = false;
+    let mut allow_dynamic = false;
+    let mut llm_retries = 0u32;
+    let mut watch_vars: Vec<String> = Vec::new();
     let mut trace_path: Option<String> = None;
     let mut trace_level = trace::TraceLevel::Metrics;
+    let mut trace_limits = trace::TraceLimits::default();
+    let mut value_limits = interpreter::ValueLimits::default();
     let mut env_path: Option<String> = None;
     let mut session_path: Option<String> = None;
+    let mut autosave: Option<std::time::Duration> = None;
     let mut memory_db: Option<String> = None;
     let mut memory_ns: Option<String> = None;
+    let mut ollama = providers::OllamaOptions::default();
+    let mut warmup_model: Option<String> = None;
+    let mut plugins: Vec<String> = Vec::new();
 
     let mut i = 1;
     while i < args.len() {
@@ -69,6 +231,7 @@ fn main() {
             "-vv" => verbosity = verbosity.max(2),
             "-vvv" => verbosity = verbosity.max(3),
             "--allow-shell" => allow_shell = true,
+            "--allow-dynamic" => allow_dynamic = true,
             "--trace" => {
                 i += 1;
                 if i < args.len() {
@@ -87,6 +250,16 @@ fn main() {
                     std::process::exit(1);
                 }
             }
+            "--autosave" => {
+                i += 1;
+                match args.get(i).and_then(|d| parse_duration(d)) {
+                    Some(every) => autosave = Some(every),
+                    None => {
+                        eprintln!("--autosave requires an interval (e.g. 30s, 5m, 1h)");
+                        std::process::exit(1);
+                    }
+                }
+            }
             "--memory" => {
                 // Enable memory with default path
                 memory_db = Some(default_memory_path());
@@ -109,6 +282,67 @@ fn main() {
                     std::process::exit(1);
                 }
             }
+            "--keep-alive" => {
+                i += 1;
+                if i < args.len() {
+                    // Bare numbers are seconds; anything else is an Ollama duration ("30m", "-1")
+                    ollama.keep_alive = Some(match args[i].parse::<i64>() {
+                        Ok(secs) => serde_json::json!(secs),
+                        Err(_) => serde_json::json!(args[i]),
+                    });
+                } else {
+                    eprintln!("--keep-alive requires a duration (e.g. 30m, -1)");
+                    std::process::exit(1);
+                }
+            }
+            "--watch-var" => {
+                i += 1;
+                match args.get(i) {
+                    Some(names) => watch_vars.extend(names.split(',').map(|n| n.trim().to_string()).filter(|n| !n.is_empty())),
+                    None => {
+                        eprintln!("--watch-var requires variable names, separated by commas");
+                        std::process::exit(1);
+                    }
+                }
+            }
+            "--llm-retries" => {
+                i += 1;
+                match args.get(i).and_then(|n| n.parse::<u32>().ok()) {
+                    Some(n) => llm_retries = n,
+                    None => {
+                        eprintln!("--llm-retries requires a number of retries");
+                        std::process::exit(1);
+                    }
+                }
+            }
+            "--num-ctx" => {
+                i += 1;
+                match args.get(i).and_then(|n| n.parse::<i64>().ok()).filter(|n| *n > 0) {
+                    Some(n) => ollama.num_ctx = Some(n),
+                    None => {
+                        eprintln!("--num-ctx requires a positive token count");
+                        std::process::exit(1);
+                    }
+                }
+            }
+            "--warmup" => {
+                i += 1;
+                if i < args.len() {
+                    warmup_model = Some(args[i].clone());
+                } else {
+                    eprintln!("--warmup requires a model name");
+                    std::process::exit(1);
+                }
+            }
+            "--plugin" => {
+                i += 1;
+                if i < args.len() {
+                    plugins.push(args[i].clone());
+                } else {
+                    eprintln!("--plugin requires a shared library path");
+                    std::process::exit(1);
+                }
+            }
             "--env" => {
                 i += 1;
                 if i < args.len() {
@@ -118,6 +352,66 @@ fn main() {
                     std::process::exit(1);
                 }
             }
+            "--trace-max-mb" => {
+                i += 1;
+                match args.get(i).and_then(|n| n.parse::<f64>().ok()).filter(|n| *n > 0.0) {
+                    Some(mb) => trace_limits.max_bytes = Some((mb * 1024.0 * 1024.0) as u64),
+                    None => {
+                        eprintln!("--trace-max-mb requires a positive size in megabytes");
+                        std::process::exit(1);
+                    }
+                }
+            }
+            "--max-string-mb" => {
+                i += 1;
+                match args.get(i).and_then(|n| n.parse::<f64>().ok()).filter(|n| *n > 0.0) {
+                    Some(mb) => value_limits.max_string_bytes = (mb * 1024.0 * 1024.0) as usize,
+                    None => {
+                        eprintln!("--max-string-mb requires a positive size in megabytes");
+                        std::process::exit(1);
+                    }
+                }
+            }
+            "--max-list-len" => {
+                i += 1;
+                match args.get(i).and_then(|n| n.parse::<usize>().ok()).filter(|n| *n > 0) {
+                    Some(n) => value_limits.max_list_len = n,
+                    None => {
+                        eprintln!("--max-list-len requires a positive item count");
+                        std::process::exit(1);
+                    }
+                }
+            }
+            "--max-depth" => {
+                i += 1;
+                match args.get(i).and_then(|n| n.parse::<usize>().ok()).filter(|n| *n > 0) {
+                    Some(n) => value_limits.max_depth = n,
+                    None => {
+                        eprintln!("--max-depth requires a positive nesting depth");
+                        std::process::exit(1);
+                    }
+                }
+            }
+            "--trace-max-payload" => {
+                i += 1;
+                match args.get(i).and_then(|n| n.parse::<usize>().ok()).filter(|n| *n > 0) {
+                    Some(n) => trace_limits.max_payload = Some(n),
+                    None => {
+                        eprintln!("--trace-max-payload requires a positive character count");
+                        std::process::exit(1);
+                    }
+                }
+            }
+            "--trace-sample" => {
+                i += 1;
+                match args.get(i).and_then(|n| n.parse::<u32>().ok()).filter(|n| *n > 0) {
+                    Some(n) => trace_limits.llm_sample = n,
+                    None => {
+                        eprintln!("--trace-sample requires a positive N (keep every Nth LLM body)");
+                        std::process::exit(1);
+                    }
+                }
+            }
             "--trace-level" => {
                 i += 1;
                 if i < args.len() {
@@ -140,8 +434,10 @@ fn main() {
         i += 1;
     }
 
-    // Initialize logging: CLI flag overrides env var
-    if verbosity > 0 || env::var("COGNOS_LOG").is_ok() {
+    // Initialize logging: CLI flag overrides env var, --quiet overrides both
+    if diagnostics::quiet() {
+        env::set_var("RUST_LOG", "cognos=warn");
+    } else if verbosity > 0 || env::var("COGNOS_LOG").is_ok() {
         let level = if verbosity > 0 {
             match verbosity {
                 1 => "info",
@@ -164,12 +460,17 @@ fn main() {
         .format_target(false)
         .init();
 
+    if let Err(e) = plugin::load(&plugins) {
+        diagnostics::error("Plugin error", &e, None);
+        std::process::exit(1);
+    }
+
     // Login command
     if command == "login" {
         match oauth::login() {
             Ok(_) => std::process::exit(0),
             Err(e) => {
-                eprintln!("Login failed: {}", e);
+                diagnostics::error("Login failed", &e, None);
                 std::process::exit(1);
             }
         }
@@ -178,7 +479,7 @@ fn main() {
     // REPL mode — no file needed
     if command == "repl" {
         if let Err(e) = repl::run_repl() {
-            eprintln!("REPL error: {}", e);
+            diagnostics::error("REPL error", &e, None);
             std::process::exit(1);
         }
         return;
@@ -192,13 +493,28 @@ fn main() {
         }
     };
 
-    let source = match fs::read_to_string(file_path) {
-        Ok(s) => s,
-        Err(e) => {
-            eprintln!("Error reading {}: {}", file_path, e);
+    // A .cogx bundle carries the program and its imports; run its entry
+    let source = if let Some(ref b) = bundle {
+        b.entry_source().to_string()
+    } else if file_path.ends_with(".cogx") {
+        let b = bundle::Bundle::read(file_path).unwrap_or_else(|e| {
+            diagnostics::error("Error", &e, None);
             std::process::exit(1);
+        });
+        let source = b.entry_source().to_string();
+        bundle = Some(b);
+        source
+    } else {
+        match fs::read_to_string(file_path) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Error reading {}: {}", file_path, e);
+                std::process::exit(1);
+            }
         }
     };
+    // Imports resolve relative to this: the file, or the bundle's entry module
+    let base_path = bundle.as_ref().map_or(file_path.to_string(), |b| b.entry.clone());
 
     log::info!("Loading {}", file_path);
 
@@ -213,29 +529,38 @@ fn main() {
             }
         }
         "parse" => {
-            let mut p = parser::Parser::new(tokens);
+            let mut p = parser::Parser::new(tokens).for_file(std::path::Path::new(file_path));
             match p.parse_program() {
                 Ok(program) => {
                     println!("✓ Parsed {} flow(s)\n", program.flows.len());
                     print!("{}", pretty::pretty_program(&program));
                 }
-                Err(e) => { eprintln!("Parse error: {}", e); std::process::exit(1); }
+                Err(e) => { diagnostics::error("Parse error", &e, Some((file_path, &source))); std::process::exit(1); }
             }
         }
         "run" => {
-            let mut p = parser::Parser::new(tokens);
+            let mut p = parser::Parser::new(tokens).for_file(std::path::Path::new(file_path));
             let program = match p.parse_program() {
                 Ok(prog) => prog,
-                Err(e) => { eprintln!("Parse error: {}", e); std::process::exit(1); }
+                Err(e) => { diagnostics::error("Parse error", &e, Some((file_path, &source))); std::process::exit(1); }
             };
             log::info!("Parsed {} flow(s)", program.flows.len());
-            let tracer = trace_path.as_ref().map(|p| {
-                std::sync::Arc::new(trace::Tracer::new_file(p, trace_level).unwrap_or_else(|e| {
-                    eprintln!("Failed to open trace file {}: {}", p, e);
-                    std::process::exit(1);
-                }))
-            });
-            let mut interp = interpreter::Interpreter::with_full_options(allow_shell, tracer);
+            let tracer = open_tracer(trace_path.as_deref(), trace_level, trace_limits, verbosity > 0);
+            let mut interp = interpreter::Interpreter::with_full_options(allow_shell, tracer.clone());
+            interp.set_ollama_options(ollama);
+            if let Some(ref profile) = profile {
+                interp.set_flow_overrides(profile.flow_overrides());
+            }
+            interp.set_value_limits(value_limits);
+            interp.set_allow_dynamic(allow_dynamic);
+            interp.set_llm_retries(llm_retries);
+            interp.set_watched_vars(&watch_vars);
+            if let Some(bundle) = bundle.take() {
+                interp.set_bundle(bundle);
+            }
+            if let Some(ref model) = warmup_model {
+                interp.warmup(model);
+            }
             // Enable memory if --memory or --memory-db provided
             if let Some(ref db_path) = memory_db {
                 // Ensure parent directory exists
@@ -249,7 +574,7 @@ fn main() {
                         interp.set_memory(store);
                     }
                     Err(e) => {
-                        eprintln!("Warning: failed to open memory DB: {}", e);
+                        diagnostics::warning(format!("failed to open memory DB: {}", e));
                     }
                 }
             }
@@ -257,22 +582,31 @@ fn main() {
             if let Some(ref sp) = session_path {
                 if std::path::Path::new(sp).exists() {
                     if let Err(e) = interp.load_session(sp) {
-                        eprintln!("Warning: failed to load session: {}", e);
+                        diagnostics::warning(format!("failed to load session: {}", e));
                     }
                 }
+                if let Some(every) = autosave {
+                    interp.set_autosave(sp, every);
+                }
+            } else if autosave.is_some() {
+                eprintln!("--autosave needs --session <path> to save to");
+                std::process::exit(1);
             }
-            if let Err(e) = interp.run_with_base(&program, Some(std::path::Path::new(file_path))) {
-                eprintln!("Runtime error: {}", e);
+            if let Err(e) = interp.run_with_base(&program, Some(std::path::Path::new(&base_path))) {
+                trace_runtime_error(tracer.as_deref(), &interp, &e);
+                finish_trace(tracer.as_deref());
+                runtime_error(&interp, &e, file_path, &source);
                 // Still save session on error
                 if let Some(ref sp) = session_path {
                     let _ = interp.save_session(sp);
                 }
                 std::process::exit(1);
             }
+            finish_trace(tracer.as_deref());
             // Save session state
             if let Some(ref sp) = session_path {
                 if let Err(e) = interp.save_session(sp) {
-                    eprintln!("Warning: failed to save session: {}", e);
+                    diagnostics::warning(format!("failed to save session: {}", e));
                 }
             }
         }
@@ -294,29 +628,38 @@ fn main() {
                 eprintln!("Invalid mock env: {}", e);
                 std::process::exit(1);
             });
-            let mut p = parser::Parser::new(tokens);
+            let mut p = parser::Parser::new(tokens).for_file(std::path::Path::new(file_path));
             let program = match p.parse_program() {
                 Ok(prog) => prog,
-                Err(e) => { eprintln!("Parse error: {}", e); std::process::exit(1); }
+                Err(e) => { diagnostics::error("Parse error", &e, Some((file_path, &source))); std::process::exit(1); }
             };
-            let tracer = trace_path.as_ref().map(|p| {
-                std::sync::Arc::new(trace::Tracer::new_file(p, trace_level).unwrap_or_else(|e| {
-                    eprintln!("Failed to open trace file {}: {}", p, e);
-                    std::process::exit(1);
-                }))
-            });
-            let mut interp = interpreter::Interpreter::with_env(Box::new(mock_env), tracer);
-            if let Err(e) = interp.run_with_base(&program, Some(std::path::Path::new(file_path))) {
-                eprintln!("Runtime error: {}", e);
+            let tracer = open_tracer(trace_path.as_deref(), trace_level, trace_limits, verbosity > 0);
+            let mut interp = interpreter::Interpreter::with_env(Box::new(mock_env), tracer.clone());
+            if let Some(ref profile) = profile {
+                interp.set_flow_overrides(profile.flow_overrides());
+            }
+            interp.set_value_limits(value_limits);
+            interp.set_allow_dynamic(allow_dynamic);
+            interp.set_llm_retries(llm_retries);
+            interp.set_watched_vars(&watch_vars);
+            if let Some(bundle) = bundle.take() {
+                interp.set_bundle(bundle);
+            }
+            if let Err(e) = interp.run_with_base(&program, Some(std::path::Path::new(&base_path))) {
+                trace_runtime_error(tracer.as_deref(), &interp, &e);
+                finish_trace(tracer.as_deref());
+                runtime_error(&interp, &e, file_path, &source);
+                eprintln!("{}", diagnostics::paint(diagnostics::Stream::Stderr, diagnostics::Style::Error, "─── Fail ✗ ───"));
                 std::process::exit(1);
             }
+            finish_trace(tracer.as_deref());
             // Print captured stdout
             if let Some(output) = interp.captured_stdout() {
                 println!("─── Mock Output ({} lines) ───", output.len());
                 for line in &output {
                     println!("  {}", line);
                 }
-                println!("─── Pass ✓ ───");
+                println!("{}", diagnostics::paint(diagnostics::Stream::Stdout, diagnostics::Style::Success, "─── Pass ✓ ───"));
             }
         }
         _ => {
@@ -326,6 +669,169 @@ fn main() {
     }
 }
 
+/// `30s`, `5m`, `1h`, or bare seconds.
+fn parse_duration(s: &str) -> Option<std::time::Duration> {
+    let (digits, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let n: u64 = digits.parse().ok().filter(|n| *n > 0)?;
+    let secs = match unit {
+        "" | "s" => n,
+        "m" => n * 60,
+        "h" => n * 3600,
+        _ => return None,
+    };
+    Some(std::time::Duration::from_secs(secs))
+}
+
+/// The `--trace` file, or under `-v` a tracer that only keeps the metrics
+/// so the run summary can be logged.
+fn open_tracer(path: Option<&str>, level: trace::TraceLevel, limits: trace::TraceLimits, verbose: bool) -> Option<std::sync::Arc<trace::Tracer>> {
+    let Some(path) = path else {
+        return verbose.then(|| std::sync::Arc::new(trace::Tracer::new_sink()));
+    };
+    let tracer = std::sync::Arc::new(trace::Tracer::new_file(path, level).unwrap_or_else(|e| {
+        eprintln!("Failed to open trace file {}: {}", path, e);
+        std::process::exit(1);
+    }).with_limits(limits));
+    trace::install_exit_hooks(&tracer);
+    Some(tracer)
+}
+
+/// Write the run's metrics event and log the totals (shown under `-v`).
+fn finish_trace(tracer: Option<&trace::Tracer>) {
+    if let Some(tracer) = tracer {
+        // Not inside log::info!, which skips its arguments when info is off
+        let totals = tracer.finish();
+        log::info!("Run: {}", totals);
+    }
+}
+
+/// The error that ends a run, as the trace's last event.
+fn trace_runtime_error(tracer: Option<&trace::Tracer>, interp: &interpreter::Interpreter, err: &anyhow::Error) {
+    if let Some(tracer) = tracer {
+        let flow = interp.error_location().and_then(|at| at.flow.clone());
+        tracer.emit(trace::TraceEvent::Error { category: "runtime".into(), message: err.to_string(), flow });
+    }
+}
+
+/// `Runtime error: ...`, pointing at the statement that raised it: in the
+/// file being run (`path`, `source`) or the library its flow came from.
+fn runtime_error(interp: &interpreter::Interpreter, err: &anyhow::Error, path: &str, source: &str) {
+    let Some(at) = interp.error_location().filter(|at| at.line > 0) else {
+        diagnostics::error("Runtime error", err, Some((path, source)));
+        return;
+    };
+    match &at.file {
+        Some(file) => {
+            let text = fs::read_to_string(file).ok();
+            diagnostics::error_at("Runtime error", err, file, text.as_deref(), at.line, at.col);
+        }
+        None => diagnostics::error_at("Runtime error", err, path, Some(source), at.line, at.col),
+    }
+}
+
+/// `cognos fmt`: rewrite each file in canonical layout, or with `--check`
+/// list the ones that aren't and exit 1.
+fn run_fmt(args: &[String]) {
+    let check = args.iter().any(|a| a == "--check");
+    let files: Vec<&String> = args.iter().filter(|a| *a != "--check").collect();
+    if files.is_empty() {
+        eprintln!("Usage: cognos fmt [--check] <file.cog>...");
+        std::process::exit(1);
+    }
+    let mut unformatted = false;
+    for file_path in files {
+        let source = std::fs::read_to_string(file_path).unwrap_or_else(|e| {
+            eprintln!("Error reading {}: {}", file_path, e);
+            std::process::exit(1);
+        });
+        let formatted = match pretty::format_source(&source) {
+            Ok(formatted) => formatted,
+            Err(e) => {
+                diagnostics::error("Parse error", &e, Some((file_path, &source)));
+                std::process::exit(1);
+            }
+        };
+        if formatted == source { continue; }
+        if check {
+            println!("would reformat {}", file_path);
+            unformatted = true;
+        } else if let Err(e) = std::fs::write(file_path, &formatted) {
+            eprintln!("Error writing {}: {}", file_path, e);
+            std::process::exit(1);
+        }
+    }
+    if unformatted { std::process::exit(1); }
+}
+
+fn run_bench(args: &[String]) {
+    let mut opts = bench::BenchOptions::default();
+    let mut i = 0;
+    while i < args.len() {
+        let flag = args[i].as_str();
+        let value = args.get(i + 1).cloned();
+        let needs_value = matches!(flag, "--iterations" | "--filter" | "--save" | "--baseline" | "--threshold");
+        if needs_value && value.is_none() {
+            eprintln!("{} requires a value", flag);
+            std::process::exit(1);
+        }
+        match flag {
+            "--list" => {
+                opts.list = true;
+                i += 1;
+                continue;
+            }
+            "--iterations" => opts.iterations = value.unwrap().parse().unwrap_or_else(|_| {
+                eprintln!("--iterations must be a number");
+                std::process::exit(1);
+            }),
+            "--filter" => opts.filter = value,
+            "--save" => opts.save = value,
+            "--baseline" => opts.baseline = value,
+            "--threshold" => opts.threshold = value.unwrap().parse().unwrap_or_else(|_| {
+                eprintln!("--threshold must be a percentage");
+                std::process::exit(1);
+            }),
+            other => {
+                eprintln!("Unknown bench flag: {}", other);
+                std::process::exit(1);
+            }
+        }
+        i += 2;
+    }
+    if let Err(e) = bench::run_bench(&opts) {
+        diagnostics::error("Bench error", &e, None);
+        std::process::exit(1);
+    }
+}
+
+fn list_models() {
+    let flag = |v: Option<bool>| match v {
+        Some(true) => "yes",
+        Some(false) => "no",
+        None => "?",
+    };
+    let results = providers::list_models(&providers::OllamaOptions::default());
+    let width = results.iter()
+        .flat_map(|(_, r)| r.iter().flatten().map(|m| m.name.len()))
+        .max().unwrap_or(0).max("MODEL".len());
+    println!("{:<width$}  {:<10}  {:<5}  VISION", "MODEL", "PROVIDER", "TOOLS");
+    let mut unavailable = Vec::new();
+    for (provider, models) in &results {
+        match models {
+            Ok(models) => for m in models {
+                println!("{:<width$}  {:<10}  {:<5}  {}", m.name, provider, flag(m.tools), flag(m.vision));
+            },
+            Err(e) => unavailable.push(format!("  {}: {}", provider, e)),
+        }
+    }
+    if !unavailable.is_empty() {
+        println!("\nUnavailable:");
+        for line in unavailable {
+            println!("{}", line);
+        }
+    }
+}
+
 fn trace_to_mock(path: &str) {
     let content = fs::read_to_string(path).unwrap_or_else(|e| {
         eprintln!("Cannot read {}: {}", path, e);
@@ -350,7 +856,7 @@ fn trace_to_mock(path: &str) {
                 let op = event.get("op").and_then(|v| v.as_str()).unwrap_or("");
                 let handle = event.get("handle").and_then(|v| v.as_str()).unwrap_or("");
                 match (op, handle) {
-                    ("read", "stdin") => {
+                    ("read", "stdin") | ("prompt", "stdin") => {
                         if let Some(c) = event.get("content").and_then(|v| v.as_str()) {
                             stdin_lines.push(c.to_string());
                         }
@@ -402,3 +908,94 @@ fn trace_to_mock(path: &str) {
 
     println!("{}", serde_json::to_string_pretty(&mock).unwrap_or_default());
 }
+
+/// Dump lexer/parser fuzz seeds derived from .cog sources. Each file is
+/// written whole, plus one seed per top-level block (imports, types, flows)
+/// so the fuzzer starts from small, valid inputs.
+fn fuzz_corpus(out_dir: &str, sources: &[String]) {
+    let sources: Vec<String> = if sources.is_empty() {
+        vec!["examples".to_string(), "lib".to_string()]
+    } else {
+        sources.to_vec()
+    };
+
+    let mut files = Vec::new();
+    for src in &sources {
+        let path = std::path::Path::new(src);
+        if path.is_dir() {
+            let entries = fs::read_dir(path).unwrap_or_else(|e| {
+                eprintln!("Cannot read {}: {}", src, e);
+                std::process::exit(1);
+            });
+            for entry in entries.flatten() {
+                let p = entry.path();
+                if p.extension().and_then(|e| e.to_str()) == Some("cog") {
+                    files.push(p);
+                }
+            }
+        } else if path.is_file() {
+            files.push(path.to_path_buf());
+        } else {
+            eprintln!("No such file or directory: {}", src);
+            std::process::exit(1);
+        }
+    }
+    files.sort();
+
+    if let Err(e) = fs::create_dir_all(out_dir) {
+        eprintln!("Cannot create {}: {}", out_dir, e);
+        std::process::exit(1);
+    }
+
+    let mut seen = std::collections::HashSet::new();
+    let mut written = 0;
+    for file in &files {
+        let source = match fs::read_to_string(file) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Skipping {}: {}", file.display(), e);
+                continue;
+            }
+        };
+        let stem = file.file_stem().and_then(|s| s.to_str()).unwrap_or("seed");
+        let mut seeds = vec![(stem.to_string(), source.clone())];
+        for (n, block) in top_level_blocks(&source).into_iter().enumerate() {
+            seeds.push((format!("{}-{}", stem, n + 1), block));
+        }
+        for (name, content) in seeds {
+            if content.trim().is_empty() || !seen.insert(content.clone()) {
+                continue;
+            }
+            let dest = std::path::Path::new(out_dir).join(format!("{}.cog", name));
+            if let Err(e) = fs::write(&dest, &content) {
+                eprintln!("Cannot write {}: {}", dest.display(), e);
+                std::process::exit(1);
+            }
+            written += 1;
+        }
+    }
+
+    eprintln!("Wrote {} seed(s) from {} file(s) to {}", written, files.len(), out_dir);
+}
+
+/// Split source into top-level blocks: every unindented, non-comment line
+/// starts a new block that runs until the next one.
+fn top_level_blocks(source: &str) -> Vec<String> {
+    let mut blocks: Vec<String> = Vec::new();
+    let mut current = String::new();
+    for line in source.lines() {
+        let starts_block = !line.is_empty()
+            && !line.starts_with(' ')
+            && !line.starts_with('\t')
+            && !line.starts_with('#');
+        if starts_block && !current.trim().is_empty() {
+            blocks.push(std::mem::take(&mut current));
+        }
+        current.push_str(line);
+        current.push('\n');
+    }
+    if !current.trim().is_empty() {
+        blocks.push(current);
+    }
+    blocks
+}
@@ -0,0 +1,320 @@
+//! `mcp()`: a client for Model Context Protocol servers.
+//!
+//! A server is reached over stdio (a process started with `command`, one
+//! JSON-RPC message per line) or over Streamable HTTP (JSON-RPC POSTed to
+//! `url`, answered with JSON or an event stream). On connecting the client
+//! runs the `initialize` handshake and lists the server's tools; calls go
+//! out as `tools/call`. Only tools are used: resources, prompts and
+//! sampling requests from the server are not supported.
+
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::time::Duration;
+
+const PROTOCOL_VERSION: &str = "2025-06-18";
+/// How long a request may take unless `timeout=` says otherwise.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A tool the server offers, as `tools/list` describes it.
+pub struct Tool {
+    pub name: String,
+    pub description: String,
+    pub input_schema: Value,
+}
+
+impl Tool {
+    /// The tool as a `think(tools=)` definition.
+    pub fn to_tool_json(&self) -> Value {
+        json!({
+            "type": "function",
+            "function": { "name": self.name, "description": self.description, "parameters": self.input_schema },
+        })
+    }
+}
+
+enum Transport {
+    Stdio { child: Child, stdin: ChildStdin, lines: Receiver<String> },
+    Http { url: String, headers: Vec<(String, String)>, session: Option<String> },
+}
+
+/// A connected server. Requests take turns on the one connection.
+pub struct Client {
+    /// The command or URL, for messages.
+    pub label: String,
+    pub tools: Vec<Tool>,
+    transport: Mutex<Transport>,
+    next_id: AtomicU64,
+    timeout: Duration,
+}
+
+impl std::fmt::Debug for Client {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "mcp({:?})", self.label)
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        if let Ok(Transport::Stdio { child, .. }) = self.transport.get_mut() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+impl Client {
+    /// Start `command` with `args` and connect to it over its stdin and stdout.
+    pub fn stdio(command: &str, args: &[String], env: &[(String, String)], timeout: Duration) -> Result<Client> {
+        let mut child = Command::new(command)
+            .args(args)
+            .envs(env.iter().map(|(k, v)| (k, v)))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .with_context(|| format!("mcp(): couldn't start '{}'", command))?;
+        let stdin = child.stdin.take().expect("piped stdin");
+        let stdout = child.stdout.take().expect("piped stdout");
+        let (tx, lines) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else { break };
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        let label = std::iter::once(command).chain(args.iter().map(String::as_str)).collect::<Vec<_>>().join(" ");
+        Client::connect(label, Transport::Stdio { child, stdin, lines }, timeout)
+    }
+
+    /// Connect to a Streamable HTTP endpoint, sending `headers` (e.g. an
+    /// `Authorization`) with every request.
+    pub fn http(url: &str, headers: Vec<(String, String)>, timeout: Duration) -> Result<Client> {
+        Client::connect(url.to_string(), Transport::Http { url: url.to_string(), headers, session: None }, timeout)
+    }
+
+    fn connect(label: String, transport: Transport, timeout: Duration) -> Result<Client> {
+        let mut client = Client { label, tools: Vec::new(), transport: Mutex::new(transport), next_id: AtomicU64::new(1), timeout };
+        let init = client.request("initialize", json!({
+            "protocolVersion": PROTOCOL_VERSION,
+            "capabilities": {},
+            "clientInfo": { "name": "cognos", "version": env!("CARGO_PKG_VERSION") },
+        }))?;
+        log::info!("mcp: connected to {} ({} {})", client.label,
+            init["serverInfo"]["name"].as_str().unwrap_or("unnamed server"), init["protocolVersion"].as_str().unwrap_or("?"));
+        client.notify("notifications/initialized")?;
+        let mut cursor: Option<String> = None;
+        loop {
+            let params = match &cursor {
+                Some(c) => json!({ "cursor": c }),
+                None => json!({}),
+            };
+            let page = client.request("tools/list", params)?;
+            for tool in page["tools"].as_array().into_iter().flatten() {
+                let Some(name) = tool["name"].as_str() else { continue };
+                client.tools.push(Tool {
+                    name: name.to_string(),
+                    description: tool["description"].as_str().unwrap_or("").to_string(),
+                    input_schema: match &tool["inputSchema"] {
+                        Value::Object(_) => tool["inputSchema"].clone(),
+                        _ => json!({ "type": "object", "properties": {} }),
+                    },
+                });
+            }
+            cursor = page["nextCursor"].as_str().map(str::to_string);
+            if cursor.is_none() {
+                break;
+            }
+        }
+        log::info!("mcp: {} offers {} tool(s)", client.label, client.tools.len());
+        Ok(client)
+    }
+
+    /// Call a tool. Structured content comes back as is, text content as
+    /// one String; a result the server marks as an error is an error.
+    pub fn call(&self, name: &str, arguments: Value) -> Result<Value> {
+        let result = self.request("tools/call", json!({ "name": name, "arguments": arguments }))?;
+        let text = result["content"].as_array().into_iter().flatten()
+            .map(|part| match part["type"].as_str() {
+                Some("text") => part["text"].as_str().unwrap_or("").to_string(),
+                Some(other) => format!("[{} content]", other),
+                None => String::new(),
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        if result["isError"] == Value::Bool(true) {
+            bail!("mcp tool '{}' failed: {}", name, text);
+        }
+        Ok(match &result["structuredContent"] {
+            Value::Null => Value::String(text),
+            structured => structured.clone(),
+        })
+    }
+
+    fn request(&self, method: &str, params: Value) -> Result<Value> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let message = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        let mut transport = self.transport.lock().unwrap_or_else(|e| e.into_inner());
+        let reply = match &mut *transport {
+            Transport::Stdio { stdin, lines, .. } => {
+                send_line(stdin, &message).with_context(|| format!("mcp: {} closed its input", self.label))?;
+                loop {
+                    let line = match lines.recv_timeout(self.timeout) {
+                        Ok(line) => line,
+                        Err(RecvTimeoutError::Timeout) => bail!("mcp: {} didn't answer {} within {}s", self.label, method, self.timeout.as_secs()),
+                        Err(RecvTimeoutError::Disconnected) => bail!("mcp: {} exited", self.label),
+                    };
+                    let Ok(incoming) = serde_json::from_str::<Value>(&line) else {
+                        log::debug!("mcp: {}: ignoring non-JSON line: {}", self.label, line);
+                        continue;
+                    };
+                    if incoming.get("method").is_some() {
+                        // A request or notification from the server
+                        if let Some(answer) = answer_server_request(&incoming) {
+                            send_line(stdin, &answer)?;
+                        }
+                        continue;
+                    }
+                    if incoming["id"] == json!(id) {
+                        break incoming;
+                    }
+                }
+            }
+            Transport::Http { url, headers, session } => {
+                let response = post(url, headers, session.as_deref(), &message, self.timeout)
+                    .with_context(|| format!("mcp: {} failed", method))?;
+                if session.is_none() {
+                    *session = response.session;
+                }
+                find_reply(&response.body, id)
+                    .ok_or_else(|| anyhow::anyhow!("mcp: {} sent no answer to {}: {}", self.label, method, preview(&response.body)))?
+            }
+        };
+        if let Some(error) = reply.get("error") {
+            bail!("mcp: {} failed: {} (code {})", method, error["message"].as_str().unwrap_or("unknown error"), error["code"]);
+        }
+        Ok(reply["result"].clone())
+    }
+
+    fn notify(&self, method: &str) -> Result<()> {
+        let message = json!({ "jsonrpc": "2.0", "method": method });
+        let mut transport = self.transport.lock().unwrap_or_else(|e| e.into_inner());
+        match &mut *transport {
+            Transport::Stdio { stdin, .. } => send_line(stdin, &message),
+            Transport::Http { url, headers, session } => post(url, headers, session.as_deref(), &message, self.timeout).map(|_| ()),
+        }
+    }
+}
+
+fn send_line(stdin: &mut ChildStdin, message: &Value) -> Result<()> {
+    writeln!(stdin, "{}", message)?;
+    stdin.flush()?;
+    Ok(())
+}
+
+/// The reply to a server's own request: `ping` is answered, anything else
+/// (sampling, roots, elicitation) is refused. Notifications get none.
+fn answer_server_request(incoming: &Value) -> Option<Value> {
+    let id = incoming.get("id")?;
+    Some(match incoming["method"].as_str() {
+        Some("ping") => json!({ "jsonrpc": "2.0", "id": id, "result": {} }),
+        method => json!({ "jsonrpc": "2.0", "id": id, "error": {
+            "code": -32601, "message": format!("cognos doesn't support {}", method.unwrap_or("this request")),
+        }}),
+    })
+}
+
+struct HttpResponse {
+    body: String,
+    session: Option<String>,
+}
+
+fn post(url: &str, headers: &[(String, String)], session: Option<&str>, message: &Value, timeout: Duration) -> Result<HttpResponse> {
+    let mut request = crate::http_client::client(crate::http_client::Service::Http)
+        .post(url)
+        .timeout(timeout)
+        .header("Accept", "application/json, text/event-stream")
+        .header("MCP-Protocol-Version", PROTOCOL_VERSION)
+        .json(message);
+    for (name, value) in headers {
+        request = request.header(name.as_str(), value.as_str());
+    }
+    if let Some(session) = session {
+        request = request.header("Mcp-Session-Id", session);
+    }
+    let response = request.send()?;
+    let status = response.status();
+    let session = response.headers().get("mcp-session-id").and_then(|v| v.to_str().ok()).map(str::to_string);
+    let body = response.text()?;
+    if !status.is_success() {
+        bail!("HTTP {}: {}", status.as_u16(), preview(&body));
+    }
+    Ok(HttpResponse { body, session })
+}
+
+/// The message answering request `id` in an HTTP reply body: plain JSON
+/// (one message or a batch), or the `data:` of an event stream.
+fn find_reply(body: &str, id: u64) -> Option<Value> {
+    let trimmed = body.trim_start();
+    let messages: Vec<Value> = if trimmed.starts_with("event:") || trimmed.starts_with("data:") || trimmed.starts_with(':') {
+        let mut messages = Vec::new();
+        let mut data = String::new();
+        for line in body.lines().chain(std::iter::once("")) {
+            if let Some(rest) = line.strip_prefix("data:") {
+                if !data.is_empty() {
+                    data.push('\n');
+                }
+                data.push_str(rest.strip_prefix(' ').unwrap_or(rest));
+            } else if line.is_empty() && !data.is_empty() {
+                messages.extend(serde_json::from_str::<Value>(&data).ok());
+                data.clear();
+            }
+        }
+        messages
+    } else {
+        match serde_json::from_str::<Value>(body).ok()? {
+            Value::Array(batch) => batch,
+            message => vec![message],
+        }
+    };
+    messages.into_iter().find(|m| m["id"] == json!(id) && m.get("method").is_none())
+}
+
+fn preview(body: &str) -> String {
+    body.chars().take(300).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_reply_in_json_and_event_streams() {
+        let plain = r#"{"jsonrpc":"2.0","id":3,"result":{"tools":[]}}"#;
+        assert_eq!(find_reply(plain, 3).unwrap()["result"]["tools"], json!([]));
+        assert!(find_reply(plain, 4).is_none());
+
+        let stream = "event: message\ndata: {\"jsonrpc\":\"2.0\",\"method\":\"notifications/progress\"}\n\n\
+            event: message\ndata: {\"jsonrpc\":\"2.0\",\n\
+            data: \"id\":7,\"result\":{\"ok\":true}}\n\n";
+        assert_eq!(find_reply(stream, 7).unwrap()["result"]["ok"], true);
+
+        let batch = r#"[{"jsonrpc":"2.0","id":1,"result":1},{"jsonrpc":"2.0","id":2,"result":2}]"#;
+        assert_eq!(find_reply(batch, 2).unwrap()["result"], 2);
+    }
+
+    #[test]
+    fn test_server_requests_get_answers_and_notifications_none() {
+        let ping = answer_server_request(&json!({"jsonrpc": "2.0", "id": 9, "method": "ping"})).unwrap();
+        assert_eq!(ping, json!({"jsonrpc": "2.0", "id": 9, "result": {}}));
+        let sampling = answer_server_request(&json!({"jsonrpc": "2.0", "id": "s1", "method": "sampling/createMessage"})).unwrap();
+        assert_eq!(sampling["error"]["code"], -32601);
+        assert!(answer_server_request(&json!({"jsonrpc": "2.0", "method": "notifications/message"})).is_none());
+    }
+}
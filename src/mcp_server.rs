@@ -0,0 +1,195 @@
+//! `cognos serve-mcp <file.cog>` — offer a program's flows to other agents
+//! as Model Context Protocol tools, over stdio.
+//!
+//! Every flow with a docstring becomes a tool, described the way
+//! `think(tools=)` describes it to a model. Messages are JSON-RPC, one per
+//! line. Calls run one at a time in a single interpreter, so globals set up
+//! by the file's top-level code persist between them. stdout carries the
+//! protocol, so what the flows print goes to stderr.
+
+use crate::environment::RealEnv;
+use crate::interpreter::{Interpreter, Value};
+use anyhow::{bail, Result};
+use serde_json::{json, Value as Json};
+use std::io::{BufRead, Write};
+
+/// Protocol versions this server speaks, newest first.
+const PROTOCOL_VERSIONS: &[&str] = &["2025-06-18", "2025-03-26", "2024-11-05"];
+
+pub fn run(args: &[String]) -> Result<()> {
+    let mut file = None;
+    let mut allow_shell = false;
+    for arg in args {
+        match arg.as_str() {
+            "--allow-shell" => allow_shell = true,
+            flag if flag.starts_with('-') => bail!("Unknown serve-mcp flag: {}", flag),
+            path if file.is_none() => file = Some(path.to_string()),
+            extra => bail!("serve-mcp serves one file; got '{}' as well", extra),
+        }
+    }
+    let Some(file) = file else { bail!("Usage: cognos serve-mcp [--allow-shell] <file.cog>") };
+
+    let env = RealEnv { allow_shell, stdio_reserved: true };
+    let mut interp = Interpreter::with_env(Box::new(env), None);
+    interp.import_library(&file)?;
+    let mut server = Server::new(interp);
+    if server.tools.is_empty() {
+        crate::diagnostics::warning(format!("{} has no flows with a docstring, so there are no tools to serve", file));
+    }
+    log::info!("serve-mcp: serving {} tool(s) from {}", server.tools.len(), file);
+
+    let mut out = std::io::stdout().lock();
+    for line in std::io::stdin().lock().lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(reply) = server.handle_line(&line) {
+            writeln!(out, "{}", reply)?;
+            out.flush()?;
+        }
+    }
+    Ok(())
+}
+
+struct Server {
+    interp: Interpreter,
+    /// `think(tools=)` definitions of the flows served.
+    tools: Vec<Json>,
+}
+
+impl Server {
+    fn new(interp: Interpreter) -> Self {
+        let tools = interp.documented_tools();
+        Server { interp, tools }
+    }
+
+    /// The reply to one line from the client; none for a notification.
+    fn handle_line(&mut self, line: &str) -> Option<Json> {
+        match serde_json::from_str::<Json>(line) {
+            Ok(msg) => self.handle(&msg),
+            Err(e) => Some(error_reply(&Json::Null, -32700, format!("parse error: {}", e))),
+        }
+    }
+
+    fn handle(&mut self, msg: &Json) -> Option<Json> {
+        let id = msg.get("id")?;
+        let method = msg["method"].as_str().unwrap_or_default();
+        let params = &msg["params"];
+        let result = match method {
+            "initialize" => {
+                let asked = params["protocolVersion"].as_str().unwrap_or_default();
+                let version = PROTOCOL_VERSIONS.iter().find(|v| **v == asked).unwrap_or(&PROTOCOL_VERSIONS[0]);
+                json!({
+                    "protocolVersion": version,
+                    "capabilities": { "tools": { "listChanged": false } },
+                    "serverInfo": { "name": "cognos", "version": env!("CARGO_PKG_VERSION") },
+                })
+            }
+            "ping" => json!({}),
+            "tools/list" => json!({
+                "tools": self.tools.iter().map(|tool| json!({
+                    "name": tool["function"]["name"],
+                    "description": tool["function"]["description"],
+                    "inputSchema": tool["function"]["parameters"],
+                })).collect::<Vec<_>>(),
+            }),
+            "tools/call" => {
+                let Some(name) = params["name"].as_str() else {
+                    return Some(error_reply(id, -32602, "tools/call needs a tool name".to_string()));
+                };
+                if !self.tools.iter().any(|t| t["function"]["name"] == name) {
+                    return Some(error_reply(id, -32602, format!("unknown tool: {}", name)));
+                }
+                self.call(name, params["arguments"].clone())
+            }
+            _ => return Some(error_reply(id, -32601, format!("method not found: {}", method))),
+        };
+        Some(json!({ "jsonrpc": "2.0", "id": id, "result": result }))
+    }
+
+    /// Run a flow. A Map it returns is also sent as structured content; an
+    /// error is a result marked as one, for the calling model to see.
+    fn call(&mut self, name: &str, arguments: Json) -> Json {
+        let arguments = match arguments {
+            Json::Null => Value::None,
+            other => Value::from_json(other),
+        };
+        match self.interp.call_tool(name, arguments) {
+            Ok(Value::String(text)) => json!({ "content": [{ "type": "text", "text": text }] }),
+            Ok(Value::None) => json!({ "content": [] }),
+            Ok(value @ Value::Map(_)) => {
+                let structured = value.to_json();
+                json!({ "content": [{ "type": "text", "text": structured.to_string() }], "structuredContent": structured })
+            }
+            Ok(value @ Value::List(_)) => json!({ "content": [{ "type": "text", "text": value.to_json().to_string() }] }),
+            Ok(value) => json!({ "content": [{ "type": "text", "text": value.to_string() }] }),
+            Err(e) => json!({ "content": [{ "type": "text", "text": e.to_string() }], "isError": true }),
+        }
+    }
+}
+
+fn error_reply(id: &Json, code: i64, message: String) -> Json {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::MockEnv;
+
+    fn server(src: &str) -> Server {
+        let tokens = crate::lexer::Lexer::new(src).tokenize();
+        let program = crate::parser::Parser::new(tokens).parse_program().unwrap();
+        let mut interp = Interpreter::with_env(Box::new(MockEnv::new()), None);
+        for flow in program.flows {
+            interp.register_flow(flow);
+        }
+        Server::new(interp)
+    }
+
+    #[test]
+    fn test_documented_flows_are_listed_and_called() {
+        let mut server = server(r#"flow add(a: Int, b: Int) -> Int:
+    "Add two numbers"
+    return a + b
+
+flow point(x: Int) -> Map:
+    "A point on the diagonal"
+    return {"x": x, "y": x}
+
+flow helper() -> String:
+    return "not a tool"
+"#);
+        let init = server.handle_line(r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"protocolVersion":"2024-11-05"}}"#).unwrap();
+        assert_eq!(init["result"]["protocolVersion"], "2024-11-05");
+        assert!(server.handle_line(r#"{"jsonrpc":"2.0","method":"notifications/initialized"}"#).is_none());
+
+        let list = server.handle_line(r#"{"jsonrpc":"2.0","id":2,"method":"tools/list"}"#).unwrap();
+        let tools = list["result"]["tools"].as_array().unwrap();
+        assert_eq!(tools.iter().map(|t| t["name"].as_str().unwrap()).collect::<Vec<_>>(), ["add", "point"]);
+        assert_eq!(tools[0]["description"], "Add two numbers");
+        assert_eq!(tools[0]["inputSchema"]["properties"]["a"]["type"], "integer");
+
+        let sum = server.handle_line(r#"{"jsonrpc":"2.0","id":3,"method":"tools/call","params":{"name":"add","arguments":{"a":2,"b":3}}}"#).unwrap();
+        assert_eq!(sum["result"], json!({"content": [{"type": "text", "text": "5"}]}));
+        let point = server.handle_line(r#"{"jsonrpc":"2.0","id":4,"method":"tools/call","params":{"name":"point","arguments":{"x":1}}}"#).unwrap();
+        assert_eq!(point["result"]["structuredContent"], json!({"x": 1, "y": 1}));
+    }
+
+    #[test]
+    fn test_errors_are_results_or_protocol_errors() {
+        let mut server = server("flow half(n: Int) -> Int:\n    \"Halve a number\"\n    return n / 0\n");
+        let failed = server.handle_line(r#"{"jsonrpc":"2.0","id":1,"method":"tools/call","params":{"name":"half","arguments":{"n":4}}}"#).unwrap();
+        assert_eq!(failed["result"]["isError"], true);
+        assert!(failed["result"]["content"][0]["text"].as_str().unwrap().contains("division by zero"), "{}", failed);
+
+        let unknown = server.handle_line(r#"{"jsonrpc":"2.0","id":2,"method":"tools/call","params":{"name":"helper"}}"#).unwrap();
+        assert_eq!(unknown["error"]["code"], -32602);
+        let method = server.handle_line(r#"{"jsonrpc":"2.0","id":3,"method":"resources/list"}"#).unwrap();
+        assert_eq!(method["error"]["code"], -32601);
+        let garbled = server.handle_line("{not json").unwrap();
+        assert_eq!(garbled["error"]["code"], -32700);
+        assert_eq!(garbled["id"], Json::Null);
+    }
+}
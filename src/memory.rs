@@ -11,6 +11,9 @@ const DEFAULT_MODEL: &str = "nomic-embed-text";
 const DEDUP_THRESHOLD: f64 = 0.95;
 const FORGET_THRESHOLD: f64 = 0.60;
 
+/// (id, text, embedding, score)
+type ScoredRow = (i64, String, Vec<f64>, f64);
+
 /// Semantic memory store.
 pub struct MemoryStore {
     db: Arc<Mutex<Connection>>,
@@ -27,6 +30,7 @@ impl MemoryStore {
     }
 
     /// Create an in-memory store (for testing).
+    #[cfg(test)]
     pub fn in_memory(namespace: &str) -> Result<Self> {
         let conn = Connection::open_in_memory()?;
         Self::init(conn, namespace)
@@ -190,6 +194,7 @@ impl MemoryStore {
     }
 
     /// Get total fact count for this namespace.
+    #[cfg(test)]
     pub fn count(&self) -> Result<usize> {
         let db = self.db.lock().unwrap();
         let count: i64 = db.query_row(
@@ -208,11 +213,11 @@ impl MemoryStore {
             "model": self.model,
             "prompt": text,
         });
-        let client = reqwest::blocking::Client::new();
+        let client = crate::http_client::client(crate::http_client::Service::Ollama);
         let resp = client.post(&url)
             .json(&body)
             .send()
-            .map_err(|e| anyhow::anyhow!("embedding request failed: {}. Is Ollama running with model '{}'?", e, self.model))?;
+            .map_err(|e| crate::retry::request_failed(format!("embedding request failed: {}. Is Ollama running with model '{}'?", e, self.model), &e))?;
 
         if !resp.status().is_success() {
             let status = resp.status();
@@ -281,7 +286,7 @@ impl MemoryStore {
             .collect())
     }
 
-    fn all_with_embeddings_and_scores(&self) -> Result<Vec<(i64, String, Vec<f64>, f64)>> {
+    fn all_with_embeddings_and_scores(&self) -> Result<Vec<ScoredRow>> {
         let db = self.db.lock().unwrap();
         let mut stmt = db.prepare(
             "SELECT id, text, embedding, score FROM memories WHERE namespace = ?1"
@@ -335,6 +340,7 @@ mod tests {
     use super::*;
 
     #[test]
+    #[allow(clippy::approx_constant)]
     fn test_cosine_similarity() {
         let a = vec![1.0, 0.0, 0.0];
         let b = vec![1.0, 0.0, 0.0];
@@ -349,6 +355,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::approx_constant)]
     fn test_embedding_blob_roundtrip() {
         let original = vec![1.0, -2.5, 3.14159, 0.0, -0.001];
         let blob = embedding_to_blob(&original);
@@ -2,6 +2,7 @@
 //!
 //! One-time browser login, then auto-refresh.
 //! Token stored in ~/.cognos/oauth.json
+#![allow(dead_code)]
 
 use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
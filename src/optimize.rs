@@ -0,0 +1,296 @@
+//! Constant folding, run on a program's flows and top-level code before it
+//! runs.
+//!
+//! Operators whose operands are all literals are worked out once, so
+//! `"=" * 40` or `60 * 60 * 24` inside a loop is not recomputed on every
+//! pass, and `if` and `while` conditions that fold to a literal drop the
+//! branches that can never run. Folding uses the interpreter's own operators
+//! and value limits: anything that would fail (`1 / 0`, an overflow, adding
+//! a String to an Int) is left as written, to fail at run time with the
+//! usual error and line.
+
+use crate::ast::{Branch, Catch, Expr, FStringPart, FlowDef, MatchArm, PathStep, Program, Stmt, StmtKind};
+use crate::interpreter::{binop, unary, Value, ValueLimits};
+
+/// Folded Strings longer than this stay as written, so a program doesn't
+/// carry a large repeated string around from the start.
+const MAX_FOLDED_STRING: usize = 4096;
+
+pub fn program(mut program: Program, limits: &ValueLimits) -> Program {
+    program.flows = program.flows.into_iter().map(|flow| self::flow(flow, limits)).collect();
+    program.setup = block(program.setup, limits);
+    program
+}
+
+pub fn flow(mut flow: FlowDef, limits: &ValueLimits) -> FlowDef {
+    for param in &mut flow.params {
+        if let Some(default) = param.default.take() {
+            param.default = Some(expr(default, limits));
+        }
+    }
+    flow.body = block(flow.body, limits);
+    flow
+}
+
+/// A block's statements folded, with an `if` whose branch is decided
+/// replaced by that branch's statements (blocks don't scope variables)
+fn block(stmts: Vec<Stmt>, limits: &ValueLimits) -> Vec<Stmt> {
+    let mut folded = Vec::with_capacity(stmts.len());
+    for stmt in stmts {
+        let span = stmt.span;
+        let kind = match stmt.kind {
+            StmtKind::If { condition, body, elifs, else_body } => {
+                let mut arms = Vec::new();
+                let mut else_body = block(else_body, limits);
+                for (condition, body) in std::iter::once((condition, body)).chain(elifs) {
+                    let condition = expr(condition, limits);
+                    match literal(&condition).map(|v| v.is_truthy()) {
+                        Some(false) => {}
+                        Some(true) => {
+                            else_body = block(body, limits);
+                            break;
+                        }
+                        None => arms.push((condition, block(body, limits))),
+                    }
+                }
+                if arms.is_empty() {
+                    folded.extend(else_body);
+                    continue;
+                }
+                let (condition, body) = arms.remove(0);
+                StmtKind::If { condition, body, elifs: arms, else_body }
+            }
+            StmtKind::While { condition, body } => {
+                let condition = expr(condition, limits);
+                if literal(&condition).is_some_and(|v| !v.is_truthy()) {
+                    continue;
+                }
+                StmtKind::While { condition, body: block(body, limits) }
+            }
+            kind => stmt_kind(kind, limits),
+        };
+        folded.push(Stmt { kind, span });
+    }
+    folded
+}
+
+fn stmt_kind(kind: StmtKind, limits: &ValueLimits) -> StmtKind {
+    let e = |e: Expr| expr(e, limits);
+    let b = |b: Vec<Stmt>| block(b, limits);
+    match kind {
+        StmtKind::Assign { name, expr } => StmtKind::Assign { name, expr: e(expr) },
+        StmtKind::AssignPath { name, path, expr } => StmtKind::AssignPath {
+            name,
+            path: path.into_iter().map(|step| match step {
+                PathStep::Index(index) => PathStep::Index(e(index)),
+                field => field,
+            }).collect(),
+            expr: e(expr),
+        },
+        StmtKind::Emit { value } => StmtKind::Emit { value: e(value) },
+        StmtKind::Return { value } => StmtKind::Return { value: e(value) },
+        StmtKind::Loop { max, body } => StmtKind::Loop { max, body: b(body) },
+        StmtKind::For { var, value_var, iterable, body } => StmtKind::For { var, value_var, iterable: e(iterable), body: b(body) },
+        StmtKind::Match { subject, arms } => StmtKind::Match {
+            subject: e(subject),
+            arms: arms.into_iter().map(|arm| MatchArm { patterns: arm.patterns, body: b(arm.body) }).collect(),
+        },
+        StmtKind::TryCatch { body, catches } => StmtKind::TryCatch {
+            body: b(body),
+            catches: catches.into_iter().map(|c| Catch { body: b(c.body), ..c }).collect(),
+        },
+        StmtKind::Parallel { branches, collect } => StmtKind::Parallel { branches: self::branches(branches, limits), collect },
+        StmtKind::Select { branches } => StmtKind::Select { branches: self::branches(branches, limits) },
+        StmtKind::With { resource, name, body } => StmtKind::With { resource: e(resource), name, body: b(body) },
+        StmtKind::Expr(expr) => StmtKind::Expr(e(expr)),
+        // `if` and `while` are handled by `block`
+        kind @ (StmtKind::If { .. } | StmtKind::While { .. } | StmtKind::Break | StmtKind::Continue | StmtKind::Pass) => kind,
+    }
+}
+
+fn branches(branches: Vec<Branch>, limits: &ValueLimits) -> Vec<Branch> {
+    branches.into_iter().map(|branch| Branch { body: block(branch.body, limits), ..branch }).collect()
+}
+
+/// `expr` with its constant parts worked out
+pub fn expr(expr: Expr, limits: &ValueLimits) -> Expr {
+    let fold = |e: Expr| self::expr(e, limits);
+    let fold_box = |e: Box<Expr>| Box::new(self::expr(*e, limits));
+    let fold_all = |es: Vec<Expr>| es.into_iter().map(|e| self::expr(e, limits)).collect::<Vec<_>>();
+    let fold_kwargs = |kwargs: Vec<(String, Expr)>| kwargs.into_iter().map(|(k, v)| (k, self::expr(v, limits))).collect::<Vec<_>>();
+    match expr {
+        Expr::BinOp { left, op, right } => {
+            let (left, right) = (fold(*left), fold(*right));
+            let folded = match (literal(&left), &op) {
+                // Only the deciding operand is evaluated, so a literal on
+                // the left is enough to pick one
+                (Some(l), crate::ast::BinOp::And | crate::ast::BinOp::Or) => {
+                    return if l.is_truthy() == matches!(op, crate::ast::BinOp::Or) { left } else { right };
+                }
+                (Some(l), _) => literal(&right).and_then(|r| binop(limits, &l, &op, &r).ok()),
+                (None, _) => None,
+            };
+            folded.and_then(to_literal)
+                .unwrap_or_else(|| Expr::BinOp { left: Box::new(left), op, right: Box::new(right) })
+        }
+        Expr::UnaryOp { op, operand } => {
+            let operand = fold(*operand);
+            literal(&operand).and_then(|v| unary(&op, v).ok()).and_then(to_literal)
+                .unwrap_or_else(|| Expr::UnaryOp { op, operand: Box::new(operand) })
+        }
+        Expr::Compare { left, rest } => {
+            let left = fold(*left);
+            let rest: Vec<_> = rest.into_iter().map(|(op, e)| (op, fold(e))).collect();
+            compare(&left, &rest, limits).map(Expr::BoolLit)
+                .unwrap_or_else(|| Expr::Compare { left: Box::new(left), rest })
+        }
+        Expr::Call { name, args, kwargs } => Expr::Call { name, args: fold_all(args), kwargs: fold_kwargs(kwargs) },
+        Expr::Async(inner) => Expr::Async(fold_box(inner)),
+        Expr::Field { object, field } => Expr::Field { object: fold_box(object), field },
+        Expr::Index { object, index } => Expr::Index { object: fold_box(object), index: fold_box(index) },
+        Expr::Slice { object, start, end } => Expr::Slice { object: fold_box(object), start: start.map(fold_box), end: end.map(fold_box) },
+        Expr::MethodCall { object, method, args, kwargs } => Expr::MethodCall {
+            object: fold_box(object), method, args: fold_all(args), kwargs: fold_kwargs(kwargs),
+        },
+        Expr::List(items) => Expr::List(fold_all(items)),
+        Expr::Map(entries) => Expr::Map(fold_kwargs(entries)),
+        Expr::FString(parts) => Expr::FString(parts.into_iter().map(|part| match part {
+            FStringPart::Expr(e) => FStringPart::Expr(fold(e)),
+            FStringPart::Formatted(e, spec) => FStringPart::Formatted(fold(e), spec),
+            literal => literal,
+        }).collect()),
+        Expr::Lambda { params, body } => Expr::Lambda { params, body: fold_box(body) },
+        leaf @ (Expr::Ident(_) | Expr::StringLit(_) | Expr::IntLit(_) | Expr::FloatLit(_) | Expr::BoolLit(_) | Expr::NoneLiteral) => leaf,
+    }
+}
+
+/// A chained comparison of literals, stopping at the first false link as
+/// `eval` does; none if a link it reaches would fail
+fn compare(left: &Expr, rest: &[(crate::ast::BinOp, Expr)], limits: &ValueLimits) -> Option<bool> {
+    let mut l = literal(left)?;
+    let operands = rest.iter().map(|(_, e)| literal(e)).collect::<Option<Vec<_>>>()?;
+    for ((op, _), r) in rest.iter().zip(operands) {
+        if !binop(limits, &l, op, &r).ok()?.is_truthy() {
+            return Some(false);
+        }
+        l = r;
+    }
+    Some(true)
+}
+
+fn literal(expr: &Expr) -> Option<Value> {
+    match expr {
+        Expr::StringLit(s) => Some(Value::String(s.clone())),
+        Expr::IntLit(n) => Some(Value::Int(*n)),
+        Expr::FloatLit(f) => Some(Value::Float(*f)),
+        Expr::BoolLit(b) => Some(Value::Bool(*b)),
+        Expr::NoneLiteral => Some(Value::None),
+        _ => None,
+    }
+}
+
+fn to_literal(value: Value) -> Option<Expr> {
+    match value {
+        Value::String(s) if s.len() <= MAX_FOLDED_STRING => Some(Expr::StringLit(s)),
+        Value::Int(n) => Some(Expr::IntLit(n)),
+        Value::Float(f) => Some(Expr::FloatLit(f)),
+        Value::Bool(b) => Some(Expr::BoolLit(b)),
+        Value::None => Some(Expr::NoneLiteral),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fold(src: &str) -> Vec<Stmt> {
+        let tokens = crate::lexer::Lexer::new(src).tokenize();
+        let parsed = crate::parser::Parser::new(tokens).parse_program().unwrap();
+        program(parsed, &ValueLimits::default()).setup
+    }
+
+    fn assigned(stmts: &[Stmt]) -> Vec<&Expr> {
+        stmts.iter().filter_map(|s| match &s.kind {
+            StmtKind::Assign { expr, .. } => Some(expr),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn test_literal_operators_fold() {
+        let stmts = fold(r#"a = "ab" + "cd" + "e"
+b = 60 * 60 * 24
+c = -(2 + 3)
+d = 7 / 2
+e = not ""
+f = 1 < 2 <= 2
+g = "=" * 3
+h = false or "default"
+i = x + 1 * 2
+"#);
+        assert_eq!(assigned(&stmts)[..8], [
+            &Expr::StringLit("abcde".to_string()),
+            &Expr::IntLit(86400),
+            &Expr::IntLit(-5),
+            &Expr::FloatLit(3.5),
+            &Expr::BoolLit(true),
+            &Expr::BoolLit(true),
+            &Expr::StringLit("===".to_string()),
+            &Expr::StringLit("default".to_string()),
+        ]);
+        // Only the literal part of `x + 1 * 2` folds
+        match assigned(&stmts)[8] {
+            Expr::BinOp { left, right, .. } => {
+                assert!(matches!(**left, Expr::Ident(_)));
+                assert_eq!(**right, Expr::IntLit(2));
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_failing_and_large_operations_are_left_for_run_time() {
+        let stmts = fold("a = 1 / 0\nb = 9223372036854775807 + 1\nc = \"n\" + 1\nd = \"x\" * 10000\ne = 1 < 0 < \"s\"\n");
+        let exprs = assigned(&stmts);
+        assert!(exprs[..4].iter().all(|e| matches!(e, Expr::BinOp { .. })), "{:?}", exprs);
+        // The failing link is never reached
+        assert_eq!(exprs[4], &Expr::BoolLit(false));
+    }
+
+    #[test]
+    fn test_decided_branches_are_pruned() {
+        let stmts = fold(r#"if false:
+    a = 1
+elif x:
+    a = 2
+elif 1 + 1 == 2:
+    a = 3
+else:
+    a = 4
+if 0:
+    b = 1
+else:
+    b = 2
+while false:
+    c = 1
+if true and x:
+    d = 1
+"#);
+        match &stmts[0].kind {
+            StmtKind::If { condition, body, elifs, else_body } => {
+                assert!(matches!(condition, Expr::Ident(_)));
+                assert_eq!(assigned(body), [&Expr::IntLit(2)]);
+                assert!(elifs.is_empty());
+                assert_eq!(assigned(else_body), [&Expr::IntLit(3)]);
+            }
+            other => panic!("{:?}", other),
+        }
+        // `if 0: ... else: b = 2` is just the else body, keeping its line
+        assert_eq!(assigned(&stmts[1..2]), [&Expr::IntLit(2)]);
+        assert_eq!(stmts[1].span.line, 12);
+        // The `while false` is gone; `true and x` is just `x`
+        assert!(matches!(&stmts[2].kind, StmtKind::If { condition: Expr::Ident(_), .. }));
+        assert_eq!(stmts.len(), 3);
+    }
+}
@@ -1,20 +1,180 @@
-/// Recursive descent parser for Cognos.
-/// Parses a token stream into an AST.
+//! Recursive descent parser for Cognos.
+//! Parses a token stream into an AST.
 
 use crate::ast::*;
 use crate::token::{Token, Spanned};
-use crate::error::{CognosError, unexpected_token};
+use crate::error::{CognosError, unexpected_token, ERROR_KINDS};
 use anyhow::{bail, Result};
 
-/// Parse f-string content into parts: literal text and {expr} interpolations
-fn parse_fstring_parts(raw: &str) -> Result<Vec<FStringPart>> {
+/// `name=value` arguments of a call, in source order
+type Kwargs = Vec<(String, Expr)>;
+
+/// `resource("path")` in a file: the contents of `path`, relative to the
+/// file's directory, read once at parse time.
+fn embed_resource(dir: &std::path::Path, line: usize, args: &[Expr], kwargs: &Kwargs) -> Result<Expr> {
+    let path = match (args, kwargs.is_empty()) {
+        ([Expr::StringLit(path)], true) => path,
+        _ => return Err(CognosError::parse(line, "resource() takes one string literal path, e.g. resource(\"prompts/system.md\")").into()),
+    };
+    // `/` works as a separator everywhere, `\` only on Windows
+    let file = dir.join(path.replace('\\', "/"));
+    std::fs::read_to_string(&file)
+        .map(Expr::StringLit)
+        .map_err(|e| CognosError::parse(line, format!("resource(\"{}\"): cannot read {}: {}", path, file.display(), e)).into())
+}
+
+/// Split `expr:spec` at its last top-level colon; colons inside brackets,
+/// braces, parentheses or quotes (slices, map literals) belong to the expr.
+fn split_format_spec(text: &str) -> (&str, Option<&str>) {
+    let mut depth = 0i32;
+    let mut quote: Option<char> = None;
+    let mut split = None;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        if let Some(q) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == q {
+                quote = None;
+            }
+            continue;
+        }
+        match c {
+            '"' | '\'' => quote = Some(c),
+            '[' | '(' | '{' => depth += 1,
+            ']' | ')' | '}' => depth -= 1,
+            ':' if depth == 0 => split = Some(i),
+            _ => {}
+        }
+    }
+    match split {
+        Some(i) => (&text[..i], Some(&text[i + 1..])),
+        None => (text, None),
+    }
+}
+
+/// Parse a type written on its own, such as `Review` or `List[Map[String, Int]]`.
+pub fn parse_type_expr(text: &str) -> Result<TypeExpr> {
+    let mut parser = Parser::new(crate::lexer::Lexer::new(text).tokenize());
+    let ty = parser.parse_type()?;
+    while parser.check(&Token::Newline) {
+        parser.advance();
+    }
+    if !parser.check(&Token::Eof) {
+        bail!("unexpected {} after the type", parser.peek_token());
+    }
+    Ok(ty)
+}
+
+/// Parse a format spec such as `.2f`, `>8`, `+,.1f` or `08d`.
+pub fn parse_format_spec(spec: &str) -> Result<FormatSpec> {
+    let chars: Vec<char> = spec.chars().collect();
+    let mut out = FormatSpec::default();
+    let mut i = 0;
+    let is_align = |c: char| matches!(c, '<' | '>' | '^');
+    if chars.len() >= 2 && is_align(chars[1]) {
+        out.fill = Some(chars[0]);
+        out.align = Some(chars[1]);
+        i = 2;
+    } else if chars.first().is_some_and(|c| is_align(*c)) {
+        out.align = Some(chars[0]);
+        i = 1;
+    }
+    if chars.get(i) == Some(&'+') {
+        out.sign = true;
+        i += 1;
+    }
+    if chars.get(i) == Some(&'0') {
+        out.zero = true;
+        i += 1;
+    }
+    let digits = |i: &mut usize| {
+        let start = *i;
+        while chars.get(*i).is_some_and(|c| c.is_ascii_digit()) {
+            *i += 1;
+        }
+        chars[start..*i].iter().collect::<String>().parse::<usize>().ok()
+    };
+    out.width = digits(&mut i);
+    if chars.get(i) == Some(&',') {
+        out.grouping = true;
+        i += 1;
+    }
+    if chars.get(i) == Some(&'.') {
+        i += 1;
+        out.precision = digits(&mut i);
+        if out.precision.is_none() {
+            bail!("invalid format spec '{}': '.' must be followed by a precision, e.g. {{x:.2f}}", spec);
+        }
+    }
+    if chars.get(i).is_some_and(|c| matches!(c, 'f' | 'e' | '%' | 'd' | 's')) {
+        out.kind = Some(chars[i]);
+        i += 1;
+    }
+    if i != chars.len() || chars.is_empty() {
+        bail!("invalid format spec '{}': expected [[fill]align][+][0][width][,][.precision][f|e|%|d|s], e.g. {{x:.2f}} or {{name:>10}}", spec);
+    }
+    Ok(out)
+}
+
+fn check_binding(name: &str, line: usize) -> Result<()> {
+    if RESERVED_NAMES.contains(&name) {
+        bail!("line {}: '{}' is a built-in and can't be reassigned; use another name", line, name);
+    }
+    Ok(())
+}
+
+/// The names `pattern` binds, in order.
+fn pattern_names(pattern: &Pattern, names: &mut Vec<Name>) {
+    match pattern {
+        Pattern::Bind(name) => names.push(name.clone()),
+        Pattern::Map(entries) => {
+            for (_, p) in entries {
+                pattern_names(p, names);
+            }
+        }
+        Pattern::Wildcard | Pattern::Literal(_) => {}
+    }
+}
+
+/// Split `m["a"].b` into its variable and the steps into it, for the left
+/// side of `=`. None unless the chain starts at a variable.
+fn assign_target(mut expr: Expr) -> Option<(Name, Vec<PathStep>)> {
+    let mut path = Vec::new();
+    loop {
+        match expr {
+            Expr::Ident(name) => {
+                path.reverse();
+                return Some((name, path));
+            }
+            Expr::Field { object, field } => {
+                path.push(PathStep::Field(field));
+                expr = *object;
+            }
+            Expr::Index { object, index } => {
+                path.push(PathStep::Index(*index));
+                expr = *object;
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// Parse f-string content into parts: literal text and {expr} interpolations.
+/// `{{` and `}}` are literal braces.
+pub fn parse_fstring_parts(raw: &str) -> Result<Vec<FStringPart>> {
     let mut parts = Vec::new();
     let mut literal = String::new();
     let chars: Vec<char> = raw.chars().collect();
     let mut i = 0;
 
     while i < chars.len() {
-        if chars[i] == '{' {
+        if matches!(chars[i], '{' | '}') && chars.get(i + 1) == Some(&chars[i]) {
+            literal.push(chars[i]);
+            i += 2;
+        } else if chars[i] == '{' {
             // Save accumulated literal
             if !literal.is_empty() {
                 parts.push(FStringPart::Literal(literal.clone()));
@@ -30,8 +190,10 @@ fn parse_fstring_parts(raw: &str) -> Result<Vec<FStringPart>> {
                 if depth > 0 { expr_str.push(chars[i]); }
                 i += 1;
             }
+            let (expr_str, spec) = split_format_spec(&expr_str);
+            let spec = spec.map(parse_format_spec).transpose()?;
             // Parse the expression
-            let mut lexer = crate::lexer::Lexer::new(&expr_str);
+            let mut lexer = crate::lexer::Lexer::new(expr_str);
             let tokens = lexer.tokenize();
             // Remove EOF
             let tokens: Vec<_> = tokens.into_iter()
@@ -42,7 +204,10 @@ fn parse_fstring_parts(raw: &str) -> Result<Vec<FStringPart>> {
             }
             let mut parser = Parser::new(tokens);
             let expr = parser.parse_expr()?;
-            parts.push(FStringPart::Expr(expr));
+            parts.push(match spec {
+                Some(spec) => FStringPart::Formatted(expr, spec),
+                None => FStringPart::Expr(expr),
+            });
         } else {
             literal.push(chars[i]);
             i += 1;
@@ -56,46 +221,145 @@ fn parse_fstring_parts(raw: &str) -> Result<Vec<FStringPart>> {
     Ok(parts)
 }
 
+/// Where things were in the source, for `cognos fmt` to put comments and
+/// blank lines back around them.
+#[derive(Debug, Default)]
+pub struct Layout {
+    /// First and last line of each import, type, field, flow, statement and
+    /// clause header (`elif`, `else`, `catch`, `branch`), in source order. A
+    /// block's span ends with its header's colon.
+    pub spans: Vec<(usize, usize)>,
+    /// The program's types, flows and top-level statements in source order,
+    /// by index into `Program`.
+    pub items: Vec<Item>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Item {
+    Type(usize),
+    Flow(usize),
+    Setup(usize),
+}
+
 pub struct Parser {
     tokens: Vec<Spanned>,
     pos: usize,
+    /// Directory `resource("...")` paths are relative to; without one they
+    /// are left as calls and read at run time.
+    base_dir: Option<std::path::PathBuf>,
+    /// Files embedded by `resource()` so far.
+    resources: Vec<std::path::PathBuf>,
+    layout: Layout,
+    /// The span in `layout` still waiting for its last line.
+    open_span: Option<usize>,
 }
 
 impl Parser {
     pub fn new(tokens: Vec<Spanned>) -> Self {
-        Self { tokens, pos: 0 }
+        Self { tokens, pos: 0, base_dir: None, resources: Vec::new(), layout: Layout::default(), open_span: None }
+    }
+
+    /// Parse the source of `file`, embedding its `resource()` files.
+    pub fn for_file(mut self, file: &std::path::Path) -> Self {
+        self.base_dir = Some(file.parent().unwrap_or(file).to_path_buf());
+        self
+    }
+
+    /// Paths of the files `resource()` calls embedded, in source order.
+    pub fn resources(&self) -> &[std::path::PathBuf] {
+        &self.resources
+    }
+
+    /// Where the program parsed so far sat in the source.
+    pub fn layout(&self) -> &Layout {
+        &self.layout
     }
 
     pub fn parse_program(&mut self) -> Result<Program> {
         let mut imports = Vec::new();
         let mut types = Vec::new();
         let mut flows = Vec::new();
+        let mut setup = Vec::new();
         self.skip_newlines();
         // Parse imports first
-        while self.check_ident("import") {
-            self.advance();
-            if let Token::StringLit(path) = self.peek_token().clone() {
+        loop {
+            if self.check_ident("import") {
+                self.open_span();
                 self.advance();
-                imports.push(path);
+                if let Token::StringLit(path) = self.peek_token().clone() {
+                    self.advance();
+                    imports.push(Import { path, names: None });
+                } else {
+                    bail!("import requires a string path: import \"file.cog\"");
+                }
+            } else if self.check_ident("from") && matches!(self.peek_ahead(1), Token::StringLit(_)) {
+                self.open_span();
+                imports.push(self.parse_from_import()?);
             } else {
-                bail!("import requires a string path: import \"file.cog\"");
+                break;
             }
+            self.close_span();
             self.skip_newlines();
         }
         while !self.is_at_end() {
-            if self.check(&Token::Type) {
-                types.push(self.parse_type_def()?);
-            } else {
-                flows.push(self.parse_flow()?);
+            match self.peek_token() {
+                Token::Type => {
+                    self.layout.items.push(Item::Type(types.len()));
+                    types.push(self.parse_type_def()?);
+                }
+                Token::Flow => {
+                    self.layout.items.push(Item::Flow(flows.len()));
+                    flows.push(self.parse_flow()?);
+                }
+                token @ (Token::Return | Token::Break | Token::Continue) => {
+                    return Err(CognosError::parse(
+                        self.current_line(),
+                        format!("{} is only allowed inside a flow", token),
+                    ).into());
+                }
+                _ => {
+                    let line = self.current_line();
+                    self.layout.items.push(Item::Setup(setup.len()));
+                    setup.push(self.parse_stmt()?);
+                    // parse_stmt stops early on junk like `this is not code`
+                    let ended = self.pos > 0 && matches!(self.tokens[self.pos - 1].token, Token::Newline | Token::Dedent);
+                    if !ended && !self.is_at_end() {
+                        return Err(CognosError::parse(
+                            line,
+                            format!("expected a new line after a top-level statement, got {}", self.peek_token()),
+                        ).into());
+                    }
+                }
             }
             self.skip_newlines();
         }
-        Ok(Program { imports, types, flows })
+        Ok(Program { imports, types, flows, setup })
+    }
+
+    /// `from "path" import name, name, ...`
+    fn parse_from_import(&mut self) -> Result<Import> {
+        self.advance(); // consume 'from'
+        let Token::StringLit(path) = self.peek_token() else { unreachable!("checked by the caller") };
+        self.advance();
+        if !self.check_ident("import") {
+            return Err(CognosError::parse(
+                self.current_line(),
+                format!("expected 'import' after from \"{}\", got {}", path, self.peek_token()),
+            ).into());
+        }
+        self.advance();
+        let mut names = vec![self.expect_ident()?];
+        while self.check(&Token::Comma) {
+            self.advance();
+            names.push(self.expect_ident()?);
+        }
+        Ok(Import { path, names: Some(names) })
     }
 
     // ─── Type Definition ───
 
     fn parse_type_def(&mut self) -> Result<TypeDef> {
+        self.open_span();
         self.expect(Token::Type)?;
         let name = self.expect_ident()?;
         self.expect(Token::Colon)?;
@@ -105,10 +369,12 @@ impl Parser {
         if let Token::StringLit(_) = self.peek_token() {
             // Inline enum: type Name: "a" | "b" | "c"
             let variants = self.parse_enum_variants()?;
+            self.close_span();
             self.skip_newlines();
             return Ok(TypeDef::Enum { name, variants });
         }
 
+        self.close_span();
         self.expect_newline()?;
 
         // Check if first token after indent is a string literal (enum on next line)
@@ -128,6 +394,7 @@ impl Parser {
             if self.check(&Token::Dedent) || self.is_at_end() {
                 break;
             }
+            self.open_span();
             let fname = self.expect_ident_or_keyword()?;
             let optional = if self.check(&Token::Question) {
                 self.advance();
@@ -138,6 +405,7 @@ impl Parser {
             self.expect(Token::Colon)?;
             let ty = self.parse_type()?;
             fields.push(TypeField { name: fname, ty, optional });
+            self.close_span();
             self.skip_newlines();
         }
         if self.check(&Token::Dedent) {
@@ -174,24 +442,40 @@ impl Parser {
     // ─── Flow ───
 
     fn parse_flow(&mut self) -> Result<FlowDef> {
+        self.open_span();
         self.expect(Token::Flow)?;
         let name = self.expect_ident()?;
+        if BUILTIN_FUNCTIONS.contains(&name.as_str()) {
+            bail!("line {}: flow '{}' has the name of a built-in function, which calls always reach instead; rename the flow",
+                self.current_line(), name);
+        }
 
         // Optional params: flow name(param: Type, ...)
         let mut params = Vec::new();
         if self.check(&Token::LParen) {
             self.advance();
             while !self.check(&Token::RParen) {
-                let pname = self.expect_ident()?;
+                let variadic = self.check(&Token::Star);
+                if variadic {
+                    self.advance();
+                    if let Some(first) = params.iter().find(|p: &&Param| p.variadic) {
+                        bail!("line {}: flow '{}' already has *{}; only one parameter can take the remaining arguments",
+                            self.current_line(), name, first.name);
+                    }
+                }
+                let pname = self.expect_binding()?;
                 self.expect(Token::Colon)?;
                 let ty = self.parse_type()?;
                 let default = if self.check(&Token::Eq) {
+                    if variadic {
+                        bail!("line {}: *{} can't have a default; it is [] when no arguments are left", self.current_line(), pname);
+                    }
                     self.advance();
                     Some(self.parse_expr()?)
                 } else {
                     None
                 };
-                params.push(Param { name: pname, ty, default });
+                params.push(Param { name: pname, ty, default, variadic });
                 if !self.check(&Token::RParen) {
                     self.expect(Token::Comma)?;
                 }
@@ -208,12 +492,13 @@ impl Parser {
         };
 
         self.expect(Token::Colon)?;
+        self.close_span();
         self.expect_newline()?;
         let body = self.parse_block()?;
 
         // Extract docstring: first statement being a bare string literal
         let mut description = None;
-        let body = if let Some(Stmt::Expr(Expr::StringLit(s))) = body.first() {
+        let body = if let Some(StmtKind::Expr(Expr::StringLit(s))) = body.first().map(|s| &s.kind) {
             description = Some(s.clone());
             body[1..].to_vec()
         } else {
@@ -244,21 +529,35 @@ impl Parser {
     // ─── Statements ───
 
     fn parse_stmt(&mut self) -> Result<Stmt> {
+        self.open_span();
+        let span = self.current_span();
+        let kind = self.parse_stmt_kind()?;
+        // Block statements closed their span at the header
+        self.close_span();
+        Ok(Stmt { kind, span })
+    }
+
+    fn parse_stmt_kind(&mut self) -> Result<StmtKind> {
         // Check for keywords first
         match self.peek_token() {
             Token::If => return self.parse_if(),
             Token::Loop => return self.parse_loop(),
+            Token::While => return self.parse_while(),
             Token::For => return self.parse_for(),
             Token::Try => return self.parse_try_catch(),
             Token::Emit => return self.parse_emit(),
             Token::Return => return self.parse_return(),
-            Token::Break => { self.advance(); self.skip_newlines(); return Ok(Stmt::Break); }
-            Token::Continue => { self.advance(); self.skip_newlines(); return Ok(Stmt::Continue); }
-            Token::Parallel => return self.parse_parallel(),
+            Token::Break => { self.advance(); self.skip_newlines(); return Ok(StmtKind::Break); }
+            Token::Continue => { self.advance(); self.skip_newlines(); return Ok(StmtKind::Continue); }
+            Token::Parallel => return self.parse_parallel(None),
             Token::Select => return self.parse_select(),
-            Token::Pass => { self.advance(); self.skip_newlines(); return Ok(Stmt::Pass); }
+            Token::With => return self.parse_with(),
+            Token::Pass => { self.advance(); self.skip_newlines(); return Ok(StmtKind::Pass); }
             _ => {}
         }
+        if self.at_match() {
+            return self.parse_match();
+        }
 
         // Assignment or bare expression
         let expr = self.parse_expr()?;
@@ -267,58 +566,60 @@ impl Parser {
         if self.check(&Token::Eq) {
             match expr {
                 Expr::Ident(name) => {
+                    check_binding(&name, self.current_line())?;
                     self.advance(); // consume =
+                    if self.check(&Token::Parallel) {
+                        return self.parse_parallel(Some(name));
+                    }
                     let value = self.parse_expr()?;
                     self.skip_newlines();
-                    return Ok(Stmt::Assign { name, expr: value });
+                    return Ok(StmtKind::Assign { name, expr: value });
                 }
-                Expr::Index { object, index } => {
-                    // map[key] = value → desugar to map = __map_set__(map, key, value)
-                    if let Expr::Ident(name) = *object {
-                        self.advance(); // consume =
-                        let value = self.parse_expr()?;
-                        self.skip_newlines();
-                        return Ok(Stmt::Assign {
-                            name: name.clone(),
-                            expr: Expr::Call {
-                                name: "__map_set__".to_string(),
-                                args: vec![Expr::Ident(name), *index, value],
-                                kwargs: vec![],
-                            },
-                        });
-                    }
-                    bail!("line {}: left side of indexed assignment must be a variable", self.current_line());
+                Expr::Index { .. } | Expr::Field { .. } => {
+                    let Some((name, path)) = assign_target(expr) else {
+                        bail!("line {}: left side of indexed assignment must start with a variable", self.current_line());
+                    };
+                    self.advance(); // consume =
+                    let value = self.parse_expr()?;
+                    self.skip_newlines();
+                    return Ok(StmtKind::AssignPath { name, path, expr: value });
                 }
                 _ => {
-                    bail!("line {}: left side of assignment must be a name", self.current_line());
+                    bail!("line {}: left side of assignment must be a name, field or index", self.current_line());
                 }
             }
         }
 
         self.skip_newlines();
-        Ok(Stmt::Expr(expr))
+        Ok(StmtKind::Expr(expr))
     }
 
-    fn parse_emit(&mut self) -> Result<Stmt> {
+    fn parse_emit(&mut self) -> Result<StmtKind> {
         self.expect(Token::Emit)?;
-        self.expect(Token::LParen)?;
-        let value = self.parse_expr()?;
-        self.expect(Token::RParen)?;
+        let call = self.parse_call("emit".to_string())?;
         self.skip_newlines();
-        Ok(Stmt::Emit { value })
+        match call {
+            Expr::Call { mut args, kwargs, .. } if args.len() == 1 && kwargs.is_empty() => {
+                Ok(StmtKind::Emit { value: args.remove(0) })
+            }
+            // emit(x, pretty=true) and friends go through the builtin
+            Expr::Call { ref args, .. } if !args.is_empty() => Ok(StmtKind::Expr(call)),
+            _ => bail!("emit() requires a value to output"),
+        }
     }
 
-    fn parse_return(&mut self) -> Result<Stmt> {
+    fn parse_return(&mut self) -> Result<StmtKind> {
         self.advance(); // consume 'return'
         let value = self.parse_expr()?;
         self.skip_newlines();
-        Ok(Stmt::Return { value })
+        Ok(StmtKind::Return { value })
     }
 
-    fn parse_if(&mut self) -> Result<Stmt> {
+    fn parse_if(&mut self) -> Result<StmtKind> {
         self.expect(Token::If)?;
         let condition = self.parse_expr()?;
         self.expect(Token::Colon)?;
+        self.close_span();
 
         // Body can be inline (single stmt on same line) or block
         let body = if self.check(&Token::Newline) {
@@ -334,9 +635,11 @@ impl Parser {
 
         self.skip_newlines();
         while self.check(&Token::Elif) {
+            self.open_span();
             self.advance();
             let cond = self.parse_expr()?;
             self.expect(Token::Colon)?;
+            self.close_span();
             let elif_body = if self.check(&Token::Newline) {
                 self.advance();
                 self.parse_block()?
@@ -348,8 +651,10 @@ impl Parser {
         }
 
         if self.check(&Token::Else) {
+            self.open_span();
             self.advance();
             self.expect(Token::Colon)?;
+            self.close_span();
             else_body = if self.check(&Token::Newline) {
                 self.advance();
                 self.parse_block()?
@@ -358,10 +663,10 @@ impl Parser {
             };
         }
 
-        Ok(Stmt::If { condition, body, elifs, else_body })
+        Ok(StmtKind::If { condition, body, elifs, else_body })
     }
 
-    fn parse_loop(&mut self) -> Result<Stmt> {
+    fn parse_loop(&mut self) -> Result<StmtKind> {
         self.expect(Token::Loop)?;
         // Optional: loop max=N (omit for infinite loop)
         let max = if self.check_ident("max") {
@@ -378,96 +683,264 @@ impl Parser {
             None // infinite loop — exits only via break/return
         };
         self.expect(Token::Colon)?;
+        self.close_span();
         self.expect_newline()?;
         let body = self.parse_block()?;
-        Ok(Stmt::Loop { max, body })
+        Ok(StmtKind::Loop { max, body })
+    }
+
+    fn parse_while(&mut self) -> Result<StmtKind> {
+        self.expect(Token::While)?;
+        let condition = self.parse_expr()?;
+        self.expect(Token::Colon)?;
+        self.close_span();
+        self.expect_newline()?;
+        let body = self.parse_block()?;
+        Ok(StmtKind::While { condition, body })
+    }
+
+    /// `match` is only a keyword at the start of a `match subject:` line,
+    /// so it stays usable as a name (`match = re_search(...)`).
+    fn at_match(&self) -> bool {
+        if !self.check_ident("match") || matches!(self.peek_ahead(1), Token::Eq | Token::Colon | Token::Newline) {
+            return false;
+        }
+        let end = self.tokens[self.pos..].iter()
+            .position(|t| matches!(t.token, Token::Newline | Token::Eof))
+            .map_or(self.tokens.len(), |i| self.pos + i);
+        matches!(self.tokens[end - 1].token, Token::Colon)
+    }
+
+    fn parse_match(&mut self) -> Result<StmtKind> {
+        self.advance(); // consume 'match'
+        let subject = self.parse_expr()?;
+        self.expect(Token::Colon)?;
+        self.close_span();
+        self.expect_newline()?;
+        self.expect(Token::Indent)?;
+        let mut arms: Vec<MatchArm> = Vec::new();
+        loop {
+            self.skip_newlines();
+            if self.check(&Token::Dedent) || self.is_at_end() {
+                break;
+            }
+            self.open_span();
+            let line = self.current_line();
+            if !self.check_ident("case") {
+                bail!("line {}: expected 'case <pattern>:' inside match, got {}", line, self.peek_token());
+            }
+            if arms.last().is_some_and(|arm| arm.patterns.iter().any(Pattern::is_irrefutable)) {
+                bail!("line {}: the case before this one matches everything, so this one can never run", line);
+            }
+            self.advance();
+            let mut patterns = vec![self.parse_pattern()?];
+            while self.check(&Token::Pipe) {
+                self.advance();
+                patterns.push(self.parse_pattern()?);
+            }
+            let mut names = Vec::new();
+            for pattern in &patterns {
+                pattern_names(pattern, &mut names);
+            }
+            if patterns.len() > 1 && !names.is_empty() {
+                bail!("line {}: a case with '|' alternatives can't bind names", line);
+            }
+            if let Some(name) = names.iter().enumerate().find_map(|(i, n)| names[..i].contains(n).then_some(n)) {
+                bail!("line {}: '{}' is bound twice in one pattern", line, name);
+            }
+            self.expect(Token::Colon)?;
+            self.close_span();
+            self.expect_newline()?;
+            let body = self.parse_block()?;
+            arms.push(MatchArm { patterns, body });
+        }
+        if self.check(&Token::Dedent) {
+            self.advance();
+        }
+        if arms.is_empty() {
+            bail!("line {}: match requires at least one case", self.current_line());
+        }
+        Ok(StmtKind::Match { subject, arms })
     }
 
-    fn parse_for(&mut self) -> Result<Stmt> {
+    fn parse_pattern(&mut self) -> Result<Pattern> {
+        let line = self.current_line();
+        let literal = match self.peek_token() {
+            Token::Ident(name) => {
+                self.advance();
+                if &*name == "_" {
+                    return Ok(Pattern::Wildcard);
+                }
+                check_binding(&name, line)?;
+                return Ok(Pattern::Bind(name));
+            }
+            Token::LBrace => {
+                self.advance();
+                let mut entries: Vec<(String, Pattern)> = Vec::new();
+                while !self.check(&Token::RBrace) {
+                    let Token::StringLit(key) = self.peek_token() else {
+                        bail!("line {}: map pattern keys must be string literals", self.current_line());
+                    };
+                    if entries.iter().any(|(k, _)| *k == key) {
+                        bail!("line {}: key \"{}\" appears twice in a map pattern", self.current_line(), key);
+                    }
+                    self.advance();
+                    self.expect(Token::Colon)?;
+                    entries.push((key, self.parse_pattern()?));
+                    if !self.check(&Token::RBrace) {
+                        self.expect(Token::Comma)?;
+                    }
+                }
+                self.expect(Token::RBrace)?;
+                return Ok(Pattern::Map(entries));
+            }
+            Token::StringLit(s) => Expr::StringLit(s),
+            Token::IntLit(n) => Expr::IntLit(n),
+            Token::FloatLit(f) => Expr::FloatLit(f),
+            Token::True => Expr::BoolLit(true),
+            Token::False => Expr::BoolLit(false),
+            Token::None_ => Expr::NoneLiteral,
+            Token::Minus => {
+                self.advance();
+                match self.peek_token() {
+                    Token::IntLit(n) => Expr::IntLit(-n),
+                    Token::FloatLit(f) => Expr::FloatLit(-f),
+                    other => bail!("line {}: expected a number after '-' in a pattern, got {}", line, other),
+                }
+            }
+            other => bail!("line {}: expected a pattern (a literal, a name, _ or {{\"key\": pattern}}), got {}", line, other),
+        };
+        self.advance();
+        Ok(Pattern::Literal(literal))
+    }
+
+    fn parse_for(&mut self) -> Result<StmtKind> {
         self.expect(Token::For)?;
-        let var = self.expect_ident()?;
+        let var = self.expect_binding()?;
         let value_var = if self.check(&Token::Comma) {
             self.advance();
-            Some(self.expect_ident()?)
+            Some(self.expect_binding()?)
         } else {
             None
         };
         self.expect(Token::In)?;
         let iterable = self.parse_expr()?;
         self.expect(Token::Colon)?;
+        self.close_span();
         self.expect_newline()?;
         let body = self.parse_block()?;
-        Ok(Stmt::For { var, value_var, iterable, body })
+        Ok(StmtKind::For { var, value_var, iterable, body })
     }
 
-    fn parse_try_catch(&mut self) -> Result<Stmt> {
+    fn parse_try_catch(&mut self) -> Result<StmtKind> {
         self.expect(Token::Try)?;
         self.expect(Token::Colon)?;
+        self.close_span();
         self.expect_newline()?;
         let body = self.parse_block()?;
         self.skip_newlines();
-        self.expect(Token::Catch)?;
-        let error_var = if !self.check(&Token::Colon) {
-            Some(self.expect_ident()?)
-        } else {
-            None
-        };
-        self.expect(Token::Colon)?;
-        self.expect_newline()?;
-        let catch_body = self.parse_block()?;
-        Ok(Stmt::TryCatch { body, error_var, catch_body })
-    }
-
-    fn parse_parallel(&mut self) -> Result<Stmt> {
-        self.expect(Token::Parallel)?;
-        self.expect(Token::Colon)?;
-        self.expect_newline()?;
-        self.expect(Token::Indent)?;
-        let mut branches = Vec::new();
-        loop {
-            self.skip_newlines();
-            if self.check(&Token::Dedent) || self.is_at_end() {
-                break;
+        if !self.check(&Token::Catch) {
+            return Err(CognosError::parse(self.current_line(), format!("expected 'catch' after the 'try' block, got {}", self.peek_token())).into());
+        }
+        let mut catches: Vec<Catch> = Vec::new();
+        while self.check(&Token::Catch) {
+            self.open_span();
+            let line = self.current_line();
+            if catches.last().is_some_and(Catch::catches_all) {
+                bail!("line {}: the catch before this one catches every error, so this one can never run", line);
+            }
+            self.advance();
+            let mut kind = None;
+            let mut var = None;
+            if let Token::Ident(name) = self.peek_token() {
+                let name = name.to_string();
+                if ERROR_KINDS.contains(&name.as_str()) {
+                    self.advance();
+                    kind = Some(name);
+                    if self.check_ident("as") {
+                        self.advance();
+                        var = Some(self.expect_binding()?);
+                    }
+                } else if matches!(self.tokens.get(self.pos + 1).map(|t| &t.token), Some(Token::Ident(next)) if &**next == "as") {
+                    return Err(CognosError::parse_hint(line, format!("unknown error kind '{}'", name), format!("catch one of: {}", ERROR_KINDS.join(", "))).into());
+                }
+            }
+            if kind.is_none() && !self.check(&Token::Colon) {
+                var = Some(self.expect_binding()?);
             }
-            self.expect(Token::Branch)?;
             self.expect(Token::Colon)?;
+            self.close_span();
             self.expect_newline()?;
             let body = self.parse_block()?;
-            branches.push(body);
-        }
-        if self.check(&Token::Dedent) {
-            self.advance();
+            catches.push(Catch { kind, var, body });
+            self.skip_newlines();
         }
-        if branches.is_empty() {
-            bail!("parallel block requires at least one branch:");
+        Ok(StmtKind::TryCatch { body, catches })
+    }
+
+    fn parse_with(&mut self) -> Result<StmtKind> {
+        self.expect(Token::With)?;
+        let resource = self.parse_expr()?;
+        if !self.check_ident("as") {
+            return Err(CognosError::parse(self.current_line(), format!("expected 'as' in 'with <handle> as <name>:', got {}", self.peek_token())).into());
         }
-        Ok(Stmt::Parallel { branches })
+        self.advance();
+        let name = self.expect_binding()?;
+        self.expect(Token::Colon)?;
+        self.close_span();
+        self.expect_newline()?;
+        let body = self.parse_block()?;
+        Ok(StmtKind::With { resource, name, body })
     }
 
-    fn parse_select(&mut self) -> Result<Stmt> {
+    fn parse_parallel(&mut self, collect: Option<Name>) -> Result<StmtKind> {
+        self.expect(Token::Parallel)?;
+        let branches = self.parse_branches("parallel")?;
+        Ok(StmtKind::Parallel { branches, collect })
+    }
+
+    fn parse_select(&mut self) -> Result<StmtKind> {
         self.expect(Token::Select)?;
+        let branches = self.parse_branches("select")?;
+        Ok(StmtKind::Select { branches })
+    }
+
+    /// The `: NEWLINE INDENT (branch [name]: block)+ DEDENT` tail shared by parallel and select
+    fn parse_branches(&mut self, keyword: &str) -> Result<Vec<Branch>> {
         self.expect(Token::Colon)?;
+        self.close_span();
         self.expect_newline()?;
         self.expect(Token::Indent)?;
-        let mut branches = Vec::new();
+        let mut branches: Vec<Branch> = Vec::new();
         loop {
             self.skip_newlines();
             if self.check(&Token::Dedent) || self.is_at_end() {
                 break;
             }
+            self.open_span();
             self.expect(Token::Branch)?;
+            let name = if self.check(&Token::Colon) { None } else { Some(self.expect_binding()?) };
+            if let Some(name) = &name {
+                if branches.iter().any(|b| b.name.as_ref() == Some(name)) {
+                    return Err(CognosError::parse(
+                        self.current_line(),
+                        format!("duplicate branch name '{}' in {} block", name, keyword),
+                    ).into());
+                }
+            }
             self.expect(Token::Colon)?;
+            self.close_span();
             self.expect_newline()?;
             let body = self.parse_block()?;
-            branches.push(body);
+            branches.push(Branch { name, body });
         }
         if self.check(&Token::Dedent) {
             self.advance();
         }
         if branches.is_empty() {
-            bail!("select block requires at least one branch:");
+            bail!("{} block requires at least one branch:", keyword);
         }
-        Ok(Stmt::Select { branches })
+        Ok(branches)
     }
 
     // ─── Expressions ───
@@ -496,8 +969,11 @@ impl Parser {
         Ok(left)
     }
 
+    /// `a < b` is a BinOp; `a < b < c` chains like Python, as
+    /// `a < b and b < c` with `b` evaluated once.
     fn parse_comparison(&mut self) -> Result<Expr> {
-        let mut left = self.parse_addition()?;
+        let left = self.parse_addition()?;
+        let mut rest = Vec::new();
         loop {
             let op = match self.peek_token() {
                 Token::EqEq => BinOp::Eq,
@@ -519,10 +995,16 @@ impl Parser {
                 _ => break,
             };
             self.advance();
-            let right = self.parse_addition()?;
-            left = Expr::BinOp { left: Box::new(left), op, right: Box::new(right) };
+            rest.push((op, self.parse_addition()?));
+        }
+        match rest.len() {
+            0 => Ok(left),
+            1 => {
+                let (op, right) = rest.remove(0);
+                Ok(Expr::BinOp { left: Box::new(left), op, right: Box::new(right) })
+            }
+            _ => Ok(Expr::Compare { left: Box::new(left), rest }),
         }
-        Ok(left)
     }
 
     fn parse_addition(&mut self) -> Result<Expr> {
@@ -546,6 +1028,7 @@ impl Parser {
             let op = match self.peek_token() {
                 Token::Star => BinOp::Mul,
                 Token::Slash => BinOp::Div,
+                Token::SlashSlash => BinOp::FloorDiv,
                 Token::Percent => BinOp::Mod,
                 _ => break,
             };
@@ -577,11 +1060,7 @@ impl Parser {
             if let Expr::FloatLit(n) = operand {
                 return Ok(Expr::FloatLit(-n));
             }
-            return Ok(Expr::BinOp {
-                left: Box::new(Expr::IntLit(0)),
-                op: BinOp::Sub,
-                right: Box::new(operand),
-            });
+            return Ok(Expr::UnaryOp { op: UnaryOp::Neg, operand: Box::new(operand) });
         }
         self.parse_postfix()
     }
@@ -594,16 +1073,8 @@ impl Parser {
                 let field = self.expect_ident_or_keyword()?;
                 // Check if it's a method call: obj.method(args)
                 if self.check(&Token::LParen) {
-                    self.advance(); // consume (
-                    let mut args = Vec::new();
-                    while !self.check(&Token::RParen) {
-                        args.push(self.parse_expr()?);
-                        if !self.check(&Token::RParen) {
-                            self.expect(Token::Comma)?;
-                        }
-                    }
-                    self.expect(Token::RParen)?;
-                    expr = Expr::MethodCall { object: Box::new(expr), method: field, args };
+                    let (args, kwargs) = self.parse_args()?;
+                    expr = Expr::MethodCall { object: Box::new(expr), method: field, args, kwargs };
                 } else {
                     expr = Expr::Field { object: Box::new(expr), field };
                 }
@@ -631,7 +1102,7 @@ impl Parser {
                 }
             } else if self.check(&Token::LParen) {
                 if let Expr::Ident(name) = expr {
-                    expr = self.parse_call(name)?;
+                    expr = self.parse_call(name.to_string())?;
                 } else {
                     break;
                 }
@@ -643,24 +1114,37 @@ impl Parser {
     }
 
     fn parse_call(&mut self, name: String) -> Result<Expr> {
+        let line = self.current_line();
+        let (args, kwargs) = self.parse_args()?;
+        match (&self.base_dir, name.as_str()) {
+            (Some(dir), "resource") => {
+                let embedded = embed_resource(dir, line, &args, &kwargs)?;
+                if let [Expr::StringLit(path)] = args.as_slice() {
+                    self.resources.push(dir.join(path.replace('\\', "/")));
+                }
+                Ok(embedded)
+            }
+            _ => Ok(Expr::Call { name, args, kwargs }),
+        }
+    }
+
+    /// `(args, name=kwargs)` of a call or method call
+    fn parse_args(&mut self) -> Result<(Vec<Expr>, Kwargs)> {
         self.expect(Token::LParen)?;
         let mut args = Vec::new();
         let mut kwargs = Vec::new();
 
         while !self.check(&Token::RParen) {
-            // Check for kwarg: name=expr
-            if let Token::Ident(pname) = self.peek_token() {
-                if self.peek_ahead(1) == Token::Eq {
-                    let pname = pname.clone();
-                    self.advance(); // consume name
-                    self.advance(); // consume =
-                    let val = self.parse_expr()?;
-                    kwargs.push((pname, val));
-                    if !self.check(&Token::RParen) {
-                        self.expect(Token::Comma)?;
-                    }
-                    continue;
+            // Check for kwarg: name=expr (keywords allowed, e.g. type="Invoice")
+            if self.peek_ahead(1) == Token::Eq {
+                let pname = self.expect_ident_or_keyword()?;
+                self.advance(); // consume =
+                let val = self.parse_expr()?;
+                kwargs.push((pname, val));
+                if !self.check(&Token::RParen) {
+                    self.expect(Token::Comma)?;
                 }
+                continue;
             }
             // Positional arg
             args.push(self.parse_expr()?);
@@ -669,7 +1153,7 @@ impl Parser {
             }
         }
         self.expect(Token::RParen)?;
-        Ok(Expr::Call { name, args, kwargs })
+        Ok((args, kwargs))
     }
 
     fn parse_primary(&mut self) -> Result<Expr> {
@@ -681,11 +1165,11 @@ impl Parser {
                     return self.parse_call("await".to_string());
                 }
                 let expr = self.parse_primary()?;
-                return Ok(Expr::Call {
+                Ok(Expr::Call {
                     name: "await".to_string(),
                     args: vec![expr],
                     kwargs: vec![],
-                });
+                })
             }
             Token::Ident(name) => {
                 let name = name.clone();
@@ -703,15 +1187,14 @@ impl Parser {
                 Ok(Expr::FString(parse_fstring_parts(&raw)?))
             }
             Token::IntLit(n) => {
-                let n = n;
                 self.advance();
                 Ok(Expr::IntLit(n))
             }
             Token::FloatLit(n) => {
-                let n = n;
                 self.advance();
                 Ok(Expr::FloatLit(n))
             }
+            Token::Pipe => self.parse_lambda(),
             Token::True => { self.advance(); Ok(Expr::BoolLit(true)) }
             Token::False => { self.advance(); Ok(Expr::BoolLit(false)) }
             Token::None_ => { self.advance(); Ok(Expr::NoneLiteral) }
@@ -754,10 +1237,32 @@ impl Parser {
                 self.expect(Token::RBrace)?;
                 Ok(Expr::Map(entries))
             }
-            other => return Err(unexpected_token(self.current_line(), &other, "").into()),
+            other => Err(unexpected_token(self.current_line(), &other, "").into()),
         }
     }
 
+    /// `|x, y| body`: the body is one expression and takes everything up to
+    /// the end of the enclosing argument, item or line
+    fn parse_lambda(&mut self) -> Result<Expr> {
+        let line = self.current_line();
+        self.expect(Token::Pipe)?;
+        let mut params: Vec<Name> = Vec::new();
+        while !self.check(&Token::Pipe) {
+            let param = self.expect_name()?;
+            check_binding(&param, line)?;
+            if params.contains(&param) {
+                bail!("line {}: lambda parameter '{}' appears twice", line, param);
+            }
+            params.push(param);
+            if !self.check(&Token::Pipe) {
+                self.expect(Token::Comma)?;
+            }
+        }
+        self.expect(Token::Pipe)?;
+        let body = self.parse_expr()?;
+        Ok(Expr::Lambda { params, body: Box::new(body) })
+    }
+
     // ─── Types ───
 
     fn parse_type(&mut self) -> Result<TypeExpr> {
@@ -805,7 +1310,7 @@ impl Parser {
     }
 
     fn check_ident(&self, name: &str) -> bool {
-        matches!(self.peek_token(), Token::Ident(ref s) if s == name)
+        matches!(self.peek_token(), Token::Ident(ref s) if &**s == name)
     }
 
     fn advance(&mut self) {
@@ -828,6 +1333,11 @@ impl Parser {
     }
 
     fn expect_ident(&mut self) -> Result<String> {
+        self.expect_name().map(|name| name.to_string())
+    }
+
+    /// Like expect_ident but keeps the interned name (for variable bindings)
+    fn expect_name(&mut self) -> Result<Name> {
         if let Token::Ident(name) = self.peek_token() {
             self.advance();
             Ok(name)
@@ -839,16 +1349,25 @@ impl Parser {
         }
     }
 
+    /// A name about to be bound to a value, which mustn't be a reserved one.
+    fn expect_binding(&mut self) -> Result<Name> {
+        let line = self.current_line();
+        let name = self.expect_name()?;
+        check_binding(&name, line)?;
+        Ok(name)
+    }
+
     /// Like expect_ident but also accepts keyword tokens (for field names in type defs)
     fn expect_ident_or_keyword(&mut self) -> Result<String> {
         let name = match self.peek_token() {
-            Token::Ident(s) => s,
+            Token::Ident(s) => s.to_string(),
             Token::Flow => "flow".to_string(),
             Token::Let => "let".to_string(),
             Token::If => "if".to_string(),
             Token::Else => "else".to_string(),
             Token::Elif => "elif".to_string(),
             Token::Loop => "loop".to_string(),
+            Token::While => "while".to_string(),
             Token::Break => "break".to_string(),
             Token::Continue => "continue".to_string(),
             Token::Return => "return".to_string(),
@@ -870,6 +1389,7 @@ impl Parser {
             Token::None_ => "none".to_string(),
             Token::Pass => "pass".to_string(),
             Token::Select => "select".to_string(),
+            Token::With => "with".to_string(),
             _ => {
                 return Err(CognosError::parse(
                     self.current_line(),
@@ -891,6 +1411,23 @@ impl Parser {
         }
     }
 
+    /// Start a span in the layout at the next token's line.
+    fn open_span(&mut self) {
+        self.open_span = Some(self.layout.spans.len());
+        let line = self.current_line();
+        self.layout.spans.push((line, line));
+    }
+
+    /// End the open span, if any, at the last token consumed.
+    fn close_span(&mut self) {
+        let Some(i) = self.open_span.take() else { return };
+        let last = self.tokens[..self.pos].iter().rev()
+            .find(|t| !matches!(t.token, Token::Newline | Token::Indent | Token::Dedent));
+        if let Some(t) = last {
+            self.layout.spans[i].1 = t.line.max(self.layout.spans[i].0);
+        }
+    }
+
     fn skip_newlines(&mut self) {
         while self.check(&Token::Newline) {
             self.advance();
@@ -901,6 +1438,13 @@ impl Parser {
         self.check(&Token::Eof)
     }
 
+    /// Line and column of the next token.
+    fn current_span(&self) -> Span {
+        self.tokens.get(self.pos)
+            .map(|t| Span { line: t.line, col: t.col })
+            .unwrap_or_default()
+    }
+
     fn current_line(&self) -> usize {
         if self.pos < self.tokens.len() {
             self.tokens[self.pos].line
@@ -942,7 +1486,7 @@ mod tests {
         let flow = &program.flows[0];
         assert_eq!(flow.name, "greet");
         assert_eq!(flow.params.len(), 1);
-        assert_eq!(flow.params[0].name, "name");
+        assert_eq!(&*flow.params[0].name, "name");
         assert!(flow.return_type.is_some());
     }
 
@@ -957,7 +1501,7 @@ mod tests {
 "#).expect("parse failed");
         let body = &program.flows[0].body;
         assert_eq!(body.len(), 2); // assign + if
-        assert!(matches!(body[1], Stmt::If { .. }));
+        assert!(matches!(body[1].kind, StmtKind::If { .. }));
     }
 
     #[test]
@@ -968,7 +1512,152 @@ mod tests {
         break
 "#).expect("parse failed");
         let body = &program.flows[0].body;
-        assert!(matches!(body[0], Stmt::Loop { max: Some(10), .. }));
+        assert!(matches!(body[0].kind, StmtKind::Loop { max: Some(10), .. }));
+    }
+
+    #[test]
+    fn test_while() {
+        let program = parse(r#"flow test:
+    while x < 3:
+        x = x + 1
+"#).expect("parse failed");
+        let body = &program.flows[0].body;
+        assert!(matches!(&body[0].kind, StmtKind::While { body, .. } if body.len() == 1));
+    }
+
+    #[test]
+    fn test_match() {
+        let program = parse(r#"flow test:
+    match = 1
+    match reply:
+        case {"intent": "refund", "order": order}:
+            pass
+        case "a" | -1 | none:
+            pass
+        case _:
+            pass
+"#).expect("parse failed");
+        let body = &program.flows[0].body;
+        assert!(matches!(body[0].kind, StmtKind::Assign { .. }));
+        let StmtKind::Match { arms, .. } = &body[1].kind else { panic!("expected match, got {:?}", body[1]) };
+        assert!(matches!(&arms[0].patterns[..], [Pattern::Map(entries)] if matches!(entries[1].1, Pattern::Bind(_))));
+        assert_eq!(arms[1].patterns[1], Pattern::Literal(Expr::IntLit(-1)));
+        assert_eq!(arms[2].patterns, vec![Pattern::Wildcard]);
+
+        for (source, error) in [
+            ("    match x:\n        case _:\n            pass\n        case 1:\n            pass\n", "can never run"),
+            ("    match x:\n        case {\"a\": a} | 1:\n            pass\n", "can't bind names"),
+            ("    match x:\n        case {\"a\": v, \"b\": v}:\n            pass\n", "bound twice"),
+            ("    match x:\n        1:\n            pass\n", "expected 'case"),
+        ] {
+            let err = parse(&format!("flow test:\n{}", source)).unwrap_err();
+            assert!(err.to_string().contains(error), "{}: {}", source, err);
+        }
+    }
+
+    #[test]
+    fn test_catch_clauses() {
+        let program = parse(r#"flow test:
+    try:
+        pass
+    catch RateLimitError:
+        pass
+    catch NetworkError as e:
+        pass
+    catch err:
+        pass
+"#).expect("parse failed");
+        let StmtKind::TryCatch { catches, .. } = &program.flows[0].body[0].kind else { panic!("expected try") };
+        let clauses: Vec<_> = catches.iter().map(|c| (c.kind.as_deref(), c.var.as_deref())).collect();
+        assert_eq!(clauses, [(Some("RateLimitError"), None), (Some("NetworkError"), Some("e")), (None, Some("err"))]);
+
+        for (source, error) in [
+            ("    try:\n        pass\n    catch e:\n        pass\n    catch IOError:\n        pass\n", "can never run"),
+            ("    try:\n        pass\n    catch Timeout as e:\n        pass\n", "unknown error kind 'Timeout'"),
+            ("    try:\n        pass\n    x = 1\n", "expected 'catch'"),
+        ] {
+            let err = parse(&format!("flow test:\n{}", source)).unwrap_err();
+            assert!(err.to_string().contains(error), "{}: {}", source, err);
+        }
+    }
+
+    #[test]
+    fn test_lambda() {
+        let program = parse("flow test:\n    f = |a, b| a + b * 2\n    g = items.map(|x| x.name, || 1)\n").expect("parse failed");
+        let body = &program.flows[0].body;
+        let StmtKind::Assign { expr: Expr::Lambda { params, body: lambda_body }, .. } = &body[0].kind else { panic!("expected lambda, got {:?}", body[0]) };
+        assert_eq!(params.iter().map(|p| &**p).collect::<Vec<_>>(), ["a", "b"]);
+        assert!(matches!(**lambda_body, Expr::BinOp { op: BinOp::Add, .. }));
+        let StmtKind::Assign { expr: Expr::MethodCall { args, .. }, .. } = &body[1].kind else { panic!("expected method call") };
+        assert!(matches!(&args[..], [Expr::Lambda { .. }, Expr::Lambda { params, .. }] if params.is_empty()));
+
+        assert!(parse("flow test:\n    f = |x, x| x\n").unwrap_err().to_string().contains("appears twice"));
+        assert!(parse("flow test:\n    f = |stdin| 1\n").unwrap_err().to_string().contains("built-in"));
+    }
+
+    #[test]
+    fn test_emit_with_kwargs_is_a_call() {
+        let program = parse("flow main():\n    emit(x)\n    emit(x, pretty=true)\n").expect("parse failed");
+        let body = &program.flows[0].body;
+        assert!(matches!(&body[0].kind, StmtKind::Emit { .. }));
+        assert!(matches!(&body[1].kind, StmtKind::Expr(Expr::Call { name, kwargs, .. }) if name == "emit" && kwargs.len() == 1));
+        assert!(parse("flow main():\n    emit()\n").is_err());
+    }
+
+    #[test]
+    fn test_named_branches() {
+        let program = parse("flow main():\n    select:\n        branch fast:\n            x = 1\n        branch:\n            x = 2\n").expect("parse failed");
+        let StmtKind::Select { branches } = &program.flows[0].body[0].kind else { panic!("expected select") };
+        assert_eq!(branches[0].name.as_deref(), Some("fast"));
+        assert_eq!(branches[1].name, None);
+        assert!(parse("flow main():\n    parallel:\n        branch a:\n            x = 1\n        branch a:\n            x = 2\n").is_err());
+
+        let program = parse("flow main():\n    results = parallel:\n        branch:\n            f()\n").expect("parse failed");
+        assert!(matches!(&program.flows[0].body[0].kind, StmtKind::Parallel { collect: Some(name), .. } if &**name == "results"));
+    }
+
+    #[test]
+    fn test_with_block() {
+        let program = parse("flow main():\n    with file(\"out.log\", mode=\"append\") as log:\n        write(log, \"hi\")\n").expect("parse failed");
+        let StmtKind::With { resource, name, body } = &program.flows[0].body[0].kind else { panic!("expected with") };
+        assert!(matches!(resource, Expr::Call { name, kwargs, .. } if name == "file" && kwargs.len() == 1));
+        assert_eq!(&**name, "log");
+        assert_eq!(body.len(), 1);
+        let err = parse("flow main():\n    with file(\"x\") f:\n        pass\n").unwrap_err();
+        assert!(err.to_string().contains("expected 'as'"), "got: {}", err);
+    }
+
+    #[test]
+    fn test_assign_path() {
+        let program = parse("flow main():\n    m[\"a\"].b[0] = 1\n").expect("parse failed");
+        let StmtKind::AssignPath { name, path, .. } = &program.flows[0].body[0].kind else { panic!("expected path assignment") };
+        assert_eq!(&**name, "m");
+        assert!(matches!(path.as_slice(),
+            [PathStep::Index(Expr::StringLit(a)), PathStep::Field(b), PathStep::Index(Expr::IntLit(0))] if a == "a" && b == "b"));
+        let err = parse("flow main():\n    f().x = 1\n").unwrap_err();
+        assert!(err.to_string().contains("must start with a variable"), "got: {}", err);
+    }
+
+    #[test]
+    fn test_parse_type_expr() {
+        let ty = parse_type_expr("Map[String, List[Review]]").expect("parse failed");
+        assert_eq!(ty, TypeExpr::Generic("Map".into(), vec![
+            TypeExpr::Named("String".into()),
+            TypeExpr::Generic("List".into(), vec![TypeExpr::Named("Review".into())]),
+        ]));
+        assert!(parse_type_expr("Review extra").is_err());
+    }
+
+    #[test]
+    fn test_variadic_param() {
+        let program = parse("flow f(a: Int, *rest: String, sep: String = \",\"):\n    pass\n").expect("parse failed");
+        let params = &program.flows[0].params;
+        assert_eq!(params.iter().map(|p| p.variadic).collect::<Vec<_>>(), [false, true, false]);
+        assert_eq!(&*params[1].name, "rest");
+        let err = parse("flow f(*a: Int, *b: Int):\n    pass\n").unwrap_err();
+        assert!(err.to_string().contains("already has *a"), "got: {}", err);
+        let err = parse("flow f(*a: Int = 1):\n    pass\n").unwrap_err();
+        assert!(err.to_string().contains("can't have a default"), "got: {}", err);
     }
 
     #[test]
@@ -977,11 +1666,24 @@ mod tests {
     x = think(input, system="hello", tools=[])
 "#).expect("parse failed");
         let body = &program.flows[0].body;
-        if let Stmt::Assign { expr: Expr::Call { kwargs, .. }, .. } = &body[0] {
+        if let StmtKind::Assign { expr: Expr::Call { kwargs, .. }, .. } = &body[0].kind {
             assert_eq!(kwargs.len(), 2);
             assert_eq!(kwargs[0].0, "system");
         } else {
             panic!("expected assignment with call");
         }
     }
+
+    #[test]
+    fn test_keyword_as_kwarg_name() {
+        let program = parse(r#"flow test:
+    x = extract(doc, type="Invoice")
+"#).expect("parse failed");
+        let body = &program.flows[0].body;
+        if let StmtKind::Assign { expr: Expr::Call { kwargs, .. }, .. } = &body[0].kind {
+            assert_eq!(kwargs[0].0, "type");
+        } else {
+            panic!("expected assignment with call");
+        }
+    }
 }
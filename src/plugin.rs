@@ -0,0 +1,220 @@
+//! Native plugins: shared libraries loaded with `--plugin lib.so` that add
+//! built-in functions, `channel()` providers and `think()` backends without
+//! touching the interpreter.
+//!
+//! The ABI is three C functions that pass JSON text, so a plugin can be
+//! written in anything that builds a shared library:
+//!
+//! ```c
+//! const char *cognos_plugin_manifest(void);        // owned by the plugin
+//! char *cognos_plugin_call(const char *request);   // JSON reply
+//! void cognos_plugin_free(char *reply);            // frees a reply
+//! ```
+//!
+//! The manifest names what the plugin provides:
+//!
+//! ```json
+//! {"abi": 1, "name": "acme",
+//!  "builtins": [{"name": "acme_lookup", "signature": "acme_lookup(id) -> Map", "summary": "..."}],
+//!  "channels": ["teams"],
+//!  "models": [{"prefix": "acme", "tools": false, "vision": false}]}
+//! ```
+//!
+//! Each request is an object whose `call` is `builtin`, `channel_read`,
+//! `channel_write` or `chat`; the reply is `{"ok": value}` or
+//! `{"error": "message"}`. Calls can come from several threads at once
+//! (`parallel:` branches, `async`), so plugins must be thread-safe. Loaded
+//! libraries stay loaded until the process exits.
+
+use crate::help::Builtin;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use std::ffi::{c_char, CStr, CString};
+use std::sync::OnceLock;
+
+/// The plugin ABI version this interpreter speaks.
+const ABI_VERSION: u64 = 1;
+
+static PLUGINS: OnceLock<Vec<Plugin>> = OnceLock::new();
+
+type CallFn = unsafe extern "C" fn(*const c_char) -> *mut c_char;
+type FreeFn = unsafe extern "C" fn(*mut c_char);
+
+pub struct Plugin {
+    pub name: String,
+    pub builtins: Vec<Builtin>,
+    pub channels: Vec<String>,
+    pub models: Vec<ModelPrefix>,
+    call_fn: CallFn,
+    free_fn: FreeFn,
+}
+
+/// Models a plugin serves: `prefix` itself and anything named `prefix:...`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModelPrefix {
+    pub prefix: String,
+    #[serde(default)]
+    pub tools: bool,
+    #[serde(default)]
+    pub vision: bool,
+}
+
+#[derive(Deserialize)]
+struct Manifest {
+    abi: u64,
+    name: String,
+    #[serde(default)]
+    builtins: Vec<ManifestBuiltin>,
+    #[serde(default)]
+    channels: Vec<String>,
+    #[serde(default)]
+    models: Vec<ModelPrefix>,
+}
+
+#[derive(Deserialize)]
+struct ManifestBuiltin {
+    name: String,
+    signature: Option<String>,
+    #[serde(default)]
+    summary: String,
+}
+
+/// Load the plugins at `paths`, once, before anything runs. A plugin can't
+/// take a name a built-in, channel or model prefix already has.
+pub fn load(paths: &[String]) -> Result<()> {
+    let mut plugins: Vec<Plugin> = Vec::new();
+    for path in paths {
+        let plugin = open(path).map_err(|e| anyhow!("can't load plugin {}: {}", path, e))?;
+        for b in &plugin.builtins {
+            if crate::help::lookup(b.name).is_some() || crate::ast::BUILTIN_FUNCTIONS.contains(&b.name) {
+                bail!("plugin '{}' defines {}(), which is already a built-in", plugin.name, b.name);
+            }
+            if let Some(other) = plugins.iter().find(|p| p.builtins.iter().any(|o| o.name == b.name)) {
+                bail!("plugins '{}' and '{}' both define {}()", other.name, plugin.name, b.name);
+            }
+        }
+        for channel in &plugin.channels {
+            if ["slack", "nats", "mqtt"].contains(&channel.as_str()) || plugins.iter().any(|p| p.channels.contains(channel)) {
+                bail!("plugin '{}': channel provider '{}' is already defined", plugin.name, channel);
+            }
+        }
+        for model in &plugin.models {
+            if plugins.iter().any(|p| p.models.iter().any(|m| m.prefix == model.prefix)) {
+                bail!("plugin '{}': model prefix '{}' is already taken", plugin.name, model.prefix);
+            }
+        }
+        log::info!("plugin: loaded '{}' from {}", plugin.name, path);
+        plugins.push(plugin);
+    }
+    if PLUGINS.set(plugins).is_err() {
+        bail!("plugins are already loaded");
+    }
+    Ok(())
+}
+
+/// The plugins loaded, in `--plugin` order.
+pub fn all() -> &'static [Plugin] {
+    PLUGINS.get().map_or(&[], |p| p.as_slice())
+}
+
+/// The plugin providing built-in `name`.
+pub fn builtin(name: &str) -> Option<&'static Plugin> {
+    all().iter().find(|p| p.builtins.iter().any(|b| b.name == name))
+}
+
+/// The plugin providing channel provider `name`.
+pub fn channel(name: &str) -> Option<&'static Plugin> {
+    all().iter().find(|p| p.channels.iter().any(|c| c == name))
+}
+
+/// The plugin serving `model`, and what it can do for it.
+pub fn model(model: &str) -> Option<(&'static Plugin, &'static ModelPrefix)> {
+    all().iter().find_map(|p| {
+        p.models.iter()
+            .find(|m| model == m.prefix || model.strip_prefix(m.prefix.as_str()).is_some_and(|rest| rest.starts_with(':')))
+            .map(|m| (p, m))
+    })
+}
+
+impl Plugin {
+    /// Send `request` and return the `ok` value of the reply.
+    pub fn call(&self, request: serde_json::Value) -> Result<serde_json::Value> {
+        // serde_json escapes NUL, so this can't fail
+        let request = CString::new(request.to_string())?;
+        // SAFETY: the ABI hands the plugin a NUL-terminated string and gets
+        // one back, which we copy before returning it to the plugin to free.
+        let text = unsafe {
+            let reply = (self.call_fn)(request.as_ptr());
+            if reply.is_null() {
+                bail!("plugin '{}' returned no reply", self.name);
+            }
+            let text = CStr::from_ptr(reply).to_string_lossy().into_owned();
+            (self.free_fn)(reply);
+            text
+        };
+        let mut reply: serde_json::Value = serde_json::from_str(&text)
+            .with_context(|| format!("plugin '{}' replied with invalid JSON", self.name))?;
+        if let Some(error) = reply.get("error") {
+            bail!("{}", error.as_str().map_or_else(|| error.to_string(), str::to_string));
+        }
+        match reply.get_mut("ok") {
+            Some(value) => Ok(value.take()),
+            None => bail!("plugin '{}' replied with neither \"ok\" nor \"error\"", self.name),
+        }
+    }
+}
+
+/// Read `symbol` out of `handle`, or say the library isn't a plugin.
+#[cfg(unix)]
+fn symbol(handle: *mut std::ffi::c_void, symbol: &str) -> Result<*mut std::ffi::c_void> {
+    let name = CString::new(symbol)?;
+    // SAFETY: `handle` came from a successful dlopen and is never closed
+    let ptr = unsafe { libc::dlsym(handle, name.as_ptr()) };
+    if ptr.is_null() {
+        bail!("it has no {}() — is it a Cognos plugin?", symbol);
+    }
+    Ok(ptr)
+}
+
+#[cfg(unix)]
+fn open(path: &str) -> Result<Plugin> {
+    // A bare file name would be searched for on the library path instead
+    let resolved = std::fs::canonicalize(path).with_context(|| format!("{} not found", path))?;
+    let c_path = CString::new(resolved.to_string_lossy().as_bytes())?;
+    // SAFETY: dlopen runs the library's initializers; loading a plugin
+    // means trusting it as much as the interpreter itself.
+    let handle = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+    if handle.is_null() {
+        // SAFETY: dlerror returns a message about the failed dlopen above
+        let error = unsafe { CStr::from_ptr(libc::dlerror()) }.to_string_lossy().into_owned();
+        bail!("{}", error);
+    }
+    // SAFETY: the symbols have the signatures the ABI gives them
+    let (manifest, call, free) = unsafe {
+        let manifest: unsafe extern "C" fn() -> *const c_char = std::mem::transmute(symbol(handle, "cognos_plugin_manifest")?);
+        let call: CallFn = std::mem::transmute(symbol(handle, "cognos_plugin_call")?);
+        let free: FreeFn = std::mem::transmute(symbol(handle, "cognos_plugin_free")?);
+        let manifest = manifest();
+        if manifest.is_null() {
+            bail!("cognos_plugin_manifest() returned nothing");
+        }
+        (CStr::from_ptr(manifest).to_string_lossy().into_owned(), call, free)
+    };
+    let manifest: Manifest = serde_json::from_str(&manifest).context("its manifest is not valid")?;
+    if manifest.abi != ABI_VERSION {
+        bail!("plugin '{}' is built for plugin ABI {}, but this cognos speaks {}", manifest.name, manifest.abi, ABI_VERSION);
+    }
+    // Plugins live as long as the process, so their help entries can too
+    let leak = |s: String| -> &'static str { Box::leak(s.into_boxed_str()) };
+    let builtins = manifest.builtins.into_iter().map(|b| Builtin {
+        signature: leak(b.signature.unwrap_or_else(|| format!("{}(...)", b.name))),
+        summary: leak(b.summary),
+        name: leak(b.name),
+    }).collect();
+    Ok(Plugin { name: manifest.name, builtins, channels: manifest.channels, models: manifest.models, call_fn: call, free_fn: free })
+}
+
+#[cfg(not(unix))]
+fn open(_path: &str) -> Result<Plugin> {
+    bail!("plugins are only supported on Unix")
+}
@@ -1,61 +1,597 @@
-/// Pretty-printer: renders AST back to readable Cognos-like syntax.
+//! Pretty-printer: renders AST back to readable Cognos-like syntax.
+//! Output re-parses to the same AST: strings are escaped and binary
+//! operands are parenthesized where precedence requires it.
+//!
+//! `format_source` is `cognos fmt`: the same printer, carrying comments,
+//! blank lines and triple-quoted strings over from the source.
 
 use crate::ast::*;
+use crate::lexer::Lexer;
+use crate::parser::{Item, Parser};
+use crate::token::{Comment, Token};
+use anyhow::{bail, Result};
+use std::collections::VecDeque;
+
+/// `cognos fmt` puts a call, list or map one item per line when it would
+/// run its line past this many columns.
+const WIDTH: usize = 100;
 
 pub fn pretty_program(prog: &Program) -> String {
-    let mut out = String::new();
+    let mut p = Printer::default();
+    for import in &prog.imports {
+        p.out.push_str(&format!("{}\n", import_line(import)));
+    }
+    if !prog.imports.is_empty() { p.out.push('\n'); }
     for td in &prog.types {
+        p.type_def(td);
+        p.out.push('\n');
+    }
+    for stmt in &prog.setup {
+        p.stmt(stmt, 0);
+    }
+    if !prog.setup.is_empty() { p.out.push('\n'); }
+    for (i, flow) in prog.flows.iter().enumerate() {
+        if i > 0 || !prog.types.is_empty() { p.out.push('\n'); }
+        p.flow(flow, 0);
+    }
+    p.out
+}
+
+/// `source` laid out the canonical way: four-space indentation, the
+/// printer's spacing, at most one blank line in a row, one between
+/// definitions, and calls too long for their line split one argument per
+/// line. Comments and triple-quoted strings are kept.
+pub fn format_source(source: &str) -> Result<String> {
+    let mut lexer = Lexer::new(source);
+    let tokens = lexer.tokenize();
+    let mut strings = Vec::new();
+    let mut fstrings = Vec::new();
+    for t in tokens.iter().filter(|t| lexer.triple_quoted().contains(&(t.line, t.col))) {
+        match &t.token {
+            Token::StringLit(s) => strings.push(s.clone()),
+            Token::FStringLit(raw) => fstrings.extend(crate::parser::parse_fstring_parts(raw).ok()),
+            _ => {}
+        }
+    }
+    let mut parser = Parser::new(tokens);
+    let prog = parser.parse_program()?;
+    let layout = parser.layout();
+    let mut p = Printer {
+        out: String::new(),
+        fmt: Some(Fmt {
+            lines: source.lines().collect(),
+            spans: layout.spans.clone().into_iter().peekable(),
+            comments: lexer.comments().iter().cloned().collect(),
+            strings,
+            fstrings,
+            last_line: 0,
+            block_start: true,
+            want_blank: false,
+        }),
+    };
+    p.formatted_program(&prog, &layout.items);
+
+    // The layout must not change what the file means or drop a comment
+    let mut relexer = Lexer::new(&p.out);
+    let reparsed = Parser::new(relexer.tokenize()).parse_program().ok();
+    let texts = |comments: &[Comment]| {
+        let mut texts: Vec<String> = comments.iter().map(|c| c.text.clone()).collect();
+        texts.sort();
+        texts
+    };
+    if reparsed.as_ref() != Some(&prog) || texts(relexer.comments()) != texts(lexer.comments()) {
+        bail!("can't format this file without changing its meaning");
+    }
+    Ok(p.out)
+}
+
+fn import_line(import: &Import) -> String {
+    match &import.names {
+        Some(names) => format!("from {} import {}", quote(&import.path), names.join(", ")),
+        None => format!("import {}", quote(&import.path)),
+    }
+}
+
+fn indent(out: &mut String, level: usize) {
+    for _ in 0..level { out.push_str("    "); }
+}
+
+#[derive(Default)]
+struct Printer<'a> {
+    out: String,
+    /// Set when formatting a source file rather than printing a tree.
+    fmt: Option<Fmt<'a>>,
+}
+
+/// What `cognos fmt` carries over from the source.
+struct Fmt<'a> {
+    lines: Vec<&'a str>,
+    /// The parser's spans, consumed as each construct is printed
+    spans: std::iter::Peekable<std::vec::IntoIter<(usize, usize)>>,
+    comments: VecDeque<Comment>,
+    /// Values of the strings and f-strings written triple-quoted
+    strings: Vec<String>,
+    fstrings: Vec<Vec<FStringPart>>,
+    /// Last source line printed, to tell where the blank lines were
+    last_line: usize,
+    /// Nothing is printed yet in the current block
+    block_start: bool,
+    /// A blank line goes before what comes next, as between definitions
+    want_blank: bool,
+}
+
+impl Fmt<'_> {
+    /// A blank line before what starts on source line `line`, if the source
+    /// had one there — but never at the top of a block.
+    fn space(&mut self, out: &mut String, line: usize) {
+        let blank = self.want_blank
+            || (self.last_line + 1..line).any(|n| self.lines.get(n - 1).is_some_and(|l| l.trim().is_empty()));
+        if blank && !self.block_start { out.push('\n'); }
+        self.want_blank = false;
+        self.block_start = false;
+    }
+
+    fn comment(&mut self, out: &mut String, level: usize, comment: &Comment) {
+        self.space(out, comment.line);
+        indent(out, level);
+        out.push('#');
+        out.push_str(&comment.text);
+        out.push('\n');
+        self.last_line = comment.line;
+    }
+
+    /// The comments and blank line before the next span's construct, printed
+    /// at `level`. A lone comment at the end of its last line is returned to
+    /// stay there; any others inside it go above.
+    fn begin(&mut self, out: &mut String, level: usize) -> Option<String> {
+        let (start, end) = self.spans.next()?;
+        while let Some(c) = self.comments.pop_front_if(|c| c.line < start) {
+            self.comment(out, level, &c);
+        }
+        let mut inside = Vec::new();
+        while let Some(c) = self.comments.pop_front_if(|c| c.line <= end) {
+            inside.push(c);
+        }
+        let trailing = match inside.as_slice() {
+            [c] if !c.own_line && c.line == end => Some(c.text.clone()),
+            _ => {
+                for c in &inside { self.comment(out, level, c); }
+                None
+            }
+        };
+        self.space(out, start);
+        self.last_line = self.last_line.max(end);
+        trailing
+    }
+
+    /// Entering a block: the source indentation of its statements, or
+    /// `usize::MAX` for a body on its header's line (`if x: return y`).
+    fn enter(&mut self) -> usize {
+        self.block_start = true;
+        match self.spans.peek() {
+            Some(&(start, _)) if start > self.last_line => {
+                let line = self.lines.get(start - 1).copied().unwrap_or("");
+                line.len() - line.trim_start_matches([' ', '\t']).len()
+            }
+            _ => usize::MAX,
+        }
+    }
+
+    /// Leaving a block entered at `indentation`: comments after its last
+    /// statement indented as far as it stay in it, at `level`.
+    fn leave(&mut self, out: &mut String, level: usize, indentation: usize) {
+        let next = self.spans.peek().map_or(usize::MAX, |s| s.0);
+        while let Some(c) = self.comments.pop_front_if(|c| c.line < next && c.col > indentation) {
+            self.comment(out, level, &c);
+        }
+        self.block_start = false;
+    }
+
+    /// `expr` on one line, except for strings written triple-quoted.
+    fn flat(&self, expr: &Expr, level: usize) -> String {
+        let flat = |e: &Expr| self.flat(e, level);
+        match expr {
+            Expr::StringLit(s) if self.strings.contains(s) => {
+                triple_quoted("", &escape_block(s, None), level).unwrap_or_else(|| quote(s))
+            }
+            Expr::FString(parts) if self.fstrings.contains(parts) => {
+                triple_quoted("f", &fstring_block(parts), level).unwrap_or_else(|| pretty_expr(expr))
+            }
+            Expr::Call { name, args, kwargs } => format!("{}({})", name, call_args(args, kwargs, flat)),
+            Expr::MethodCall { object, method, args, kwargs } => {
+                format!("{}.{}({})", pretty_postfix_object(object), method, call_args(args, kwargs, flat))
+            }
+            Expr::List(items) => format!("[{}]", items.iter().map(flat).collect::<Vec<_>>().join(", ")),
+            Expr::Map(entries) => {
+                let parts: Vec<String> = entries.iter().map(|(k, v)| format!("{}: {}", quote(k), flat(v))).collect();
+                format!("{{{}}}", parts.join(", "))
+            }
+            _ => pretty_expr(expr),
+        }
+    }
+}
+
+fn call_args(args: &[Expr], kwargs: &[(String, Expr)], expr: impl Fn(&Expr) -> String) -> String {
+    let mut parts: Vec<String> = args.iter().map(&expr).collect();
+    for (k, v) in kwargs {
+        parts.push(format!("{}={}", k, expr(v)));
+    }
+    parts.join(", ")
+}
+
+/// String text as written between `"""`s: backslashes and tabs escaped,
+/// and a quote only where it could close the string. `next` is the
+/// character following `s` in the literal, if any.
+fn escape_block(s: &str, next: Option<char>) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push_str("\\t"),
+            '"' if matches!(chars.peek().copied().or(next), Some('"') | None) => out.push_str("\\\""),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn fstring_block(parts: &[FStringPart]) -> String {
+    let mut s = String::new();
+    for (i, part) in parts.iter().enumerate() {
+        match part {
+            FStringPart::Literal(lit) => {
+                let next = if i + 1 < parts.len() { Some('{') } else { None };
+                s.push_str(&escape_block(lit, next).replace('{', "{{").replace('}', "}}"));
+            }
+            FStringPart::Expr(e) => s.push_str(&format!("{{{}}}", escape(&pretty_expr(e)))),
+            FStringPart::Formatted(e, spec) => {
+                s.push_str(&format!("{{{}:{}}}", escape(&pretty_expr(e)), escape(&pretty_format_spec(spec))));
+            }
+        }
+    }
+    s
+}
+
+/// `text` between `"""`s for a line at `level`. Text of several lines that
+/// reads back through the lexer's dedent goes on the lines after the opening
+/// quotes, one level in; otherwise it starts right after them, as written.
+/// `None` when neither reads back.
+fn triple_quoted(prefix: &str, text: &str, level: usize) -> Option<String> {
+    let lines: Vec<&str> = text.split('\n').collect();
+    let dedents = lines.len() > 1
+        && lines.iter().all(|l| l.is_empty() || !l.trim().is_empty())
+        && lines.iter().any(|l| !l.is_empty() && !l.starts_with([' ', '\t']));
+    if dedents {
+        let pad = "    ".repeat(level + 1);
+        let mut s = format!("{}\"\"\"\n", prefix);
+        for line in lines {
+            if !line.is_empty() {
+                s.push_str(&pad);
+                s.push_str(line);
+            }
+            s.push('\n');
+        }
+        indent(&mut s, level);
+        s.push_str("\"\"\"");
+        Some(s)
+    } else if !text.starts_with('\n') {
+        Some(format!("{}\"\"\"{}\"\"\"", prefix, text))
+    } else {
+        None
+    }
+}
+
+impl Printer<'_> {
+    fn begin(&mut self, level: usize) -> Option<String> {
+        self.fmt.as_mut().and_then(|f| f.begin(&mut self.out, level))
+    }
+
+    /// `text` as a line at `level`, followed by its comment.
+    fn line(&mut self, level: usize, text: &str, comment: Option<String>) {
+        indent(&mut self.out, level);
+        self.out.push_str(text);
+        if let Some(comment) = comment {
+            self.out.push_str("  #");
+            self.out.push_str(&comment);
+        }
+        self.out.push('\n');
+    }
+
+    fn enter(&mut self) -> Option<usize> {
+        self.fmt.as_mut().map(Fmt::enter)
+    }
+
+    fn leave(&mut self, level: usize, indentation: Option<usize>) {
+        if let (Some(fmt), Some(indentation)) = (self.fmt.as_mut(), indentation) {
+            fmt.leave(&mut self.out, level, indentation);
+        }
+    }
+
+    fn body(&mut self, stmts: &[Stmt], level: usize) {
+        let indentation = self.enter();
+        for s in stmts { self.stmt(s, level); }
+        self.leave(level, indentation);
+    }
+
+    /// `expr` on one line, as `pretty_expr` has it unless formatting.
+    fn flat(&self, expr: &Expr, level: usize) -> String {
+        match &self.fmt {
+            Some(fmt) => fmt.flat(expr, level),
+            None => pretty_expr(expr),
+        }
+    }
+
+    /// `expr` for a line at `level` already `used` columns wide. When
+    /// formatting, a call, list or map that doesn't fit goes one item per
+    /// line.
+    fn expr(&self, expr: &Expr, level: usize, used: usize) -> String {
+        let flat = self.flat(expr, level);
+        if self.fmt.is_none() || used + flat.lines().next().map_or(0, |l| l.chars().count()) <= WIDTH {
+            return flat;
+        }
+        let (open, close, items): (String, &str, Vec<(String, &Expr)>) = match expr {
+            Expr::Call { name, args, kwargs } => (
+                format!("{}(", name),
+                ")",
+                args.iter().map(|a| (String::new(), a)).chain(kwargs.iter().map(|(k, v)| (format!("{}=", k), v))).collect(),
+            ),
+            Expr::MethodCall { object, method, args, kwargs } => (
+                format!("{}.{}(", pretty_postfix_object(object), method),
+                ")",
+                args.iter().map(|a| (String::new(), a)).chain(kwargs.iter().map(|(k, v)| (format!("{}=", k), v))).collect(),
+            ),
+            Expr::List(items) => ("[".into(), "]", items.iter().map(|e| (String::new(), e)).collect()),
+            Expr::Map(entries) => ("{".into(), "}", entries.iter().map(|(k, v)| (format!("{}: ", quote(k)), v)).collect()),
+            _ => return flat,
+        };
+        if items.is_empty() { return flat; }
+        let mut s = open;
+        s.push('\n');
+        for (prefix, item) in items {
+            indent(&mut s, level + 1);
+            s.push_str(&prefix);
+            s.push_str(&self.expr(item, level + 1, (level + 1) * 4 + prefix.chars().count()));
+            s.push_str(",\n");
+        }
+        indent(&mut s, level);
+        s.push_str(close);
+        s
+    }
+
+    /// Imports, then the types, flows and top-level statements in source
+    /// order, with a blank line around each definition.
+    fn formatted_program(&mut self, prog: &Program, items: &[Item]) {
+        for import in &prog.imports {
+            let comment = self.begin(0);
+            self.line(0, &import_line(import), comment);
+        }
+        let mut after_def = !prog.imports.is_empty();
+        for item in items {
+            let def = !matches!(item, Item::Setup(_));
+            if let Some(fmt) = self.fmt.as_mut() {
+                fmt.want_blank = def || after_def;
+            }
+            match *item {
+                Item::Type(i) => self.type_def(&prog.types[i]),
+                Item::Flow(i) => self.flow(&prog.flows[i], 0),
+                Item::Setup(i) => self.stmt(&prog.setup[i], 0),
+            }
+            after_def = def;
+        }
+        if let Some(fmt) = self.fmt.as_mut() {
+            while let Some(c) = fmt.comments.pop_front() {
+                fmt.comment(&mut self.out, 0, &c);
+            }
+        }
+    }
+
+    fn type_def(&mut self, td: &TypeDef) {
+        let comment = self.begin(0);
         match td {
             TypeDef::Struct { name, fields } => {
-                out.push_str(&format!("type {}:\n", name));
+                self.line(0, &format!("type {}:", name), comment);
+                let indentation = self.enter();
                 for f in fields {
+                    let comment = self.begin(1);
                     let opt = if f.optional { "?" } else { "" };
-                    out.push_str(&format!("    {}{}: {}\n", f.name, opt, pretty_type(&f.ty)));
+                    self.line(1, &format!("{}{}: {}", f.name, opt, pretty_type(&f.ty)), comment);
                 }
+                self.leave(1, indentation);
             }
             TypeDef::Enum { name, variants } => {
-                let quoted: Vec<String> = variants.iter().map(|v| format!("\"{}\"", v)).collect();
-                out.push_str(&format!("type {}: {}\n", name, quoted.join(" | ")));
+                let quoted: Vec<String> = variants.iter().map(|v| quote(v)).collect();
+                self.line(0, &format!("type {}: {}", name, quoted.join(" | ")), comment);
             }
         }
-        out.push('\n');
     }
-    for (i, flow) in prog.flows.iter().enumerate() {
-        if i > 0 || !prog.types.is_empty() { out.push('\n'); }
-        pretty_flow(&mut out, flow, 0);
+
+    fn flow(&mut self, flow: &FlowDef, level: usize) {
+        let comment = self.begin(level);
+        self.line(level, &format!("{}:", flow_signature(flow)), comment);
+        let indentation = self.enter();
+        if let Some(ref doc) = flow.description {
+            let comment = self.begin(level + 1);
+            let doc = self.flat(&Expr::StringLit(doc.clone()), level + 1);
+            self.line(level + 1, &doc, comment);
+        }
+        for stmt in &flow.body {
+            self.stmt(stmt, level + 1);
+        }
+        self.leave(level + 1, indentation);
     }
-    out
-}
 
-fn indent(out: &mut String, level: usize) {
-    for _ in 0..level { out.push_str("    "); }
+    fn stmt(&mut self, stmt: &Stmt, level: usize) {
+        let comment = self.begin(level);
+        let used = level * 4;
+        match &stmt.kind {
+            StmtKind::Assign { name, expr } => {
+                let prefix = format!("{} = ", name);
+                let value = self.expr(expr, level, used + prefix.chars().count());
+                self.line(level, &format!("{}{}", prefix, value), comment);
+            }
+            StmtKind::AssignPath { name, path, expr } => {
+                let mut target = name.to_string();
+                for step in path {
+                    match step {
+                        PathStep::Field(field) => target.push_str(&format!(".{}", field)),
+                        PathStep::Index(index) => target.push_str(&format!("[{}]", pretty_expr(index))),
+                    }
+                }
+                let prefix = format!("{} = ", target);
+                let value = self.expr(expr, level, used + prefix.chars().count());
+                self.line(level, &format!("{}{}", prefix, value), comment);
+            }
+            StmtKind::Emit { value } => {
+                let value = self.expr(value, level, used + "emit(".len());
+                self.line(level, &format!("emit({})", value), comment);
+            }
+            StmtKind::Return { value } => {
+                let value = self.expr(value, level, used + "return ".len());
+                self.line(level, &format!("return {}", value), comment);
+            }
+            StmtKind::Break => self.line(level, "break", comment),
+            StmtKind::Continue => self.line(level, "continue", comment),
+            StmtKind::Pass => self.line(level, "pass", comment),
+            StmtKind::If { condition, body, elifs, else_body } => {
+                let header = format!("if {}:", self.flat(condition, level));
+                self.line(level, &header, comment);
+                self.body(body, level + 1);
+                for (cond, stmts) in elifs {
+                    let comment = self.begin(level);
+                    let header = format!("elif {}:", self.flat(cond, level));
+                    self.line(level, &header, comment);
+                    self.body(stmts, level + 1);
+                }
+                if !else_body.is_empty() {
+                    let comment = self.begin(level);
+                    self.line(level, "else:", comment);
+                    self.body(else_body, level + 1);
+                }
+            }
+            StmtKind::TryCatch { body, catches } => {
+                self.line(level, "try:", comment);
+                self.body(body, level + 1);
+                for catch in catches {
+                    let comment = self.begin(level);
+                    let header = match (&catch.kind, &catch.var) {
+                        (Some(kind), Some(var)) => format!("catch {} as {}:", kind, var),
+                        (Some(kind), None) => format!("catch {}:", kind),
+                        (None, Some(var)) => format!("catch {}:", var),
+                        (None, None) => "catch:".to_string(),
+                    };
+                    self.line(level, &header, comment);
+                    self.body(&catch.body, level + 1);
+                }
+            }
+            StmtKind::For { var, value_var, iterable, body } => {
+                let iterable = self.flat(iterable, level);
+                let header = match value_var {
+                    Some(vv) => format!("for {}, {} in {}:", var, vv, iterable),
+                    None => format!("for {} in {}:", var, iterable),
+                };
+                self.line(level, &header, comment);
+                self.body(body, level + 1);
+            }
+            StmtKind::Loop { max, body } => {
+                match max {
+                    Some(n) => self.line(level, &format!("loop max={}:", n), comment),
+                    None => self.line(level, "loop:", comment),
+                }
+                self.body(body, level + 1);
+            }
+            StmtKind::While { condition, body } => {
+                let condition = self.flat(condition, level);
+                self.line(level, &format!("while {}:", condition), comment);
+                self.body(body, level + 1);
+            }
+            StmtKind::Match { subject, arms } => {
+                let header = format!("match {}:", self.flat(subject, level));
+                self.line(level, &header, comment);
+                let indentation = self.enter();
+                for arm in arms {
+                    let comment = self.begin(level + 1);
+                    let patterns: Vec<String> = arm.patterns.iter().map(pretty_pattern).collect();
+                    self.line(level + 1, &format!("case {}:", patterns.join(" | ")), comment);
+                    self.body(&arm.body, level + 2);
+                }
+                self.leave(level + 1, indentation);
+            }
+            StmtKind::Parallel { branches, collect } => {
+                match collect {
+                    Some(name) => self.line(level, &format!("{} = parallel:", name), comment),
+                    None => self.line(level, "parallel:", comment),
+                }
+                self.branches(branches, level + 1);
+            }
+            StmtKind::Select { branches } => {
+                self.line(level, "select:", comment);
+                self.branches(branches, level + 1);
+            }
+            StmtKind::With { resource, name, body } => {
+                let header = format!("with {} as {}:", self.flat(resource, level), name);
+                self.line(level, &header, comment);
+                self.body(body, level + 1);
+            }
+            StmtKind::Expr(expr) => {
+                let expr = self.expr(expr, level, used);
+                self.line(level, &expr, comment);
+            }
+        }
+    }
+
+    fn branches(&mut self, branches: &[Branch], level: usize) {
+        let indentation = self.enter();
+        for branch in branches {
+            let comment = self.begin(level);
+            match &branch.name {
+                Some(name) => self.line(level, &format!("branch {}:", name), comment),
+                None => self.line(level, "branch:", comment),
+            }
+            self.body(&branch.body, level + 1);
+        }
+        self.leave(level, indentation);
+    }
 }
 
-fn pretty_flow(out: &mut String, flow: &FlowDef, level: usize) {
-    indent(out, level);
-    out.push_str(&format!("flow {}", flow.name));
+/// `flow name(params) -> Type`, without the colon or body.
+pub fn flow_signature(flow: &FlowDef) -> String {
+    let mut out = format!("flow {}", flow.name);
     if !flow.params.is_empty() {
         out.push('(');
         for (i, p) in flow.params.iter().enumerate() {
             if i > 0 { out.push_str(", "); }
+            if p.variadic { out.push('*'); }
             out.push_str(&format!("{}: {}", p.name, pretty_type(&p.ty)));
+            if let Some(ref default) = p.default {
+                out.push_str(&format!(" = {}", pretty_expr(default)));
+            }
         }
         out.push(')');
     }
     if let Some(ref rt) = flow.return_type {
         out.push_str(&format!(" -> {}", pretty_type(rt)));
     }
-    out.push_str(":\n");
-    for stmt in &flow.body {
-        pretty_stmt(out, stmt, level + 1);
+    out
+}
+
+pub fn pretty_pattern(pattern: &Pattern) -> String {
+    match pattern {
+        Pattern::Wildcard => "_".to_string(),
+        Pattern::Bind(name) => name.to_string(),
+        Pattern::Literal(literal) => pretty_expr(literal),
+        Pattern::Map(entries) => {
+            let entries: Vec<String> = entries.iter().map(|(k, p)| format!("{}: {}", quote(k), pretty_pattern(p))).collect();
+            format!("{{{}}}", entries.join(", "))
+        }
     }
 }
 
-fn pretty_type(ty: &TypeExpr) -> String {
+pub fn pretty_type(ty: &TypeExpr) -> String {
     match ty {
         TypeExpr::Named(n) => n.clone(),
         TypeExpr::Generic(n, args) => {
-            let a: Vec<String> = args.iter().map(|t| pretty_type(t)).collect();
+            let a: Vec<String> = args.iter().map(pretty_type).collect();
             format!("{}[{}]", n, a.join(", "))
         }
         TypeExpr::Struct(fields) => {
@@ -65,157 +601,164 @@ fn pretty_type(ty: &TypeExpr) -> String {
     }
 }
 
-fn pretty_stmt(out: &mut String, stmt: &Stmt, level: usize) {
-    match stmt {
-        Stmt::Assign { name, expr } => {
-            indent(out, level);
-            out.push_str(&format!("{} = {}\n", name, pretty_expr(expr)));
-        }
-        Stmt::Emit { value } => {
-            indent(out, level);
-            out.push_str(&format!("emit({})\n", pretty_expr(value)));
-        }
-        Stmt::Return { value } => {
-            indent(out, level);
-            out.push_str(&format!("return {}\n", pretty_expr(value)));
-        }
-        Stmt::Break => {
-            indent(out, level);
-            out.push_str("break\n");
-        }
-        Stmt::Continue => {
-            indent(out, level);
-            out.push_str("continue\n");
-        }
-        Stmt::Pass => {
-            indent(out, level);
-            out.push_str("pass\n");
-        }
-        Stmt::If { condition, body, elifs, else_body } => {
-            indent(out, level);
-            out.push_str(&format!("if {}:\n", pretty_expr(condition)));
-            for s in body { pretty_stmt(out, s, level + 1); }
-            for (cond, stmts) in elifs {
-                indent(out, level);
-                out.push_str(&format!("elif {}:\n", pretty_expr(cond)));
-                for s in stmts { pretty_stmt(out, s, level + 1); }
-            }
-            if !else_body.is_empty() {
-                indent(out, level);
-                out.push_str("else:\n");
-                for s in else_body { pretty_stmt(out, s, level + 1); }
-            }
-        }
-        Stmt::TryCatch { body, error_var, catch_body } => {
-            indent(out, level);
-            out.push_str("try:\n");
-            for s in body { pretty_stmt(out, s, level + 1); }
-            indent(out, level);
-            if let Some(var) = error_var {
-                out.push_str(&format!("catch {}:\n", var));
-            } else {
-                out.push_str("catch:\n");
-            }
-            for s in catch_body { pretty_stmt(out, s, level + 1); }
-        }
-        Stmt::For { var, value_var, iterable, body } => {
-            indent(out, level);
-            if let Some(vv) = value_var {
-                out.push_str(&format!("for {}, {} in {}:\n", var, vv, pretty_expr(iterable)));
-            } else {
-                out.push_str(&format!("for {} in {}:\n", var, pretty_expr(iterable)));
-            }
-            for s in body { pretty_stmt(out, s, level + 1); }
-        }
-        Stmt::Loop { max, body } => {
-            indent(out, level);
-            if let Some(n) = max {
-                out.push_str(&format!("loop max={}:\n", n));
-            } else {
-                out.push_str("loop:\n");
-            }
-            for s in body { pretty_stmt(out, s, level + 1); }
-        }
-        Stmt::Parallel { branches } => {
-            indent(out, level);
-            out.push_str("parallel:\n");
-            for branch in branches {
-                indent(out, level + 1);
-                out.push_str("branch:\n");
-                for s in branch { pretty_stmt(out, s, level + 2); }
-            }
-        }
-        Stmt::Select { branches } => {
-            indent(out, level);
-            out.push_str("select:\n");
-            for branch in branches {
-                indent(out, level + 1);
-                out.push_str("branch:\n");
-                for s in branch { pretty_stmt(out, s, level + 2); }
-            }
-        }
-        Stmt::Expr(expr) => {
-            indent(out, level);
-            out.push_str(&format!("{}\n", pretty_expr(expr)));
+/// Quote a string literal, escaping what the lexer unescapes.
+fn pretty_format_spec(spec: &FormatSpec) -> String {
+    let mut s = String::new();
+    if let Some(align) = spec.align {
+        s.extend(spec.fill);
+        s.push(align);
+    }
+    if spec.sign { s.push('+'); }
+    if spec.zero { s.push('0'); }
+    if let Some(width) = spec.width { s.push_str(&width.to_string()); }
+    if spec.grouping { s.push(','); }
+    if let Some(precision) = spec.precision { s.push_str(&format!(".{}", precision)); }
+    s.extend(spec.kind);
+    s
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", escape(s))
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
         }
     }
+    out
+}
+
+/// Binding strength, mirroring the parser's precedence chain.
+fn precedence(expr: &Expr) -> u8 {
+    match expr {
+        // The body runs to the end, so a lambda operand needs parentheses
+        Expr::Lambda { .. } => 0,
+        Expr::BinOp { op, .. } => match op {
+            BinOp::Or => 1,
+            BinOp::And => 2,
+            BinOp::Eq | BinOp::NotEq | BinOp::Lt | BinOp::Gt | BinOp::LtEq | BinOp::GtEq
+            | BinOp::In | BinOp::NotIn => 3,
+            BinOp::Add | BinOp::Sub => 4,
+            BinOp::Mul | BinOp::Div | BinOp::FloorDiv | BinOp::Mod => 5,
+        },
+        Expr::Compare { .. } => 3,
+        Expr::UnaryOp { .. } | Expr::Async(_) => 6,
+        // A negative literal is lexed as unary minus
+        Expr::IntLit(n) if *n < 0 => 6,
+        Expr::FloatLit(n) if n.is_sign_negative() => 6,
+        _ => 7,
+    }
+}
+
+fn pretty_operand(expr: &Expr, min: u8) -> String {
+    if precedence(expr) < min {
+        format!("({})", pretty_expr(expr))
+    } else {
+        pretty_expr(expr)
+    }
 }
 
-fn pretty_expr(expr: &Expr) -> String {
+/// The receiver of `.field`, `[index]` or `.method()`. Number literals are
+/// wrapped too, since `1.x` would lex as a float.
+fn pretty_postfix_object(expr: &Expr) -> String {
     match expr {
-        Expr::Ident(name) => name.clone(),
-        Expr::StringLit(s) => format!("\"{}\"", s),
+        Expr::IntLit(_) | Expr::FloatLit(_) => format!("({})", pretty_expr(expr)),
+        _ => pretty_operand(expr, 7),
+    }
+}
+
+fn bin_op_str(op: &BinOp) -> &'static str {
+    match op {
+        BinOp::Add => "+", BinOp::Sub => "-", BinOp::Mul => "*", BinOp::Div => "/",
+        BinOp::Eq => "==", BinOp::NotEq => "!=",
+        BinOp::Lt => "<", BinOp::Gt => ">", BinOp::LtEq => "<=", BinOp::GtEq => ">=",
+        BinOp::And => "and", BinOp::Or => "or", BinOp::In => "in", BinOp::NotIn => "not in", BinOp::Mod => "%", BinOp::FloorDiv => "//",
+    }
+}
+
+fn pretty_float(n: f64) -> String {
+    let s = format!("{}", n);
+    if s.contains('.') || !n.is_finite() { s } else { format!("{}.0", s) }
+}
+
+pub fn pretty_expr(expr: &Expr) -> String {
+    match expr {
+        Expr::Ident(name) => name.to_string(),
+        Expr::StringLit(s) => quote(s),
         Expr::IntLit(n) => n.to_string(),
-        Expr::FloatLit(n) => format!("{}", n),
+        Expr::FloatLit(n) => pretty_float(*n),
         Expr::BoolLit(b) => b.to_string(),
         Expr::NoneLiteral => "none".to_string(),
         Expr::Call { name, args, kwargs } => {
-            let mut parts: Vec<String> = args.iter().map(|a| pretty_expr(a)).collect();
+            let mut parts: Vec<String> = args.iter().map(pretty_expr).collect();
             for (k, v) in kwargs {
                 parts.push(format!("{}={}", k, pretty_expr(v)));
             }
             format!("{}({})", name, parts.join(", "))
         }
         Expr::Field { object, field } => {
-            format!("{}.{}", pretty_expr(object), field)
+            format!("{}.{}", pretty_postfix_object(object), field)
         }
         Expr::Index { object, index } => {
-            format!("{}[{}]", pretty_expr(object), pretty_expr(index))
+            format!("{}[{}]", pretty_postfix_object(object), pretty_expr(index))
         }
         Expr::Slice { object, start, end } => {
             let s = start.as_ref().map(|e| pretty_expr(e)).unwrap_or_default();
             let e = end.as_ref().map(|e| pretty_expr(e)).unwrap_or_default();
-            format!("{}[{}:{}]", pretty_expr(object), s, e)
+            format!("{}[{}:{}]", pretty_postfix_object(object), s, e)
         }
-        Expr::MethodCall { object, method, args } => {
-            let a: Vec<String> = args.iter().map(|e| pretty_expr(e)).collect();
-            format!("{}.{}({})", pretty_expr(object), method, a.join(", "))
+        Expr::MethodCall { object, method, args, kwargs } => {
+            let mut parts: Vec<String> = args.iter().map(pretty_expr).collect();
+            for (k, v) in kwargs {
+                parts.push(format!("{}={}", k, pretty_expr(v)));
+            }
+            format!("{}.{}({})", pretty_postfix_object(object), method, parts.join(", "))
         }
         Expr::BinOp { left, op, right } => {
-            let op_str = match op {
-                BinOp::Add => "+", BinOp::Sub => "-", BinOp::Mul => "*", BinOp::Div => "/",
-                BinOp::Eq => "==", BinOp::NotEq => "!=",
-                BinOp::Lt => "<", BinOp::Gt => ">", BinOp::LtEq => "<=", BinOp::GtEq => ">=",
-                BinOp::And => "and", BinOp::Or => "or", BinOp::In => "in", BinOp::NotIn => "not in", BinOp::Mod => "%",
-            };
-            format!("{} {} {}", pretty_expr(left), op_str, pretty_expr(right))
+            // Left-associative: the right operand must bind strictly tighter.
+            // Comparisons chain instead, so both sides of one must.
+            let prec = precedence(expr);
+            let left_min = if prec == 3 { prec + 1 } else { prec };
+            format!("{} {} {}", pretty_operand(left, left_min), bin_op_str(op), pretty_operand(right, prec + 1))
+        }
+        Expr::Compare { left, rest } => {
+            let mut s = pretty_operand(left, 4);
+            for (op, right) in rest {
+                s.push_str(&format!(" {} {}", bin_op_str(op), pretty_operand(right, 4)));
+            }
+            s
         }
         Expr::UnaryOp { op, operand } => {
-            let op_str = match op { UnaryOp::Not => "not " };
-            format!("{}{}", op_str, pretty_expr(operand))
+            let op_str = match op { UnaryOp::Not => "not ", UnaryOp::Neg => "-" };
+            format!("{}{}", op_str, pretty_operand(operand, 6))
         }
         Expr::List(items) => {
-            let parts: Vec<String> = items.iter().map(|i| pretty_expr(i)).collect();
+            let parts: Vec<String> = items.iter().map(pretty_expr).collect();
             format!("[{}]", parts.join(", "))
         }
         Expr::FString(parts) => {
             let mut s = String::from("f\"");
             for part in parts {
                 match part {
-                    crate::ast::FStringPart::Literal(lit) => s.push_str(lit),
+                    crate::ast::FStringPart::Literal(lit) => s.push_str(&escape(lit).replace('{', "{{").replace('}', "}}")),
                     crate::ast::FStringPart::Expr(e) => {
                         s.push('{');
-                        s.push_str(&pretty_expr(e));
+                        // Nested string literals must survive the f-string's own unescaping
+                        s.push_str(&escape(&pretty_expr(e)));
+                        s.push('}');
+                    }
+                    crate::ast::FStringPart::Formatted(e, spec) => {
+                        s.push('{');
+                        s.push_str(&escape(&pretty_expr(e)));
+                        s.push(':');
+                        s.push_str(&escape(&pretty_format_spec(spec)));
                         s.push('}');
                     }
                 }
@@ -224,13 +767,405 @@ fn pretty_expr(expr: &Expr) -> String {
             s
         }
         Expr::Async(inner) => {
-            format!("async {}", pretty_expr(inner))
+            format!("async {}", pretty_operand(inner, 6))
         }
         Expr::Map(entries) => {
             let parts: Vec<String> = entries.iter()
-                .map(|(k, v)| format!("\"{}\": {}", k, pretty_expr(v)))
+                .map(|(k, v)| format!("{}: {}", quote(k), pretty_expr(v)))
                 .collect();
             format!("{{{}}}", parts.join(", "))
         }
+        Expr::Lambda { params, body } => {
+            let params: Vec<&str> = params.iter().map(|p| &**p).collect();
+            format!("|{}| {}", params.join(", "), pretty_expr(body))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use proptest::prelude::*;
+
+    fn parse(source: &str) -> anyhow::Result<Program> {
+        Parser::new(Lexer::new(source).tokenize()).parse_program()
+    }
+
+    const KEYWORDS: &[&str] = &[
+        "flow", "let", "if", "else", "elif", "loop", "while", "break", "continue", "return", "emit",
+        "parallel", "branch", "async", "await", "for", "in", "try", "catch", "type", "and",
+        "or", "not", "true", "false", "none", "pass", "select", "with",
+    ];
+
+    fn ident() -> impl Strategy<Value = String> {
+        "[a-z][a-z0-9_]{0,6}".prop_filter("keyword or built-in", |s| {
+            ![KEYWORDS, RESERVED_NAMES, BUILTIN_FUNCTIONS].iter().any(|names| names.contains(&s.as_str()))
+        })
+    }
+
+    fn name() -> impl Strategy<Value = Name> {
+        ident().prop_map(Name::from)
+    }
+
+    fn type_name() -> impl Strategy<Value = String> {
+        "[A-Z][a-z]{0,6}"
+    }
+
+    /// String contents, including characters that need escaping.
+    fn text() -> impl Strategy<Value = String> {
+        "[a-zA-Z0-9 _#{}\"\\\\\n\té]{0,10}"
+    }
+
+    /// Text that can sit inside an f-string, where braces mean interpolation.
+    fn fstring_text() -> impl Strategy<Value = String> {
+        "[a-zA-Z0-9 _#\"\\\\\n\té]{1,10}"
+    }
+
+    /// Literal f-string text; braces in it are written doubled.
+    fn fstring_literal() -> impl Strategy<Value = String> {
+        "[a-zA-Z0-9 _#\"\\\\\n\té{}]{1,10}"
+    }
+
+    fn comparison_op() -> impl Strategy<Value = BinOp> {
+        prop_oneof![
+            Just(BinOp::Eq), Just(BinOp::NotEq), Just(BinOp::Lt), Just(BinOp::Gt),
+            Just(BinOp::LtEq), Just(BinOp::GtEq), Just(BinOp::In), Just(BinOp::NotIn),
+        ]
+    }
+
+    fn bin_op() -> impl Strategy<Value = BinOp> {
+        prop_oneof![
+            Just(BinOp::Add), Just(BinOp::Sub), Just(BinOp::Mul), Just(BinOp::Div),
+            Just(BinOp::Mod), Just(BinOp::FloorDiv), Just(BinOp::Eq), Just(BinOp::NotEq), Just(BinOp::Lt),
+            Just(BinOp::Gt), Just(BinOp::LtEq), Just(BinOp::GtEq), Just(BinOp::And),
+            Just(BinOp::Or), Just(BinOp::In), Just(BinOp::NotIn),
+        ]
+    }
+
+    fn leaf() -> impl Strategy<Value = Expr> {
+        prop_oneof![
+            name().prop_map(Expr::Ident),
+            text().prop_map(Expr::StringLit),
+            (i64::MIN + 1..=i64::MAX).prop_map(Expr::IntLit),
+            (-1e6f64..1e6).prop_map(Expr::FloatLit),
+            any::<bool>().prop_map(Expr::BoolLit),
+            Just(Expr::NoneLiteral),
+        ]
+    }
+
+    /// Expressions simple enough to interpolate: no braces anywhere inside.
+    fn fstring_expr() -> impl Strategy<Value = Expr> {
+        prop_oneof![
+            name().prop_map(Expr::Ident),
+            any::<i64>().prop_filter("i64::MIN", |n| *n != i64::MIN).prop_map(Expr::IntLit),
+            (name(), ident()).prop_map(|(o, f)| Expr::Field { object: Box::new(Expr::Ident(o)), field: f }),
+            (ident(), fstring_text()).prop_map(|(name, s)| Expr::Call {
+                name, args: vec![Expr::StringLit(s)], kwargs: vec![],
+            }),
+        ]
+    }
+
+    fn format_spec() -> impl Strategy<Value = FormatSpec> {
+        (
+            prop::option::of((prop::option::of(prop::sample::select(vec!['*', '-', '.', ' '])), prop::sample::select(vec!['<', '>', '^']))),
+            any::<bool>(), any::<bool>(), prop::option::of(1usize..30), any::<bool>(),
+            prop::option::of(0usize..10), prop::option::of(prop::sample::select(vec!['f', 'e', '%', 'd', 's'])),
+        ).prop_filter("spec must not be empty", |(align, sign, zero, width, grouping, precision, kind)| {
+            align.is_some() || *sign || *zero || width.is_some() || *grouping || precision.is_some() || kind.is_some()
+        }).prop_map(|(align, sign, zero, width, grouping, precision, kind)| FormatSpec {
+            fill: align.and_then(|(fill, _)| fill),
+            align: align.map(|(_, align)| align),
+            sign, zero, width, grouping, precision, kind,
+        })
+    }
+
+    fn fstring() -> impl Strategy<Value = Expr> {
+        let part = prop_oneof![
+            fstring_literal().prop_map(FStringPart::Literal),
+            fstring_expr().prop_map(FStringPart::Expr),
+            (fstring_expr(), format_spec()).prop_map(|(e, spec)| FStringPart::Formatted(e, spec)),
+        ];
+        prop::collection::vec(part, 0..4).prop_map(|parts| {
+            // The parser merges adjacent literal text into one part
+            let mut merged: Vec<FStringPart> = Vec::new();
+            for part in parts {
+                match (merged.last_mut(), part) {
+                    (Some(FStringPart::Literal(prev)), FStringPart::Literal(lit)) => prev.push_str(&lit),
+                    (_, part) => merged.push(part),
+                }
+            }
+            Expr::FString(merged)
+        })
+    }
+
+    fn expr() -> impl Strategy<Value = Expr> {
+        prop_oneof![leaf(), fstring()].prop_recursive(4, 32, 4, |inner| {
+            let boxed = inner.clone().prop_map(Box::new);
+            prop_oneof![
+                (ident(), prop::collection::vec(inner.clone(), 0..3),
+                 prop::collection::vec((ident(), inner.clone()), 0..2))
+                    .prop_map(|(name, args, kwargs)| Expr::Call { name, args, kwargs }),
+                (boxed.clone(), ident()).prop_map(|(object, field)| Expr::Field { object, field }),
+                (boxed.clone(), boxed.clone()).prop_map(|(object, index)| Expr::Index { object, index }),
+                (boxed.clone(), prop::option::of(boxed.clone()), prop::option::of(boxed.clone()))
+                    .prop_map(|(object, start, end)| Expr::Slice { object, start, end }),
+                (boxed.clone(), ident(), prop::collection::vec(inner.clone(), 0..3),
+                 prop::collection::vec((ident(), inner.clone()), 0..2))
+                    .prop_map(|(object, method, args, kwargs)| Expr::MethodCall { object, method, args, kwargs }),
+                (boxed.clone(), bin_op(), boxed.clone())
+                    .prop_map(|(left, op, right)| Expr::BinOp { left, op, right }),
+                boxed.clone().prop_map(|operand| Expr::UnaryOp { op: UnaryOp::Not, operand }),
+                // The parser folds -<literal> into a negative literal
+                boxed.clone().prop_filter("number literal", |e| !matches!(**e, Expr::IntLit(_) | Expr::FloatLit(_)))
+                    .prop_map(|operand| Expr::UnaryOp { op: UnaryOp::Neg, operand }),
+                (boxed.clone(), prop::collection::vec((comparison_op(), inner.clone()), 2..4))
+                    .prop_map(|(left, rest)| Expr::Compare { left, rest }),
+                boxed.clone().prop_map(Expr::Async),
+                (prop::collection::btree_set(name(), 0..3), boxed.clone())
+                    .prop_map(|(params, body)| Expr::Lambda { params: params.into_iter().collect(), body }),
+                prop::collection::vec(inner.clone(), 0..3).prop_map(Expr::List),
+                prop::collection::vec((text(), inner), 0..3).prop_map(Expr::Map),
+            ]
+        })
+    }
+
+    fn path_step() -> impl Strategy<Value = PathStep> {
+        prop_oneof![
+            name().prop_map(|n| PathStep::Field(n.to_string())),
+            expr().prop_map(PathStep::Index),
+        ]
+    }
+
+    /// Patterns that bind nothing, so any of them can be `|` alternatives
+    fn literal_pattern() -> impl Strategy<Value = Pattern> {
+        let literal = || leaf().prop_filter("literal", |e| !matches!(e, Expr::Ident(_))).prop_map(Pattern::Literal);
+        prop_oneof![
+            literal(),
+            prop::collection::btree_map(text(), literal(), 0..3).prop_map(|entries| Pattern::Map(entries.into_iter().collect())),
+        ]
+    }
+
+    /// `match` arms; only the last may match everything
+    fn match_arms(stmt: impl Strategy<Value = Stmt> + Clone) -> impl Strategy<Value = Vec<MatchArm>> {
+        let last = (prop_oneof![Just(Pattern::Wildcard), name().prop_map(Pattern::Bind)], block(stmt.clone()));
+        (prop::collection::vec((prop::collection::vec(literal_pattern(), 1..3), block(stmt)), 1..3), prop::option::of(last))
+            .prop_map(|(arms, last)| {
+                let mut arms: Vec<MatchArm> = arms.into_iter().map(|(patterns, body)| MatchArm { patterns, body }).collect();
+                arms.extend(last.map(|(pattern, body)| MatchArm { patterns: vec![pattern], body }));
+                arms
+            })
+    }
+
+    /// Catch clauses by kind, then maybe one that catches everything
+    fn catches(stmt: impl Strategy<Value = Stmt> + Clone) -> impl Strategy<Value = Vec<Catch>> {
+        let kind = prop::sample::select(&crate::error::ERROR_KINDS[1..]).prop_map(String::from);
+        let last = (prop::option::of(name()), block(stmt.clone()));
+        (prop::collection::vec((kind, prop::option::of(name()), block(stmt)), 0..3), prop::option::of(last))
+            .prop_filter("a try needs a catch", |(kinded, last)| !kinded.is_empty() || last.is_some())
+            .prop_map(|(kinded, last)| {
+                let mut catches: Vec<Catch> = kinded.into_iter().map(|(kind, var, body)| Catch { kind: Some(kind), var, body }).collect();
+                catches.extend(last.map(|(var, body)| Catch { kind: None, var, body }));
+                catches
+            })
+    }
+
+    fn block(stmt: impl Strategy<Value = Stmt>) -> impl Strategy<Value = Vec<Stmt>> {
+        prop::collection::vec(stmt, 1..3)
+    }
+
+    /// Parallel/select branches, some named; repeated names are dropped since they don't parse
+    fn branches(stmt: impl Strategy<Value = Stmt> + Clone) -> impl Strategy<Value = Vec<Branch>> {
+        prop::collection::vec((prop::option::of(name()), block(stmt)), 1..3).prop_map(|raw| {
+            let mut branches: Vec<Branch> = Vec::new();
+            for (name, body) in raw {
+                let name = name.filter(|n| !branches.iter().any(|b| b.name.as_ref() == Some(n)));
+                branches.push(Branch { name, body });
+            }
+            branches
+        })
+    }
+
+    fn stmt() -> impl Strategy<Value = Stmt> {
+        let simple = prop_oneof![
+            (name(), expr()).prop_map(|(name, expr)| StmtKind::Assign { name, expr }),
+            (name(), prop::collection::vec(path_step(), 1..3), expr())
+                .prop_map(|(name, path, expr)| StmtKind::AssignPath { name, path, expr }),
+            expr().prop_map(|value| StmtKind::Emit { value }),
+            expr().prop_map(|value| StmtKind::Return { value }),
+            Just(StmtKind::Break),
+            Just(StmtKind::Continue),
+            Just(StmtKind::Pass),
+            expr().prop_map(StmtKind::Expr),
+        ].prop_map(Stmt::from);
+        simple.prop_recursive(3, 24, 3, |inner| {
+            prop_oneof![
+                (expr(), block(inner.clone()),
+                 prop::collection::vec((expr(), block(inner.clone())), 0..2),
+                 prop::collection::vec(inner.clone(), 0..2))
+                    .prop_map(|(condition, body, elifs, else_body)| StmtKind::If { condition, body, elifs, else_body }),
+                (prop::option::of(any::<u32>()), block(inner.clone()))
+                    .prop_map(|(max, body)| StmtKind::Loop { max, body }),
+                (expr(), block(inner.clone()))
+                    .prop_map(|(condition, body)| StmtKind::While { condition, body }),
+                (expr(), match_arms(inner.clone()))
+                    .prop_map(|(subject, arms)| StmtKind::Match { subject, arms }),
+                (name(), prop::option::of(name()), expr(), block(inner.clone()))
+                    .prop_map(|(var, value_var, iterable, body)| StmtKind::For { var, value_var, iterable, body }),
+                (block(inner.clone()), catches(inner.clone()))
+                    .prop_map(|(body, catches)| StmtKind::TryCatch { body, catches }),
+                (branches(inner.clone()), prop::option::of(name()))
+                    .prop_map(|(branches, collect)| StmtKind::Parallel { branches, collect }),
+                branches(inner.clone()).prop_map(|branches| StmtKind::Select { branches }),
+                (expr(), name(), block(inner))
+                    .prop_map(|(resource, name, body)| StmtKind::With { resource, name, body }),
+            ].prop_map(Stmt::from)
+        })
+    }
+
+    fn type_expr() -> impl Strategy<Value = TypeExpr> {
+        type_name().prop_map(TypeExpr::Named).prop_recursive(2, 6, 2, |inner| {
+            (type_name(), prop::collection::vec(inner, 1..3))
+                .prop_map(|(name, args)| TypeExpr::Generic(name, args))
+        })
+    }
+
+    fn type_def() -> impl Strategy<Value = TypeDef> {
+        prop_oneof![
+            (type_name(), prop::collection::vec((ident(), type_expr(), any::<bool>()), 1..3))
+                .prop_map(|(name, fields)| TypeDef::Struct {
+                    name,
+                    fields: fields.into_iter()
+                        .map(|(name, ty, optional)| TypeField { name, ty, optional })
+                        .collect(),
+                }),
+            (type_name(), prop::collection::vec(text(), 1..3))
+                .prop_map(|(name, variants)| TypeDef::Enum { name, variants }),
+        ]
+    }
+
+    fn flow_def() -> impl Strategy<Value = FlowDef> {
+        let param = || (name(), type_expr(), prop::option::of(expr()))
+            .prop_map(|(name, ty, default)| Param { name, ty, default, variadic: false });
+        let variadic = (name(), type_expr()).prop_map(|(name, ty)| Param { name, ty, default: None, variadic: true });
+        let params = (prop::collection::vec(param(), 0..3), prop::option::of(variadic), prop::collection::vec(param(), 0..2))
+            .prop_map(|(mut before, variadic, after)| {
+                if let Some(variadic) = variadic {
+                    before.push(variadic);
+                    before.extend(after);
+                }
+                before
+            });
+        (ident(), prop::option::of(text()), params,
+         prop::option::of(type_expr()), block(stmt()))
+            // A leading bare string would be read back as the docstring
+            .prop_filter("docstring-shaped body", |(_, _, _, _, body)| {
+                !matches!(body.first().map(|s| &s.kind), Some(StmtKind::Expr(Expr::StringLit(_))))
+            })
+            .prop_map(|(name, description, params, return_type, body)| FlowDef {
+                name, description, params, return_type, body,
+            })
+    }
+
+    /// A top-level statement: no `return`/`break`/`continue`, and nothing
+    /// that would read back as an `import` line.
+    fn setup_stmt() -> impl Strategy<Value = Stmt> {
+        stmt().prop_filter("top-level statement", |s| {
+            let mut p = Printer::default();
+            p.stmt(s, 0);
+            !matches!(s.kind, StmtKind::Return { .. } | StmtKind::Break | StmtKind::Continue) && !p.out.starts_with("import")
+        })
+    }
+
+    fn program() -> impl Strategy<Value = Program> {
+        (prop::collection::vec((text(), prop::option::of(prop::collection::vec(ident(), 1..3)))
+            .prop_map(|(path, names)| Import { path, names }), 0..2),
+         prop::collection::vec(type_def(), 0..2),
+         prop::collection::vec(flow_def(), 0..3),
+         prop::collection::vec(setup_stmt(), 0..2))
+            .prop_map(|(imports, types, flows, setup)| Program { imports, types, flows, setup })
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(256))]
+
+        #[test]
+        fn prop_program_round_trips(prog in program()) {
+            let printed = pretty_program(&prog);
+            let reparsed = parse(&printed)
+                .map_err(|e| TestCaseError::fail(format!("{}\n--- source ---\n{}", e, printed)))?;
+            prop_assert_eq!(reparsed, prog, "source:\n{}", printed);
+        }
+    }
+
+    #[test]
+    fn test_examples_round_trip() {
+        for dir in ["examples", "lib"] {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.extension().and_then(|e| e.to_str()) != Some("cog") {
+                    continue;
+                }
+                let source = std::fs::read_to_string(&path).unwrap();
+                let Ok(prog) = parse(&source) else { continue };
+                let printed = pretty_program(&prog);
+                let reparsed = parse(&printed)
+                    .unwrap_or_else(|e| panic!("{}: {}\n{}", path.display(), e, printed));
+                assert_eq!(reparsed, prog, "{} did not round-trip:\n{}", path.display(), printed);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parenthesizes_by_precedence() {
+        let src = "flow main():\n    x = (a + b) * c\n    y = a - (b - c)\n    z = not (a and b)\n    w = (-1).abs()\n";
+        let prog = parse(src).unwrap();
+        let printed = pretty_program(&prog);
+        assert!(printed.contains("x = (a + b) * c"), "{}", printed);
+        assert!(printed.contains("y = a - (b - c)"), "{}", printed);
+        assert!(printed.contains("z = not (a and b)"), "{}", printed);
+        assert!(printed.contains("w = (-1).abs()"), "{}", printed);
+    }
+
+    #[test]
+    fn test_format_keeps_comments_and_blank_lines() {
+        let src = "# top\nimport \"lib/x.cog\"   # why\nflow main():\n    # leading\n    x = 1   # trailing\n\n\n    if x > 0:  # check\n        emit(x)\n        # end of if\n    # before else\n    else:\n        pass\n# last\n";
+        let formatted = format_source(src).unwrap();
+        assert_eq!(formatted, "# top\nimport \"lib/x.cog\"  # why\n\nflow main:\n    # leading\n    x = 1  # trailing\n\n    if x > 0:  # check\n        emit(x)\n        # end of if\n    # before else\n    else:\n        pass\n# last\n");
+        assert_eq!(format_source(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn test_format_breaks_long_calls() {
+        let src = format!("flow main():\n    r = think(\"{}\", model=\"m\", tools=[\"a\", \"b\"])\n", "x".repeat(90));
+        let formatted = format_source(&src).unwrap();
+        assert_eq!(formatted, format!("flow main:\n    r = think(\n        \"{}\",\n        model=\"m\",\n        tools=[\"a\", \"b\"],\n    )\n", "x".repeat(90)));
+        assert_eq!(format_source(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn test_format_keeps_triple_quoted_strings() {
+        let src = "flow main():\n    \"\"\"One line.\"\"\"\n    s = f\"\"\"Hello {name},\n  indented \"quoted\"\n\"\"\"\n";
+        let formatted = format_source(src).unwrap();
+        assert_eq!(formatted, "flow main:\n    \"\"\"One line.\"\"\"\n    s = f\"\"\"\n        Hello {name},\n          indented \"quoted\"\n\n    \"\"\"\n");
+        assert_eq!(format_source(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn test_format_examples_is_idempotent() {
+        for dir in ["examples", "lib"] {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.extension().and_then(|e| e.to_str()) != Some("cog") {
+                    continue;
+                }
+                let source = std::fs::read_to_string(&path).unwrap();
+                if parse(&source).is_err() { continue; }
+                let formatted = format_source(&source)
+                    .unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+                assert_eq!(format_source(&formatted).unwrap(), formatted, "{} is not stable", path.display());
+            }
+        }
     }
 }
@@ -0,0 +1,462 @@
+//! Anthropic Messages API: single-turn, vision and multi-turn conversations.
+
+use super::{field, Capabilities, ChatRequest, ChatResponse, ModelInfo, Provider, ToolCall, Turn};
+use crate::auth::Source;
+use crate::http_client::Service;
+use crate::interpreter::{Entries, Value};
+use anyhow::{bail, Result};
+
+const MESSAGES_URL: &str = "https://api.anthropic.com/v1/messages";
+
+pub struct AnthropicApi;
+
+/// Current model aliases; the API has no cheap unauthenticated listing.
+const MODELS: &[&str] = &[
+    "claude-opus-4-1",
+    "claude-opus-4-0",
+    "claude-sonnet-4-5",
+    "claude-sonnet-4-0",
+    "claude-3-7-sonnet-latest",
+    "claude-3-5-haiku-latest",
+];
+
+pub(super) fn static_models() -> Vec<ModelInfo> {
+    MODELS.iter().map(|name| ModelInfo { name: name.to_string(), tools: Some(true), vision: Some(true) }).collect()
+}
+
+/// Whether to prefer the API over the Claude CLI.
+pub(super) fn has_api_key() -> bool {
+    crate::auth::lookup("ANTHROPIC_API_KEY").is_some()
+}
+
+/// An API key or OAuth token; a `cognos login` token is refreshed if stale.
+fn token() -> Result<String> {
+    match crate::auth::lookup("ANTHROPIC_API_KEY") {
+        Some(c) if c.source == Source::CognosLogin => crate::oauth::get_access_token(),
+        Some(c) => {
+            log::info!("Using Anthropic credential from {}", c.source);
+            Ok(c.value)
+        }
+        None => bail!("No Anthropic token found. Set ANTHROPIC_API_KEY, run 'cognos login' or 'openclaw configure'."),
+    }
+}
+
+/// Beta features the native tool types need, by type.
+const TOOL_BETAS: &[(&str, &str)] = &[
+    ("computer_20241022", "computer-use-2024-10-22"),
+    ("bash_20241022", "computer-use-2024-10-22"),
+    ("text_editor_20241022", "computer-use-2024-10-22"),
+    ("computer_20250124", "computer-use-2025-01-24"),
+    ("code_execution_20250522", "code-execution-2025-05-22"),
+];
+
+/// The `anthropic-beta` features a request's tools need.
+fn tool_betas(body: &serde_json::Value) -> Vec<&'static str> {
+    let mut betas: Vec<&str> = Vec::new();
+    for tool in body["tools"].as_array().into_iter().flatten() {
+        let beta = TOOL_BETAS.iter().find(|(ty, _)| tool["type"] == *ty).map(|(_, beta)| *beta);
+        if let Some(beta) = beta.filter(|b| !betas.contains(b)) {
+            betas.push(beta);
+        }
+    }
+    betas
+}
+
+/// Convert OpenAI-format tool definitions to Anthropic's `input_schema` form.
+/// Native tools from `builtin_tool()` are already in Anthropic's form.
+fn tools_payload(tool_defs: &[serde_json::Value]) -> serde_json::Value {
+    let api_tools: Vec<serde_json::Value> = tool_defs.iter().map(|t| {
+        if super::is_native_tool(t) {
+            return t.clone();
+        }
+        serde_json::json!({
+            "name": t["function"]["name"].as_str().unwrap_or("unknown"),
+            "description": t["function"]["description"].as_str().unwrap_or(""),
+            "input_schema": t["function"]["parameters"]
+        })
+    }).collect();
+    serde_json::json!(api_tools)
+}
+
+fn request_body(req: &ChatRequest, messages: serde_json::Value) -> serde_json::Value {
+    let mut body = serde_json::json!({
+        "model": req.model,
+        "max_tokens": 4096,
+        "messages": messages
+    });
+    if !req.system.is_empty() {
+        body["system"] = serde_json::json!(req.system);
+    }
+    if let Some(tool_defs) = req.tools {
+        body["tools"] = tools_payload(tool_defs);
+    }
+    if let Some(temperature) = req.temperature {
+        body["temperature"] = serde_json::json!(temperature);
+    }
+    body
+}
+
+fn post(body: &serde_json::Value) -> Result<reqwest::blocking::Response> {
+    let token = token()?;
+    log::debug!("API request body: {}", serde_json::to_string(body).unwrap_or_default());
+    let client = crate::http_client::client(Service::Anthropic);
+    let mut req = client.post(MESSAGES_URL);
+    let mut betas = tool_betas(body);
+    if !token.starts_with("sk-ant-oat") {
+        req = req.header("x-api-key", &token);
+    } else {
+        req = req.header("Authorization", format!("Bearer {}", token));
+        betas.insert(0, "oauth-2025-04-20");
+    }
+    if !betas.is_empty() {
+        req = req.header("anthropic-beta", betas.join(","));
+    }
+    let resp = req
+        .header("anthropic-version", "2023-06-01")
+        .header("content-type", "application/json")
+        .json(body)
+        .send()
+        .map_err(|e| crate::retry::request_failed(format!("Anthropic API request failed: {}", e), &e))?;
+
+    let status = resp.status();
+    if !status.is_success() {
+        let body = resp.text().unwrap_or_default();
+        return Err(crate::retry::StatusError { what: "Anthropic API".to_string(), status: status.as_u16(), body }.into());
+    }
+    Ok(resp)
+}
+
+fn send(body: &serde_json::Value) -> Result<serde_json::Value> {
+    let resp_text = post(body)?.text().map_err(|e| anyhow::anyhow!("Failed to read API response: {}", e))?;
+    serde_json::from_str(&resp_text)
+        .map_err(|e| anyhow::anyhow!("Failed to parse API response: {}", e))
+}
+
+/// Split response content blocks into joined text and tool calls.
+fn parse_blocks(blocks: &[serde_json::Value], with_ids: bool) -> (String, Vec<ToolCall>) {
+    let mut text_parts: Vec<String> = Vec::new();
+    let mut tool_calls = Vec::new();
+    for block in blocks {
+        match block["type"].as_str() {
+            Some("text") => {
+                if let Some(t) = block["text"].as_str() {
+                    text_parts.push(t.to_string());
+                }
+            }
+            Some("tool_use") => {
+                let id = with_ids.then(|| block["id"].as_str()
+                    .map(|s| s.to_string())
+                    .unwrap_or_else(|| format!("call_{}", tool_calls.len())));
+                tool_calls.push(ToolCall {
+                    id,
+                    name: block["name"].as_str().unwrap_or("").to_string(),
+                    arguments: block["input"].clone(),
+                });
+            }
+            _ => {}
+        }
+    }
+    (text_parts.join("\n"), tool_calls)
+}
+
+fn content_blocks(parsed: &serde_json::Value) -> Result<&Vec<serde_json::Value>> {
+    parsed["content"].as_array().ok_or_else(|| anyhow::anyhow!("No content in API response"))
+}
+
+/// An image file as a base64 `image` content block.
+fn image_block(path: &str) -> Result<serde_json::Value> {
+    let data = std::fs::read(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read image {}: {}", path, e))?;
+    let b64 = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, &data);
+    let media_type = if path.ends_with(".png") { "image/png" }
+        else if path.ends_with(".gif") { "image/gif" }
+        else if path.ends_with(".webp") { "image/webp" }
+        else { "image/jpeg" };
+    Ok(serde_json::json!({
+        "type": "image",
+        "source": { "type": "base64", "media_type": media_type, "data": b64 }
+    }))
+}
+
+/// The few-shot examples and the prompt, with any images ahead of its text.
+fn messages(req: &ChatRequest) -> Result<Vec<serde_json::Value>> {
+    let content = if req.images.is_empty() {
+        serde_json::json!(req.prompt)
+    } else {
+        // Multimodal content: images first, then text
+        let mut parts: Vec<serde_json::Value> = Vec::new();
+        for path in req.images {
+            parts.push(image_block(path)?);
+        }
+        parts.push(serde_json::json!({ "type": "text", "text": req.prompt }));
+        serde_json::json!(parts)
+    };
+    let mut messages = req.example_messages();
+    messages.push(serde_json::json!({"role": "user", "content": content}));
+    Ok(messages)
+}
+
+impl Provider for AnthropicApi {
+    fn name(&self) -> &'static str { "anthropic" }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities { tools: true, vision: true, multi_turn: true, streaming: true, native_tools: true, ..Default::default() }
+    }
+
+    fn models(&self) -> Result<Vec<ModelInfo>> {
+        Ok(static_models())
+    }
+
+    fn chat(&self, req: &ChatRequest) -> Result<ChatResponse> {
+        log::info!("Calling Anthropic API: model={}, images={}, tools={}",
+            req.model, req.images.len(), req.tools.map(|t| t.len()).unwrap_or(0));
+        let body = request_body(req, serde_json::json!(messages(req)?));
+        let parsed = send(&body)?;
+        let (content, tool_calls) = parse_blocks(content_blocks(&parsed)?, false);
+        log::info!("Anthropic API: stop={}, tools={}", parsed["stop_reason"].as_str().unwrap_or(""), tool_calls.len());
+        Ok(ChatResponse {
+            content,
+            tool_calls,
+            label: if req.images.is_empty() { "anthropic-api" } else { "anthropic-api-vision" },
+            system: None,
+            finish_reason: super::finish_reason(&parsed["stop_reason"]),
+            logprobs: None,
+        })
+    }
+
+    /// Text arrives in `content_block_delta` events; the stop reason in `message_delta`.
+    fn chat_stream(&self, req: &ChatRequest, on_token: &mut dyn FnMut(&str) -> Result<()>) -> Result<ChatResponse> {
+        log::info!("Calling Anthropic API (streaming): model={}, images={}", req.model, req.images.len());
+        let mut body = request_body(req, serde_json::json!(messages(req)?));
+        body["stream"] = serde_json::json!(true);
+        let mut content = String::new();
+        let mut finish_reason = None;
+        super::read_sse(post(&body)?, |event| {
+            match event["type"].as_str() {
+                Some("content_block_delta") => {
+                    if let Some(text) = event["delta"]["text"].as_str() {
+                        content.push_str(text);
+                        on_token(text)?;
+                    }
+                }
+                Some("message_delta") => finish_reason = super::finish_reason(&event["delta"]["stop_reason"]),
+                Some("error") => bail!("Anthropic API error: {}", event["error"]["message"].as_str().unwrap_or("stream failed")),
+                _ => {}
+            }
+            Ok(())
+        })?;
+        Ok(ChatResponse {
+            content,
+            tool_calls: Vec::new(),
+            label: if req.images.is_empty() { "anthropic-api-stream" } else { "anthropic-api-vision-stream" },
+            system: None,
+            finish_reason,
+            logprobs: None,
+        })
+    }
+
+    fn chat_turn(&self, req: &ChatRequest, conversation: &[Value], tool_results: Option<&[Value]>) -> Result<Turn> {
+        log::info!("Calling Anthropic API (multi-turn): model={}, conversation_msgs={}, tools={}",
+            req.model, conversation.len(), req.tools.map(|t| t.len()).unwrap_or(0));
+        let mut messages = req.example_messages();
+        messages.extend(build_messages(conversation, req.prompt, tool_results)?);
+
+        // Truncate old tool_result content to stay under context limits.
+        // Keep last 6 messages at full size, truncate tool_result content in older messages.
+        let msg_count = messages.len();
+        if msg_count > 8 {
+            let keep_full_from = msg_count.saturating_sub(6);
+            for msg in messages.iter_mut().take(keep_full_from) {
+                let Some(blocks) = msg.get_mut("content").and_then(|c| c.as_array_mut()) else { continue };
+                for block in blocks.iter_mut() {
+                    if block.get("type").and_then(|t| t.as_str()) == Some("tool_result") {
+                        if let Some(c) = block.get_mut("content") {
+                            let text = c.as_str().unwrap_or("").to_string();
+                            if text.len() > 200 {
+                                *c = serde_json::json!(format!("{}...(truncated)", &text[..200]));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        let body = request_body(req, serde_json::json!(messages));
+        let parsed = send(&body)?;
+        let blocks = content_blocks(&parsed)?;
+        let (content, tool_calls) = parse_blocks(blocks, true);
+        log::info!("Anthropic API (multi-turn): stop={}, tools={}", parsed["stop_reason"].as_str().unwrap_or(""), tool_calls.len());
+
+        // The updated conversation carries the user message just sent and the
+        // assistant's raw content blocks, so later tool_result messages can
+        // reference tool_use blocks by id.
+        let mut updated = conversation.to_vec();
+        if let Some(last_user_msg) = messages.iter().rev().find(|m| m["role"] == "user") {
+            updated.push(Value::from_json(last_user_msg.clone()));
+        }
+        let has_tool_calls = !tool_calls.is_empty();
+        let mut assistant_msg = Entries::from([
+            ("role".to_string(), Value::String("assistant".to_string())),
+            ("content".to_string(), Value::List(blocks.iter().map(|b| Value::from_json(b.clone())).collect())),
+            ("has_tool_calls".to_string(), Value::Bool(has_tool_calls)),
+        ]);
+        if has_tool_calls {
+            assistant_msg.insert("tool_calls".to_string(), Value::List(tool_calls.iter().map(ToolCall::to_value).collect()));
+        }
+        updated.push(Value::Map(assistant_msg));
+
+        Ok(Turn {
+            response: ChatResponse {
+                content, tool_calls, label: "anthropic-api-multi-turn", system: None,
+                finish_reason: super::finish_reason(&parsed["stop_reason"]),
+                logprobs: None,
+            },
+            conversation: updated,
+        })
+    }
+}
+
+/// A tool result's `content`: its text, or blocks as sent before. With
+/// `image` (a path), the image goes ahead of the text, as a computer-use
+/// screenshot must.
+fn tool_result_content(entries: &Entries) -> Result<serde_json::Value> {
+    let image = entries.get("image").filter(|v| !matches!(v, Value::None));
+    let Some(image) = image.filter(|_| !matches!(entries.get("content"), Some(Value::List(_)))) else {
+        return Ok(sent_content(entries));
+    };
+    let mut blocks = vec![image_block(&image.to_string())?];
+    let text = field(entries, "content");
+    if !text.is_empty() {
+        blocks.push(serde_json::json!({ "type": "text", "text": text }));
+    }
+    Ok(serde_json::json!(blocks))
+}
+
+fn tool_result_blocks(tool_results: &[Value]) -> Result<Vec<serde_json::Value>> {
+    tool_results.iter().map(|result| {
+        Ok(if let Value::Map(entries) = result {
+            serde_json::json!({
+                "type": "tool_result",
+                "tool_use_id": field(entries, "tool_use_id"),
+                "content": tool_result_content(entries)?
+            })
+        } else {
+            serde_json::json!({
+                "type": "tool_result",
+                "tool_use_id": "unknown",
+                "content": result.to_string()
+            })
+        })
+    }).collect()
+}
+
+/// A tool result's `content` from the conversation: text, or the blocks it
+/// was sent as.
+fn sent_content(entries: &Entries) -> serde_json::Value {
+    match entries.get("content") {
+        Some(Value::List(blocks)) => serde_json::json!(blocks.iter().map(Value::to_json).collect::<Vec<_>>()),
+        _ => serde_json::json!(field(entries, "content")),
+    }
+}
+
+fn content_block(block: &Value) -> serde_json::Value {
+    let Value::Map(entries) = block else {
+        // Fallback: convert value to text block
+        return serde_json::json!({ "type": "text", "text": block.to_string() });
+    };
+    match field(entries, "type").as_str() {
+        "tool_result" => serde_json::json!({
+            "type": "tool_result",
+            "tool_use_id": field(entries, "tool_use_id"),
+            "content": sent_content(entries)
+        }),
+        "tool_use" => {
+            let input = entries.get("input").map(|v| v.to_json())
+                .unwrap_or(serde_json::json!({}));
+            serde_json::json!({
+                "type": "tool_use",
+                "id": field(entries, "id"),
+                "name": field(entries, "name"),
+                "input": input
+            })
+        }
+        "text" => serde_json::json!({ "type": "text", "text": field(entries, "text") }),
+        "" => {
+            let text = entries.get("text").or_else(|| entries.get("content"))
+                .map(|v| v.to_string()).unwrap_or_default();
+            serde_json::json!({ "type": "text", "text": text })
+        }
+        // Images, and server tool blocks (code execution, web search), go back as they came
+        _ => block.to_json(),
+    }
+}
+
+/// Convert a Cognos conversation plus the new prompt and/or tool results
+/// into Messages API `messages`.
+pub fn build_messages(conversation: &[Value], prompt: &str, tool_results: Option<&[Value]>) -> Result<Vec<serde_json::Value>> {
+    let mut messages: Vec<serde_json::Value> = Vec::new();
+
+    for msg in conversation {
+        let Value::Map(entries) = msg else { continue };
+        let role = field(entries, "role");
+        let Some(content_val) = entries.get("content") else { continue };
+        let content = match content_val {
+            Value::String(text) => serde_json::json!(text),
+            // Content blocks (for tool use and results)
+            Value::List(blocks) => serde_json::json!(blocks.iter().map(content_block).collect::<Vec<_>>()),
+            other => serde_json::json!(other.to_string()),
+        };
+        messages.push(serde_json::json!({ "role": role, "content": content }));
+    }
+
+    match tool_results {
+        Some(tr) => {
+            let mut blocks = Vec::new();
+            if !prompt.trim().is_empty() {
+                // Prompt text goes ahead of the results
+                blocks.push(serde_json::json!({ "type": "text", "text": prompt }));
+            }
+            blocks.extend(tool_result_blocks(tr)?);
+            messages.push(serde_json::json!({ "role": "user", "content": blocks }));
+        }
+        None if !prompt.is_empty() => {
+            messages.push(serde_json::json!({ "role": "user", "content": prompt }));
+        }
+        None => {}
+    }
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_native_tools_pass_through_with_their_beta() {
+        let computer = serde_json::json!({"type": "computer_20250124", "name": "computer", "display_width_px": 1280});
+        let flow = serde_json::json!({"type": "function", "function": {
+            "name": "notes", "description": "Read notes", "parameters": {"type": "object"}}});
+        let body = serde_json::json!({"tools": tools_payload(&[flow, computer.clone()])});
+        assert_eq!(body["tools"][0]["input_schema"], serde_json::json!({"type": "object"}));
+        assert_eq!(body["tools"][1], computer);
+        assert_eq!(tool_betas(&body), vec!["computer-use-2025-01-24"]);
+        assert!(tool_betas(&serde_json::json!({})).is_empty());
+    }
+
+    #[test]
+    fn test_tool_result_image_goes_before_the_text() {
+        let path = std::env::temp_dir().join(format!("cognos-tool-result-{}.png", std::process::id()));
+        std::fs::write(&path, b"\x89PNG").unwrap();
+        let result = Value::Map(Entries::from([
+            ("tool_use_id".to_string(), Value::String("toolu_1".to_string())),
+            ("content".to_string(), Value::String("clicked".to_string())),
+            ("image".to_string(), Value::String(path.display().to_string())),
+        ]));
+        let blocks = tool_result_blocks(&[result]).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let content = &blocks[0]["content"];
+        assert_eq!(content[0]["type"], "image");
+        assert_eq!(content[0]["source"]["media_type"], "image/png");
+        assert_eq!(content[1], serde_json::json!({"type": "text", "text": "clicked"}));
+    }
+}
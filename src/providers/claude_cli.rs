@@ -0,0 +1,297 @@
+//! The `claude` CLI, used for Claude models when no API key is configured.
+//! It has no native tool support, so tools are described in the system
+//! prompt and calls are parsed back out of the reply text. A call that
+//! doesn't parse is sent back with the error for another try.
+//!
+//! `COGNOS_CLAUDE_PATH` names the binary (default `claude`, looked up on
+//! `PATH`) and `COGNOS_CLAUDE_ARGS` adds arguments to every run. With
+//! `COGNOS_CLAUDE_SESSION=1`, calls with the same model and system prompt
+//! continue one CLI session for the rest of the run instead of starting
+//! fresh each time.
+
+use super::{Capabilities, ChatRequest, ChatResponse, ModelInfo, Provider, ToolCall};
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+pub struct ClaudeCli;
+
+/// Session ids by (model, system prompt), for `COGNOS_CLAUDE_SESSION`.
+/// Held for the whole run of a call, so session calls go one at a time.
+static SESSIONS: Mutex<Option<HashMap<(String, String), String>>> = Mutex::new(None);
+
+/// The binary to run: `COGNOS_CLAUDE_PATH`, default `claude`.
+pub fn cli_path() -> String {
+    std::env::var("COGNOS_CLAUDE_PATH").ok()
+        .filter(|p| !p.trim().is_empty())
+        .unwrap_or_else(|| "claude".to_string())
+}
+
+/// Whether the binary is there: an existing file for a path, a `PATH`
+/// entry for a bare name.
+pub fn cli_installed() -> bool {
+    let bin = cli_path();
+    if bin.contains('/') || bin.contains(std::path::MAIN_SEPARATOR) {
+        return std::path::Path::new(&bin).is_file();
+    }
+    crate::environment::on_path(&bin)
+}
+
+/// Extra arguments for every run: `COGNOS_CLAUDE_ARGS`, split on whitespace.
+fn extra_args() -> Vec<String> {
+    std::env::var("COGNOS_CLAUDE_ARGS").unwrap_or_default()
+        .split_whitespace().map(str::to_string).collect()
+}
+
+fn session_reuse() -> bool {
+    matches!(std::env::var("COGNOS_CLAUDE_SESSION").as_deref(), Ok("1" | "true" | "yes"))
+}
+
+/// A random (version 4) UUID for `--session-id`, from std's per-process
+/// hash seeds since there's no RNG dependency.
+fn new_session_id() -> String {
+    use std::hash::{BuildHasher, Hasher};
+    let word = || {
+        let mut h = std::collections::hash_map::RandomState::new().build_hasher();
+        h.write_u128(std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0));
+        h.write_u32(std::process::id());
+        h.finish()
+    };
+    let mut bytes = [0u8; 16];
+    bytes[..8].copy_from_slice(&word().to_le_bytes());
+    bytes[8..].copy_from_slice(&word().to_le_bytes());
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+/// Times a reply with a malformed tool call is sent back to be fixed:
+/// `COGNOS_TOOL_CALL_RETRIES`, default 2.
+fn tool_call_retries() -> usize {
+    std::env::var("COGNOS_TOOL_CALL_RETRIES").ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(2)
+}
+
+impl Provider for ClaudeCli {
+    fn name(&self) -> &'static str { "claude-cli" }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities { tools: true, ..Default::default() }
+    }
+
+    fn models(&self) -> Result<Vec<ModelInfo>> {
+        Ok(super::anthropic::static_models())
+    }
+
+    fn chat(&self, req: &ChatRequest) -> Result<ChatResponse> {
+        log::info!("Calling Claude CLI: model={}, tools={}", req.model, req.tools.map(|t| t.len()).unwrap_or(0));
+        let call_start = std::time::Instant::now();
+
+        // Build system prompt with tools embedded
+        let mut full_system = req.system.to_string();
+        if let Some(tool_defs) = req.tools {
+            full_system.push_str("\n\n## TOOL USE INSTRUCTIONS\n\nYou MUST use tools when they would help answer the user's question. You have FULL PERMISSION to use all tools listed below. NEVER say you need permission or cannot use a tool.\n\nTo call a tool, your ENTIRE response must be ONLY this JSON (no other text before or after):\n```\n{\"tool_calls\": [{\"name\": \"TOOL_NAME\", \"arguments\": {\"param\": \"value\"}}]}\n```\n\nYou can call multiple tools at once:\n```\n{\"tool_calls\": [{\"name\": \"tool1\", \"arguments\": {...}}, {\"name\": \"tool2\", \"arguments\": {...}}]}\n```\n\nAvailable tools:\n");
+            for t in tool_defs {
+                let name = t["function"]["name"].as_str().unwrap_or("");
+                let desc = t["function"]["description"].as_str().unwrap_or("");
+                let params = serde_json::to_string_pretty(&t["function"]["parameters"]).unwrap_or_default();
+                full_system.push_str(&format!("\n### {}\n{}\nParameters: {}\n", name, desc, params));
+            }
+            full_system.push_str("\nRULES:\n1. If a tool can help, USE IT. Your entire response must be the JSON tool call.\n2. If no tool is needed, respond with plain text (no JSON).\n3. NEVER ask for permission. NEVER say you cannot use a tool. You have full access.\n4. For web/internet questions, use web_search. For system info, use shell. For files, use read_file.\n");
+        }
+
+        // The CLI takes a single prompt, so examples go in the system prompt
+        if !req.examples.is_empty() {
+            full_system.push_str("\n\n## Examples\n");
+            for (input, output) in req.examples {
+                full_system.push_str(&format!("\nInput:\n{}\nOutput:\n{}\n", input, output));
+            }
+        }
+
+        let mut raw_text = run(req.model, &full_system, req.prompt)?;
+        log::info!("Claude CLI response: {} chars in {}ms", raw_text.len(), call_start.elapsed().as_millis());
+
+        let mut tool_calls = Vec::new();
+        if let Some(tool_defs) = req.tools {
+            let retries = tool_call_retries();
+            let mut attempt = 0;
+            tool_calls = loop {
+                let err = match parse_tool_calls(&raw_text, tool_defs) {
+                    Ok(calls) => break calls,
+                    Err(e) if attempt < retries => e,
+                    Err(e) => bail!("Claude CLI: the reply's tool call is still invalid after {} retries: {}", retries, e),
+                };
+                attempt += 1;
+                log::info!("Claude CLI: invalid tool call ({}), asking again ({}/{})", err, attempt, retries);
+                let retry = format!("{}\n\n---\nYour previous reply:\n\n{}\n\n---\nYour tool call JSON was invalid: {}. \
+                    Please retry: reply with only the corrected {{\"tool_calls\": [...]}} JSON, or with plain text if no tool is needed.",
+                    req.prompt, raw_text, err);
+                raw_text = run(req.model, &full_system, &retry)?;
+            };
+        }
+        let content = if tool_calls.is_empty() {
+            raw_text
+        } else {
+            raw_text.split("```json").next().unwrap_or("").trim().to_string()
+        };
+        Ok(ChatResponse { content, tool_calls, label: "claude-cli", system: Some(full_system), finish_reason: None, logprobs: None })
+    }
+}
+
+/// One `claude -p` run: the reply text. In a reused session the first
+/// call names the session and later ones resume it.
+fn run(model: &str, system: &str, prompt: &str) -> Result<String> {
+    if !session_reuse() {
+        return spawn(model, system, prompt, None);
+    }
+    let mut sessions = SESSIONS.lock().unwrap_or_else(|e| e.into_inner());
+    let key = (model.to_string(), system.to_string());
+    if let Some(id) = sessions.get_or_insert_with(HashMap::new).get(&key) {
+        return spawn(model, system, prompt, Some(("--resume", id)));
+    }
+    let id = new_session_id();
+    let text = spawn(model, system, prompt, Some(("--session-id", &id)))?;
+    // Only a session that got a reply exists to resume
+    sessions.get_or_insert_with(HashMap::new).insert(key, id);
+    Ok(text)
+}
+
+fn spawn(model: &str, system: &str, prompt: &str, session: Option<(&str, &str)>) -> Result<String> {
+    let bin = cli_path();
+    let mut cmd = std::process::Command::new(&bin);
+    cmd.args(["-p", "--output-format", "json"]);
+    match session {
+        Some((flag, id)) => { cmd.args([flag, id]); }
+        None => { cmd.arg("--no-session-persistence"); }
+    }
+    cmd.args(["--model", model, "--system-prompt", system, "--tools", ""])
+        .args(extra_args());
+    let output = cmd
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .and_then(|mut child| {
+            use std::io::Write;
+            if let Some(ref mut stdin) = child.stdin {
+                stdin.write_all(prompt.as_bytes())?;
+            }
+            child.wait_with_output()
+        })
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => anyhow::anyhow!(
+                "Claude CLI: '{}' not found. Install it with `npm install -g @anthropic-ai/claude-code` and log in with `claude`, \
+                 set COGNOS_CLAUDE_PATH (or claude_path in cognos.toml) to where it is, or set ANTHROPIC_API_KEY to use the API instead", bin),
+            _ => anyhow::anyhow!("Claude CLI error running '{}': {}", bin, e),
+        })?;
+
+    if !output.status.success() {
+        let err = String::from_utf8_lossy(&output.stdout);
+        bail!("Claude CLI failed (exit {}): {}", output.status, err);
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let parsed: serde_json::Value = serde_json::from_str(&stdout)
+        .map_err(|e| anyhow::anyhow!("Failed to parse Claude CLI output: {}\nRaw: {}", e, &stdout[..stdout.len().min(500)]))?;
+    let raw_text = parsed["result"].as_str().unwrap_or("").to_string();
+    if parsed["is_error"] == serde_json::Value::Bool(true) {
+        bail!("Claude CLI error: {}", raw_text);
+    }
+    Ok(raw_text)
+}
+
+/// Find a `{"tool_calls": [...]}` object in reply text, fenced or raw.
+/// None is fine (a plain answer); one that doesn't parse, or calls a tool
+/// that isn't in `tool_defs` or without an arguments object, is an error.
+fn parse_tool_calls(text: &str, tool_defs: &[serde_json::Value]) -> Result<Vec<ToolCall>> {
+    let json_str = if let Some(start) = text.find("```json") {
+        let after = &text[start + 7..];
+        after.find("```").map(|end| after[..end].trim())
+    } else if let Some(start) = text.find("```\n{") {
+        let after = &text[start + 4..];
+        after.find("```").map(|end| after[..end].trim())
+    } else if let Some(start) = text.find("\"tool_calls\"").and_then(|key| text[..key].rfind('{')) {
+        // Raw JSON
+        let after = &text[start..];
+        let mut depth = 0;
+        let mut end = 0;
+        for (i, c) in after.char_indices() {
+            match c {
+                '{' => depth += 1,
+                '}' => { depth -= 1; if depth == 0 { end = i + 1; break; } }
+                _ => {}
+            }
+        }
+        if end == 0 {
+            bail!("the JSON object is never closed");
+        }
+        Some(&after[..end])
+    } else {
+        None
+    };
+    // JSON that isn't a tool call is just part of the answer
+    let Some(json_str) = json_str.filter(|s| s.contains("\"tool_calls\"")) else { return Ok(Vec::new()) };
+
+    let parsed: serde_json::Value = serde_json::from_str(json_str)?;
+    let Some(calls) = parsed["tool_calls"].as_array() else { bail!("\"tool_calls\" must be a list of calls") };
+    let names: Vec<&str> = tool_defs.iter().filter_map(|t| t["function"]["name"].as_str()).collect();
+    calls.iter().enumerate().map(|(i, c)| {
+        let Some(name) = c["name"].as_str() else { bail!("call {} has no \"name\"", i + 1) };
+        if !names.contains(&name) {
+            bail!("there is no tool named '{}' (tools: {})", name, names.join(", "));
+        }
+        let arguments = match &c["arguments"] {
+            serde_json::Value::Null => serde_json::json!({}),
+            args @ serde_json::Value::Object(_) => args.clone(),
+            _ => bail!("the \"arguments\" of call {} ({}) must be an object", i + 1, name),
+        };
+        Ok(ToolCall { id: None, name: name.to_string(), arguments })
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tool_calls_fenced_and_raw() {
+        let tools = [
+            serde_json::json!({"type": "function", "function": {"name": "shell"}}),
+            serde_json::json!({"type": "function", "function": {"name": "a"}}),
+            serde_json::json!({"type": "function", "function": {"name": "b"}}),
+        ];
+        let fenced = "Sure.\n```json\n{\"tool_calls\": [{\"name\": \"shell\", \"arguments\": {\"command\": \"ls\"}}]}\n```";
+        let calls = parse_tool_calls(fenced, &tools).unwrap();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].name, "shell");
+        assert_eq!(calls[0].arguments["command"], "ls");
+
+        let raw = "{\"tool_calls\": [{\"name\": \"a\", \"arguments\": {}}, {\"name\": \"b\"}]} trailing";
+        assert_eq!(parse_tool_calls(raw, &tools).unwrap().len(), 2);
+        assert!(parse_tool_calls("just text", &tools).unwrap().is_empty());
+        assert!(parse_tool_calls("```json\n{\"answer\": 4}\n```", &tools).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_tool_calls_reports_malformed_calls() {
+        let tools = [serde_json::json!({"type": "function", "function": {"name": "shell"}})];
+        let err = |text: &str| parse_tool_calls(text, &tools).unwrap_err().to_string();
+        assert!(err("```json\n{\"tool_calls\": [{'name': 'shell'}]}\n```").contains("key must be a string"));
+        assert_eq!(err("{\"tool_calls\": [{\"name\": \"shell\""), "the JSON object is never closed");
+        assert_eq!(err("{ \"tool_calls\": [{\"name\": \"rm\"}]}"), "there is no tool named 'rm' (tools: shell)");
+        assert_eq!(err("{\"tool_calls\": [{\"name\": \"shell\", \"arguments\": \"ls\"}]}"),
+            "the \"arguments\" of call 1 (shell) must be an object");
+    }
+
+    #[test]
+    fn test_session_ids_are_distinct_v4_uuids() {
+        let (a, b) = (new_session_id(), new_session_id());
+        assert_ne!(a, b);
+        let groups: Vec<&str> = a.split('-').collect();
+        assert_eq!(groups.iter().map(|g| g.len()).collect::<Vec<_>>(), [8, 4, 4, 4, 12]);
+        assert!(groups[2].starts_with('4'), "{}", a);
+        assert!(matches!(&groups[3][..1], "8" | "9" | "a" | "b"), "{}", a);
+    }
+}
@@ -0,0 +1,167 @@
+//! Local GGUF models run in-process with llama.cpp: `think(model="file:./m.gguf")`.
+//! Needs a build with `--features gguf`; without it the provider only
+//! explains how to get one.
+
+use super::{Capabilities, ChatRequest, ChatResponse, OllamaOptions, Provider};
+use anyhow::{bail, Result};
+
+pub struct Gguf {
+    path: std::path::PathBuf,
+    num_ctx: Option<i64>,
+}
+
+impl Gguf {
+    pub fn new(path: &str, options: &OllamaOptions) -> Self {
+        Self { path: path.into(), num_ctx: options.num_ctx }
+    }
+}
+
+impl Provider for Gguf {
+    fn name(&self) -> &'static str { "gguf" }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+
+    fn chat(&self, req: &ChatRequest) -> Result<ChatResponse> {
+        if req.tools.is_some() || !req.images.is_empty() {
+            bail!("{}: local GGUF models don't support tools= or images=", req.model);
+        }
+        if !self.path.is_file() {
+            bail!("GGUF model not found: {}", self.path.display());
+        }
+        log::info!("Running GGUF model {} (num_ctx={:?})", self.path.display(), self.num_ctx);
+        let content = engine::generate(&self.path, req.system, req.examples, req.prompt, self.num_ctx)?;
+        Ok(ChatResponse { content, tool_calls: Vec::new(), label: "gguf", system: None, finish_reason: None, logprobs: None })
+    }
+}
+
+/// Exact token count from the model's own vocabulary, when it can be loaded.
+pub fn count_tokens(path: &str, text: &str) -> Option<usize> {
+    let path = std::path::Path::new(path);
+    if !path.is_file() {
+        return None;
+    }
+    engine::count_tokens(path, text).ok()
+}
+
+#[cfg(not(feature = "gguf"))]
+mod engine {
+    use anyhow::{bail, Result};
+
+    pub fn generate(_: &std::path::Path, _: &str, _: &[(String, String)], _: &str, _: Option<i64>) -> Result<String> {
+        bail!("file: models need llama.cpp support. Rebuild with: cargo install --path . --features gguf")
+    }
+
+    pub fn count_tokens(_: &std::path::Path, _: &str) -> Result<usize> {
+        bail!("no llama.cpp tokenizer in this build")
+    }
+}
+
+#[cfg(feature = "gguf")]
+mod engine {
+    use anyhow::{bail, Result};
+    use llama_cpp_2::context::params::LlamaContextParams;
+    use llama_cpp_2::llama_backend::LlamaBackend;
+    use llama_cpp_2::llama_batch::LlamaBatch;
+    use llama_cpp_2::model::params::LlamaModelParams;
+    use llama_cpp_2::model::{LlamaChatMessage, LlamaModel};
+    use llama_cpp_2::sampling::LlamaSampler;
+    use std::collections::HashMap;
+    use std::num::NonZeroU32;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex, OnceLock};
+
+    /// Upper bound on generated tokens per call.
+    const MAX_TOKENS: usize = 1024;
+
+    /// llama.cpp may only be initialised once per process.
+    fn backend() -> Result<&'static LlamaBackend> {
+        static BACKEND: OnceLock<LlamaBackend> = OnceLock::new();
+        if let Some(backend) = BACKEND.get() {
+            return Ok(backend);
+        }
+        llama_cpp_2::send_logs_to_tracing(llama_cpp_2::LogOptions::default().with_logs_enabled(false));
+        let backend = LlamaBackend::init()?;
+        Ok(BACKEND.get_or_init(|| backend))
+    }
+
+    /// Loaded models stay resident for the rest of the run.
+    fn load(path: &Path) -> Result<Arc<LlamaModel>> {
+        static MODELS: OnceLock<Mutex<HashMap<PathBuf, Arc<LlamaModel>>>> = OnceLock::new();
+        let key = path.canonicalize()?;
+        let mut models = MODELS.get_or_init(Default::default).lock().unwrap();
+        if let Some(model) = models.get(&key) {
+            return Ok(model.clone());
+        }
+        let start = std::time::Instant::now();
+        let model = Arc::new(LlamaModel::load_from_file(backend()?, &key, &LlamaModelParams::default())?);
+        log::info!("Loaded {} in {}ms", key.display(), start.elapsed().as_millis());
+        models.insert(key, model.clone());
+        Ok(model)
+    }
+
+    pub fn count_tokens(path: &Path, text: &str) -> Result<usize> {
+        Ok(load(path)?.vocab().tokenize(text.as_bytes(), false, false).len())
+    }
+
+    pub fn generate(path: &Path, system: &str, examples: &[(String, String)], prompt: &str, num_ctx: Option<i64>) -> Result<String> {
+        let model = load(path)?;
+
+        // Use the model's own chat template; base models without one get plain text
+        let mut chat = Vec::new();
+        if !system.is_empty() {
+            chat.push(LlamaChatMessage::new("system".into(), system.into())?);
+        }
+        for (input, output) in examples {
+            chat.push(LlamaChatMessage::new("user".into(), input.clone())?);
+            chat.push(LlamaChatMessage::new("assistant".into(), output.clone())?);
+        }
+        chat.push(LlamaChatMessage::new("user".into(), prompt.into())?);
+        let text = match model.chat_template(None) {
+            Ok(template) => model.apply_chat_template(&template, &chat, true)?,
+            Err(_) if system.is_empty() => prompt.to_string(),
+            Err(_) => format!("{}\n\n{}", system, prompt),
+        };
+
+        let n_ctx = num_ctx.map(|n| n as u32).unwrap_or_else(|| model.n_ctx_train().min(4096));
+        let params = LlamaContextParams::default()
+            .with_n_ctx(NonZeroU32::new(n_ctx))
+            .with_n_batch(n_ctx);
+        let mut ctx = model.new_context(backend()?, params)?;
+
+        let vocab = model.vocab();
+        let tokens = vocab.tokenize(text.as_bytes(), true, true);
+        if tokens.len() >= n_ctx as usize {
+            bail!("prompt is {} tokens but the context holds {}; raise num_ctx", tokens.len(), n_ctx);
+        }
+        let mut batch = LlamaBatch::new(n_ctx as usize, 1);
+        let last = tokens.len() - 1;
+        for (i, token) in tokens.iter().enumerate() {
+            batch.add(*token, i as i32, &[0], i == last)?;
+        }
+        ctx.decode(&mut batch)?;
+
+        let seed = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.subsec_nanos()).unwrap_or(0);
+        let mut sampler = LlamaSampler::chain_simple([
+            LlamaSampler::top_p(0.95, 1),
+            LlamaSampler::temp(0.7),
+            LlamaSampler::dist(seed),
+        ]);
+        let mut output = Vec::new();
+        let mut pos = tokens.len() as i32;
+        for _ in 0..MAX_TOKENS {
+            let token = sampler.sample(&ctx, batch.n_tokens() - 1);
+            if vocab.is_eog(token) || pos as u32 >= n_ctx {
+                break;
+            }
+            output.extend(vocab.token_to_piece(token, false, None));
+            batch.clear();
+            batch.add(token, pos, &[0], true)?;
+            pos += 1;
+            ctx.decode(&mut batch)?;
+        }
+        Ok(String::from_utf8_lossy(&output).trim().to_string())
+    }
+}
@@ -0,0 +1,373 @@
+//! LLM provider backends.
+//! Each backend implements [`Provider`]; the interpreter picks one with
+//! [`resolve`] and only ever calls the trait. Adding a backend is one file
+//! here plus an entry in `candidates`. Servers declared in `cognos.toml`
+//! ([`register`]) are routed to ahead of the built-in prefixes.
+
+mod anthropic;
+mod claude_cli;
+mod gguf;
+mod ollama;
+mod openai;
+mod plugin;
+mod registered;
+mod responses;
+mod tokens;
+
+#[cfg(test)]
+pub use anthropic::build_messages as anthropic_messages;
+pub use claude_cli::{cli_installed as claude_cli_installed, cli_path as claude_cli_path};
+pub use ollama::{warmup as ollama_warmup, OllamaOptions};
+pub use responses::upload_file;
+pub use tokens::count_tokens;
+
+use crate::interpreter::{Entries, Value};
+use anyhow::{bail, Result};
+use std::sync::OnceLock;
+
+/// `[providers]` from `cognos.toml`, set once at startup.
+static REGISTRY: OnceLock<crate::config::Providers> = OnceLock::new();
+
+/// Route model names to the servers `cognos.toml` declares.
+pub fn register(providers: crate::config::Providers) {
+    let _ = REGISTRY.set(providers);
+}
+
+/// What a backend can do. Also used to describe what a call needs.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Capabilities {
+    pub tools: bool,
+    pub vision: bool,
+    pub multi_turn: bool,
+    pub streaming: bool,
+    /// Takes the provider's own tool types (computer use, code execution)
+    /// from `builtin_tool()`, sent as they are.
+    pub native_tools: bool,
+    /// Takes files uploaded with `file_upload()` (`files=`).
+    pub files: bool,
+    /// Reports token log probabilities (`logprobs=`).
+    pub logprobs: bool,
+}
+
+impl Capabilities {
+    fn covers(&self, need: &Capabilities) -> bool {
+        (self.tools || !need.tools)
+            && (self.vision || !need.vision)
+            && (self.multi_turn || !need.multi_turn)
+            && (self.streaming || !need.streaming)
+            && (self.native_tools || !need.native_tools)
+            && (self.files || !need.files)
+            && (self.logprobs || !need.logprobs)
+    }
+}
+
+/// Whether a tool definition is a provider-native one from `builtin_tool()`
+/// rather than a flow in OpenAI function format.
+pub fn is_native_tool(def: &serde_json::Value) -> bool {
+    def["type"].as_str() != Some("function")
+}
+
+/// Fail unless `provider` can take the native tools and files a call
+/// needs and report logprobs if asked. Other gaps are left to the
+/// provider's own error.
+pub fn check_support(provider: &dyn Provider, need: &Capabilities) -> Result<()> {
+    let caps = provider.capabilities();
+    if need.native_tools && !caps.native_tools {
+        bail!("{} can't run builtin_tool() tools — use a claude or gpt-* model, or a flow", provider.name());
+    }
+    if need.files && !caps.files {
+        bail!("{} can't take files= — uploaded files need a gpt-* model", provider.name());
+    }
+    if need.logprobs && !caps.logprobs {
+        bail!("{} doesn't report logprobs= — use an OpenAI-compatible model (gpt-*, deepseek, endpoint=)", provider.name());
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
+pub struct ChatRequest<'a> {
+    pub model: &'a str,
+    pub system: &'a str,
+    pub prompt: &'a str,
+    /// Tool definitions in OpenAI function format, and any native ones
+    /// (see [`is_native_tool`]).
+    pub tools: Option<&'a [serde_json::Value]>,
+    pub images: &'a [String],
+    /// Ids of files from `file_upload()`, attached to the prompt.
+    pub files: &'a [String],
+    /// Few-shot (input, output) pairs, sent ahead of the prompt.
+    pub examples: &'a [(String, String)],
+    /// Sampling temperature; `None` leaves the provider's default.
+    pub temperature: Option<f64>,
+    /// Constrain the reply to a JSON object, where the provider can.
+    pub json: bool,
+    /// The JSON Schema of the `format=` type. Backends with a native
+    /// structured-output mode hold the reply to it; the rest go by the
+    /// schema text in the system prompt.
+    pub schema: Option<&'a serde_json::Value>,
+    /// Return each reply token's log probability.
+    pub logprobs: bool,
+}
+
+impl ChatRequest<'_> {
+    /// The few-shot examples as alternating user/assistant messages.
+    fn example_messages(&self) -> Vec<serde_json::Value> {
+        self.examples.iter().flat_map(|(input, output)| [
+            serde_json::json!({"role": "user", "content": input}),
+            serde_json::json!({"role": "assistant", "content": output}),
+        ]).collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolCall {
+    pub id: Option<String>,
+    pub name: String,
+    pub arguments: serde_json::Value,
+}
+
+impl ToolCall {
+    /// The `{id?, name, arguments}` map that `think()` hands back to flows.
+    pub fn to_value(&self) -> Value {
+        let mut entries = Entries::new();
+        if let Some(ref id) = self.id {
+            entries.insert("id".to_string(), Value::String(id.clone()));
+        }
+        entries.insert("name".to_string(), Value::String(self.name.clone()));
+        entries.insert("arguments".to_string(), Value::from_json(self.arguments.clone()));
+        Value::Map(entries)
+    }
+}
+
+#[derive(Debug)]
+pub struct ChatResponse {
+    pub content: String,
+    pub tool_calls: Vec<ToolCall>,
+    /// Trace label, e.g. "anthropic-api-vision".
+    pub label: &'static str,
+    /// The system prompt actually sent, when the provider rewrote it.
+    pub system: Option<String>,
+    /// Why generation stopped, normalized by [`finish_reason`].
+    pub finish_reason: Option<String>,
+    /// Each reply token with its log probability, when asked for (`logprobs=`).
+    pub logprobs: Option<Vec<TokenLogprob>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenLogprob {
+    pub token: String,
+    pub logprob: f64,
+}
+
+/// How sure the model was of a reply: the geometric mean of its tokens'
+/// probabilities, from 0 to 1. `None` for an empty reply.
+pub fn confidence(logprobs: &[TokenLogprob]) -> Option<f64> {
+    if logprobs.is_empty() {
+        return None;
+    }
+    let mean = logprobs.iter().map(|t| t.logprob).sum::<f64>() / logprobs.len() as f64;
+    Some(mean.exp())
+}
+
+/// A provider's stop reason in OpenAI's terms where one exists: "stop",
+/// "length", "tool_calls" or "content_filter". Others pass through as is.
+pub fn finish_reason(raw: &serde_json::Value) -> Option<String> {
+    let reason = match raw.as_str()? {
+        "end_turn" | "stop_sequence" => "stop",
+        "max_tokens" => "length",
+        "tool_use" => "tool_calls",
+        "refusal" => "content_filter",
+        other => other,
+    };
+    Some(reason.to_string())
+}
+
+/// A model a provider offers. `None` means the provider can't tell.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelInfo {
+    pub name: String,
+    pub tools: Option<bool>,
+    pub vision: Option<bool>,
+}
+
+/// A multi-turn response plus the conversation extended with this exchange.
+pub struct Turn {
+    pub response: ChatResponse,
+    pub conversation: Vec<Value>,
+}
+
+pub trait Provider {
+    fn name(&self) -> &'static str;
+    fn capabilities(&self) -> Capabilities;
+    fn chat(&self, req: &ChatRequest) -> Result<ChatResponse>;
+
+    /// Like [`Provider::chat`], handing each piece of the reply text to
+    /// `on_token` as it arrives. Backends that can't stream hand over the
+    /// whole reply at once.
+    fn chat_stream(&self, req: &ChatRequest, on_token: &mut dyn FnMut(&str) -> Result<()>) -> Result<ChatResponse> {
+        let resp = self.chat(req)?;
+        if !resp.content.is_empty() {
+            on_token(&resp.content)?;
+        }
+        Ok(resp)
+    }
+
+    /// Continue `conversation`, answering either `req.prompt` or `tool_results`.
+    fn chat_turn(&self, req: &ChatRequest, conversation: &[Value], tool_results: Option<&[Value]>) -> Result<Turn> {
+        let _ = (req, conversation, tool_results);
+        bail!("{} does not support conversation=", self.name())
+    }
+
+    /// Models this backend can serve right now.
+    fn models(&self) -> Result<Vec<ModelInfo>> {
+        Ok(Vec::new())
+    }
+}
+
+/// Pick the backend for `model`: the first candidate that covers `need`,
+/// or the preferred one if none does (its own error then explains why).
+pub fn resolve(model: &str, need: Capabilities, ollama: &OllamaOptions) -> Box<dyn Provider> {
+    let mut candidates = candidates(model, ollama);
+    let index = candidates.iter().position(|p| p.capabilities().covers(&need)).unwrap_or(0);
+    candidates.swap_remove(index)
+}
+
+/// The backend for a call with its own `endpoint=`: any OpenAI-compatible
+/// server (vLLM, TGI, llama.cpp), whatever the model is called.
+pub fn at_endpoint(endpoint: &str, api_key: Option<String>) -> Box<dyn Provider> {
+    Box::new(openai::OpenAiCompat::custom(endpoint, api_key))
+}
+
+/// Ask every backend for its models. Only names that `think(model=...)`
+/// would route back to the same backend are kept, and each flag is also
+/// limited by what that backend supports.
+pub fn list_models(ollama: &OllamaOptions) -> Vec<(&'static str, Result<Vec<ModelInfo>>)> {
+    let mut backends: Vec<Box<dyn Provider>> = vec![
+        candidates("claude", ollama).swap_remove(0),
+        Box::new(openai::OpenAiCompat::openai()),
+        Box::new(openai::OpenAiCompat::deepseek()),
+        Box::new(openai::OpenAiCompat::minimax()),
+        Box::new(ollama::Ollama::new(ollama.clone())),
+    ];
+    for (name, config) in REGISTRY.get().into_iter().flat_map(|r| r.iter()) {
+        backends.push(Box::new(registered::Registered::new(name, config, ollama)));
+    }
+    backends.iter().map(|backend| {
+        let models = backend.models().map(|models| {
+            let mut kept: Vec<ModelInfo> = models.into_iter().filter_map(|m| {
+                let routed = resolve(&m.name, Capabilities::default(), ollama);
+                if routed.name() != backend.name() {
+                    return None;
+                }
+                let caps = routed.capabilities();
+                Some(ModelInfo {
+                    tools: if caps.tools { m.tools } else { Some(false) },
+                    vision: if caps.vision { m.vision } else { Some(false) },
+                    name: m.name,
+                })
+            }).collect();
+            kept.sort_by(|a, b| a.name.cmp(&b.name));
+            kept
+        });
+        (backend.name(), models)
+    }).collect()
+}
+
+fn candidates(model: &str, ollama: &OllamaOptions) -> Vec<Box<dyn Provider>> {
+    if let Some((plugin, prefix)) = crate::plugin::model(model) {
+        return vec![Box::new(plugin::PluginBackend::new(plugin, prefix))];
+    }
+    if let Some((name, config)) = REGISTRY.get().and_then(|r| r.get(model)) {
+        return vec![Box::new(registered::Registered::new(name, config, ollama))];
+    }
+    if let Some(path) = model.strip_prefix("file:") {
+        return vec![Box::new(gguf::Gguf::new(path, ollama))];
+    }
+    if model.starts_with("claude") {
+        // The API has native tools; the CLI is the fallback without a key
+        if anthropic::has_api_key() {
+            return vec![Box::new(anthropic::AnthropicApi)];
+        }
+        return vec![Box::new(claude_cli::ClaudeCli), Box::new(anthropic::AnthropicApi)];
+    }
+    if model.starts_with("deepseek") {
+        return vec![Box::new(openai::OpenAiCompat::deepseek())];
+    }
+    if model.starts_with("MiniMax") || model.starts_with("minimax") {
+        return vec![Box::new(openai::OpenAiCompat::minimax())];
+    }
+    if model.starts_with("gpt-") || model.starts_with("o1-") || model.starts_with("o3-") {
+        // Hosted tools and uploaded files need the Responses API
+        return vec![Box::new(openai::OpenAiCompat::openai()), Box::new(responses::OpenAiResponses)];
+    }
+    vec![Box::new(ollama::Ollama::new(ollama.clone()))]
+}
+
+/// Hand the JSON payload of each `data:` line of a server-sent event stream
+/// to `on_event`, until the stream ends or sends `[DONE]`.
+fn read_sse(resp: reqwest::blocking::Response, mut on_event: impl FnMut(serde_json::Value) -> Result<()>) -> Result<()> {
+    use std::io::BufRead;
+    for line in std::io::BufReader::new(resp).lines() {
+        let line = line.map_err(|e| anyhow::anyhow!("response stream broke off: {}", e))?;
+        let Some(data) = line.strip_prefix("data:").map(str::trim) else { continue };
+        if data == "[DONE]" {
+            break;
+        }
+        let event = serde_json::from_str(data)
+            .map_err(|e| anyhow::anyhow!("bad event in response stream: {}", e))?;
+        on_event(event)?;
+    }
+    Ok(())
+}
+
+/// Look up a string field in a conversation message map.
+fn field(entries: &Entries, key: &str) -> String {
+    entries.get(key).map(|v| v.to_string()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_routes_by_model_prefix() {
+        let opts = OllamaOptions::default();
+        let need = Capabilities::default();
+        assert_eq!(resolve("deepseek-chat", need, &opts).name(), "deepseek");
+        assert_eq!(resolve("MiniMax-M1", need, &opts).name(), "minimax");
+        assert_eq!(resolve("gpt-4o", need, &opts).name(), "openai");
+        assert_eq!(resolve("qwen2.5:7b", need, &opts).name(), "ollama");
+        assert_eq!(resolve("file:./models/q4.gguf", need, &opts).name(), "gguf");
+        let hosted = Capabilities { native_tools: true, ..Default::default() };
+        assert_eq!(resolve("gpt-4o", hosted, &opts).name(), "openai-responses");
+    }
+
+    #[test]
+    fn test_finish_reason_normalized() {
+        assert_eq!(finish_reason(&serde_json::json!("max_tokens")).as_deref(), Some("length"));
+        assert_eq!(finish_reason(&serde_json::json!("end_turn")).as_deref(), Some("stop"));
+        assert_eq!(finish_reason(&serde_json::json!("content_filter")).as_deref(), Some("content_filter"));
+        assert_eq!(finish_reason(&serde_json::Value::Null), None);
+    }
+
+    #[test]
+    fn test_confidence_is_the_geometric_mean_probability() {
+        let token = |logprob: f64| TokenLogprob { token: "x".to_string(), logprob };
+        assert_eq!(confidence(&[token(0.0), token(0.0)]), Some(1.0));
+        let c = confidence(&[token(0.5f64.ln()), token(0.125f64.ln())]).unwrap();
+        assert!((c - 0.25).abs() < 1e-12, "got {}", c);
+        assert_eq!(confidence(&[]), None);
+    }
+
+    #[test]
+    fn test_resolve_skips_providers_missing_a_capability() {
+        let cli_and_api: Vec<Box<dyn Provider>> = vec![Box::new(claude_cli::ClaudeCli), Box::new(anthropic::AnthropicApi)];
+        let vision = Capabilities { vision: true, ..Default::default() };
+        let turn = Capabilities { multi_turn: true, ..Default::default() };
+        assert!(!cli_and_api[0].capabilities().covers(&vision));
+        assert!(!cli_and_api[0].capabilities().covers(&turn));
+        assert!(cli_and_api[1].capabilities().covers(&vision));
+        assert!(cli_and_api[1].capabilities().covers(&turn));
+        assert!(cli_and_api[0].capabilities().covers(&Capabilities { tools: true, ..Default::default() }));
+    }
+}
@@ -0,0 +1,222 @@
+//! Local models served by Ollama. Single calls use the native `/api/chat`;
+//! conversations go through its OpenAI-compatible `/v1` endpoint.
+
+use super::openai::OpenAiCompat;
+use super::{Capabilities, ChatRequest, ChatResponse, ModelInfo, Provider, ToolCall, Turn};
+use crate::http_client::Service;
+use crate::interpreter::Value;
+use anyhow::Result;
+use std::time::Duration;
+
+/// Model residency settings sent with every Ollama request.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OllamaOptions {
+    /// How long the server keeps the model loaded: a duration string
+    /// ("30m", "-1") or a number of seconds.
+    pub keep_alive: Option<serde_json::Value>,
+    /// Context window size, in tokens.
+    pub num_ctx: Option<i64>,
+}
+
+impl OllamaOptions {
+    fn apply(&self, body: &mut serde_json::Value) {
+        if let Some(ref keep_alive) = self.keep_alive {
+            body["keep_alive"] = keep_alive.clone();
+        }
+        if let Some(num_ctx) = self.num_ctx {
+            body["options"]["num_ctx"] = serde_json::json!(num_ctx);
+        }
+    }
+}
+
+fn url() -> String {
+    std::env::var("OLLAMA_URL").unwrap_or_else(|_| "http://localhost:11434".to_string())
+}
+
+/// Turn an Ollama error response into an actionable message.
+fn error(model: &str, status: u16, body: &str) -> anyhow::Error {
+    let detail = serde_json::from_str::<serde_json::Value>(body).ok()
+        .and_then(|j| j["error"].as_str().map(|s| s.to_string()))
+        .unwrap_or_else(|| body.trim().to_string());
+    if status == 404 || detail.contains("not found") {
+        anyhow::anyhow!("Ollama model '{}' is not available locally. Run: ollama pull {}", model, model)
+    } else {
+        crate::retry::StatusError { what: "Ollama".to_string(), status, body: detail }.into()
+    }
+}
+
+fn post(base: &str, timeout: Option<Duration>, path: &str, model: &str, body: &serde_json::Value) -> Result<reqwest::blocking::Response> {
+    let resp = crate::http_client::client_for(Service::Ollama, timeout)
+        .post(format!("{}{}", base, path))
+        .json(body)
+        .send()
+        .map_err(|e| crate::retry::request_failed(format!("Ollama error: {}. Is Ollama running? (ollama serve)", e), &e))?;
+    let status = resp.status().as_u16();
+    if status >= 400 {
+        return Err(error(model, status, &resp.text().unwrap_or_default()));
+    }
+    Ok(resp)
+}
+
+/// Load `model` into Ollama without generating anything.
+pub fn warmup(model: &str, options: &OllamaOptions) -> Result<()> {
+    let mut body = serde_json::json!({ "model": model });
+    options.apply(&mut body);
+    post(&url(), None, "/api/generate", model, &body)?;
+    Ok(())
+}
+
+pub struct Ollama {
+    options: OllamaOptions,
+    /// The server's root URL.
+    base: String,
+    timeout: Option<Duration>,
+}
+
+impl Ollama {
+    pub fn new(options: OllamaOptions) -> Self {
+        Self { options, base: url(), timeout: None }
+    }
+
+    /// A server named in `cognos.toml`, rather than `OLLAMA_URL`.
+    pub fn at(base: &str, options: OllamaOptions, timeout: Option<Duration>) -> Self {
+        Self { options, base: base.trim_end_matches('/').to_string(), timeout }
+    }
+
+    fn post(&self, path: &str, model: &str, body: &serde_json::Value) -> Result<reqwest::blocking::Response> {
+        post(&self.base, self.timeout, path, model, body)
+    }
+
+    /// The `/api/chat` request body, images inlined as base64.
+    fn body(&self, req: &ChatRequest, stream: bool) -> Result<serde_json::Value> {
+        let mut messages = Vec::new();
+        if !req.system.is_empty() {
+            messages.push(serde_json::json!({"role": "system", "content": req.system}));
+        }
+        messages.extend(req.example_messages());
+
+        // Build user message with optional images (base64-encoded)
+        let mut user_msg = serde_json::json!({"role": "user", "content": req.prompt});
+        if !req.images.is_empty() {
+            let mut b64_images = Vec::new();
+            for path in req.images {
+                let bytes = std::fs::read(path)
+                    .map_err(|e| anyhow::anyhow!("cannot read image '{}': {}", path, e))?;
+                use base64::Engine;
+                b64_images.push(serde_json::Value::String(
+                    base64::engine::general_purpose::STANDARD.encode(&bytes)
+                ));
+                log::info!("image: {} ({} bytes)", path, bytes.len());
+            }
+            user_msg["images"] = serde_json::Value::Array(b64_images);
+        }
+        messages.push(user_msg);
+
+        let mut body = serde_json::json!({
+            "model": req.model,
+            "messages": messages,
+            "stream": stream
+        });
+        if let Some(tool_defs) = req.tools {
+            body["tools"] = serde_json::json!(tool_defs);
+        }
+        if let Some(temperature) = req.temperature {
+            body["options"]["temperature"] = serde_json::json!(temperature);
+        }
+        if let Some(schema) = req.schema {
+            body["format"] = schema.clone();
+        } else if req.json {
+            body["format"] = serde_json::json!("json");
+        }
+        self.options.apply(&mut body);
+        Ok(body)
+    }
+}
+
+impl Provider for Ollama {
+    fn name(&self) -> &'static str { "ollama" }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities { tools: true, vision: true, multi_turn: true, streaming: true, ..Default::default() }
+    }
+
+    fn chat(&self, req: &ChatRequest) -> Result<ChatResponse> {
+        log::info!("Calling Ollama: model={}, system={:?}, tools={}, images={}",
+            req.model, req.system, req.tools.map(|t| t.len()).unwrap_or(0), req.images.len());
+        let json: serde_json::Value = self.post("/api/chat", req.model, &self.body(req, false)?)?.json()
+            .map_err(|e| anyhow::anyhow!("Ollama JSON error: {}", e))?;
+
+        let message = &json["message"];
+        let tool_calls = message.get("tool_calls").and_then(|v| v.as_array())
+            .map(|calls| calls.iter().map(|c| ToolCall {
+                id: None,
+                name: c["function"]["name"].as_str().unwrap_or("").to_string(),
+                arguments: c["function"]["arguments"].clone(),
+            }).collect())
+            .unwrap_or_default();
+        Ok(ChatResponse {
+            content: message["content"].as_str().unwrap_or("").to_string(),
+            tool_calls,
+            label: "ollama",
+            system: None,
+            finish_reason: super::finish_reason(&json["done_reason"]),
+            logprobs: None,
+        })
+    }
+
+    /// The native API streams one JSON object per line, the last with `done`.
+    fn chat_stream(&self, req: &ChatRequest, on_token: &mut dyn FnMut(&str) -> Result<()>) -> Result<ChatResponse> {
+        use std::io::BufRead;
+        log::info!("Calling Ollama (streaming): model={}, images={}", req.model, req.images.len());
+        let resp = self.post("/api/chat", req.model, &self.body(req, true)?)?;
+        let mut content = String::new();
+        let mut finish_reason = None;
+        for line in std::io::BufReader::new(resp).lines() {
+            let line = line.map_err(|e| anyhow::anyhow!("Ollama stream broke off: {}", e))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let json: serde_json::Value = serde_json::from_str(&line)
+                .map_err(|e| anyhow::anyhow!("Ollama JSON error: {}", e))?;
+            if let Some(err) = json["error"].as_str() {
+                anyhow::bail!("Ollama error: {}", err);
+            }
+            if let Some(text) = json["message"]["content"].as_str().filter(|t| !t.is_empty()) {
+                content.push_str(text);
+                on_token(text)?;
+            }
+            if json["done"].as_bool() == Some(true) {
+                finish_reason = super::finish_reason(&json["done_reason"]);
+                break;
+            }
+        }
+        Ok(ChatResponse { content, tool_calls: Vec::new(), label: "ollama-stream", system: None, finish_reason, logprobs: None })
+    }
+
+    /// Pulled models from `/api/tags`; flags come from `/api/show`, which
+    /// only newer servers answer with a `capabilities` list.
+    fn models(&self) -> Result<Vec<ModelInfo>> {
+        let resp = crate::http_client::client_for(Service::Ollama, self.timeout)
+            .get(format!("{}/api/tags", self.base))
+            .send()
+            .map_err(|e| crate::retry::request_failed(format!("Ollama error: {}. Is Ollama running? (ollama serve)", e), &e))?;
+        let json: serde_json::Value = resp.json()
+            .map_err(|e| anyhow::anyhow!("Ollama JSON error: {}", e))?;
+        let names: Vec<String> = json["models"].as_array().map(|models| models.iter()
+            .filter_map(|m| m["name"].as_str().map(|n| n.to_string()))
+            .collect()).unwrap_or_default();
+        Ok(names.into_iter().map(|name| {
+            let caps = self.post("/api/show", &name, &serde_json::json!({ "model": name })).ok()
+                .and_then(|resp| resp.json::<serde_json::Value>().ok())
+                .and_then(|show| show["capabilities"].as_array().cloned());
+            let has = |cap: &str| caps.as_ref().map(|c| c.iter().any(|v| v == cap));
+            ModelInfo { tools: has("tools"), vision: has("vision"), name }
+        }).collect())
+    }
+
+    fn chat_turn(&self, req: &ChatRequest, conversation: &[Value], tool_results: Option<&[Value]>) -> Result<Turn> {
+        OpenAiCompat::local("ollama", format!("{}/v1/chat/completions", self.base), "ollama-multi-turn")
+            .with_timeout(self.timeout)
+            .chat_turn(req, conversation, tool_results)
+    }
+}
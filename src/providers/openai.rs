@@ -0,0 +1,533 @@
+//! OpenAI chat completions and compatible APIs (DeepSeek, MiniMax, Ollama's
+//! `/v1` endpoint).
+
+use super::{field, Capabilities, ChatRequest, ChatResponse, ModelInfo, Provider, TokenLogprob, ToolCall, Turn};
+use crate::http_client::Service;
+use crate::interpreter::{Entries, Value};
+use anyhow::{bail, Result};
+use std::time::Duration;
+
+pub struct OpenAiCompat {
+    name: &'static str,
+    endpoint: String,
+    /// Some backends serve conversations from a different endpoint.
+    turn_endpoint: String,
+    /// Env var (or .env entry) holding the API key; `None` means no auth.
+    env_key: Option<&'static str>,
+    /// A key given with the call itself (`api_key=`), over `env_key`.
+    api_key: Option<String>,
+    /// Request timeout, instead of the shared client's.
+    timeout: Option<Duration>,
+    turn_label: &'static str,
+    /// Known models, for backends without a `/models` listing.
+    known_models: &'static [&'static str],
+}
+
+impl OpenAiCompat {
+    pub fn openai() -> Self {
+        Self::hosted("openai", "https://api.openai.com/v1/chat/completions", None, "OPENAI_API_KEY")
+    }
+
+    pub fn deepseek() -> Self {
+        Self::hosted("deepseek", "https://api.deepseek.com/v1/chat/completions", None, "DEEPSEEK_API_KEY")
+    }
+
+    pub fn minimax() -> Self {
+        Self {
+            known_models: &["MiniMax-M2", "MiniMax-M1", "MiniMax-Text-01"],
+            ..Self::hosted("minimax", "https://api.minimax.io/v1/chat/completions",
+                Some("https://api.minimax.chat/v1/text/chatcompletion_v2"), "MINIMAX_API_KEY")
+        }
+    }
+
+    /// An unauthenticated local server, e.g. Ollama.
+    pub fn local(name: &'static str, endpoint: String, turn_label: &'static str) -> Self {
+        Self { name, turn_endpoint: endpoint.clone(), endpoint, env_key: None, api_key: None, timeout: None, turn_label, known_models: &[] }
+    }
+
+    /// A server named in `cognos.toml`: a `/v1` base URL or the full chat
+    /// completions URL, keyed by `env_key` if it needs a key.
+    pub fn registered(name: &'static str, base: &str, env_key: Option<&'static str>) -> Self {
+        let base = base.trim_end_matches('/');
+        let endpoint = if base.ends_with("/chat/completions") { base.to_string() } else { format!("{}/chat/completions", base) };
+        Self { env_key, ..Self::local(name, endpoint, "openai-multi-turn") }
+    }
+
+    /// A server named by `think(endpoint=...)`, with the key (if any) passed along.
+    pub fn custom(base: &str, api_key: Option<String>) -> Self {
+        Self { api_key, ..Self::registered("endpoint", base, None) }
+    }
+
+    pub fn with_timeout(self, timeout: Option<Duration>) -> Self {
+        Self { timeout, ..self }
+    }
+
+    fn hosted(name: &'static str, endpoint: &str, turn_endpoint: Option<&str>, env_key: &'static str) -> Self {
+        Self {
+            name,
+            endpoint: endpoint.to_string(),
+            turn_endpoint: turn_endpoint.unwrap_or(endpoint).to_string(),
+            env_key: Some(env_key),
+            api_key: None,
+            timeout: None,
+            turn_label: "openai-multi-turn",
+            known_models: &[],
+        }
+    }
+
+    fn authorize(&self, req: reqwest::blocking::RequestBuilder) -> Result<reqwest::blocking::RequestBuilder> {
+        match (&self.api_key, self.env_key) {
+            (Some(key), _) => Ok(req.header("Authorization", format!("Bearer {}", key))),
+            (None, Some(env_key)) => Ok(req.header("Authorization", format!("Bearer {}", crate::auth::require(env_key)?))),
+            (None, None) => Ok(req),
+        }
+    }
+
+    /// An error status comes back as a `StatusError`, for retries to see.
+    fn post(&self, endpoint: &str, body: &serde_json::Value) -> Result<reqwest::blocking::Response> {
+        let req = crate::http_client::client_for(Service::OpenAi, self.timeout).post(endpoint)
+            .header("Content-Type", "application/json");
+        let resp = self.authorize(req)?.json(body).send().map_err(|e| crate::retry::request_failed(format!("API error: {}", e), &e))?;
+        let status = resp.status();
+        if !status.is_success() {
+            return Err(crate::retry::StatusError { what: format!("{} API", self.name), status: status.as_u16(), body: resp.text().unwrap_or_default() }.into());
+        }
+        Ok(resp)
+    }
+}
+
+fn request_body(req: &ChatRequest, messages: Vec<serde_json::Value>) -> serde_json::Value {
+    let mut body = serde_json::json!({
+        "model": req.model,
+        "messages": messages
+    });
+    if let Some(tool_defs) = req.tools {
+        body["tools"] = serde_json::json!(tool_defs);
+        body["tool_choice"] = serde_json::json!("auto");
+    }
+    if let Some(temperature) = req.temperature {
+        body["temperature"] = serde_json::json!(temperature);
+    }
+    if let Some(format) = req.schema.and_then(schema_format) {
+        body["response_format"] = serde_json::json!({"type": "json_schema", "json_schema": format});
+    } else if req.json {
+        body["response_format"] = serde_json::json!({"type": "json_object"});
+    }
+    if req.logprobs {
+        body["logprobs"] = serde_json::json!(true);
+    }
+    body
+}
+
+/// The `json_schema` settings for a reply matching `schema`, or `None` if
+/// its top level isn't an object, which OpenAI can't constrain. Strict mode,
+/// which guarantees a match, is used where the schema allows it.
+pub(super) fn schema_format(schema: &serde_json::Value) -> Option<serde_json::Value> {
+    if schema["type"] != "object" {
+        return None;
+    }
+    let name: String = schema["title"].as_str().unwrap_or("response").chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-')
+        .take(64)
+        .collect();
+    let mut strict = schema.clone();
+    let format = match make_strict(&mut strict) {
+        true => serde_json::json!({"name": name, "schema": strict, "strict": true}),
+        false => serde_json::json!({"name": name, "schema": schema, "strict": false}),
+    };
+    Some(format)
+}
+
+/// Close every object in `schema` to extra keys, as strict mode requires.
+/// False if strict mode can't express it: an optional field, a Map's open
+/// keys, or a List or object of unknown shape.
+fn make_strict(schema: &mut serde_json::Value) -> bool {
+    match schema["type"].as_str() {
+        Some("object") => {
+            let required = schema["required"].as_array().map_or(0, Vec::len);
+            let Some(properties) = schema["properties"].as_object_mut() else { return false };
+            if required != properties.len() || !properties.values_mut().all(make_strict) {
+                return false;
+            }
+            schema["additionalProperties"] = serde_json::json!(false);
+            true
+        }
+        Some("array") => schema.get_mut("items").is_some_and(make_strict),
+        _ => true,
+    }
+}
+
+/// The tokens of a choice (or a streamed delta) with their log probabilities.
+fn choice_logprobs(choice: &serde_json::Value) -> Vec<TokenLogprob> {
+    choice["logprobs"]["content"].as_array().into_iter().flatten()
+        .filter_map(|t| Some(TokenLogprob { token: t["token"].as_str()?.to_string(), logprob: t["logprob"].as_f64()? }))
+        .collect()
+}
+
+/// The system prompt, few-shot examples and prompt.
+fn messages(req: &ChatRequest) -> Vec<serde_json::Value> {
+    let mut messages = Vec::new();
+    if !req.system.is_empty() {
+        messages.push(serde_json::json!({"role": "system", "content": req.system}));
+    }
+    messages.extend(req.example_messages());
+    messages.push(serde_json::json!({"role": "user", "content": req.prompt}));
+    messages
+}
+
+/// Holds back streamed text while it could still be a leading
+/// `<think>...</think>` block, which [`message_content`] drops from whole replies.
+#[derive(Default)]
+struct ThinkFilter {
+    held: String,
+    passing: bool,
+}
+
+impl ThinkFilter {
+    /// The part of `chunk` to show, if any yet.
+    fn feed(&mut self, chunk: &str) -> Option<String> {
+        if self.passing {
+            return Some(chunk.to_string());
+        }
+        self.held.push_str(chunk);
+        let start = self.held.trim_start();
+        if "<think>".starts_with(start) {
+            return None;
+        }
+        if start.starts_with("<think>") {
+            let end = start.find("</think>")? + 8;
+            let rest = start[end..].trim_start().to_string();
+            self.passing = !rest.is_empty();
+            self.held = if self.passing { String::new() } else { "<think></think>".to_string() };
+            return self.passing.then_some(rest);
+        }
+        self.passing = true;
+        Some(std::mem::take(&mut self.held))
+    }
+
+    /// Text still held when the stream ends that wasn't reasoning after all.
+    fn finish(self) -> Option<String> {
+        let start = self.held.trim_start();
+        (!self.passing && !start.is_empty() && !start.starts_with("<think>")).then_some(self.held)
+    }
+}
+
+/// Message text, minus any leading `<think>...</think>` reasoning (MiniMax).
+fn message_content(message: &serde_json::Value) -> String {
+    let raw = message["content"].as_str().unwrap_or("");
+    match raw.find("</think>") {
+        Some(end) => raw[end + 8..].trim().to_string(),
+        None => raw.to_string(),
+    }
+}
+
+/// Tool calls carry arguments as a JSON string; unparseable ones become `{}`.
+fn message_tool_calls(message: &serde_json::Value, with_ids: bool) -> Vec<ToolCall> {
+    let Some(calls) = message.get("tool_calls").and_then(|v| v.as_array()) else { return Vec::new() };
+    calls.iter().map(|c| {
+        let func = &c["function"];
+        let args_str = func["arguments"].as_str().unwrap_or("{}");
+        ToolCall {
+            id: with_ids.then(|| c["id"].as_str().unwrap_or("").to_string()),
+            name: func["name"].as_str().unwrap_or("").to_string(),
+            arguments: serde_json::from_str(args_str).unwrap_or(serde_json::json!({})),
+        }
+    }).collect()
+}
+
+fn tool_message(entries: &Entries) -> serde_json::Value {
+    let tool_call_id = entries.get("tool_call_id").or_else(|| entries.get("tool_use_id"))
+        .map(|v| v.to_string()).unwrap_or_default();
+    serde_json::json!({
+        "role": "tool",
+        "tool_call_id": tool_call_id,
+        "content": field(entries, "content")
+    })
+}
+
+impl Provider for OpenAiCompat {
+    fn name(&self) -> &'static str { self.name }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities { tools: true, multi_turn: true, streaming: true, logprobs: true, ..Default::default() }
+    }
+
+    fn models(&self) -> Result<Vec<ModelInfo>> {
+        if !self.known_models.is_empty() {
+            // Without a key none of them are usable
+            if let Some(env_key) = self.env_key {
+                crate::auth::require(env_key)?;
+            }
+            return Ok(self.known_models.iter()
+                .map(|name| ModelInfo { name: name.to_string(), tools: Some(true), vision: None })
+                .collect());
+        }
+        let url = self.endpoint.replace("/chat/completions", "/models");
+        let resp = self.authorize(crate::http_client::client_for(Service::OpenAi, self.timeout).get(&url))?
+            .send().map_err(|e| crate::retry::request_failed(format!("API error: {}", e), &e))?;
+        let status = resp.status();
+        let json: serde_json::Value = resp.json().map_err(|e| anyhow::anyhow!("JSON parse error: {}", e))?;
+        if !status.is_success() {
+            return Err(crate::retry::StatusError { what: format!("{} models", self.name), status: status.as_u16(), body: json["error"].to_string() }.into());
+        }
+        // Skip non-chat models (audio, image generation, embeddings, ...)
+        const NON_CHAT: &[&str] = &["audio", "realtime", "transcribe", "tts", "image", "embedding", "search", "moderation"];
+        Ok(json["data"].as_array().map(|models| models.iter()
+            .filter_map(|m| m["id"].as_str())
+            .filter(|id| !NON_CHAT.iter().any(|skip| id.contains(skip)))
+            .map(|id| ModelInfo { name: id.to_string(), tools: Some(true), vision: None })
+            .collect()).unwrap_or_default())
+    }
+
+    fn chat(&self, req: &ChatRequest) -> Result<ChatResponse> {
+        log::info!("Calling {}: model={}, tools={}", self.name, req.model, req.tools.map(|t| t.len()).unwrap_or(0));
+        let resp = self.post(&self.endpoint, &request_body(req, messages(req)))?;
+        let json: serde_json::Value = resp.json()
+            .map_err(|e| anyhow::anyhow!("OpenAI JSON error: {}", e))?;
+        if let Some(err) = json.get("error") {
+            bail!("OpenAI API error: {}", err);
+        }
+
+        let message = &json["choices"][0]["message"];
+        Ok(ChatResponse {
+            content: message_content(message),
+            tool_calls: message_tool_calls(message, false),
+            label: "openai",
+            system: None,
+            finish_reason: super::finish_reason(&json["choices"][0]["finish_reason"]),
+            logprobs: req.logprobs.then(|| choice_logprobs(&json["choices"][0])),
+        })
+    }
+
+    /// Each event carries a `delta` of the message; the last one a finish reason.
+    fn chat_stream(&self, req: &ChatRequest, on_token: &mut dyn FnMut(&str) -> Result<()>) -> Result<ChatResponse> {
+        log::info!("Calling {} (streaming): model={}", self.name, req.model);
+        let mut body = request_body(req, messages(req));
+        body["stream"] = serde_json::json!(true);
+        let resp = self.post(&self.endpoint, &body)?;
+        let mut raw = String::new();
+        let mut finish_reason = None;
+        let mut logprobs = Vec::new();
+        let mut filter = ThinkFilter::default();
+        super::read_sse(resp, |event| {
+            if let Some(err) = event.get("error") {
+                bail!("OpenAI API error: {}", err);
+            }
+            let choice = &event["choices"][0];
+            if let Some(text) = choice["delta"]["content"].as_str() {
+                raw.push_str(text);
+                if let Some(shown) = filter.feed(text) {
+                    on_token(&shown)?;
+                }
+            }
+            logprobs.extend(choice_logprobs(choice));
+            if !choice["finish_reason"].is_null() {
+                finish_reason = super::finish_reason(&choice["finish_reason"]);
+            }
+            Ok(())
+        })?;
+        if let Some(rest) = filter.finish() {
+            on_token(&rest)?;
+        }
+        Ok(ChatResponse {
+            content: message_content(&serde_json::json!({ "content": raw })),
+            tool_calls: Vec::new(),
+            label: "openai-stream",
+            system: None,
+            finish_reason,
+            logprobs: req.logprobs.then_some(logprobs),
+        })
+    }
+
+    fn chat_turn(&self, req: &ChatRequest, conversation: &[Value], tool_results: Option<&[Value]>) -> Result<Turn> {
+        log::info!("Calling OpenAI-compat API (multi-turn): model={}, endpoint={}, conversation_msgs={}, tools={}",
+            req.model, self.turn_endpoint, conversation.len(), req.tools.map(|t| t.len()).unwrap_or(0));
+
+        let mut messages: Vec<serde_json::Value> = Vec::new();
+        if !req.system.is_empty() {
+            messages.push(serde_json::json!({"role": "system", "content": req.system}));
+        }
+        messages.extend(req.example_messages());
+
+        // Convert conversation history to OpenAI format
+        for msg in conversation {
+            let Value::Map(entries) = msg else { continue };
+            let role = field(entries, "role");
+            let has_tool_calls = entries.get("has_tool_calls").map(|v| matches!(v, Value::Bool(true))).unwrap_or(false);
+
+            if role == "assistant" && has_tool_calls {
+                // Assistant message with tool calls — reconstruct OpenAI format
+                let content_str = field(entries, "content");
+                let mut msg_json = serde_json::json!({"role": "assistant"});
+                msg_json["content"] = if content_str.is_empty() { serde_json::Value::Null } else { serde_json::json!(content_str) };
+                if let Some(Value::List(calls)) = entries.get("tool_calls") {
+                    let tc: Vec<serde_json::Value> = calls.iter().map(|call| {
+                        let Value::Map(ce) = call else { return serde_json::json!({}) };
+                        let args = ce.get("arguments").map(|v| v.to_json())
+                            .unwrap_or(serde_json::json!({}));
+                        serde_json::json!({
+                            "id": field(ce, "id"),
+                            "type": "function",
+                            "function": {
+                                "name": field(ce, "name"),
+                                "arguments": serde_json::to_string(&args).unwrap_or_default()
+                            }
+                        })
+                    }).collect();
+                    msg_json["tool_calls"] = serde_json::json!(tc);
+                }
+                messages.push(msg_json);
+            } else if role == "tool" {
+                messages.push(serde_json::json!({
+                    "role": "tool",
+                    "tool_call_id": field(entries, "tool_call_id"),
+                    "content": field(entries, "content")
+                }));
+            } else {
+                messages.push(serde_json::json!({
+                    "role": role,
+                    "content": field(entries, "content")
+                }));
+            }
+        }
+
+        // Tool results go out as individual "tool" messages, then any prompt
+        if let Some(tr) = tool_results {
+            for result in tr {
+                if let Value::Map(entries) = result {
+                    messages.push(tool_message(entries));
+                }
+            }
+        }
+        if !req.prompt.is_empty() {
+            messages.push(serde_json::json!({"role": "user", "content": req.prompt}));
+        }
+
+        // Truncate old tool message content to manage tokens
+        let msg_count = messages.len();
+        if msg_count > 8 {
+            let keep_full_from = msg_count.saturating_sub(6);
+            for msg in messages.iter_mut().take(keep_full_from) {
+                if msg.get("role").and_then(|r| r.as_str()) == Some("tool") {
+                    if let Some(c) = msg.get_mut("content") {
+                        let text = c.as_str().unwrap_or("").to_string();
+                        if text.len() > 200 {
+                            *c = serde_json::json!(format!("{}...(truncated)", &text[..200]));
+                        }
+                    }
+                }
+            }
+        }
+
+        let resp = self.post(&self.turn_endpoint, &request_body(req, messages))?;
+        let resp_text = resp.text().map_err(|e| anyhow::anyhow!("Failed to read API response: {}", e))?;
+        let json: serde_json::Value = serde_json::from_str(&resp_text)
+            .map_err(|e| anyhow::anyhow!("JSON parse error: {}", e))?;
+        if let Some(err) = json.get("error") {
+            bail!("API error: {}", err);
+        }
+
+        let message = &json["choices"][0]["message"];
+        let content = message_content(message);
+        let tool_calls = message_tool_calls(message, true);
+        let has_tool_calls = !tool_calls.is_empty();
+
+        // Record what was sent, then the assistant reply
+        let mut updated = conversation.to_vec();
+        if let Some(tr) = tool_results {
+            for result in tr {
+                if let Value::Map(entries) = result {
+                    let msg = tool_message(entries);
+                    updated.push(Value::Map(Entries::from([
+                        ("role".to_string(), Value::String("tool".to_string())),
+                        ("tool_call_id".to_string(), Value::from_json(msg["tool_call_id"].clone())),
+                        ("content".to_string(), Value::from_json(msg["content"].clone())),
+                    ])));
+                }
+            }
+        } else if !req.prompt.is_empty() {
+            updated.push(Value::Map(Entries::from([
+                ("role".to_string(), Value::String("user".to_string())),
+                ("content".to_string(), Value::String(req.prompt.to_string())),
+            ])));
+        }
+        let mut assistant_msg = Entries::from([
+            ("role".to_string(), Value::String("assistant".to_string())),
+            ("content".to_string(), Value::String(content.clone())),
+            ("has_tool_calls".to_string(), Value::Bool(has_tool_calls)),
+        ]);
+        if has_tool_calls {
+            assistant_msg.insert("tool_calls".to_string(), Value::List(tool_calls.iter().map(ToolCall::to_value).collect()));
+        }
+        updated.push(Value::Map(assistant_msg));
+
+        Ok(Turn {
+            response: ChatResponse {
+                content, tool_calls, label: self.turn_label, system: None,
+                finish_reason: super::finish_reason(&json["choices"][0]["finish_reason"]),
+                logprobs: req.logprobs.then(|| choice_logprobs(&json["choices"][0])),
+            },
+            conversation: updated,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shown(chunks: &[&str]) -> String {
+        let mut filter = ThinkFilter::default();
+        let mut out: String = chunks.iter().filter_map(|c| filter.feed(c)).collect();
+        out.extend(filter.finish());
+        out
+    }
+
+    #[test]
+    fn test_choice_logprobs() {
+        let choice = serde_json::json!({"logprobs": {"content": [
+            {"token": "yes", "logprob": -0.01, "bytes": [121, 101, 115], "top_logprobs": []},
+            {"token": ".", "logprob": -0.2},
+        ]}});
+        assert_eq!(choice_logprobs(&choice), vec![
+            TokenLogprob { token: "yes".to_string(), logprob: -0.01 },
+            TokenLogprob { token: ".".to_string(), logprob: -0.2 },
+        ]);
+        assert!(choice_logprobs(&serde_json::json!({"logprobs": null})).is_empty());
+    }
+
+    #[test]
+    fn test_schema_format_is_strict_only_when_it_can_be() {
+        let closed = serde_json::json!({"title": "Invoice", "type": "object",
+            "properties": {"lines": {"type": "array", "items": {"type": "object", "properties": {"total": {"type": "number"}}, "required": ["total"]}}},
+            "required": ["lines"]});
+        let format = schema_format(&closed).unwrap();
+        assert_eq!(format["strict"], true);
+        assert_eq!(format["schema"]["additionalProperties"], false);
+        assert_eq!(format["schema"]["properties"]["lines"]["items"]["additionalProperties"], false);
+
+        let optional = serde_json::json!({"title": "Person", "type": "object",
+            "properties": {"name": {"type": "string"}, "nick": {"type": "string"}}, "required": ["name"]});
+        let format = schema_format(&optional).unwrap();
+        assert_eq!(format["strict"], false);
+        assert_eq!(format["schema"], optional);
+        assert_eq!(schema_format(&serde_json::json!({"type": "string", "enum": ["a", "b"]})), None);
+    }
+
+    #[test]
+    fn test_custom_endpoint_takes_a_base_or_full_url() {
+        assert_eq!(OpenAiCompat::custom("http://my-vllm:8000/v1", None).endpoint, "http://my-vllm:8000/v1/chat/completions");
+        assert_eq!(OpenAiCompat::custom("http://my-vllm:8000/v1/", None).endpoint, "http://my-vllm:8000/v1/chat/completions");
+        let full = OpenAiCompat::custom("http://localhost:8080/v1/chat/completions", Some("sk-local".to_string()));
+        assert_eq!(full.turn_endpoint, "http://localhost:8080/v1/chat/completions");
+        assert_eq!(full.api_key.as_deref(), Some("sk-local"));
+    }
+
+    #[test]
+    fn test_think_filter_drops_leading_reasoning() {
+        assert_eq!(shown(&["<thi", "nk>plan", "ning</th", "ink>\n\n", "Hello", " world"]), "Hello world");
+        assert_eq!(shown(&["Hello", " <think>kept</think>"]), "Hello <think>kept</think>");
+        assert_eq!(shown(&["<", "b>bold</b>"]), "<b>bold</b>");
+        assert_eq!(shown(&["<thi"]), "<thi");
+    }
+}
@@ -0,0 +1,53 @@
+//! Models served by a native plugin (see `crate::plugin`): the request goes
+//! to the plugin as a `chat` call and its reply is read back as a response.
+
+use super::{Capabilities, ChatRequest, ChatResponse, Provider, ToolCall};
+use crate::plugin::{ModelPrefix, Plugin};
+use anyhow::Result;
+
+pub struct PluginBackend {
+    plugin: &'static Plugin,
+    model: &'static ModelPrefix,
+}
+
+impl PluginBackend {
+    pub fn new(plugin: &'static Plugin, model: &'static ModelPrefix) -> Self {
+        Self { plugin, model }
+    }
+}
+
+impl Provider for PluginBackend {
+    fn name(&self) -> &'static str { "plugin" }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities { tools: self.model.tools, vision: self.model.vision, ..Default::default() }
+    }
+
+    fn chat(&self, req: &ChatRequest) -> Result<ChatResponse> {
+        let reply = self.plugin.call(serde_json::json!({
+            "call": "chat",
+            "model": req.model,
+            "system": req.system,
+            "prompt": req.prompt,
+            "tools": req.tools,
+            "images": req.images,
+            "examples": req.examples,
+            "temperature": req.temperature,
+            "json": req.json,
+            "schema": req.schema,
+        }))?;
+        let tool_calls = reply["tool_calls"].as_array().map(|calls| calls.iter().map(|c| ToolCall {
+            id: c["id"].as_str().map(str::to_string),
+            name: c["name"].as_str().unwrap_or_default().to_string(),
+            arguments: c["arguments"].clone(),
+        }).collect()).unwrap_or_default();
+        Ok(ChatResponse {
+            content: reply["content"].as_str().unwrap_or_default().to_string(),
+            tool_calls,
+            label: "plugin",
+            system: None,
+            finish_reason: super::finish_reason(&reply["finish_reason"]),
+            logprobs: None,
+        })
+    }
+}
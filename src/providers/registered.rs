@@ -0,0 +1,59 @@
+//! Model servers declared in `cognos.toml` (`[providers.<name>]`), speaking
+//! the OpenAI or Ollama wire format under the name they were given there.
+
+use super::ollama::{Ollama, OllamaOptions};
+use super::openai::OpenAiCompat;
+use super::{Capabilities, ChatRequest, ChatResponse, ModelInfo, Provider, Turn};
+use crate::config::ProviderConfig;
+use crate::interpreter::Value;
+use anyhow::Result;
+use std::time::Duration;
+
+pub struct Registered {
+    name: &'static str,
+    inner: Box<dyn Provider>,
+    /// Sent as the model when a call asks for the provider by name.
+    default_model: Option<&'static str>,
+}
+
+impl Registered {
+    pub fn new(name: &'static str, config: &'static ProviderConfig, ollama: &OllamaOptions) -> Self {
+        let timeout = config.timeout.map(Duration::from_secs);
+        let inner: Box<dyn Provider> = match config.api.as_deref() {
+            Some("ollama") => Box::new(Ollama::at(&config.endpoint, ollama.clone(), timeout)),
+            _ => Box::new(OpenAiCompat::registered(name, &config.endpoint, config.api_key_env.as_deref()).with_timeout(timeout)),
+        };
+        Self { name, inner, default_model: config.default_model.as_deref() }
+    }
+
+    fn request<'a>(&self, req: &ChatRequest<'a>) -> ChatRequest<'a> {
+        match self.default_model {
+            Some(model) if req.model == self.name => ChatRequest { model, ..*req },
+            _ => *req,
+        }
+    }
+}
+
+impl Provider for Registered {
+    fn name(&self) -> &'static str { self.name }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn chat(&self, req: &ChatRequest) -> Result<ChatResponse> {
+        self.inner.chat(&self.request(req))
+    }
+
+    fn chat_stream(&self, req: &ChatRequest, on_token: &mut dyn FnMut(&str) -> Result<()>) -> Result<ChatResponse> {
+        self.inner.chat_stream(&self.request(req), on_token)
+    }
+
+    fn chat_turn(&self, req: &ChatRequest, conversation: &[Value], tool_results: Option<&[Value]>) -> Result<Turn> {
+        self.inner.chat_turn(&self.request(req), conversation, tool_results)
+    }
+
+    fn models(&self) -> Result<Vec<ModelInfo>> {
+        self.inner.models()
+    }
+}
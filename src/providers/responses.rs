@@ -0,0 +1,330 @@
+//! OpenAI's Responses API, for calls the chat completions endpoint can't
+//! serve: hosted tools from `builtin_tool()` (`web_search`,
+//! `code_interpreter`, `file_search`) and files from `file_upload()`.
+//! Replies are mapped back to the same content, tool calls and finish
+//! reason as any other backend.
+
+use super::{field, Capabilities, ChatRequest, ChatResponse, Provider, ToolCall, Turn};
+use crate::http_client::Service;
+use crate::interpreter::{Entries, Value};
+use anyhow::{bail, Result};
+
+const RESPONSES_URL: &str = "https://api.openai.com/v1/responses";
+const FILES_URL: &str = "https://api.openai.com/v1/files";
+
+pub struct OpenAiResponses;
+
+/// Upload a file to OpenAI for `purpose` ("user_data", "assistants", ...),
+/// returning its file id.
+pub fn upload_file(path: &std::path::Path, purpose: &str) -> Result<String> {
+    let data = std::fs::read(path)
+        .map_err(|e| anyhow::anyhow!("file_upload: cannot read '{}': {}", path.display(), e))?;
+    let filename = path.file_name().map(|n| n.to_string_lossy().replace('"', "")).unwrap_or_else(|| "upload".to_string());
+    let boundary = format!("cognos-{:x}", std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or_default());
+    let mut body = format!("--{boundary}\r\nContent-Disposition: form-data; name=\"purpose\"\r\n\r\n{purpose}\r\n\
+        --{boundary}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{filename}\"\r\n\
+        Content-Type: application/octet-stream\r\n\r\n").into_bytes();
+    body.extend_from_slice(&data);
+    body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+
+    let resp = crate::http_client::client(Service::OpenAi).post(FILES_URL)
+        .header("Authorization", format!("Bearer {}", crate::auth::require("OPENAI_API_KEY")?))
+        .header("Content-Type", format!("multipart/form-data; boundary={}", boundary))
+        .body(body)
+        .send().map_err(|e| anyhow::anyhow!("file_upload: API error: {}", e))?;
+    let status = resp.status();
+    let json: serde_json::Value = resp.json().map_err(|e| anyhow::anyhow!("file_upload: JSON parse error: {}", e))?;
+    match json["id"].as_str() {
+        Some(id) if status.is_success() => Ok(id.to_string()),
+        _ => bail!("file_upload: OpenAI API error ({}): {}", status, json["error"]),
+    }
+}
+
+/// Flow tools in the Responses form (no `function` wrapper); hosted tools
+/// as they are.
+fn tools_payload(tool_defs: &[serde_json::Value]) -> serde_json::Value {
+    serde_json::json!(tool_defs.iter().map(|t| {
+        if super::is_native_tool(t) {
+            return t.clone();
+        }
+        serde_json::json!({
+            "type": "function",
+            "name": t["function"]["name"],
+            "description": t["function"]["description"],
+            "parameters": t["function"]["parameters"],
+        })
+    }).collect::<Vec<_>>())
+}
+
+fn request_body(req: &ChatRequest, input: Vec<serde_json::Value>) -> serde_json::Value {
+    let mut body = serde_json::json!({
+        "model": req.model,
+        "input": input,
+    });
+    if !req.system.is_empty() {
+        body["instructions"] = serde_json::json!(req.system);
+    }
+    if let Some(tool_defs) = req.tools {
+        body["tools"] = tools_payload(tool_defs);
+    }
+    if let Some(temperature) = req.temperature {
+        body["temperature"] = serde_json::json!(temperature);
+    }
+    if let Some(mut format) = req.schema.and_then(super::openai::schema_format) {
+        format["type"] = serde_json::json!("json_schema");
+        body["text"] = serde_json::json!({"format": format});
+    } else if req.json {
+        body["text"] = serde_json::json!({"format": {"type": "json_object"}});
+    }
+    body
+}
+
+/// The prompt as a user message, with any files and images ahead of its text.
+fn prompt_message(req: &ChatRequest) -> Result<serde_json::Value> {
+    if req.files.is_empty() && req.images.is_empty() {
+        return Ok(serde_json::json!({"role": "user", "content": req.prompt}));
+    }
+    let mut parts: Vec<serde_json::Value> = req.files.iter()
+        .map(|id| serde_json::json!({"type": "input_file", "file_id": id}))
+        .collect();
+    for path in req.images {
+        parts.push(image_part(path)?);
+    }
+    parts.push(serde_json::json!({"type": "input_text", "text": req.prompt}));
+    Ok(serde_json::json!({"role": "user", "content": parts}))
+}
+
+/// An image file as a data-URL `input_image` part.
+fn image_part(path: &str) -> Result<serde_json::Value> {
+    let data = std::fs::read(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read image {}: {}", path, e))?;
+    let b64 = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, &data);
+    let media_type = if path.ends_with(".png") { "image/png" }
+        else if path.ends_with(".gif") { "image/gif" }
+        else if path.ends_with(".webp") { "image/webp" }
+        else { "image/jpeg" };
+    Ok(serde_json::json!({"type": "input_image", "image_url": format!("data:{};base64,{}", media_type, b64)}))
+}
+
+/// A `conversation=` message as Responses input items: an assistant's tool
+/// calls become `function_call` items and tool replies `function_call_output`.
+fn input_items(msg: &Entries) -> Vec<serde_json::Value> {
+    let role = field(msg, "role");
+    let content = field(msg, "content");
+    if role == "tool" {
+        return vec![function_call_output(msg)];
+    }
+    let mut items = Vec::new();
+    if !content.is_empty() || role != "assistant" {
+        items.push(serde_json::json!({"role": role, "content": content}));
+    }
+    if let Some(Value::List(calls)) = msg.get("tool_calls") {
+        for call in calls {
+            let Value::Map(call) = call else { continue };
+            let args = call.get("arguments").map(|v| v.to_json()).unwrap_or(serde_json::json!({}));
+            items.push(serde_json::json!({
+                "type": "function_call",
+                "call_id": field(call, "id"),
+                "name": field(call, "name"),
+                "arguments": serde_json::to_string(&args).unwrap_or_default(),
+            }));
+        }
+    }
+    items
+}
+
+fn function_call_output(result: &Entries) -> serde_json::Value {
+    let call_id = result.get("tool_call_id").or_else(|| result.get("tool_use_id"))
+        .map(|v| v.to_string()).unwrap_or_default();
+    serde_json::json!({"type": "function_call_output", "call_id": call_id, "output": field(result, "content")})
+}
+
+/// The reply text, function calls and finish reason from a response's
+/// `output` items. Hosted tool calls (`web_search_call`, ...) ran on
+/// OpenAI's side; only their effect on the text is kept.
+fn parse_response(json: &serde_json::Value, label: &'static str) -> Result<ChatResponse> {
+    if let Some(err) = json.get("error").filter(|e| !e.is_null()) {
+        bail!("OpenAI API error: {}", err);
+    }
+    let mut content = String::new();
+    let mut tool_calls = Vec::new();
+    let mut refused = false;
+    for item in json["output"].as_array().into_iter().flatten() {
+        match item["type"].as_str() {
+            Some("message") => for part in item["content"].as_array().into_iter().flatten() {
+                match part["type"].as_str() {
+                    Some("output_text") => content.push_str(part["text"].as_str().unwrap_or("")),
+                    Some("refusal") => {
+                        refused = true;
+                        content.push_str(part["refusal"].as_str().unwrap_or(""));
+                    }
+                    _ => {}
+                }
+            },
+            Some("function_call") => tool_calls.push(ToolCall {
+                id: item["call_id"].as_str().map(String::from),
+                name: item["name"].as_str().unwrap_or("").to_string(),
+                arguments: serde_json::from_str(item["arguments"].as_str().unwrap_or("{}")).unwrap_or(serde_json::json!({})),
+            }),
+            _ => {}
+        }
+    }
+    let finish_reason = match json["status"].as_str() {
+        Some("incomplete") => match json["incomplete_details"]["reason"].as_str() {
+            Some("max_output_tokens") => Some("length".to_string()),
+            other => other.map(String::from),
+        },
+        _ if refused => Some("content_filter".to_string()),
+        _ if !tool_calls.is_empty() => Some("tool_calls".to_string()),
+        Some("completed") => Some("stop".to_string()),
+        other => other.map(String::from),
+    };
+    Ok(ChatResponse { content, tool_calls, label, system: None, finish_reason, logprobs: None })
+}
+
+impl OpenAiResponses {
+    fn post(&self, body: &serde_json::Value) -> Result<serde_json::Value> {
+        log::debug!("Responses API request body: {}", serde_json::to_string(body).unwrap_or_default());
+        let resp = crate::http_client::client(Service::OpenAi).post(RESPONSES_URL)
+            .header("Authorization", format!("Bearer {}", crate::auth::require("OPENAI_API_KEY")?))
+            .json(body)
+            .send().map_err(|e| crate::retry::request_failed(format!("API error: {}", e), &e))?;
+        let status = resp.status();
+        let text = resp.text().map_err(|e| anyhow::anyhow!("Failed to read API response: {}", e))?;
+        if !status.is_success() {
+            return Err(crate::retry::StatusError { what: "OpenAI Responses API".to_string(), status: status.as_u16(), body: text }.into());
+        }
+        serde_json::from_str(&text).map_err(|e| anyhow::anyhow!("JSON parse error: {}", e))
+    }
+}
+
+impl Provider for OpenAiResponses {
+    fn name(&self) -> &'static str { "openai-responses" }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities { tools: true, vision: true, multi_turn: true, native_tools: true, files: true, ..Default::default() }
+    }
+
+    fn chat(&self, req: &ChatRequest) -> Result<ChatResponse> {
+        log::info!("Calling OpenAI Responses API: model={}, tools={}, files={}",
+            req.model, req.tools.map(|t| t.len()).unwrap_or(0), req.files.len());
+        let mut input = req.example_messages();
+        input.push(prompt_message(req)?);
+        parse_response(&self.post(&request_body(req, input))?, "openai-responses")
+    }
+
+    fn chat_turn(&self, req: &ChatRequest, conversation: &[Value], tool_results: Option<&[Value]>) -> Result<Turn> {
+        log::info!("Calling OpenAI Responses API (multi-turn): model={}, conversation_msgs={}", req.model, conversation.len());
+        let mut input = req.example_messages();
+        for msg in conversation {
+            if let Value::Map(entries) = msg {
+                input.extend(input_items(entries));
+            }
+        }
+        let results: Vec<&Entries> = tool_results.unwrap_or_default().iter()
+            .filter_map(|r| if let Value::Map(entries) = r { Some(entries) } else { None })
+            .collect();
+        input.extend(results.iter().map(|entries| function_call_output(entries)));
+        if !req.prompt.is_empty() {
+            input.push(prompt_message(req)?);
+        }
+        let response = parse_response(&self.post(&request_body(req, input))?, "openai-responses-multi-turn")?;
+
+        // Record what was sent, then the assistant reply, as chat completions does
+        let mut updated = conversation.to_vec();
+        if tool_results.is_some() {
+            for entries in results {
+                let item = function_call_output(entries);
+                updated.push(Value::Map(Entries::from([
+                    ("role".to_string(), Value::String("tool".to_string())),
+                    ("tool_call_id".to_string(), Value::from_json(item["call_id"].clone())),
+                    ("content".to_string(), Value::from_json(item["output"].clone())),
+                ])));
+            }
+        } else if !req.prompt.is_empty() {
+            updated.push(Value::Map(Entries::from([
+                ("role".to_string(), Value::String("user".to_string())),
+                ("content".to_string(), Value::String(req.prompt.to_string())),
+            ])));
+        }
+        let has_tool_calls = !response.tool_calls.is_empty();
+        let mut assistant_msg = Entries::from([
+            ("role".to_string(), Value::String("assistant".to_string())),
+            ("content".to_string(), Value::String(response.content.clone())),
+            ("has_tool_calls".to_string(), Value::Bool(has_tool_calls)),
+        ]);
+        if has_tool_calls {
+            assistant_msg.insert("tool_calls".to_string(), Value::List(response.tool_calls.iter().map(ToolCall::to_value).collect()));
+        }
+        updated.push(Value::Map(assistant_msg));
+        Ok(Turn { response, conversation: updated })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request<'a>(tools: Option<&'a [serde_json::Value]>, files: &'a [String]) -> ChatRequest<'a> {
+        ChatRequest {
+            model: "gpt-4o", system: "Be brief.", prompt: "Summarize the report", tools,
+            images: &[], files, examples: &[], temperature: None, json: false, schema: None, logprobs: false,
+        }
+    }
+
+    #[test]
+    fn test_request_flattens_flow_tools_and_attaches_files() {
+        let tools = [
+            serde_json::json!({"type": "function", "function": {"name": "notes", "description": "Read notes", "parameters": {"type": "object"}}}),
+            serde_json::json!({"type": "web_search"}),
+        ];
+        let files = ["file-abc".to_string()];
+        let req = request(Some(&tools), &files);
+        let body = request_body(&req, vec![prompt_message(&req).unwrap()]);
+        assert_eq!(body["instructions"], "Be brief.");
+        assert_eq!(body["tools"][0], serde_json::json!({"type": "function", "name": "notes", "description": "Read notes", "parameters": {"type": "object"}}));
+        assert_eq!(body["tools"][1], serde_json::json!({"type": "web_search"}));
+        assert_eq!(body["input"][0]["content"], serde_json::json!([
+            {"type": "input_file", "file_id": "file-abc"},
+            {"type": "input_text", "text": "Summarize the report"},
+        ]));
+    }
+
+    #[test]
+    fn test_response_output_maps_to_content_and_tool_calls() {
+        let json = serde_json::json!({"status": "completed", "output": [
+            {"type": "web_search_call", "id": "ws_1", "status": "completed"},
+            {"type": "message", "role": "assistant", "content": [{"type": "output_text", "text": "Rust 1.90 is out.", "annotations": []}]},
+            {"type": "function_call", "call_id": "call_1", "name": "notes", "arguments": "{\"topic\": \"rust\"}"},
+        ]});
+        let resp = parse_response(&json, "openai-responses").unwrap();
+        assert_eq!(resp.content, "Rust 1.90 is out.");
+        assert_eq!(resp.tool_calls, vec![ToolCall { id: Some("call_1".to_string()), name: "notes".to_string(), arguments: serde_json::json!({"topic": "rust"}) }]);
+        assert_eq!(resp.finish_reason.as_deref(), Some("tool_calls"));
+
+        let cut = serde_json::json!({"status": "incomplete", "incomplete_details": {"reason": "max_output_tokens"}, "output": []});
+        assert_eq!(parse_response(&cut, "openai-responses").unwrap().finish_reason.as_deref(), Some("length"));
+    }
+
+    #[test]
+    fn test_conversation_tool_calls_become_function_call_items() {
+        let call = Value::Map(Entries::from([
+            ("id".to_string(), Value::String("call_1".to_string())),
+            ("name".to_string(), Value::String("notes".to_string())),
+            ("arguments".to_string(), Value::Map(Entries::new())),
+        ]));
+        let assistant = Entries::from([
+            ("role".to_string(), Value::String("assistant".to_string())),
+            ("content".to_string(), Value::String(String::new())),
+            ("tool_calls".to_string(), Value::List(vec![call])),
+        ]);
+        assert_eq!(input_items(&assistant), vec![serde_json::json!({"type": "function_call", "call_id": "call_1", "name": "notes", "arguments": "{}"})]);
+        let tool = Entries::from([
+            ("role".to_string(), Value::String("tool".to_string())),
+            ("tool_call_id".to_string(), Value::String("call_1".to_string())),
+            ("content".to_string(), Value::String("no notes".to_string())),
+        ]);
+        assert_eq!(input_items(&tool), vec![serde_json::json!({"type": "function_call_output", "call_id": "call_1", "output": "no notes"})]);
+    }
+}
@@ -0,0 +1,100 @@
+//! Token counting for budgets and chunking. Local GGUF models are counted
+//! exactly with their own vocabulary; hosted models get an estimate shaped
+//! after their family's tokenizer, since the real ones aren't shipped here.
+
+/// Tokenizer families that count differently enough to matter.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Family {
+    /// tiktoken (cl100k/o200k): long merges, digits in groups of three.
+    OpenAi,
+    /// Claude's tokenizer: shorter pieces than tiktoken on English text.
+    Claude,
+    /// Llama/Qwen style vocabularies served by Ollama: digits split one by one.
+    Local,
+}
+
+fn family(model: &str) -> Family {
+    if model.starts_with("claude") {
+        Family::Claude
+    } else if ["gpt-", "o1-", "o3-", "deepseek", "MiniMax", "minimax"].iter().any(|p| model.starts_with(p)) {
+        Family::OpenAi
+    } else {
+        Family::Local
+    }
+}
+
+/// Tokens `text` takes up for `model`.
+pub fn count_tokens(model: &str, text: &str) -> usize {
+    if let Some(path) = model.strip_prefix("file:") {
+        if let Some(n) = super::gguf::count_tokens(path, text) {
+            return n;
+        }
+    }
+    estimate(family(model), text)
+}
+
+/// Walk the text in the runs a BPE pre-tokenizer would produce: letter runs
+/// (with their leading space), digit runs, symbols, whitespace.
+fn estimate(family: Family, text: &str) -> usize {
+    let chars_per_token = match family {
+        Family::OpenAi => 4.5,
+        Family::Claude => 3.8,
+        Family::Local => 4.0,
+    };
+    let mut tokens = 0;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if is_ideograph(c) {
+            tokens += 1;
+        } else if c.is_alphabetic() {
+            let mut len = 1;
+            while chars.next_if(|c| c.is_alphabetic() && !is_ideograph(*c)).is_some() {
+                len += 1;
+            }
+            tokens += ((len as f64 / chars_per_token).round() as usize).max(1);
+        } else if c.is_ascii_digit() {
+            let mut len: usize = 1;
+            while chars.next_if(|c| c.is_ascii_digit()).is_some() {
+                len += 1;
+            }
+            tokens += match family {
+                Family::Local => len,
+                _ => len.div_ceil(3),
+            };
+        } else if c.is_whitespace() {
+            // A single space joins the next word; newlines and indentation cost one
+            let mut run = std::string::String::from(c);
+            while let Some(w) = chars.next_if(|c| c.is_whitespace()) {
+                run.push(w);
+            }
+            if run != " " {
+                tokens += 1;
+            }
+        } else {
+            tokens += 1;
+        }
+    }
+    tokens
+}
+
+/// CJK and similar scripts, which tokenizers split about one char per token.
+fn is_ideograph(c: char) -> bool {
+    c as u32 >= 0x2E80 && c.is_alphabetic()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_follows_word_and_digit_runs() {
+        assert_eq!(count_tokens("gpt-4o", ""), 0);
+        assert_eq!(count_tokens("gpt-4o", "Hello world"), 2);
+        assert_eq!(count_tokens("gpt-4o", "Hello, world!\n"), 5);
+        // tiktoken groups digits in threes; Llama-style vocabularies don't
+        assert_eq!(count_tokens("gpt-4o", "1234567"), 3);
+        assert_eq!(count_tokens("qwen2.5:7b", "1234567"), 7);
+        assert_eq!(count_tokens("claude-sonnet-4-20250514", "你好世界"), 4);
+        assert!(count_tokens("claude-sonnet-4-20250514", "internationalization") > count_tokens("gpt-4o", "internationalization"));
+    }
+}
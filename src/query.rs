@@ -0,0 +1,367 @@
+//! `query(value, "$.items[*].name")` — a small JSONPath subset over Values.
+//!
+//! Supported: `$` root, `.key` and `['key']` children, `*` wildcards, `[n]`
+//! indexes (negative from the end), `[start:end]` slices, `..key` recursive
+//! descent and `[?(@.key op literal)]` filters. Matches come back in document
+//! order; a path that matches nothing gives an empty list, not an error.
+
+use crate::interpreter::Value;
+use anyhow::{bail, Result};
+use std::cmp::Ordering;
+
+enum Selector {
+    Key(String),
+    Wildcard,
+    Index(i64),
+    Slice(Option<i64>, Option<i64>),
+    Filter(Filter),
+}
+
+struct Step {
+    /// `..`: apply the selector to the value and everything below it
+    recursive: bool,
+    selector: Selector,
+}
+
+/// `?(@.a.b)` keeps items that have the key; `?(@.a.b == "x")` also compares.
+struct Filter {
+    path: Vec<String>,
+    test: Option<(Op, Value)>,
+}
+
+#[derive(Clone, Copy)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// Every value in `value` that `path` selects.
+pub fn query(value: &Value, path: &str) -> Result<Vec<Value>> {
+    let steps = Parser::new(path).parse()?;
+    let mut current = vec![value];
+    for step in &steps {
+        let mut next = Vec::new();
+        for v in current {
+            if step.recursive {
+                let mut all = Vec::new();
+                descendants(v, &mut all);
+                for d in all {
+                    select(d, &step.selector, &mut next);
+                }
+            } else {
+                select(v, &step.selector, &mut next);
+            }
+        }
+        current = next;
+    }
+    Ok(current.into_iter().cloned().collect())
+}
+
+fn select<'a>(value: &'a Value, selector: &Selector, out: &mut Vec<&'a Value>) {
+    match (selector, value) {
+        (Selector::Key(key), Value::Map(_)) => out.extend(value.get_field(key)),
+        (Selector::Wildcard, Value::Map(entries)) => out.extend(entries.iter().map(|(_, v)| v)),
+        (Selector::Wildcard, Value::List(items)) => out.extend(items),
+        (Selector::Index(i), Value::List(items)) => {
+            let i = if *i < 0 { items.len() as i64 + i } else { *i };
+            out.extend(usize::try_from(i).ok().and_then(|i| items.get(i)));
+        }
+        (Selector::Slice(start, end), Value::List(items)) => {
+            let start = start.map_or(0, |s| clamp(s, items.len()));
+            let end = end.map_or(items.len(), |e| clamp(e, items.len()));
+            if start < end {
+                out.extend(&items[start..end]);
+            }
+        }
+        (Selector::Filter(filter), Value::List(items)) => out.extend(items.iter().filter(|v| filter.matches(v))),
+        (Selector::Filter(filter), Value::Map(entries)) => {
+            out.extend(entries.iter().map(|(_, v)| v).filter(|v| filter.matches(v)))
+        }
+        _ => {}
+    }
+}
+
+/// `value` and everything nested in it, parents before children.
+fn descendants<'a>(value: &'a Value, out: &mut Vec<&'a Value>) {
+    out.push(value);
+    match value {
+        Value::List(items) => items.iter().for_each(|v| descendants(v, out)),
+        Value::Map(entries) => entries.iter().for_each(|(_, v)| descendants(v, out)),
+        _ => {}
+    }
+}
+
+/// A slice bound, negative from the end, clamped to the list.
+fn clamp(i: i64, len: usize) -> usize {
+    let i = if i < 0 { len as i64 + i } else { i };
+    i.clamp(0, len as i64) as usize
+}
+
+impl Filter {
+    fn matches(&self, item: &Value) -> bool {
+        let Some(found) = self.path.iter().try_fold(item, |v, key| v.get_field(key)) else {
+            return false;
+        };
+        let Some((op, literal)) = &self.test else { return true };
+        match compare(found, literal) {
+            Some(ord) => match op {
+                Op::Eq => ord.is_eq(),
+                Op::Ne => ord.is_ne(),
+                Op::Lt => ord.is_lt(),
+                Op::Le => ord.is_le(),
+                Op::Gt => ord.is_gt(),
+                Op::Ge => ord.is_ge(),
+            },
+            // Values of different types are only ever unequal
+            None => matches!(op, Op::Ne),
+        }
+    }
+}
+
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Int(a), Value::Int(b)) => Some(a.cmp(b)),
+        (Value::Int(a), Value::Float(b)) => (*a as f64).partial_cmp(b),
+        (Value::Float(a), Value::Int(b)) => a.partial_cmp(&(*b as f64)),
+        (Value::Float(a), Value::Float(b)) => a.partial_cmp(b),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+        (Value::None, Value::None) => Some(Ordering::Equal),
+        _ => None,
+    }
+}
+
+struct Parser<'a> {
+    path: &'a str,
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(path: &'a str) -> Self {
+        Parser { path, chars: path.trim().chars().collect(), pos: 0 }
+    }
+
+    fn parse(mut self) -> Result<Vec<Step>> {
+        if !self.eat('$') {
+            bail!("query path must start with '$', e.g. \"$.items[*].name\" (got \"{}\")", self.path);
+        }
+        let mut steps = Vec::new();
+        while self.pos < self.chars.len() {
+            if self.eat('.') {
+                let recursive = self.eat('.');
+                let selector = if self.eat('*') {
+                    Selector::Wildcard
+                } else if recursive && self.peek() == Some('[') {
+                    self.bracket()?
+                } else {
+                    Selector::Key(self.name()?)
+                };
+                steps.push(Step { recursive, selector });
+            } else if self.peek() == Some('[') {
+                steps.push(Step { recursive: false, selector: self.bracket()? });
+            } else {
+                return self.fail("expected '.' or '['");
+            }
+        }
+        Ok(steps)
+    }
+
+    /// `[*]`, `['key']`, `[n]`, `[start:end]` or `[?(...)]`.
+    fn bracket(&mut self) -> Result<Selector> {
+        self.expect('[')?;
+        self.skip_ws();
+        let selector = if self.eat('*') {
+            Selector::Wildcard
+        } else if matches!(self.peek(), Some('\'' | '"')) {
+            Selector::Key(self.quoted()?)
+        } else if self.eat('?') {
+            self.expect('(')?;
+            let filter = self.filter()?;
+            self.expect(')')?;
+            Selector::Filter(filter)
+        } else {
+            let start = self.int()?;
+            self.skip_ws();
+            if self.eat(':') {
+                self.skip_ws();
+                Selector::Slice(start, self.int()?)
+            } else {
+                match start {
+                    Some(i) => Selector::Index(i),
+                    None => return self.fail("expected an index, '*', a quoted key or a filter"),
+                }
+            }
+        };
+        self.skip_ws();
+        self.expect(']')?;
+        Ok(selector)
+    }
+
+    fn filter(&mut self) -> Result<Filter> {
+        self.skip_ws();
+        self.expect('@')?;
+        let mut path = Vec::new();
+        while self.eat('.') {
+            path.push(self.name()?);
+        }
+        self.skip_ws();
+        let op = [("==", Op::Eq), ("!=", Op::Ne), ("<=", Op::Le), (">=", Op::Ge), ("<", Op::Lt), (">", Op::Gt)]
+            .into_iter()
+            .find(|(text, _)| self.chars[self.pos..].starts_with(&text.chars().collect::<Vec<_>>()));
+        let Some((text, op)) = op else {
+            return Ok(Filter { path, test: None });
+        };
+        self.pos += text.len();
+        self.skip_ws();
+        let literal = self.literal()?;
+        self.skip_ws();
+        Ok(Filter { path, test: Some((op, literal)) })
+    }
+
+    /// A quoted string, number, `true`, `false` or `null`.
+    fn literal(&mut self) -> Result<Value> {
+        if matches!(self.peek(), Some('\'' | '"')) {
+            return Ok(Value::String(self.quoted()?));
+        }
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_alphanumeric() || matches!(c, '-' | '.' | '_')) {
+            self.pos += 1;
+        }
+        let word: String = self.chars[start..self.pos].iter().collect();
+        match word.as_str() {
+            "true" => Ok(Value::Bool(true)),
+            "false" => Ok(Value::Bool(false)),
+            "null" | "none" => Ok(Value::None),
+            _ => match (word.parse::<i64>(), word.parse::<f64>()) {
+                (Ok(n), _) => Ok(Value::Int(n)),
+                (_, Ok(f)) => Ok(Value::Float(f)),
+                _ => {
+                    self.pos = start;
+                    self.fail("expected a string, number, true, false or null")
+                }
+            },
+        }
+    }
+
+    fn name(&mut self) -> Result<String> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_alphanumeric() || matches!(c, '_' | '-')) {
+            self.pos += 1;
+        }
+        if self.pos == start {
+            return self.fail("expected a key name");
+        }
+        Ok(self.chars[start..self.pos].iter().collect())
+    }
+
+    fn quoted(&mut self) -> Result<String> {
+        let quote = self.chars[self.pos];
+        self.pos += 1;
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c != quote) {
+            self.pos += 1;
+        }
+        if self.peek().is_none() {
+            return self.fail("unterminated quoted key");
+        }
+        let text = self.chars[start..self.pos].iter().collect();
+        self.pos += 1;
+        Ok(text)
+    }
+
+    fn int(&mut self) -> Result<Option<i64>> {
+        let start = self.pos;
+        self.eat('-');
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        if self.pos == start {
+            return Ok(None);
+        }
+        let text: String = self.chars[start..self.pos].iter().collect();
+        match text.parse() {
+            Ok(n) => Ok(Some(n)),
+            Err(_) => {
+                self.pos = start;
+                self.fail("expected an integer")
+            }
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<()> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            self.fail(&format!("expected '{}'", c))
+        }
+    }
+
+    fn skip_ws(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn fail<T>(&self, message: &str) -> Result<T> {
+        bail!("invalid query path \"{}\" at position {}: {}", self.path, self.pos, message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(value: &Value, path: &str) -> Vec<String> {
+        query(value, path).unwrap().iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn test_query_selectors() {
+        let doc = Value::from_json(serde_json::json!({
+            "items": [
+                {"name": "a", "price": 5, "tags": ["x"]},
+                {"name": "b", "price": 12.5},
+                {"name": "c", "price": 20, "meta": {"name": "inner"}},
+            ],
+            "owner": {"name": "ada"},
+        }));
+        assert_eq!(names(&doc, "$.items[*].name"), ["a", "b", "c"]);
+        assert_eq!(names(&doc, "$.items[-1].name"), ["c"]);
+        assert_eq!(names(&doc, "$.items[0:2].name"), ["a", "b"]);
+        assert_eq!(names(&doc, "$['owner']['name']"), ["ada"]);
+        assert_eq!(names(&doc, "$..name"), ["a", "b", "c", "inner", "ada"]);
+        assert_eq!(names(&doc, "$.items[?(@.price > 10)].name"), ["b", "c"]);
+        assert_eq!(names(&doc, "$.items[?(@.name == 'a')].price"), ["5"]);
+        assert_eq!(names(&doc, "$.items[?(@.tags)].name"), ["a"]);
+        assert_eq!(names(&doc, "$.items[?(@.name != 1)].name"), ["a", "b", "c"]);
+        assert!(names(&doc, "$.missing[*].name").is_empty());
+        assert_eq!(names(&doc, "$").len(), 1);
+    }
+
+    #[test]
+    fn test_query_rejects_bad_paths() {
+        let doc = Value::None;
+        assert!(query(&doc, "items").unwrap_err().to_string().contains("must start with '$'"));
+        assert!(query(&doc, "$.items[").unwrap_err().to_string().contains("position 8"));
+        assert!(query(&doc, "$.items[?(@.a == )]").is_err());
+        assert!(query(&doc, "$['open").is_err());
+    }
+}
@@ -1,4 +1,4 @@
-/// Interactive REPL for Cognos.
+//! Interactive REPL for Cognos.
 
 use std::io::{self, BufRead, Write};
 use crate::interpreter::Interpreter;
@@ -9,10 +9,10 @@ use anyhow::Result;
 
 pub fn run_repl() -> Result<()> {
     eprintln!("Cognos REPL v0.1.0");
-    eprintln!("Type expressions or statements. Use 'exit' or Ctrl-D to quit.\n");
+    eprintln!("Type expressions or statements, help() to list built-ins. Use 'exit' or Ctrl-D to quit.\n");
 
     let mut interp = Interpreter::new();
-    let empty = Program { imports: vec![], types: vec![], flows: vec![] };
+    let empty = Program { imports: vec![], types: vec![], flows: vec![], setup: vec![] };
     let _ = interp.run(&empty);
 
     let stdin = io::stdin();
@@ -105,6 +105,10 @@ fn eval_repl_input(interp: &mut Interpreter, input: &str) {
         eprintln!("Error: incomplete loop — usage: loop max=N: ...");
         return;
     }
+    if trimmed == "while" || (trimmed.starts_with("while ") && !trimmed.ends_with(':')) {
+        eprintln!("Error: incomplete while loop — usage: while condition: ...");
+        return;
+    }
     if trimmed == "for" || (trimmed.starts_with("for ") && !trimmed.contains(" in ")) {
         eprintln!("Error: incomplete for loop — usage: for item in collection: ...");
         return;
@@ -114,7 +118,7 @@ fn eval_repl_input(interp: &mut Interpreter, input: &str) {
         return;
     }
     // Bare function names
-    let bare_fns = ["emit", "think", "act", "run", "log", "remember", "recall"];
+    let bare_fns = ["emit", "think", "classify", "judge", "extract", "summarize", "act", "run", "log", "remember", "recall"];
     let bare = trimmed.trim_end_matches("()");
     if bare_fns.contains(&bare) && (trimmed == bare || trimmed == format!("{}()", bare)) {
         eprintln!("Error: '{}' needs arguments — did you mean {}(...)?", bare, bare);
@@ -127,6 +131,7 @@ fn eval_repl_input(interp: &mut Interpreter, input: &str) {
         || trimmed.starts_with("loop ")
         || (trimmed.starts_with("for ") && trimmed.ends_with(':'))
         || trimmed.starts_with("log(")
+        || trimmed.starts_with("help(")
         || trimmed.starts_with("pass")
         || trimmed.starts_with("break")
         || trimmed.starts_with("continue")
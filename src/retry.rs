@@ -0,0 +1,171 @@
+//! Retry policy for LLM and HTTP calls.
+//! A call that fails for a reason worth trying again (rate limit, 5xx, a
+//! timeout or dropped connection) is repeated up to `retries` more times,
+//! waiting longer between attempts as `backoff` says.
+
+use anyhow::{bail, Result};
+use std::time::Duration;
+
+const BASE_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Backoff {
+    /// The same wait before every retry
+    Constant,
+    /// 1s, 2s, 3s, ...
+    Linear,
+    /// 1s, 2s, 4s, ...
+    #[default]
+    Exponential,
+}
+
+impl Backoff {
+    pub fn parse(name: &str) -> Result<Backoff> {
+        match name {
+            "constant" => Ok(Backoff::Constant),
+            "linear" => Ok(Backoff::Linear),
+            "exponential" => Ok(Backoff::Exponential),
+            other => bail!("backoff must be \"constant\", \"linear\" or \"exponential\", got \"{}\"", other),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Policy {
+    /// Attempts after the first; 0 never retries.
+    pub retries: u32,
+    pub backoff: Backoff,
+}
+
+impl Policy {
+    /// How long to wait after failed attempt `attempt` (1-based).
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = match self.backoff {
+            Backoff::Constant => 1,
+            Backoff::Linear => attempt,
+            Backoff::Exponential => 1u32.checked_shl(attempt.saturating_sub(1)).unwrap_or(u32::MAX),
+        };
+        BASE_DELAY.saturating_mul(factor).min(MAX_DELAY)
+    }
+
+    /// Whether a call that failed on attempt `attempt` with `err` gets another.
+    pub fn should_retry(&self, attempt: u32, err: &anyhow::Error) -> bool {
+        attempt <= self.retries && is_transient(err)
+    }
+}
+
+/// A request that got no response. The message is the caller's; whether
+/// it is worth retrying comes from the reqwest error behind it.
+#[derive(Debug)]
+pub struct RequestFailed {
+    pub message: String,
+    /// Timed out, or couldn't connect
+    pub transient: bool,
+}
+
+/// `err`, reported with `message` as a `RequestFailed`.
+pub fn request_failed(message: String, err: &reqwest::Error) -> anyhow::Error {
+    anyhow::Error::new(RequestFailed { message, transient: err.is_timeout() || err.is_connect() })
+}
+
+impl std::fmt::Display for RequestFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for RequestFailed {}
+
+/// An HTTP response with an error status, kept with its body.
+#[derive(Debug)]
+pub struct StatusError {
+    /// Who answered it, e.g. "HTTP GET" or "Anthropic API"
+    pub what: String,
+    pub status: u16,
+    pub body: String,
+}
+
+impl std::fmt::Display for StatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let reason = reqwest::StatusCode::from_u16(self.status).ok().and_then(|s| s.canonical_reason()).unwrap_or("<unknown status code>");
+        write!(f, "{} error ({} {}): {}", self.what, self.status, reason, &self.body[..self.body.floor_char_boundary(500)])
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+/// Whether `err` is the kind of failure that may pass on its own: HTTP 429
+/// or 5xx, a timeout, or a connection that couldn't be made or was dropped.
+pub fn is_transient(err: &anyhow::Error) -> bool {
+    let failed_request = err.chain().any(|cause| {
+        cause.downcast_ref::<RequestFailed>().is_some_and(|e| e.transient)
+            || cause.downcast_ref::<reqwest::Error>().is_some_and(|e| e.is_timeout() || e.is_connect())
+            || cause.downcast_ref::<std::io::Error>().is_some_and(transient_io)
+    });
+    failed_request || status(err).is_some_and(transient_status)
+}
+
+/// The HTTP error status `err` reports, if any: a `StatusError` from a
+/// provider or `http.*`, or a reqwest error.
+pub fn status(err: &anyhow::Error) -> Option<u16> {
+    err.chain().find_map(|cause| match cause.downcast_ref::<StatusError>() {
+        Some(e) => Some(e.status),
+        None => cause.downcast_ref::<reqwest::Error>().and_then(|e| e.status()).map(|s| s.as_u16()),
+    })
+}
+
+/// A connection that timed out, couldn't be made or was dropped.
+pub fn transient_io(err: &std::io::Error) -> bool {
+    use std::io::ErrorKind::*;
+    matches!(err.kind(), TimedOut | ConnectionRefused | ConnectionReset | ConnectionAborted)
+}
+
+fn transient_status(status: u16) -> bool {
+    status == 429 || (500..600).contains(&status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_by_backoff() {
+        let policy = |backoff| Policy { retries: 10, backoff };
+        let secs = |p: Policy| (1..=4).map(|a| p.delay(a).as_secs()).collect::<Vec<_>>();
+        assert_eq!(secs(policy(Backoff::Constant)), [1, 1, 1, 1]);
+        assert_eq!(secs(policy(Backoff::Linear)), [1, 2, 3, 4]);
+        assert_eq!(secs(policy(Backoff::Exponential)), [1, 2, 4, 8]);
+        assert_eq!(policy(Backoff::Exponential).delay(40), MAX_DELAY);
+    }
+
+    #[test]
+    fn test_transient_errors() {
+        let status = |status| anyhow::Error::new(StatusError { what: "HTTP GET".to_string(), status, body: "busy".to_string() });
+        assert!(is_transient(&status(529)));
+        assert!(is_transient(&status(429)));
+        assert!(is_transient(&status(503)));
+        assert!(!is_transient(&status(400)));
+        assert!(!is_transient(&status(404)));
+        assert_eq!(status(503).to_string(), "HTTP GET error (503 Service Unavailable): busy");
+        // Only what failed decides, not words in the message
+        let transient = |m: &str| is_transient(&anyhow::anyhow!("{}", m));
+        assert!(!transient("OpenAI-compat API error (503 Service Unavailable): "));
+        assert!(!transient("No Anthropic token found. Set ANTHROPIC_API_KEY"));
+        assert!(!transient("tool error: config key 'timeout' is not set"));
+        let failed = |transient| anyhow::Error::new(RequestFailed { message: "HTTP GET error: refused".to_string(), transient });
+        assert!(is_transient(&failed(true)));
+        assert!(!is_transient(&failed(false)));
+        assert!(is_transient(&anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::ConnectionReset)).context("reading reply")));
+    }
+
+    #[test]
+    fn test_should_retry_counts_attempts() {
+        let policy = Policy { retries: 2, backoff: Backoff::Constant };
+        let err = anyhow::Error::new(StatusError { what: "API".to_string(), status: 500, body: String::new() });
+        assert!(policy.should_retry(1, &err));
+        assert!(policy.should_retry(2, &err));
+        assert!(!policy.should_retry(3, &err));
+        assert!(!Policy::default().should_retry(1, &err));
+    }
+}
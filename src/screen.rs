@@ -0,0 +1,99 @@
+//! Screen capture for `screenshot()` and `screen_size()`, through the tool
+//! each OS has: `screencapture` on macOS, PowerShell on Windows, and on
+//! Linux the first of `grim`, `gnome-screenshot`, `scrot` or ImageMagick's
+//! `import` found on PATH.
+
+use anyhow::{bail, Result};
+use std::path::Path;
+use std::process::Command;
+
+/// Save the main screen as a PNG at `path`.
+pub fn capture(path: &Path) -> Result<()> {
+    let mut command = capture_command(path)?;
+    let program = command.get_program().to_string_lossy().to_string();
+    let output = command.output()
+        .map_err(|e| anyhow::anyhow!("screenshot: cannot run '{}': {}", program, e))?;
+    if !output.status.success() || !path.is_file() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("screenshot: '{}' failed: {}", program, stderr.trim());
+    }
+    Ok(())
+}
+
+/// Width and height of the main screen, in pixels, from a capture.
+pub fn size() -> Result<(u32, u32)> {
+    let path = std::env::temp_dir().join(format!("cognos-screen-{}.png", std::process::id()));
+    capture(&path)?;
+    let bytes = std::fs::read(&path);
+    let _ = std::fs::remove_file(&path);
+    png_size(&bytes?).ok_or_else(|| anyhow::anyhow!("screen_size: the capture is not a PNG"))
+}
+
+/// Width and height from a PNG's header.
+pub fn png_size(bytes: &[u8]) -> Option<(u32, u32)> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    if !bytes.starts_with(SIGNATURE) || bytes.get(12..16)? != b"IHDR" {
+        return None;
+    }
+    let width = u32::from_be_bytes(bytes.get(16..20)?.try_into().ok()?);
+    let height = u32::from_be_bytes(bytes.get(20..24)?.try_into().ok()?);
+    Some((width, height))
+}
+
+#[cfg(target_os = "macos")]
+fn capture_command(path: &Path) -> Result<Command> {
+    let mut command = Command::new("screencapture");
+    command.args(["-x", "-t", "png"]).arg(path);
+    Ok(command)
+}
+
+#[cfg(windows)]
+fn capture_command(path: &Path) -> Result<Command> {
+    let script = format!(
+        "Add-Type -AssemblyName System.Windows.Forms,System.Drawing; \
+         $b = [System.Windows.Forms.Screen]::PrimaryScreen.Bounds; \
+         $bmp = New-Object System.Drawing.Bitmap $b.Width, $b.Height; \
+         [System.Drawing.Graphics]::FromImage($bmp).CopyFromScreen($b.Location, [System.Drawing.Point]::Empty, $b.Size); \
+         $bmp.Save('{}', [System.Drawing.Imaging.ImageFormat]::Png)",
+        path.display().to_string().replace('\'', "''"));
+    let mut command = Command::new("powershell");
+    command.args(["-NoProfile", "-NonInteractive", "-Command", &script]);
+    Ok(command)
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn capture_command(path: &Path) -> Result<Command> {
+    // Wayland first: the X11 tools capture nothing useful there
+    let tools: [(&str, &[&str]); 4] = [
+        ("grim", &[]),
+        ("gnome-screenshot", &["-f"]),
+        ("scrot", &["--overwrite"]),
+        ("import", &["-window", "root"]),
+    ];
+    let Some((program, args)) = tools.into_iter().find(|(program, _)| crate::environment::on_path(program)) else {
+        bail!("screenshot: no capture tool found; install grim (Wayland), scrot or ImageMagick (X11)");
+    };
+    let mut command = Command::new(program);
+    command.args(args).arg(path);
+    Ok(command)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn capture_command(_path: &Path) -> Result<Command> {
+    bail!("screenshot: not supported on this OS")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_png_size_reads_the_header() {
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        png.extend_from_slice(&1920u32.to_be_bytes());
+        png.extend_from_slice(&1080u32.to_be_bytes());
+        assert_eq!(png_size(&png), Some((1920, 1080)));
+        assert_eq!(png_size(b"GIF89a"), None);
+        assert_eq!(png_size(&png[..18]), None);
+    }
+}
@@ -0,0 +1,284 @@
+//! `cognos session` — inspect and repair `--session` files.
+//!
+//! `show` lists the variables with their types, `set`/`unset` edit them in
+//! place, and `diff` compares two files by variable name, showing changed
+//! values as a line diff of their pretty-printed JSON.
+
+use crate::diagnostics::{paint, Stream, Style};
+use anyhow::{bail, Result};
+
+/// Unchanged lines kept around each change in a value diff.
+const CONTEXT: usize = 3;
+
+const USAGE: &str = "usage: cognos session show <file>\n       \
+    cognos session set <file> <name>=<json>...\n       \
+    cognos session unset <file> <name>...\n       \
+    cognos session diff <a.json> <b.json>";
+
+pub fn run(args: &[String]) -> Result<()> {
+    match args {
+        [cmd, path] if cmd == "show" => {
+            print!("{}", show(&read(path)?));
+            Ok(())
+        }
+        [cmd, path, assignments @ ..] if cmd == "set" && !assignments.is_empty() => {
+            let mut vars = read(path)?;
+            for assignment in assignments {
+                let (name, value) = parse_assignment(assignment)?;
+                eprintln!("{} {}: {}", if vars.contains_key(&name) { "Changed" } else { "Added" }, name, type_of(&value));
+                vars.insert(name, value);
+            }
+            write(path, &vars)
+        }
+        [cmd, path, names @ ..] if cmd == "unset" && !names.is_empty() => {
+            let mut vars = read(path)?;
+            for name in names {
+                if vars.remove(name).is_none() {
+                    bail!("{} has no variable '{}'", path, name);
+                }
+                eprintln!("Removed {}", name);
+            }
+            write(path, &vars)
+        }
+        [cmd, a, b] if cmd == "diff" => {
+            let (old, new) = (read(a)?, read(b)?);
+            print!("{}", diff(&old, &new));
+            Ok(())
+        }
+        _ => bail!("{}", USAGE),
+    }
+}
+
+fn read(path: &str) -> Result<serde_json::Map<String, serde_json::Value>> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("cannot read session '{}': {}", path, e))?;
+    match serde_json::from_str(&content) {
+        Ok(serde_json::Value::Object(vars)) => Ok(vars),
+        Ok(_) => bail!("{} is not a session file: expected a JSON object of variables", path),
+        Err(e) => bail!("{} is not a session file: {}", path, e),
+    }
+}
+
+/// Replace the file in one step, so a failed write can't leave half a session.
+fn write(path: &str, vars: &serde_json::Map<String, serde_json::Value>) -> Result<()> {
+    let tmp = format!("{}.tmp", path);
+    std::fs::write(&tmp, serde_json::to_string_pretty(vars)?)
+        .and_then(|_| std::fs::rename(&tmp, path))
+        .map_err(|e| anyhow::anyhow!("cannot write session '{}': {}", path, e))
+}
+
+/// `name=<json>`, with a name a flow could use.
+fn parse_assignment(assignment: &str) -> Result<(String, serde_json::Value)> {
+    let Some((name, json)) = assignment.split_once('=') else {
+        bail!("expected <name>=<json>, got '{}'", assignment);
+    };
+    let valid = name.chars().next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_alphanumeric() || c == '_');
+    if !valid || matches!(name, "stdin" | "stdout" | "http" | "grpc") {
+        bail!("'{}' is not a variable name a session can hold", name);
+    }
+    let value = serde_json::from_str(json).map_err(|e| anyhow::anyhow!(
+        "value for '{}' is not JSON ({}); quote strings, e.g. {}='\"text\"'", name, e, name))?;
+    Ok((name.to_string(), value))
+}
+
+/// The Cognos type a JSON value loads as.
+fn type_of(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Null => "None".into(),
+        serde_json::Value::Bool(_) => "Bool".into(),
+        serde_json::Value::Number(n) if n.is_i64() => "Int".into(),
+        serde_json::Value::Number(_) => "Float".into(),
+        serde_json::Value::String(_) => "String".into(),
+        serde_json::Value::Array(items) => format!("List ({} item{})", items.len(), if items.len() == 1 { "" } else { "s" }),
+        serde_json::Value::Object(entries) => format!("Map ({} key{})", entries.len(), if entries.len() == 1 { "" } else { "s" }),
+    }
+}
+
+/// One `name: Type = value` line per variable; lists and maps follow their
+/// line as indented JSON.
+fn show(vars: &serde_json::Map<String, serde_json::Value>) -> String {
+    if vars.is_empty() {
+        return "No variables\n".to_string();
+    }
+    let mut out = String::new();
+    for (name, value) in vars {
+        let typed = format!("{}: {}", name, paint(Stream::Stdout, Style::Hint, &type_of(value)));
+        match value {
+            serde_json::Value::Array(_) | serde_json::Value::Object(_) => {
+                out.push_str(&format!("{} =\n", typed));
+                for line in serde_json::to_string_pretty(value).unwrap_or_default().lines() {
+                    out.push_str(&format!("    {}\n", line));
+                }
+            }
+            _ => out.push_str(&format!("{} = {}\n", typed, value)),
+        }
+    }
+    out
+}
+
+/// Added (`+`), removed (`-`) and changed (`~`) variables, then a count.
+fn diff(old: &serde_json::Map<String, serde_json::Value>, new: &serde_json::Map<String, serde_json::Value>) -> String {
+    let mut out = String::new();
+    let (mut added, mut removed, mut changed) = (0, 0, 0);
+    let mut names: Vec<&String> = old.keys().chain(new.keys()).collect();
+    names.sort();
+    names.dedup();
+    for name in names {
+        match (old.get(name), new.get(name)) {
+            (None, Some(value)) => {
+                added += 1;
+                out.push_str(&paint(Stream::Stdout, Style::Success, &format!("+ {} = {}", name, value)));
+                out.push('\n');
+            }
+            (Some(value), None) => {
+                removed += 1;
+                out.push_str(&paint(Stream::Stdout, Style::Error, &format!("- {} = {}", name, value)));
+                out.push('\n');
+            }
+            (Some(a), Some(b)) if a != b => {
+                changed += 1;
+                out.push_str(&paint(Stream::Stdout, Style::Warning, &format!("~ {}", name)));
+                out.push('\n');
+                out.push_str(&value_diff(a, b));
+            }
+            _ => {}
+        }
+    }
+    if added + removed + changed == 0 {
+        out.push_str("No differences\n");
+    } else {
+        out.push_str(&format!("{} added, {} removed, {} changed\n", added, removed, changed));
+    }
+    out
+}
+
+/// Line diff of two values' pretty JSON, indented under the variable name.
+fn value_diff(a: &serde_json::Value, b: &serde_json::Value) -> String {
+    let old = serde_json::to_string_pretty(a).unwrap_or_default();
+    let new = serde_json::to_string_pretty(b).unwrap_or_default();
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    let ops = line_ops(&old, &new);
+
+    // Keep changes and the context lines around them; elide the rest
+    let near_change = |i: usize| {
+        let lo = i.saturating_sub(CONTEXT);
+        let hi = (i + CONTEXT + 1).min(ops.len());
+        ops[lo..hi].iter().any(|(op, _)| *op != ' ')
+    };
+    let mut out = String::new();
+    let mut skipped = false;
+    for (i, (op, line)) in ops.iter().enumerate() {
+        if *op == ' ' && !near_change(i) {
+            if !skipped {
+                out.push_str(&paint(Stream::Stdout, Style::Dim, "    ..."));
+                out.push('\n');
+                skipped = true;
+            }
+            continue;
+        }
+        skipped = false;
+        let text = format!("  {} {}", op, line);
+        out.push_str(&match op {
+            '+' => paint(Stream::Stdout, Style::Success, &text),
+            '-' => paint(Stream::Stdout, Style::Error, &text),
+            _ => text,
+        });
+        out.push('\n');
+    }
+    out
+}
+
+/// Edit script from `old` to `new` as (' ' | '-' | '+', line), from a
+/// longest-common-subsequence table. Values too big for the table are
+/// shown as all removed, then all added.
+fn line_ops<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<(char, &'a str)> {
+    if old.len().saturating_mul(new.len()) > 4_000_000 {
+        return old.iter().map(|l| ('-', *l)).chain(new.iter().map(|l| ('+', *l))).collect();
+    }
+    // lcs[i][j]: common lines between old[i..] and new[j..]
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] { lcs[i + 1][j + 1] + 1 } else { lcs[i + 1][j].max(lcs[i][j + 1]) };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut ops = Vec::new();
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            ops.push((' ', old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            // Removals first, so a replaced line reads as - then +
+            ops.push(('-', old[i]));
+            i += 1;
+        } else {
+            ops.push(('+', new[j]));
+            j += 1;
+        }
+    }
+    ops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(json: serde_json::Value) -> serde_json::Map<String, serde_json::Value> {
+        json.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn test_diff_reports_added_removed_changed() {
+        let old = vars(serde_json::json!({"count": 1, "gone": "x", "same": true, "history": ["a", "b"]}));
+        let new = vars(serde_json::json!({"count": 2, "same": true, "history": ["a", "c"], "mood": "ok"}));
+        // Test output isn't a terminal, so no escape codes
+        assert_eq!(diff(&old, &new), "\
+~ count
+  - 1
+  + 2
+- gone = \"x\"
+~ history
+    [
+      \"a\",
+  -   \"b\"
+  +   \"c\"
+    ]
++ mood = \"ok\"
+1 added, 1 removed, 2 changed
+");
+        assert_eq!(diff(&old, &old), "No differences\n");
+    }
+
+    #[test]
+    fn test_show_and_assignments() {
+        let session = vars(serde_json::json!({"count": 3, "ratio": 0.5, "name": "ada", "notes": ["x"]}));
+        assert_eq!(show(&session), "\
+count: Int = 3
+name: String = \"ada\"
+notes: List (1 item) =
+    [
+      \"x\"
+    ]
+ratio: Float = 0.5
+");
+        assert_eq!(parse_assignment("mood={\"ok\": true}").unwrap(), ("mood".to_string(), serde_json::json!({"ok": true})));
+        assert!(parse_assignment("mood=ok").unwrap_err().to_string().contains("quote strings"));
+        assert!(parse_assignment("2x=1").is_err());
+        assert!(parse_assignment("stdin=1").is_err());
+        assert!(parse_assignment("count").is_err());
+    }
+
+    #[test]
+    fn test_value_diff_elides_unchanged_runs() {
+        let a: Vec<i64> = (0..20).collect();
+        let mut b = a.clone();
+        b[10] = 99;
+        let out = value_diff(&serde_json::json!(a), &serde_json::json!(b));
+        assert_eq!(out.lines().filter(|l| l.trim() == "...").count(), 2);
+        assert!(out.contains("  -   10,\n  +   99,\n"), "got: {}", out);
+    }
+}
@@ -0,0 +1,133 @@
+//! Interactive prompts for `prompt_user()` when a person is at the
+//! terminal: an arrow-key selector for choices and a line prompt with a
+//! default. Drawn on stderr, so stdout keeps only the program's output.
+//! Without a terminal the interpreter asks through stdin/stdout instead.
+
+use crate::diagnostics::{paint, Stream, Style};
+use anyhow::{bail, Result};
+use std::io::{IsTerminal, Write};
+
+/// Whether prompts can be interactive: stdin and stdout are a terminal.
+pub fn interactive() -> bool {
+    std::io::stdin().is_terminal() && std::io::stdout().is_terminal()
+}
+
+/// Ask for a line of text; an empty answer takes `default`.
+pub fn ask(question: &str, default: Option<&str>) -> Result<Option<String>> {
+    let hint = default.map(|d| paint(Stream::Stderr, Style::Dim, &format!(" ({})", d))).unwrap_or_default();
+    eprint!("{} {}{} ", paint(Stream::Stderr, Style::Hint, "?"), question, hint);
+    let _ = std::io::stderr().flush();
+    let mut line = String::new();
+    if std::io::stdin().read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let answer = line.trim_end_matches(['\r', '\n']);
+    Ok(Some(match (answer.is_empty(), default) {
+        (true, Some(d)) => d.to_string(),
+        _ => answer.to_string(),
+    }))
+}
+
+/// Let the user pick one of `choices` with the arrow keys (or j/k, or a
+/// number) and Enter. Returns the index picked.
+#[cfg(unix)]
+pub fn select(question: &str, choices: &[String], start: usize) -> Result<usize> {
+    let _raw = RawMode::enable()?;
+    let mut selected = start;
+    let mut drawn = 0;
+    loop {
+        draw(question, choices, selected, drawn);
+        drawn = choices.len() + 1;
+        let mut key = [0u8; 3];
+        let n = unsafe { libc::read(libc::STDIN_FILENO, key.as_mut_ptr() as *mut libc::c_void, key.len()) };
+        if n <= 0 {
+            bail!("prompt_user(): no answer (end of input)");
+        }
+        match &key[..n as usize] {
+            [b'\r'] | [b'\n'] => break,
+            [0x1b, b'[', b'A'] | [b'k'] => selected = selected.checked_sub(1).unwrap_or(choices.len() - 1),
+            [0x1b, b'[', b'B'] | [b'j'] => selected = (selected + 1) % choices.len(),
+            // Ctrl-C and Ctrl-D: the terminal's own handling is off in raw mode
+            [3] | [4] => {
+                eprint!("\x1b[{}A\x1b[J", drawn);
+                bail!("prompt_user(): cancelled");
+            }
+            [digit @ b'1'..=b'9'] if ((digit - b'0') as usize) <= choices.len() => selected = (digit - b'1') as usize,
+            _ => {}
+        }
+    }
+    // Collapse the list into the answer
+    eprintln!("\x1b[{}A\x1b[J{} {} {}", drawn, paint(Stream::Stderr, Style::Hint, "?"), question,
+        paint(Stream::Stderr, Style::Literal, &choices[selected]));
+    let _ = std::io::stderr().flush();
+    Ok(selected)
+}
+
+/// Without termios, pick by number.
+#[cfg(not(unix))]
+pub fn select(question: &str, choices: &[String], start: usize) -> Result<usize> {
+    for (i, choice) in choices.iter().enumerate() {
+        eprintln!("  {}) {}", i + 1, choice);
+    }
+    loop {
+        let Some(answer) = ask(question, Some(&(start + 1).to_string()))? else {
+            bail!("prompt_user(): no answer (end of input)");
+        };
+        if let Some(i) = answer.trim().parse::<usize>().ok().filter(|i| (1..=choices.len()).contains(i)) {
+            return Ok(i - 1);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn draw(question: &str, choices: &[String], selected: usize, drawn: usize) {
+    let mut out = String::new();
+    if drawn > 0 {
+        out.push_str(&format!("\x1b[{}A\x1b[J", drawn));
+    }
+    out.push_str(&format!("{} {} {}\n", paint(Stream::Stderr, Style::Hint, "?"), question,
+        paint(Stream::Stderr, Style::Dim, "(↑/↓, enter)")));
+    for (i, choice) in choices.iter().enumerate() {
+        if i == selected {
+            out.push_str(&format!("{} {}\n", paint(Stream::Stderr, Style::Hint, "❯"), paint(Stream::Stderr, Style::Hint, choice)));
+        } else {
+            out.push_str(&format!("  {}\n", choice));
+        }
+    }
+    eprint!("{}", out);
+    let _ = std::io::stderr().flush();
+}
+
+/// Keys arrive one at a time and unechoed while this is alive; the
+/// terminal's settings come back on drop, including on error.
+#[cfg(unix)]
+struct RawMode(libc::termios);
+
+#[cfg(unix)]
+impl RawMode {
+    fn enable() -> Result<RawMode> {
+        unsafe {
+            let mut original: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                bail!("prompt_user(): cannot read terminal settings");
+            }
+            let mut raw = original;
+            raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+            raw.c_cc[libc::VMIN] = 1;
+            raw.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                bail!("prompt_user(): cannot set terminal settings");
+            }
+            Ok(RawMode(original))
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.0);
+        }
+    }
+}
@@ -1,4 +1,8 @@
-/// Token types for the Cognos lexer.
+//! Token types for the Cognos lexer.
+
+/// An interned identifier. The lexer hands out one shared allocation per
+/// distinct name, so cloning a name into a scope is a refcount bump.
+pub type Name = std::sync::Arc<str>;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
@@ -9,6 +13,7 @@ pub enum Token {
     Else,
     Elif,
     Loop,
+    While,
     Break,
     Continue,
     Return,
@@ -30,13 +35,16 @@ pub enum Token {
     None_,
     Pass,
     Select,
+    With,
 
     // Identifiers and literals
-    Ident(String),
+    Ident(Name),
     StringLit(String),
     FStringLit(String),  // f"..." — raw content, parsed later
     IntLit(i64),
     FloatLit(f64),
+    /// A number literal that doesn't fit or parse (`0x`, `99999999999999999999`)
+    BadNumber(String),
 
     // Operators
     Eq,         // =
@@ -50,6 +58,7 @@ pub enum Token {
     Minus,      // -
     Star,       // *
     Slash,      // /
+    SlashSlash, // //
     Percent,    // %
     Dot,        // .
     Comma,      // ,
@@ -83,6 +92,7 @@ impl std::fmt::Display for Token {
             Token::Else => write!(f, "'else'"),
             Token::Elif => write!(f, "'elif'"),
             Token::Loop => write!(f, "'loop'"),
+            Token::While => write!(f, "'while'"),
             Token::Break => write!(f, "'break'"),
             Token::Continue => write!(f, "'continue'"),
             Token::Return => write!(f, "'return'"),
@@ -104,11 +114,13 @@ impl std::fmt::Display for Token {
             Token::None_ => write!(f, "'none'"),
             Token::Pass => write!(f, "'pass'"),
             Token::Select => write!(f, "'select'"),
+            Token::With => write!(f, "'with'"),
             Token::Ident(s) => write!(f, "'{}'", s),
             Token::StringLit(s) => write!(f, "\"{}\"", s),
             Token::FStringLit(s) => write!(f, "f\"{}\"", s),
             Token::IntLit(n) => write!(f, "{}", n),
             Token::FloatLit(n) => write!(f, "{}", n),
+            Token::BadNumber(s) => write!(f, "'{}'", s),
             Token::Eq => write!(f, "'='"),
             Token::EqEq => write!(f, "'=='"),
             Token::NotEq => write!(f, "'!='"),
@@ -120,6 +132,7 @@ impl std::fmt::Display for Token {
             Token::Minus => write!(f, "'-'"),
             Token::Star => write!(f, "'*'"),
             Token::Slash => write!(f, "'/'"),
+            Token::SlashSlash => write!(f, "'//'"),
             Token::Percent => write!(f, "'%'"),
             Token::Dot => write!(f, "'.'"),
             Token::Comma => write!(f, "','"),
@@ -148,3 +161,30 @@ pub struct Spanned {
     pub line: usize,
     pub col: usize,
 }
+
+/// Where a statement starts: 1-based line and column. Spans never take part
+/// in comparisons, so the same code parses to an equal AST wherever it sits.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+}
+
+impl PartialEq for Span {
+    fn eq(&self, _: &Span) -> bool {
+        true
+    }
+}
+
+/// A `# comment`. The parser never sees these; the lexer keeps them for
+/// `cognos fmt`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comment {
+    pub line: usize,
+    /// Column of the `#`
+    pub col: usize,
+    /// Everything after the `#`, less trailing whitespace
+    pub text: String,
+    /// Whether only indentation comes before it on its line
+    pub own_line: bool,
+}
@@ -1,62 +1,212 @@
-/// Structured tracing for Cognos runtime diagnostics.
-/// Outputs JSONL events to a trace file or stderr.
+//! Structured tracing for Cognos runtime diagnostics.
+//! Outputs JSONL events to a trace file or stderr.
+//!
+//! Trace files are written through, one `write` per event, so a run that
+//! errors or is killed leaves every event up to that point on disk as whole
+//! lines. `install_exit_hooks` adds a final event for panics and signals.
 
 use std::io::Write;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Instant;
 
+/// The trace file's descriptor, for the signal handler; -1 when there is none.
+#[cfg(unix)]
+static TRACE_FD: std::sync::atomic::AtomicI32 = std::sync::atomic::AtomicI32::new(-1);
+
 #[derive(Clone, Copy, PartialEq)]
 pub enum TraceLevel {
     Metrics,  // default: latency, sizes, counts
     Full,     // includes prompt, response, command output
 }
 
+/// Bounds on how much a trace records. The defaults record everything.
+#[derive(Clone, Copy, Default)]
+pub struct TraceLimits {
+    /// Stop writing events (other than errors) once the file reaches this size.
+    pub max_bytes: Option<u64>,
+    /// Cut full-level payloads (prompts, responses, output) to this many chars.
+    pub max_payload: Option<usize>,
+    /// Keep the full prompt and response of only every Nth LLM call; failed
+    /// calls always keep theirs. 0 and 1 both mean every call.
+    pub llm_sample: u32,
+}
+
+/// Counts behind the `metrics` event, over a turn or the whole run.
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct Metrics {
+    pub turns: u32,
+    pub llm_calls: u64,
+    pub prompt_tokens: u64,
+    pub response_tokens: u64,
+    pub tool_calls: u64,
+    pub shell_execs: u64,
+    pub errors: u64,
+    pub wall_ms: u64,
+}
+
+impl Metrics {
+    fn is_empty(&self) -> bool {
+        self.llm_calls + self.tool_calls + self.shell_execs + self.errors == 0
+    }
+
+    /// What happened after `earlier` was taken.
+    fn since(&self, earlier: &Metrics) -> Metrics {
+        Metrics {
+            turns: self.turns - earlier.turns,
+            llm_calls: self.llm_calls - earlier.llm_calls,
+            prompt_tokens: self.prompt_tokens - earlier.prompt_tokens,
+            response_tokens: self.response_tokens - earlier.response_tokens,
+            tool_calls: self.tool_calls - earlier.tool_calls,
+            shell_execs: self.shell_execs - earlier.shell_execs,
+            errors: self.errors - earlier.errors,
+            wall_ms: self.wall_ms - earlier.wall_ms,
+        }
+    }
+}
+
+impl std::fmt::Display for Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} turns, {} LLM calls (~{} tokens in, ~{} out), {} tool calls, {} shell execs, {} errors, {:.1}s",
+            self.turns, self.llm_calls, self.prompt_tokens, self.response_tokens,
+            self.tool_calls, self.shell_execs, self.errors, self.wall_ms as f64 / 1000.0)
+    }
+}
+
 pub struct Tracer {
     output: Mutex<Box<dyn Write + Send>>,
     start: Instant,
     turn: Mutex<u32>,
     pub level: TraceLevel,
+    limits: TraceLimits,
+    /// Bytes written so far, and whether `max_bytes` has been hit.
+    written: Mutex<(u64, bool)>,
+    llm_calls: AtomicU32,
+    /// Ids handed out to `loop`/`for` statements as they start.
+    loops: AtomicU32,
+    /// Run totals so far, and the totals when the current turn began.
+    metrics: Mutex<(Metrics, Metrics)>,
 }
 
 impl Tracer {
     pub fn new_file(path: &str, level: TraceLevel) -> std::io::Result<Self> {
         let file = std::fs::File::create(path)?;
-        Ok(Self {
-            output: Mutex::new(Box::new(std::io::BufWriter::new(file))),
-            start: Instant::now(),
-            turn: Mutex::new(0),
-            level,
-        })
+        #[cfg(unix)]
+        TRACE_FD.store(std::os::unix::io::AsRawFd::as_raw_fd(&file), Ordering::SeqCst);
+        Ok(Self::new_output(Box::new(file), level))
     }
 
+    #[allow(dead_code)]
     pub fn new_stderr(level: TraceLevel) -> Self {
+        Self::new_output(Box::new(std::io::stderr()), level)
+    }
+
+    /// Records nothing, but still counts for `finish()`.
+    pub fn new_sink() -> Self {
+        Self::new_output(Box::new(std::io::sink()), TraceLevel::Metrics)
+    }
+
+    fn new_output(output: Box<dyn Write + Send>, level: TraceLevel) -> Self {
         Self {
-            output: Mutex::new(Box::new(std::io::stderr())),
+            output: Mutex::new(output),
             start: Instant::now(),
             turn: Mutex::new(0),
             level,
+            limits: TraceLimits::default(),
+            written: Mutex::new((0, false)),
+            llm_calls: AtomicU32::new(0),
+            loops: AtomicU32::new(0),
+            metrics: Mutex::new((Metrics::default(), Metrics::default())),
         }
     }
 
+    pub fn with_limits(mut self, limits: TraceLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Start the next turn, first emitting a `metrics` event for the one
+    /// ending if anything happened in it.
     pub fn increment_turn(&self) -> u32 {
+        let ended = {
+            let mut metrics = self.metrics.lock().unwrap_or_else(|e| e.into_inner());
+            let (ref mut total, ref mut turn_start) = *metrics;
+            total.turns += 1;
+            total.wall_ms = self.start.elapsed().as_millis() as u64;
+            let ended = total.since(turn_start);
+            *turn_start = *total;
+            ended
+        };
+        if !ended.is_empty() {
+            self.emit(TraceEvent::Metrics { scope: "turn".into(), metrics: ended });
+        }
         let mut turn = self.turn.lock().unwrap_or_else(|e| e.into_inner());
         *turn += 1;
         *turn
     }
 
+    /// Emit the run's `metrics` event and return the totals.
+    pub fn finish(&self) -> Metrics {
+        let mut total = self.metrics.lock().unwrap_or_else(|e| e.into_inner()).0;
+        total.wall_ms = self.start.elapsed().as_millis() as u64;
+        self.emit(TraceEvent::Metrics { scope: "run".into(), metrics: total });
+        total
+    }
+
+    fn count(&self, event: &TraceEvent) {
+        let mut metrics = self.metrics.lock().unwrap_or_else(|e| e.into_inner());
+        let total = &mut metrics.0;
+        match event {
+            TraceEvent::LlmCall { prompt_tokens, response_tokens, error, .. } => {
+                total.llm_calls += 1;
+                total.prompt_tokens += *prompt_tokens as u64;
+                total.response_tokens += *response_tokens as u64;
+                total.errors += error.is_some() as u64;
+            }
+            TraceEvent::ToolExec { success, .. } => {
+                total.tool_calls += 1;
+                total.errors += !success as u64;
+            }
+            TraceEvent::ShellExec { .. } => total.shell_execs += 1,
+            TraceEvent::Error { .. } => total.errors += 1,
+            _ => {}
+        }
+    }
+
     pub fn current_turn(&self) -> u32 {
         *self.turn.lock().unwrap_or_else(|e| e.into_inner())
     }
 
     pub fn emit(&self, event: TraceEvent) {
+        self.write_event(event, true, &[]);
+    }
+
+    /// Emit an event from inside `loop`/`for` bodies, tagged with the
+    /// enclosing loops, outermost first.
+    pub fn emit_in_loops(&self, event: TraceEvent, loops: &[LoopFrame]) {
+        self.write_event(event, true, loops);
+    }
+
+    /// A fresh id for a loop statement that is starting to run.
+    pub fn next_loop_id(&self) -> u32 {
+        self.loops.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Build the JSON line for `event` and write it. With `wait` false the
+    /// event is dropped rather than blocking on a held output lock, which is
+    /// what a panic hook needs: the panicking thread may be the one holding it.
+    fn write_event(&self, event: TraceEvent, wait: bool, loops: &[LoopFrame]) {
+        if wait {
+            self.count(&event);
+        }
         let elapsed_ms = self.start.elapsed().as_millis() as u64;
         let ts = chrono_now();
         let turn = self.current_turn();
 
         let is_full = self.level == TraceLevel::Full;
 
-        let json = match event {
-            TraceEvent::LlmCall { model, provider, latency_ms, prompt_chars, response_chars, has_tool_calls, error, prompt, response, system } => {
+        let mut json = match event {
+            TraceEvent::LlmCall { model, provider, latency_ms, prompt_chars, response_chars, prompt_tokens, response_tokens, has_tool_calls, finish_reason, error, branch, prompt, response, system } => {
                 let mut j = serde_json::json!({
                     "ts": ts, "elapsed_ms": elapsed_ms, "turn": turn,
                     "event": "llm_call",
@@ -64,13 +214,19 @@ impl Tracer {
                     "latency_ms": latency_ms,
                     "prompt_chars": prompt_chars,
                     "response_chars": response_chars,
+                    "prompt_tokens": prompt_tokens,
+                    "response_tokens": response_tokens,
                     "has_tool_calls": has_tool_calls,
+                    "finish_reason": finish_reason,
                     "error": error,
                 });
-                if is_full {
-                    if let Some(p) = prompt { j["prompt"] = serde_json::Value::String(p); }
-                    if let Some(r) = response { j["response"] = serde_json::Value::String(r); }
-                    if let Some(s) = system { j["system"] = serde_json::Value::String(s); }
+                if let Some(branch) = branch { j["branch"] = branch.into(); }
+                // Sampling thins out bodies, never the metrics above
+                let sampled = self.next_llm_call().is_multiple_of(self.limits.llm_sample.max(1));
+                if is_full && (sampled || j["error"].is_string()) {
+                    if let Some(p) = prompt { j["prompt"] = self.payload(p); }
+                    if let Some(r) = response { j["response"] = self.payload(r); }
+                    if let Some(s) = system { j["system"] = self.payload(s); }
                 }
                 j
             }
@@ -85,17 +241,23 @@ impl Tracer {
                     "error": error,
                 })
             }
-            TraceEvent::FlowStart { name } => {
-                serde_json::json!({
+            TraceEvent::FlowStart { name, depth, args } => {
+                let mut j = serde_json::json!({
                     "ts": ts, "elapsed_ms": elapsed_ms, "turn": turn,
-                    "event": "flow_start", "flow": name,
-                })
+                    "event": "flow_start", "flow": name, "depth": depth,
+                });
+                if is_full {
+                    if let Some(a) = args { j["args"] = serde_json::Value::String(a); }
+                }
+                j
             }
-            TraceEvent::FlowEnd { name, duration_ms } => {
+            TraceEvent::FlowEnd { name, depth, duration_ms, result_type, error } => {
                 serde_json::json!({
                     "ts": ts, "elapsed_ms": elapsed_ms, "turn": turn,
-                    "event": "flow_end", "flow": name,
+                    "event": "flow_end", "flow": name, "depth": depth,
                     "duration_ms": duration_ms,
+                    "result_type": result_type,
+                    "error": error,
                 })
             }
             TraceEvent::IoOp { operation, handle_type, path, bytes, content } => {
@@ -106,10 +268,58 @@ impl Tracer {
                     "path": path, "bytes": bytes,
                 });
                 if is_full {
-                    if let Some(c) = content { j["content"] = serde_json::Value::String(c); }
+                    if let Some(c) = content { j["content"] = self.payload(c); }
                 }
                 j
             }
+            TraceEvent::FlowDefined { name, source, replaced } => {
+                serde_json::json!({
+                    "ts": ts, "elapsed_ms": elapsed_ms, "turn": turn,
+                    "event": "flow_defined", "flow": name,
+                    "replaced": replaced,
+                    "source": source,
+                })
+            }
+            TraceEvent::PluginCall { plugin, function, latency_ms, error } => {
+                serde_json::json!({
+                    "ts": ts, "elapsed_ms": elapsed_ms, "turn": turn,
+                    "event": "plugin_call", "plugin": plugin,
+                    "function": function,
+                    "latency_ms": latency_ms,
+                    "error": error,
+                })
+            }
+            TraceEvent::WasmExec { module, latency_ms, exit_code, fuel_used, output_chars, error } => {
+                serde_json::json!({
+                    "ts": ts, "elapsed_ms": elapsed_ms, "turn": turn,
+                    "event": "wasm_exec", "module": module,
+                    "latency_ms": latency_ms,
+                    "exit_code": exit_code,
+                    "fuel_used": fuel_used,
+                    "output_chars": output_chars,
+                    "error": error,
+                })
+            }
+            TraceEvent::GrpcCall { endpoint, method, latency_ms, error } => {
+                serde_json::json!({
+                    "ts": ts, "elapsed_ms": elapsed_ms, "turn": turn,
+                    "event": "grpc_call", "endpoint": endpoint,
+                    "method": method,
+                    "latency_ms": latency_ms,
+                    "error": error,
+                })
+            }
+            TraceEvent::Retry { call, target, attempt, retries, delay_ms, error } => {
+                serde_json::json!({
+                    "ts": ts, "elapsed_ms": elapsed_ms, "turn": turn,
+                    "event": "retry", "call": call,
+                    "target": target,
+                    "attempt": attempt,
+                    "retries": retries,
+                    "delay_ms": delay_ms,
+                    "error": error,
+                })
+            }
             TraceEvent::ShellExec { command, latency_ms, exit_code, output_chars, output } => {
                 let mut j = serde_json::json!({
                     "ts": ts, "elapsed_ms": elapsed_ms, "turn": turn,
@@ -120,10 +330,51 @@ impl Tracer {
                     "output_chars": output_chars,
                 });
                 if is_full {
-                    if let Some(o) = output { j["output"] = serde_json::Value::String(o); }
+                    if let Some(o) = output { j["output"] = self.payload(o); }
                 }
                 j
             }
+            TraceEvent::SystemPrompt { segments } => {
+                let segments: Vec<serde_json::Value> = segments.into_iter().map(|(source, text)| {
+                    let mut seg = serde_json::json!({ "source": source, "chars": text.len() });
+                    if is_full { seg["text"] = self.payload(text); }
+                    seg
+                }).collect();
+                serde_json::json!({
+                    "ts": ts, "elapsed_ms": elapsed_ms, "turn": turn,
+                    "event": "system_prompt",
+                    "segments": segments,
+                })
+            }
+            TraceEvent::ToolResultTruncated { tool, tool_use_id, original_chars, kept_chars } => {
+                serde_json::json!({
+                    "ts": ts, "elapsed_ms": elapsed_ms, "turn": turn,
+                    "event": "tool_result_truncated",
+                    "tool": tool, "tool_use_id": tool_use_id,
+                    "original_chars": original_chars,
+                    "kept_chars": kept_chars,
+                })
+            }
+            TraceEvent::SummarizeStage { stage, calls, input_chars, output_chars, latency_ms } => {
+                serde_json::json!({
+                    "ts": ts, "elapsed_ms": elapsed_ms, "turn": turn,
+                    "event": "summarize_stage",
+                    "stage": stage, "calls": calls,
+                    "input_chars": input_chars,
+                    "output_chars": output_chars,
+                    "latency_ms": latency_ms,
+                })
+            }
+            TraceEvent::Judge { model, score, threshold, pass, latency_ms, reasoning } => {
+                let mut j = serde_json::json!({
+                    "ts": ts, "elapsed_ms": elapsed_ms, "turn": turn,
+                    "event": "judge", "model": model,
+                    "score": score, "threshold": threshold, "pass": pass,
+                    "latency_ms": latency_ms,
+                });
+                if is_full { j["reasoning"] = self.payload(reasoning); }
+                j
+            }
             TraceEvent::Context { history_len, context_chars } => {
                 serde_json::json!({
                     "ts": ts, "elapsed_ms": elapsed_ms, "turn": turn,
@@ -132,6 +383,27 @@ impl Tracer {
                     "context_chars": context_chars,
                 })
             }
+            TraceEvent::Metrics { scope, metrics } => {
+                serde_json::json!({
+                    "ts": ts, "elapsed_ms": elapsed_ms, "turn": turn,
+                    "event": "metrics", "scope": scope,
+                    "turns": metrics.turns,
+                    "llm_calls": metrics.llm_calls,
+                    "prompt_tokens": metrics.prompt_tokens,
+                    "response_tokens": metrics.response_tokens,
+                    "tool_calls": metrics.tool_calls,
+                    "shell_execs": metrics.shell_execs,
+                    "errors": metrics.errors,
+                    "wall_ms": metrics.wall_ms,
+                })
+            }
+            TraceEvent::Warning { category, message } => {
+                serde_json::json!({
+                    "ts": ts, "elapsed_ms": elapsed_ms, "turn": turn,
+                    "event": "warning",
+                    "category": category, "message": message,
+                })
+            }
             TraceEvent::Error { category, message, flow } => {
                 serde_json::json!({
                     "ts": ts, "elapsed_ms": elapsed_ms, "turn": turn,
@@ -141,13 +413,128 @@ impl Tracer {
             }
         };
 
-        if let Ok(mut out) = self.output.lock() {
-            let _ = writeln!(out, "{}", json);
+        if !loops.is_empty() {
+            json["loops"] = loops.iter()
+                .map(|l| serde_json::json!({"id": l.id, "iteration": l.iteration}))
+                .collect();
+        }
+
+        let out = if wait {
+            Some(self.output.lock().unwrap_or_else(|e| e.into_inner()))
+        } else {
+            match self.output.try_lock() {
+                Ok(out) => Some(out),
+                Err(std::sync::TryLockError::Poisoned(e)) => Some(e.into_inner()),
+                Err(std::sync::TryLockError::WouldBlock) => None,
+            }
+        };
+        if let Some(mut out) = out {
+            let mut line = format!("{}\n", json);
+            if let Some(max) = self.limits.max_bytes {
+                let mut written = self.written.lock().unwrap_or_else(|e| e.into_inner());
+                // Errors and the run's totals are small and worth keeping past the cap
+                let always = json["event"] == "error" || json["scope"] == "run";
+                if written.1 && !always {
+                    return;
+                }
+                if !written.1 && written.0 + line.len() as u64 > max && !always {
+                    // Say why the trace stops here, once
+                    written.1 = true;
+                    line = format!("{}\n", serde_json::json!({
+                        "ts": json["ts"], "elapsed_ms": json["elapsed_ms"], "turn": json["turn"],
+                        "event": "trace_limit", "max_bytes": max,
+                    }));
+                }
+                written.0 += line.len() as u64;
+            }
+            // One write per line, so a kill never leaves half an event behind
+            let _ = out.write_all(line.as_bytes());
             let _ = out.flush();
         }
     }
+
+    /// A full-level payload, cut to `max_payload` chars with a note of how
+    /// much was dropped.
+    fn payload(&self, text: String) -> serde_json::Value {
+        let Some(max) = self.limits.max_payload else { return serde_json::Value::String(text) };
+        let total = text.chars().count();
+        if total <= max {
+            return serde_json::Value::String(text);
+        }
+        let kept: String = text.chars().take(max).collect();
+        serde_json::Value::String(format!("{}… [{} more chars]", kept, total - max))
+    }
+
+    fn next_llm_call(&self) -> u32 {
+        self.llm_calls.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        // Stop the signal handler writing to a descriptor that may be reused
+        TRACE_FD.store(-1, Ordering::SeqCst);
+    }
+}
+
+/// Record how the process ended when it doesn't end normally: a panic adds
+/// an `error` event with category `panic`, and on Unix SIGINT, SIGTERM and
+/// SIGHUP add an `interrupted` event before the default action runs.
+pub fn install_exit_hooks(tracer: &Arc<Tracer>) {
+    let weak = Arc::downgrade(tracer);
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if let Some(tracer) = weak.upgrade() {
+            let message = match info.payload().downcast_ref::<&str>() {
+                Some(s) => s.to_string(),
+                None => info.payload().downcast_ref::<String>().cloned().unwrap_or_else(|| "panic".into()),
+            };
+            let message = match info.location() {
+                Some(loc) => format!("{} at {}:{}", message, loc.file(), loc.line()),
+                None => message,
+            };
+            tracer.write_event(TraceEvent::Error { category: "panic".into(), message, flow: None }, false, &[]);
+        }
+        previous(info);
+    }));
+    #[cfg(unix)]
+    unsafe {
+        for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+            libc::signal(signal, on_signal as *const () as libc::sighandler_t);
+        }
+    }
+}
+
+/// Async-signal-safe: no locks or allocation, just a fixed line written to
+/// the raw descriptor, then the signal re-raised with its default action.
+#[cfg(unix)]
+extern "C" fn on_signal(signal: libc::c_int) {
+    let line: &[u8] = match signal {
+        libc::SIGINT => b"{\"event\":\"interrupted\",\"signal\":\"SIGINT\"}\n",
+        libc::SIGTERM => b"{\"event\":\"interrupted\",\"signal\":\"SIGTERM\"}\n",
+        _ => b"{\"event\":\"interrupted\",\"signal\":\"SIGHUP\"}\n",
+    };
+    let fd = TRACE_FD.load(Ordering::SeqCst);
+    unsafe {
+        if fd >= 0 {
+            libc::write(fd, line.as_ptr() as *const libc::c_void, line.len());
+        }
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+}
+
+/// A `loop`/`for` statement an event was emitted inside.
+#[derive(Debug, Clone, Copy)]
+pub struct LoopFrame {
+    /// Unique per loop statement run, in the order they start.
+    pub id: u32,
+    /// 1-based iteration the loop is on.
+    pub iteration: u64,
 }
 
+#[allow(dead_code)]
 pub enum TraceEvent {
     LlmCall {
         model: String,
@@ -155,8 +542,15 @@ pub enum TraceEvent {
         latency_ms: u64,
         prompt_chars: usize,
         response_chars: usize,
+        /// Estimated with `providers::count_tokens`, system prompt included.
+        prompt_tokens: usize,
+        response_tokens: usize,
         has_tool_calls: bool,
+        /// Normalized provider stop reason, e.g. "stop" or "length".
+        finish_reason: Option<String>,
         error: Option<String>,
+        /// The parallel/select branch that made the call, when inside one.
+        branch: Option<String>,
         // Full level only
         prompt: Option<String>,
         response: Option<String>,
@@ -170,12 +564,20 @@ pub enum TraceEvent {
         success: bool,
         error: Option<String>,
     },
+    /// A flow being entered; the entry flow is `depth` 0.
     FlowStart {
         name: String,
+        depth: usize,
+        // Full level only
+        args: Option<String>,
     },
+    /// The matching exit, with the result's type or the error that escaped.
     FlowEnd {
         name: String,
+        depth: usize,
         duration_ms: u64,
+        result_type: Option<String>,
+        error: Option<String>,
     },
     IoOp {
         operation: String,
@@ -184,6 +586,50 @@ pub enum TraceEvent {
         bytes: usize,
         content: Option<String>,
     },
+    /// A flow added at run time by `define_flow()`. The source is recorded
+    /// whole at every level, like a shell command: it is code the run wrote.
+    FlowDefined {
+        name: String,
+        source: String,
+        /// Whether it replaced a flow an earlier `define_flow()` made.
+        replaced: bool,
+    },
+    /// A built-in or channel call handled by a native plugin.
+    PluginCall {
+        plugin: String,
+        /// The built-in called, or `read`/`write` for a channel
+        function: String,
+        latency_ms: u64,
+        error: Option<String>,
+    },
+    WasmExec {
+        module: String,
+        latency_ms: u64,
+        /// None when the module failed to load, trapped or ran out of fuel
+        exit_code: Option<i32>,
+        fuel_used: Option<u64>,
+        output_chars: usize,
+        error: Option<String>,
+    },
+    GrpcCall {
+        endpoint: String,
+        /// `service/method`, as the script named them
+        method: String,
+        latency_ms: u64,
+        error: Option<String>,
+    },
+    /// A failed attempt at an LLM or HTTP call that is about to be retried.
+    Retry {
+        /// "llm", "http.get" or "http.post"
+        call: String,
+        /// The model or URL
+        target: String,
+        /// 1-based attempt that failed
+        attempt: u32,
+        retries: u32,
+        delay_ms: u64,
+        error: String,
+    },
     ShellExec {
         command: String,
         latency_ms: u64,
@@ -195,6 +641,45 @@ pub enum TraceEvent {
         history_len: usize,
         context_chars: usize,
     },
+    /// A `system=` prompt assembled from several parts, as (source, text).
+    SystemPrompt {
+        segments: Vec<(String, String)>,
+    },
+    ToolResultTruncated {
+        tool: Option<String>,
+        tool_use_id: String,
+        original_chars: usize,
+        kept_chars: usize,
+    },
+    /// One map or reduce round of `summarize()`.
+    SummarizeStage {
+        stage: String,
+        calls: usize,
+        input_chars: usize,
+        output_chars: usize,
+        latency_ms: u64,
+    },
+    /// One `judge()` verdict.
+    Judge {
+        model: String,
+        score: f64,
+        threshold: f64,
+        pass: bool,
+        latency_ms: u64,
+        // Full level only
+        reasoning: String,
+    },
+    /// Totals for one turn (`scope` "turn") or the whole run ("run").
+    Metrics {
+        scope: String,
+        metrics: Metrics,
+    },
+    /// Something the run got past but that likely needs a look, such as a
+    /// reply cut off at the length limit (`category` "length").
+    Warning {
+        category: String,
+        message: String,
+    },
     Error {
         category: String,
         message: String,
@@ -211,3 +696,74 @@ fn chrono_now() -> String {
     // Good enough for tracing — exact formatting not critical
     format!("{}", secs)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn llm_call(prompt: &str, error: Option<&str>) -> TraceEvent {
+        TraceEvent::LlmCall {
+            model: "m".into(), provider: "p".into(), latency_ms: 1,
+            prompt_chars: prompt.len(), response_chars: 2, prompt_tokens: 1, response_tokens: 1, has_tool_calls: false, finish_reason: None,
+            error: error.map(String::from), branch: None,
+            prompt: Some(prompt.into()), response: Some("ok".into()), system: None,
+        }
+    }
+
+    fn events(path: &std::path::Path) -> Vec<serde_json::Value> {
+        std::fs::read_to_string(path).unwrap().lines().map(|l| serde_json::from_str(l).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_limits_sample_truncate_and_cap() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace.jsonl");
+        let limits = TraceLimits { max_bytes: Some(1000), max_payload: Some(5), llm_sample: 2 };
+        let tracer = Tracer::new_file(path.to_str().unwrap(), TraceLevel::Full).unwrap().with_limits(limits);
+        tracer.emit(llm_call("hello world", None));
+        tracer.emit(llm_call("skipped", None));
+        tracer.emit(llm_call("failed", Some("boom")));
+        for _ in 0..20 {
+            tracer.emit(TraceEvent::FlowStart { name: "main".into(), depth: 0, args: None });
+        }
+        tracer.emit(TraceEvent::Error { category: "runtime".into(), message: "late".into(), flow: None });
+
+        let events = events(&path);
+        assert_eq!(events[0]["prompt"], "hello… [6 more chars]");
+        assert!(events[1].get("prompt").is_none());
+        assert_eq!(events[1]["prompt_chars"], 7);
+        assert_eq!(events[2]["prompt"], "faile… [1 more chars]");
+        let cut = events.iter().position(|e| e["event"] == "trace_limit").unwrap();
+        assert_eq!(events[cut + 1..].len(), 1);
+        assert_eq!(events.last().unwrap()["message"], "late");
+        // Only the cut marker and the error go past the cap
+        assert!(std::fs::metadata(&path).unwrap().len() < 1300);
+    }
+
+    #[test]
+    fn test_metrics_per_turn_and_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace.jsonl");
+        let tracer = Tracer::new_file(path.to_str().unwrap(), TraceLevel::Metrics).unwrap();
+        // Nothing happened before the first input: no turn event
+        tracer.increment_turn();
+        tracer.emit(llm_call("a", None));
+        tracer.emit(llm_call("b", Some("boom")));
+        tracer.increment_turn();
+        tracer.emit(TraceEvent::ToolExec {
+            name: "add".into(), args_summary: "{}".into(), latency_ms: 1,
+            result_chars: 1, success: true, error: None,
+        });
+        let run = tracer.finish();
+        assert_eq!((run.turns, run.llm_calls, run.prompt_tokens, run.tool_calls, run.errors), (2, 2, 2, 1, 1));
+
+        let metrics: Vec<serde_json::Value> = events(&path).into_iter().filter(|e| e["event"] == "metrics").collect();
+        assert_eq!(metrics.len(), 2);
+        assert_eq!(metrics[0]["scope"], "turn");
+        assert_eq!(metrics[0]["turn"], 1);
+        assert_eq!(metrics[0]["llm_calls"], 2);
+        assert_eq!(metrics[0]["tool_calls"], 0);
+        assert_eq!(metrics[1]["scope"], "run");
+        assert_eq!(metrics[1]["tool_calls"], 1);
+    }
+}
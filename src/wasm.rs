@@ -0,0 +1,689 @@
+//! `wasm()`: run an untrusted tool compiled to WebAssembly in a sandbox.
+//!
+//! The module is a WASI command (`_start`, as `wasm32-wasip1` builds make)
+//! run by the wasmi interpreter. Only a subset of WASI preview 1 is
+//! provided: stdin, stdout and stderr, a clock, random bytes, and files
+//! under the directories mapped in with `dirs=` (read-only with
+//! `ro_dirs=`). Sockets, other imports and paths outside the mapped
+//! directories are refused, and fuel and memory caps bound what a run can
+//! take. Compared with `--allow-shell` a tool can only touch what it's
+//! given; compared with a `--plugin` it can't touch anything else.
+
+use anyhow::{bail, Result};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use wasmi::{Caller, Config, Engine, Extern, ExternType, Linker, Module, Store, StoreLimits, StoreLimitsBuilder, Val};
+
+const WASI: &str = "wasi_snapshot_preview1";
+
+/// Instructions a run may execute unless `fuel=` says otherwise.
+pub const DEFAULT_FUEL: u64 = 10_000_000_000;
+/// Linear memory a run may grow to unless `memory_mb=` says otherwise.
+pub const DEFAULT_MEMORY_MB: usize = 256;
+/// Output kept per stream; writes past it fail with EFBIG.
+const MAX_OUTPUT: usize = 64 * 1024 * 1024;
+
+pub struct WasmOptions {
+    pub dirs: Vec<MappedDir>,
+    pub fuel: u64,
+    pub memory_mb: usize,
+}
+
+impl Default for WasmOptions {
+    fn default() -> Self {
+        Self { dirs: Vec::new(), fuel: DEFAULT_FUEL, memory_mb: DEFAULT_MEMORY_MB }
+    }
+}
+
+/// A host directory the module sees at `guest`.
+pub struct MappedDir {
+    pub guest: String,
+    pub host: PathBuf,
+    pub writable: bool,
+}
+
+#[derive(Debug)]
+pub struct WasmOutput {
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_code: i32,
+    pub fuel_used: u64,
+}
+
+/// Run the module at `path` with `stdin` as its input.
+pub fn run(path: &Path, stdin: Vec<u8>, options: &WasmOptions) -> Result<WasmOutput> {
+    let bytes = std::fs::read(path).map_err(|e| anyhow::anyhow!("can't read {}: {}", path.display(), e))?;
+    let name = path.file_name().map_or_else(|| "module".to_string(), |n| n.to_string_lossy().into_owned());
+    run_bytes(&name, &bytes, stdin, options)
+}
+
+pub fn run_bytes(name: &str, bytes: &[u8], stdin: Vec<u8>, options: &WasmOptions) -> Result<WasmOutput> {
+    let mut config = Config::default();
+    config.consume_fuel(true);
+    let engine = Engine::new(&config);
+    let module = Module::new(&engine, bytes).map_err(|e| anyhow::anyhow!("{} is not a valid WebAssembly module: {}", name, e))?;
+
+    let mut fds = vec![Some(Fd::Stdin), Some(Fd::Stdout), Some(Fd::Stderr)];
+    for dir in &options.dirs {
+        let host = dir.host.canonicalize()
+            .map_err(|e| anyhow::anyhow!("can't map {} to {}: {}", dir.host.display(), dir.guest, e))?;
+        if !host.is_dir() {
+            bail!("can't map {} to {}: not a directory", dir.host.display(), dir.guest);
+        }
+        fds.push(Some(Fd::Dir { guest: Some(dir.guest.clone()), root: host.clone(), path: host, writable: dir.writable }));
+    }
+    let wasi = Wasi {
+        args: vec![name.to_string()],
+        stdin: std::io::Cursor::new(stdin),
+        stdout: Vec::new(),
+        stderr: Vec::new(),
+        fds,
+        started: Instant::now(),
+        limits: StoreLimitsBuilder::new().memory_size(options.memory_mb * 1024 * 1024).build(),
+    };
+    let mut store = Store::new(&engine, wasi);
+    store.limiter(|wasi| &mut wasi.limits);
+    store.set_fuel(options.fuel).map_err(|e| anyhow::anyhow!("{}", e))?;
+
+    let mut linker = Linker::new(&engine);
+    define_wasi(&mut linker)?;
+    // What a module may import beyond the subset fails with ENOSYS
+    for import in module.imports() {
+        let defined = linker.get(&store, import.module(), import.name()).is_some() || IMPLEMENTED.contains(&import.name());
+        match import.ty() {
+            ExternType::Func(ty) if import.module() == WASI && !defined => {
+                let returns_errno = !ty.results().is_empty();
+                linker.func_new(WASI, import.name(), ty.clone(), move |_, _, results| {
+                    if returns_errno {
+                        results[0] = Val::I32(NOSYS as i32);
+                    }
+                    Ok(())
+                })?;
+            }
+            _ if import.module() == WASI => {}
+            _ => bail!("{} imports {}::{}, but only WASI ({}) is available to wasm tools", name, import.module(), import.name(), WASI),
+        }
+    }
+
+    let instance = linker.instantiate(&mut store, &module)?.start(&mut store)?;
+    let start = instance.get_typed_func::<(), ()>(&store, "_start")
+        .map_err(|_| anyhow::anyhow!("{} has no _start function; build it as a WASI command", name))?;
+    let exit_code = match start.call(&mut store, ()) {
+        Ok(()) => 0,
+        Err(e) => match e.i32_exit_status() {
+            Some(code) => code,
+            None if e.as_trap_code() == Some(wasmi::core::TrapCode::OutOfFuel) => {
+                bail!("{} ran out of fuel after {} instructions; raise fuel= if it needs more", name, options.fuel)
+            }
+            None => bail!("{} trapped: {}", name, e),
+        },
+    };
+    let fuel_used = options.fuel - store.get_fuel().unwrap_or(0);
+    let wasi = store.into_data();
+    Ok(WasmOutput {
+        stdout: String::from_utf8_lossy(&wasi.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&wasi.stderr).into_owned(),
+        exit_code,
+        fuel_used,
+    })
+}
+
+// WASI errno values
+type Errno = u16;
+const SUCCESS: Errno = 0;
+const EBADF: Errno = 8;
+const EEXIST: Errno = 20;
+const EFAULT: Errno = 21;
+const EFBIG: Errno = 22;
+const EINVAL: Errno = 28;
+const EIO: Errno = 29;
+const EISDIR: Errno = 31;
+const ENOENT: Errno = 44;
+const NOSYS: Errno = 52;
+const ENOTDIR: Errno = 54;
+const ENOTEMPTY: Errno = 55;
+const ESPIPE: Errno = 70;
+const ENOTCAPABLE: Errno = 76;
+
+// File types
+const CHARACTER_DEVICE: u8 = 2;
+const DIRECTORY: u8 = 3;
+const REGULAR_FILE: u8 = 4;
+const SYMBOLIC_LINK: u8 = 7;
+
+// path_open flags and rights
+const O_CREAT: i32 = 1;
+const O_DIRECTORY: i32 = 2;
+const O_EXCL: i32 = 4;
+const O_TRUNC: i32 = 8;
+const FDFLAG_APPEND: i32 = 1;
+const RIGHT_FD_WRITE: i64 = 1 << 6;
+
+enum Fd {
+    Stdin,
+    Stdout,
+    Stderr,
+    /// A directory; `guest` is set for the mapped ones, which the module
+    /// finds with `fd_prestat_get`.
+    Dir { guest: Option<String>, root: PathBuf, path: PathBuf, writable: bool },
+    File { file: std::fs::File, path: PathBuf },
+}
+
+struct Wasi {
+    args: Vec<String>,
+    stdin: std::io::Cursor<Vec<u8>>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    fds: Vec<Option<Fd>>,
+    started: Instant,
+    limits: StoreLimits,
+}
+
+impl Wasi {
+    fn fd(&mut self, fd: i32) -> Result<&mut Fd, Errno> {
+        usize::try_from(fd).ok().and_then(|fd| self.fds.get_mut(fd)).and_then(Option::as_mut).ok_or(EBADF)
+    }
+
+    fn insert(&mut self, fd: Fd) -> u32 {
+        match self.fds.iter().position(Option::is_none) {
+            Some(free) => {
+                self.fds[free] = Some(fd);
+                free as u32
+            }
+            None => {
+                self.fds.push(Some(fd));
+                (self.fds.len() - 1) as u32
+            }
+        }
+    }
+
+    /// `path` under directory `dirfd`, as a host path and whether it may be
+    /// written. Paths that would leave the mapped directory, lexically or
+    /// through a symlink, are refused.
+    fn resolve(&mut self, dirfd: i32, path: &str) -> Result<(PathBuf, PathBuf, bool), Errno> {
+        let Fd::Dir { root, path: dir, writable, .. } = self.fd(dirfd)? else { return Err(ENOTDIR) };
+        let (root, mut resolved, writable) = (root.clone(), dir.clone(), *writable);
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(part) => resolved.push(part),
+                Component::CurDir => {}
+                Component::ParentDir if resolved != root => { resolved.pop(); }
+                _ => return Err(ENOTCAPABLE),
+            }
+        }
+        // The nearest part that exists must really be inside the root
+        let mut existing = resolved.as_path();
+        while existing.symlink_metadata().is_err() {
+            existing = existing.parent().ok_or(ENOTCAPABLE)?;
+        }
+        match existing.canonicalize() {
+            Ok(real) if real.starts_with(&root) => Ok((root, resolved, writable)),
+            _ => Err(ENOTCAPABLE),
+        }
+    }
+}
+
+/// The calls defined below; other WASI imports get an ENOSYS stub.
+const IMPLEMENTED: &[&str] = &[
+    "args_sizes_get", "args_get", "environ_sizes_get", "environ_get", "clock_res_get", "clock_time_get",
+    "random_get", "proc_exit", "sched_yield", "fd_write", "fd_read", "fd_close", "fd_seek", "fd_tell",
+    "fd_fdstat_get", "fd_filestat_get", "fd_prestat_get", "fd_prestat_dir_name", "fd_readdir",
+    "path_open", "path_filestat_get", "path_create_directory", "path_remove_directory",
+    "path_unlink_file", "path_rename",
+];
+
+/// Bounds-checked access to the module's memory.
+struct Memory<'a>(&'a mut [u8]);
+
+impl Memory<'_> {
+    fn slice(&mut self, ptr: i32, len: u32) -> Result<&mut [u8], Errno> {
+        let start = ptr as u32 as usize;
+        let end = start.checked_add(len as usize).ok_or(EFAULT)?;
+        self.0.get_mut(start..end).ok_or(EFAULT)
+    }
+
+    fn read_u32(&mut self, ptr: i32) -> Result<u32, Errno> {
+        Ok(u32::from_le_bytes(self.slice(ptr, 4)?.try_into().unwrap_or_default()))
+    }
+
+    fn write(&mut self, ptr: i32, bytes: &[u8]) -> Result<(), Errno> {
+        self.slice(ptr, bytes.len() as u32)?.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn write_u32(&mut self, ptr: i32, value: u32) -> Result<(), Errno> {
+        self.write(ptr, &value.to_le_bytes())
+    }
+
+    fn write_u64(&mut self, ptr: i32, value: u64) -> Result<(), Errno> {
+        self.write(ptr, &value.to_le_bytes())
+    }
+
+    fn string(&mut self, ptr: i32, len: i32) -> Result<String, Errno> {
+        String::from_utf8(self.slice(ptr, len as u32)?.to_vec()).map_err(|_| EINVAL)
+    }
+
+    /// The (pointer, length) pairs of an iovec array.
+    fn iovecs(&mut self, ptr: i32, len: i32) -> Result<Vec<(i32, u32)>, Errno> {
+        (0..len).map(|i| Ok((self.read_u32(ptr + i * 8)? as i32, self.read_u32(ptr + i * 8 + 4)?))).collect()
+    }
+
+    /// A 64-byte `filestat`.
+    fn write_filestat(&mut self, ptr: i32, filetype: u8, meta: Option<&std::fs::Metadata>) -> Result<(), Errno> {
+        let mut stat = [0u8; 64];
+        stat[16] = filetype;
+        if let Some(meta) = meta {
+            stat[24..32].copy_from_slice(&1u64.to_le_bytes());
+            stat[32..40].copy_from_slice(&meta.len().to_le_bytes());
+            let nanos = |t: std::io::Result<SystemTime>| t.ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |d| d.as_nanos() as u64);
+            stat[40..48].copy_from_slice(&nanos(meta.accessed()).to_le_bytes());
+            stat[48..56].copy_from_slice(&nanos(meta.modified()).to_le_bytes());
+            stat[56..64].copy_from_slice(&nanos(meta.modified()).to_le_bytes());
+        }
+        self.write(ptr, &stat)
+    }
+}
+
+/// Run `f` with the module's memory and the WASI state, as an errno.
+fn with_memory(caller: &mut Caller<'_, Wasi>, f: impl FnOnce(&mut Memory, &mut Wasi) -> Result<(), Errno>) -> i32 {
+    let Some(memory) = caller.get_export("memory").and_then(Extern::into_memory) else { return EFAULT as i32 };
+    let (data, wasi) = memory.data_and_store_mut(caller);
+    match f(&mut Memory(data), wasi) {
+        Ok(()) => SUCCESS as i32,
+        Err(errno) => errno as i32,
+    }
+}
+
+fn io_errno(e: std::io::Error) -> Errno {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => ENOENT,
+        std::io::ErrorKind::AlreadyExists => EEXIST,
+        std::io::ErrorKind::PermissionDenied => ENOTCAPABLE,
+        _ if e.raw_os_error() == Some(39) || e.raw_os_error() == Some(66) => ENOTEMPTY,
+        _ => EIO,
+    }
+}
+
+fn file_type(meta: &std::fs::Metadata) -> u8 {
+    if meta.is_dir() {
+        DIRECTORY
+    } else if meta.file_type().is_symlink() {
+        SYMBOLIC_LINK
+    } else {
+        REGULAR_FILE
+    }
+}
+
+/// Bytes that are random enough for hash seeds, without a dependency.
+fn random_bytes(buf: &mut [u8]) {
+    use std::hash::{BuildHasher, Hasher};
+    let state = std::collections::hash_map::RandomState::new();
+    for (i, chunk) in buf.chunks_mut(8).enumerate() {
+        let mut hasher = state.build_hasher();
+        hasher.write_usize(i);
+        chunk.copy_from_slice(&hasher.finish().to_le_bytes()[..chunk.len()]);
+    }
+}
+
+fn define_wasi(linker: &mut Linker<Wasi>) -> Result<()> {
+    linker.func_wrap(WASI, "args_sizes_get", |mut c: Caller<'_, Wasi>, argc: i32, size: i32| -> i32 {
+        with_memory(&mut c, |mem, wasi| {
+            mem.write_u32(argc, wasi.args.len() as u32)?;
+            mem.write_u32(size, wasi.args.iter().map(|a| a.len() as u32 + 1).sum())
+        })
+    })?;
+    linker.func_wrap(WASI, "args_get", |mut c: Caller<'_, Wasi>, argv: i32, buf: i32| -> i32 {
+        with_memory(&mut c, |mem, wasi| {
+            let mut at = buf;
+            for (i, arg) in wasi.args.iter().enumerate() {
+                mem.write_u32(argv + i as i32 * 4, at as u32)?;
+                mem.write(at, arg.as_bytes())?;
+                mem.write(at + arg.len() as i32, &[0])?;
+                at += arg.len() as i32 + 1;
+            }
+            Ok(())
+        })
+    })?;
+    linker.func_wrap(WASI, "environ_sizes_get", |mut c: Caller<'_, Wasi>, count: i32, size: i32| -> i32 {
+        with_memory(&mut c, |mem, _| {
+            mem.write_u32(count, 0)?;
+            mem.write_u32(size, 0)
+        })
+    })?;
+    linker.func_wrap(WASI, "environ_get", |_: Caller<'_, Wasi>, _: i32, _: i32| -> i32 { SUCCESS as i32 })?;
+    linker.func_wrap(WASI, "clock_res_get", |mut c: Caller<'_, Wasi>, _id: i32, res: i32| -> i32 {
+        with_memory(&mut c, |mem, _| mem.write_u64(res, 1_000))
+    })?;
+    linker.func_wrap(WASI, "clock_time_get", |mut c: Caller<'_, Wasi>, id: i32, _precision: i64, time: i32| -> i32 {
+        with_memory(&mut c, |mem, wasi| {
+            let nanos = match id {
+                0 => SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64),
+                1..=3 => wasi.started.elapsed().as_nanos() as u64,
+                _ => return Err(EINVAL),
+            };
+            mem.write_u64(time, nanos)
+        })
+    })?;
+    linker.func_wrap(WASI, "random_get", |mut c: Caller<'_, Wasi>, buf: i32, len: i32| -> i32 {
+        with_memory(&mut c, |mem, _| {
+            random_bytes(mem.slice(buf, len as u32)?);
+            Ok(())
+        })
+    })?;
+    linker.func_wrap(WASI, "proc_exit", |_: Caller<'_, Wasi>, code: i32| -> Result<(), wasmi::Error> {
+        Err(wasmi::Error::i32_exit(code))
+    })?;
+    linker.func_wrap(WASI, "sched_yield", |_: Caller<'_, Wasi>| -> i32 { SUCCESS as i32 })?;
+
+    linker.func_wrap(WASI, "fd_write", |mut c: Caller<'_, Wasi>, fd: i32, iovs: i32, iovs_len: i32, written: i32| -> i32 {
+        with_memory(&mut c, |mem, wasi| {
+            let mut total = 0u32;
+            for (ptr, len) in mem.iovecs(iovs, iovs_len)? {
+                let data = mem.slice(ptr, len)?;
+                match wasi.fd(fd)? {
+                    Fd::Stdout | Fd::Stderr => {
+                        let out = if fd == 1 { &mut wasi.stdout } else { &mut wasi.stderr };
+                        if out.len() + data.len() > MAX_OUTPUT {
+                            return Err(EFBIG);
+                        }
+                        out.extend_from_slice(data);
+                    }
+                    Fd::File { file, .. } => file.write_all(data).map_err(io_errno)?,
+                    Fd::Stdin => return Err(EBADF),
+                    Fd::Dir { .. } => return Err(EISDIR),
+                }
+                total += len;
+            }
+            mem.write_u32(written, total)
+        })
+    })?;
+    linker.func_wrap(WASI, "fd_read", |mut c: Caller<'_, Wasi>, fd: i32, iovs: i32, iovs_len: i32, read: i32| -> i32 {
+        with_memory(&mut c, |mem, wasi| {
+            let mut total = 0u32;
+            for (ptr, len) in mem.iovecs(iovs, iovs_len)? {
+                let buf = mem.slice(ptr, len)?;
+                let n = match wasi.fd(fd)? {
+                    Fd::Stdin => wasi.stdin.read(buf).map_err(io_errno)?,
+                    Fd::File { file, .. } => file.read(buf).map_err(io_errno)?,
+                    Fd::Stdout | Fd::Stderr => return Err(EBADF),
+                    Fd::Dir { .. } => return Err(EISDIR),
+                };
+                total += n as u32;
+                if n < len as usize {
+                    break;
+                }
+            }
+            mem.write_u32(read, total)
+        })
+    })?;
+    linker.func_wrap(WASI, "fd_close", |mut c: Caller<'_, Wasi>, fd: i32| -> i32 {
+        let wasi = c.data_mut();
+        match wasi.fd(fd) {
+            Ok(_) => {
+                wasi.fds[fd as usize] = None;
+                SUCCESS as i32
+            }
+            Err(errno) => errno as i32,
+        }
+    })?;
+    linker.func_wrap(WASI, "fd_seek", |mut c: Caller<'_, Wasi>, fd: i32, offset: i64, whence: i32, new_offset: i32| -> i32 {
+        with_memory(&mut c, |mem, wasi| {
+            let Fd::File { file, .. } = wasi.fd(fd)? else { return Err(ESPIPE) };
+            let from = match whence {
+                0 => SeekFrom::Start(u64::try_from(offset).map_err(|_| EINVAL)?),
+                1 => SeekFrom::Current(offset),
+                2 => SeekFrom::End(offset),
+                _ => return Err(EINVAL),
+            };
+            let position = file.seek(from).map_err(io_errno)?;
+            mem.write_u64(new_offset, position)
+        })
+    })?;
+    linker.func_wrap(WASI, "fd_tell", |mut c: Caller<'_, Wasi>, fd: i32, offset: i32| -> i32 {
+        with_memory(&mut c, |mem, wasi| {
+            let Fd::File { file, .. } = wasi.fd(fd)? else { return Err(ESPIPE) };
+            let position = file.stream_position().map_err(io_errno)?;
+            mem.write_u64(offset, position)
+        })
+    })?;
+    linker.func_wrap(WASI, "fd_fdstat_get", |mut c: Caller<'_, Wasi>, fd: i32, stat: i32| -> i32 {
+        with_memory(&mut c, |mem, wasi| {
+            let filetype = match wasi.fd(fd)? {
+                Fd::Stdin | Fd::Stdout | Fd::Stderr => CHARACTER_DEVICE,
+                Fd::Dir { .. } => DIRECTORY,
+                Fd::File { .. } => REGULAR_FILE,
+            };
+            let mut bytes = [0u8; 24];
+            bytes[0] = filetype;
+            // Rights are checked when a path is opened, not per call
+            bytes[8..16].copy_from_slice(&u64::MAX.to_le_bytes());
+            bytes[16..24].copy_from_slice(&u64::MAX.to_le_bytes());
+            mem.write(stat, &bytes)
+        })
+    })?;
+    linker.func_wrap(WASI, "fd_filestat_get", |mut c: Caller<'_, Wasi>, fd: i32, stat: i32| -> i32 {
+        with_memory(&mut c, |mem, wasi| match wasi.fd(fd)? {
+            Fd::Stdin | Fd::Stdout | Fd::Stderr => mem.write_filestat(stat, CHARACTER_DEVICE, None),
+            Fd::Dir { path, .. } | Fd::File { path, .. } => {
+                let meta = std::fs::metadata(&*path).map_err(io_errno)?;
+                mem.write_filestat(stat, file_type(&meta), Some(&meta))
+            }
+        })
+    })?;
+    linker.func_wrap(WASI, "fd_prestat_get", |mut c: Caller<'_, Wasi>, fd: i32, prestat: i32| -> i32 {
+        with_memory(&mut c, |mem, wasi| {
+            let Fd::Dir { guest: Some(guest), .. } = wasi.fd(fd)? else { return Err(EBADF) };
+            let mut bytes = [0u8; 8];
+            bytes[4..8].copy_from_slice(&(guest.len() as u32).to_le_bytes());
+            mem.write(prestat, &bytes)
+        })
+    })?;
+    linker.func_wrap(WASI, "fd_prestat_dir_name", |mut c: Caller<'_, Wasi>, fd: i32, path: i32, len: i32| -> i32 {
+        with_memory(&mut c, |mem, wasi| {
+            let Fd::Dir { guest: Some(guest), .. } = wasi.fd(fd)? else { return Err(EBADF) };
+            let name = guest.as_bytes();
+            mem.write(path, &name[..name.len().min(len as usize)])
+        })
+    })?;
+    linker.func_wrap(WASI, "fd_readdir", |mut c: Caller<'_, Wasi>, fd: i32, buf: i32, buf_len: i32, cookie: i64, used: i32| -> i32 {
+        with_memory(&mut c, |mem, wasi| {
+            let Fd::Dir { path, .. } = wasi.fd(fd)? else { return Err(ENOTDIR) };
+            let mut entries: Vec<(String, u8)> = std::fs::read_dir(&*path).map_err(io_errno)?
+                .filter_map(|e| e.ok())
+                .map(|e| (e.file_name().to_string_lossy().into_owned(), e.file_type().map_or(0, |t| {
+                    if t.is_dir() { DIRECTORY } else if t.is_symlink() { SYMBOLIC_LINK } else { REGULAR_FILE }
+                })))
+                .collect();
+            entries.sort();
+            let mut out = Vec::new();
+            for (i, (name, filetype)) in entries.iter().enumerate().skip(cookie.max(0) as usize) {
+                if out.len() >= buf_len as usize {
+                    break;
+                }
+                out.extend_from_slice(&(i as u64 + 1).to_le_bytes());
+                out.extend_from_slice(&0u64.to_le_bytes());
+                out.extend_from_slice(&(name.len() as u32).to_le_bytes());
+                out.extend_from_slice(&[*filetype, 0, 0, 0]);
+                out.extend_from_slice(name.as_bytes());
+            }
+            // A full buffer tells the caller to ask again from its last cookie
+            out.truncate(buf_len as usize);
+            mem.write(buf, &out)?;
+            mem.write_u32(used, out.len() as u32)
+        })
+    })?;
+
+    linker.func_wrap(WASI, "path_open", |mut c: Caller<'_, Wasi>, dirfd: i32, _lookup: i32, path: i32, path_len: i32, oflags: i32, rights: i64, _inheriting: i64, fdflags: i32, opened: i32| -> i32 {
+        with_memory(&mut c, |mem, wasi| {
+            let path = mem.string(path, path_len)?;
+            let (root, host, writable) = wasi.resolve(dirfd, &path)?;
+            let is_dir = std::fs::metadata(&host).is_ok_and(|m| m.is_dir());
+            let fd = if is_dir || oflags & O_DIRECTORY != 0 {
+                if !is_dir {
+                    return Err(ENOTDIR);
+                }
+                Fd::Dir { guest: None, root, path: host, writable }
+            } else {
+                let write = rights & RIGHT_FD_WRITE != 0 || oflags & (O_CREAT | O_TRUNC) != 0 || fdflags & FDFLAG_APPEND != 0;
+                if write && !writable {
+                    return Err(ENOTCAPABLE);
+                }
+                let file = std::fs::OpenOptions::new()
+                    .read(true)
+                    .write(write && fdflags & FDFLAG_APPEND == 0)
+                    .append(fdflags & FDFLAG_APPEND != 0)
+                    .create(oflags & O_CREAT != 0 && oflags & O_EXCL == 0)
+                    .create_new(oflags & O_CREAT != 0 && oflags & O_EXCL != 0)
+                    .truncate(oflags & O_TRUNC != 0)
+                    .open(&host)
+                    .map_err(io_errno)?;
+                Fd::File { file, path: host }
+            };
+            let fd = wasi.insert(fd);
+            mem.write_u32(opened, fd)
+        })
+    })?;
+    linker.func_wrap(WASI, "path_filestat_get", |mut c: Caller<'_, Wasi>, dirfd: i32, _flags: i32, path: i32, path_len: i32, stat: i32| -> i32 {
+        with_memory(&mut c, |mem, wasi| {
+            let path = mem.string(path, path_len)?;
+            let (_, host, _) = wasi.resolve(dirfd, &path)?;
+            let meta = std::fs::metadata(&host).map_err(io_errno)?;
+            mem.write_filestat(stat, file_type(&meta), Some(&meta))
+        })
+    })?;
+    linker.func_wrap(WASI, "path_create_directory", |mut c: Caller<'_, Wasi>, dirfd: i32, path: i32, path_len: i32| -> i32 {
+        with_memory(&mut c, |mem, wasi| {
+            let path = mem.string(path, path_len)?;
+            let (_, host, writable) = wasi.resolve(dirfd, &path)?;
+            if !writable {
+                return Err(ENOTCAPABLE);
+            }
+            std::fs::create_dir(host).map_err(io_errno)
+        })
+    })?;
+    linker.func_wrap(WASI, "path_remove_directory", |mut c: Caller<'_, Wasi>, dirfd: i32, path: i32, path_len: i32| -> i32 {
+        with_memory(&mut c, |mem, wasi| {
+            let path = mem.string(path, path_len)?;
+            let (root, host, writable) = wasi.resolve(dirfd, &path)?;
+            if !writable || host == root {
+                return Err(ENOTCAPABLE);
+            }
+            std::fs::remove_dir(host).map_err(io_errno)
+        })
+    })?;
+    linker.func_wrap(WASI, "path_unlink_file", |mut c: Caller<'_, Wasi>, dirfd: i32, path: i32, path_len: i32| -> i32 {
+        with_memory(&mut c, |mem, wasi| {
+            let path = mem.string(path, path_len)?;
+            let (_, host, writable) = wasi.resolve(dirfd, &path)?;
+            if !writable {
+                return Err(ENOTCAPABLE);
+            }
+            if host.is_dir() {
+                return Err(EISDIR);
+            }
+            std::fs::remove_file(host).map_err(io_errno)
+        })
+    })?;
+    linker.func_wrap(WASI, "path_rename", |mut c: Caller<'_, Wasi>, old_fd: i32, old: i32, old_len: i32, new_fd: i32, new: i32, new_len: i32| -> i32 {
+        with_memory(&mut c, |mem, wasi| {
+            let (old, new) = (mem.string(old, old_len)?, mem.string(new, new_len)?);
+            let (_, from, from_writable) = wasi.resolve(old_fd, &old)?;
+            let (_, to, to_writable) = wasi.resolve(new_fd, &new)?;
+            if !from_writable || !to_writable {
+                return Err(ENOTCAPABLE);
+            }
+            std::fs::rename(from, to).map_err(io_errno)
+        })
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A WASI command that copies stdin to stdout, then writes `suffix`.
+    fn echo_module(suffix: &str) -> Vec<u8> {
+        wat::parse_str(format!(r#"
+            (module
+              (import "wasi_snapshot_preview1" "fd_read" (func $read (param i32 i32 i32 i32) (result i32)))
+              (import "wasi_snapshot_preview1" "fd_write" (func $write (param i32 i32 i32 i32) (result i32)))
+              (memory (export "memory") 1)
+              (data (i32.const 512) "{suffix}")
+              (func (export "_start")
+                ;; iovec at 0: buffer at 1024, 4096 bytes
+                (i32.store (i32.const 0) (i32.const 1024))
+                (i32.store (i32.const 4) (i32.const 4096))
+                (drop (call $read (i32.const 0) (i32.const 0) (i32.const 1) (i32.const 8)))
+                (i32.store (i32.const 4) (i32.load (i32.const 8)))
+                (drop (call $write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))
+                (i32.store (i32.const 0) (i32.const 512))
+                (i32.store (i32.const 4) (i32.const {len}))
+                (drop (call $write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))))
+        "#, suffix = suffix, len = suffix.len())).unwrap()
+    }
+
+    #[test]
+    fn test_runs_a_wasi_command() {
+        let out = run_bytes("echo.wasm", &echo_module("!"), b"hello".to_vec(), &WasmOptions::default()).unwrap();
+        assert_eq!(out.stdout, "hello!");
+        assert_eq!(out.exit_code, 0);
+        assert!(out.fuel_used > 0);
+    }
+
+    #[test]
+    fn test_exit_status_fuel_and_foreign_imports() {
+        let exit = wat::parse_str(r#"
+            (module
+              (import "wasi_snapshot_preview1" "proc_exit" (func $exit (param i32)))
+              (memory (export "memory") 1)
+              (func (export "_start") (call $exit (i32.const 3))))
+        "#).unwrap();
+        assert_eq!(run_bytes("exit.wasm", &exit, Vec::new(), &WasmOptions::default()).unwrap().exit_code, 3);
+
+        let spin = wat::parse_str(r#"(module (memory (export "memory") 1) (func (export "_start") (loop (br 0))))"#).unwrap();
+        let options = WasmOptions { fuel: 10_000, ..Default::default() };
+        let err = run_bytes("spin.wasm", &spin, Vec::new(), &options).unwrap_err();
+        assert!(err.to_string().contains("ran out of fuel"), "{}", err);
+
+        let socket = wat::parse_str(r#"
+            (module
+              (import "env" "connect" (func (param i32) (result i32)))
+              (memory (export "memory") 1)
+              (func (export "_start")))
+        "#).unwrap();
+        let err = run_bytes("net.wasm", &socket, Vec::new(), &WasmOptions::default()).unwrap_err();
+        assert!(err.to_string().contains("only WASI"), "{}", err);
+    }
+
+    #[test]
+    fn test_paths_stay_inside_mapped_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("data")).unwrap();
+        std::fs::write(dir.path().join("data/in.txt"), "x").unwrap();
+        std::fs::write(dir.path().join("secret.txt"), "s").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(dir.path().join("secret.txt"), dir.path().join("data/link")).unwrap();
+        let mut wasi = Wasi {
+            args: Vec::new(),
+            stdin: std::io::Cursor::new(Vec::new()),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            fds: vec![None, None, None],
+            started: Instant::now(),
+            limits: StoreLimitsBuilder::new().build(),
+        };
+        let root = dir.path().join("data").canonicalize().unwrap();
+        wasi.fds.push(Some(Fd::Dir { guest: Some("/data".into()), root: root.clone(), path: root.clone(), writable: false }));
+        assert_eq!(wasi.resolve(3, "in.txt").unwrap().1, root.join("in.txt"));
+        assert_eq!(wasi.resolve(3, "sub/../new.txt").unwrap().1, root.join("new.txt"));
+        assert_eq!(wasi.resolve(3, "../secret.txt").unwrap_err(), ENOTCAPABLE);
+        assert_eq!(wasi.resolve(3, "/etc/passwd").unwrap_err(), ENOTCAPABLE);
+        #[cfg(unix)]
+        assert_eq!(wasi.resolve(3, "link").unwrap_err(), ENOTCAPABLE);
+        assert_eq!(wasi.resolve(0, "in.txt").unwrap_err(), EBADF);
+    }
+}
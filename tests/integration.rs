@@ -1,5 +1,5 @@
-/// Integration tests for Cognos.
-/// Each test runs a .cog file and checks stdout/stderr/exit code.
+//! Integration tests for Cognos.
+//! Each test runs a .cog file and checks stdout/stderr/exit code.
 
 use std::process::Command;
 use std::path::PathBuf;
@@ -424,6 +424,101 @@ fn test_infinite_loop_with_break() {
     assert_eq!(out.trim(), "0\n1\n2");
 }
 
+#[test]
+fn test_lambdas_and_list_methods() {
+    let out = expect_run_ok(concat!(
+        "flow shout(s: String) -> String:\n",
+        "    return s.upper()\n",
+        "\n",
+        "flow main():\n",
+        "    rate = 2\n",
+        "    scale = |x| x * rate\n",
+        "    rate = 100\n",
+        "    emit(scale(21))\n",
+        "    emit(scale)\n",
+        "    nums = [3, 1, 2]\n",
+        "    emit(nums.map(|n| n * 10))\n",
+        "    emit(nums.filter(|n| n > 1))\n",
+        "    emit(nums.reduce(|acc, n| acc + n))\n",
+        "    emit(nums.reduce(|acc, n| acc + [n], []))\n",
+        "    people = [{\"name\": \"bo\", \"age\": 30}, {\"name\": \"al\", \"age\": 25}, {\"name\": \"cy\", \"age\": 30}]\n",
+        "    emit(people.sort_by(|p| p[\"age\"]).map(|p| p[\"name\"]).join(\",\"))\n",
+        "    emit([\"a\", \"b\"].map(\"shout\"))\n",
+        "    try:\n",
+        "        scale(1, 2)\n",
+        "    catch e:\n",
+        "        emit(e.message)\n",
+        "    try:\n",
+        "        [].reduce(|acc, n| acc)\n",
+        "    catch e:\n",
+        "        emit(e.message)\n",
+        "    try:\n",
+        "        [1, \"x\"].sort_by(|v| v)\n",
+        "    catch e:\n",
+        "        emit(e.message)\n",
+    ));
+    assert_eq!(out.trim(), concat!(
+        "42\n",
+        "|x| x * rate\n",
+        "[30, 10, 20]\n",
+        "[3, 2]\n",
+        "6\n",
+        "[3, 1, 2]\n",
+        "al,bo,cy\n",
+        "[A, B]\n",
+        "lambda |x| x * rate takes 1 argument(s), got 2\n",
+        ".reduce() of an empty list needs an initial value: .reduce(f, initial)\n",
+        ".sort_by() keys must be all numbers or all Strings, got String and Int",
+    ));
+}
+
+#[test]
+fn test_while_loop() {
+    let out = expect_run_ok(concat!(
+        "flow main():\n",
+        "    queue = [1, 2, 3, 4]\n",
+        "    while queue.length > 0:\n",
+        "        job = queue[0]\n",
+        "        queue = queue[1:]\n",
+        "        if job == 2:\n",
+        "            continue\n",
+        "        if job == 4:\n",
+        "            break\n",
+        "        emit(job)\n",
+        "    while false:\n",
+        "        emit(\"never\")\n",
+        "    emit(queue.length)\n",
+    ));
+    assert_eq!(out.trim(), "1\n3\n0");
+}
+
+#[test]
+fn test_match_dispatches_and_binds() {
+    let out = expect_run_ok(r#"type Intent: "refund" | "question" | "other"
+
+flow route(msg: Map) -> String:
+    match msg:
+        case {"intent": "refund", "order": {"id": id}}:
+            return f"refund {id}"
+        case {"intent": "question"} | {"intent": "other"}:
+            return "faq"
+        case {"intent": intent}:
+            return f"unknown {intent}"
+
+flow main():
+    emit(route({"intent": "refund", "order": {"id": 7}, "note": "late"}))
+    emit(route({"intent": "other"}))
+    emit(route({"intent": "refund"}))
+    match 2:
+        case 1:
+            emit("one")
+    match none:
+        case "x" | none:
+            emit("nothing")
+"#);
+    assert_eq!(out.trim(), "refund 7\nfaq\nunknown refund\nnothing");
+}
+
 // ─── For loop tests ───
 
 #[test]
@@ -890,6 +985,175 @@ fn test_fstring_edge_cases() {
     assert_eq!(out.trim(), "len=5");
 }
 
+#[test]
+fn test_fstring_format_specs_and_float_display() {
+    let src = r#"
+flow main:
+    x = 0.1 + 0.2
+    s = "ab"
+    items = [1, 2, 3, 4]
+    write(stdout, x)
+    write(stdout, [1.1 * 3])
+    write(stdout, f"{x:.2f}|{1234567.891:,.2f}|{-3.5:+08.1f}|{42:>6}|{s:^6}|{s:*<5}|{0.256:.1%}")
+    write(stdout, f"{12345.678:.2e}|{7:03d}|{7:+d}|{s:.1}|{3.14159:.3}|{items[1:3]}")
+    write(stdout, round(2.675, 2))
+    write(stdout, round(2.5))
+    write(stdout, round(-2.5))
+    write(stdout, round(7, 2))
+    try:
+        write(stdout, f"{s:d}")
+    catch err:
+        write(stdout, err.message)
+"#;
+    let (out, err, code) = run_inline(src, "");
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out.lines().collect::<Vec<_>>(), [
+        "0.3",
+        "[3.3]",
+        "0.30|1,234,567.89|-00003.5|    42|  ab  |ab***|25.6%",
+        "1.23e4|007|+7|a|3.142|[2, 3]",
+        "2.67",
+        "3",
+        "-3",
+        "7",
+        "format spec 'd' requires an Int, got ab (String)",
+    ]);
+
+    let (_, err, code) = run_inline("flow main:\n    write(stdout, f\"{1:.x}\")\n", "");
+    assert_ne!(code, 0);
+    assert!(err.contains("invalid format spec '.x'"), "stderr: {}", err);
+}
+
+#[test]
+fn test_fstring_literal_braces() {
+    let src = r#"
+flow main:
+    name = "ada"
+    width = 3
+    write(stdout, f"{{\"name\": \"{name}\"}}")
+    write(stdout, f"\{literal\} {{{width}}} }} {name:>5}|")
+    write(stdout, f"""{{"list": [{width}]}}""")
+"#;
+    let (out, err, code) = run_inline(src, "");
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out.lines().collect::<Vec<_>>(), [
+        r#"{"name": "ada"}"#,
+        "{literal} {3} }   ada|",
+        r#"{"list": [3]}"#,
+    ]);
+}
+
+#[test]
+fn test_raw_and_multiline_strings() {
+    let src = "flow main:\n    prompt = \"\"\"You review code.\n    Reply \"ok\" or a list.\"\"\"\n    write(stdout, prompt)\n    pattern = r\"\\d+\\.\\d\"\n    write(stdout, pattern)\n    write(stdout, pattern.length)\n";
+    let (out, err, code) = run_inline(src, "");
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out, "You review code.\n    Reply \"ok\" or a list.\n\\d+\\.\\d\n7\n");
+}
+
+#[test]
+fn test_triple_quoted_fstring_prompt_block() {
+    let src = r#"
+flow main:
+    style = "naming"
+    items = ["a", "b"]
+    if true:
+        prompt = f"""
+            Review for {style} issues.
+              Quote "exact lines"; answer as {{"ok": bool}}.
+
+            {items.join(", ")}
+            """
+        write(stdout, prompt)
+        write(stdout, "|")
+"#;
+    let (out, err, code) = run_inline(src, "");
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out, "Review for naming issues.\n  Quote \"exact lines\"; answer as {\"ok\": bool}.\n\na, b\n|\n");
+}
+
+#[test]
+fn test_number_literal_forms() {
+    let src = r#"
+flow main:
+    budget = 1_000_000
+    write(stdout, budget + 0xFF)
+    write(stdout, 1.5e6)
+    write(stdout, 2.5e-3 * 2)
+    write(stdout, type_of(1e3))
+"#;
+    let (out, err, code) = run_inline(src, "");
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out.lines().collect::<Vec<_>>(), ["1000255", "1500000", "0.005", "Float"]);
+
+    let (_, err, code) = run_inline("flow main:\n    x = 99999999999999999999\n", "");
+    assert_ne!(code, 0);
+    assert!(err.contains("invalid number literal '99999999999999999999'"), "stderr: {}", err);
+}
+
+#[test]
+fn test_chained_comparisons_and_unary_minus() {
+    let src = r#"
+flow check(x: Int) -> Int:
+    log(f"checked {x}")
+    return x
+
+flow main:
+    x = 5
+    a = 2
+    b = 3
+    write(stdout, 0 <= x < 10)
+    write(stdout, 0 <= x < 5)
+    write(stdout, 1 < 2 < 3 == 3 != 4)
+    write(stdout, 10 < check(x) < 20)
+    write(stdout, -x)
+    write(stdout, -(a + b) * 2)
+    write(stdout, --x)
+    write(stdout, -[1, 2].length)
+    write(stdout, -2.5 - -a)
+    try:
+        write(stdout, -"abc")
+    catch err:
+        write(stdout, err.message)
+"#;
+    let (out, err, code) = run_inline(src, "");
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out.lines().collect::<Vec<_>>(), [
+        "true", "false", "true", "false", "-5", "-10", "5", "-2", "-0.5",
+        "cannot negate abc (type: String)",
+    ]);
+    // The middle operand is evaluated once
+    assert_eq!(err.matches("checked 5").count(), 1, "stderr: {}", err);
+}
+
+#[test]
+fn test_and_or_short_circuit_and_return_operands() {
+    let src = r#"
+flow side(x: String) -> String:
+    log(f"ran {x}")
+    return x
+
+flow main:
+    empty = ""
+    write(stdout, empty or "default")
+    write(stdout, "given" or side("unused-or"))
+    write(stdout, none and side("unused-and"))
+    write(stdout, [1] and {"k": 2})
+    write(stdout, 0 or [] or none)
+    write(stdout, true and false)
+    write(stdout, not "x")
+    if empty or side("ran"):
+        write(stdout, "branch")
+"#;
+    let (out, err, code) = run_inline(src, "");
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out.lines().collect::<Vec<_>>(), [
+        "default", "given", "none", "{\"k\": 2}", "none", "false", "false", "branch",
+    ]);
+    assert!(!err.contains("unused"), "stderr: {}", err);
+    assert!(err.contains("ran ran"), "stderr: {}", err);
+}
+
 #[test]
 fn test_nested_control_flow() {
     let out = expect_run_ok(concat!(
@@ -1222,7 +1486,7 @@ flow main():
     try:
         x = 1 / 0
     catch err:
-        write(stdout, f"Error: {err}")
+        write(stdout, f"Error: {err.message}")
 "#;
     let (out, _, code) = run_inline(src, "");
     assert_eq!(code, 0);
@@ -1244,6 +1508,109 @@ flow main():
     assert_eq!(out.trim(), "x=42");
 }
 
+#[test]
+fn test_catch_by_kind() {
+    let src = r#"
+flow inner():
+    x = 1 / 0
+
+flow main():
+    try:
+        inner()
+    catch e:
+        write(stdout, f"{e.kind} in {e.source}: {e.message} (line {e.line})")
+    try:
+        load("missing.json")
+    catch ParseError:
+        write(stdout, "wrong clause")
+    catch IOError as e:
+        write(stdout, e.kind)
+    try:
+        try:
+            x = [1][5]
+        catch NetworkError:
+            write(stdout, "wrong clause")
+    catch Error as e:
+        write(stdout, f"outer caught {e.kind}")
+    try:
+        x = [1][5]
+    catch RateLimitError:
+        write(stdout, "unreachable")
+"#;
+    let (out, err, code) = run_inline(src, "");
+    assert_eq!(code, 1, "an error no clause takes goes on unwinding");
+    assert_eq!(out, concat!(
+        "RuntimeError in inner: division by zero (line 3)\n",
+        "IOError\n",
+        "outer caught RuntimeError\n",
+    ));
+    assert!(err.contains("out of"), "stderr: {}", err);
+}
+
+#[test]
+fn test_catch_network_and_type_errors() {
+    let src = r#"type Review:
+    score: Int
+
+flow main():
+    try:
+        think("a")
+    catch RateLimitError as e:
+        print(e.kind)
+    try:
+        think("b")
+    catch NetworkError as e:
+        print(e.kind)
+    try:
+        think("c", format="Review")
+    catch TypeError as e:
+        print(e.kind)
+    try:
+        think("d", format="json")
+    catch ParseError as e:
+        print(e.kind)
+"#;
+    let dir = tempfile::tempdir().unwrap();
+    let cog = dir.path().join("test.cog");
+    let mock = dir.path().join("mock.json");
+    std::fs::write(&cog, src).unwrap();
+    std::fs::write(&mock, serde_json::json!({"llm_responses": [
+        {"error": "slow down", "status": 429},
+        {"error": "HTTP error: operation timed out", "transient": true},
+        "{\"score\": \"high\"}",
+        "not json",
+    ]}).to_string()).unwrap();
+    let output = Command::new(cognos_bin())
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
+        .output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.starts_with("RateLimitError\nNetworkError\nTypeError\nParseError\n"), "got: {}", stdout);
+}
+
+#[test]
+fn test_error_kind_ignores_message_text() {
+    let src = r#"flow main():
+    try:
+        int("budget exceeded (500 tokens)")
+    catch NetworkError:
+        print("wrong clause")
+    catch e:
+        print(e.kind)
+    try:
+        float("connection reset (os error 104)")
+    catch e:
+        print(e.kind)
+    try:
+        read(file("no/such/file.txt"))
+    catch IOError as e:
+        print(e.kind)
+"#;
+    let (out, err, code) = run_inline(src, "");
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out, "RuntimeError\nRuntimeError\nIOError\n");
+}
+
 // ─── Save/Load ───
 
 #[test]
@@ -1276,7 +1643,7 @@ flow main():
 fn run_test(cog_file: &str, env_file: &str) -> (String, String, i32) {
     let bin = cognos_bin();
     let output = Command::new(&bin)
-        .args(&["test", &format!("examples/{}", cog_file), "--env", &format!("examples/mocks/{}", env_file)])
+        .args(["test", &format!("examples/{}", cog_file), "--env", &format!("examples/mocks/{}", env_file)])
         .stdout(std::process::Stdio::piped())
         .stderr(std::process::Stdio::piped())
         .output()
@@ -1364,7 +1731,7 @@ flow main():
 
     let bin = cognos_bin();
     Command::new(&bin)
-        .args(&["run", "--session", session.to_str().unwrap(), cog.to_str().unwrap()])
+        .args(["run", "--session", session.to_str().unwrap(), cog.to_str().unwrap()])
         .output().unwrap();
 
     assert!(session.exists(), "session file should be created");
@@ -1394,7 +1761,7 @@ flow main():
 
     let bin = cognos_bin();
     let output = Command::new(&bin)
-        .args(&["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
         .output().unwrap();
     let stderr = String::from_utf8_lossy(&output.stderr).to_string();
     assert!(stderr.contains("missing field 'summary'"), "got: {}", stderr);
@@ -1418,7 +1785,7 @@ flow main():
 
     let bin = cognos_bin();
     let output = Command::new(&bin)
-        .args(&["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
         .output().unwrap();
     let stderr = String::from_utf8_lossy(&output.stderr).to_string();
     assert!(stderr.contains("field 'score': expected Int"), "got: {}", stderr);
@@ -1442,7 +1809,7 @@ flow main():
 
     let bin = cognos_bin();
     let output = Command::new(&bin)
-        .args(&["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
         .output().unwrap();
     let stdout = String::from_utf8_lossy(&output.stdout).to_string();
     assert!(stdout.contains("score=8"), "got: {}", stdout);
@@ -1571,6 +1938,314 @@ flow main():
     assert!(err.contains("cannot import") || err.contains("No such file"), "got: {}", err);
 }
 
+#[test]
+fn test_diamond_import_loads_once() {
+    let dir = tempfile::tempdir().unwrap();
+    let lib = dir.path().join("lib");
+    std::fs::create_dir(&lib).unwrap();
+    std::fs::write(lib.join("util.cog"), "flow shout(s: String) -> String:\n    return s.upper()\n").unwrap();
+    std::fs::write(lib.join("a.cog"), "import \"util.cog\"\nflow a() -> String:\n    return shout(\"a\")\n").unwrap();
+    std::fs::write(lib.join("b.cog"), "import \"./util.cog\"\nflow b() -> String:\n    return shout(\"b\")\n").unwrap();
+    let main = dir.path().join("main.cog");
+    std::fs::write(&main, "import \"lib/a.cog\"\nimport \"lib/b.cog\"\nimport \"lib/util.cog\"\nflow main():\n    write(stdout, a() + b())\n").unwrap();
+
+    let output = Command::new(cognos_bin()).arg("run").arg(&main).output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "stderr: {}", stderr);
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "AB");
+    assert!(!stderr.contains("Warning"), "unexpected warning: {}", stderr);
+}
+
+#[test]
+fn test_resource_embeds_file_relative_to_source() {
+    let dir = tempfile::tempdir().unwrap();
+    let lib = dir.path().join("lib");
+    std::fs::create_dir_all(lib.join("prompts")).unwrap();
+    std::fs::write(lib.join("prompts/system.md"), "Be brief.\n").unwrap();
+    std::fs::write(lib.join("reviewer.cog"), "flow system_prompt() -> String:\n    return resource(\"prompts/system.md\")\n").unwrap();
+    let main = dir.path().join("main.cog");
+    std::fs::write(&main, "import \"lib/reviewer.cog\"\nflow main():\n    write(stdout, system_prompt())\n").unwrap();
+    // Run from elsewhere: the path is relative to reviewer.cog, not the cwd
+    let output = Command::new(cognos_bin()).arg("run").arg(&main).current_dir(std::env::temp_dir()).output().unwrap();
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Be brief.\n\n");
+
+    // A missing resource fails at parse time, before anything runs
+    std::fs::write(&main, "flow main():\n    write(stdout, \"started\")\n    x = resource(\"missing.md\")\n").unwrap();
+    let output = Command::new(cognos_bin()).arg("run").arg(&main).output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(stderr.contains("Parse error") && stderr.contains("resource(\"missing.md\"): cannot read"), "got: {}", stderr);
+
+    std::fs::write(&main, "flow main():\n    p = \"a.md\"\n    x = resource(p)\n").unwrap();
+    let output = Command::new(cognos_bin()).arg("run").arg(&main).output().unwrap();
+    assert!(String::from_utf8_lossy(&output.stderr).contains("resource() takes one string literal path"));
+}
+
+#[test]
+fn test_bundle_runs_without_the_source_tree() {
+    let dir = tempfile::tempdir().unwrap();
+    let app = dir.path().join("app");
+    std::fs::create_dir_all(app.join("lib/prompts")).unwrap();
+    std::fs::write(app.join("lib/prompts/greeting.md"), "Hello from the bundle").unwrap();
+    std::fs::write(app.join("lib/util.cog"), "flow shout(s: String) -> String:\n    return s.upper()\n").unwrap();
+    std::fs::write(app.join("lib/greet.cog"), "import \"./util.cog\"\nGREETING = resource(\"prompts/greeting.md\")\nflow greet() -> String:\n    return shout(GREETING)\n").unwrap();
+    std::fs::write(app.join("main.cog"), "from \"lib/greet.cog\" import greet\nflow main():\n    write(stdout, greet())\n").unwrap();
+
+    let out = dir.path().join("agent.cogx");
+    let output = Command::new(cognos_bin()).arg("bundle").arg(app.join("main.cog")).arg("-o").arg(&out).output().unwrap();
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Bundled 3 module(s) and 1 resource(s)"));
+    let bundle: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&out).unwrap()).unwrap();
+    assert_eq!(bundle["manifest"]["entry"], "main.cog");
+    assert_eq!(bundle["manifest"]["modules"], serde_json::json!(["lib/greet.cog", "lib/util.cog", "main.cog"]));
+    assert_eq!(bundle["manifest"]["resources"], serde_json::json!(["lib/prompts/greeting.md"]));
+
+    // The original tree is gone; the bundle is all that's left
+    std::fs::remove_dir_all(&app).unwrap();
+    let output = Command::new(cognos_bin()).arg("run").arg(&out).output().unwrap();
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "HELLO FROM THE BUNDLE");
+
+    std::fs::write(&out, r#"{"manifest": {"format": 99, "entry": "main.cog"}, "modules": {}}"#).unwrap();
+    let output = Command::new(cognos_bin()).arg("run").arg(&out).output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("bundle format 99"));
+}
+
+#[test]
+fn test_build_embeds_program_in_executable() {
+    let dir = tempfile::tempdir().unwrap();
+    let app = dir.path().join("app");
+    std::fs::create_dir_all(app.join("lib")).unwrap();
+    std::fs::write(app.join("lib/util.cog"), "flow shout(s: String) -> String:\n    return s.upper()\n").unwrap();
+    std::fs::write(app.join("main.cog"), "import \"lib/util.cog\"\nflow main(name: String):\n    write(stdout, shout(\"hi \" + name))\n").unwrap();
+
+    let exe = dir.path().join("my-agent");
+    let output = Command::new(cognos_bin()).arg("build").arg(app.join("main.cog")).arg("-o").arg(&exe).output().unwrap();
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    std::fs::remove_dir_all(&app).unwrap();
+
+    use std::io::Write;
+    let mut child = Command::new(&exe).arg("-q").current_dir(dir.path())
+        .stdin(std::process::Stdio::piped()).stdout(std::process::Stdio::piped()).stderr(std::process::Stdio::piped())
+        .spawn().unwrap();
+    child.stdin.take().unwrap().write_all(b"ops\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "HI OPS");
+}
+
+#[test]
+fn test_cognos_shell_and_backslash_paths() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(dir.path().join("lib")).unwrap();
+    std::fs::write(dir.path().join("lib/util.cog"), "flow shell_name() -> String:\n    return __exec_shell__(\"echo ${BASH_VERSION:+bash}\").strip()\n").unwrap();
+    std::fs::write(dir.path().join("lib/note.txt"), "from a file").unwrap();
+    std::fs::write(dir.path().join("main.cog"), "import \"lib\\\\util.cog\"\nflow main():\n    write(stdout, shell_name())\n    write(stdout, read(file(\"lib\\\\note.txt\")))\n").unwrap();
+
+    let output = Command::new(cognos_bin()).args(["run", "--allow-shell", "main.cog"]).current_dir(dir.path())
+        .env("COGNOS_SHELL", "bash").output().unwrap();
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "bash\nfrom a file\n");
+
+    let output = Command::new(cognos_bin()).args(["run", "--allow-shell", "main.cog"]).current_dir(dir.path())
+        .env("COGNOS_SHELL", "no-such-shell").output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("cannot start shell 'no-such-shell'"));
+}
+
+#[test]
+fn test_prompt_user_without_a_terminal() {
+    let src = "flow main():\n    env = prompt_user(\"Deploy to?\", choices=[\"staging\", \"prod\"])\n    ok = prompt_user(\"Proceed?\", choices=[\"yes\", \"no\"], default=\"no\")\n    who = prompt_user(\"Your name?\", default=\"ops\")\n    write(stdout, f\"{env} {ok} {who}\")\n";
+    // By number, after a bad answer; by name, any case; empty takes the default
+    let (stdout, stderr, code) = run_inline(src, "3\n2\nYES\n\n");
+    assert_eq!(code, 0, "stderr: {}", stderr);
+    assert_eq!(stdout, "Deploy to?\n  1) staging\n  2) prod\nPlease answer 1-2 or one of: staging, prod\nProceed? (no)\n  1) yes\n  2) no\nYour name? (ops)\nprod yes ops\n");
+
+    // At end of input a default is taken; without one it's an error
+    let (stdout, _, code) = run_inline(src, "1\n");
+    assert_eq!(code, 0);
+    assert!(stdout.ends_with("staging no ops\n"), "got: {}", stdout);
+    let (_, stderr, code) = run_inline(src, "");
+    assert_ne!(code, 0);
+    assert!(stderr.contains("prompt_user(): no answer (end of input)"), "got: {}", stderr);
+
+    assert!(expect_error("flow main():\n    x = prompt_user(\"?\", choices=[\"a\"], default=\"b\")\n").contains("default 'b' is not one of the choices"));
+    assert!(expect_error("flow main():\n    x = prompt_user(\"?\", choices=[])\n").contains("choices must be a non-empty List"));
+}
+
+#[test]
+fn test_with_block_closes_handles_on_exit() {
+    let dir = tempfile::tempdir().unwrap();
+    let log = dir.path().join("out.log");
+    std::fs::write(&log, "stale\n").unwrap();
+    let src = format!(r#"flow slow() -> String:
+    return __exec_shell__("sleep 2")
+
+flow main():
+    with file("{log}") as f:
+        write(f, "one\n")
+        write(f, "two\n")
+    write(file("{log}", mode="append"), "three\n")
+    try:
+        with file("{log}", mode="append") as f:
+            write(f, "four\n")
+            x = 1 / 0
+    catch err:
+        write(stdout, "caught")
+    with async slow() as task:
+        write(stdout, task.done())
+    try:
+        await(task)
+    catch err:
+        write(stdout, err.message)
+"#, log = log.display());
+    let path = dir.path().join("with.cog");
+    std::fs::write(&path, src).unwrap();
+    let trace = dir.path().join("trace.jsonl");
+    let output = Command::new(cognos_bin()).args(["run", "--allow-shell"]).arg(&path).arg("--trace").arg(&trace).output().unwrap();
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    // The task left running at block exit is cancelled, not awaited
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "caught\nfalse\ninvalid or already-consumed future handle 0\n");
+    assert_eq!(std::fs::read_to_string(&log).unwrap(), "one\ntwo\nthree\nfour\n");
+    let closes = std::fs::read_to_string(&trace).unwrap().lines()
+        .filter(|l| l.contains("\"op\":\"close\""))
+        .count();
+    assert_eq!(closes, 2);
+
+    assert!(expect_error("flow main():\n    with 5 as x:\n        pass\n").contains("with needs a handle or a future, got Int"));
+    assert!(expect_error("flow main():\n    f = file(\"x\", mode=\"rw\")\n").contains("mode must be \"write\" or \"append\""));
+}
+
+#[test]
+fn test_from_import_selects_names_and_facades_reexport() {
+    let dir = tempfile::tempdir().unwrap();
+    let lib = dir.path().join("lib");
+    std::fs::create_dir(&lib).unwrap();
+    std::fs::write(lib.join("agent.cog"), r#"flow helper(s: String) -> String:
+    return s.upper()
+
+flow agent_think(s: String) -> String:
+    return helper(s)
+
+flow exec(s: String) -> String:
+    return f"exec {s}"
+
+flow unrelated():
+    pass
+"#).unwrap();
+    // A facade that re-exports part of agent.cog
+    std::fs::write(lib.join("facade.cog"), "from \"agent.cog\" import agent_think\n").unwrap();
+    let main = dir.path().join("main.cog");
+    std::fs::write(&main, r#"from "lib/facade.cog" import agent_think
+from "lib/agent.cog" import exec
+
+flow main():
+    emit(agent_think("hi"))
+    emit(exec("ls"))
+    unrelated()
+"#).unwrap();
+    let output = Command::new(cognos_bin()).arg("run").arg(&main).output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    // agent_think brings its helper along, but unrelated() was not imported
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "HI\nexec ls\n");
+    assert!(!output.status.success());
+    assert!(stderr.contains("unrelated"), "got: {}", stderr);
+
+    std::fs::write(&main, "from \"lib/agent.cog\" import exec, helper\nflow main():\n    emit(exec(\"x\"))\n").unwrap();
+    let output = Command::new(cognos_bin()).arg("run").arg(&main).output().unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("'helper' is imported from 'lib/agent.cog' but never used"), "got: {}", stderr);
+    assert!(!stderr.contains("'exec'"), "got: {}", stderr);
+
+    std::fs::write(&main, "from \"lib/facade.cog\" import exec\nflow main():\n    emit(exec(\"x\"))\n").unwrap();
+    let output = Command::new(cognos_bin()).arg("run").arg(&main).output().unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("'exec' is not defined in 'lib/facade.cog'"), "got: {}", stderr);
+}
+
+#[test]
+fn test_imported_top_level_code_runs_once_and_sets_globals() {
+    let dir = tempfile::tempdir().unwrap();
+    let lib = dir.path().join("lib");
+    std::fs::create_dir(&lib).unwrap();
+    // Setup can use its own flows and what its imports set up
+    std::fs::write(lib.join("base.cog"), "emit(\"base setup\")\nSCALE = 10\n").unwrap();
+    std::fs::write(lib.join("labels.cog"), r#"import "base.cog"
+
+flow weight(n: Int) -> Int:
+    return n * SCALE
+
+emit("labels setup")
+PRIORITY = {"bug": weight(1), "feature": weight(2)}
+
+flow priority(label: String) -> Int:
+    return PRIORITY[label]
+"#).unwrap();
+    std::fs::write(lib.join("other.cog"), "import \"labels.cog\"\nimport \"base.cog\"\n").unwrap();
+    let main = dir.path().join("main.cog");
+    std::fs::write(&main, r#"import "lib/labels.cog"
+import "lib/other.cog"
+
+GREETING = "ready"
+
+flow main():
+    emit(GREETING)
+    emit(priority("feature"))
+    SCALE = 1
+    emit(SCALE)
+    emit(weight(3))
+"#).unwrap();
+    let output = Command::new(cognos_bin()).arg("run").arg(&main).output().unwrap();
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    // Shadowing SCALE in main leaves the global weight() reads alone
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "base setup\nlabels setup\nready\n20\n1\n30\n");
+
+    std::fs::write(lib.join("broken.cog"), "LIMIT = missing + 1\n").unwrap();
+    std::fs::write(&main, "import \"lib/broken.cog\"\nflow main():\n    pass\n").unwrap();
+    let output = Command::new(cognos_bin()).arg("run").arg(&main).output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("in top-level code of 'lib/broken.cog': undefined variable: 'missing'"), "got: {}", stderr);
+
+    let err = expect_error("return 1\nflow main():\n    pass\n");
+    assert!(err.contains("'return' is only allowed inside a flow"), "got: {}", err);
+}
+
+#[test]
+fn test_duplicate_flow_across_imports() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("one.cog"), "flow helper() -> String:\n    return \"one\"\n").unwrap();
+    std::fs::write(dir.path().join("two.cog"), "flow helper() -> String:\n    return \"two\"\n").unwrap();
+    let main = dir.path().join("main.cog");
+    std::fs::write(&main, "import \"one.cog\"\nimport \"two.cog\"\nflow main():\n    write(stdout, helper())\n").unwrap();
+
+    let output = Command::new(cognos_bin()).arg("run").arg(&main).output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "stderr: {}", stderr);
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "two");
+    assert!(stderr.contains("flow 'helper' is defined in both"), "got: {}", stderr);
+    assert!(stderr.contains("one.cog") && stderr.contains("two.cog"), "got: {}", stderr);
+}
+
+#[test]
+fn test_local_flow_shadowing_import_warns() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("lib.cog"), "flow helper() -> String:\n    return \"lib\"\n").unwrap();
+    let main = dir.path().join("main.cog");
+    std::fs::write(&main, "import \"lib.cog\"\nflow helper() -> String:\n    return \"local\"\nflow main():\n    write(stdout, helper())\n").unwrap();
+
+    let output = Command::new(cognos_bin()).arg("run").arg(&main).output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "stderr: {}", stderr);
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "local");
+    assert!(stderr.contains("flow 'helper' shadows the one imported from"), "got: {}", stderr);
+}
+
 // ─── Try/catch edge cases ───
 
 #[test]
@@ -1580,10 +2255,10 @@ fn test_nested_try_catch() {
         try:
             x = 1 / 0
         catch inner_err:
-            write(stdout, f"inner: {inner_err}")
+            write(stdout, f"inner: {inner_err.message}")
             y = 1 / 0
     catch outer_err:
-        write(stdout, f"outer: {outer_err}")
+        write(stdout, f"outer: {outer_err.message}")
 "#);
     assert!(out.contains("inner: division by zero"), "got: {}", out);
     assert!(out.contains("outer: division by zero"), "got: {}", out);
@@ -1625,7 +2300,7 @@ flow main():
 
     let bin = cognos_bin();
     let output = Command::new(&bin)
-        .args(&["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
         .output().unwrap();
     assert!(output.status.success(), "extra fields should pass, stderr: {}", String::from_utf8_lossy(&output.stderr));
     let stdout = String::from_utf8_lossy(&output.stdout);
@@ -1649,7 +2324,7 @@ flow main():
 
     let bin = cognos_bin();
     let output = Command::new(&bin)
-        .args(&["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
         .output().unwrap();
     assert!(output.status.success(), "format=json should not validate types, stderr: {}", String::from_utf8_lossy(&output.stderr));
     let stdout = String::from_utf8_lossy(&output.stdout);
@@ -1671,7 +2346,7 @@ flow main():
 
     let bin = cognos_bin();
     let output = Command::new(&bin)
-        .args(&["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
         .output().unwrap();
     assert!(!output.status.success());
     let stderr = String::from_utf8_lossy(&output.stderr);
@@ -1691,7 +2366,7 @@ flow main():
 
     let bin = cognos_bin();
     let output = Command::new(&bin)
-        .args(&["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
         .output().unwrap();
     assert!(!output.status.success());
     let stderr = String::from_utf8_lossy(&output.stderr);
@@ -1716,7 +2391,7 @@ flow main():
 
     let bin = cognos_bin();
     let output = Command::new(&bin)
-        .args(&["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
         .output().unwrap();
     let stdout = String::from_utf8_lossy(&output.stdout);
     assert!(stdout.contains("not configured") || stdout.contains("mock"), "got: {}", stdout);
@@ -1790,7 +2465,7 @@ flow main():
 
     let bin = cognos_bin();
     Command::new(&bin)
-        .args(&["run", "--session", session.to_str().unwrap(), cog.to_str().unwrap()])
+        .args(["run", "--session", session.to_str().unwrap(), cog.to_str().unwrap()])
         .output().unwrap();
 
     assert!(session.exists());
@@ -1811,7 +2486,7 @@ flow main():
 
     let bin = cognos_bin();
     Command::new(&bin)
-        .args(&["run", "--session", session.to_str().unwrap(), cog.to_str().unwrap()])
+        .args(["run", "--session", session.to_str().unwrap(), cog.to_str().unwrap()])
         .output().unwrap();
 
     let content = std::fs::read_to_string(&session).unwrap();
@@ -2289,7 +2964,7 @@ flow main():
     std::fs::write(&mock, r#"{"stdin": [], "llm_responses": ["{\"insights\": [{\"text\": \"good\", \"score\": 9}, {\"text\": \"bad\", \"score\": 2}]}"]}"#).unwrap();
     let bin = cognos_bin();
     let output = Command::new(&bin)
-        .args(&["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
         .output().unwrap();
     let stdout = String::from_utf8_lossy(&output.stdout).to_string();
     assert_eq!(output.status.code().unwrap_or(-1), 0, "stderr: {}", String::from_utf8_lossy(&output.stderr));
@@ -2316,7 +2991,7 @@ flow main():
     std::fs::write(&mock, r#"{"stdin": [], "llm_responses": ["{\"insights\": [{\"text\": \"good\", \"score\": 9}, \"just a string\"]}"]}"#).unwrap();
     let bin = cognos_bin();
     let output = Command::new(&bin)
-        .args(&["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
         .output().unwrap();
     let stderr = String::from_utf8_lossy(&output.stderr).to_string();
     assert!(stderr.contains("element [1]"), "got: {}", stderr);
@@ -2338,7 +3013,7 @@ flow main():
     std::fs::write(&mock, r#"{"stdin": [], "llm_responses": ["{\"settings\": {\"timeout\": 30, \"retries\": 3}}"]}"#).unwrap();
     let bin = cognos_bin();
     let output = Command::new(&bin)
-        .args(&["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
         .output().unwrap();
     assert_eq!(output.status.code().unwrap_or(-1), 0, "stderr: {}", String::from_utf8_lossy(&output.stderr));
 }
@@ -2358,7 +3033,7 @@ flow main():
     std::fs::write(&mock, r#"{"stdin": [], "llm_responses": ["{\"settings\": {\"timeout\": 30, \"retries\": \"three\"}}"]}"#).unwrap();
     let bin = cognos_bin();
     let output = Command::new(&bin)
-        .args(&["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
         .output().unwrap();
     let stderr = String::from_utf8_lossy(&output.stderr).to_string();
     assert!(stderr.contains("key 'retries'"), "got: {}", stderr);
@@ -2381,7 +3056,7 @@ flow main():
     std::fs::write(&mock, r#"{"stdin": [], "llm_responses": ["{\"tags\": [\"a\", 1, true]}"]}"#).unwrap();
     let bin = cognos_bin();
     let output = Command::new(&bin)
-        .args(&["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
         .output().unwrap();
     assert_eq!(output.status.code().unwrap_or(-1), 0, "stderr: {}", String::from_utf8_lossy(&output.stderr));
     assert!(String::from_utf8_lossy(&output.stdout).starts_with("3"), "got: {}", String::from_utf8_lossy(&output.stdout));
@@ -2410,7 +3085,7 @@ flow main():
     std::fs::write(&mock, r#"{"stdin": [], "llm_responses": ["{\"name\": \"test\"}"]}"#).unwrap();
     let bin = cognos_bin();
     let output = Command::new(&bin)
-        .args(&["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
         .output().unwrap();
     assert_eq!(output.status.code().unwrap_or(-1), 0, "stderr: {}", String::from_utf8_lossy(&output.stderr));
     assert!(String::from_utf8_lossy(&output.stdout).starts_with("test"), "got: {}", String::from_utf8_lossy(&output.stdout));
@@ -2433,7 +3108,7 @@ flow main():
     std::fs::write(&mock, r#"{"stdin": [], "llm_responses": ["{\"name\": \"test\", \"description\": \"a config\"}"]}"#).unwrap();
     let bin = cognos_bin();
     let output = Command::new(&bin)
-        .args(&["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
         .output().unwrap();
     assert_eq!(output.status.code().unwrap_or(-1), 0, "stderr: {}", String::from_utf8_lossy(&output.stderr));
     assert!(String::from_utf8_lossy(&output.stdout).starts_with("a config"), "got: {}", String::from_utf8_lossy(&output.stdout));
@@ -2456,7 +3131,7 @@ flow main():
     std::fs::write(&mock, r#"{"stdin": [], "llm_responses": ["{\"description\": \"oops\"}"]}"#).unwrap();
     let bin = cognos_bin();
     let output = Command::new(&bin)
-        .args(&["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
         .output().unwrap();
     let stderr = String::from_utf8_lossy(&output.stderr).to_string();
     assert!(stderr.contains("missing field 'name'"), "got: {}", stderr);
@@ -2485,7 +3160,7 @@ flow main():
     std::fs::write(&mock, r#"{"stdin": [], "llm_responses": ["{\"title\": \"bug\", \"severity\": \"high\"}"]}"#).unwrap();
     let bin = cognos_bin();
     let output = Command::new(&bin)
-        .args(&["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
         .output().unwrap();
     assert_eq!(output.status.code().unwrap_or(-1), 0, "stderr: {}", String::from_utf8_lossy(&output.stderr));
     assert!(String::from_utf8_lossy(&output.stdout).starts_with("high"), "got: {}", String::from_utf8_lossy(&output.stdout));
@@ -2509,7 +3184,7 @@ flow main():
     std::fs::write(&mock, r#"{"stdin": [], "llm_responses": ["{\"title\": \"bug\", \"severity\": \"extreme\"}"]}"#).unwrap();
     let bin = cognos_bin();
     let output = Command::new(&bin)
-        .args(&["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
         .output().unwrap();
     let stderr = String::from_utf8_lossy(&output.stderr).to_string();
     assert!(stderr.contains("not one of"), "got: {}", stderr);
@@ -2552,7 +3227,7 @@ flow main():
     std::fs::write(&mock, r#"{"stdin": [], "llm_responses": ["{\"issues\": [{\"title\": \"bug1\", \"severity\": \"low\"}, {\"title\": \"bug2\", \"severity\": \"high\"}]}"]}"#).unwrap();
     let bin = cognos_bin();
     let output = Command::new(&bin)
-        .args(&["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
         .output().unwrap();
     assert_eq!(output.status.code().unwrap_or(-1), 0, "stderr: {}", String::from_utf8_lossy(&output.stderr));
     assert!(String::from_utf8_lossy(&output.stdout).starts_with("2"), "got: {}", String::from_utf8_lossy(&output.stdout));
@@ -2579,7 +3254,7 @@ flow main():
     std::fs::write(&mock, r#"{"stdin": [], "llm_responses": ["{\"issues\": [{\"title\": \"bug1\", \"severity\": \"extreme\"}]}"]}"#).unwrap();
     let bin = cognos_bin();
     let output = Command::new(&bin)
-        .args(&["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
         .output().unwrap();
     let stderr = String::from_utf8_lossy(&output.stderr).to_string();
     assert!(stderr.contains("not one of"), "got: {}", stderr);
@@ -2651,7 +3326,7 @@ flow main():
     std::fs::write(&mock, r#"{"stdin": [], "llm_responses": ["{\"name\": \"Sprint 1\", \"tasks\": [{\"title\": \"Fix bug\", \"priority\": \"high\"}, {\"title\": \"Add feature\", \"priority\": \"medium\", \"notes\": \"needs design\"}], \"config\": {\"velocity\": 10, \"days\": 14}}"]}"#).unwrap();
     let bin = cognos_bin();
     let output = Command::new(&bin)
-        .args(&["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
         .output().unwrap();
     let stdout = String::from_utf8_lossy(&output.stdout).to_string();
     assert_eq!(output.status.code().unwrap_or(-1), 0, "stderr: {}", String::from_utf8_lossy(&output.stderr));
@@ -2694,7 +3369,7 @@ flow main():
     std::fs::write(&env_json, r#"{"llm_responses": ["Hi there!"]}"#).unwrap();
     let bin = cognos_bin();
     let output = Command::new(&bin)
-        .args(&["test", cog.to_str().unwrap(), "--env", env_json.to_str().unwrap()])
+        .args(["test", cog.to_str().unwrap(), "--env", env_json.to_str().unwrap()])
         .output().unwrap();
     let out = String::from_utf8_lossy(&output.stdout).to_string();
     assert_eq!(output.status.code().unwrap_or(-1), 0, "stderr: {}", String::from_utf8_lossy(&output.stderr));
@@ -2721,7 +3396,7 @@ flow main():
     std::fs::write(&env_json, r#"{"llm_responses": ["Hi there!"]}"#).unwrap();
     let bin = cognos_bin();
     let output = Command::new(&bin)
-        .args(&["test", cog.to_str().unwrap(), "--env", env_json.to_str().unwrap()])
+        .args(["test", cog.to_str().unwrap(), "--env", env_json.to_str().unwrap()])
         .output().unwrap();
     let out = String::from_utf8_lossy(&output.stdout).to_string();
     assert_eq!(output.status.code().unwrap_or(-1), 0, "stderr: {}", String::from_utf8_lossy(&output.stderr));
@@ -2752,7 +3427,7 @@ flow main():
     std::fs::write(&env_json, r#"{"llm_responses": ["Hi there!"]}"#).unwrap();
     let bin = cognos_bin();
     let output = Command::new(&bin)
-        .args(&["test", cog.to_str().unwrap(), "--env", env_json.to_str().unwrap()])
+        .args(["test", cog.to_str().unwrap(), "--env", env_json.to_str().unwrap()])
         .output().unwrap();
     let out = String::from_utf8_lossy(&output.stdout).to_string();
     assert_eq!(output.status.code().unwrap_or(-1), 0, "stderr: {}", String::from_utf8_lossy(&output.stderr));
@@ -3192,33 +3867,135 @@ flow main:
     assert_eq!(lines[1], "[2, 3, 4]");
 }
 
-// ─── Async: Complex scenarios ───
-
 #[test]
-fn test_async_await_basic_flow() {
+fn test_shared_map_updates_across_branches() {
     let src = r#"
-flow slow_add(a: Int, b: Int) -> Int:
-    return a + b
+flow inc(n: Int) -> Int:
+    return n + 1
 
-flow main:
-    h = async slow_add(10, 20)
-    immediate = 42
-    result = await(h)
-    emit(f"{result} {immediate}")
+flow main():
+    s = shared({"count": 0})
+    parallel:
+        branch:
+            for i in [1, 2, 3, 4, 5]:
+                s.update("count", "inc")
+            s.append("done", "a")
+        branch:
+            for i in [1, 2, 3, 4, 5]:
+                s.update("count", "inc")
+            s.append("done", "b")
+        branch:
+            for i in [1, 2, 3, 4, 5]:
+                s.update("count", "inc")
+            s.append("done", "c")
+    emit(s.get("count"))
+    emit(s.get("done").length)
+    emit(s.get("missing", "default"))
+    emit(s.snapshot()["count"])
 "#;
     let (out, err, code) = run_inline(src, "");
     assert_eq!(code, 0, "stderr: {}", err);
-    assert_eq!(out.trim(), "30 42");
+    assert_eq!(out.trim().lines().collect::<Vec<_>>(), ["15", "3", "default", "15"]);
+
+    // The flow gets the map itself as the current value and tries to read it
+    let (_, err, code) = run_inline(r#"
+flow peek(m: Shared) -> Int:
+    return m.get("count")
+
+flow main():
+    s = shared({"count": 0})
+    s.set("me", s)
+    s.update("me", "peek")
+"#, "");
+    assert_ne!(code, 0);
+    assert!(err.contains("inside its own update()"), "got: {}", err);
 }
 
 #[test]
-fn test_async_multiple_handles() {
-    let src = r#"
-flow add(a: Int, b: Int) -> Int:
-    return a + b
+fn test_parallel_merge_uses_value_equality() {
+    // "1" differs from 1, though both print the same
+    let (out, err, code) = run_inline(r#"
+flow main():
+    x = 1
+    parallel:
+        branch:
+            x = "1"
+        branch:
+            y = 2
+    emit(x == "1")
+"#, "");
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out.trim(), "true");
 
-flow main:
-    h1 = async add(1, 2)
+    // Agreeing branches are fine; disagreeing ones are an error, not a coin flip
+    let (out, err, code) = run_inline(r#"
+flow main():
+    parallel:
+        branch:
+            x = [1, {"a": 2}]
+        branch:
+            x = [1, {"a": 2}]
+    emit(x)
+"#, "");
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert!(out.contains("[1, {\"a\": 2}]"), "got: {}", out);
+    let (_, err, code) = run_inline(r#"
+flow main():
+    parallel:
+        branch:
+            x = 1
+        branch:
+            x = 2
+"#, "");
+    assert_ne!(code, 0);
+    assert!(err.contains("branches 1 and 2 both set 'x' to different values"), "got: {}", err);
+}
+
+#[test]
+fn test_parallel_branches_keep_conversation_history() {
+    let (out, err, code) = run_mock_llm(r#"flow main():
+    r = think("first")
+    parallel:
+        branch:
+            a = think("left")
+        branch:
+            b = think("right")
+    h = history()
+    emit(h.length)
+    emit(h[0]["content"])
+"#, &["one", "two", "three"]);
+    assert_eq!(code, 0, "stderr: {}", err);
+    let lines: Vec<&str> = out.lines().map(|l| l.trim()).filter(|l| !l.is_empty() && !l.starts_with('─')).collect();
+    assert_eq!(lines, ["6", "first"], "got: {}", out);
+}
+
+// ─── Async: Complex scenarios ───
+
+#[test]
+fn test_async_await_basic_flow() {
+    let src = r#"
+flow slow_add(a: Int, b: Int) -> Int:
+    return a + b
+
+flow main:
+    h = async slow_add(10, 20)
+    immediate = 42
+    result = await(h)
+    emit(f"{result} {immediate}")
+"#;
+    let (out, err, code) = run_inline(src, "");
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out.trim(), "30 42");
+}
+
+#[test]
+fn test_async_multiple_handles() {
+    let src = r#"
+flow add(a: Int, b: Int) -> Int:
+    return a + b
+
+flow main:
+    h1 = async add(1, 2)
     h2 = async add(3, 4)
     h3 = async add(5, 6)
     r1 = await(h1)
@@ -3278,7 +4055,7 @@ flow main():
 
     let bin = cognos_bin();
     let output = Command::new(&bin)
-        .args(&["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
         .output().unwrap();
     let stdout = String::from_utf8_lossy(&output.stdout);
     let stderr = String::from_utf8_lossy(&output.stderr);
@@ -3304,7 +4081,7 @@ flow main():
 
     let bin = cognos_bin();
     let output = Command::new(&bin)
-        .args(&["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
         .output().unwrap();
     let stdout = String::from_utf8_lossy(&output.stdout);
     let stderr = String::from_utf8_lossy(&output.stderr);
@@ -3439,7 +4216,7 @@ flow main:
         result = await(handle)
         write(stdout, "should not get here")
     catch e:
-        write(stdout, f"error: {e}")
+        write(stdout, f"error: {e.message}")
 "#;
     let (out, err, code) = run_inline(src, "");
     assert_eq!(code, 0, "stderr: {}", err);
@@ -3525,6 +4302,114 @@ flow main:
     assert!(out.contains("val=42"), "got: {}", out);
 }
 
+#[test]
+fn test_nested_field_and_index_assignment() {
+    let src = r#"
+flow main:
+    state = {"user": {"name": "bo"}, "rows": [{"score": 1}, {"score": 2}]}
+    copy = state
+    state["user"]["name"] = "ada"
+    state.rows[-1].score = 9
+    state.tags = ["a", "b"]
+    state["tags"][0] = "z"
+    write(stdout, state)
+    write(stdout, copy)
+    counts = shared({"hits": {"home": 0}})
+    parallel:
+        branch:
+            counts["hits"]["home"] = 1
+        branch:
+            counts.total = 2
+    write(stdout, counts)
+    for target in ["missing", "range", "type"]:
+        try:
+            if target == "missing":
+                state["nope"]["x"] = 1
+            elif target == "range":
+                state.rows[2] = {}
+            else:
+                state.user.name.first = "a"
+        catch err:
+            write(stdout, err.message)
+"#;
+    let (out, err, code) = run_inline(src, "");
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out, "{\"user\": {\"name\": ada}, \"rows\": [{\"score\": 1}, {\"score\": 9}], \"tags\": [z, b]}\n\
+        {\"user\": {\"name\": bo}, \"rows\": [{\"score\": 1}, {\"score\": 2}]}\n\
+        shared({\"hits\": {\"home\": 1}, \"total\": 2})\n\
+        map has no key 'nope'\n\
+        index 2 out of range (list has 2 elements)\n\
+        cannot index-assign on ada (type: String)\n");
+}
+
+#[test]
+fn test_list_push_pop_insert() {
+    let src = r#"
+flow main:
+    items = [1, 2]
+    copy = items
+    items.push(3)
+    items.insert(0, 0)
+    items.insert(-1, 9)
+    write(stdout, f"{items.pop()} {items.pop(0)} {items} {copy}")
+    state = {"rows": [{"tags": []}]}
+    state.rows[0].tags.push("a")
+    state["rows"][-1]["tags"].insert(0, "b")
+    write(stdout, state)
+    queue = shared({"jobs": []})
+    parallel:
+        branch:
+            queue["jobs"].push(1)
+        branch:
+            queue.jobs.push(2)
+    write(stdout, queue.get("jobs").length)
+    empty = []
+    for call in ["pop", "insert", "literal"]:
+        try:
+            if call == "pop":
+                empty.pop()
+            elif call == "insert":
+                empty.insert(5, 1)
+            else:
+                [1].push(2)
+        catch err:
+            write(stdout, err.message)
+"#;
+    let (out, err, code) = run_inline(src, "");
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out, "3 0 [1, 2, 9] [1, 2]\n\
+        {\"rows\": [{\"tags\": [b, a]}]}\n\
+        2\n\
+        .pop() from an empty list\n\
+        .insert(): index 5 out of range (list has 0 elements)\n\
+        .push() changes a list held in a variable, e.g. items.push(...); for a new list use concatenation: items + [item]\n");
+}
+
+#[test]
+fn test_list_method_arguments_evaluated_once() {
+    let src = r#"
+flow next(n: Int) -> Int:
+    write(stdout, f"evaluated {n}")
+    return n
+
+flow main:
+    items = []
+    items.push(next(1))
+    settings = {"a": 1}
+    try:
+        settings.push(next(2))
+    catch err:
+        write(stdout, err.message)
+    write(stdout, items)
+"#;
+    let (out, err, code) = run_inline(src, "");
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out.matches("evaluated 2").count(), 1, "got: {}", out);
+    assert!(out.starts_with("evaluated 1\nevaluated 2\n"), "got: {}", out);
+    assert!(out.contains("has no method 'push'"), "got: {}", out);
+    assert!(out.ends_with("[1]\n"), "got: {}", out);
+}
+
 #[test]
 fn test_mock_general_assistant() {
     let (out, _, code) = run_test("general-assistant.cog", "general-assistant-test.json");
@@ -3606,6 +4491,127 @@ flow main:
     assert!(out.contains("false"), "got: {}", out);
 }
 
+#[test]
+fn test_list_and_map_equality() {
+    let src = r#"
+flow main:
+    result = []
+    write(stdout, result == [])
+    write(stdout, [1, [2, "x"]] == [1, [2, "x"]])
+    write(stdout, [1, 2] == [2, 1])
+    write(stdout, [1] != ["1"])
+    write(stdout, {"a": 1, "b": [none]} == {"b": [none], "a": 1})
+    write(stdout, {"a": 1} != {"a": 1.0})
+    write(stdout, [[1], {"k": "v"}].contains({"k": "v"}))
+    write(stdout, [none].contains(none))
+"#;
+    let (out, err, code) = run_inline(src, "");
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out.lines().collect::<Vec<_>>(), ["true", "true", "false", "true", "true", "true", "true", "true"]);
+}
+
+#[test]
+fn test_type_of_and_is_predicates() {
+    let src = r#"
+flow main:
+    values = ["hi", 3, 1.5, true, [1], {"a": 1}, none]
+    for v in values:
+        write(stdout, f"{type_of(v)} {is_string(v)} {is_int(v)} {is_list(v)} {is_map(v)} {is_none(v)}")
+    try:
+        is_int(1, 2)
+    catch err:
+        write(stdout, err.message)
+"#;
+    let (out, err, code) = run_inline(src, "");
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out.lines().collect::<Vec<_>>(), [
+        "String true false false false false",
+        "Int false true false false false",
+        "Float false false false false false",
+        "Bool false false false false false",
+        "List false false true false false",
+        "Map false false false true false",
+        "None false false false false true",
+        "is_int() takes exactly one value",
+    ]);
+}
+
+#[test]
+fn test_get_path_reads_nested_values() {
+    let src = r#"
+flow main:
+    result = {"user": {"name": "ada", "tags": ["x", "y"]}, "items": [{"id": 7}]}
+    write(stdout, get_path(result, "user.name"))
+    write(stdout, get_path(result, "user.tags.-1"))
+    write(stdout, get_path(result, "items.0.id"))
+    write(stdout, get_path(result, "user.email", "none given"))
+    write(stdout, is_none(get_path(result, "items.3.id")))
+    write(stdout, get_path(result, "user.name.first", "?"))
+    write(stdout, get_path("not a map", "a", 0))
+"#;
+    let (out, err, code) = run_inline(src, "");
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out.lines().collect::<Vec<_>>(), ["ada", "y", "7", "none given", "true", "?", "0"]);
+}
+
+#[test]
+fn test_query_filters_nested_values() {
+    let src = r#"
+flow main:
+    response = {"items": [{"name": "pen", "price": 2}, {"name": "desk", "price": 150}, {"name": "lamp", "price": 40}]}
+    names = query(response, "$.items[?(@.price > 10)].name")
+    write(stdout, names.join(","))
+    write(stdout, query(response, "$.items[-1].price"))
+    write(stdout, query(response, "$.nothing[*]").length)
+    try:
+        query(response, "items[0]")
+    catch err:
+        write(stdout, err.message)
+"#;
+    let (out, err, code) = run_inline(src, "");
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out.lines().collect::<Vec<_>>(), [
+        "desk,lamp",
+        "[40]",
+        "0",
+        "query path must start with '$', e.g. \"$.items[*].name\" (got \"items[0]\")",
+    ]);
+}
+
+#[test]
+fn test_pretty_printing() {
+    let src = r#"
+flow main:
+    data = {"user": {"name": "ada", "tags": ["x", "y"]}, "items": [1, 2, 3, 4], "bio": "a long biography"}
+    emit(data, pretty=true)
+    pprint(data, depth=1, max_items=2, max_string=6)
+    emit(data, depth=1, pretty=false)
+    try:
+        pprint(data, indent=4)
+    catch err:
+        emit(err.message)
+"#;
+    let (out, err, code) = run_inline(src, "");
+    assert_eq!(code, 0, "stderr: {}", err);
+    // Not a terminal, so no color
+    assert_eq!(out, r#"{
+  "user": {
+    "name": "ada",
+    "tags": ["x", "y"]
+  },
+  "items": [1, 2, 3, 4],
+  "bio": "a long biography"
+}
+{
+  "user": {… 2 keys},
+  "items": [… 4 items],
+  … 1 more
+}
+{"user": {"name": ada, "tags": [x, y]}, "items": [1, 2, 3, 4], "bio": a long biography}
+pprint(): unknown kwarg 'indent'
+"#);
+}
+
 #[test]
 fn test_string_contains() {
     let src = r#"
@@ -3619,6 +4625,28 @@ flow main:
     assert!(out.contains("true"), "got: {}", out);
 }
 
+#[test]
+fn test_in_agrees_with_contains() {
+    let out = expect_run_ok(r#"flow main():
+    items = [1, [2], {"a": 3}]
+    write(stdout, ["1" in items, 1 in items, [2] in items, {"a": 3} in items, 1.0 in items])
+    write(stdout, ["1" not in items, 1 not in items, items.contains("1"), items.contains(1)])
+    m = {"1": 2}
+    write(stdout, ["1" in m, "2" not in m, m.contains("1")])
+    for check in ["in", "not in"]:
+        try:
+            if check == "in":
+                x = 1 in m
+            else:
+                x = 1 not in m
+        catch err:
+            write(stdout, err.message)
+"#);
+    assert_eq!(out, "[false, true, true, true, false]\n[true, false, false, true]\n[true, true, true]\n\
+        cannot Int in Map — map keys are Strings\n\
+        cannot Int not in Map — map keys are Strings\n");
+}
+
 #[test]
 fn test_string_strip() {
     let src = r#"
@@ -3802,3 +4830,2751 @@ flow main():
     assert_eq!(code, 0, "stderr: {}", err);
     assert!(out.contains("count=1"), "expected count=1, got: {}", out);
 }
+
+// ── Fuzz corpus ──
+
+#[test]
+fn test_fuzz_corpus_writes_seeds() {
+    let dir = tempfile::tempdir().unwrap();
+    let src = dir.path().join("sample.cog");
+    std::fs::write(&src, "import \"lib/x.cog\"\n\nflow helper():\n    pass\n\nflow main():\n    helper()\n").unwrap();
+    let out = dir.path().join("corpus");
+    let output = Command::new(cognos_bin())
+        .arg("fuzz-corpus")
+        .arg(&out)
+        .arg(&src)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    let mut names: Vec<String> = std::fs::read_dir(&out).unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["sample-1.cog", "sample-2.cog", "sample-3.cog", "sample.cog"]);
+    let flow = std::fs::read_to_string(out.join("sample-3.cog")).unwrap();
+    assert!(flow.starts_with("flow main():"), "got: {}", flow);
+}
+
+// ── Bench ──
+
+#[test]
+fn test_bench_list_and_filter() {
+    let output = Command::new(cognos_bin()).args(["bench", "--list"]).output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert!(stdout.contains("tight_loop"), "got: {}", stdout);
+
+    let output = Command::new(cognos_bin())
+        .args(["bench", "--filter", "no_such_workload"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no workload matches"));
+}
+
+// ── Ollama options ──
+
+/// Answer each incoming request with the next canned (status, body) pair,
+/// one request per connection. The handle yields "<request line>\n<body>"
+/// for every request served.
+fn fake_http_server(responses: Vec<(u16, &'static str)>) -> (String, std::thread::JoinHandle<Vec<String>>) {
+    use std::io::{BufRead, BufReader, Read, Write};
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let handle = std::thread::spawn(move || {
+        let mut seen = Vec::new();
+        for (status, body) in responses {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" || line.is_empty() { break; }
+                if let Some(v) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                    content_length = v.trim().parse().unwrap();
+                }
+            }
+            let mut request_body = vec![0; content_length];
+            reader.read_exact(&mut request_body).unwrap();
+            seen.push(format!("{}\n{}", request_line.trim(), String::from_utf8_lossy(&request_body)));
+            let mut writer = stream;
+            write!(writer, "HTTP/1.1 {} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status, body.len(), body).unwrap();
+        }
+        seen
+    });
+    (url, handle)
+}
+
+fn run_with_ollama(src: &str, url: &str, flags: &[&str]) -> (String, String, i32) {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("test.cog");
+    std::fs::write(&file, src).unwrap();
+    let output = Command::new(cognos_bin())
+        .arg("run")
+        .args(flags)
+        .arg(&file)
+        .env("OLLAMA_URL", url)
+        .output()
+        .unwrap();
+    (String::from_utf8_lossy(&output.stdout).to_string(),
+     String::from_utf8_lossy(&output.stderr).to_string(),
+     output.status.code().unwrap_or(-1))
+}
+
+#[test]
+fn test_ollama_keep_alive_and_num_ctx() {
+    let (url, server) = fake_http_server(vec![(200, r#"{"message":{"content":"hi there"}}"#)]);
+    let (out, err, code) = run_with_ollama(r#"flow main():
+    r = think("hello", model="llama3", keep_alive="30m", num_ctx=8192)
+    write(stdout, r)
+"#, &url, &["--num-ctx", "2048"]);
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out.trim(), "hi there");
+    let requests = server.join().unwrap();
+    assert!(requests[0].starts_with("POST /api/chat"), "got: {:?}", requests);
+    let body: serde_json::Value = serde_json::from_str(requests[0].split_once('\n').unwrap().1).unwrap();
+    assert_eq!(body["keep_alive"], "30m");
+    assert_eq!(body["options"]["num_ctx"], 8192, "kwarg should override --num-ctx");
+}
+
+#[test]
+fn test_ollama_json_mode_and_temperature() {
+    let (url, server) = fake_http_server(vec![(200, r#"{"message":{"content":"{\"ok\": true}"}}"#)]);
+    let (out, err, code) = run_with_ollama(r#"flow main():
+    r = think("status?", model="llama3", json_mode=true, temperature=0.3)
+    write(stdout, r["ok"])
+"#, &url, &[]);
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out.trim(), "true");
+    let requests = server.join().unwrap();
+    let body: serde_json::Value = serde_json::from_str(requests[0].split_once('\n').unwrap().1).unwrap();
+    assert_eq!(body["format"], "json");
+    assert_eq!(body["options"]["temperature"], 0.3);
+    // The instruction still goes along, for models that ignore the flag
+    assert!(body["messages"][0]["content"].as_str().unwrap().contains("valid JSON"), "got: {}", body);
+}
+
+#[test]
+fn test_ollama_warmup_preloads_model() {
+    let (url, server) = fake_http_server(vec![(200, "{}"), (200, r#"{"message":{"content":"ok"}}"#)]);
+    let (out, err, code) = run_with_ollama(r#"flow main():
+    write(stdout, think("hello", model="llama3"))
+"#, &url, &["--warmup", "llama3", "--keep-alive", "600"]);
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out.trim(), "ok");
+    let requests = server.join().unwrap();
+    assert!(requests[0].starts_with("POST /api/generate"), "got: {:?}", requests);
+    assert!(requests[1].starts_with("POST /api/chat"), "got: {:?}", requests);
+    let body: serde_json::Value = serde_json::from_str(requests[0].split_once('\n').unwrap().1).unwrap();
+    assert_eq!(body["model"], "llama3");
+    assert_eq!(body["keep_alive"], 600);
+}
+
+#[test]
+fn test_ollama_model_not_found_suggests_pull() {
+    let (url, server) = fake_http_server(vec![(404, r#"{"error":"model \"nope:7b\" not found, try pulling it first"}"#)]);
+    let (_, err, code) = run_with_ollama(r#"flow main():
+    r = think("hello", model="nope:7b")
+"#, &url, &[]);
+    server.join().unwrap();
+    assert_ne!(code, 0);
+    assert!(err.contains("ollama pull nope:7b"), "got: {}", err);
+}
+
+fn auth_command(home: &std::path::Path, cwd: &std::path::Path, envs: &[(&str, &str)]) -> Command {
+    let mut cmd = Command::new(cognos_bin());
+    cmd.current_dir(cwd).env("HOME", home);
+    for key in ["ANTHROPIC_API_KEY", "OPENAI_API_KEY", "DEEPSEEK_API_KEY", "MINIMAX_API_KEY", "SLACK_BOT_TOKEN", "NATS_TOKEN", "MQTT_USERNAME", "MQTT_PASSWORD"] {
+        cmd.env_remove(key);
+    }
+    cmd.envs(envs.iter().copied());
+    cmd
+}
+
+#[test]
+fn test_auth_status_reports_source_per_key() {
+    let home = tempfile::tempdir().unwrap();
+    let cwd = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(home.path().join(".cognos")).unwrap();
+    std::fs::write(home.path().join(".cognos/credentials.json"), r#"{"MINIMAX_API_KEY": "mm-config-0123456789"}"#).unwrap();
+    std::fs::write(cwd.path().join(".env"), "DEEPSEEK_API_KEY=\"ds-dotenv-0123456789\"\nOPENAI_API_KEY=sk-dotenv-0123456789\n").unwrap();
+
+    let output = auth_command(home.path(), cwd.path(), &[("OPENAI_API_KEY", "sk-env-secret-9876")])
+        .args(["auth", "status"]).output().unwrap();
+    let out = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    let line = |key: &str| out.lines().find(|l| l.contains(key)).unwrap_or_default().to_string();
+    assert!(line("OPENAI_API_KEY").ends_with("(env)"), "env should win over .env: {}", out);
+    assert!(line("DEEPSEEK_API_KEY").ends_with("(.env)"), "got: {}", out);
+    assert!(line("MINIMAX_API_KEY").contains("credentials.json"), "got: {}", out);
+    assert!(line("ANTHROPIC_API_KEY").ends_with("not set"), "got: {}", out);
+    assert!(!out.contains("secret"), "keys must be masked: {}", out);
+}
+
+#[test]
+fn test_missing_api_key_names_all_sources() {
+    let home = tempfile::tempdir().unwrap();
+    let cwd = tempfile::tempdir().unwrap();
+    let file = cwd.path().join("test.cog");
+    std::fs::write(&file, "flow main():\n    write(stdout, think(\"hi\", model=\"gpt-4o\"))\n").unwrap();
+    let output = auth_command(home.path(), cwd.path(), &[]).arg("run").arg(&file).output().unwrap();
+    let err = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(err.contains("OPENAI_API_KEY not set"), "got: {}", err);
+    assert!(err.contains("credentials.json"), "got: {}", err);
+}
+
+#[test]
+fn test_models_lists_ollama_tags_with_capabilities() {
+    let (url, server) = fake_http_server(vec![
+        (200, r#"{"models":[{"name":"qwen2.5:7b"},{"name":"llava:13b"}]}"#),
+        (200, r#"{"capabilities":["completion","tools"]}"#),
+        (200, r#"{"details":{}}"#),
+    ]);
+    let home = tempfile::tempdir().unwrap();
+    let output = auth_command(home.path(), home.path(), &[("OLLAMA_URL", &url)])
+        .arg("models").output().unwrap();
+    let out = String::from_utf8_lossy(&output.stdout);
+    let requests = server.join().unwrap();
+    assert!(output.status.success());
+    assert!(requests[0].starts_with("GET /api/tags"), "got: {:?}", requests);
+    let row = |name: &str| out.lines().find(|l| l.starts_with(name)).unwrap_or_default()
+        .split_whitespace().map(|s| s.to_string()).collect::<Vec<_>>();
+    assert_eq!(row("qwen2.5:7b"), ["qwen2.5:7b", "ollama", "yes", "no"], "got: {}", out);
+    assert_eq!(row("llava:13b"), ["llava:13b", "ollama", "?", "?"], "got: {}", out);
+    // No Anthropic key: Claude models go through the CLI, which can't take images
+    assert_eq!(row("claude-sonnet-4-5"), ["claude-sonnet-4-5", "claude-cli", "yes", "no"], "got: {}", out);
+    assert!(out.contains("openai: OPENAI_API_KEY not set"), "got: {}", out);
+}
+
+#[test]
+fn test_file_model_reports_missing_gguf() {
+    let (_, err, code) = run_inline(r#"flow main():
+    write(stdout, think("hi", model="file:./no-such-model.gguf"))
+"#, "");
+    assert_ne!(code, 0);
+    assert!(err.contains("GGUF model not found: ./no-such-model.gguf"), "got: {}", err);
+}
+
+#[cfg(not(feature = "gguf"))]
+#[test]
+fn test_file_model_without_gguf_feature_explains_rebuild() {
+    let dir = tempfile::tempdir().unwrap();
+    let model = dir.path().join("tiny.gguf");
+    std::fs::write(&model, b"GGUF").unwrap();
+    let (_, err, code) = run_inline(&format!(r#"flow main():
+    write(stdout, think("hi", model="file:{}"))
+"#, model.display()), "");
+    assert_ne!(code, 0);
+    assert!(err.contains("--features gguf"), "got: {}", err);
+}
+
+#[test]
+fn test_oversized_tool_results_are_truncated_and_traced() {
+    let reply = r#"{"choices":[{"message":{"role":"assistant","content":"done"}}]}"#;
+    let (url, server) = fake_http_server(vec![(200, reply)]);
+    let dir = tempfile::tempdir().unwrap();
+    let trace = dir.path().join("trace.jsonl");
+    let (out, err, code) = run_with_ollama(r#"flow main():
+    call = {"id": "call_1", "name": "shell", "arguments": {}}
+    conv = [{"role": "user", "content": "list"}, {"role": "assistant", "content": "", "has_tool_calls": true, "tool_calls": [call]}]
+    big = "x" * 5000
+    r = think("", model="llama3", conversation=conv, tool_results=[{"tool_use_id": "call_1", "content": big}], max_tool_result=1000)
+    write(stdout, r["content"])
+"#, &url, &["--trace", trace.to_str().unwrap()]);
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out.trim(), "done");
+    let requests = server.join().unwrap();
+    assert!(requests[0].starts_with("POST /v1/chat/completions"), "got: {:?}", requests);
+    let body: serde_json::Value = serde_json::from_str(requests[0].split_once('\n').unwrap().1).unwrap();
+    let sent = body["messages"].as_array().unwrap().iter()
+        .find(|m| m["role"] == "tool").unwrap()["content"].as_str().unwrap().to_string();
+    assert!(sent.contains("[4000 of 5000 chars elided]"), "got: {}", sent);
+    assert!(sent.len() < 1100);
+    let events = std::fs::read_to_string(&trace).unwrap();
+    let event: serde_json::Value = events.lines().map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+        .find(|e| e["event"] == "tool_result_truncated").expect("truncation event");
+    assert_eq!(event["tool"], "shell");
+    assert_eq!(event["original_chars"], 5000);
+    assert_eq!(event["kept_chars"], 1000);
+}
+
+#[test]
+fn test_system_list_joins_segments_and_traces_them() {
+    let (url, server) = fake_http_server(vec![(200, r#"{"message":{"content":"ok"}}"#)]);
+    let dir = tempfile::tempdir().unwrap();
+    let base = dir.path().join("base.md");
+    std::fs::write(&base, "You are terse.\n").unwrap();
+    let trace = dir.path().join("trace.jsonl");
+    let (out, err, code) = run_with_ollama(&format!(r#"flow main():
+    persona = "Speak like a pirate."
+    day = "Monday"
+    r = think("hi", model="llama3", system=[file("{}"), f"Today is {{day}}", none, persona])
+    write(stdout, r)
+"#, base.display()), &url, &["--trace", trace.to_str().unwrap()]);
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out.trim(), "ok");
+    let requests = server.join().unwrap();
+    let body: serde_json::Value = serde_json::from_str(requests[0].split_once('\n').unwrap().1).unwrap();
+    assert_eq!(body["messages"][0]["role"], "system");
+    assert_eq!(body["messages"][0]["content"], "You are terse.\n\nToday is Monday\n\nSpeak like a pirate.");
+    let events = std::fs::read_to_string(&trace).unwrap();
+    let event: serde_json::Value = events.lines().map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+        .find(|e| e["event"] == "system_prompt").expect("system_prompt event");
+    let segments = event["segments"].as_array().unwrap();
+    assert_eq!(segments.len(), 3);
+    assert!(segments[0]["source"].as_str().unwrap().ends_with("base.md"));
+    assert_eq!(segments[1]["source"], "inline");
+    assert_eq!(segments[2]["chars"], 20);
+    assert!(segments[0].get("text").is_none(), "metrics traces carry no text");
+}
+
+#[test]
+fn test_system_list_missing_file_errors() {
+    let (_, err, code) = run_inline(r#"flow main():
+    r = think("hi", model="llama3", system=[file("/no/such/base.md"), "x"])
+"#, "");
+    assert_ne!(code, 0);
+    assert!(err.contains("system=: cannot read '/no/such/base.md'"), "got: {}", err);
+}
+
+#[test]
+fn test_think_examples_become_chat_turns() {
+    let (url, server) = fake_http_server(vec![(200, r#"{"message":{"content":"{\"sentiment\": \"neutral\"}"}}"#)]);
+    let (out, err, code) = run_with_ollama(r#"flow main():
+    ex = [{"input": "I love it", "output": {"sentiment": "positive"}}, {"input": "Awful.", "output": "{\"sentiment\": \"negative\"}"}]
+    r = think("It arrived.", model="llama3", system="Classify.", examples=ex)
+    write(stdout, r)
+"#, &url, &[]);
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert!(out.contains("neutral"));
+    let requests = server.join().unwrap();
+    let body: serde_json::Value = serde_json::from_str(requests[0].split_once('\n').unwrap().1).unwrap();
+    let turns: Vec<(&str, &str)> = body["messages"].as_array().unwrap().iter()
+        .map(|m| (m["role"].as_str().unwrap(), m["content"].as_str().unwrap())).collect();
+    assert_eq!(turns, vec![
+        ("system", "Classify."),
+        ("user", "I love it"),
+        ("assistant", r#"{"sentiment":"positive"}"#),
+        ("user", "Awful."),
+        ("assistant", r#"{"sentiment": "negative"}"#),
+        ("user", "It arrived."),
+    ]);
+}
+
+#[test]
+fn test_think_examples_rejects_bad_items() {
+    let (_, err, code) = run_inline(r#"flow main():
+    r = think("hi", model="llama3", examples=[{"input": "only input"}])
+"#, "");
+    assert_ne!(code, 0);
+    assert!(err.contains("examples= items must be Maps with \"input\" and \"output\""), "got: {}", err);
+}
+
+fn run_mock_llm(src: &str, llm_responses: &[&str]) -> (String, String, i32) {
+    let dir = tempfile::tempdir().unwrap();
+    let cog = dir.path().join("test.cog");
+    let mock = dir.path().join("mock.json");
+    std::fs::write(&cog, src).unwrap();
+    std::fs::write(&mock, serde_json::json!({"stdin": [], "llm_responses": llm_responses}).to_string()).unwrap();
+    let output = Command::new(cognos_bin())
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
+        .output().unwrap();
+    (String::from_utf8_lossy(&output.stdout).to_string(),
+     String::from_utf8_lossy(&output.stderr).to_string(),
+     output.status.code().unwrap_or(-1))
+}
+
+#[test]
+fn test_classify_returns_label_and_retries_off_list_answer() {
+    let src = r#"flow main():
+    write(stdout, classify("App crashes on start", labels=["bug", "feature", "question"]))
+"#;
+    let (out, err, code) = run_mock_llm(src, &["Bug."]);
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert!(out.contains("bug"), "got: {}", out);
+
+    let (out, err, code) = run_mock_llm(src, &["defect", "bug"]);
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert!(out.contains("bug"), "got: {}", out);
+
+    let (_, err, code) = run_mock_llm(src, &["defect", "problem"]);
+    assert_ne!(code, 0);
+    assert!(err.contains("model answered 'problem', which is not one of: bug, feature, question"), "got: {}", err);
+}
+
+#[test]
+fn test_classify_user_flow_takes_precedence() {
+    let (out, err, code) = run_mock_llm(r#"flow classify(text: String) -> String:
+    return "mine"
+
+flow main():
+    write(stdout, classify("x"))
+"#, &[]);
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert!(out.contains("mine"), "got: {}", out);
+}
+
+#[test]
+fn test_extract_single_typed_value() {
+    let (out, err, code) = run_mock_llm(r#"type Invoice:
+    number: String
+    total: Float
+
+flow main():
+    inv = extract("Invoice INV-7, total due 12.50", type="Invoice")
+    write(stdout, f"{inv[\"number\"]} {inv[\"total\"]}")
+"#, &[r#"{"number": "INV-7", "total": 12.5}"#]);
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert!(out.contains("INV-7 12.5"), "got: {}", out);
+}
+
+#[test]
+fn test_extract_list_chunks_and_dedups() {
+    let (out, err, code) = run_mock_llm(r#"type Person:
+    name: String
+
+flow main():
+    doc = "Alice met Bob.\n\nBob called Carol."
+    people = extract(doc, type="List[Person]", chunk_size=6)
+    write(stdout, people.length)
+    for p in people:
+        write(stdout, p["name"])
+"#, &[r#"[{"name": "Alice"}, {"name": "Bob"}]"#, r#"[{"name": "Bob"}, {"name": "Carol"}]"#]);
+    assert_eq!(code, 0, "stderr: {}", err);
+    let lines: Vec<&str> = out.lines().map(|l| l.trim()).collect();
+    assert!(lines.windows(4).any(|w| w == ["3", "Alice", "Bob", "Carol"]), "got: {}", out);
+}
+
+#[test]
+fn test_extract_validates_items() {
+    let (_, err, code) = run_mock_llm(r#"type Person:
+    name: String
+
+flow main():
+    people = extract("Alice", type="List[Person]")
+"#, &[r#"[{"age": 3}]"#]);
+    assert_ne!(code, 0);
+    assert!(err.contains("extract(): chunk 1/1: type Person validation failed"), "got: {}", err);
+}
+
+#[test]
+fn test_summarize_maps_chunks_then_reduces() {
+    let part = r#"{"message":{"content":"a part"}}"#;
+    let (url, server) = fake_http_server(vec![(200, part), (200, part), (200, part), (200, r#"{"message":{"content":"the whole"}}"#)]);
+    let dir = tempfile::tempdir().unwrap();
+    let trace = dir.path().join("trace.jsonl");
+    let (out, err, code) = run_with_ollama(r#"flow main():
+    doc = ("a" * 50) + "\n\n" + ("b" * 50) + "\n\n" + ("c" * 50)
+    write(stdout, summarize(doc, model="llama3", max_words=30, chunk_size=15))
+"#, &url, &["--trace", trace.to_str().unwrap()]);
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out.trim(), "the whole");
+    let requests = server.join().unwrap();
+    let bodies: Vec<serde_json::Value> = requests.iter()
+        .map(|r| serde_json::from_str(r.split_once('\n').unwrap().1).unwrap()).collect();
+    for body in &bodies[..3] {
+        assert!(body["messages"][0]["content"].as_str().unwrap().contains("one part of a longer document"));
+    }
+    assert!(bodies[3]["messages"][0]["content"].as_str().unwrap().contains("at most 30 words"));
+    assert_eq!(bodies[3]["messages"][1]["content"], "a part\n\na part\n\na part");
+    let stages: Vec<(String, u64)> = std::fs::read_to_string(&trace).unwrap().lines()
+        .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+        .filter(|e| e["event"] == "summarize_stage")
+        .map(|e| (e["stage"].as_str().unwrap().to_string(), e["calls"].as_u64().unwrap())).collect();
+    assert_eq!(stages, vec![("map".to_string(), 3), ("reduce".to_string(), 1)]);
+}
+
+#[test]
+fn test_summarize_reads_file_handle() {
+    let (url, server) = fake_http_server(vec![(200, r#"{"message":{"content":"Notes, briefly."}}"#)]);
+    let dir = tempfile::tempdir().unwrap();
+    let doc = dir.path().join("notes.txt");
+    std::fs::write(&doc, "Short notes.").unwrap();
+    let (out, err, code) = run_with_ollama(&format!(r#"flow main():
+    write(stdout, summarize(file("{}"), model="llama3"))
+"#, doc.display()), &url, &[]);
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out.trim(), "Notes, briefly.");
+    let requests = server.join().unwrap();
+    assert!(requests[0].contains("Short notes."), "got: {:?}", requests);
+}
+
+#[test]
+fn test_count_tokens_and_chunk_text() {
+    let (out, err, code) = run_inline(r#"flow main():
+    write(stdout, count_tokens("Hello, world!", model="gpt-4o"))
+    doc = "one two three four five six seven eight nine ten"
+    chunks = chunk_text(doc, max_tokens=6, overlap=2, model="gpt-4o")
+    for c in chunks:
+        write(stdout, c)
+"#, "");
+    assert_eq!(code, 0, "stderr: {}", err);
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines[0], "4");
+    assert_eq!(&lines[1..], ["one two three four ", "three four five six seven eight ", "seven eight nine ten"]);
+}
+
+#[test]
+fn test_chat_runs_tools_and_saves_session() {
+    let tool_call = r#"{"choices":[{"message":{"role":"assistant","content":"","tool_calls":[{"id":"call_1","type":"function","function":{"name":"add","arguments":"{\"a\": 2, \"b\": 3}"}}]}}]}"#;
+    let answer = r#"{"choices":[{"message":{"role":"assistant","content":"It is **5**."}}]}"#;
+    let (url, server) = fake_http_server(vec![(200, tool_call), (200, answer)]);
+    let dir = tempfile::tempdir().unwrap();
+    let lib = dir.path().join("tools.cog");
+    std::fs::write(&lib, "flow add(a: Int, b: Int) -> Int:\n    \"Add two numbers\"\n    return a + b\n").unwrap();
+    let session = dir.path().join("session.json");
+
+    let mut child = Command::new(cognos_bin())
+        .args(["chat", "--model", "llama3", "--tools", lib.to_str().unwrap()])
+        .env("OLLAMA_URL", &url)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn().unwrap();
+    use std::io::Write;
+    write!(child.stdin.take().unwrap(), "What is 2 + 3?\n/save {}\n/exit\n", session.display()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "stderr: {}", stderr);
+    // Piped output is left as plain markdown
+    assert_eq!(stdout.trim(), "It is **5**.");
+    assert!(stderr.contains("→ add({\"a\":2,\"b\":3})"), "got: {}", stderr);
+
+    let requests = server.join().unwrap();
+    let body: serde_json::Value = serde_json::from_str(requests[1].split_once('\n').unwrap().1).unwrap();
+    let tool_msg = body["messages"].as_array().unwrap().iter().find(|m| m["role"] == "tool").unwrap();
+    assert_eq!(tool_msg["tool_call_id"], "call_1");
+    assert_eq!(tool_msg["content"], "5");
+
+    let saved: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&session).unwrap()).unwrap();
+    assert_eq!(saved["model"], "llama3");
+    let roles: Vec<&str> = saved["conversation"].as_array().unwrap().iter().map(|m| m["role"].as_str().unwrap()).collect();
+    assert_eq!(roles, ["user", "assistant", "tool", "assistant"]);
+}
+
+#[test]
+fn test_chat_load_rejects_bad_session() {
+    let dir = tempfile::tempdir().unwrap();
+    let session = dir.path().join("bad.json");
+    std::fs::write(&session, "{\"model\": \"x\"}").unwrap();
+    let output = Command::new(cognos_bin())
+        .args(["chat", "--load", session.to_str().unwrap()])
+        .stdin(std::process::Stdio::null())
+        .output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is not a chat session"));
+}
+
+#[test]
+fn test_parse_error_shows_code_frame() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("bad.cog");
+    std::fs::write(&file, "flow main():\n    x = )\n    y = 1\n").unwrap();
+    let output = Command::new(cognos_bin())
+        .args(["run", "--no-color", file.to_str().unwrap()])
+        .output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.starts_with("Parse error: line 2:"), "got: {}", stderr);
+    assert!(stderr.contains(&format!("--> {}:2", file.display())), "got: {}", stderr);
+    assert!(stderr.contains("2 |     x = )"), "got: {}", stderr);
+    assert!(!stderr.contains('\x1b'), "got: {:?}", stderr);
+}
+
+#[test]
+fn test_runtime_error_shows_line_and_column() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("app.cog");
+    std::fs::write(&file, "flow main():\n    y = 1\n    if y > 0:\n        print(x)\n").unwrap();
+    let output = Command::new(cognos_bin())
+        .args(["run", "--no-color", file.to_str().unwrap()])
+        .output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.starts_with("Runtime error: undefined variable: 'x'\n"), "got: {}", stderr);
+    assert!(stderr.contains(&format!("--> {}:4:9\n", file.display())), "got: {}", stderr);
+    assert!(stderr.contains("4 |         print(x)\n  |         ^\n"), "got: {}", stderr);
+}
+
+#[test]
+fn test_runtime_error_in_imported_flow_points_at_library() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("lib.cog"), "flow boom():\n    x = missing\n").unwrap();
+    let file = dir.path().join("app.cog");
+    std::fs::write(&file, "import \"lib.cog\"\n\nflow main():\n    boom()\n").unwrap();
+    let output = Command::new(cognos_bin())
+        .args(["run", "--no-color", file.to_str().unwrap()])
+        .output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("lib.cog:2:5\n"), "got: {}", stderr);
+    assert!(stderr.contains("2 |     x = missing"), "got: {}", stderr);
+}
+
+#[test]
+fn test_mock_failure_prints_fail_summary() {
+    let (out, err, code) = run_mock_llm("flow main():\n    x = undefined_var\n", &[]);
+    assert_ne!(code, 0);
+    assert!(err.contains("Runtime error: undefined variable: 'undefined_var'"), "got: {}", err);
+    assert!(err.contains("─── Fail ✗ ───"), "got: {}", err);
+    assert!(!out.contains("Pass"));
+}
+
+#[test]
+fn test_read_prompt_is_silent_when_piped() {
+    let src = "flow main():\n    name = read(stdin, prompt=\"> \")\n    write(stdout, f\"hi {name}\")\n    log(\"done\")\n";
+    let (out, err, code) = run_inline(src, "ada\n");
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out, "hi ada\n");
+    assert!(!err.contains('>'), "got: {}", err);
+    assert!(err.contains("[log] done"), "got: {}", err);
+}
+
+#[test]
+fn test_quiet_hides_log_output() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("quiet.cog");
+    std::fs::write(&file, "flow main():\n    log(\"noise\")\n    write(stdout, \"result\")\n").unwrap();
+    for flag in ["-q", "--quiet"] {
+        let output = Command::new(cognos_bin())
+            .args(["run", flag, "-v", file.to_str().unwrap()])
+            .output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "result\n");
+        assert_eq!(String::from_utf8_lossy(&output.stderr), "", "with {}", flag);
+    }
+}
+
+#[test]
+fn test_trace_records_runtime_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("fail.cog");
+    let trace = dir.path().join("trace.jsonl");
+    std::fs::write(&file, "flow main():\n    for x in 42:\n        write(stdout, x)\n").unwrap();
+    let output = Command::new(cognos_bin())
+        .args(["run", "--trace", trace.to_str().unwrap(), file.to_str().unwrap()])
+        .output().unwrap();
+    assert!(!output.status.success());
+    let content = std::fs::read_to_string(&trace).unwrap();
+    let events: Vec<serde_json::Value> = content.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+    // The error, then the run's totals close the trace
+    let error = &events[events.len() - 2];
+    assert_eq!(error["event"], "error");
+    assert_eq!(error["category"], "runtime");
+    assert!(error["message"].as_str().unwrap().contains("42"), "got: {}", error);
+    assert_eq!(events.last().unwrap()["errors"], 1);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_trace_survives_sigterm() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("wait.cog");
+    let trace = dir.path().join("trace.jsonl");
+    std::fs::write(&file, "flow main():\n    write(stdout, \"waiting\")\n    line = read(stdin)\n").unwrap();
+    let mut child = Command::new(cognos_bin())
+        .args(["run", "--trace", trace.to_str().unwrap(), file.to_str().unwrap()])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn().unwrap();
+    // The write event lands before the program blocks on stdin
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    while !std::fs::read_to_string(&trace).unwrap_or_default().contains("\"op\":\"write\"") {
+        assert!(std::time::Instant::now() < deadline, "trace never showed the write");
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    Command::new("kill").args(["-TERM", &child.id().to_string()]).status().unwrap();
+    let status = child.wait().unwrap();
+    assert!(!status.success());
+
+    let content = std::fs::read_to_string(&trace).unwrap();
+    let events: Vec<serde_json::Value> = content.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+    assert!(events.iter().any(|e| e["op"] == "write"));
+    assert_eq!(events.last().unwrap()["event"], "interrupted");
+    assert_eq!(events.last().unwrap()["signal"], "SIGTERM");
+}
+
+#[test]
+fn test_future_methods_and_pending_futures_cancelled_at_flow_exit() {
+    let (out, err, code) = run_inline(r#"flow double(x: Int) -> Int:
+    return x * 2
+
+flow spin() -> Int:
+    n = 0
+    loop max=5000000:
+        n = n + 1
+    return n
+
+flow start():
+    return async double(5)
+
+flow leaky():
+    h = async spin()
+    emit("left running")
+
+flow main():
+    h = async double(21)
+    emit(h.result(timeout=10))
+    emit(await(start()))
+    slow = async spin()
+    try:
+        slow.result(timeout=0)
+    catch e:
+        emit(e.message)
+    emit(slow.done())
+    cancel(slow)
+    leaky()
+"#, "");
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out, "42\n10\nasync task did not finish within 0s; the future is still pending\nfalse\nleft running\n");
+    // The returned future survives start(); only leaky()'s is cancelled
+    assert_eq!(err.matches("never awaited").count(), 1, "got: {}", err);
+    assert!(err.contains("flow 'leaky' ended with 1 async task(s) never awaited"), "got: {}", err);
+
+    let (_, err, code) = run_inline("flow main():\n    h = async 1\n    h.result(wait=1)\n", "");
+    assert_ne!(code, 0);
+    assert!(err.contains(".result(): unknown kwarg 'wait'"), "got: {}", err);
+}
+
+#[test]
+fn test_parallel_collects_branch_results() {
+    let out = expect_run_ok(r#"flow double(x: Int) -> Int:
+    return x * 2
+
+flow main():
+    results = parallel:
+        branch:
+            double(1)
+        branch:
+            y = 10
+            return y + 1
+        branch:
+            z = 3
+    emit(results)
+    named = parallel:
+        branch fast:
+            "f"
+        branch:
+            double(4)
+    emit(named)
+"#);
+    assert_eq!(out, "[2, 11, none]\n{\"fast\": f, \"2\": 8}\n");
+
+    // Branch variables don't leak out of a collecting block
+    let (_, err, code) = run_inline("flow main():\n    r = parallel:\n        branch:\n            y = 1\n    emit(y)\n", "");
+    assert_ne!(code, 0);
+    assert!(err.contains("undefined variable: 'y'"), "got: {}", err);
+}
+
+#[test]
+fn test_named_branches_namespace_vars_and_label_trace() {
+    let out = expect_run_ok(r#"flow main():
+    parallel:
+        branch fetch_a:
+            result = "a"
+            n = 1
+        branch fetch_b:
+            result = "b"
+        branch:
+            plain = "merged as before"
+    emit(fetch_a.result)
+    emit(fetch_b.result)
+    emit(fetch_a.n)
+    emit(plain)
+    select:
+        branch winner:
+            got = "only"
+    emit(winner.got)
+"#);
+    assert_eq!(out, "a\nb\n1\nmerged as before\nonly\n");
+
+    // A name may only be used once per block
+    let (_, err, code) = run_inline("flow main():\n    parallel:\n        branch a:\n            x = 1\n        branch a:\n            x = 2\n", "");
+    assert_ne!(code, 0);
+    assert!(err.contains("duplicate branch name 'a' in parallel block"), "got: {}", err);
+
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("b.cog");
+    std::fs::write(&file, r#"flow main():
+    parallel:
+        branch fetch_a:
+            r = think("a")
+        branch:
+            parallel:
+                branch inner:
+                    r = think("b")
+    r = think("c")
+"#).unwrap();
+    let env = dir.path().join("env.json");
+    std::fs::write(&env, r#"{"llm_responses": ["x", "x", "x"]}"#).unwrap();
+    let trace = dir.path().join("trace.jsonl");
+    let output = Command::new(cognos_bin())
+        .args(["test", "--trace", trace.to_str().unwrap(), file.to_str().unwrap(), "--env", env.to_str().unwrap()])
+        .output().unwrap();
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    let content = std::fs::read_to_string(&trace).unwrap();
+    let mut branches: Vec<serde_json::Value> = content.lines()
+        .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+        .filter(|e| e["event"] == "llm_call")
+        .map(|e| e.get("branch").cloned().unwrap_or(serde_json::Value::Null))
+        .collect();
+    branches.sort_by_key(|b| b.to_string());
+    assert_eq!(branches, vec![serde_json::json!("2/inner"), serde_json::json!("fetch_a"), serde_json::Value::Null]);
+}
+
+#[test]
+fn test_trace_events_carry_loop_iterations() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("l.cog");
+    std::fs::write(&file, r#"flow step(n: Int) -> String:
+    return think(f"step {n}")
+
+flow main():
+    for i in [1, 2]:
+        loop max=2:
+            r = step(i)
+    r = think("after")
+"#).unwrap();
+    let env = dir.path().join("env.json");
+    std::fs::write(&env, r#"{"llm_responses": ["a", "b", "c", "d", "e"]}"#).unwrap();
+    let trace = dir.path().join("trace.jsonl");
+    let output = Command::new(cognos_bin())
+        .args(["test", "--trace", trace.to_str().unwrap(), file.to_str().unwrap(), "--env", env.to_str().unwrap()])
+        .output().unwrap();
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    let content = std::fs::read_to_string(&trace).unwrap();
+    let loops: Vec<serde_json::Value> = content.lines()
+        .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+        .filter(|e| e["event"] == "llm_call")
+        .map(|e| e["loops"].clone())
+        .collect();
+    let frame = |id: u32, iteration: u32| serde_json::json!({"id": id, "iteration": iteration});
+    assert_eq!(loops, vec![
+        serde_json::json!([frame(1, 1), frame(2, 1)]),
+        serde_json::json!([frame(1, 1), frame(2, 2)]),
+        // The inner loop statement runs again, under a new id
+        serde_json::json!([frame(1, 2), frame(3, 1)]),
+        serde_json::json!([frame(1, 2), frame(3, 2)]),
+        serde_json::Value::Null,
+    ]);
+}
+
+#[test]
+fn test_verbose_logs_run_metrics() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("m.cog");
+    std::fs::write(&file, "flow main():\n    r = think(\"hi\")\n    write(stdout, r)\n").unwrap();
+    let env = dir.path().join("env.json");
+    std::fs::write(&env, r#"{"llm_responses": ["hello"]}"#).unwrap();
+    let trace = dir.path().join("trace.jsonl");
+    let output = Command::new(cognos_bin())
+        .args(["test", "-v", "--trace", trace.to_str().unwrap(), file.to_str().unwrap(), "--env", env.to_str().unwrap()])
+        .env_remove("COGNOS_LOG")
+        .output().unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Run: 0 turns, 1 LLM calls"), "got: {}", stderr);
+    let content = std::fs::read_to_string(&trace).unwrap();
+    let last: serde_json::Value = serde_json::from_str(content.lines().last().unwrap()).unwrap();
+    assert_eq!(last["event"], "metrics");
+    assert_eq!(last["scope"], "run");
+    assert_eq!(last["llm_calls"], 1);
+}
+
+#[test]
+fn test_trace_ends_with_run_metrics_without_verbose() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("m.cog");
+    let trace = dir.path().join("trace.jsonl");
+    std::fs::write(&file, "flow main():\n    write(stdout, \"hi\")\n").unwrap();
+    let output = Command::new(cognos_bin())
+        .args(["run", "--trace", trace.to_str().unwrap(), file.to_str().unwrap()])
+        .env_remove("COGNOS_LOG")
+        .output().unwrap();
+    assert!(output.status.success());
+    let content = std::fs::read_to_string(&trace).unwrap();
+    let last: serde_json::Value = serde_json::from_str(content.lines().last().unwrap()).unwrap();
+    assert_eq!(last["event"], "metrics");
+    assert_eq!(last["scope"], "run");
+}
+
+#[test]
+fn test_trace_flow_call_tree() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("tree.cog");
+    let trace = dir.path().join("trace.jsonl");
+    std::fs::write(&file, "flow sq(x: Int) -> Int:\n    return x * x\n\nflow boom():\n    return 1 / 0\n\nflow main():\n    write(stdout, f\"{sq(3)}\")\n    try:\n        boom()\n    catch err:\n        write(stdout, \"caught\")\n").unwrap();
+    for (level, has_args) in [("metrics", false), ("full", true)] {
+        let output = Command::new(cognos_bin())
+            .args(["run", "--trace", trace.to_str().unwrap(), "--trace-level", level, file.to_str().unwrap()])
+            .output().unwrap();
+        assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+        let events: Vec<serde_json::Value> = std::fs::read_to_string(&trace).unwrap().lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .filter(|e: &serde_json::Value| e["event"].as_str().unwrap().starts_with("flow_"))
+            .collect();
+        let tree: Vec<(&str, &str, u64)> = events.iter()
+            .map(|e| (e["event"].as_str().unwrap(), e["flow"].as_str().unwrap(), e["depth"].as_u64().unwrap()))
+            .collect();
+        assert_eq!(tree, [
+            ("flow_start", "main", 0), ("flow_start", "sq", 1), ("flow_end", "sq", 1),
+            ("flow_start", "boom", 1), ("flow_end", "boom", 1), ("flow_end", "main", 0),
+        ]);
+        assert_eq!(events[2]["result_type"], "Int");
+        assert!(events[4]["error"].is_string());
+        assert_eq!(events[1].get("args").is_some(), has_args);
+        if has_args {
+            assert_eq!(events[1]["args"], "{\"x\":3}");
+        }
+    }
+}
+
+#[test]
+fn test_session_diff() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("agent.cog");
+    let a = dir.path().join("a.json");
+    let b = dir.path().join("b.json");
+    std::fs::write(&file, "flow main():\n    notes = [\"one\"]\n    mood = \"ok\"\n").unwrap();
+    let run = |session: &std::path::Path| Command::new(cognos_bin())
+        .args(["run", "--session", session.to_str().unwrap(), file.to_str().unwrap()])
+        .output().unwrap();
+    assert!(run(&a).status.success());
+    std::fs::write(&file, "flow main():\n    notes = [\"one\", \"two\"]\n    count = 1\n").unwrap();
+    assert!(run(&b).status.success());
+
+    let output = Command::new(cognos_bin()).args(["session", "diff", a.to_str().unwrap(), b.to_str().unwrap()]).output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains("+ count = 1\n"), "got: {}", stdout);
+    assert!(stdout.contains("- mood = \"ok\"\n"), "got: {}", stdout);
+    assert!(stdout.contains("~ notes\n"), "got: {}", stdout);
+    assert!(stdout.contains("  +   \"two\"\n"), "got: {}", stdout);
+    assert!(stdout.ends_with("1 added, 1 removed, 1 changed\n"), "got: {}", stdout);
+
+    std::fs::write(&b, "[1, 2]").unwrap();
+    let output = Command::new(cognos_bin()).args(["session", "diff", a.to_str().unwrap(), b.to_str().unwrap()]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is not a session file"));
+}
+
+#[test]
+fn test_session_show_set_unset() {
+    let dir = tempfile::tempdir().unwrap();
+    let session = dir.path().join("s.json");
+    std::fs::write(&session, r#"{"count": 3, "pending": "task-1"}"#).unwrap();
+    let path = session.to_str().unwrap();
+    let cognos = |args: &[&str]| Command::new(cognos_bin()).arg("session").args(args).output().unwrap();
+
+    let out = cognos(&["set", path, "count=4", "mode=\"chat\""]);
+    assert!(out.status.success(), "stderr: {}", String::from_utf8_lossy(&out.stderr));
+    let out = cognos(&["unset", path, "pending"]);
+    assert!(out.status.success());
+    let out = cognos(&["show", path]);
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "count: Int = 4\nmode: String = \"chat\"\n");
+
+    let out = cognos(&["unset", path, "pending"]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("has no variable 'pending'"));
+    let out = cognos(&["set", path, "mode=chat"]);
+    assert!(!out.status.success());
+    // A rejected edit leaves the file alone
+    let saved: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&session).unwrap()).unwrap();
+    assert_eq!(saved, serde_json::json!({"count": 4, "mode": "chat"}));
+}
+
+#[test]
+fn test_autosave_writes_session_while_running() {
+    use std::io::Write as _;
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("agent.cog");
+    let session = dir.path().join("s.json");
+    std::fs::write(&file, "flow main():\n    first = read(stdin)\n    count = 1\n    second = read(stdin)\n").unwrap();
+    let mut child = Command::new(cognos_bin())
+        .args(["run", "--session", session.to_str().unwrap(), "--autosave", "1s", file.to_str().unwrap()])
+        .stdin(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn().unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    // Let the interval pass, so the statement after this read saves
+    std::thread::sleep(std::time::Duration::from_millis(1200));
+    writeln!(stdin, "hello").unwrap();
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    while !session.exists() {
+        assert!(std::time::Instant::now() < deadline, "no autosave while the program waited on stdin");
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    let saved: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&session).unwrap()).unwrap();
+    assert_eq!(saved["first"], "hello");
+    assert!(saved.get("second").is_none());
+    drop(stdin);
+    assert!(child.wait().unwrap().success());
+
+    let output = Command::new(cognos_bin())
+        .args(["run", "--autosave", "1s", file.to_str().unwrap()])
+        .output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--autosave needs --session"));
+}
+
+#[test]
+fn test_profiles_from_cognos_toml() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("cognos.toml"), r#"
+[profile.default]
+session = "default.json"
+
+[profile.ops]
+allow_shell = true
+session = "ops.json"
+"#).unwrap();
+    std::fs::write(dir.path().join("agent.cog"), "flow main():\n    out = __exec_shell__(\"echo hi\")\n    write(stdout, out.strip())\n").unwrap();
+    std::fs::write(dir.path().join("plain.cog"), "flow main():\n    x = 1\n").unwrap();
+    let cognos = |args: &[&str]| Command::new(cognos_bin()).args(args).current_dir(dir.path())
+        .env_remove("COGNOS_PROFILE").output().unwrap();
+
+    let out = cognos(&["run", "--profile", "ops", "agent.cog"]);
+    assert!(out.status.success(), "stderr: {}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "hi\n");
+    assert!(dir.path().join("ops.json").exists());
+
+    // No --profile: [profile.default] applies; flags still win over it
+    assert!(cognos(&["run", "plain.cog"]).status.success());
+    assert!(dir.path().join("default.json").exists());
+    assert!(cognos(&["run", "--session", "mine.json", "plain.cog"]).status.success());
+    assert!(dir.path().join("mine.json").exists());
+    assert!(!cognos(&["run", "agent.cog"]).status.success());
+
+    let out = cognos(&["run", "--profile", "prod", "plain.cog"]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("no profile 'prod' (available: default, ops)"));
+
+    std::fs::write(dir.path().join("cognos.toml"), "[profile.prod]\notlp = \"http://collector:4317\"\n").unwrap();
+    let out = cognos(&["run", "--profile", "prod", "plain.cog"]);
+    assert!(String::from_utf8_lossy(&out.stderr).contains("unknown field `otlp`"), "got: {}", String::from_utf8_lossy(&out.stderr));
+}
+
+#[test]
+fn test_flow_model_overrides_from_cognos_toml() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("cognos.toml"), r#"
+[flows."draft_*"]
+model = "cheap-model"
+temperature = 0.2
+
+[profile.prod.flows.draft_final]
+model = "big-model"
+"#).unwrap();
+    std::fs::write(dir.path().join("agent.cog"), "\
+flow draft_intro() -> String:
+    return think(\"intro\", model=\"expensive\")
+
+flow draft_final() -> String:
+    return think(\"final\", model=\"expensive\", temperature=1)
+
+flow main():
+    a = draft_intro()
+    b = draft_final()
+    c = think(\"rest\", model=\"expensive\")
+").unwrap();
+    std::fs::write(dir.path().join("mock.json"), r#"{"llm_responses": ["a", "b", "c"]}"#).unwrap();
+    let models = |args: &[&str]| {
+        let out = Command::new(cognos_bin()).args(args)
+            .args(["agent.cog", "--env", "mock.json", "--trace", "trace.jsonl"])
+            .current_dir(dir.path()).env_remove("COGNOS_PROFILE").output().unwrap();
+        assert!(out.status.success(), "stderr: {}", String::from_utf8_lossy(&out.stderr));
+        std::fs::read_to_string(dir.path().join("trace.jsonl")).unwrap().lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .filter(|e| e["event"] == "llm_call")
+            .map(|e| e["model"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(models(&["test"]), ["cheap-model", "cheap-model", "expensive"]);
+    // The profile's own [flows] win over the top-level ones
+    assert_eq!(models(&["test", "--profile", "prod"]), ["cheap-model", "big-model", "expensive"]);
+
+    std::fs::write(dir.path().join("cognos.toml"), "[flows.main]\ntemperature = 3\n").unwrap();
+    let out = Command::new(cognos_bin()).args(["test", "agent.cog", "--env", "mock.json"])
+        .current_dir(dir.path()).env_remove("COGNOS_PROFILE").output().unwrap();
+    assert!(String::from_utf8_lossy(&out.stderr).contains("temperature must be between 0 and 2"));
+}
+
+#[test]
+fn test_think_finish_reason_and_auto_continue() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("test.cog"), r#"flow main():
+    short = think("tell a story", model="m")
+    write(stdout, f"[{short}]")
+    full = think("tell a story", model="m", auto_continue=true)
+    write(stdout, f"[{full}]")
+    r = think("tell a story", model="m", tools=["main"])
+    write(stdout, r["finish_reason"])
+"#).unwrap();
+    std::fs::write(dir.path().join("mock.json"), serde_json::json!({"llm_responses": [
+        {"content": "Once upon", "finish_reason": "length"},
+        {"content": "Once upon", "finish_reason": "length"},
+        {"content": " a time.", "finish_reason": "stop"},
+        {"content": "nope", "finish_reason": "content_filter"},
+    ]}).to_string()).unwrap();
+    let output = Command::new(cognos_bin())
+        .args(["test", "test.cog", "--env", "mock.json", "--trace", "trace.jsonl"])
+        .current_dir(dir.path()).output().unwrap();
+    let (out, err) = (String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+    assert!(output.status.success(), "stderr: {}", err);
+    assert!(out.contains("  [Once upon]\n  [Once upon a time.]\n  content_filter\n"), "got: {}", out);
+    // Warned once for the cut-off reply left as is, not for the continued one
+    assert_eq!(err.matches("hit its output length limit").count(), 1, "got: {}", err);
+    assert!(err.contains("content filter"), "got: {}", err);
+
+    let events: Vec<serde_json::Value> = std::fs::read_to_string(dir.path().join("trace.jsonl")).unwrap()
+        .lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+    let reasons: Vec<&str> = events.iter().filter(|e| e["event"] == "llm_call")
+        .map(|e| e["finish_reason"].as_str().unwrap()).collect();
+    assert_eq!(reasons, ["length", "length", "stop", "content_filter"]);
+    let warnings: Vec<&str> = events.iter().filter(|e| e["event"] == "warning")
+        .map(|e| e["category"].as_str().unwrap()).collect();
+    assert_eq!(warnings, ["length", "length", "content_filter"]);
+}
+
+#[test]
+fn test_value_limits_stop_runaway_growth() {
+    let src = r#"flow main():
+    s = "x"
+    try:
+        loop:
+            s = s + s
+    catch err:
+        write(stdout, err.message)
+    items = []
+    try:
+        loop:
+            items = items + [1]
+    catch err:
+        write(stdout, err.message)
+    v = 1
+    try:
+        loop:
+            v = [v]
+    catch err:
+        write(stdout, err.message)
+"#;
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("limits.cog");
+    std::fs::write(&path, src).unwrap();
+    let output = Command::new(cognos_bin())
+        .args(["run", "--max-string-mb", "1", "--max-list-len", "100", "--max-depth", "10"])
+        .arg(&path).output().unwrap();
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout),
+        "string of 2097152 bytes is over the 1048576-byte limit (--max-string-mb)\n\
+         collection of 101 items is over the 100-item limit (--max-list-len)\n\
+         value nested more than 10 levels deep (--max-depth)\n");
+
+    // The defaults are finite too
+    assert!(expect_error("flow main():\n    s = \"x\" * 100000000\n").contains("over the 67108864-byte limit"));
+}
+
+#[test]
+fn test_map_keys_stay_in_insertion_order_and_unique() {
+    let out = expect_run_ok(r#"flow main():
+    m = {"a": 1, "b": 2, "a": 3}
+    write(stdout, m)
+    m["c"] = 4
+    m["b"] = 5
+    write(stdout, m.keys())
+    m = remove(m, "a")
+    m["a"] = 6
+    write(stdout, m)
+    write(stdout, m == {"a": 6, "c": 4, "b": 5})
+"#);
+    assert_eq!(out, "{\"a\": 3, \"b\": 2}\n[a, b, c]\n{\"b\": 5, \"c\": 4, \"a\": 6}\ntrue\n");
+}
+
+#[test]
+fn test_int_overflow_is_an_error_and_floats_follow_ieee() {
+    let out = expect_run_ok(r#"flow main():
+    big = 9223372036854775807
+    small = -big - 1
+    for attempt in ["add", "mul", "div", "neg"]:
+        try:
+            if attempt == "add":
+                x = big + 1
+            elif attempt == "mul":
+                x = big * 2
+            elif attempt == "div":
+                x = small // -1
+            else:
+                x = -small
+        catch err:
+            write(stdout, err.message)
+    write(stdout, small % -1)
+    n = float("nan")
+    write(stdout, [n, float("inf"), -float("inf"), 1e308 * 10.0])
+    write(stdout, [n == n, n != n, n < 1.0, n >= 1.0])
+    write(stdout, f"{n:.2f} {small:,d}")
+    try:
+        x = int(float("inf"))
+    catch err:
+        write(stdout, err.message)
+    write(stdout, int(-9223372036854775808.0))
+"#);
+    assert_eq!(out, "integer overflow in 9223372036854775807 + 1\n\
+        integer overflow in 9223372036854775807 * 2\n\
+        integer overflow in -9223372036854775808 // -1\n\
+        integer overflow negating -9223372036854775808\n\
+        0\n\
+        [nan, inf, -inf, inf]\n\
+        [false, true, false, false]\n\
+        nan -9,223,372,036,854,775,808\n\
+        cannot convert inf to Int\n\
+        -9223372036854775808\n");
+}
+
+#[test]
+fn test_true_division_floor_division_and_divmod() {
+    let out = expect_run_ok(r#"flow main():
+    write(stdout, [10 / 4, type_of(10 / 2), 10 // 3, -7 // 2, 7 // -2, 7.5 // 2, type_of(7.5 // 2)])
+    write(stdout, [divmod(7, 2), divmod(-7, 2), divmod(7, -2), divmod(7.5, 2)])
+    write(stdout, [-7 % 2, 7 % -2, -7 % -2, 6 % -3])
+    a = -7
+    b = 2
+    write(stdout, a == (a // b) * b + a % b)
+    write(stdout, [7.5 % 2, -7.5 % 2, 7 % -2.5, -7.5 % -2, 6.0 % 3])
+    write(stdout, [divmod(-7.5, 2)[1] == -7.5 % 2, -7.5 == (-7.5 // 2) * 2 + -7.5 % 2])
+    write(stdout, 2 + 10 // 3 * 2)
+    try:
+        x = divmod(1, 0)
+    catch err:
+        write(stdout, err.message)
+    try:
+        x = 1.5 % 0
+    catch err:
+        write(stdout, err.message)
+"#);
+    assert_eq!(out, "[2.5, Float, 3, -4, -4, 3, Float]\n[[3, 1], [-4, 1], [-4, -1], [3, 1.5]]\n[1, -1, -1, 0]\ntrue\n\
+        [1.5, 0.5, -0.5, -1.5, 0]\n[true, true]\n8\ndivision by zero\nmodulo by zero\n");
+}
+
+#[test]
+fn test_builtin_names_cannot_be_rebound() {
+    assert!(expect_error("flow main():\n    stdin = \"x\"\n").contains("line 2: 'stdin' is a built-in and can't be reassigned"));
+    assert!(expect_error("flow main():\n    for http in [1]:\n        pass\n").contains("'http' is a built-in"));
+    assert!(expect_error("flow f(stdout: String):\n    pass\n").contains("'stdout' is a built-in"));
+    assert!(expect_error("flow write(x: String):\n    pass\n").contains("flow 'write' has the name of a built-in function"));
+    // Variables may share a function's name, and some built-ins step aside for a flow
+    let out = expect_run_ok("flow round(x: Int) -> Int:\n    return x\n\nflow main():\n    file = \"a\"\n    write(stdout, f\"{file}{round(7)}\")\n");
+    assert_eq!(out, "a7\n");
+}
+
+#[test]
+fn test_bare_builtin_name_suggests_a_call() {
+    for name in ["save", "screenshot", "think"] {
+        let err = expect_error(&format!("flow main():\n    x = {}\n", name));
+        assert!(err.contains(&format!("'{}' is a function — did you mean {}(...)?", name, name)), "stderr: {}", err);
+    }
+    assert!(expect_error("flow main():\n    x = nothing_here\n").contains("undefined variable: 'nothing_here'"));
+}
+
+#[test]
+fn test_variadic_flow_parameters() {
+    let out = expect_run_ok(r#"flow join(label: String, *parts: String, sep: String = " ") -> String:
+    return f"{label}: {parts.join(sep)}"
+
+flow main():
+    write(stdout, join("a", "x", "y", "z"))
+    write(stdout, join("b"))
+    write(stdout, join("c", "x", "y", sep=","))
+    write(stdout, join("d", parts=["p", "q"]))
+"#);
+    assert_eq!(out, "a: x y z\nb: \nc: x,y\nd: p q\n");
+    assert!(expect_error("flow f(*xs: Int):\n    pass\n\nflow main():\n    f(1, xs=[2])\n").contains("duplicate argument for 'xs'"));
+    assert!(expect_error("flow f(*xs: Int):\n    pass\n\nflow main():\n    f(xs=2)\n").contains("*xs passed by keyword must be a List"));
+}
+
+#[test]
+fn test_help_and_introspection() {
+    let code = r#"type Mood: "happy" | "sad"
+
+type Review:
+    score: Int
+    note?: String
+
+flow search(query: String, *sites: String, limit: Int = 5) -> List:
+    "Search the web"
+    return []
+
+flow main():
+    help("think")
+    help("search")
+    help("Mood")
+    for f in flows():
+        write(stdout, f)
+    for t in types():
+        write(stdout, t)
+"#;
+    let out = expect_run_ok(code);
+    let lines: Vec<&str> = out.lines().collect();
+    assert!(lines[0].starts_with("think(context, model=\"\""), "got: {}", out);
+    assert!(lines.contains(&"flow search(query: String, *sites: String, limit: Int = 5) -> List"), "got: {}", out);
+    assert!(lines.contains(&"    Search the web"), "got: {}", out);
+    assert!(lines.contains(&"type Mood: \"happy\" | \"sad\""), "got: {}", out);
+    assert!(out.contains(r#"{"name": search, "description": Search the web, "params": [{"name": query, "type": String, "default": none, "variadic": false}, {"name": sites, "type": String, "default": none, "variadic": true}, {"name": limit, "type": Int, "default": 5, "variadic": false}], "returns": List}"#), "got: {}", out);
+    assert!(out.contains(r#"{"name": main, "description": none, "params": [], "returns": none}"#), "got: {}", out);
+    assert!(out.contains(r#"{"name": Mood, "kind": enum, "variants": [happy, sad]}"#), "got: {}", out);
+    assert!(out.contains(r#"{"name": Review, "kind": struct, "fields": [{"name": score, "type": Int, "optional": false}, {"name": note, "type": String, "optional": true}]}"#), "got: {}", out);
+
+    let listing = expect_run_ok("flow main():\n    help()\n");
+    assert!(listing.starts_with("Built-in functions:\n"), "got: {}", listing);
+    assert!(listing.contains("\n  flows ") && listing.contains("Flows:\n  flow main\n"), "got: {}", listing);
+    assert!(expect_error("flow main():\n    help(\"nope\")\n").contains("no built-in, flow or type named 'nope'"));
+}
+
+#[test]
+fn test_think_stream_passes_tokens_to_on_token() {
+    let dir = tempfile::tempdir().unwrap();
+    let cog = dir.path().join("test.cog");
+    let mock = dir.path().join("mock.json");
+    std::fs::write(&cog, r#"
+flow show(token: String):
+    write(stdout, f"token: {token}")
+
+flow main():
+    answer = think("hi", stream=true, on_token="show")
+    write(stdout, f"answer: {answer}")
+    plain = think("again", stream=true)
+    write(stdout, f"plain: {plain}")
+"#).unwrap();
+    std::fs::write(&mock, r#"{"stdin": [], "llm_responses": ["hello there", "second"]}"#).unwrap();
+    let output = Command::new(cognos_bin())
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
+        .output().unwrap();
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // A streamed reply without on_token= goes to stdout as its own line
+    assert!(stdout.contains("  token: hello there\n  answer: hello there\n  second\n  plain: second\n"), "got: {}", stdout);
+
+    assert!(expect_error("flow main():\n    think(\"x\", on_token=\"nope\", stream=true)\n").contains("on_token: flow 'nope' not defined"));
+    assert!(expect_error("flow f(t: String):\n    pass\n\nflow main():\n    think(\"x\", on_token=\"f\")\n").contains("on_token= only takes effect with stream=true"));
+    assert!(expect_error("flow f(t: String):\n    pass\n\nflow main():\n    think(\"x\", stream=true, tools=[\"f\"])\n").contains("can't be combined with tools="));
+}
+
+#[test]
+fn test_schema_builtin_returns_json_schema() {
+    let code = r#"type Mood: "happy" | "sad"
+
+type Review:
+    score: Int
+    mood: Mood
+    tags?: List[String]
+
+type Node:
+    value: Int
+    children: List[Node]
+
+flow main():
+    s = schema("Review")
+    write(stdout, s["title"])
+    write(stdout, s["required"])
+    write(stdout, s["properties"]["mood"]["enum"])
+    write(stdout, s["properties"]["tags"]["items"]["type"])
+    write(stdout, schema("Map[String, Float]")["additionalProperties"]["type"])
+    write(stdout, schema("Node")["properties"]["children"]["items"])
+    write(stdout, schema("Mood", text=true))
+"#;
+    let out = expect_run_ok(code);
+    assert_eq!(out, "Review\n[score, mood]\n[happy, sad]\nstring\nnumber\n{\"$ref\": #}\n{\n  \"enum\": [\n    \"happy\",\n    \"sad\"\n  ],\n  \"title\": \"Mood\",\n  \"type\": \"string\"\n}\n");
+    assert!(expect_error("flow main():\n    schema(\"Nope\")\n").contains("unknown type 'Nope'"));
+    assert!(expect_error("flow main():\n    schema(\"List[\")\n").contains("schema(): 'List[' is not a type"));
+}
+
+#[test]
+fn test_define_flow_behind_allow_dynamic() {
+    let code = r#"flow main():
+    src = "flow double(x: Int) -> Int:\n    return x * 2"
+    name = define_flow(src)
+    print(name, invoke(name, {"x": 21}), double(4))
+    define_flow("flow double(x: Int) -> Int:\n    return x * 3")
+    print(double(4))
+    try:
+        define_flow("flow main():\n    pass")
+    catch err:
+        print(err.message)
+    try:
+        define_flow("flow a():\n    pass\nflow b():\n    pass")
+    catch err:
+        print(err.message)
+"#;
+    assert!(expect_error(code).contains("define_flow() is disabled — use: cognos run --allow-dynamic file.cog"));
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("dynamic.cog");
+    std::fs::write(&path, code).unwrap();
+    let trace = dir.path().join("trace.jsonl");
+    let output = Command::new(cognos_bin()).args(["run", "--allow-dynamic"]).arg(&path).arg("--trace").arg(&trace).output().unwrap();
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "double 42 8\n12\n\
+        define_flow(): flow 'main' is already defined by the program and can't be replaced\n\
+        define_flow() takes exactly one flow, got 2\n");
+
+    // The source is traced whole, even at the metrics level
+    let defined: Vec<serde_json::Value> = std::fs::read_to_string(&trace).unwrap().lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .filter(|e: &serde_json::Value| e["event"] == "flow_defined")
+        .collect();
+    assert_eq!(defined.len(), 2);
+    assert_eq!(defined[0]["flow"], "double");
+    assert_eq!(defined[0]["replaced"], false);
+    assert_eq!(defined[0]["source"], "flow double(x: Int) -> Int:\n    return x * 2");
+    assert_eq!(defined[1]["replaced"], true);
+}
+
+#[test]
+fn test_fmt_check_and_rewrite() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("messy.cog");
+    std::fs::write(&file, "flow main():\n  x=1   # one\n  emit( x )\n").unwrap();
+
+    let check = Command::new(cognos_bin()).args(["fmt", "--check"]).arg(&file).output().unwrap();
+    assert_eq!(check.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&check.stdout).contains("would reformat"));
+
+    let output = Command::new(cognos_bin()).arg("fmt").arg(&file).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), "flow main:\n    x = 1  # one\n    emit(x)\n");
+
+    let check = Command::new(cognos_bin()).args(["fmt", "--check"]).arg(&file).output().unwrap();
+    assert!(check.status.success());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_plugin_adds_builtins_channels_and_models() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("echo.rs");
+    std::fs::write(&source, r#"use std::ffi::{c_char, CStr, CString};
+
+#[no_mangle]
+pub extern "C" fn cognos_plugin_manifest() -> *const c_char {
+    c"{\"abi\": 1, \"name\": \"echo\", \"builtins\": [{\"name\": \"plugin_echo\", \"signature\": \"plugin_echo(...) -> Map\", \"summary\": \"The request, as the plugin saw it\"}, {\"name\": \"plugin_fail\"}], \"channels\": [\"memo\"], \"models\": [{\"prefix\": \"echo\"}]}".as_ptr()
+}
+
+#[no_mangle]
+pub extern "C" fn cognos_plugin_call(request: *const c_char) -> *mut c_char {
+    let request = unsafe { CStr::from_ptr(request) }.to_str().unwrap();
+    let reply = if request.contains("\"plugin_fail\"") {
+        "{\"error\": \"boom\"}".to_string()
+    } else if request.contains("\"call\":\"chat\"") {
+        "{\"ok\": {\"content\": \"plugin says hi\"}}".to_string()
+    } else if request.contains("\"call\":\"channel_read\"") {
+        "{\"ok\": \"from memo\"}".to_string()
+    } else {
+        format!("{{\"ok\": {}}}", request)
+    };
+    CString::new(reply).unwrap().into_raw()
+}
+
+#[no_mangle]
+pub extern "C" fn cognos_plugin_free(reply: *mut c_char) {
+    drop(unsafe { CString::from_raw(reply) });
+}
+"#).unwrap();
+    let library = dir.path().join("libecho.so");
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let status = Command::new(rustc)
+        .args(["--edition", "2021", "--crate-type", "cdylib", "-o"])
+        .arg(&library)
+        .arg(&source)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let file = dir.path().join("main.cog");
+    std::fs::write(&file, r#"flow main():
+    r = plugin_echo(1, "a", k=true)
+    print(r["call"])
+    print(r["args"])
+    print(r["kwargs"]["k"])
+    m = channel("memo", room="x")
+    write(m, "hello")
+    print(read(m))
+    print(think("hi", model="echo:small"))
+    try:
+        plugin_fail()
+    catch e:
+        print(e.message)
+    help("plugin_echo")
+"#).unwrap();
+    let trace = dir.path().join("trace.jsonl");
+    let output = Command::new(cognos_bin())
+        .args(["run", "--plugin"])
+        .arg(&library)
+        .arg("--trace")
+        .arg(&trace)
+        .arg(&file)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(stdout, "builtin\n[1, a]\ntrue\nfrom memo\nplugin says hi\nplugin_fail(): boom\nplugin_echo(...) -> Map\n    The request, as the plugin saw it\n");
+    let trace = std::fs::read_to_string(&trace).unwrap();
+    assert_eq!(trace.matches("\"event\":\"plugin_call\"").count(), 4, "{}", trace);
+
+    // Without the plugin its functions don't exist
+    let output = Command::new(cognos_bin()).arg("run").arg(&file).output().unwrap();
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unknown function: plugin_echo()"));
+}
+
+#[test]
+fn test_lsp_speaks_json_rpc_over_stdio() {
+    use std::io::Write as _;
+    let messages = [
+        serde_json::json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {}}),
+        serde_json::json!({"jsonrpc": "2.0", "method": "initialized", "params": {}}),
+        serde_json::json!({"jsonrpc": "2.0", "method": "textDocument/didOpen", "params": {"textDocument": {
+            "uri": "untitled:a.cog", "languageId": "cognos", "version": 1, "text": "flow main():\n    x = \n",
+        }}}),
+        serde_json::json!({"jsonrpc": "2.0", "id": 2, "method": "workspace/symbol", "params": {}}),
+        serde_json::json!({"jsonrpc": "2.0", "id": 3, "method": "shutdown"}),
+        serde_json::json!({"jsonrpc": "2.0", "method": "exit"}),
+    ];
+    let mut child = Command::new(cognos_bin()).arg("lsp")
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn().unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    for msg in &messages {
+        let body = msg.to_string();
+        write!(stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+    }
+    drop(stdin);
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let replies: Vec<serde_json::Value> = stdout.split("Content-Length: ").skip(1)
+        .map(|m| serde_json::from_str(m.split_once("\r\n\r\n").unwrap().1).unwrap())
+        .collect();
+    assert_eq!(replies.len(), 4, "got: {}", stdout);
+    assert_eq!(replies[0]["result"]["capabilities"]["hoverProvider"], true);
+    assert_eq!(replies[1]["method"], "textDocument/publishDiagnostics");
+    assert_eq!(replies[1]["params"]["diagnostics"][0]["range"]["start"]["line"], 1);
+    assert_eq!(replies[2]["error"]["code"], -32601);
+    assert_eq!(replies[3], serde_json::json!({"jsonrpc": "2.0", "id": 3, "result": null}));
+}
+
+#[test]
+fn test_wasm_tool_reads_only_mapped_dirs() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir(dir.path().join("data")).unwrap();
+    std::fs::write(dir.path().join("data/note.txt"), "{\"note\": \"from the sandbox\"}").unwrap();
+    std::fs::write(dir.path().join("secret.txt"), "secret").unwrap();
+    // cat: print the file under /data (fd 3) named on stdin
+    let module = wat::parse_str(r#"
+        (module
+          (import "wasi_snapshot_preview1" "fd_read" (func $read (param i32 i32 i32 i32) (result i32)))
+          (import "wasi_snapshot_preview1" "fd_write" (func $write (param i32 i32 i32 i32) (result i32)))
+          (import "wasi_snapshot_preview1" "path_open" (func $open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+          (import "wasi_snapshot_preview1" "proc_exit" (func $exit (param i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 512) "denied")
+          (func (export "_start")
+            (i32.store (i32.const 0) (i32.const 1024))
+            (i32.store (i32.const 4) (i32.const 512))
+            (drop (call $read (i32.const 0) (i32.const 0) (i32.const 1) (i32.const 8)))
+            (if (call $open (i32.const 3) (i32.const 0) (i32.const 1024) (i32.load (i32.const 8))
+                            (i32.const 0) (i64.const 2) (i64.const 0) (i32.const 0) (i32.const 16))
+              (then
+                (i32.store (i32.const 0) (i32.const 512))
+                (i32.store (i32.const 4) (i32.const 6))
+                (drop (call $write (i32.const 2) (i32.const 0) (i32.const 1) (i32.const 8)))
+                (call $exit (i32.const 1))))
+            (i32.store (i32.const 0) (i32.const 2048))
+            (i32.store (i32.const 4) (i32.const 4096))
+            (drop (call $read (i32.load (i32.const 16)) (i32.const 0) (i32.const 1) (i32.const 8)))
+            (i32.store (i32.const 4) (i32.load (i32.const 8)))
+            (drop (call $write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))))
+    "#).unwrap();
+    std::fs::write(dir.path().join("cat.wasm"), module).unwrap();
+
+    let file = dir.path().join("main.cog");
+    std::fs::write(&file, r#"flow cat(path: String) -> Map:
+    "Read a file from the data directory"
+    return wasm("cat.wasm", path, ro_dirs={"/data": "data"})
+
+flow main():
+    print(cat("note.txt")["note"])
+    try:
+        cat("../secret.txt")
+    catch e:
+        print(e.message)
+"#).unwrap();
+    let trace = dir.path().join("trace.jsonl");
+    let output = Command::new(cognos_bin())
+        .current_dir(dir.path())
+        .args(["run", "--trace"])
+        .arg(&trace)
+        .arg(&file)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(stdout, "from the sandbox\ncat.wasm exited with status 1: denied\n");
+    let trace = std::fs::read_to_string(&trace).unwrap();
+    assert_eq!(trace.matches("\"event\":\"wasm_exec\"").count(), 2, "{}", trace);
+}
+
+#[test]
+fn test_grpc_call_against_a_server() {
+    use bytes::Bytes;
+    // A shop.Orders server: Get answers with the caller's x-user as the name,
+    // or NOT_FOUND for id 404; Watch streams two orders
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    listener.set_nonblocking(true).unwrap();
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(async move {
+            let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut connection = h2::server::handshake(socket).await.unwrap();
+                    while let Some(Ok((request, mut respond))) = connection.accept().await {
+                        let (head, mut body) = request.into_parts();
+                        let mut data = Vec::new();
+                        while let Some(chunk) = body.data().await {
+                            data.extend_from_slice(&chunk.unwrap());
+                        }
+                        // 5-byte frame header, then GetRequest { id = 1 }
+                        assert_eq!(&data[..6], &[0, 0, 0, 0, 2, 0x08]);
+                        let id = data[6];
+                        let user = head.headers.get("x-user").map_or("", |v| v.to_str().unwrap()).to_string();
+                        let order = |name: &str| {
+                            // Order { id = 1; name = 2; status = 3 (SHIPPED) }
+                            let mut message = vec![0x08, id, 0x12, name.len() as u8];
+                            message.extend_from_slice(name.as_bytes());
+                            message.extend_from_slice(&[0x18, 0x01]);
+                            let mut frame = vec![0, 0, 0, 0, message.len() as u8];
+                            frame.extend(message);
+                            frame
+                        };
+                        let mut response = http::Response::builder().header("content-type", "application/grpc");
+                        if id == 44 {
+                            response = response.header("grpc-status", "5").header("grpc-message", "order%2044%20not%20found");
+                            respond.send_response(response.body(()).unwrap(), true).unwrap();
+                            continue;
+                        }
+                        let mut send = respond.send_response(response.body(()).unwrap(), false).unwrap();
+                        let frames = if head.uri.path() == "/shop.Orders/Watch" {
+                            [order("a"), order("b")].concat()
+                        } else {
+                            order(&user)
+                        };
+                        send.send_data(Bytes::from(frames), false).unwrap();
+                        let mut trailers = http::HeaderMap::new();
+                        trailers.insert("grpc-status", "0".parse().unwrap());
+                        send.send_trailers(trailers).unwrap();
+                    }
+                });
+            }
+        });
+    });
+
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("shop.proto"), r#"
+syntax = "proto3";
+package shop;
+
+service Orders {
+  rpc Get (GetRequest) returns (Order);
+  rpc Watch (GetRequest) returns (stream Order);
+}
+message GetRequest { int64 id = 1; }
+message Order {
+  int64 id = 1;
+  string name = 2;
+  Status status = 3;
+  repeated string tags = 4;
+  enum Status { PENDING = 0; SHIPPED = 1; }
+}
+"#).unwrap();
+    let file = dir.path().join("main.cog");
+    std::fs::write(&file, format!(r#"flow main():
+    endpoint = "localhost:{port}"
+    order = grpc.call(endpoint, "Orders", "Get", {{"id": 7}}, proto="shop.proto", metadata={{"x-user": "ada"}})
+    print(order)
+    orders = grpc.call(endpoint, "shop.Orders", "Watch", {{"id": 8}}, proto="shop.proto")
+    print(orders.length)
+    try:
+        grpc.call(endpoint, "Orders", "Get", {{"id": 44}}, proto="shop.proto")
+    catch e:
+        print(e.message)
+    try:
+        grpc.call(endpoint, "Orders", "Get", {{"order": 1}}, proto="shop.proto")
+    catch e:
+        print(e.message)
+"#)).unwrap();
+    let trace = dir.path().join("trace.jsonl");
+    let output = Command::new(cognos_bin())
+        .current_dir(dir.path())
+        .args(["run", "--trace"])
+        .arg(&trace)
+        .arg(&file)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(stdout, concat!(
+        "{\"id\": 7, \"name\": ada, \"status\": SHIPPED, \"tags\": []}\n",
+        "2\n",
+        "grpc.call(Orders/Get): NOT_FOUND: order 44 not found\n",
+        "grpc.call(Orders/Get): shop.GetRequest: message shop.GetRequest has no field 'order'\n",
+    ));
+    let trace = std::fs::read_to_string(&trace).unwrap();
+    assert_eq!(trace.matches("\"event\":\"grpc_call\"").count(), 4, "{}", trace);
+}
+
+#[test]
+fn test_nats_channel_against_a_server() {
+    use std::io::{BufRead, BufReader, Write};
+    // A NATS server that delivers one order once the client subscribes and
+    // records what the client sends
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = std::thread::spawn(move || {
+        let (mut socket, _) = listener.accept().unwrap();
+        socket.write_all(b"INFO {\"server_id\":\"test\"}\r\n").unwrap();
+        let mut reader = BufReader::new(socket.try_clone().unwrap());
+        let mut seen = Vec::new();
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap_or(0) > 0 {
+            let got = line.trim_end().to_string();
+            line.clear();
+            match got.split(' ').next().unwrap() {
+                "PING" => socket.write_all(b"PONG\r\n").unwrap(),
+                "SUB" => {
+                    let sid = got.rsplit(' ').next().unwrap();
+                    socket.write_all(format!("MSG orders.new {sid} _INBOX.1 9\r\nwidget x2\r\n").as_bytes()).unwrap();
+                    seen.push(got);
+                }
+                "PUB" => {
+                    let mut payload = String::new();
+                    reader.read_line(&mut payload).unwrap();
+                    seen.push(format!("{} {}", got, payload.trim_end()));
+                }
+                _ => seen.push(got),
+            }
+        }
+        seen
+    });
+
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("main.cog");
+    std::fs::write(&file, format!(r#"flow main():
+    url = "nats://127.0.0.1:{port}"
+    orders = channel("nats", url=url, subject="orders.*", queue="agents", timeout=0.3)
+    order = read(orders)
+    print(order["text"])
+    print(order["subject"])
+    print(order["reply"])
+    write(channel("nats", url=url, subject="orders.done"), order["text"].upper())
+    print(read(orders))
+    try:
+        channel("nats", url=url)
+    catch e:
+        print(e.message)
+"#)).unwrap();
+    let output = Command::new(cognos_bin())
+        .current_dir(dir.path())
+        .env("NATS_TOKEN", "s3cret")
+        .arg("run")
+        .arg(&file)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(stdout, "widget x2\norders.new\n_INBOX.1\nnone\nnats channel requires subject= parameter\n");
+    let seen = server.join().unwrap();
+    assert!(seen[0].starts_with("CONNECT ") && seen[0].contains("\"auth_token\":\"s3cret\""), "{:?}", seen);
+    assert_eq!(&seen[1..], ["SUB orders.* agents 1", "SUB orders.done 2", "PUB orders.done 9 WIDGET X2"]);
+}
+
+#[test]
+fn test_mqtt_channel_against_a_broker() {
+    use std::io::{Read, Write};
+    // An MQTT broker that answers a subscription with a retained motion
+    // event and records what the client sends
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let broker = std::thread::spawn(move || {
+        let (mut socket, _) = listener.accept().unwrap();
+        let mut seen = Vec::new();
+        let string = |body: &[u8], at: &mut usize| {
+            let len = u16::from_be_bytes([body[*at], body[*at + 1]]) as usize;
+            *at += 2 + len;
+            String::from_utf8(body[*at - len..*at].to_vec()).unwrap()
+        };
+        loop {
+            let mut header = [0u8; 1];
+            if socket.read_exact(&mut header).is_err() {
+                break;
+            }
+            let (mut len, mut shift) = (0usize, 0);
+            loop {
+                let mut byte = [0u8; 1];
+                socket.read_exact(&mut byte).unwrap();
+                len |= ((byte[0] & 0x7f) as usize) << shift;
+                shift += 7;
+                if byte[0] & 0x80 == 0 {
+                    break;
+                }
+            }
+            let mut body = vec![0u8; len];
+            socket.read_exact(&mut body).unwrap();
+            let mut at = 0;
+            match header[0] >> 4 {
+                1 => {
+                    // CONNECT: protocol name, level, flags, keep alive, client id, username, password
+                    string(&body, &mut at);
+                    let flags = body[at + 1];
+                    at += 4;
+                    string(&body, &mut at);
+                    assert_eq!(flags & 0xc0, 0xc0);
+                    seen.push(format!("CONNECT {} {}", string(&body, &mut at), string(&body, &mut at)));
+                    socket.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap();
+                }
+                8 => {
+                    let pkid = [body[0], body[1]];
+                    at = 2;
+                    let filter = string(&body, &mut at);
+                    seen.push(format!("SUBSCRIBE {} {}", filter, body[at]));
+                    socket.write_all(&[0x90, 0x03, pkid[0], pkid[1], body[at]]).unwrap();
+                    let (topic, payload) = (b"home/hall/motion", b"detected");
+                    let mut publish = vec![0x31, (2 + topic.len() + payload.len()) as u8, 0, topic.len() as u8];
+                    publish.extend_from_slice(topic);
+                    publish.extend_from_slice(payload);
+                    socket.write_all(&publish).unwrap();
+                }
+                3 => {
+                    let qos = (header[0] >> 1) & 3;
+                    let topic = string(&body, &mut at);
+                    if qos > 0 {
+                        socket.write_all(&[0x40, 0x02, body[at], body[at + 1]]).unwrap();
+                        at += 2;
+                    }
+                    let retain = if header[0] & 1 == 1 { " retain" } else { "" };
+                    seen.push(format!("PUBLISH {} {}{} {}", topic, qos, retain, String::from_utf8_lossy(&body[at..])));
+                }
+                12 => socket.write_all(&[0xd0, 0x00]).unwrap(),
+                _ => {}
+            }
+        }
+        seen
+    });
+
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("main.cog");
+    std::fs::write(&file, format!(r#"flow main():
+    broker = "mqtt://127.0.0.1:{port}"
+    motion = channel("mqtt", broker=broker, topic="home/+/motion", qos=1, timeout=5)
+    event = read(motion)
+    print(event["text"])
+    print(event["topic"])
+    print(event["retained"])
+    write(channel("mqtt", broker=broker, topic="home/hall/light/set", qos=1, retain=true), "ON")
+    print(read(channel("mqtt", broker=broker, topic="home/+/motion", timeout=0.3)))
+    try:
+        write(channel("mqtt", broker=broker, topic="home/#"), "x")
+    catch e:
+        print(e.message)
+    try:
+        channel("mqtt", broker=broker, topic="t", qos=3)
+    catch e:
+        print(e.message)
+"#)).unwrap();
+    let output = Command::new(cognos_bin())
+        .current_dir(dir.path())
+        .env("MQTT_USERNAME", "ha")
+        .env("MQTT_PASSWORD", "pw")
+        .arg("run")
+        .arg(&file)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(stdout, concat!(
+        "detected\nhome/hall/motion\ntrue\nnone\n",
+        "can't publish to 'home/#': MQTT topics to publish to can't be empty or contain + or #\n",
+        "mqtt channel: qos= must be 0, 1 or 2, got '3'\n",
+    ));
+    let seen = broker.join().unwrap();
+    assert_eq!(seen, ["CONNECT ha pw", "SUBSCRIBE home/+/motion 1", "PUBLISH home/hall/light/set 1 retain ON"]);
+}
+
+#[test]
+fn test_mock_screenshots_and_screen_size() {
+    let dir = tempfile::tempdir().unwrap();
+    let cog = dir.path().join("test.cog");
+    let mock = dir.path().join("mock.json");
+    std::fs::write(&cog, r#"flow main():
+    size = screen_size()
+    print(f"{size.width}x{size.height}")
+    shot = screenshot("screen.png")
+    print(shot)
+    print(think("what is open?", images=[shot]))
+    try:
+        screenshot("again.png")
+    catch e:
+        print(e.message)
+"#).unwrap();
+    std::fs::write(&mock, serde_json::json!({
+        "screenshots": ["fixtures/login.png"],
+        "screen_size": [1280, 800],
+        "llm_responses": ["a login form"],
+    }).to_string()).unwrap();
+    let output = Command::new(cognos_bin())
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
+        .current_dir(dir.path())
+        .output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.starts_with("1280x800\nfixtures/login.png\na login form\nMockEnv: no more screenshots (used 1)\n"), "got: {}", stdout);
+    assert!(!dir.path().join("screen.png").exists(), "a mock screenshot writes nothing");
+
+    // A flow of either name is called instead
+    let out = expect_run_ok("flow screenshot(path: String) -> String:\n    return \"own \" + path\n\nflow screen_size() -> Int:\n    return 3\n\nflow main():\n    shot = screenshot(\"a.png\")\n    write(stdout, f\"{shot} {screen_size()}\")\n");
+    assert_eq!(out, "own a.png 3\n");
+
+    // Capturing runs a program, which takes --allow-shell
+    for call in ["screenshot(\"a.png\")", "screen_size()"] {
+        let err = expect_error(&format!("flow main():\n    x = {}\n", call));
+        assert!(err.contains("runs a screen capture program — use: cognos run --allow-shell"), "stderr: {}", err);
+    }
+}
+
+#[test]
+fn test_builtin_tool_definitions() {
+    let dir = tempfile::tempdir().unwrap();
+    let cog = dir.path().join("test.cog");
+    std::fs::write(&cog, r#"flow main():
+    computer = builtin_tool("computer_20250124", display_width_px=1280, display_height_px=800)
+    print(computer["name"])
+    print(computer["display_width_px"])
+    print(builtin_tool("text_editor_20250124")["name"])
+    print(builtin_tool("bash_20250124", name="sh")["name"])
+    print(builtin_tool("web_search"))
+    think("open the settings", model="qwen2.5:7b", tools=[computer])
+"#).unwrap();
+    let output = Command::new(cognos_bin())
+        .args(["run", cog.to_str().unwrap()])
+        .output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(stdout, "computer\n1280\nstr_replace_editor\nsh\n{\"type\": web_search}\n");
+    assert!(!output.status.success());
+    assert!(stderr.contains("ollama can't run builtin_tool() tools"), "got: {}", stderr);
+
+    // A flow of that name is called instead
+    let out = expect_run_ok("flow builtin_tool(x: String) -> String:\n    return \"own \" + x\n\nflow main():\n    write(stdout, builtin_tool(\"bash\"))\n");
+    assert_eq!(out, "own bash\n");
+}
+
+#[test]
+fn test_mock_file_upload() {
+    let dir = tempfile::tempdir().unwrap();
+    let cog = dir.path().join("test.cog");
+    let mock = dir.path().join("mock.json");
+    std::fs::write(&cog, r#"flow main():
+    report = file_upload("q3-report.pdf")
+    data = file_upload("sales.csv", purpose="assistants")
+    print(report, data)
+    python = builtin_tool("code_interpreter", container={"type": "auto", "file_ids": [data]})
+    print(think("Compare the two", model="gpt-4o", files=[report], tools=[python])["content"])
+"#).unwrap();
+    std::fs::write(&mock, serde_json::json!({"llm_responses": ["Sales are up 4%."]}).to_string()).unwrap();
+    let output = Command::new(cognos_bin())
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
+        .output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.starts_with("file-mock-1 file-mock-2\nSales are up 4%.\n"), "got: {}", stdout);
+
+    // A flow of that name is called instead
+    let out = expect_run_ok("flow file_upload(path: String) -> String:\n    return \"local:\" + path\n\nflow main():\n    write(stdout, file_upload(\"a.pdf\"))\n");
+    assert_eq!(out, "local:a.pdf\n");
+}
+
+#[test]
+fn test_think_files_need_an_openai_model() {
+    let dir = tempfile::tempdir().unwrap();
+    let cog = dir.path().join("test.cog");
+    std::fs::write(&cog, r#"flow main():
+    think("Summarize", model="qwen2.5:7b", files=["file-abc"])
+"#).unwrap();
+    let output = Command::new(cognos_bin())
+        .args(["run", cog.to_str().unwrap()])
+        .output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("ollama can't take files="), "got: {}", stderr);
+}
+
+#[test]
+fn test_think_retries_transient_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    let cog = dir.path().join("test.cog");
+    let mock = dir.path().join("mock.json");
+    let trace = dir.path().join("trace.jsonl");
+    std::fs::write(&cog, r#"flow main():
+    print(think("hi", retries=2, backoff="linear"))
+    try:
+        think("again")
+    catch e:
+        print(e.message)
+"#).unwrap();
+    std::fs::write(&mock, serde_json::json!({"llm_responses": [
+        {"error": "overloaded", "status": 529},
+        {"error": "API error: operation timed out", "transient": true},
+        "hello",
+        {"error": "busy", "status": 503},
+    ]}).to_string()).unwrap();
+    let output = Command::new(cognos_bin())
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap(), "--trace", trace.to_str().unwrap()])
+        .output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.starts_with("hello\n"), "got: {}", stdout);
+    // Without retries= or --llm-retries the first failure stands
+    assert!(stdout.contains("(503 Service Unavailable): busy"), "got: {}", stdout);
+
+    let retries: Vec<serde_json::Value> = std::fs::read_to_string(&trace).unwrap().lines()
+        .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+        .filter(|e| e["event"] == "retry")
+        .collect();
+    assert_eq!(retries.len(), 2);
+    assert_eq!((retries[0]["attempt"].as_u64(), retries[0]["delay_ms"].as_u64()), (Some(1), Some(1000)));
+    assert_eq!((retries[1]["attempt"].as_u64(), retries[1]["delay_ms"].as_u64()), (Some(2), Some(2000)));
+    assert_eq!(retries[0]["call"], "llm");
+}
+
+#[test]
+fn test_llm_retries_flag_and_permanent_errors() {
+    let src = r#"flow main():
+    print(think("hi"))
+    try:
+        think("bad", retries=5)
+    catch e:
+        print(e.message)
+"#;
+    let dir = tempfile::tempdir().unwrap();
+    let cog = dir.path().join("test.cog");
+    let mock = dir.path().join("mock.json");
+    std::fs::write(&cog, src).unwrap();
+    std::fs::write(&mock, serde_json::json!({"llm_responses": [
+        {"error": "slow down", "status": 429},
+        "ok",
+        {"error": "no such model", "status": 400},
+        "never reached",
+    ]}).to_string()).unwrap();
+    let output = Command::new(cognos_bin())
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap(), "--llm-retries", "1"])
+        .output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.starts_with("ok\n"), "got: {}", stdout);
+    assert!(stdout.contains("(400 Bad Request): no such model"), "got: {}", stdout);
+
+    let (_, err, code) = run_mock_llm("flow main():\n    think(\"x\", backoff=\"random\")\n", &[]);
+    assert_ne!(code, 0);
+    assert!(err.contains("backoff must be \"constant\", \"linear\" or \"exponential\""), "stderr: {}", err);
+}
+
+#[test]
+fn test_http_get_retries_server_errors() {
+    use std::io::{BufRead, BufReader, Write};
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    let server = std::thread::spawn(move || {
+        for status in ["503 Service Unavailable", "200 OK", "503 Service Unavailable", "429 Too Many Requests", "429 Too Many Requests"] {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap_or(0) > 0 && line != "\r\n" {
+                line.clear();
+            }
+            let body = if status.starts_with("200") { "fine" } else { "later" };
+            let mut stream = stream;
+            write!(stream, "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body).unwrap();
+        }
+    });
+    let (out, err, code) = run_inline(&format!(r#"flow main():
+    print(http.get("{url}", retries=1, backoff="constant"))
+    # Without retries= an error status is just a response
+    print(http.get("{url}"))
+    try:
+        http.get("{url}", retries=1, backoff="constant")
+    catch RateLimitError as e:
+        print(e.message)
+"#), "");
+    server.join().unwrap();
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out, "fine\nlater\nHTTP GET error (429 Too Many Requests): later\n");
+}
+
+/// A one-request OpenAI-compatible server answering `content`. The handle
+/// yields the request's head lines and JSON body.
+fn serve_chat_completion(content: &str) -> (String, std::thread::JoinHandle<(Vec<String>, serde_json::Value)>) {
+    use std::io::{BufRead, BufReader, Read, Write};
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let base = format!("http://{}/v1", listener.local_addr().unwrap());
+    let reply = serde_json::json!({"choices": [{"message": {"role": "assistant", "content": content}, "finish_reason": "stop"}]}).to_string();
+    let server = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut head = Vec::new();
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap_or(0) > 0 && line != "\r\n" {
+            head.push(line.trim_end().to_string());
+            line.clear();
+        }
+        let length = head.iter()
+            .find_map(|h| h.to_lowercase().strip_prefix("content-length: ").map(|n| n.parse::<usize>().unwrap()))
+            .unwrap_or(0);
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        let mut stream = stream;
+        write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", reply.len(), reply).unwrap();
+        (head, serde_json::from_slice::<serde_json::Value>(&body).unwrap())
+    });
+    (base, server)
+}
+
+#[test]
+fn test_think_endpoint_override() {
+    let (base, server) = serve_chat_completion("served locally");
+    let (out, err, code) = run_inline(&format!(r#"flow main():
+    print(think("hi", model="meta-llama/Llama-3.1-8B-Instruct", endpoint="{base}", api_key="sk-local"))
+"#), "");
+    let (head, body) = server.join().unwrap();
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out, "served locally\n");
+    assert_eq!(head[0], "POST /v1/chat/completions HTTP/1.1");
+    assert!(head.iter().any(|h| h.eq_ignore_ascii_case("authorization: Bearer sk-local")), "{:?}", head);
+    assert_eq!(body["model"], "meta-llama/Llama-3.1-8B-Instruct");
+
+    let (_, err, code) = run_inline(r#"flow main():
+    think("hi", api_key="sk-local")
+"#, "");
+    assert_ne!(code, 0);
+    assert!(err.contains("api_key= is for the server given with endpoint="), "stderr: {}", err);
+}
+
+#[test]
+fn test_providers_from_cognos_toml() {
+    let dir = tempfile::tempdir().unwrap();
+    let cog = dir.path().join("test.cog");
+    std::fs::write(&cog, "flow main():\n    print(think(\"hi\", model=\"gpu\"))\n").unwrap();
+    let run = || Command::new(cognos_bin())
+        .args(["run", cog.to_str().unwrap()])
+        .current_dir(dir.path())
+        .env("GPU_BOX_KEY", "sk-gpu")
+        .output().unwrap();
+
+    let (base, server) = serve_chat_completion("from the gpu box");
+    std::fs::write(dir.path().join("cognos.toml"), format!(r#"
+[providers.gpu]
+endpoint = "{base}"
+api_key_env = "GPU_BOX_KEY"
+models = ["meta-llama/*"]
+default_model = "meta-llama/Llama-3.1-70B-Instruct"
+timeout = 30
+"#)).unwrap();
+    let output = run();
+    let (head, body) = server.join().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "from the gpu box\n");
+    assert_eq!(body["model"], "meta-llama/Llama-3.1-70B-Instruct");
+    assert!(head.iter().any(|h| h.eq_ignore_ascii_case("authorization: Bearer sk-gpu")), "{:?}", head);
+
+    std::fs::write(dir.path().join("cognos.toml"), "[providers.gpu]\nendpoint = \"http://gpu-box:8000/v1\"\napi = \"tgi\"\n").unwrap();
+    let output = run();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("providers.gpu: api must be one of openai, ollama, got \"tgi\""), "got: {}", stderr);
+}
+
+#[test]
+fn test_think_logprobs_and_confidence() {
+    let dir = tempfile::tempdir().unwrap();
+    let cog = dir.path().join("test.cog");
+    let mock = dir.path().join("mock.json");
+    std::fs::write(&cog, r#"flow main():
+    r = think("Is this spam?", model="gpt-4o-mini", logprobs=true)
+    print(r["content"])
+    print(round(r["confidence"], 2))
+    print(r["logprobs"][1]["token"], r["logprobs"][1]["logprob"])
+    if r["confidence"] < 0.9:
+        print("abstain")
+    try:
+        think("Is this spam?", logprobs=true, format="json")
+    catch e:
+        print(e.message)
+"#).unwrap();
+    std::fs::write(&mock, serde_json::json!({"llm_responses": [
+        {"content": "yes", "logprobs": [{"token": "y", "logprob": -0.1}, {"token": "es", "logprob": -0.3}]},
+    ]}).to_string()).unwrap();
+    let output = Command::new(cognos_bin())
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
+        .output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.starts_with("yes\n0.82\nes -0.3\nabstain\nthink(): logprobs= returns a Map"), "got: {}", stdout);
+
+    let (_, err, code) = run_inline(r#"flow main():
+    think("Is this spam?", model="qwen2.5:7b", logprobs=true)
+"#, "");
+    assert_ne!(code, 0);
+    assert!(err.contains("ollama doesn't report logprobs="), "stderr: {}", err);
+}
+
+#[test]
+fn test_think_samples_and_reduce() {
+    let dir = tempfile::tempdir().unwrap();
+    let cog = dir.path().join("test.cog");
+    let mock = dir.path().join("mock.json");
+    std::fs::write(&cog, r#"flow main():
+    r = think("Capital of France?", n=3, reduce="majority")
+    print(r["content"], r["votes"], r["samples"].length)
+    all = think("Capital of France?", n=2)
+    print(all)
+    j = think("Capital of France?", n=3, reduce="judge", judge="claude-sonnet")
+    print(j["content"], j["votes"])
+    try:
+        think("Capital of France?", reduce="majority")
+    catch e:
+        print(e.message)
+    try:
+        think("Capital of France?", n=2, stream=true)
+    catch e:
+        print(e.message)
+"#).unwrap();
+    std::fs::write(&mock, serde_json::json!({"llm_responses": [
+        "Lyon", "Paris", "paris.",
+        "Paris", "Marseille",
+        "Paris", "Lyon", "Nice", "Answer 2",
+    ]}).to_string()).unwrap();
+    let output = Command::new(cognos_bin())
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap()])
+        .output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines[0], "Paris 2 3", "got: {}", stdout);
+    assert_eq!(lines[1], "[Paris, Marseille]", "got: {}", stdout);
+    assert_eq!(lines[2], "Lyon 1", "got: {}", stdout);
+    assert!(lines[3].starts_with("think(): reduce= combines several samples"), "got: {}", stdout);
+    assert!(lines[4].starts_with("think(): n= samples plain replies"), "got: {}", stdout);
+}
+
+#[test]
+fn test_judge_grades_against_criteria() {
+    let dir = tempfile::tempdir().unwrap();
+    let cog = dir.path().join("test.cog");
+    let mock = dir.path().join("mock.json");
+    let trace = dir.path().join("trace.jsonl");
+    std::fs::write(&cog, r#"flow main():
+    v = judge("Paris", "Names the capital of France", question="Capital of France?")
+    print(v["score"], v["pass"], v["reasoning"])
+    v = judge("Lyon", "Names the capital of France", reference="Paris", threshold=0.5)
+    print(v["score"], v["pass"])
+    try:
+        judge("Lyon", "Names the capital of France")
+    catch e:
+        print(e.message)
+"#).unwrap();
+    std::fs::write(&mock, serde_json::json!({"llm_responses": [
+        r#"{"reasoning": "Correct and complete.", "grade": 5}"#,
+        "I'd say it's wrong.",
+        r#"{"reasoning": "Lyon is not the capital.", "grade": "1"}"#,
+        "no idea", r#"{"grade": 9}"#,
+    ]}).to_string()).unwrap();
+    let output = Command::new(cognos_bin())
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap(), "--trace", trace.to_str().unwrap()])
+        .output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines[0], "1 true Correct and complete.", "got: {}", stdout);
+    assert_eq!(lines[1], "0 false", "got: {}", stdout);
+    assert!(lines[2].starts_with("judge(): qwen2.5:7b replied '{\"grade\": 9}', not a grade"), "got: {}", stdout);
+    let verdicts: Vec<(f64, bool)> = std::fs::read_to_string(&trace).unwrap().lines()
+        .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+        .filter(|e| e["event"] == "judge")
+        .map(|e| (e["score"].as_f64().unwrap(), e["pass"].as_bool().unwrap())).collect();
+    assert_eq!(verdicts, vec![(1.0, true), (0.0, false)]);
+}
+
+#[test]
+fn test_format_type_sends_native_json_schema() {
+    let src = r#"type Person:
+    name: String
+    age: Int
+
+flow main():
+    p = think("Alice, 30", model="served", format="Person", endpoint="BASE")
+    print(p["name"], p["age"])
+"#;
+    let (base, server) = serve_chat_completion(r#"{"name": "Alice", "age": 30}"#);
+    let (out, err, code) = run_inline(&src.replace("BASE", &base), "");
+    let (_, body) = server.join().unwrap();
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out, "Alice 30\n");
+    assert_eq!(body["response_format"], serde_json::json!({"type": "json_schema", "json_schema": {
+        "name": "Person", "strict": true,
+        "schema": {"title": "Person", "type": "object", "additionalProperties": false,
+            "properties": {"name": {"type": "string"}, "age": {"type": "integer"}},
+            "required": ["name", "age"]},
+    }}));
+    // The schema text stays in the prompt for backends without the parameter
+    assert!(body["messages"][0]["content"].as_str().unwrap().contains("\"age\": <integer>"));
+
+    let (url, server) = fake_http_server(vec![(200, r#"{"message":{"content":"{\"name\": \"Bob\", \"age\": 4}"}}"#)]);
+    let (out, err, code) = run_with_ollama(&src.replace(r#"model="served", "#, r#"model="llama3", "#).replace(r#", endpoint="BASE""#, ""), &url, &[]);
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out.trim(), "Bob 4");
+    let request = server.join().unwrap().remove(0);
+    let body: serde_json::Value = serde_json::from_str(request.split_once('\n').unwrap().1).unwrap();
+    assert_eq!(body["format"]["properties"]["age"], serde_json::json!({"type": "integer"}));
+    assert_eq!(body["format"]["required"], serde_json::json!(["name", "age"]));
+}
+
+#[test]
+fn test_think_repair_reasks_with_validation_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let cog = dir.path().join("test.cog");
+    let mock = dir.path().join("mock.json");
+    let trace = dir.path().join("trace.jsonl");
+    std::fs::write(&cog, r#"type Report:
+    title: String
+    summary: String
+
+flow main():
+    r = think("Report on Q3", format="Report", repair=2)
+    print(r["summary"])
+    try:
+        think("Report on Q4", format="Report", repair=1)
+    catch e:
+        print(e.message)
+    try:
+        think("Report on Q4", repair=1)
+    catch e:
+        print(e.message)
+"#).unwrap();
+    std::fs::write(&mock, serde_json::json!({"llm_responses": [
+        "Sure! Here it is.",
+        r#"{"title": "Q3"}"#,
+        r#"{"title": "Q3", "summary": "Up 4%"}"#,
+        r#"{"title": "Q4"}"#,
+        r#"{"title": "Q4"}"#,
+    ]}).to_string()).unwrap();
+    let output = Command::new(cognos_bin())
+        .args(["test", cog.to_str().unwrap(), "--env", mock.to_str().unwrap(), "--trace", trace.to_str().unwrap(), "--trace-level", "full"])
+        .output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.starts_with("Up 4%\ntype Report validation failed:\n  missing field 'summary'"), "got: {}", stdout);
+    assert!(stdout.contains("\nthink(): repair= re-asks"), "got: {}", stdout);
+    let events: Vec<serde_json::Value> = std::fs::read_to_string(&trace).unwrap().lines()
+        .map(|l| serde_json::from_str(l).unwrap()).collect();
+    let repairs: Vec<&serde_json::Value> = events.iter().filter(|e| e["event"] == "retry" && e["call"] == "repair").collect();
+    assert_eq!(repairs.len(), 3);
+    assert!(repairs[1]["error"].as_str().unwrap().contains("summary"), "{:?}", repairs[1]);
+    let prompts: Vec<&str> = events.iter().filter(|e| e["event"] == "llm_call").filter_map(|e| e["prompt"].as_str()).collect();
+    assert!(prompts[1].contains("Your previous reply:\n\nSure! Here it is."), "{}", prompts[1]);
+}
+
+#[test]
+fn test_conversation_fork_and_rollback() {
+    let (out, err, code) = run_inline(r#"flow main():
+    conv = [
+        {"role": "user", "content": "List the files"},
+        {"role": "assistant", "content": "", "has_tool_calls": true, "tool_calls": [{"id": "t1", "name": "ls", "arguments": {}}]},
+        {"role": "user", "content": [{"type": "tool_result", "tool_use_id": "t1", "content": "a.txt"}]},
+        {"role": "assistant", "content": "Just a.txt", "has_tool_calls": false},
+        {"role": "user", "content": "Delete it"},
+        {"role": "assistant", "content": "Deleted", "has_tool_calls": false},
+    ]
+    trial = conv.fork()
+    dropped = trial.rollback()
+    print(trial.length, conv.length, dropped[0]["content"])
+    trial.rollback(1)
+    print(trial.length)
+    try:
+        conv.rollback(3)
+    catch e:
+        print(e.message)
+    try:
+        [1, 2].fork()
+    catch e:
+        print(e.message)
+"#, "");
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out, "4 6 Delete it\n0\n.rollback(3): the conversation has only 2 turn(s)\n\
+        .fork() works on conversations: Lists of message Maps with a \"role\"\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_claude_cli_resends_malformed_tool_call() {
+    use std::os::unix::fs::PermissionsExt;
+    let home = tempfile::tempdir().unwrap();
+    let bin = home.path().join("bin");
+    std::fs::create_dir_all(&bin).unwrap();
+    // Replies in turn with reply<N>, keeping each prompt as prompt<N>
+    std::fs::write(bin.join("claude"), "#!/bin/sh\ndir=\"$(dirname \"$0\")\"\n\
+        n=$(( $(cat \"$dir/count\" 2>/dev/null || echo 0) + 1 ))\necho $n > \"$dir/count\"\n\
+        cat > \"$dir/prompt$n\"\ncat \"$dir/reply$n\"\n").unwrap();
+    std::fs::set_permissions(bin.join("claude"), std::fs::Permissions::from_mode(0o755)).unwrap();
+    let reply = |text: &str| serde_json::json!({"result": text, "is_error": false}).to_string();
+    std::fs::write(bin.join("reply1"), reply(r#"{"tool_calls": [{"name": "list_files", "arguments": "."}]}"#)).unwrap();
+    std::fs::write(bin.join("reply2"), reply(r#"{"tool_calls": [{"name": "list_files", "arguments": {"dir": "."}}]}"#)).unwrap();
+    let cog = home.path().join("test.cog");
+    std::fs::write(&cog, r#"flow list_files(dir: String) -> String:
+    return "a.txt"
+
+flow main():
+    r = think("What's here?", model="claude-sonnet-4-5", tools=["list_files"])
+    print(r["tool_calls"][0]["name"], r["tool_calls"][0]["arguments"]["dir"])
+"#).unwrap();
+    let path = format!("{}:{}", bin.display(), std::env::var("PATH").unwrap_or_default());
+    let output = auth_command(home.path(), home.path(), &[("PATH", &path)])
+        .args(["run", cog.to_str().unwrap()]).output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(stdout, "list_files .\n");
+    let retry = std::fs::read_to_string(bin.join("prompt2")).unwrap();
+    assert!(retry.starts_with("What's here?\n\n---\nYour previous reply:"), "{}", retry);
+    assert!(retry.contains("Your tool call JSON was invalid: the \"arguments\" of call 1 (list_files) must be an object"), "{}", retry);
+
+    // Out of retries: an error, not a reply quietly without tool calls
+    std::fs::remove_file(bin.join("count")).unwrap();
+    let output = auth_command(home.path(), home.path(), &[("PATH", &path), ("COGNOS_TOOL_CALL_RETRIES", "0")])
+        .args(["run", cog.to_str().unwrap()]).output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("tool call is still invalid after 0 retries"), "{}", stderr);
+}
+
+#[test]
+fn test_claude_cli_path_args_and_session_reuse() {
+    use std::os::unix::fs::PermissionsExt;
+    let home = tempfile::tempdir().unwrap();
+    let bin = home.path().join("opt");
+    std::fs::create_dir_all(&bin).unwrap();
+    // Not on PATH: found through COGNOS_CLAUDE_PATH. Keeps each run's args as args<N>
+    let script = bin.join("my-claude");
+    std::fs::write(&script, "#!/bin/sh\ndir=\"$(dirname \"$0\")\"\n\
+        n=$(( $(cat \"$dir/count\" 2>/dev/null || echo 0) + 1 ))\necho $n > \"$dir/count\"\n\
+        printf '%s\\n' \"$@\" > \"$dir/args$n\"\ncat > /dev/null\n\
+        echo '{\"result\": \"ok\", \"is_error\": false}'\n").unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let cog = home.path().join("test.cog");
+    std::fs::write(&cog, r#"flow main():
+    a = think("one", model="claude-sonnet-4-5")
+    b = think("two", model="claude-sonnet-4-5")
+    print(a, b)
+"#).unwrap();
+    let output = auth_command(home.path(), home.path(), &[
+        ("COGNOS_CLAUDE_PATH", script.to_str().unwrap()),
+        ("COGNOS_CLAUDE_ARGS", "--max-turns 3"),
+        ("COGNOS_CLAUDE_SESSION", "1"),
+    ]).args(["run", cog.to_str().unwrap()]).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "ok ok\n");
+    let args = |n: u32| std::fs::read_to_string(bin.join(format!("args{}", n))).unwrap();
+    let (first, second) = (args(1), args(2));
+    assert!(first.contains("--max-turns\n3\n"), "{}", first);
+    assert!(!first.contains("--no-session-persistence"), "{}", first);
+    let id = first.split("--session-id\n").nth(1).and_then(|rest| rest.lines().next()).expect(&first);
+    assert_eq!(id.len(), 36, "{}", id);
+    assert!(second.contains(&format!("--resume\n{}\n", id)), "{}", second);
+
+    // Without session reuse, each call starts fresh
+    std::fs::remove_file(bin.join("count")).unwrap();
+    let output = auth_command(home.path(), home.path(), &[("COGNOS_CLAUDE_PATH", script.to_str().unwrap())])
+        .args(["run", cog.to_str().unwrap()]).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(args(2).contains("--no-session-persistence"), "{}", args(2));
+    assert!(!args(2).contains("--resume"), "{}", args(2));
+
+    // A missing binary says how to get one
+    let output = auth_command(home.path(), home.path(), &[("COGNOS_CLAUDE_PATH", "/nonexistent/claude")])
+        .args(["run", cog.to_str().unwrap()]).output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("'/nonexistent/claude' not found. Install it with `npm install -g @anthropic-ai/claude-code`"), "{}", stderr);
+}
+
+/// A stdio MCP server in sh: `shout` upper-cases its text (pinging the
+/// client first), `fail` is always an error.
+const FAKE_MCP_SERVER: &str = r#"while IFS= read -r line; do
+  id=$(printf '%s\n' "$line" | sed -n 's/.*"id":\([0-9][0-9]*\).*/\1/p')
+  case "$line" in
+    *'"method":"initialize"'*)
+      printf '{"jsonrpc":"2.0","id":%s,"result":{"protocolVersion":"2025-06-18","capabilities":{"tools":{}},"serverInfo":{"name":"fake"}}}\n' "$id" ;;
+    *'"method":"tools/list"'*)
+      printf '{"jsonrpc":"2.0","id":%s,"result":{"tools":[{"name":"shout","description":"Upper-case text","inputSchema":{"type":"object","properties":{"text":{"type":"string"}},"required":["text"]}},{"name":"fail","description":"Always fails"}]}}\n' "$id" ;;
+    *'"name":"shout"'*)
+      printf '{"jsonrpc":"2.0","method":"notifications/message","params":{"level":"info","data":"shouting"}}\n'
+      printf '{"jsonrpc":"2.0","id":"p1","method":"ping"}\n'
+      IFS= read -r pong
+      case "$pong" in *'"result":{}'*) ;; *) exit 1 ;; esac
+      text=$(printf '%s\n' "$line" | sed -n 's/.*"text":"\([^"]*\)".*/\1/p' | tr a-z A-Z)
+      printf '{"jsonrpc":"2.0","id":%s,"result":{"content":[{"type":"text","text":"%s"}]}}\n' "$id" "$text" ;;
+    *'"name":"fail"'*)
+      printf '{"jsonrpc":"2.0","id":%s,"result":{"content":[{"type":"text","text":"disk full"}],"isError":true}}\n' "$id" ;;
+  esac
+done
+"#;
+
+#[test]
+fn test_mcp_stdio_server_tools() {
+    let dir = tempfile::tempdir().unwrap();
+    let server = dir.path().join("server.sh");
+    std::fs::write(&server, FAKE_MCP_SERVER).unwrap();
+    let src = r#"flow main():
+    srv = mcp("stdio", command="sh", args=["SERVER"])
+    print(srv)
+    for tool in srv.tools:
+        print(tool["name"], "-", tool["description"])
+    print(srv.call("shout", {"text": "hello"}))
+    print(invoke("shout", {"text": "by name"}))
+    try:
+        srv.call("fail")
+    catch e:
+        print(e["message"])
+    try:
+        srv.call("whisper")
+    catch e:
+        print(e["message"])
+"#.replace("SERVER", server.to_str().unwrap());
+    let (url, _) = fake_http_server(vec![]);
+    let (out, err, code) = run_with_ollama(&src, &url, &["--allow-shell"]);
+    assert_eq!(code, 0, "stderr: {}", err);
+    let label = format!("mcp(\"sh {}\")", server.display());
+    assert_eq!(out, format!("{label}\nshout - Upper-case text\nfail - Always fails\nHELLO\nBY NAME\n\
+        mcp tool 'fail' failed: disk full\n{label} has no tool 'whisper' (tools: shout, fail)\n"));
+
+    // Starting a process needs --allow-shell
+    let (_, err, code) = run_with_ollama(&src, &url, &[]);
+    assert_ne!(code, 0);
+    assert!(err.contains("mcp(\"stdio\") starts a server process — use: cognos run --allow-shell"), "{}", err);
+}
+
+#[test]
+fn test_mcp_tools_offered_to_think_and_proxied() {
+    let dir = tempfile::tempdir().unwrap();
+    let server = dir.path().join("server.sh");
+    std::fs::write(&server, FAKE_MCP_SERVER).unwrap();
+    let src = r#"flow main():
+    srv = mcp("stdio", command="sh SERVER")
+    r = think("Shout hi", model="llama3", tools=[srv])
+    for call in r["tool_calls"]:
+        print(call["name"], invoke(call["name"], call["arguments"]))
+    r = think("Shout again", model="llama3", tools=["shout"])
+    print(r["tool_calls"][0]["name"])
+"#.replace("SERVER", server.to_str().unwrap());
+    let reply = r#"{"message":{"content":"","tool_calls":[{"function":{"name":"shout","arguments":{"text":"hi"}}}]}}"#;
+    let (url, requests) = fake_http_server(vec![(200, reply), (200, reply)]);
+    let (out, err, code) = run_with_ollama(&src, &url, &["--allow-shell"]);
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out, "shout HI\nshout\n");
+    let requests = requests.join().unwrap();
+    let body: serde_json::Value = serde_json::from_str(requests[0].split_once('\n').unwrap().1).unwrap();
+    let names: Vec<&str> = body["tools"].as_array().unwrap().iter().map(|t| t["function"]["name"].as_str().unwrap()).collect();
+    assert_eq!(names, ["shout", "fail"]);
+    assert_eq!(body["tools"][0]["function"]["parameters"]["required"], serde_json::json!(["text"]));
+    let body: serde_json::Value = serde_json::from_str(requests[1].split_once('\n').unwrap().1).unwrap();
+    assert_eq!(body["tools"].as_array().unwrap().len(), 1);
+}
+
+#[test]
+fn test_mcp_http_server_json_and_event_stream_replies() {
+    let (url, requests) = fake_http_server(vec![
+        (200, r#"{"jsonrpc":"2.0","id":1,"result":{"protocolVersion":"2025-06-18","capabilities":{},"serverInfo":{"name":"remote"}}}"#),
+        (202, ""),
+        (200, "event: message\ndata: {\"jsonrpc\":\"2.0\",\"id\":2,\"result\":{\"tools\":[{\"name\":\"lookup\",\"description\":\"Find a record\",\"inputSchema\":{\"type\":\"object\"}}]}}\n\n"),
+        (200, r#"{"jsonrpc":"2.0","id":3,"result":{"content":[{"type":"text","text":"{}"}],"structuredContent":{"id":7,"city":"Paris"}}}"#),
+    ]);
+    let (out, err, code) = run_inline(&format!(r#"flow main():
+    srv = mcp("http", url="{url}/mcp", headers={{"Authorization": "Bearer t0k"}})
+    record = srv.call("lookup", {{"id": 7}})
+    print(record["city"], record["id"] + 1)
+"#), "");
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out, "Paris 8\n");
+    let requests = requests.join().unwrap();
+    assert!(requests[0].starts_with("POST /mcp HTTP/1.1\n") && requests[0].contains(r#""method":"initialize""#), "{}", requests[0]);
+    assert!(requests[1].contains(r#""method":"notifications/initialized""#), "{}", requests[1]);
+    assert!(requests[3].contains(r#""name":"lookup""#) && requests[3].contains(r#""arguments":{"id":7}"#), "{}", requests[3]);
+}
+
+#[test]
+fn test_serve_mcp_offers_documented_flows() {
+    let dir = tempfile::tempdir().unwrap();
+    let tools = dir.path().join("tools.cog");
+    std::fs::write(&tools, r#"greeting = "Hello"
+
+flow greet(name: String) -> String:
+    "Greet someone by name"
+    print("greeting", name)
+    return f"{greeting}, {name}!"
+
+flow stats(values: List) -> Map:
+    "Count and sum numbers"
+    total = 0
+    for v in values:
+        total = total + v
+    return {"count": values.length, "sum": total}
+
+flow helper() -> String:
+    return "not served"
+"#).unwrap();
+    // Our own mcp() client talks to it
+    let src = format!(r#"flow main():
+    srv = mcp("stdio", command="{bin}", args=["serve-mcp", "{tools}"])
+    for tool in srv.tools:
+        print(tool["name"], "-", tool["description"])
+    print(srv.call("greet", {{"name": "Ada"}}))
+    s = srv.call("stats", {{"values": [1, 2, 3]}})
+    print(s["count"], s["sum"])
+"#, bin = cognos_bin().display(), tools = tools.display());
+    let (url, _) = fake_http_server(vec![]);
+    let (out, err, code) = run_with_ollama(&src, &url, &["--allow-shell"]);
+    assert_eq!(code, 0, "stderr: {}", err);
+    assert_eq!(out, "greet - Greet someone by name\nstats - Count and sum numbers\nHello, Ada!\n3 6\n");
+
+    // A file that doesn't parse fails before serving
+    std::fs::write(&tools, "flow broken(:\n").unwrap();
+    let output = Command::new(cognos_bin()).args(["serve-mcp", tools.to_str().unwrap()])
+        .stdin(std::process::Stdio::null()).output().unwrap();
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn test_serve_mcp_keeps_program_output_off_stdout() {
+    use std::io::Write as _;
+    let dir = tempfile::tempdir().unwrap();
+    let tools = dir.path().join("tools.cog");
+    std::fs::write(&tools, r#"print("loading tools")
+
+flow noisy(text: String) -> String:
+    "Print and emit, then answer"
+    print("debug:", text)
+    pprint({"text": text})
+    emit("also emitted")
+    help("noisy")
+    return text
+"#).unwrap();
+    let mut child = Command::new(cognos_bin()).args(["serve-mcp", tools.to_str().unwrap()])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn().unwrap();
+    child.stdin.take().unwrap().write_all(concat!(
+        r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"protocolVersion":"2025-06-18"}}"#, "\n",
+        r#"{"jsonrpc":"2.0","id":2,"method":"tools/call","params":{"name":"noisy","arguments":{"text":"hi"}}}"#, "\n",
+    ).as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let replies: Vec<serde_json::Value> = stdout.lines()
+        .map(|line| serde_json::from_str(line).unwrap_or_else(|e| panic!("not JSON-RPC: {:?} ({})", line, e)))
+        .collect();
+    assert_eq!(replies.len(), 2, "{}", stdout);
+    assert_eq!(replies[1]["result"]["content"][0]["text"], "hi");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    for printed in ["loading tools", "debug: hi", "also emitted", "Print and emit, then answer"] {
+        assert!(stderr.contains(printed), "{:?} missing from stderr: {}", printed, stderr);
+    }
+}
+
+#[test]
+fn test_watch_var_reports_changes_with_location() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("test.cog");
+    std::fs::write(&file, r#"limit = 2
+
+flow add_cost(total: Float, cost: Float) -> Float:
+    total = total + cost
+    return total
+
+flow main():
+    total = 0.0
+    history = []
+    other = 1
+    for c in [0.5, 0.25]:
+        total = add_cost(total, c)
+        history = history + [f"cost {c}"]
+    history = history[:1]
+    total = total
+    print(total, limit)
+"#).unwrap();
+    let output = Command::new(cognos_bin())
+        .args(["run", "--watch-var", "total,history,limit", file.to_str().unwrap()])
+        .output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "0.75 2\n");
+    let watched: Vec<String> = String::from_utf8_lossy(&output.stderr).lines()
+        .filter(|l| l.starts_with("[watch]")).map(str::to_string).collect();
+    assert_eq!(watched, [
+        "[watch] limit = 2  (top level, line 1)",
+        "[watch] total = 0.0  (main, line 8)",
+        "[watch] history = []  (main, line 9)",
+        "[watch] total: 0.0 → 0.5  (add_cost, line 4)",
+        "[watch] total: 0.0 → 0.5  (main, line 12)",
+        "[watch] history += [\"cost 0.5\"]  (main, line 13)",
+        "[watch] total: 0.5 → 0.75  (add_cost, line 4)",
+        "[watch] total: 0.5 → 0.75  (main, line 12)",
+        "[watch] history += [\"cost 0.25\"]  (main, line 13)",
+        "[watch] history lost its last 1 item(s), now 1  (main, line 14)",
+    ]);
+
+    // Nothing is checked or printed without the flag
+    let output = Command::new(cognos_bin()).args(["run", file.to_str().unwrap()]).output().unwrap();
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("[watch]"));
+}
+
+#[test]
+fn test_constant_folding_keeps_results_and_errors() {
+    let src = r#"DEBUG = false
+flow main():
+    rule = "=" * 5 + "|"
+    total = 0
+    for i in [1, 2, 3]:
+        total = total + i * (60 * 60)
+        if false:
+            print(undefined_thing())
+        elif 2 > 1 and "yes":
+            total = total + 1
+    while false:
+        total = 0
+    print(rule, total, 7 / 2, -(3 - 5), 1 < 2 < 3)
+    if DEBUG:
+        print("debug")
+    try:
+        x = 9223372036854775807 + 1
+    catch e:
+        print(e["message"])
+    y = 10 // 0
+"#;
+    let (out, err, code) = run_inline(src, "");
+    assert_eq!(code, 1);
+    assert_eq!(out, "=====| 21603 3.5 2 true\ninteger overflow in 9223372036854775807 + 1\n");
+    assert!(err.contains("division by zero") && err.contains("test.cog:20:5"), "stderr: {}", err);
+}